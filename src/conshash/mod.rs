@@ -1,35 +1,31 @@
 use std;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::Bound::{Included, Excluded, Unbounded};
 use std::sync::Arc;
-use std::thread;
-use std::sync::atomic::{AtomicU64, Ordering};
 use parking_lot::{RwLock, RwLockWriteGuard};
 use serde;
 
 use bifrost_hasher::{hash_str, hash_bytes};
 use membership::client::{ObserverClient as MembershipClient, Member, WatchResult};
-use conshash::weights::DEFAULT_SERVICE_ID;
-use conshash::weights::client::{SMClient as WeightSMClient};
 use raft::client::{RaftClient};
 use utils::bincode::{serialize};
 use rand;
 
-pub mod weights;
+// virtual nodes of a member for each unit of its weight
+pub static DEFAULT_VIRTUAL_NODES: u64 = 256;
+// the metadata tag of members with their weight, members without it weigh 1
+pub static WEIGHT_TAG: &'static str = "weight";
 
-pub static DEFAULT_NODE_LIST_SIZE: f64 = 2048f64;
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Action {
     Joined,
     Left,
+    Reweighted,
 }
 
 #[derive(Debug)]
 pub enum InitTableError {
     GroupNotExisted,
-    NoWeightService,
-    NoWeightGroup,
-    NoWeightInfo,
     Unknown,
 }
 
@@ -39,68 +35,123 @@ pub enum CHError {
     InitTableError(InitTableError),
 }
 
-#[derive(Clone)]
-struct Node {
-    start: u64,
-    server: u64,
+// hashes from start up to end, both included. keys are in it by their hash_str or hash_bytes, the
+// same as their servers are looked up by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+// keys of the range moved from one server to another. none for the ring without servers, before
+// or after the change
+#[derive(Debug, Clone, PartialEq)]
+pub struct MovedRange {
+    pub range: HashRange,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+// keys of the range the from server should hand off to the to server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub range: HashRange,
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RingChange {
+    pub member: Member,
+    pub action: Action,
+    pub moved: Vec<MovedRange>,
+}
+
+// start of each virtual node and its server, keys belong to the virtual node starting before them.
+// snapshots of it can be diffed, see ConsistentHashing::diff
+#[derive(Debug, Clone)]
+pub struct Ring {
+    nodes: BTreeMap<u64, u64>,
 }
 
 struct LookupTables {
-    nodes: Vec<Node>,
-    addrs: HashMap<u64, String>
+    ring: Ring,
+    addrs: HashMap<u64, String>,
+    // virtual nodes of the servers in the ring
+    vnodes: HashMap<u64, u64>,
+    // version of the membership the server was last changed by
+    versions: HashMap<u64, u64>,
+    // events up to the version the table was initialized by are in it already
+    base_version: u64,
 }
 
 pub struct ConsistentHashing {
     tables: RwLock<LookupTables>,
     membership: Arc<MembershipClient>,
-    weight_sm_client: WeightSMClient,
     group_name: String,
-    watchers: RwLock<Vec<Box<Fn(&Member, &Action, &LookupTables, &Vec<Node>) + Send + Sync>>>,
-    version: AtomicU64
+    virtual_nodes: u64,
+    watchers: RwLock<Vec<Box<Fn(&RingChange) + Send + Sync>>>,
 }
 
 impl ConsistentHashing {
     pub fn new<'a>(group: &'a str, raft_client: &Arc<RaftClient>) -> Result<Arc<ConsistentHashing>, CHError>  {
+        ConsistentHashing::new_with_virtual_nodes(group, raft_client, DEFAULT_VIRTUAL_NODES)
+    }
+    // more virtual nodes spread keys more evenly over the servers, for more memory and longer changes
+    pub fn new_with_virtual_nodes<'a>(
+        group: &'a str, raft_client: &Arc<RaftClient>, virtual_nodes: u64
+    ) -> Result<Arc<ConsistentHashing>, CHError>  {
         let membership = Arc::new(MembershipClient::new(raft_client));
         let ch = Arc::new(ConsistentHashing {
             tables: RwLock::new(LookupTables {
-                nodes: Vec::new(),
-                addrs: HashMap::new()
+                ring: Ring::new(),
+                addrs: HashMap::new(),
+                vnodes: HashMap::new(),
+                versions: HashMap::new(),
+                base_version: 0,
             }),
             membership: membership.clone(),
-            weight_sm_client: WeightSMClient::new(DEFAULT_SERVICE_ID, &raft_client),
             group_name: group.to_string(),
+            virtual_nodes: virtual_nodes,
             watchers: RwLock::new(Vec::new()),
-            version: AtomicU64::new(0)
         });
         {
             let ch = ch.clone();
             let res = membership.on_group_member_joined(move |r| {
-                if let Ok((member, version)) = r { server_joined(&ch, member, version); }
+                if let Ok((member, version)) = r { server_changed(&ch, member, Action::Joined, version); }
             }, group);
             if let Ok(Ok(_)) = res {} else {return Err(CHError::WatchError(res));}
         }
         {
             let ch = ch.clone();
             let res = membership.on_group_member_online(move |r| {
-                if let Ok((member, version)) = r { server_joined(&ch, member, version); }
+                if let Ok((member, version)) = r { server_changed(&ch, member, Action::Joined, version); }
             }, group);
             if let Ok(Ok(_)) = res {} else {return Err(CHError::WatchError(res));}
         }
         {
             let ch = ch.clone();
             let res = membership.on_group_member_left(move |r| {
-                if let Ok((member, version)) = r { server_left(&ch, member, version); }
+                if let Ok((member, version)) = r { server_changed(&ch, member, Action::Left, version); }
             }, group);
             if let Ok(Ok(_)) = res {} else {return Err(CHError::WatchError(res));}
         }
         {
             let ch = ch.clone();
             let res = membership.on_group_member_offline(move |r| {
-                if let Ok((member, version)) = r { server_left(&ch, member, version); }
+                if let Ok((member, version)) = r { server_changed(&ch, member, Action::Left, version); }
             }, group);
             if let Ok(Ok(_)) = res {} else {return Err(CHError::WatchError(res));}
         }
+        {
+            let ch = ch.clone();
+            let res = membership.on_any_member_meta_changed(move |r| {
+                if let Ok((member, key, _, version)) = r {
+                    if key == WEIGHT_TAG { server_changed(&ch, member, Action::Reweighted, version); }
+                }
+            });
+            if let Ok(Ok(_)) = res {} else {return Err(CHError::WatchError(res));}
+        }
         Ok(ch)
     }
     pub fn new_client<'a>(group: &'a str, raft_client: &Arc<RaftClient>) -> Result<Arc<ConsistentHashing>, CHError> {
@@ -126,38 +177,24 @@ impl ConsistentHashing {
         }
     }
     pub fn get_by_server_id(&self, hash: u64) -> Option<u64> {
+        self.tables.read().ring.server_of(hash)
+    }
+    // id and address of the server the key belongs to
+    pub fn get_server(&self, key: &str) -> Option<(u64, String)> {
+        self.get_server_by_id(hash_str(key))
+    }
+    pub fn get_server_by_id(&self, hash: u64) -> Option<(u64, String)> {
         let lookup_table = self.tables.read();
-        let nodes = &lookup_table.nodes;
-        let len = nodes.len();
-        if len == 0 {return None;}
-        let first_node = nodes.first().unwrap();
-        let last_node = nodes.last().unwrap();
-        if hash < first_node.start {return Some(first_node.server)}
-        if hash >= last_node.start {return Some(last_node.server)}
-        let mut low = 0;
-        let mut high = len - 1;
-        while low <= high {
-            let mid = low + (high - low) / 2;
-            let curr_node = &nodes[mid];
-            let next_node = &nodes[mid + 1];
-            if hash >= curr_node.start && hash < next_node.start {
-                return Some(curr_node.server);
-            } else if hash < curr_node.start {
-                high = mid - 1;
-            } else {
-                low = mid + 1;
-            }
+        match lookup_table.ring.server_of(hash) {
+            Some(server) => Some((server, lookup_table.addrs.get(&server).unwrap().clone())),
+            None => None
         }
-        None
-    }
-    pub fn get_server(&self, hash: u64) -> Option<String> {
-        self.to_server_name(self.get_by_server_id(hash))
     }
     pub fn get_server_by_string(&self, string: &String) -> Option<String> {
-        self.get_server(hash_str(string))
+        self.get_server(string).map(|(_, addr)| addr)
     }
     pub fn get_server_by<T>(&self, obj: &T) -> Option<String> where T: serde::Serialize {
-        self.get_server(hash_bytes(serialize(obj).as_slice()))
+        self.get_server_by_id(hash_bytes(serialize(obj).as_slice())).map(|(_, addr)| addr)
     }
     pub fn get_server_id_by_string(&self, string: &String) -> Option<u64> {
         self.get_by_server_id(hash_str(string))
@@ -165,118 +202,283 @@ impl ConsistentHashing {
     pub fn get_server_id_by<T>(&self, obj: &T) -> Option<u64> where T: serde::Serialize {
         self.get_by_server_id(hash_bytes(serialize(obj).as_slice()))
     }
+    // up to n different servers for the key, like for its replicas. the one it belongs to comes
+    // first, then those of the virtual nodes after it around the ring
+    pub fn nodes_for(&self, key: &str, n: usize) -> Vec<(u64, String)> {
+        let lookup_table = self.tables.read();
+        let mut servers: Vec<(u64, String)> = Vec::new();
+        let start = match lookup_table.ring.owner_start(hash_str(key)) {
+            Some(start) => start,
+            None => return servers
+        };
+        let following = lookup_table.ring.nodes.range((Included(start), Unbounded));
+        let wrapped = lookup_table.ring.nodes.range((Unbounded, Excluded(start)));
+        for (_, server) in following.chain(wrapped) {
+            if servers.len() >= n {break;}
+            if servers.iter().all(|&(id, _)| id != *server) {
+                servers.push((*server, lookup_table.addrs.get(server).unwrap().clone()));
+            }
+        }
+        servers
+    }
     pub fn rand_server(&self) -> Option<String> {
         let rand = rand::random::<u64>();
-        self.get_server(rand)
+        self.get_server_by_id(rand).map(|(_, addr)| addr)
     }
     pub fn nodes_count(&self) -> usize {
         let lookup_table = self.tables.read();
-        return lookup_table.nodes.len();
+        return lookup_table.ring.len();
     }
+    // the weight is of the member and counts in all of its groups, it is kept in the weight tag of
+    // the member metadata. it used to be set for each group in the weights state machine, which is gone.
+    // the server gets virtual_nodes of the ring for each unit of the weight
     pub fn set_weight(&self, server_name: &String, weight: u64) -> bool {
-        let group_id = hash_str(&self.group_name);
         let server_id = hash_str(server_name);
-        if let Ok(Ok(_)) = self.weight_sm_client.set_weight(&group_id, &server_id, &weight) { true } else { false }
+        let tag = WEIGHT_TAG.to_string();
+        if let Ok(Ok(_)) = self.membership.sm_client.update_meta(&server_id, &tag, &weight.to_string()) { true } else { false }
     }
-    pub fn watch_all_actions<F>(&self, f: F)
-        where F: Fn(&Member, &Action, &LookupTables, &Vec<Node>) + 'static + Send + Sync {
+    // called after the ring changed with the key ranges that moved between servers, caches of keys
+    // in them can be invalidated. all other keys still belong to the same servers
+    pub fn on_ring_changed<F>(&self, f: F)
+        where F: Fn(&RingChange) + 'static + Send + Sync {
         let mut watchers = self.watchers.write();
         watchers.push(Box::new(f));
     }
+    // called with the transfers servers should make after the ring changed with the membership,
+    // like the diff of the ring before and after it
+    pub fn on_rebalance_needed<F>(&self, f: F)
+        where F: Fn(&Vec<Transfer>) + 'static + Send + Sync {
+        self.on_ring_changed(move |change: &RingChange| {
+            let mut moved: Vec<&MovedRange> = change.moved.iter().collect();
+            moved.sort_by_key(|moved| moved.range.start);
+            let mut transfers = Vec::new();
+            for moved in moved {
+                if let (Some(from), Some(to)) = (moved.from, moved.to) {
+                    push_transfer(&mut transfers, Transfer { range: moved.range, from: from, to: to });
+                }
+            }
+            if !transfers.is_empty() {f(&transfers);}
+        });
+    }
     pub fn watch_server_nodes_range_changed<F>(&self, server: &String, f: F)
-        // return ranges [...,...)
+        // return ranges [...,...) moved to or from the server
         where F: Fn(Vec<(u64, u64)>) + 'static + Send + Sync {
         let server_id = hash_str(server);
-        let wrapper = move |
-            _: &Member,_: &Action,
-            lookup_table: &LookupTables, _: &Vec<Node>| {
-            let nodes = &lookup_table.nodes;
-            let node_len = nodes.len();
-            let mut ranges: Vec<(u64, u64)> = Vec::new();
-            for ni in 0..node_len {
-                let node = &nodes[ni];
-                if node.server == server_id {
-                    ranges.push((node.start, if ni == node_len - 1 {
-                        std::u64::MAX
-                    } else {
-                        nodes[ni + 1].start
-                    }));
+        self.on_ring_changed(move |change: &RingChange| {
+            let ranges: Vec<(u64, u64)> = change.moved.iter()
+                .filter(|moved| moved.from == Some(server_id) || moved.to == Some(server_id))
+                .map(|moved| (moved.range.start, moved.range.end.saturating_add(1)))
+                .collect();
+            if !ranges.is_empty() {f(ranges);}
+        });
+    }
+    // snapshot of the ring as it is now
+    pub fn ring(&self) -> Ring {
+        self.tables.read().ring.clone()
+    }
+    // ranges of keys whose server is different in the new ring than in the old one, in the order of
+    // their hashes. keys without a server in either of the rings have nothing to hand off
+    pub fn diff(old_ring: &Ring, new_ring: &Ring) -> Vec<Transfer> {
+        let mut bounds: Vec<u64> = old_ring.nodes.keys().chain(new_ring.nodes.keys()).cloned().collect();
+        bounds.push(0);
+        bounds.sort();
+        bounds.dedup();
+        let mut transfers = Vec::new();
+        for (i, start) in bounds.iter().enumerate() {
+            let end = match bounds.get(i + 1) {
+                Some(next) => *next - 1,
+                None => std::u64::MAX
+            };
+            // no virtual node of either ring starts inside the range, its keys have the servers of its start
+            if let (Some(from), Some(to)) = (old_ring.server_of(*start), new_ring.server_of(*start)) {
+                if from != to {
+                    push_transfer(&mut transfers, Transfer { range: HashRange { start: *start, end: end }, from: from, to: to });
                 }
             }
-            if !ranges.is_empty() {f(ranges);}
-        };
-        self.watch_all_actions(wrapper);
+        }
+        transfers
     }
 
+    fn vnodes_of(&self, member: &Member) -> u64 {
+        let weight = match member.meta.get(WEIGHT_TAG) {
+            Some(weight) => weight.parse::<u64>().unwrap_or(1),
+            None => 1
+        };
+        self.virtual_nodes * weight
+    }
     fn init_table_(&self, lookup_table: &mut RwLockWriteGuard<LookupTables>) -> Result<(), InitTableError> {
-        if let Ok(Ok((members, version))) = self.membership.group_members(&self.group_name, true) {
-            let group_id = hash_str(&self.group_name);
-            match self.weight_sm_client.get_weights(&group_id) {
-                Ok(Ok(Some(weights))) =>  {
-                    if let Some(min_weight) = weights.values().min() {
-                        lookup_table.nodes.clear(); // refresh nodes
-                        let mut factors: HashMap<u64, f64> = HashMap::new();
-                        let min_weight = *min_weight as f64;
-                        for member in members.iter() {
-                            let k = member.id;
-                            let w = match weights.get(&k) {
-                                Some(w) => *w as f64,
-                                None => min_weight,
-                            };
-                            factors.insert(k, w / min_weight);
-                        }
-                        let factor_sum: f64 = factors.values().sum();
-                        let nodes_size = if factor_sum > DEFAULT_NODE_LIST_SIZE {factor_sum} else {DEFAULT_NODE_LIST_SIZE};
-                        let factor_scale = nodes_size / factor_sum;
-                        for member in members.iter() {lookup_table.addrs.insert(member.id, member.address.clone());}
-                        for (k, f) in factors.iter() {
-                            let weight = (*f * factor_scale) as u64;
-                            for i in 0..weight {
-                                let node_key = format!("{}_{}", k, i);
-                                let hash = hash_str(&node_key);
-                                lookup_table.nodes.push(Node{
-                                    start: hash, server: *k
-                                });
-                            }
-                        };
-                        lookup_table.nodes.sort_by(|n1, n2| n1.start.cmp(&n2.start));
-                        self.version.store(version, Ordering::Relaxed);
-                        Ok(())
-                    } else {
-                        Err(InitTableError::NoWeightInfo)
-                    }
-                },
-                Err(_) => Err(InitTableError::NoWeightService),
-                Ok(Ok(None)) => Err(InitTableError::NoWeightGroup),
-                _ => Err(InitTableError::Unknown)
+        match self.membership.group_members(&self.group_name, true) {
+            Ok(Ok((members, version))) => {
+                lookup_table.ring.nodes.clear(); // refresh nodes
+                lookup_table.vnodes.clear();
+                lookup_table.versions.clear();
+                for member in members.iter() {
+                    lookup_table.addrs.insert(member.id, member.address.clone());
+                    lookup_table.set_vnodes(member.id, self.vnodes_of(member));
+                }
+                lookup_table.base_version = version;
+                Ok(())
+            },
+            Ok(Err(_)) => Err(InitTableError::GroupNotExisted),
+            Err(_) => Err(InitTableError::Unknown)
+        }
+    }
+}
+
+impl HashRange {
+    pub fn contains(&self, hash: u64) -> bool {
+        hash >= self.start && hash <= self.end
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.contains(hash_str(key))
+    }
+}
 
+impl Ring {
+    pub fn new() -> Ring {
+        Ring { nodes: BTreeMap::new() }
+    }
+    // the ring of the servers with their numbers of virtual nodes, like it would be in
+    // ConsistentHashing. rings of servers that are yet to join can be diffed to plan transfers
+    pub fn of_servers(servers: &Vec<(u64, u64)>) -> Ring {
+        let mut ring = Ring::new();
+        for &(server, vnodes) in servers {
+            for i in 0..vnodes {
+                ring.nodes.entry(vnode_start(server, i)).or_insert(server);
+            }
+        }
+        ring
+    }
+    // server of the key hashed to the hash
+    pub fn server_of(&self, hash: u64) -> Option<u64> {
+        self.nodes.range((Unbounded, Included(hash))).next_back()
+            .or_else(|| self.nodes.iter().next_back())
+            .map(|(_, server)| *server)
+    }
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    // starts of the virtual nodes with their servers, in order
+    pub fn nodes(&self) -> Vec<(u64, u64)> {
+        self.nodes.iter().map(|(start, server)| (*start, *server)).collect()
+    }
+    // start of the virtual node the hash belongs to, hashes before the first one belong to the last
+    fn owner_start(&self, hash: u64) -> Option<u64> {
+        self.nodes.range((Unbounded, Included(hash))).next_back()
+            .or_else(|| self.nodes.iter().next_back())
+            .map(|(start, _)| *start)
+    }
+    // ranges of the virtual node starting at start, the last one also has the keys before the first
+    fn ranges_of(&self, start: u64) -> Vec<HashRange> {
+        match self.nodes.range((Excluded(start), Unbounded)).next() {
+            Some((next, _)) => vec!(HashRange { start: start, end: *next - 1 }),
+            None => {
+                let mut ranges = vec!(HashRange { start: start, end: std::u64::MAX });
+                let first = *self.nodes.keys().next().unwrap();
+                if first > 0 { ranges.push(HashRange { start: 0, end: first - 1 }); }
+                ranges
             }
-        } else {
-            Err(InitTableError::GroupNotExisted)
         }
     }
 }
 
-fn server_joined(ch: &Arc<ConsistentHashing>, member: Member, version: u64) {
-    server_changed(ch, member, Action::Joined, version);
+impl LookupTables {
+    // adds or removes virtual nodes of the server up to count, others are left as they are.
+    // answered the ranges that moved to or from other servers. virtual nodes of the server next to
+    // its own ones keep their keys on it
+    fn set_vnodes(&mut self, server: u64, count: u64) -> Vec<MovedRange> {
+        let current = self.vnodes.get(&server).cloned().unwrap_or(0);
+        let mut moved = Vec::new();
+        if count > current {
+            // starts taken by other servers are skipped
+            let starts: Vec<u64> = (current..count)
+                .map(|i| vnode_start(server, i))
+                .filter(|start| !self.ring.nodes.contains_key(start))
+                .collect();
+            let owners: Vec<Option<u64>> = starts.iter().map(|start| self.ring.server_of(*start)).collect();
+            for start in &starts {
+                self.ring.nodes.insert(*start, server);
+            }
+            for (start, from) in starts.iter().zip(owners) {
+                if from == Some(server) {continue;}
+                for range in self.ring.ranges_of(*start) {
+                    moved.push(MovedRange { range: range, from: from, to: Some(server) });
+                }
+            }
+        } else if count < current {
+            let starts: Vec<u64> = (count..current)
+                .map(|i| vnode_start(server, i))
+                .filter(|start| self.ring.nodes.get(start) == Some(&server))
+                .collect();
+            let ranges: Vec<Vec<HashRange>> = starts.iter().map(|start| self.ring.ranges_of(*start)).collect();
+            for start in &starts {
+                self.ring.nodes.remove(start);
+            }
+            for (start, ranges) in starts.iter().zip(ranges) {
+                let to = self.ring.server_of(*start);
+                if to == Some(server) {continue;}
+                for range in ranges {
+                    moved.push(MovedRange { range: range, from: Some(server), to: to });
+                }
+            }
+        }
+        self.vnodes.insert(server, count);
+        moved
+    }
 }
-fn server_left(ch: &Arc<ConsistentHashing>, member: Member, version: u64) {
-    server_changed(ch, member, Action::Left, version);
+
+fn vnode_start(server: u64, i: u64) -> u64 {
+    hash_str(&format!("{}_{}", server, i))
 }
+
+// ranges next to each other between the same servers are merged
+fn push_transfer(transfers: &mut Vec<Transfer>, transfer: Transfer) {
+    if let Some(last) = transfers.last_mut() {
+        if last.from == transfer.from && last.to == transfer.to && last.range.end.checked_add(1) == Some(transfer.range.start) {
+            last.range.end = transfer.range.end;
+            return;
+        }
+    }
+    transfers.push(transfer);
+}
+
+// only virtual nodes of the member are added or removed. events of a member are applied in the
+// order of their versions, those delivered after a later one are left out
 fn server_changed(ch: &Arc<ConsistentHashing>, member: Member, action: Action, version: u64) {
-    let ch_version = ch.version.load(Ordering::Relaxed);
-    if ch_version < version {
-        let ch = ch.clone();
-        thread::spawn(move || {
-            let mut lookup_table = ch.tables.write();
-            let watchers = ch.watchers.read();
-            let ch_version = ch.version.load(Ordering::Relaxed);
-            if ch_version >= version {return;}
-            let old_nodes = lookup_table.nodes.clone();
-            ch.init_table_(&mut lookup_table);
-            for watch in watchers.iter() {
-                watch(&member, &action, &*lookup_table, &old_nodes);
+    let moved = {
+        let mut lookup_table = ch.tables.write();
+        if version <= lookup_table.base_version {return;}
+        if let Some(applied) = lookup_table.versions.get(&member.id) {
+            if *applied >= version {return;}
+        }
+        match action {
+            Action::Joined => {
+                lookup_table.versions.insert(member.id, version);
+                lookup_table.addrs.insert(member.id, member.address.clone());
+                lookup_table.set_vnodes(member.id, ch.vnodes_of(&member))
+            },
+            Action::Left => {
+                lookup_table.versions.insert(member.id, version);
+                let moved = lookup_table.set_vnodes(member.id, 0);
+                lookup_table.vnodes.remove(&member.id);
+                moved
+            },
+            // of members in other groups or offline, they weigh as they are when they come online
+            Action::Reweighted => if lookup_table.vnodes.contains_key(&member.id) {
+                lookup_table.set_vnodes(member.id, ch.vnodes_of(&member))
+            } else {
+                return;
             }
-        });
+        }
+    };
+    if moved.is_empty() {return;}
+    let change = RingChange {
+        member: member,
+        action: action,
+        moved: moved,
+    };
+    let watchers = ch.watchers.read();
+    for watch in watchers.iter() {
+        watch(&change);
     }
-}
\ No newline at end of file
+}
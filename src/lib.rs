@@ -26,6 +26,7 @@ pub mod conshash;
 pub mod vector_clock;
 
 extern crate byteorder;
+extern crate bytes;
 
 extern crate tokio_core;
 extern crate tokio_io;
@@ -33,6 +34,9 @@ extern crate tokio_service;
 extern crate tokio_proto;
 extern crate tokio_timer;
 extern crate tokio_middleware;
+extern crate tokio_tls;
+extern crate tokio_uds;
+extern crate native_tls;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate parking_lot;
@@ -55,6 +59,9 @@ extern crate num_cpus;
 #[macro_use]
 extern crate lazy_static;
 extern crate backtrace;
+extern crate snap;
+extern crate crc;
+extern crate crypto;
 
 extern crate bifrost_plugins;
 extern crate bifrost_hasher;
@@ -1,17 +1,44 @@
 use std::sync::Arc;
-use raft::client::{RaftClient, SubscriptionError};
+use std::collections::HashMap;
+use raft::client::{RaftClient, SubscriptionError, SubscriptionHandle};
 use raft::state_machine::master::ExecError;
 use bifrost_hasher::hash_str;
 use super::raft::client::SMClient;
 use super::DEFAULT_SERVICE_ID;
 
-pub type WatchResult = Result<Result<u64, SubscriptionError>, ExecError>;
+pub type WatchResult = Result<Result<SubscriptionHandle, SubscriptionError>, ExecError>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MemberStatus {
+    Alive,
+    // missed heartbeats, still online
+    Suspected,
+    Dead,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Member {
     pub id: u64,
     pub address: String,
+    // unless it is dead
     pub online: bool,
+    pub status: MemberStatus,
+    // like the availability zone, weight or roles of it
+    pub meta: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DepartureReason {
+    Left,
+    Dead,
+}
+
+// the member as it was when it left or died, by the clock of the leader
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Departure {
+    pub member: Member,
+    pub reason: DepartureReason,
+    pub at_ms: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -33,6 +60,12 @@ impl MemberClient {
     pub fn leave_group(&self, group: &String) -> Result<Result<(), ()>, ExecError> {
         self.sm_client.leave_group(&hash_str(group), &self.id)
     }
+    pub fn update_meta(&self, key: &String, value: &String) -> Result<Result<(), ()>, ExecError> {
+        self.sm_client.update_meta(&self.id, key, value)
+    }
+    pub fn remove_meta(&self, key: &String) -> Result<Result<(), ()>, ExecError> {
+        self.sm_client.remove_meta(&self.id, key)
+    }
 }
 
 pub struct ObserverClient {
@@ -56,6 +89,17 @@ impl ObserverClient {
     pub fn del_group(&self, name: &String) -> Result<Result<(), ()>, ExecError> {
         self.sm_client.del_group(&hash_str(name))
     }
+    // the member of the address in the group, which is created when it is not yet
+    pub fn join_group(&self, group: &String, address: &String) -> Result<Result<(), ()>, ExecError> {
+        self.sm_client.join_group(group, &hash_str(address))
+    }
+    pub fn leave_group(&self, group: &String, address: &String) -> Result<Result<(), ()>, ExecError> {
+        self.sm_client.leave_group(&hash_str(group), &hash_str(address))
+    }
+    // with their names and member counts
+    pub fn all_groups(&self) -> Result<Result<(Vec<Group>, u64), ()>, ExecError> {
+        self.sm_client.all_groups()
+    }
     pub fn group_leader(&self, group: &String) -> Result<Result<(Option<Member>, u64), ()>, ExecError> {
         self.sm_client.group_leader(&hash_str(group))
     }
@@ -65,6 +109,24 @@ impl ObserverClient {
     pub fn all_members(&self, online_only: bool) -> Result<Result<(Vec<Member>, u64), ()>, ExecError> {
         self.sm_client.all_members(&online_only)
     }
+    // every member with its status, dead ones too
+    pub fn roster(&self) -> Result<Result<(Vec<Member>, u64), ()>, ExecError> {
+        self.sm_client.all_members(&false)
+    }
+    pub fn member_status(&self, id: u64) -> Result<Result<Option<MemberStatus>, ()>, ExecError> {
+        self.sm_client.member_status(&id)
+    }
+    pub fn departure(&self, id: u64) -> Result<Result<Option<Departure>, ()>, ExecError> {
+        self.sm_client.departure(&id)
+    }
+    // the member of the address leaves as planned, unlike those that stop heartbeats
+    pub fn leave(&self, address: &String) -> Result<Result<(), ()>, ExecError> {
+        self.sm_client.leave(&hash_str(address))
+    }
+    // members whose metadata has the value for the key
+    pub fn members_with_tag(&self, key: &String, value: &String) -> Result<Result<(Vec<Member>, u64), ()>, ExecError> {
+        self.sm_client.members_with_tag(key, value)
+    }
     pub fn on_group_member_offline<'a, F>(&self, f: F, group: &'a str) -> WatchResult
         where F: Fn(Result<(Member, u64), ()>)  + 'static + Send + Sync {
         self.sm_client.on_group_member_offline(f, &hash_str(group))
@@ -102,4 +164,24 @@ impl ObserverClient {
         where F: Fn(Result<(Option<Member>, Option<Member>, u64), ()>)  + 'static + Send + Sync {
         self.sm_client.on_group_leader_changed(f, &hash_str(group))
     }
-}
\ No newline at end of file
+    pub fn on_member_suspected<F>(&self, f: F) -> WatchResult
+        where F: Fn(Result<(Member, u64), ()>)  + 'static + Send + Sync {
+        self.sm_client.on_member_suspected(f)
+    }
+    pub fn on_member_dead<F>(&self, f: F) -> WatchResult
+        where F: Fn(Result<(Member, u64), ()>)  + 'static + Send + Sync {
+        self.sm_client.on_member_dead(f)
+    }
+    pub fn on_member_recovered<F>(&self, f: F) -> WatchResult
+        where F: Fn(Result<(Member, u64), ()>)  + 'static + Send + Sync {
+        self.sm_client.on_member_recovered(f)
+    }
+    pub fn on_any_member_meta_changed<F>(&self, f: F) -> WatchResult
+        where F: Fn(Result<(Member, String, Option<String>, u64), ()>)  + 'static + Send + Sync {
+        self.sm_client.on_any_member_meta_changed(f)
+    }
+    pub fn on_member_meta_changed<F>(&self, f: F, id: u64) -> WatchResult
+        where F: Fn(Result<(Member, String, Option<String>, u64), ()>)  + 'static + Send + Sync {
+        self.sm_client.on_member_meta_changed(f, &id)
+    }
+}
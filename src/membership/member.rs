@@ -1,16 +1,16 @@
 use raft::client::RaftClient;
 use raft::state_machine::master::ExecError;
-use super::DEFAULT_SERVICE_ID;
+use super::{DEFAULT_SERVICE_ID, FailureDetection};
 use super::heartbeat_rpc::*;
 use super::raft::client::SMClient;
 use super::client::{MemberClient, ObserverClient as ObserverClient};
 use std::sync::Arc;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::{thread, time};
+use std::thread;
+use std::time::Duration;
 use bifrost_hasher::hash_str;
 
-static PING_INTERVAL: u64 = 100;
-
 pub struct MemberService {
     member_client: MemberClient,
     sm_client: Arc<SMClient>,
@@ -22,6 +22,16 @@ pub struct MemberService {
 
 impl MemberService {
     pub fn new(server_address: &String, raft_client: &Arc<RaftClient>) -> Arc<MemberService> {
+        MemberService::new_with_interval(server_address, raft_client, FailureDetection::Default().heartbeat_interval)
+    }
+    // the heartbeat interval of the membership servers, see Membership::new_with_detection
+    pub fn new_with_interval(server_address: &String, raft_client: &Arc<RaftClient>, ping_interval: Duration) -> Arc<MemberService> {
+        MemberService::new_with_meta(server_address, raft_client, &HashMap::new(), ping_interval)
+    }
+    // joins with the metadata, if it did not join before
+    pub fn new_with_meta(
+        server_address: &String, raft_client: &Arc<RaftClient>, meta: &HashMap<String, String>, ping_interval: Duration
+    ) -> Arc<MemberService> {
         let server_id = hash_str(server_address);
         let sm_client = Arc::new(SMClient::new(DEFAULT_SERVICE_ID, &raft_client));
         let service = Arc::new(MemberService {
@@ -35,7 +45,7 @@ impl MemberService {
             closed: AtomicBool::new(false),
             id: server_id,
         });
-        sm_client.join(&server_address);
+        sm_client.join_with_meta(&server_address, meta);
         let service_clone = service.clone();
         thread::spawn(move || {
             while !service_clone.closed.load(Ordering::Relaxed) {
@@ -44,7 +54,7 @@ impl MemberService {
                     let heartbeat_client = SyncServiceClient::new(DEFAULT_SERVICE_ID, &rpc_client);
                     heartbeat_client.ping(&service_clone.id);
                 }
-                thread::sleep(time::Duration::from_millis(PING_INTERVAL))
+                thread::sleep(ping_interval)
             }
         });
         return service;
@@ -62,6 +72,12 @@ impl MemberService {
     pub fn leave_group(&self, group: &String) -> Result<Result<(), ()>, ExecError> {
         self.member_client.leave_group(group)
     }
+    pub fn update_meta(&self, key: &String, value: &String) -> Result<Result<(), ()>, ExecError> {
+        self.member_client.update_meta(key, value)
+    }
+    pub fn remove_meta(&self, key: &String) -> Result<Result<(), ()>, ExecError> {
+        self.member_client.remove_meta(key)
+    }
     pub fn client(&self) -> ObserverClient {
         ObserverClient::new_from_sm(&self.sm_client)
     }
@@ -2,16 +2,49 @@ pub mod client;
 pub mod server;
 pub mod member;
 
-use membership::client::{Member as ClientMember};
+use membership::client::{Group as ClientGroup, Member as ClientMember, MemberStatus, Departure};
+use std::collections::HashMap;
+use std::time::Duration;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_MEMBERSHIP_SERVICE) as u64;
 
+// members ping the leader every heartbeat_interval. those that missed suspect_after heartbeats in a
+// row are suspected, dead_after and they are dead and offline. the leader commits every transition
+#[derive(Clone, Copy, Debug)]
+pub struct FailureDetection {
+    pub heartbeat_interval: Duration,
+    pub suspect_after: u64,
+    pub dead_after: u64,
+    // dead members leave all their groups, instead of staying offline in them. they join the groups
+    // again if they recover. every membership server should have the same
+    pub leave_groups_when_dead: bool,
+    // how long members that left or died are remembered with the reason, see Departure
+    pub departure_retention: Duration,
+}
+
+impl FailureDetection {
+    pub fn Default() -> FailureDetection {
+        FailureDetection {
+            heartbeat_interval: Duration::from_millis(100),
+            suspect_after: 5,
+            dead_after: 10,
+            leave_groups_when_dead: false,
+            departure_retention: Duration::from_secs(60),
+        }
+    }
+}
+
 pub mod raft {
     use super::*;
     raft_state_machine! {
-        def cmd hb_online_changed(online: Vec<u64>, offline: Vec<u64>);
+        def cmd hb_status_changed(ctx, changes: Vec<(u64, MemberStatus)>);
         def cmd join(address: String) -> u64;
-        def cmd leave(id: u64);
+        // members that joined already keep the metadata they have
+        def cmd join_with_meta(address: String, meta: HashMap<String, String>) -> u64;
+        def cmd update_meta(id: u64, key: String, value: String);
+        def cmd remove_meta(id: u64, key: String);
+        // planned, observers get on_any_member_left and never on_member_dead for it
+        def cmd leave(ctx, id: u64);
         def cmd join_group(group_name: String, id: u64);
         def cmd leave_group(group: u64, id: u64);
         def cmd new_group(name: String) -> u64 | u64;
@@ -19,6 +52,11 @@ pub mod raft {
         def qry group_leader(group: u64) -> (Option<ClientMember>, u64);
         def qry group_members (group: u64, online_only: bool) -> (Vec<ClientMember>, u64);
         def qry all_members (online_only: bool) -> (Vec<ClientMember>, u64);
+        def qry all_groups() -> (Vec<ClientGroup>, u64);
+        def qry member_status(id: u64) -> Option<MemberStatus>;
+        // why and when the member left or died, for departure_retention
+        def qry departure(id: u64) -> Option<Departure>;
+        def qry members_with_tag(key: String, value: String) -> (Vec<ClientMember>, u64);
         def sub on_group_member_offline(group: u64) -> (ClientMember, u64); //
         def sub on_any_member_offline() -> (ClientMember, u64); //
         def sub on_group_member_online(group: u64) -> (ClientMember, u64); //
@@ -28,6 +66,13 @@ pub mod raft {
         def sub on_group_member_left(group: u64) -> (ClientMember, u64); //
         def sub on_any_member_left() -> (ClientMember, u64); //
         def sub on_group_leader_changed(group: u64) -> (Option<ClientMember>, Option<ClientMember>, u64);
+        def sub on_member_suspected() -> (ClientMember, u64);
+        def sub on_member_dead() -> (ClientMember, u64);
+        // alive again after it was suspected or dead
+        def sub on_member_recovered() -> (ClientMember, u64);
+        // the member with its metadata after the change, the key and the value it had before
+        def sub on_any_member_meta_changed() -> (ClientMember, String, Option<String>, u64);
+        def sub on_member_meta_changed(id: u64) -> (ClientMember, String, Option<String>, u64);
     }
 }
 
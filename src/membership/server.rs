@@ -3,27 +3,29 @@ use super::heartbeat_rpc::*;
 use super::raft::*;
 use super::*;
 use raft::{RaftService, LogEntry, RaftMsg, Service as raft_svr_trait};
-use raft::state_machine::StateMachineCtl;
+use raft::state_machine::{StateMachineCtl, CommandCtx};
 use raft::state_machine::callback::server::{SMCallback, notify as cb_notify};
 use rpc::Server;
 use parking_lot::{RwLock};
 use std::collections::{HashMap, HashSet, BTreeSet};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::{thread, time as std_time};
+use std::cmp::max;
+use std::mem;
+use std::thread;
 use bifrost_hasher::hash_str;
-use membership::client::{Group as ClientGroup, Member as ClientMember};
-
-static MAX_TIMEOUT: i64 = 1000; //5 secs for 500ms heartbeat
+use membership::client::{Group as ClientGroup, Member as ClientMember, MemberStatus, Departure, DepartureReason};
 
 struct HBStatus {
-    online: bool,
+    // as the leader last proposed it
+    status: MemberStatus,
     last_updated: i64,
 }
 
 pub struct HeartbeatService {
     status: RwLock<HashMap<u64, HBStatus>>,
     raft_service: Arc<RaftService>,
+    detection: FailureDetection,
     closed: AtomicBool,
     was_leader: AtomicBool,
 }
@@ -33,7 +35,7 @@ impl Service for HeartbeatService {
         let mut stat_map = self.status.write();
         let current_time = time::get_time();
         let mut stat = stat_map.entry(*id).or_insert_with(|| HBStatus {
-            online: false,
+            status: MemberStatus::Dead,
             last_updated: current_time,
             //orthodoxy info will trigger the watcher thread to update
         });
@@ -43,26 +45,54 @@ impl Service for HeartbeatService {
     }
 }
 impl HeartbeatService {
-    fn update_raft(&self, online: &Vec<u64>, offline: &Vec<u64>) {
-        let log = commands::hb_online_changed::new(online, offline);
+    fn update_raft(&self, changes: &Vec<(u64, MemberStatus)>) {
+        let log = commands::hb_status_changed::new(changes);
         let fn_id = log.encode().0;
         self.raft_service.c_command(&LogEntry {
             id: 0,
             term: 0,
             sm_id: DEFAULT_SERVICE_ID,
             fn_id: fn_id,
-            data: log.data
+            data: log.data,
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0
         });
     }
     fn transfer_leadership(&self) { //update timestamp for every alive server
         let mut stat_map = self.status.write();
         let current_time = time::get_time();
         for stat in stat_map.values_mut() {
-            if stat.online {
+            if stat.status != MemberStatus::Dead {
                 stat.last_updated = current_time;
             }
         }
     }
+    // the status of every member by the heartbeats it missed, those that changed since last time
+    fn detect(&self) -> Vec<(u64, MemberStatus)> {
+        let detection = &self.detection;
+        let interval = detection.heartbeat_interval;
+        let interval_ms = max(1, time::duration_to_ms(interval) as i64);
+        let current_time = time::get_time();
+        let mut changes = Vec::new();
+        let mut status_map = self.status.write();
+        for (id, stat) in status_map.iter_mut() {
+            let missed = ((current_time - stat.last_updated) / interval_ms) as u64;
+            let status = if missed >= detection.dead_after {
+                MemberStatus::Dead
+            } else if missed >= detection.suspect_after {
+                MemberStatus::Suspected
+            } else {
+                MemberStatus::Alive
+            };
+            if status != stat.status {
+                stat.status = status;
+                changes.push((*id, status));
+            }
+        }
+        changes
+    }
 }
 dispatch_rpc_service_functions!(HeartbeatService);
 
@@ -70,6 +100,11 @@ struct Member {
     pub id: u64,
     pub address: String,
     pub groups: HashSet<u64>,
+    // as committed, the leader decides it by the heartbeats
+    pub status: MemberStatus,
+    pub meta: HashMap<String, String>,
+    // groups it left when it was declared dead, joined again when it recovers
+    pub dead_groups: HashSet<u64>,
 }
 
 struct MemberGroup {
@@ -83,6 +118,8 @@ pub struct Membership {
     heartbeat: Arc<HeartbeatService>,
     groups: HashMap<u64, MemberGroup>,
     members: HashMap<u64, Member>,
+    // of members that left or died, kept for departure_retention
+    departures: HashMap<u64, Departure>,
     callback: Option<SMCallback>,
     version: u64,
 }
@@ -94,10 +131,14 @@ impl Drop for Membership {
 
 impl Membership {
     pub fn new(server: &Arc<Server>, raft_service: &Arc<RaftService>) {
+        Membership::new_with_detection(server, raft_service, FailureDetection::Default())
+    }
+    pub fn new_with_detection(server: &Arc<Server>, raft_service: &Arc<RaftService>, detection: FailureDetection) {
         let service = Arc::new(HeartbeatService {
             status: RwLock::new(HashMap::new()),
             closed: AtomicBool::new(false),
             raft_service: raft_service.clone(),
+            detection: detection,
             was_leader: AtomicBool::new(false),
         });
         let service_clone = service.clone();
@@ -108,40 +149,19 @@ impl Membership {
                 if !was_leader && is_leader {service_clone.transfer_leadership()}
                 if was_leader != is_leader {service_clone.was_leader.store(is_leader, Ordering::Relaxed);}
                 if is_leader {
-                    let current_time = time::get_time();
-                    let mut outdated_members: Vec<u64> = Vec::new();
-                    let mut backedin_members: Vec<u64> = Vec::new();
-                    {
-                        let mut status_map = service_clone.status.write();
-                        let mut members_to_update: HashMap<u64, bool> = HashMap::new();
-                        for (id, status) in status_map.iter() {
-                            let alive = (current_time - status.last_updated) < MAX_TIMEOUT;
-                            if status.online && !alive {
-                                outdated_members.push(*id);
-                                members_to_update.insert(*id, alive);
-                            }
-                            if !status.online && alive {
-                                backedin_members.push(*id);
-                                members_to_update.insert(*id, alive);
-                            }
-                        }
-                        for (id, alive) in members_to_update.iter() {
-                            let mut status = status_map.get_mut(&id).unwrap();
-                            status.online = *alive;
-                        }
-
-                    }
-                    if backedin_members.len() + outdated_members.len() > 0 {
-                        service_clone.update_raft(&backedin_members, &outdated_members);
+                    let changes = service_clone.detect();
+                    if !changes.is_empty() {
+                        service_clone.update_raft(&changes);
                     }
                 }
-                thread::sleep(std_time::Duration::from_secs(1));
+                thread::sleep(service_clone.detection.heartbeat_interval);
             }
         });
         let mut membership_service = Membership {
             heartbeat: service.clone(),
             groups: HashMap::new(),
             members: HashMap::new(),
+            departures: HashMap::new(),
             callback: None,
             version: 0,
         };
@@ -151,11 +171,12 @@ impl Membership {
     }
     fn compose_client_member(&self, id: u64) -> ClientMember {
         let member = self.members.get(&id).unwrap();
-        let stat_map = self.heartbeat.status.read();
         ClientMember {
             id: id,
             address: member.address.clone(),
-            online: stat_map.get(&id).unwrap().online
+            online: member.status != MemberStatus::Dead,
+            status: member.status,
+            meta: member.meta.clone(),
         }
     }
     fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
@@ -197,6 +218,43 @@ impl Membership {
             }
         }
     }
+    fn notify_for_status_changed(&self, id: u64, old: MemberStatus, new: MemberStatus) {
+        let client_member = self.compose_client_member(id);
+        let version = self.version;
+        match new {
+            MemberStatus::Suspected => cb_notify(
+                &self.callback,
+                &commands::on_member_suspected::new(),
+                || Ok((client_member.clone(), version))
+            ),
+            MemberStatus::Dead => cb_notify(
+                &self.callback,
+                &commands::on_member_dead::new(),
+                || Ok((client_member.clone(), version))
+            ),
+            MemberStatus::Alive => if old != MemberStatus::Alive {
+                cb_notify(
+                    &self.callback,
+                    &commands::on_member_recovered::new(),
+                    || Ok((client_member.clone(), version))
+                )
+            }
+        }
+    }
+    fn notify_for_meta_changed(&self, id: u64, key: &String, old: &Option<String>) {
+        let client_member = self.compose_client_member(id);
+        let version = self.version;
+        cb_notify(
+            &self.callback,
+            &commands::on_any_member_meta_changed::new(),
+            || Ok((client_member.clone(), key.clone(), old.clone(), version))
+        );
+        cb_notify(
+            &self.callback,
+            &commands::on_member_meta_changed::new(&id),
+            || Ok((client_member.clone(), key.clone(), old.clone(), version))
+        );
+    }
     fn notify_for_member_left(&self, id: u64) {
         let client_member = self.compose_client_member(id);
         let version = self.version;
@@ -238,6 +296,55 @@ impl Membership {
             return Err(());
         }
     }
+    fn join_group_(&mut self, group_id: u64, id: u64) -> Result<(), ()> {
+        let mut success = false;
+        if let Some(ref mut group) = self.groups.get_mut(&group_id) {
+            if let Some(ref mut member) = self.members.get_mut(&id) {
+                group.members.insert(id);
+                member.groups.insert(group_id);
+                success = true;
+            }
+        }
+        if success {
+            cb_notify(
+                &self.callback,
+                &commands::on_group_member_joined::new(&group_id),
+                || Ok((self.compose_client_member(id), self.version))
+            );
+            self.group_leader_candidate_available(group_id, id);
+            return Ok(());
+        } else {
+            return Err(());
+        }
+    }
+    fn leave_groups_when_dead(&mut self, id: u64) {
+        let groups = match self.member_groups(id) {
+            Some(groups) => groups,
+            None => return
+        };
+        for group_id in &groups {
+            self.leave_group_(*group_id, id, true);
+        }
+        if let Some(member) = self.members.get_mut(&id) {
+            member.dead_groups = groups;
+        }
+    }
+    fn rejoin_dead_groups(&mut self, id: u64) {
+        let groups = match self.members.get_mut(&id) {
+            Some(member) => mem::replace(&mut member.dead_groups, HashSet::new()),
+            None => return
+        };
+        // unless the group was deleted since
+        for group_id in groups {
+            if self.groups.contains_key(&group_id) {
+                self.join_group_(group_id, id);
+            }
+        }
+    }
+    fn expire_departures(&mut self, now: u64) {
+        let retention = time::duration_to_ms(self.heartbeat.detection.departure_retention);
+        self.departures.retain(|_, departure| departure.at_ms + retention > now);
+    }
     fn member_groups(&self, member: u64) -> Option<HashSet<u64>> {
         if let Some(member) = self.members.get(&member) {
             Some(member.groups.clone())
@@ -247,11 +354,10 @@ impl Membership {
     }
     fn group_first_online_member_id(&self, group: u64) -> Result<Option<u64>, ()> {
         if let Some(group) = self.groups.get(&group) {
-            let stat_map = self.heartbeat.status.read();
             for member in group.members.iter() {
-                if let Some(member_stat) = stat_map.get(&member) {
-                    if member_stat.online {
-                        return Ok(Some(*member))
+                if let Some(member) = self.members.get(member) {
+                    if member.status != MemberStatus::Dead {
+                        return Ok(Some(member.id))
                     }
                 }
             }
@@ -326,32 +432,59 @@ impl Membership {
 }
 
 impl StateMachineCmds for Membership {
-    fn hb_online_changed(&mut self, online: Vec<u64>, offline: Vec<u64>) -> Result<(), ()> {
+    fn hb_status_changed(&mut self, ctx: CommandCtx, changes: Vec<(u64, MemberStatus)>) -> Result<(), ()> {
         self.version += 1;
+        self.expire_departures(ctx.leader_time_ms);
         {
+            // followers taking over the leadership start from the committed statuses. members that
+            // left are not watched anymore, even if they pinged after they left
             let mut stat_map = self.heartbeat.status.write();
-            for id in &online {
-                if let Some(ref mut stat) = stat_map.get_mut(&id) {
-                    stat.online = true;
+            for &(id, status) in &changes {
+                if !self.members.contains_key(&id) {
+                    stat_map.remove(&id);
+                } else if let Some(ref mut stat) = stat_map.get_mut(&id) {
+                    stat.status = status;
                 }
             }
-            for id in &offline {
-                if let Some(ref mut stat) = stat_map.get_mut(&id) {
-                    stat.online = false;
+        }
+        for (id, status) in changes {
+            let old = match self.members.get_mut(&id) {
+                Some(member) => {
+                    let old = member.status;
+                    member.status = status;
+                    old
+                },
+                None => continue
+            };
+            if old == status {
+                continue;
+            }
+            self.notify_for_status_changed(id, old, status);
+            if status == MemberStatus::Dead {
+                let departure = Departure {
+                    member: self.compose_client_member(id),
+                    reason: DepartureReason::Dead,
+                    at_ms: ctx.leader_time_ms,
+                };
+                self.departures.insert(id, departure);
+                self.notify_for_member_offline(id);
+                self.leader_candidate_unavailable(id);
+                if self.heartbeat.detection.leave_groups_when_dead {
+                    self.leave_groups_when_dead(id);
                 }
+            } else if old == MemberStatus::Dead {
+                self.departures.remove(&id);
+                self.rejoin_dead_groups(id);
+                self.notify_for_member_online(id);
+                self.leader_candidate_available(id);
             }
         }
-        for id in online {
-            self.notify_for_member_online(id);
-            self.leader_candidate_available(id);
-        }
-        for id in offline {
-            self.notify_for_member_offline(id);
-            self.leader_candidate_unavailable(id);
-        }
         Ok(())
     }
     fn join(&mut self, address: String) -> Result<u64, ()> {
+        self.join_with_meta(address, HashMap::new())
+    }
+    fn join_with_meta(&mut self, address: String, meta: HashMap<String, String>) -> Result<u64, ()> {
         self.version += 1;
         let id = hash_str(&address);
         let mut joined = false;
@@ -360,20 +493,24 @@ impl StateMachineCmds for Membership {
             self.members.entry(id).or_insert_with(|| {
                 let current_time = time::get_time();
                 let mut stat = stat_map.entry(id).or_insert_with(|| HBStatus {
-                    online: true,
+                    status: MemberStatus::Alive,
                     last_updated: current_time
                 });
-                stat.online = true;
+                stat.status = MemberStatus::Alive;
                 stat.last_updated = current_time;
                 joined = true;
                 Member {
                     id: id,
                     address: address.clone(),
                     groups: HashSet::new(),
+                    status: MemberStatus::Alive,
+                    meta: meta,
+                    dead_groups: HashSet::new(),
                 }
             });
         }
         if joined {
+            self.departures.remove(&id);
             cb_notify(
                 &self.callback,
                 &commands::on_any_member_joined::new(),
@@ -384,9 +521,34 @@ impl StateMachineCmds for Membership {
             Err(())
         }
     }
-    fn leave(&mut self, id: u64) -> Result<(), ()> {
+    fn update_meta(&mut self, id: u64, key: String, value: String) -> Result<(), ()> {
         if !self.members.contains_key(&id) {return Err(())};
         self.version += 1;
+        let old = self.members.get_mut(&id).unwrap().meta.insert(key.clone(), value.clone());
+        if old.as_ref() != Some(&value) {
+            self.notify_for_meta_changed(id, &key, &old);
+        }
+        Ok(())
+    }
+    fn remove_meta(&mut self, id: u64, key: String) -> Result<(), ()> {
+        if !self.members.contains_key(&id) {return Err(())};
+        self.version += 1;
+        let old = self.members.get_mut(&id).unwrap().meta.remove(&key);
+        if old.is_some() {
+            self.notify_for_meta_changed(id, &key, &old);
+        }
+        Ok(())
+    }
+    fn leave(&mut self, ctx: CommandCtx, id: u64) -> Result<(), ()> {
+        if !self.members.contains_key(&id) {return Err(())};
+        self.version += 1;
+        self.expire_departures(ctx.leader_time_ms);
+        let departure = Departure {
+            member: self.compose_client_member(id),
+            reason: DepartureReason::Left,
+            at_ms: ctx.leader_time_ms,
+        };
+        self.departures.insert(id, departure);
         let mut groups:Vec<u64> = Vec::new();
         if let Some(member) = self.members.get(&id) {
             for group in &member.groups {
@@ -410,26 +572,8 @@ impl StateMachineCmds for Membership {
     fn join_group(&mut self, group_name: String, id: u64) -> Result<(), ()> {
         let group_id = hash_str(&group_name);
         self.version += 1;
-        let mut success = false;
         if !self.groups.contains_key(&group_id) {self.new_group(group_name);} // create group if not exists
-        if let Some(ref mut group) = self.groups.get_mut(&group_id) {
-            if let Some(ref mut member) = self.members.get_mut(&id) {
-                group.members.insert(id);
-                member.groups.insert(group_id);
-                success = true;
-            }
-        }
-        if success {
-            cb_notify(
-                &self.callback,
-                &commands::on_group_member_joined::new(&group_id),
-                || Ok((self.compose_client_member(id), self.version))
-            );
-            self.group_leader_candidate_available(group_id, id);
-            return Ok(());
-        } else {
-            return Err(());
-        }
+        self.join_group_(group_id, id)
     }
     fn leave_group(&mut self, group_id: u64, id: u64) -> Result<(), ()> {
         self.version += 1;
@@ -497,6 +641,27 @@ impl StateMachineCmds for Membership {
             .filter(|member| !online_only || member.online)
             .collect(), self.version))
     }
+    fn all_groups(&self) -> Result<(Vec<ClientGroup>, u64), ()> {
+        Ok((self.groups.values()
+            .map(|group| ClientGroup {
+                id: group.id,
+                name: group.name.clone(),
+                members: group.members.len() as u64,
+            })
+            .collect(), self.version))
+    }
+    fn member_status(&self, id: u64) -> Result<Option<MemberStatus>, ()> {
+        Ok(self.members.get(&id).map(|member| member.status))
+    }
+    fn departure(&self, id: u64) -> Result<Option<Departure>, ()> {
+        Ok(self.departures.get(&id).cloned())
+    }
+    fn members_with_tag(&self, key: String, value: String) -> Result<(Vec<ClientMember>, u64), ()> {
+        Ok((self.members.values()
+            .filter(|member| member.meta.get(&key) == Some(&value))
+            .map(|member| self.compose_client_member(member.id))
+            .collect(), self.version))
+    }
 }
 impl StateMachineCtl for Membership {
     raft_sm_complete!();
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use bifrost_hasher::hash_bytes;
+use utils::bincode;
+use super::LogEntry;
+
+// what is compared between members, the payload only by its checksum
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct EntryMeta {
+    pub index: u64,
+    pub term: u64,
+    pub sm_id: u64,
+    pub fn_id: u64,
+    // bytes of the payload
+    pub size: u64,
+    // of the whole entry as the member keeps it, see LogEntry::digest
+    pub crc: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminError {
+    // the server has no auth token and does not serve admin rpcs without one
+    Unauthorized,
+    // the entry is compacted into the snapshot or not in the log yet
+    EntryNotFound,
+}
+
+impl EntryMeta {
+    pub fn of(entry: &LogEntry) -> EntryMeta {
+        EntryMeta {
+            index: entry.id,
+            term: entry.term,
+            sm_id: entry.sm_id,
+            fn_id: entry.fn_id,
+            size: entry.data.len() as u64,
+            crc: entry.digest(),
+        }
+    }
+}
+
+// rolling over the entries in order, members with the same entries have the same digest
+pub fn log_digest<'a, I>(entries: I) -> u64 where I: IntoIterator<Item = &'a EntryMeta> {
+    entries.into_iter().fold(0, |digest, meta| hash_bytes(&bincode::serialize(&(digest, meta))))
+}
+
+// digests of the members for the same range of the log
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaDigests {
+    pub digests: HashMap<u64, u64>,
+    // members that did not answer
+    pub unreachable: Vec<u64>,
+    // members with another digest than most of those answered, sorted by id
+    pub mismatching: Vec<u64>,
+}
+
+impl ReplicaDigests {
+    pub fn new(digests: HashMap<u64, u64>, mut unreachable: Vec<u64>) -> ReplicaDigests {
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for digest in digests.values() {
+            *counts.entry(*digest).or_insert(0) += 1;
+        }
+        // ties go to the smaller digest, the same on every run
+        let common = counts.iter()
+            .max_by(|&(digest_a, count_a), &(digest_b, count_b)| count_a.cmp(count_b).then(digest_b.cmp(digest_a)))
+            .map(|(digest, _)| *digest);
+        let mut mismatching: Vec<u64> = digests.iter()
+            .filter(|&(_, digest)| Some(*digest) != common)
+            .map(|(id, _)| *id)
+            .collect();
+        mismatching.sort();
+        unreachable.sort();
+        ReplicaDigests {
+            digests: digests,
+            unreachable: unreachable,
+            mismatching: mismatching,
+        }
+    }
+    pub fn consistent(&self) -> bool {
+        self.mismatching.is_empty() && self.unreachable.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(id: u64, term: u64, data: Vec<u8>) -> EntryMeta {
+        let mut entry = LogEntry {
+            id: id,
+            term: term,
+            sm_id: 12,
+            fn_id: 3,
+            data: data,
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0
+        };
+        entry.checksum = entry.digest();
+        EntryMeta::of(&entry)
+    }
+
+    #[test]
+    fn digest_of_entries() {
+        let log = vec![entry(1, 1, vec![1]), entry(2, 1, vec![2, 2]), entry(3, 2, vec![])];
+        assert_eq!(log[1].size, 2);
+        assert_eq!(log_digest(&log), log_digest(&log.clone()));
+        assert_ne!(log_digest(&log), log_digest(&log[..2]));
+        // same metadata, another payload
+        let mut diverged = log.clone();
+        diverged[1] = entry(2, 1, vec![2, 3]);
+        assert_ne!(log_digest(&log), log_digest(&diverged));
+        let reordered = vec![log[1], log[0], log[2]];
+        assert_ne!(log_digest(&log), log_digest(&reordered));
+    }
+
+    #[test]
+    fn mismatching_members() {
+        let digests: HashMap<u64, u64> = vec![(1, 10), (2, 10), (3, 11)].into_iter().collect();
+        let replicas = ReplicaDigests::new(digests, vec![5, 4]);
+        assert_eq!(replicas.mismatching, vec![3]);
+        assert_eq!(replicas.unreachable, vec![4, 5]);
+        assert!(!replicas.consistent());
+        let digests: HashMap<u64, u64> = vec![(1, 10), (2, 10)].into_iter().collect();
+        assert!(ReplicaDigests::new(digests, Vec::new()).consistent());
+    }
+}
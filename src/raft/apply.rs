@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, Condvar};
+use threadpool::ThreadPool;
+use super::ClientSession;
+use super::state_machine::master::ExecResult;
+
+struct Applying {
+    // dispatched entries not applied yet
+    in_flight: BTreeSet<u64>,
+    // applied entries with sessions, their responses are recorded by the raft service in collect
+    done: BTreeMap<u64, (ClientSession, ExecResult)>,
+    // entry of each (session, sequence) dispatched and not collected yet
+    sessions: HashMap<(u64, u64), u64>,
+    // the highest applied entry of each state machine
+    sm_applied: HashMap<u64, u64>,
+    lane_of: HashMap<u64, usize>,
+}
+
+// commands of different state machines are applied on their own lanes at the same time.
+// a state machine always goes to the same lane, its commands are applied in the order of the log
+pub struct Applier {
+    lanes: Vec<Mutex<ThreadPool>>,
+    applying: Arc<(Mutex<Applying>, Condvar)>,
+}
+
+impl Applier {
+    pub fn new(lanes: usize) -> Applier {
+        Applier {
+            lanes: (0..lanes).map(|_| Mutex::new(ThreadPool::new(1))).collect(),
+            applying: Arc::new((Mutex::new(Applying {
+                in_flight: BTreeSet::new(),
+                done: BTreeMap::new(),
+                sessions: HashMap::new(),
+                sm_applied: HashMap::new(),
+                lane_of: HashMap::new(),
+            }), Condvar::new())),
+        }
+    }
+    // the response goes to the client waiting for it on the leader
+    pub fn dispatch<F>(&self, id: u64, sm_id: u64, session: Option<ClientSession>, proposal: Option<Sender<ExecResult>>, command: F)
+        where F: FnOnce() -> ExecResult + Send + 'static {
+        let lane = {
+            let mut applying = self.applying.0.lock();
+            applying.in_flight.insert(id);
+            if let Some(ref session) = session {
+                applying.sessions.insert((session.id, session.seq), id);
+            }
+            // lanes are handed out in turns, the first state machines never share one
+            let next_lane = applying.lane_of.len() % self.lanes.len();
+            *applying.lane_of.entry(sm_id).or_insert(next_lane)
+        };
+        let applying = self.applying.clone();
+        self.lanes[lane].lock().execute(move || {
+            let response = command();
+            if let Some(proposal) = proposal {
+                proposal.send(response.clone()).ok();
+            }
+            let &(ref lock, ref applied) = &*applying;
+            let mut applying = lock.lock();
+            applying.in_flight.remove(&id);
+            if let Some(session) = session {
+                applying.done.insert(id, (session, response));
+            }
+            let sm_applied = applying.sm_applied.entry(sm_id).or_insert(0);
+            if *sm_applied < id {
+                *sm_applied = id;
+            }
+            applied.notify_all();
+        });
+    }
+    // every entry up to the id is applied, given entries are dispatched up to the last one
+    pub fn applied(&self, last_dispatched: u64) -> u64 {
+        match self.applying.0.lock().in_flight.iter().next() {
+            Some(first) => first - 1,
+            None => last_dispatched
+        }
+    }
+    pub fn sm_applied(&self, sm_id: u64) -> u64 {
+        self.applying.0.lock().sm_applied.get(&sm_id).cloned().unwrap_or(0)
+    }
+    // the entry of the session dispatched before and not collected yet
+    pub fn in_flight_session(&self, session: &ClientSession) -> Option<u64> {
+        self.applying.0.lock().sessions.get(&(session.id, session.seq)).cloned()
+    }
+    // returns once entries up to the id are applied
+    pub fn wait(&self, id: u64) {
+        let &(ref lock, ref applied) = &*self.applying;
+        let mut applying = lock.lock();
+        while applying.in_flight.iter().next().map(|first| *first <= id).unwrap_or(false) {
+            applied.wait(&mut applying);
+        }
+    }
+    // sessions and responses of applied entries, in the order of the log
+    pub fn collect(&self) -> Vec<(ClientSession, ExecResult)> {
+        let mut applying = self.applying.0.lock();
+        let done = ::std::mem::replace(&mut applying.done, BTreeMap::new());
+        done.into_iter().map(|(id, (session, response))| {
+            applying.sessions.remove(&(session.id, session.seq));
+            debug!("collected response of entry {} in session {}", id, session.id);
+            (session, response)
+        }).collect()
+    }
+}
+
+// the last applied entry of the server, clients wait on it to read what others wrote
+pub struct AppliedIndex {
+    index: Mutex<u64>,
+    moved: Condvar,
+}
+
+impl AppliedIndex {
+    pub fn new() -> AppliedIndex {
+        AppliedIndex {
+            index: Mutex::new(0),
+            moved: Condvar::new(),
+        }
+    }
+    pub fn get(&self) -> u64 {
+        *self.index.lock()
+    }
+    pub fn set(&self, id: u64) {
+        let mut index = self.index.lock();
+        if *index != id {
+            *index = id;
+            self.moved.notify_all();
+        }
+    }
+    // true once entries up to the id are applied, false on timeout
+    pub fn wait(&self, id: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut index = self.index.lock();
+        while *index < id {
+            if self.moved.wait_until(&mut index, deadline).timed_out() {
+                return *index >= id;
+            }
+        }
+        true
+    }
+}
@@ -1,20 +1,32 @@
 use raft::{
-    SyncServiceClient, RaftMsg, LogEntry, ClientQryResponse, 
-    ClientCmdResponse};
+    SyncServiceClient, RaftMsg, LogEntry, ClientQryResponse, ClientReadResponse,
+    ClientCmdResponse, TransferError, PromoteError, Consistency, ClientSession, NodeStatus};
 use raft::state_machine::OpType;
-use raft::state_machine::master::{ExecResult, ExecError};
+use raft::admin::ReplicaDigests;
+use raft::metrics::RaftMetricsSnapshot;
+use raft::state_machine::master::{ExecResult, ExecError, BATCH_SM_ID};
+use raft::state_machine::callback::{SubKey, SubFilter};
 use raft::state_machine::callback::client::SubscriptionService;
-use raft::state_machine::configs::CONFIG_SM_ID;
-use raft::state_machine::configs::commands::{subscribe as conf_subscribe};
-use std::collections::{HashMap, BTreeMap, HashSet};
-use std::iter::FromIterator;
-use parking_lot::{RwLock, RwLockWriteGuard};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::cmp::max;
+use raft::state_machine::configs::{CONFIG_SM_ID, MemberRole};
+use raft::state_machine::configs::commands::{
+    subscribe as conf_subscribe, subscribe_filtered as conf_subscribe_filtered,
+    subscribed as conf_subscribed, unsubscribe as conf_unsubscribe, new_member_, del_member_};
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use std::ops::Range;
+use parking_lot::{Mutex, RwLock, Condvar};
+use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::cmp::{min, max};
+use std::thread;
+use std::fmt;
+use std::time::{Duration, Instant};
+use futures::{future, Future};
+use futures::sync::oneshot;
 use bifrost_hasher::{hash_str, hash_bytes};
 use rand;
 use rpc;
+use utils::time::{get_time, duration_to_ms};
+use utils::bincode;
 use backtrace::Backtrace;
 
 const ORDERING: Ordering = Ordering::Relaxed;
@@ -30,10 +42,81 @@ pub enum ClientError {
     ServerUnreachable,
 }
 
+// no member claimed leadership within the timeout
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoLeader;
+
 #[derive(Debug)]
 pub enum SubscriptionError {
     RemoteError,
     SubServiceNotSet,
+    // the subscription was cancelled before, or not made through this client
+    NotSubscribed,
+    // the raft client of the handle is gone
+    ClientDropped,
+    Exec(ExecError),
+}
+
+// returned for subscriptions made through SMClient stubs, it does not keep the raft client alive
+pub struct SubscriptionHandle {
+    sub_id: u64,
+    sm_id: u64,
+    client: Weak<RaftClient>,
+    cancel_on_drop: bool,
+    cancelled: AtomicBool,
+}
+
+impl SubscriptionHandle {
+    pub fn new(client: &Arc<RaftClient>, sm_id: u64, sub_id: u64) -> SubscriptionHandle {
+        SubscriptionHandle {
+            sub_id: sub_id,
+            sm_id: sm_id,
+            client: Arc::downgrade(client),
+            cancel_on_drop: client.options.cancel_dropped_subscriptions,
+            cancelled: AtomicBool::new(false),
+        }
+    }
+    pub fn id(&self) -> u64 {self.sub_id}
+    pub fn sm_id(&self) -> u64 {self.sm_id}
+    // the closure is dropped before the servers are asked, it is not called once this returns
+    pub fn cancel(&self) -> Result<(), SubscriptionError> {
+        match self.client.upgrade() {
+            Some(client) => {
+                self.cancelled.store(true, ORDERING);
+                client.unsubscribe(self.sub_id)
+            },
+            None => Err(SubscriptionError::ClientDropped)
+        }
+    }
+    // called when the leader may have dropped events of the subscription before the closure got
+    // them, state the closure keeps should be read again
+    pub fn on_resync<F>(&self, resync: F) -> Result<(), SubscriptionError>
+        where F: Fn() + Send + Sync + 'static
+    {
+        match CALLBACK.read().clone() {
+            Some(callback) => {
+                callback.on_resync(self.sub_id, Arc::new(resync));
+                Ok(())
+            },
+            None => Err(SubscriptionError::SubServiceNotSet)
+        }
+    }
+}
+
+impl fmt::Debug for SubscriptionHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SubscriptionHandle {{ sub_id: {}, sm_id: {} }}", self.sub_id, self.sm_id)
+    }
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if self.cancel_on_drop && !self.cancelled.load(ORDERING) {
+            if let Err(e) = self.cancel() {
+                debug!("CLIENT: cannot cancel dropped subscription {}, {:?}", self.sub_id, e);
+            }
+        }
+    }
 }
 
 struct QryMeta {
@@ -45,17 +128,166 @@ struct Members {
     id_map: HashMap<u64, String>,
 }
 
+type MembersListener = Box<Fn(&Vec<(u64, String)>) + Send + Sync>;
+type ResubscribedListener = Box<Fn(&Vec<u64>) + Send + Sync>;
+
+#[derive(Default)]
+struct RefreshState {
+    // a server redirected the client, members are refreshed before the interval is up
+    wanted: bool,
+    // the client is dropped
+    closed: bool,
+}
+
+// transient failures, like finding no leader in an election, are tried again after a backoff
+// growing from base to max, until max_retries or the deadline since the first attempt
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    pub deadline: Duration,
+}
+
+impl RetryPolicy {
+    pub fn Default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 10,
+            base_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(500),
+            deadline: Duration::from_secs(5),
+        }
+    }
+    // half of it is random, clients failing at the same time do not retry together.
+    // retries count from 1
+    fn backoff_ms(&self, retries: u32) -> u64 {
+        let base = duration_to_ms(self.base_backoff);
+        let backoff = min(duration_to_ms(self.max_backoff), base.saturating_mul(1 << min(retries - 1, 20)));
+        backoff / 2 + (rand::random::<f64>() * (backoff / 2) as f64) as u64
+    }
+}
+
+// commands wait up to max_delay for others to go in the same log entry, max_batch of them at most
+#[derive(Clone, Copy, Debug)]
+pub struct Batching {
+    pub max_delay: Duration,
+    pub max_batch: usize,
+}
+
+impl Batching {
+    pub fn Default() -> Batching {
+        Batching {
+            max_delay: Duration::from_millis(2),
+            max_batch: 128,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClientOptions {
+    pub retry: RetryPolicy,
+    // commands go in a session and are applied once, so they are retried even when it is unknown
+    // whether they took effect. without it only commands executed as idempotent are
+    pub sessions: bool,
+    // members are asked for the members that often on a thread of the client, and on redirects.
+    // None to refresh only on redirects and when no leader is known. the leader is asked for the
+    // subscriptions of the client at the same time
+    pub members_refresh: Option<Duration>,
+    // dropping a SubscriptionHandle cancels its subscription, otherwise it lasts as long as the client
+    pub cancel_dropped_subscriptions: bool,
+    // commands from any thread of the client are batched, queries and membership changes are not.
+    // commands go one by one again once some member does not apply batches
+    pub batching: Option<Batching>,
+}
+
+impl ClientOptions {
+    pub fn Default() -> ClientOptions {
+        ClientOptions {
+            retry: RetryPolicy::Default(),
+            sessions: true,
+            members_refresh: Some(Duration::from_secs(5)),
+            cancel_dropped_subscriptions: false,
+            batching: None,
+        }
+    }
+}
+
+enum Attempt {
+    Done(Result<ExecResult, ExecError>),
+    // sent to the leader the server pointed to, tried again without backoff
+    Redirected,
+    // the request did not take effect
+    Rejected,
+    // no member claims leadership, the request was not sent
+    NoLeader,
+    // the request may have taken effect
+    Failed(ExecError),
+}
+
+// servers apply commands of the session once for each sequence, retries get the first response.
+// the session is opened by its first command
+struct Session {
+    id: u64,
+    last_seq: u64,
+    // sequences of commands not answered yet
+    pending: BTreeSet<u64>,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session {
+            id: rand::random::<u64>(),
+            last_seq: 0,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+// the response of a batched command and the index of the batch
+type BatchedResponse = (Result<ExecResult, ExecError>, u64);
+
+struct Batched {
+    sm_id: u64,
+    fn_id: u64,
+    data: Vec<u8>,
+    response: oneshot::Sender<BatchedResponse>,
+}
+
+#[derive(Default)]
+struct BatchQueue {
+    commands: Vec<Batched>,
+    // the client is dropped, commands left are canceled
+    closed: bool,
+}
+
 pub struct RaftClient {
     qry_meta: QryMeta,
     members: RwLock<Members>,
     leader_id: AtomicU64,
     last_log_id: AtomicU64,
     last_log_term: AtomicU64,
-    service_id: u64
+    consistency: RwLock<Consistency>,
+    session: Mutex<Session>,
+    options: ClientOptions,
+    service_id: u64,
+    // asked when none of the members answer
+    seeds: Vec<String>,
+    // one refresh at a time, commands use the members known meanwhile
+    refreshing: Mutex<()>,
+    refresh: Arc<(Mutex<RefreshState>, Condvar)>,
+    listeners: RwLock<Vec<MembersListener>>,
+    batches: Arc<(Mutex<BatchQueue>, Condvar)>,
+    batches_supported: AtomicBool,
+    // subscriptions made through the client by id, registered again when the leader lost them
+    subscriptions: Mutex<BTreeMap<u64, (SubKey, Option<SubFilter>)>>,
+    resubscribed: RwLock<Vec<ResubscribedListener>>,
 }
 
 impl RaftClient {
     pub fn new(servers: &Vec<String>, service_id: u64) -> Result<Arc<RaftClient>, ClientError> {
+        RaftClient::with_options(servers, service_id, ClientOptions::Default())
+    }
+    pub fn with_options(servers: &Vec<String>, service_id: u64, options: ClientOptions) -> Result<Arc<RaftClient>, ClientError> {
         let client = RaftClient {
             qry_meta: QryMeta {
                 pos: AtomicU64::new(rand::random::<u64>())
@@ -67,19 +299,26 @@ impl RaftClient {
             leader_id: AtomicU64::new(0),
             last_log_id: AtomicU64::new(0),
             last_log_term: AtomicU64::new(0),
+            consistency: RwLock::new(Consistency::Linearizable),
+            session: Mutex::new(Session::new()),
+            options: options,
             service_id: service_id,
+            seeds: servers.clone(),
+            refreshing: Mutex::new(()),
+            refresh: Arc::new((Mutex::new(RefreshState::default()), Condvar::new())),
+            listeners: RwLock::new(Vec::new()),
+            batches: Arc::new((Mutex::new(BatchQueue::default()), Condvar::new())),
+            batches_supported: AtomicBool::new(true),
+            subscriptions: Mutex::new(BTreeMap::new()),
+            resubscribed: RwLock::new(Vec::new()),
         };
-        let init = {
-            let mut members = client.members.write();
-            client.update_info(
-                &mut members,
-                &HashSet::from_iter(servers.iter().cloned())
-            )
-        };
-        match init {
-            Ok(_) => Ok(Arc::new(client)),
-            Err(e) => Err(e)
+        client.update_info(servers)?;
+        let client = Arc::new(client);
+        start_refreshing(&client);
+        if let Some(batching) = options.batching {
+            start_batching(&client, batching);
         }
+        Ok(client)
     }
     pub fn prepare_subscription(server: &Arc<rpc::Server>) -> Option<()> {
         let mut callback = CALLBACK.write();
@@ -92,19 +331,20 @@ impl RaftClient {
         }
     }
 
-   fn update_info(&self, members: &mut RwLockWriteGuard<Members>, servers: &HashSet<String>) -> Result<(), ClientError> {
+    // the servers are asked in order for the members until one knows the leader.
+    // no lock is held while waiting for them, commands go on with the members known before
+    fn update_info(&self, servers: &Vec<String>) -> Result<(), ClientError> {
+        let _refreshing = self.refreshing.lock();
+        let known = self.members.read().clients.clone();
+        let mut connected = HashMap::new();
         let mut cluster_info = None;
         for server_addr in servers {
             let id = hash_str(&server_addr);
-            if !members.clients.contains_key(&id) {
-                match rpc::DEFAULT_CLIENT_POOL.get(&server_addr) {
-                    Ok(client) => {
-                        members.clients.insert(id, SyncServiceClient::new(self.service_id, &client));
-                    },
-                    Err(_) => {continue;}
-                }
-            }
-            let client = members.clients.get(&id).unwrap();
+            let client = match known.get(&id).cloned().or_else(|| self.connect(server_addr)) {
+                Some(client) => client,
+                None => continue
+            };
+            connected.insert(id, client.clone());
             if let Ok(Ok(info)) = client.c_server_cluster_info() {
                 if info.leader_id != 0 {
                     cluster_info = Some(info);
@@ -112,55 +352,272 @@ impl RaftClient {
                 }
             }
         }
-        match cluster_info {
-            Some(info) => {
-                let remote_members = info.members;
-                let mut remote_ids = HashSet::with_capacity(remote_members.len());
-                members.id_map.clear();
-                for (id, addr) in remote_members {
-                    members.id_map.insert(id, addr);
-                    remote_ids.insert(id);
-                }
-                let mut connected_ids = HashSet::with_capacity(members.clients.len());
-                for id in members.clients.keys() {connected_ids.insert(*id);}
-                let ids_to_remove = connected_ids.difference(&remote_ids);
-                for id in ids_to_remove {members.clients.remove(id);}
-                for id in remote_ids.difference(&connected_ids) {
-                    let addr = members.id_map.get(id).unwrap().clone();
-                    if !members.clients.contains_key(id) {
-                        if let Ok(client) = rpc::DEFAULT_CLIENT_POOL.get(&addr) {
-                            members.clients.insert(*id, SyncServiceClient::new(self.service_id, &client));
-                        }
-                    }
-                }
-                self.leader_id.store(info.leader_id, ORDERING);
-                Ok(())
-            },
-            None => Err(ClientError::ServerUnreachable),
+        let info = match cluster_info {
+            Some(info) => info,
+            None => return Err(ClientError::ServerUnreachable)
+        };
+        let mut clients = BTreeMap::new();
+        let mut id_map = HashMap::new();
+        for (id, addr) in info.members {
+            let client = known.get(&id).cloned()
+                .or_else(|| connected.get(&id).cloned())
+                .or_else(|| self.connect(&addr));
+            if let Some(client) = client {
+                clients.insert(id, client);
+            }
+            id_map.insert(id, addr);
+        }
+        let changed = {
+            let mut members = self.members.write();
+            let changed = members.id_map != id_map;
+            members.clients = clients;
+            members.id_map = id_map;
+            changed
+        };
+        self.leader_id.store(info.leader_id, ORDERING);
+        if changed {
+            let members = self.members();
+            for listener in self.listeners.read().iter() {
+                listener(&members);
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self, address: &String) -> Option<Client> {
+        rpc::DEFAULT_CLIENT_POOL.get(address).ok()
+            .map(|client| SyncServiceClient::new(self.service_id, &client))
+    }
+
+    // asks the members known to the client, then the servers it was created with
+    pub fn refresh_members(&self) -> Result<(), ClientError> {
+        let mut servers: Vec<String> = self.members().into_iter().map(|(_, address)| address).collect();
+        for seed in &self.seeds {
+            if !servers.contains(seed) {
+                servers.push(seed.clone());
+            }
         }
+        self.update_info(&servers)
+    }
+
+    // ids and addresses of the members by id, as of the last refresh
+    pub fn members(&self) -> Vec<(u64, String)> {
+        let mut members: Vec<(u64, String)> = self.members.read().id_map.iter()
+            .map(|(id, address)| (*id, address.clone()))
+            .collect();
+        members.sort();
+        members
+    }
+
+    // called with the new members on the thread that found them, members may have changed again since
+    pub fn on_members_changed<F>(&self, listener: F) where F: Fn(&Vec<(u64, String)>) + Send + Sync + 'static {
+        self.listeners.write().push(Box::new(listener));
+    }
+
+    // the refreshing thread does it without holding up the caller
+    fn want_refresh(&self) {
+        let &(ref state, ref woken) = &*self.refresh;
+        state.lock().wanted = true;
+        woken.notify_one();
     }
 
     pub fn execute<R>(&self, sm_id: u64, msg: &RaftMsg<R>) -> Result<R, ExecError> {
+        self.execute_(sm_id, msg, false).map(|(result, _)| result)
+    }
+
+    // commands are tried again even when they may have been applied, for ones applying twice is fine
+    pub fn execute_idempotent<R>(&self, sm_id: u64, msg: &RaftMsg<R>) -> Result<R, ExecError> {
+        self.execute_(sm_id, msg, true).map(|(result, _)| result)
+    }
+
+    // with the index of the command in the log, other clients pass it to wait_applied
+    // before reading what the command did. 0 for queries
+    pub fn execute_with_index<R>(&self, sm_id: u64, msg: &RaftMsg<R>) -> Result<(R, u64), ExecError> {
+        self.execute_(sm_id, msg, false)
+    }
+
+    fn execute_<R>(&self, sm_id: u64, msg: &RaftMsg<R>, idempotent: bool) -> Result<(R, u64), ExecError> {
         let (fn_id, op, req_data) = msg.encode();
+        let mut index = 0;
         let response = match op {
             OpType::QUERY => {
-                self.query(sm_id, fn_id, &req_data, 0)
+                match *self.consistency.read() {
+                    Consistency::Stale => self.retry(true, || self.query(sm_id, fn_id, &req_data)),
+                    Consistency::BoundedStale(max_lag) => self.bounded_query(None, sm_id, fn_id, &req_data, max_lag),
+                    consistency => self.retry(true, || self.leader_query(sm_id, fn_id, &req_data, consistency))
+                }
             },
-            OpType::COMMAND | OpType::SUBSCRIBE => {
-                self.command(sm_id, fn_id, &req_data, 0)
+            OpType::COMMAND if self.batching(sm_id) => {
+                match self.enqueue(sm_id, fn_id, req_data.clone()).wait() {
+                    Ok((response, batch_index)) => {
+                        index = batch_index;
+                        response
+                    },
+                    Err(_) => Err(ExecError::Unknown) // the client is dropped
+                }
             },
+            OpType::COMMAND | OpType::SUBSCRIBE => self.single_command(sm_id, fn_id, &req_data, idempotent, &mut index),
+        };
+        decode_response(sm_id, msg, response).map(|result| (result, index))
+    }
+
+    // resolved once the command is applied when batching, the command goes in the next batch.
+    // executed before returning otherwise
+    pub fn execute_async<M, R>(&self, sm_id: u64, msg: M) -> Box<Future<Item = R, Error = ExecError>>
+        where M: RaftMsg<R> + 'static, R: 'static {
+        let queued = {
+            let (fn_id, op, req_data) = msg.encode();
+            match op {
+                OpType::COMMAND if self.batching(sm_id) => Some(self.enqueue(sm_id, fn_id, req_data)),
+                _ => None
+            }
+        };
+        match queued {
+            Some(queued) => Box::new(queued.then(move |queued| {
+                let response = queued.map(|(response, _)| response).unwrap_or(Err(ExecError::Unknown));
+                decode_response(sm_id, &msg, response)
+            })),
+            None => Box::new(future::result(self.execute(sm_id, &msg)))
+        }
+    }
+
+    // the command in a log entry of its own
+    fn single_command(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>, idempotent: bool, index: &mut u64) -> Result<ExecResult, ExecError> {
+        let session = if self.options.sessions {Some(self.begin_command())} else {None};
+        let retry_failed = idempotent || session.is_some();
+        let response = self.retry(retry_failed, || self.command(sm_id, fn_id, data, session, &mut *index));
+        if let Some(ref session) = session {
+            self.end_command(session, &response);
+        }
+        response
+    }
+
+    fn batching(&self, sm_id: u64) -> bool {
+        self.options.batching.is_some() && sm_id != CONFIG_SM_ID && self.batches_supported.load(ORDERING)
+    }
+
+    fn enqueue(&self, sm_id: u64, fn_id: u64, data: Vec<u8>) -> oneshot::Receiver<BatchedResponse> {
+        let (tx, rx) = oneshot::channel();
+        let &(ref queue, ref added) = &*self.batches;
+        queue.lock().commands.push(Batched {
+            sm_id: sm_id,
+            fn_id: fn_id,
+            data: data,
+            response: tx,
+        });
+        added.notify_one();
+        rx
+    }
+
+    // the batch is one command, retried as a whole. each command gets its own response in it
+    fn send_batch(&self, batch: Vec<Batched>) {
+        if batch.len() == 1 || !self.batches_supported.load(ORDERING) {
+            return self.send_each(batch);
+        }
+        let data = {
+            let commands: Vec<(u64, u64, &Vec<u8>)> = batch.iter()
+                .map(|command| (command.sm_id, command.fn_id, &command.data))
+                .collect();
+            bincode::serialize(&commands)
         };
-        match response {
-            Ok(data) => {
-                match data {
-                    Ok(data) => Ok(msg.decode_return(&data)),
-                    Err(e) => Err(e)
+        let mut index = 0;
+        match self.single_command(BATCH_SM_ID, 0, &data, false, &mut index) {
+            Ok(Ok(data)) => {
+                let responses: Option<Vec<ExecResult>> = bincode::try_deserialize(&data);
+                let responses = match responses {
+                    Some(responses) => if responses.len() == batch.len() {Some(responses)} else {None},
+                    None => None
+                };
+                match responses {
+                    Some(responses) => for (command, response) in batch.into_iter().zip(responses) {
+                        command.response.send((Ok(response), index)).ok();
+                    },
+                    None => {
+                        warn!("CLIENT: cannot decode responses of batch at {}", index);
+                        for command in batch {
+                            command.response.send((Err(ExecError::DecodeError), index)).ok();
+                        }
+                    }
                 }
             },
-            Err(e) => Err(e)
+            Err(ExecError::BatchUnsupported) => {
+                warn!("CLIENT: some member does not apply batches, commands are sent one by one");
+                self.batches_supported.store(false, ORDERING);
+                self.send_each(batch);
+            },
+            // each of them may still fit in an entry
+            Err(ExecError::EntryTooLarge { .. }) => self.send_each(batch),
+            response => for command in batch {
+                command.response.send((response.clone(), index)).ok();
+            }
+        }
+    }
+
+    fn send_each(&self, batch: Vec<Batched>) {
+        for command in batch {
+            let mut index = 0;
+            let response = self.single_command(command.sm_id, command.fn_id, &command.data, false, &mut index);
+            command.response.send((response, index)).ok();
+        }
+    }
+
+    // waits for every member to apply entries up to the index, false when some did not before the timeout.
+    // stale and bounded queries after it see the entry on whichever member they go to
+    pub fn wait_applied(&self, index: u64, timeout: Duration) -> bool {
+        let deadline = get_time() + duration_to_ms(timeout) as i64;
+        let clients: Vec<Client> = self.members.read().clients.values().cloned().collect();
+        clients.iter().all(|client| wait_member_applied(client, index, deadline))
+    }
+
+    // digests of the range from every member. the range should be one all members keep in their
+    // logs, members that compacted some of it differ as well
+    pub fn verify_replicas(&self, range: Range<u64>) -> ReplicaDigests {
+        let clients: Vec<(u64, Client)> = self.members.read().clients.iter()
+            .map(|(id, client)| (*id, client.clone()))
+            .collect();
+        let mut digests = HashMap::new();
+        let mut unreachable = Vec::new();
+        for (id, client) in clients {
+            match client.c_log_digest(&range.start, &range.end) {
+                Ok(Ok(Ok(digest))) => {digests.insert(id, digest);},
+                // refused admin rpcs as well
+                _ => unreachable.push(id)
+            }
+        }
+        ReplicaDigests::new(digests, unreachable)
+    }
+
+    // metrics of every member known to the client, those did not answer are left out
+    pub fn scrape_metrics(&self) -> HashMap<u64, RaftMetricsSnapshot> {
+        let clients: Vec<(u64, Client)> = self.members.read().clients.iter()
+            .map(|(id, client)| (*id, client.clone()))
+            .collect();
+        let mut metrics = HashMap::new();
+        for (id, client) in clients {
+            if let Ok(Ok(info)) = client.c_server_cluster_info() {
+                metrics.insert(id, info.metrics);
+            }
+        }
+        metrics
+    }
+
+    // for reads of query_on from the member
+    pub fn wait_applied_on(&self, node_id: u64, index: u64, timeout: Duration) -> bool {
+        let deadline = get_time() + duration_to_ms(timeout) as i64;
+        let client = self.members.read().clients.get(&node_id).cloned();
+        match client {
+            Some(client) => wait_member_applied(&client, index, deadline),
+            None => false
         }
     }
 
+    // reads from the member when it was up to date with the leader within max_lag, from the leader otherwise
+    pub fn query_on<R>(&self, node_id: u64, sm_id: u64, msg: &RaftMsg<R>, max_lag: Duration) -> Result<R, ExecError> {
+        let (fn_id, _, req_data) = msg.encode();
+        let response = self.bounded_query(Some(node_id), sm_id, fn_id, &req_data, max_lag);
+        decode_response(sm_id, msg, response)
+    }
+
     pub fn can_callback() -> bool {
         let callback = CALLBACK.read();
         callback.is_some()
@@ -169,6 +626,16 @@ impl RaftClient {
     pub fn subscribe
     <M, R, F>
     (&self, sm_id: u64, msg: M, f: F) -> Result<Result<u64, SubscriptionError>, ExecError>
+    where M: RaftMsg<R> + 'static,
+          F: Fn(R) + 'static + Send + Sync
+    {
+        self.subscribe_filtered(sm_id, msg, None, f)
+    }
+
+    // with a filter the leader only sends events whose key passes it, see SMCallback::notify_keyed
+    pub fn subscribe_filtered
+    <M, R, F>
+    (&self, sm_id: u64, msg: M, filter: Option<SubFilter>, f: F) -> Result<Result<u64, SubscriptionError>, ExecError>
     where M: RaftMsg<R> + 'static,
           F: Fn(R) + 'static + Send + Sync
     {
@@ -184,135 +651,461 @@ impl RaftClient {
             (fn_id, hash_bytes(pattern_data.as_slice()))
         };
         let wrapper_fn = move |data: Vec<u8>| {
-            f(msg.decode_return(&data))
+            match msg.decode_return(&data) {
+                Ok(res) => f(res),
+                Err(e) => warn!("Cannot decode subscription message, {:?}", e)
+            }
         };
         let key = (raft_sid, sm_id, fn_id, pattern_id);
-        let mut subs_map = callback.subs.write();
-        let mut subs_lst = subs_map.entry(key).or_insert_with(|| Vec::new());
-        subs_lst.push(Box::new(wrapper_fn));
-        let cluster_subs = self.execute(
-            CONFIG_SM_ID,
-            &conf_subscribe::new(&key, &callback.server_address, &callback.session_id)
-        );
+        let sub_id = rand::random::<u64>();
+        callback.subs.write().entry(key).or_insert_with(|| Vec::new()).push((sub_id, Arc::new(wrapper_fn)));
+        let cluster_subs = self.register_subscription(&callback, &key, &filter, &sub_id);
         match cluster_subs {
-            Ok(sub_result) => match sub_result {
-                Ok(sub_id) => Ok(Ok(sub_id)),
-                Err(_) => Ok(Err(SubscriptionError::RemoteError))
+            Ok(Ok(sub_id)) => {
+                self.subscriptions.lock().insert(sub_id, (key, filter));
+                Ok(Ok(sub_id))
+            },
+            Ok(Err(_)) => {
+                callback.remove(&key, sub_id);
+                Ok(Err(SubscriptionError::RemoteError))
             },
-            Err(e) => Err(e)
+            Err(e) => {
+                callback.remove(&key, sub_id);
+                Err(e)
+            }
         }
     }
 
-    fn query(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>, depth: usize) -> Result<ExecResult, ExecError> {
+    // the closure is dropped at once. the servers may still notify when they did not take the
+    // cancel, or drop it when the process is unreachable for the subscriber ttl
+    pub fn unsubscribe(&self, sub_id: u64) -> Result<(), SubscriptionError> {
+        let key = match self.subscriptions.lock().remove(&sub_id) {
+            Some((key, _)) => key,
+            None => return Err(SubscriptionError::NotSubscribed)
+        };
+        if let Some(callback) = CALLBACK.read().clone() {
+            callback.remove(&key, sub_id);
+        }
+        match self.execute(CONFIG_SM_ID, &conf_unsubscribe::new(&sub_id)) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(())) => Err(SubscriptionError::RemoteError),
+            Err(e) => Err(SubscriptionError::Exec(e))
+        }
+    }
+
+    // called with the ids of subscriptions registered again, notifications sent meanwhile are lost.
+    // runs on the thread refreshing members
+    pub fn on_resubscribed<F>(&self, listener: F) where F: Fn(&Vec<u64>) + Send + Sync + 'static {
+        self.resubscribed.write().push(Box::new(listener));
+    }
+
+    // the leader is asked for the subscriptions it has of this process, a new leader may have
+    // installed a snapshot without them. those missing are registered again with the same ids
+    fn check_subscriptions(&self) {
+        let active = self.subscriptions.lock().clone();
+        if active.is_empty() {return;}
+        let callback = match CALLBACK.read().clone() {
+            Some(callback) => callback,
+            None => return
+        };
+        let registered = match self.execute(
+            CONFIG_SM_ID, &conf_subscribed::new(&callback.server_address, &callback.session_id)
+        ) {
+            Ok(Ok(registered)) => registered,
+            _ => return // asked again on the next refresh
+        };
+        let resubscribed: Vec<u64> = active.iter()
+            .filter(|&(id, _)| !registered.contains(id))
+            .filter(|&(id, &(ref key, ref filter))| {
+                let subscribed = self.register_subscription(&callback, key, filter, id);
+                match subscribed {
+                    Ok(Ok(_)) => true,
+                    _ => {
+                        debug!("CLIENT: cannot subscribe {} again, {:?}", id, subscribed);
+                        false
+                    }
+                }
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        if resubscribed.is_empty() {return;}
+        warn!("CLIENT: subscriptions {:?} registered again", resubscribed);
+        for listener in self.resubscribed.read().iter() {
+            listener(&resubscribed);
+        }
+    }
+
+    fn register_subscription(&self, callback: &SubscriptionService, key: &SubKey,
+                             filter: &Option<SubFilter>, sub_id: &u64) -> Result<Result<u64, ()>, ExecError> {
+        let (address, session_id) = (&callback.server_address, &callback.session_id);
+        match *filter {
+            Some(ref filter) => self.execute(
+                CONFIG_SM_ID, &conf_subscribe_filtered::new(key, address, session_id, sub_id, filter)
+            ),
+            None => self.execute(CONFIG_SM_ID, &conf_subscribe::new(key, address, session_id, sub_id))
+        }
+    }
+
+    // tries until the attempt is done, backing off between transient failures
+    fn retry<F>(&self, retry_failed: bool, mut attempt: F) -> Result<ExecResult, ExecError>
+        where F: FnMut() -> Attempt {
+        let policy = self.options.retry;
+        let deadline = get_time() + duration_to_ms(policy.deadline) as i64;
+        let mut attempts = 0;
+        loop {
+            if rpc::context::current().is_expired() {
+                return Err(ExecError::DeadlineExceeded)
+            }
+            attempts += 1;
+            let mut no_leader = false;
+            let backoff = match attempt() {
+                Attempt::Done(result) => return result,
+                Attempt::Redirected => 0,
+                Attempt::Rejected => policy.backoff_ms(attempts),
+                Attempt::NoLeader => {
+                    no_leader = true;
+                    policy.backoff_ms(attempts)
+                },
+                Attempt::Failed(e) => {
+                    if !retry_failed {return Err(e);}
+                    policy.backoff_ms(attempts)
+                }
+            };
+            // giving up after the last attempt found no leader tells it apart from faults
+            if no_leader && (attempts > policy.max_retries || get_time() + backoff as i64 > deadline) {
+                return Err(ExecError::NoLeader)
+            }
+            if attempts > policy.max_retries {
+                return Err(ExecError::TooManyRetry { attempts: attempts })
+            }
+            if get_time() + backoff as i64 > deadline {
+                return Err(ExecError::DeadlineExceeded)
+            }
+            if backoff > 0 {
+                thread::sleep(Duration::from_millis(backoff));
+            }
+        }
+    }
+
+    fn query(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>) -> Attempt {
         let pos = self.qry_meta.pos.fetch_add(1, ORDERING);
-        let mut num_members = 0;
         let res = {
             let members = self.members.read();
             let client = {
                 let members_count = members.clients.len();
                 if members_count < 1 {
-                    return Err(ExecError::ServersUnreachable)
+                    return Attempt::Done(Err(ExecError::ServersUnreachable))
                 } else {
                     members.clients.values().nth(pos as usize % members_count).unwrap()
                 }
             };
-            num_members = members.clients.len();
-            client.c_query(&self.gen_log_entry(sm_id, fn_id, data))
+            client.c_query(&self.gen_log_entry(sm_id, fn_id, data, None))
         };
         match res {
             Ok(Ok(res)) => {
                 match res {
-                    ClientQryResponse::LeftBehind => {
-                        if depth >= num_members {
-                            Err(ExecError::TooManyRetry)
-                        } else {
-                            self.query(sm_id, fn_id, data, depth + 1)
-                        }
-                    },
+                    ClientQryResponse::LeftBehind => Attempt::Rejected, // the next member may be up to date
                     ClientQryResponse::Success{
                         data, last_log_term, last_log_id
                     } => {
                         swap_when_greater(&self.last_log_id, last_log_id);
                         swap_when_greater(&self.last_log_term, last_log_term);
-                        Ok(data)
+                        Attempt::Done(Ok(data))
+                    },
+                }
+            },
+            _ => Attempt::Failed(ExecError::Unknown)
+        }
+    }
+
+    // served by the leader once it knows it is still the leader, sees every command committed before
+    fn leader_query(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>, consistency: Consistency) -> Attempt {
+        match self.current_leader_client() {
+            Some((leader_id, client)) => {
+                match client.c_leader_query(&self.gen_log_entry(sm_id, fn_id, data, None), &consistency) {
+                    Ok(Ok(ClientReadResponse::Success {
+                        data, last_log_term, last_log_id
+                    })) => {
+                        swap_when_greater(&self.last_log_id, last_log_id);
+                        swap_when_greater(&self.last_log_term, last_log_term);
+                        Attempt::Done(Ok(data))
+                    },
+                    Ok(Ok(ClientReadResponse::NotLeader { leader_hint })) => self.redirect(leader_hint),
+                    Ok(Ok(ClientReadResponse::NotConfirmed)) => {
+                        debug!("CLIENT: leadership not confirmed - {}", leader_id);
+                        self.switch_leader();
+                        Attempt::Rejected
+                    },
+                    Ok(Ok(ClientReadResponse::TooStale { .. })) => Attempt::Rejected, // only for bounded queries
+                    Err(e) => {
+                        debug!("CLIENT: E1 - {} - {:?}", leader_id, e);
+                        self.invalidate_leader(leader_id);
+                        Attempt::Failed(ExecError::Unknown)
                     },
+                    Ok(Err(e)) => {
+                        debug!("CLIENT: E2 - {} - {:?}", leader_id, e);
+                        self.switch_leader();
+                        Attempt::Failed(ExecError::Unknown)
+                    }
                 }
             },
-            _ => Err(ExecError::Unknown)
+            None => Attempt::NoLeader // members will be updated when looking for the leader again
         }
     }
 
-    fn command(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>, depth: usize) -> Result<ExecResult, ExecError> {
-        enum FailureAction {
-            SwitchLeader,
-            NotCommitted,
-            UpdateInfo,
-            NotLeader,
-            Retry,
+    // any member when node_id is none
+    fn bounded_query(&self, node_id: Option<u64>, sm_id: u64, fn_id: u64, data: &Vec<u8>, max_lag: Duration) -> Result<ExecResult, ExecError> {
+        let client = {
+            let members = self.members.read();
+            match node_id {
+                Some(id) => members.clients.get(&id).cloned(),
+                None => {
+                    let members_count = members.clients.len();
+                    if members_count < 1 {
+                        None
+                    } else {
+                        let pos = self.qry_meta.pos.fetch_add(1, ORDERING);
+                        members.clients.values().nth(pos as usize % members_count).cloned()
+                    }
+                }
+            }
+        };
+        let max_lag_ms = max_lag.as_secs() * 1000 + (max_lag.subsec_nanos() / 1_000_000) as u64;
+        if let Some(client) = client {
+            match client.c_bounded_query(&self.gen_log_entry(sm_id, fn_id, data, None), &max_lag_ms) {
+                Ok(Ok(ClientReadResponse::Success {
+                    data, last_log_term, last_log_id
+                })) => {
+                    swap_when_greater(&self.last_log_id, last_log_id);
+                    swap_when_greater(&self.last_log_term, last_log_term);
+                    return Ok(data);
+                },
+                Ok(Ok(ClientReadResponse::TooStale { lag })) => {
+                    debug!("CLIENT: member too stale for {}ms, lag {}ms", max_lag_ms, lag);
+                },
+                _ => {
+                    debug!("CLIENT: member cannot serve bounded query");
+                }
+            }
         }
-        let failure = {
-            if depth > 0 {
-                let members = self.members.read();
-                let num_members = members.clients.len();
-                if depth >= max(num_members, 5) {
-                    return Err(ExecError::TooManyRetry)
-                };
+        // falls back to the leader
+        self.retry(true, || self.leader_query(sm_id, fn_id, data, Consistency::Linearizable))
+    }
+
+    // follows the hint of a member that is not the leader, members are asked again without one
+    fn redirect(&self, leader_hint: Option<(u64, String)>) -> Attempt {
+        self.want_refresh();
+        let (leader_id, address) = match leader_hint {
+            Some(hint) => hint,
+            None => {
+                self.leader_id.store(0, ORDERING);
+                return Attempt::Rejected;
             }
-            match self.current_leader_client() {
-                Some((leader_id, client)) => {
-                    match client.c_command(&self.gen_log_entry(sm_id, fn_id, data)) {
-                        Ok(Ok(ClientCmdResponse::Success {
-                                  data, last_log_term, last_log_id
-                              })) => {
-                            swap_when_greater(&self.last_log_id, last_log_id);
-                            swap_when_greater(&self.last_log_term, last_log_term);
-                            return Ok(data);
-                        },
-                        Ok(Ok(ClientCmdResponse::NotLeader(leader_id))) => {
-                            self.leader_id.store(leader_id, ORDERING);
-                            FailureAction::NotLeader
-                        },
-                        Ok(Ok(ClientCmdResponse::NotCommitted)) => {
-                            FailureAction::NotCommitted
-                        },
-                        Err(e) => {
-                            debug!("CLIENT: E1 - {} - {:?}", leader_id, e);
-                            FailureAction::SwitchLeader // need switch server for leader
-                        }
-                        Ok(Err(e)) => {
-                            debug!("CLIENT: E2 - {} - {:?}", leader_id, e);
-                            FailureAction::SwitchLeader // need switch server for leader
-                        }
+        };
+        {
+            let mut members = self.members.write();
+            if !members.clients.contains_key(&leader_id) {
+                // the leader joined after the members were last updated
+                match rpc::DEFAULT_CLIENT_POOL.get(&address) {
+                    Ok(client) => {
+                        members.clients.insert(leader_id, SyncServiceClient::new(self.service_id, &client));
+                        members.id_map.insert(leader_id, address);
+                    },
+                    Err(_) => {
+                        self.leader_id.store(0, ORDERING);
+                        return Attempt::Rejected;
                     }
-                },
-                None => FailureAction::UpdateInfo // need update members
+                }
             }
-        }; //
-        match failure {
-            FailureAction::SwitchLeader => {
-                let members = self.members.read();
-                let num_members = members.clients.len();
-                let pos = self.qry_meta.pos.load(ORDERING);
-                let leader_id = self.leader_id.load(ORDERING);
-                let index = members.clients.keys()
-                    .nth(pos as usize % num_members)
-                    .unwrap();
-                self.leader_id.compare_and_swap(leader_id, *index, ORDERING);
-                debug!("CLIENT: Switch leader");
+        }
+        self.leader_id.store(leader_id, ORDERING);
+        Attempt::Redirected
+    }
+
+    // unless another request already found a new one
+    fn invalidate_leader(&self, leader_id: u64) {
+        self.leader_id.compare_and_swap(leader_id, 0, ORDERING);
+    }
+
+    fn switch_leader(&self) {
+        let members = self.members.read();
+        let num_members = members.clients.len();
+        if num_members < 1 {return;}
+        let pos = self.qry_meta.pos.load(ORDERING);
+        let leader_id = self.leader_id.load(ORDERING);
+        let index = members.clients.keys()
+            .nth(pos as usize % num_members)
+            .unwrap();
+        self.leader_id.compare_and_swap(leader_id, *index, ORDERING);
+        debug!("CLIENT: Switch leader");
+    }
+
+    // every retry carries the same session sequence
+    // the index is set to the entry of the command once it is applied
+    fn command(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>, session: Option<ClientSession>, index: &mut u64) -> Attempt {
+        match self.current_leader_client() {
+            Some((leader_id, client)) => {
+                match client.c_command(&self.gen_log_entry(sm_id, fn_id, data, session)) {
+                    Ok(Ok(ClientCmdResponse::Success {
+                              data, last_log_term, last_log_id
+                          })) => {
+                        *index = last_log_id;
+                        swap_when_greater(&self.last_log_id, last_log_id);
+                        swap_when_greater(&self.last_log_term, last_log_term);
+                        Attempt::Done(Ok(data))
+                    },
+                    Ok(Ok(ClientCmdResponse::NotLeader { leader_hint })) => self.redirect(leader_hint),
+                    Ok(Ok(ClientCmdResponse::DeadlineExceeded)) => {
+                        Attempt::Done(Err(ExecError::DeadlineExceeded))
+                    },
+                    Ok(Ok(ClientCmdResponse::ConfigChangeInProgress)) => {
+                        Attempt::Done(Err(ExecError::ConfigChangeInProgress))
+                    },
+                    // callers back off as they see fit
+                    Ok(Ok(ClientCmdResponse::ProposalQueueFull)) => {
+                        Attempt::Done(Err(ExecError::ProposalQueueFull))
+                    },
+                    Ok(Ok(ClientCmdResponse::EntryTooLarge { max_bytes })) => {
+                        Attempt::Done(Err(ExecError::EntryTooLarge { max_bytes: max_bytes }))
+                    },
+                    Ok(Ok(ClientCmdResponse::BatchUnsupported)) => {
+                        Attempt::Done(Err(ExecError::BatchUnsupported))
+                    },
+                    Ok(Ok(ClientCmdResponse::NotCommitted)) => {
+                        Attempt::Failed(ExecError::NotCommitted)
+                    },
+                    Ok(Ok(ClientCmdResponse::LostLeadership)) => {
+                        debug!("CLIENT: leader stepped down - {}", leader_id);
+                        self.invalidate_leader(leader_id);
+                        Attempt::Failed(ExecError::LostLeadership)
+                    },
+                    Err(e) => {
+                        debug!("CLIENT: E1 - {} - {:?}", leader_id, e);
+                        self.invalidate_leader(leader_id); // the leader may be gone, ask members
+                        Attempt::Failed(ExecError::Unknown)
+                    }
+                    Ok(Err(e)) => {
+                        debug!("CLIENT: E2 - {} - {:?}", leader_id, e);
+                        self.switch_leader(); // need switch server for leader
+                        Attempt::Failed(ExecError::Unknown)
+                    }
+                }
             },
-            _ => {}
+            None => Attempt::NoLeader // no member knows the leader either
         }
-        self.command(sm_id, fn_id, data, depth + 1)
     }
 
-    fn gen_log_entry(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>) -> LogEntry {
+    fn begin_command(&self) -> ClientSession {
+        let mut session = self.session.lock();
+        session.last_seq += 1;
+        let seq = session.last_seq;
+        session.pending.insert(seq);
+        ClientSession {
+            id: session.id,
+            seq: seq,
+            acked: session.pending.iter().next().unwrap() - 1,
+            time: 0 // set by the leader
+        }
+    }
+
+    fn end_command(&self, command: &ClientSession, response: &Result<ExecResult, ExecError>) {
+        let mut session = self.session.lock();
+        if session.id != command.id {return;}
+        if let Ok(Err(ExecError::SessionExpired)) = *response {
+            // servers forgot the session, commands from now on go in a new one
+            warn!("CLIENT: session {} expired", command.id);
+            *session = Session::new();
+            return;
+        }
+        session.pending.remove(&command.seq);
+    }
+
+    fn gen_log_entry(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>, session: Option<ClientSession>) -> LogEntry {
         LogEntry {
             id: self.last_log_id.load(ORDERING),
             term: self.last_log_term.load(ORDERING),
             sm_id: sm_id,
             fn_id: fn_id,
-            data: data.clone()
+            data: data.clone(),
+            trace_id: rpc::context::current_trace_id(),
+            session: session,
+            time: 0,
+            checksum: 0,
+        }
+    }
+    // membership changes go one at a time, ConfigChangeInProgress until the last one is committed
+    pub fn add_member(&self, address: &String) -> Result<Result<(), ()>, ExecError> {
+        self.execute(CONFIG_SM_ID, &new_member_::new(address, &MemberRole::Voter))
+    }
+    pub fn remove_member(&self, id: u64) -> Result<Result<(), ()>, ExecError> {
+        let mut address = self.members.read().id_map.get(&id).cloned();
+        if address.is_none() {
+            // the member may have joined after the last update
+            self.refresh_members().ok();
+            address = self.members.read().id_map.get(&id).cloned();
+        }
+        match address {
+            Some(address) => self.execute(CONFIG_SM_ID, &del_member_::new(&address)),
+            None => Ok(Err(()))
+        }
+    }
+    pub fn add_learner(&self, address: &String) -> Result<Result<(), ()>, ExecError> {
+        self.execute(CONFIG_SM_ID, &new_member_::new(address, &MemberRole::Learner))
+    }
+    pub fn promote_learner(&self, id: u64) -> Result<(), PromoteError> {
+        let client = match self.current_leader_client() {
+            Some((_, client)) => client,
+            None => return Err(PromoteError::LeaderUnreachable)
+        };
+        match client.c_promote_learner(&id) {
+            Ok(Ok(Err(PromoteError::NotLeader(leader_id)))) => {
+                self.leader_id.store(leader_id, ORDERING);
+                Err(PromoteError::NotLeader(leader_id))
+            },
+            Ok(Ok(result)) => result,
+            _ => Err(PromoteError::LeaderUnreachable)
+        }
+    }
+    // asks the current leader to hand leadership over to the member
+    pub fn transfer_leadership(&self, target_id: u64) -> Result<(), TransferError> {
+        let client = match self.current_leader_client() {
+            Some((_, client)) => client,
+            None => return Err(TransferError::LeaderUnreachable)
+        };
+        match client.c_transfer_leadership(&target_id) {
+            Ok(Ok(Ok(()))) => {
+                self.leader_id.store(target_id, ORDERING);
+                Ok(())
+            },
+            Ok(Ok(Err(TransferError::NotLeader(leader_id)))) => {
+                self.leader_id.store(leader_id, ORDERING);
+                Err(TransferError::NotLeader(leader_id))
+            },
+            Ok(Ok(Err(e))) => Err(e),
+            _ => Err(TransferError::LeaderUnreachable)
+        }
+    }
+    // queries go to the leader unless reading stale state is fine
+    pub fn read_consistency(&self, consistency: Consistency) {
+        *self.consistency.write() = consistency;
+    }
+    // status of the leader when it answers, of any other member otherwise
+    pub fn cluster_status(&self) -> Result<NodeStatus, ClientError> {
+        let leader_id = self.leader_id();
+        let clients: Vec<(u64, Client)> = {
+            let members = self.members.read();
+            let mut clients: Vec<(u64, Client)> = members.clients.iter()
+                .map(|(id, client)| (*id, client.clone()))
+                .collect();
+            clients.sort_by_key(|&(id, _)| id != leader_id);
+            clients
+        };
+        for (_, client) in clients {
+            if let Ok(Ok(status)) = client.c_status() {
+                return Ok(status);
+            }
         }
+        Err(ClientError::ServerUnreachable)
     }
     pub fn leader_id(&self) -> u64 {self.leader_id.load(ORDERING)}
     pub fn leader_client(&self) -> Option<(u64, Client)> {
@@ -331,20 +1124,30 @@ impl RaftClient {
                 return leader_client
             }
         }
-        {
-            let mut members = self.members.write();
-            let mut members_addrs = HashSet::new();
-            for address in members.id_map.values() {
-                members_addrs.insert(address.clone());
-
+        // no leader known, the members tell who it is
+        self.refresh_members().ok();
+        self.leader_client()
+    }
+    // polls the members until the one they point to claims leadership itself
+    pub fn wait_for_leader(&self, timeout: Duration) -> Result<(u64, String), NoLeader> {
+        let deadline = get_time() + duration_to_ms(timeout) as i64;
+        loop {
+            if let Some((leader_id, client)) = self.current_leader_client() {
+                if let Ok(Ok(info)) = client.c_server_cluster_info() {
+                    if info.leader_id == leader_id {
+                        let address = self.members.read().id_map.get(&leader_id).cloned();
+                        if let Some(address) = address {
+                            return Ok((leader_id, address));
+                        }
+                    }
+                }
+                self.invalidate_leader(leader_id);
             }
-            self.update_info(&mut members, &members_addrs);
-            let leader_id = self.leader_id.load(ORDERING);
-            if let Some(client) = members.clients.get(&leader_id) {
-                Some((leader_id,  client.clone()))
-            } else {
-                None
+            let remaining = deadline - get_time();
+            if remaining <= 0 {
+                return Err(NoLeader);
             }
+            thread::sleep(Duration::from_millis(min(remaining as u64, 50)));
         }
     }
     pub fn current_leader_rpc_client(&self) -> Option<Arc<rpc::RPCClient>> {
@@ -355,6 +1158,106 @@ impl RaftClient {
     }
 }
 
+impl Drop for RaftClient {
+    fn drop(&mut self) {
+        let &(ref state, ref woken) = &*self.refresh;
+        state.lock().closed = true;
+        woken.notify_one();
+        let &(ref queue, ref added) = &*self.batches;
+        queue.lock().closed = true;
+        added.notify_one();
+    }
+}
+
+// refreshes the members and checks the subscriptions of the client until it is dropped, the thread
+// only holds on to it while refreshing
+fn start_refreshing(client: &Arc<RaftClient>) {
+    let weak: Weak<RaftClient> = Arc::downgrade(client);
+    let refresh = client.refresh.clone();
+    let interval = client.options.members_refresh;
+    thread::Builder::new()
+        .name(String::from("raft client members"))
+        .spawn(move || loop {
+            {
+                let &(ref lock, ref woken) = &*refresh;
+                let mut state = lock.lock();
+                if !state.wanted && !state.closed {
+                    match interval {
+                        Some(interval) => {woken.wait_for(&mut state, interval);},
+                        None => woken.wait(&mut state)
+                    }
+                }
+                if state.closed {return;}
+                state.wanted = false;
+            }
+            match weak.upgrade() {
+                Some(client) => {
+                    if let Err(e) = client.refresh_members() {
+                        debug!("CLIENT: cannot refresh members, {:?}", e);
+                    }
+                    client.check_subscriptions();
+                },
+                None => return
+            }
+        })
+        .unwrap();
+}
+
+// sends the commands queued on the client until it is dropped, a batch at a time
+fn start_batching(client: &Arc<RaftClient>, batching: Batching) {
+    let weak: Weak<RaftClient> = Arc::downgrade(client);
+    let batches = client.batches.clone();
+    let max_batch = max(batching.max_batch, 1);
+    thread::Builder::new()
+        .name(String::from("raft client batches"))
+        .spawn(move || loop {
+            let batch: Vec<Batched> = {
+                let &(ref lock, ref added) = &*batches;
+                let mut queue = lock.lock();
+                while queue.commands.is_empty() && !queue.closed {
+                    added.wait(&mut queue);
+                }
+                // the first command waits for the others at most max_delay
+                let deadline = Instant::now() + batching.max_delay;
+                while queue.commands.len() < max_batch && !queue.closed {
+                    if added.wait_until(&mut queue, deadline).timed_out() {break;}
+                }
+                if queue.closed {return;}
+                let size = min(queue.commands.len(), max_batch);
+                queue.commands.drain(..size).collect()
+            };
+            match weak.upgrade() {
+                Some(client) => client.send_batch(batch),
+                None => return
+            }
+        })
+        .unwrap();
+}
+
+fn decode_response<R>(sm_id: u64, msg: &RaftMsg<R>, response: Result<ExecResult, ExecError>) -> Result<R, ExecError> {
+    match response {
+        Ok(data) => {
+            match data {
+                Ok(data) => msg.decode_return(&data).map_err(|e| {
+                    warn!("Cannot decode return from state machine {}, {:?}", sm_id, e);
+                    ExecError::DecodeError
+                }),
+                Err(e) => Err(e)
+            }
+        },
+        Err(e) => Err(e)
+    }
+}
+
+// members unreachable have not applied it as far as the client knows
+fn wait_member_applied(client: &Client, index: u64, deadline: i64) -> bool {
+    let timeout_ms = max(deadline - get_time(), 0) as u64;
+    match client.c_wait_applied(&index, &timeout_ms) {
+        Ok(Ok(applied)) => applied >= index,
+        _ => false
+    }
+}
+
 fn swap_when_greater(atomic: &AtomicU64, value: u64) {
     let mut orig_num = atomic.load(ORDERING);
     loop {
@@ -368,4 +1271,26 @@ fn swap_when_greater(atomic: &AtomicU64, value: u64) {
             orig_num = actual;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_to_max() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+            deadline: Duration::from_secs(1),
+        };
+        for _ in 0..100 {
+            let first = policy.backoff_ms(1);
+            assert!(first >= 5 && first <= 10);
+            let third = policy.backoff_ms(3);
+            assert!(third >= 20 && third <= 40);
+            let last = policy.backoff_ms(10);
+            assert!(last >= 50 && last <= 100);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use parking_lot::{Mutex, Condvar};
+use threadpool::ThreadPool;
+use num_cpus;
+use utils::time::get_time;
+use super::{RaftService, CHECKER_MS};
+
+// time of elections, heartbeats and leases, in milliseconds
+pub trait Clock: Send + Sync {
+    fn now(&self) -> i64;
+    // servers on the shared timer are checked by it, others only by RaftService::tick
+    fn ticking(&self) -> bool { true }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        get_time()
+    }
+}
+
+// moves only when told, for tests to time out and elect step by step
+pub struct ManualClock {
+    now: Mutex<i64>,
+}
+
+impl ManualClock {
+    // starts at the system time, times of entries look like those of other servers
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Mutex::new(get_time()),
+        }
+    }
+    pub fn advance(&self, ms: i64) -> i64 {
+        let mut now = self.now.lock();
+        *now += ms;
+        *now
+    }
+    pub fn set(&self, now: i64) {
+        *self.now.lock() = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        *self.now.lock()
+    }
+    fn ticking(&self) -> bool {
+        false
+    }
+}
+
+lazy_static! {
+    static ref TIMER: Arc<Timer> = Timer::start();
+}
+
+// one thread for the servers of the process, checks that are due run on the pool.
+// a server is out of the timer while checked, the next check is due one interval after it started
+struct Timer {
+    // by due time and the order they were added in
+    due: Mutex<BTreeMap<(i64, u64), Arc<RaftService>>>,
+    added: Condvar,
+    seq: AtomicU64,
+    pool: Mutex<ThreadPool>,
+}
+
+impl Timer {
+    fn start() -> Arc<Timer> {
+        let timer = Arc::new(Timer {
+            due: Mutex::new(BTreeMap::new()),
+            added: Condvar::new(),
+            seq: AtomicU64::new(0),
+            pool: Mutex::new(ThreadPool::new(::std::cmp::max(num_cpus::get() * 5, 10))),
+        });
+        let timer_ref = timer.clone();
+        thread::Builder::new()
+            .name(String::from("raft timer"))
+            .spawn(move || timer_ref.run())
+            .unwrap();
+        timer
+    }
+    fn schedule(&self, server: Arc<RaftService>, at: i64) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.due.lock().insert((at, seq), server);
+        self.added.notify_one();
+    }
+    fn run(&self) {
+        let mut due = self.due.lock();
+        loop {
+            let now = get_time();
+            let next = due.keys().next().map(|&(at, _)| at);
+            match next {
+                None => {
+                    self.added.wait(&mut due);
+                },
+                Some(at) if at > now => {
+                    self.added.wait_for(&mut due, Duration::from_millis((at - now) as u64));
+                },
+                Some(_) => {
+                    let later = due.split_off(&(now + 1, 0));
+                    let servers = mem::replace(&mut *due, later);
+                    let pool = self.pool.lock();
+                    for (_, server) in servers {
+                        pool.execute(move || check(server));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check(server: Arc<RaftService>) {
+    let started = get_time();
+    // offline servers leave the timer
+    if RaftService::tick(&server) {
+        let interval = ::std::cmp::min(CHECKER_MS, server.options.heartbeat_ms());
+        TIMER.schedule(server, started + interval);
+    }
+}
+
+// the server is checked until it goes offline
+pub fn start_ticking(server: &Arc<RaftService>) {
+    TIMER.schedule(server.clone(), get_time());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        assert!(!clock.ticking());
+        assert_eq!(clock.now(), started);
+        assert_eq!(clock.advance(250), started + 250);
+        assert_eq!(clock.now(), started + 250);
+        clock.set(1000);
+        assert_eq!(clock.now(), 1000);
+        assert!(SystemClock.ticking());
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use utils::bincode;
+use super::Snapshot;
+use super::snapshot::read_snapshot;
+
+// what a server promised to the cluster, it has to be on disk before the server answers for it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct HardState {
+    pub term: u64,
+    pub vote_for: Option<u64>,
+    pub last_log_id: u64,
+}
+
+// [crc32][bincode of the state], replaced as a whole on every change
+pub struct StateFile {
+    path: PathBuf,
+    saved: HardState,
+}
+
+fn corrupted(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Raft file {} is corrupted", path.display()))
+}
+
+// a new file is not durable until the directory entry pointing to it is synced
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(())
+    }
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(())
+    }
+}
+
+// [crc32][data], None when the file does not exist
+fn read_checked(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    if data.len() < 4 || LittleEndian::read_u32(&data) != crc32::checksum_ieee(&data[4..]) {
+        return Err(corrupted(path));
+    }
+    Ok(Some(data.split_off(4)))
+}
+
+// the old file is kept until the new one is complete, so a crash leaves one of them
+fn write_checked(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut checksum = [0u8; 4];
+    LittleEndian::write_u32(&mut checksum, crc32::checksum_ieee(data));
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+        file.write_all(&checksum)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    sync_dir(path)
+}
+
+impl StateFile {
+    // the state in the file, or the initial state when the server never saved one
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(StateFile, HardState)> {
+        let path = path.as_ref().to_path_buf();
+        create_parent(&path)?;
+        let state = match read_checked(&path)? {
+            Some(data) => match bincode::try_deserialize(&data) {
+                Some(state) => state,
+                None => return Err(corrupted(&path))
+            },
+            None => HardState::default()
+        };
+        Ok((StateFile {
+            path: path,
+            saved: state,
+        }, state))
+    }
+    pub fn saved(&self) -> HardState {
+        self.saved
+    }
+    // returns after the state reached the disk, unchanged states are not written again
+    pub fn save(&mut self, state: &HardState) -> io::Result<()> {
+        if *state == self.saved {
+            return Ok(());
+        }
+        write_checked(&self.path, &bincode::serialize(state))?;
+        self.saved = *state;
+        Ok(())
+    }
+}
+
+// the snapshot replacing the compacted log prefix, None when the server never compacted
+pub fn load_snapshot<P: AsRef<Path>>(path: P) -> io::Result<Option<Snapshot>> {
+    let path = path.as_ref();
+    match read_checked(path)? {
+        Some(data) => match read_snapshot(&data) {
+            Ok(contents) => Ok(Some(Snapshot {
+                last_included_id: contents.last_included_id,
+                last_included_term: contents.last_included_term,
+                data: data,
+            })),
+            Err(_) => Err(corrupted(path))
+        },
+        None => Ok(None)
+    }
+}
+
+// the data of the snapshot is in the format of raft::snapshot, it keeps the id and term
+pub fn save_snapshot<P: AsRef<Path>>(path: P, snapshot: &Snapshot) -> io::Result<()> {
+    let path = path.as_ref();
+    create_parent(path)?;
+    write_checked(path, &snapshot.data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn save_and_recover() {
+        let path = env::temp_dir().join("bifrost-raft-state-unit");
+        fs::remove_file(&path).ok();
+        let (mut file, state) = StateFile::open(&path).unwrap();
+        assert_eq!(state, HardState::default());
+        let state = HardState { term: 3, vote_for: Some(42), last_log_id: 7 };
+        file.save(&state).unwrap();
+        assert_eq!(StateFile::open(&path).unwrap().1, state);
+        // damaged on disk
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_eq!(StateFile::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).ok();
+    }
+}
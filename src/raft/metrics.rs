@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use rpc::metrics::{Histogram, HistogramSnapshot};
+
+// always on, counters are only added to and latencies go to fixed buckets
+pub struct RaftMetrics {
+    elections_started: AtomicU64,
+    elections_won: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    heartbeats_received: AtomicU64,
+    append_rejections: AtomicU64,
+    snapshots_installed: AtomicU64,
+    proposals_accepted: AtomicU64,
+    proposals_rejected: AtomicU64,
+    // from the leader appending the entry to committing it
+    commit_latency: Histogram,
+    // from the server knowing the entry is committed to the state machine applying it
+    apply_latency: Histogram,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RaftMetricsSnapshot {
+    pub elections_started: u64,
+    pub elections_won: u64,
+    // rounds of append entries from the leader to each follower
+    pub heartbeats_sent: u64,
+    // append entries from leaders without entries
+    pub heartbeats_received: u64,
+    // append entries the server answered with anything but ok
+    pub append_rejections: u64,
+    pub snapshots_installed: u64,
+    // commands the leader appended to its log, and those refused before
+    pub proposals_accepted: u64,
+    pub proposals_rejected: u64,
+    pub commit_latency: HistogramSnapshot,
+    pub apply_latency: HistogramSnapshot,
+    // follower id to entries it is behind the last log entry, only leaders know
+    pub replication_lag: BTreeMap<u64, u64>,
+}
+
+fn add(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl RaftMetrics {
+    pub fn new() -> RaftMetrics {
+        RaftMetrics {
+            elections_started: AtomicU64::new(0),
+            elections_won: AtomicU64::new(0),
+            heartbeats_sent: AtomicU64::new(0),
+            heartbeats_received: AtomicU64::new(0),
+            append_rejections: AtomicU64::new(0),
+            snapshots_installed: AtomicU64::new(0),
+            proposals_accepted: AtomicU64::new(0),
+            proposals_rejected: AtomicU64::new(0),
+            commit_latency: Histogram::new(),
+            apply_latency: Histogram::new(),
+        }
+    }
+    pub fn election_started(&self) { add(&self.elections_started); }
+    pub fn election_won(&self) { add(&self.elections_won); }
+    pub fn heartbeat_sent(&self) { add(&self.heartbeats_sent); }
+    pub fn heartbeat_received(&self) { add(&self.heartbeats_received); }
+    pub fn append_rejected(&self) { add(&self.append_rejections); }
+    pub fn snapshot_installed(&self) { add(&self.snapshots_installed); }
+    pub fn proposal_accepted(&self) { add(&self.proposals_accepted); }
+    pub fn proposal_rejected(&self) { add(&self.proposals_rejected); }
+    pub fn record_commit(&self, elapsed: Duration) {
+        self.commit_latency.record(elapsed);
+    }
+    pub fn record_apply(&self, elapsed: Duration) {
+        self.apply_latency.record(elapsed);
+    }
+    pub fn snapshot(&self, replication_lag: BTreeMap<u64, u64>) -> RaftMetricsSnapshot {
+        RaftMetricsSnapshot {
+            elections_started: self.elections_started.load(Ordering::Relaxed),
+            elections_won: self.elections_won.load(Ordering::Relaxed),
+            heartbeats_sent: self.heartbeats_sent.load(Ordering::Relaxed),
+            heartbeats_received: self.heartbeats_received.load(Ordering::Relaxed),
+            append_rejections: self.append_rejections.load(Ordering::Relaxed),
+            snapshots_installed: self.snapshots_installed.load(Ordering::Relaxed),
+            proposals_accepted: self.proposals_accepted.load(Ordering::Relaxed),
+            proposals_rejected: self.proposals_rejected.load(Ordering::Relaxed),
+            commit_latency: self.commit_latency.snapshot(),
+            apply_latency: self.apply_latency.snapshot(),
+            replication_lag: replication_lag,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_and_latencies() {
+        let metrics = RaftMetrics::new();
+        metrics.election_started();
+        metrics.election_started();
+        metrics.election_won();
+        metrics.proposal_rejected();
+        metrics.record_commit(Duration::from_millis(3));
+        let lag: BTreeMap<u64, u64> = vec![(2, 5)].into_iter().collect();
+        let snapshot = metrics.snapshot(lag);
+        assert_eq!(snapshot.elections_started, 2);
+        assert_eq!(snapshot.elections_won, 1);
+        assert_eq!(snapshot.proposals_accepted, 0);
+        assert_eq!(snapshot.proposals_rejected, 1);
+        assert_eq!(snapshot.commit_latency.count, 1);
+        assert_eq!(snapshot.commit_latency.percentile(50.0), Some(5000));
+        assert_eq!(snapshot.apply_latency.count, 0);
+        assert_eq!(snapshot.replication_lag.get(&2), Some(&5));
+    }
+}
@@ -1,39 +1,86 @@
 use rand;
 use rand::distributions::{IndependentSample, Range};
 use std::thread;
+use std::path::Path;
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard, Mutex};
 use std::collections::{BTreeMap, HashMap};
-use std::collections::Bound::{Included, Unbounded};
+use std::collections::Bound::{Included, Excluded, Unbounded};
 use std::cmp::{min, max};
-use std::sync::mpsc::channel;
-use self::state_machine::OpType;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use futures::{Async, Future};
+use futures::executor::{self, Notify};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::mem;
+use std::time::Instant;
+use self::state_machine::{OpType, StateMachineCtl, CommandCtx};
 use self::state_machine::master::{
     MasterStateMachine, ExecResult,
-    ExecError, SubStateMachine};
-use self::state_machine::configs::{CONFIG_SM_ID, RaftMember};
+    ExecError, SubStateMachine, dispatch_sub_cmd, NOOP_SM_ID, BATCH_SM_ID};
+use self::state_machine::configs::{CONFIG_SM_ID, RaftMember, MemberRole};
+use self::state_machine::callback::Emission;
 use self::state_machine::configs::commands::{new_member_, del_member_, member_address};
 use self::client::RaftClient;
+use self::disk::HardState;
+use self::wal::SyncPolicy;
+use self::storage::{LogStore, FileStore};
+use self::snapshot::{IncomingSnapshot, send_snapshot, read_snapshot, write_snapshot, DEFAULT_CHUNK_SIZE};
+use self::replication::{batch_entries, prev_log_info, keepalive_prev, Progress};
+use self::apply::{Applier, AppliedIndex};
+use self::observer::{Observers, Observed, RaftObserver};
+use self::multi::{GroupLink, Heartbeat};
+use self::admin::{EntryMeta, AdminError, log_digest};
+use self::clock::{Clock, SystemClock};
+use self::transport::{Peer, Transport, RpcTransport};
+use self::metrics::{RaftMetrics, RaftMetricsSnapshot};
 use bifrost_hasher::hash_str;
-use utils::time::get_time;
+use utils::time::{get_time, duration_to_ms};
+use utils::codec::CodecError;
+use utils::bincode;
+use crc::crc32;
+use rpc::{self, context};
+use tcp;
 use threadpool::ThreadPool;
+use futures_cpupool::CpuPool;
 use num_cpus;
 
 #[macro_use]
 pub mod state_machine;
 pub mod client;
+pub mod disk;
+pub mod wal;
+pub mod storage;
+pub mod snapshot;
+pub mod replication;
+pub mod apply;
+pub mod observer;
+pub mod multi;
+pub mod admin;
+pub mod clock;
+pub mod transport;
+pub mod testing;
+pub mod metrics;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_DEFAULT_SERVICE) as u64;
 
 def_bindings! {
+    // the server applying the entry leads the term of it, see emits_events
     bind val IS_LEADER: bool = false;
+    // index of the entry the thread applies, events raised by state machines are numbered by it
+    bind val APPLYING_INDEX: u64 = 0;
 }
 
 pub trait RaftMsg<R>: Send + Sync {
     fn encode(&self) -> (u64, OpType, &Vec<u8>);
-    fn decode_return(&self, data: &Vec<u8>) -> R;
+    fn decode_return(&self, data: &Vec<u8>) -> Result<R, CodecError>;
+    // for subs, whose events are emitted
+    fn emission(&self) -> Emission {Emission::Leader}
 }
 
 const CHECKER_MS: i64 = 10;
+// defaults of options
+const MIN_ELECTION_TIMEOUT_MS: i64 = 200;
+const MAX_ELECTION_TIMEOUT_MS: i64 = 500;
+const HEARTBEAT_INTERVAL_MS: i64 = 10;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LogEntry {
@@ -41,7 +88,36 @@ pub struct LogEntry {
     pub term: u64,
     pub sm_id: u64,
     pub fn_id: u64,
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+    // trace id of the proposing request, state machines see it in context::current_trace_id
+    pub trace_id: Option<u64>,
+    // commands in a client session are applied once for each sequence
+    pub session: Option<ClientSession>,
+    // clock of the leader when it appended the entry, state machines see it in CommandCtx
+    pub time: i64,
+    // crc32 of everything above, set by the leader when appended
+    pub checksum: u32,
+}
+
+impl LogEntry {
+    // covers the id and term too, entries cannot be moved to another place in the log
+    pub fn digest(&self) -> u32 {
+        let content = (self.id, self.term, self.sm_id, self.fn_id, &self.data, self.trace_id, self.session, self.time);
+        crc32::checksum_ieee(&bincode::serialize(&content))
+    }
+    pub fn is_intact(&self) -> bool {
+        self.checksum == self.digest()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClientSession {
+    pub id: u64,
+    pub seq: u64,
+    // the client got responses up to this sequence, servers can forget them
+    pub acked: u64,
+    // set by the leader, idle sessions expire by the time in the log
+    pub time: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,8 +127,20 @@ pub enum ClientCmdResponse {
         last_log_term: u64,
         last_log_id: u64,
     },
-    NotLeader(u64),
+    // id and address of the leader the server follows, if it knows one
+    NotLeader { leader_hint: Option<(u64, String)> },
     NotCommitted,
+    // the server stepped down before the command was committed, it may still be by the next leader
+    LostLeadership,
+    DeadlineExceeded,
+    // the last membership change has not been committed yet
+    ConfigChangeInProgress,
+    // too many entries of the leader are not applied yet, the command was not proposed
+    ProposalQueueFull,
+    // bytes of the payload the server takes at most, the command was not proposed
+    EntryTooLarge { max_bytes: u64 },
+    // some member is older or was never reached and may not apply batches, they were not proposed
+    BatchUnsupported,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientQryResponse {
@@ -63,32 +151,188 @@ pub enum ClientQryResponse {
     },
     LeftBehind
 }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClientReadResponse {
+    Success{
+        data: ExecResult,
+        last_log_term: u64,
+        last_log_id: u64,
+    },
+    NotLeader { leader_hint: Option<(u64, String)> },
+    // a majority of voters did not answer, the server may not be the leader anymore
+    NotConfirmed,
+    // milliseconds since the follower was last known to be up to date with the leader
+    TooStale { lag: u64 },
+}
+
+// how clients read state machines
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Consistency {
+    // the leader confirms it is still the leader before reading
+    Linearizable,
+    // the leader skips confirmation while its lease is valid, relies on clocks
+    LeaderLease,
+    // any server, may not reflect the latest commands
+    Stale,
+    // followers serve reads when up to date with the leader within the bound, the leader serves the rest
+    BoundedStale(Duration),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientClusterInfo {
     members: Vec<(u64, String)>,
     last_log_id: u64,
     last_log_term: u64,
     leader_id: u64,
+    // entries each follower is behind the last log entry, only leaders know
+    follower_lags: Vec<(u64, u64)>,
+    // of the server answered
+    metrics: RaftMetricsSnapshot,
+}
+
+impl ClientClusterInfo {
+    pub fn follower_lag(&self, id: u64) -> Option<u64> {
+        self.follower_lags.iter().find(|&&(follower_id, _)| follower_id == id).map(|&(_, lag)| lag)
+    }
+    pub fn metrics(&self) -> &RaftMetricsSnapshot {
+        &self.metrics
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Leader,
+    Follower,
+    Candidate,
+    Learner,
+    Witness,
+    Offline,
+    Undefined,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemberStatus {
+    pub id: u64,
+    pub address: String,
+    pub role: MemberRole,
+    // last entry the member is known to have, only leaders know
+    pub match_index: Option<u64>,
+}
+
+// what the server knows about itself and the cluster
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeStatus {
+    pub id: u64,
+    pub address: String,
+    pub role: Role,
+    pub term: u64,
+    pub leader_id: u64,
+    pub last_log_id: u64,
+    pub last_log_term: u64,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_id: Option<u64>,
+    pub members: Vec<MemberStatus>,
+    pub uptime_ms: i64,
+    // entries the leader has not applied yet, 0 on other servers
+    pub proposal_queue: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum AppendEntriesResult {
     Ok,
     TermOut(u64),
-    LogMismatch
+    LogMismatch,
+    // entries failed their checksums on the way, nothing was appended
+    Corrupted
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TransferError {
+    NotLeader(u64),
+    UnknownMember,
+    // learners cannot lead, promote them first
+    NotVoter,
+    // witnesses have no data to lead with
+    Witness,
+    // the target did not take over within an election timeout, the leader keeps serving
+    Timeout,
+    LeaderUnreachable,
+}
+
+// servers with a log or a vote may be in a cluster already, they join it instead
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BootstrapError {
+    Witness,
+    ExistingState,
+    // the server already knows a leader, the id of it
+    SeenLeader(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LeaveError {
+    // the leader is the only voter with data, nobody can take over
+    NoSuccessor,
+    Transfer(TransferError),
+    CannotConstructClient,
+    // the removal was not committed, the server is still a member
+    NotRemoved(ExecError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PromoteError {
+    NotLeader(u64),
+    UnknownMember,
+    NotLearner,
+    // entries the learner is behind the commit index of the leader
+    LaggingBehind(u64),
+    NotCommitted,
+    LeaderUnreachable,
+}
+
+// state machines at the last included entry, it replaces the log up to the entry. the data is
+// in the format of raft::snapshot with a section for each state machine
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub last_included_id: u64,
+    pub last_included_term: u64,
+    pub data: Vec<u8>,
+}
+
+// applying goes on past the last included entry, the log is kept until the snapshot is saved
+struct Compacting {
+    last_included_id: u64,
+    last_included_term: u64,
+    // data of the snapshot, Err when some state machine cannot snapshot
+    data: Mutex<Receiver<Result<Vec<u8>, ()>>>,
 }
 
 type LogEntries = Vec<LogEntry>;
 type LogsMap = BTreeMap<u64, LogEntry>;
 
+// servers of the version on apply entries of BATCH_SM_ID
+pub const BATCH_VERSION: u32 = 1;
+
 service! {
+    version 1;
     rpc append_entries(term: u64, leaderId: u64, prev_log_id: u64, prev_log_term: u64, entries: Option<LogEntries>, leader_commit: u64) -> (u64, AppendEntriesResult);
     rpc request_vote(term: u64, candidate_id: u64, last_log_id: u64, last_log_term: u64) -> ((u64, u64), bool); // term, voteGranted
-    rpc install_snapshot(term: u64, leader_id: u64, last_included_index: u64, last_included_term: u64, data: Vec<u8>, done: bool) -> u64;
+    rpc pre_vote(term: u64, candidate_id: u64, last_log_id: u64, last_log_term: u64) -> bool; // term of the election the candidate would start
+    rpc timeout_now(term: u64, leader_id: u64) -> bool; // the leader hands over, start an election right away
+    rpc install_snapshot(term: u64, leader_id: u64, last_included_index: u64, last_included_term: u64, offset: u64, data: Vec<u8>, checksum: u32, done: bool) -> u64;
     rpc c_command(entry: LogEntry) -> ClientCmdResponse;
     rpc c_query(entry: LogEntry) -> ClientQryResponse;
+    rpc c_leader_query(entry: LogEntry, consistency: Consistency) -> ClientReadResponse;
+    rpc c_bounded_query(entry: LogEntry, max_lag_ms: u64) -> ClientReadResponse;
     rpc c_server_cluster_info() -> ClientClusterInfo;
+    rpc c_status() -> NodeStatus;
     rpc c_put_offline() -> bool;
+    rpc c_transfer_leadership(target_id: u64) -> Result<(), TransferError>;
+    rpc c_promote_learner(id: u64) -> Result<(), PromoteError>;
+    rpc c_wait_applied(index: u64, timeout_ms: u64) -> u64; // last applied, once it reaches the index or on timeout
+    rpc c_dump_log(from: u64, to: u64) -> Result<Vec<EntryMeta>, AdminError>;
+    rpc c_entry_payload(index: u64) -> Result<LogEntry, AdminError>;
+    rpc c_log_digest(from: u64, to: u64) -> Result<u64, AdminError>;
 }
 
 fn gen_rand(lower: i64, higher: i64) -> i64 {
@@ -97,25 +341,83 @@ fn gen_rand(lower: i64, higher: i64) -> i64 {
     between.ind_sample(&mut rng) + 1
 }
 
-fn gen_timeout() -> i64 {
-    gen_rand(200, 500)
+fn gen_timeout(opts: &Options) -> i64 {
+    gen_rand(opts.min_election_timeout_ms(), opts.max_election_timeout_ms())
 }
 
 struct FollowerStatus {
     next_index: u64,
     match_index: u64,
+    // batches sent by the pipeline and not answered yet
+    in_flight: usize,
+    // too far behind, entries only go with heartbeats one batch at a time
+    lagging: bool,
+}
+
+impl FollowerStatus {
+    // the follower has every entry up to the id, answers may come out of order
+    fn matched(&mut self, id: u64) {
+        self.match_index = max(self.match_index, id);
+        self.next_index = max(self.next_index, id + 1);
+    }
+    // send again from the id, entries the follower already has are skipped by it
+    fn retry_from(&mut self, id: u64) {
+        self.next_index = max(min(self.next_index, id), self.match_index + 1);
+    }
+}
+
+struct Follower {
+    status: Mutex<FollowerStatus>,
+    // held by the heartbeat worker of the follower
+    heartbeat: Mutex<()>,
+}
+
+// id and term of the entry before the empty heartbeat to the follower, None when it needs entries,
+// the snapshot or its worker is busy. multi raft servers batch those heartbeats
+fn idle_heartbeat(follower: &Follower, logs: &LogsMap, snapshot: &Option<Arc<Snapshot>>, last_log_id: u64) -> Option<(u64, u64)> {
+    if follower.heartbeat.try_lock().is_none() {
+        return None;
+    }
+    let status = follower.status.lock();
+    if status.in_flight > 0 || status.next_index <= last_log_id {
+        return None;
+    }
+    if let Some(ref snapshot) = *snapshot {
+        if status.next_index <= snapshot.last_included_id {
+            return None;
+        }
+    }
+    prev_log_info(logs, snapshot, status.next_index - 1)
+}
+
+// heartbeats acked by followers in the term of the leader
+#[derive(Default)]
+struct Lease {
+    // when the last acked heartbeat to each follower was sent
+    acked: HashMap<u64, i64>,
+    // a higher term was seen, another leader may have been elected
+    revoked: bool,
 }
 
 pub struct LeaderMeta {
     last_updated: i64,
-    followers: HashMap<u64, Arc<Mutex<FollowerStatus>>>
+    elected_at: i64,
+    lease: Arc<Mutex<Lease>>,
+    followers: HashMap<u64, Arc<Follower>>,
+    progress: Arc<Progress>,
+    // results of proposed entries go to the waiting clients once committed
+    proposals: HashMap<u64, Sender<ExecResult>>,
 }
 
 impl LeaderMeta {
-    fn new() -> LeaderMeta {
+    fn new(now: i64) -> LeaderMeta {
         LeaderMeta{
-            last_updated: get_time(),
+            last_updated: now,
+            elected_at: now,
+            lease: Arc::new(Mutex::new(Lease::default())),
             followers: HashMap::new(),
+            progress: Arc::new(Progress::new()),
+            proposals: HashMap::new(),
         }
     }
 }
@@ -137,15 +439,55 @@ pub struct RaftMeta {
     logs: Arc<RwLock<LogsMap>>,
     state_machine: RwLock<MasterStateMachine>,
     commit_index: u64,
+    // entries up to it are applied, state machines may have applied some after
     last_applied: u64,
+    // entries up to it went to the state machines, equal to last applied when applying in order
+    last_dispatched: u64,
+    // None when applying in order
+    applier: Option<Arc<Applier>>,
+    // last applied for waiters outside of the meta lock
+    applied: Arc<AppliedIndex>,
     leader_id: u64,
-    workers: Mutex<ThreadPool>,
+    // shared by the groups of a multi raft server
+    workers: Arc<Mutex<ThreadPool>>,
+    // drives pipelined append entries, answers of slow followers do not hold threads
+    replicator: CpuPool,
+    // None for servers in memory
+    store: Option<Mutex<Box<LogStore>>>,
+    // None before the first compaction
+    snapshot: Option<Arc<Snapshot>>,
+    // size of the entry data in logs
+    log_bytes: AtomicU64,
+    // chunks of the snapshot the leader is sending
+    incoming_snapshot: Option<IncomingSnapshot>,
+    // the snapshot state machines are taking on other threads
+    compacting: Option<Compacting>,
+    // the leader takes no new commands while handing over to this member
+    transferring_to: Option<u64>,
+    // set by timeout_now, the election starts without waiting for the timeout or pre votes
+    election_now: bool,
+    // log id of the membership change the leader has not committed yet, only one at a time
+    config_change_id: Option<u64>,
+    // when a heartbeat last found the server applied everything the leader committed
+    caught_up_at: i64,
+    // when the leader last sent heartbeats to all followers
+    last_heartbeat: i64,
+    observers: Observers,
+    // the metrics of the service, entries are applied under the meta lock
+    metrics: Arc<RaftMetrics>,
 }
 
-#[derive(Clone)]
 pub enum Storage {
     MEMORY,
+    // path of the file keeping term and vote of the server across restarts
     DISK(String),
+    // directory keeping term, vote and the log of the server across restarts
+    DiskOptions {
+        path: String,
+        sync_policy: SyncPolicy,
+    },
+    // moves into the server when it is created
+    Custom(Box<LogStore>),
 }
 
 impl Storage {
@@ -154,17 +496,187 @@ impl Storage {
     }
 }
 
-#[derive(Clone)]
+// the log is compacted into a snapshot when it passes either limit, None for no limit
+#[derive(Clone, Copy, Debug)]
+pub struct Compaction {
+    pub max_entries: Option<u64>,
+    pub max_bytes: Option<u64>,
+    // snapshots are sent to followers in chunks of this size
+    pub snapshot_chunk_size: usize,
+}
+
+impl Compaction {
+    pub fn Default() -> Compaction {
+        Compaction {
+            max_entries: Some(100000),
+            max_bytes: Some(64 * 1024 * 1024),
+            snapshot_chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+// new entries are sent to each follower in batches of at most max_batch_bytes and max_batch_entries,
+// without waiting for up to max_in_flight batches sent before. commands with payloads larger than
+// max_entry_bytes or a batch are refused with EntryTooLarge, an entry never takes a batch of its own.
+// followers more than max_follower_lag entries behind are not pipelined to, the leader compacts its log
+// so they catch up from the snapshot. they are pipelined to again once within half of it
+#[derive(Clone, Copy, Debug)]
+pub struct Replication {
+    pub max_in_flight: usize,
+    pub max_batch_bytes: u64,
+    pub max_batch_entries: usize,
+    pub max_entry_bytes: u64,
+    pub max_follower_lag: Option<u64>,
+}
+
+impl Replication {
+    pub fn Default() -> Replication {
+        Replication {
+            max_in_flight: 8,
+            max_batch_bytes: 1024 * 1024,
+            max_batch_entries: 1024,
+            max_entry_bytes: 1024 * 1024,
+            max_follower_lag: Some(100_000),
+        }
+    }
+    // bytes of the largest payload proposals may have
+    pub fn entry_limit(&self) -> u64 {
+        min(self.max_entry_bytes, self.max_batch_bytes)
+    }
+}
+
+// witnesses vote and acknowledge entries for commitment, they keep ids and terms of entries
+// but only the payloads of membership changes. they never lead, apply commands or serve reads.
+// their heartbeat acks confirm leadership like those of other voters, so a cluster of two data
+// servers and a witness survives one of them failing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeRole {
+    Data,
+    Witness,
+}
+
 pub struct Options {
     pub storage: Storage,
     pub address: String,
     pub service_id: u64,
+    // shared by the cluster, members are reached through connections proving it. servers from
+    // new_server are started with it, rpc servers started otherwise need it in their server options
+    pub auth_token: Option<String>,
+    pub compaction: Compaction,
+    pub replication: Replication,
+    // servers only start an election after a majority answered a pre vote, terms of servers
+    // partitioned away from the cluster stay behind and cannot disrupt the leader when they return
+    pub pre_vote: bool,
+    // learners are only promoted to voters when at most this many entries behind the commit index
+    pub max_learner_lag: u64,
+    // leaders serve LeaderLease reads without a round of heartbeats while their lease is valid.
+    // followers will not elect another leader within an election timeout after the heartbeats a
+    // quorum acked, the lease lasts that long times the safety factor to allow for clock drift.
+    // turn it off when clocks of servers cannot be trusted, LeaderLease reads are confirmed as
+    // linearizable ones then
+    pub lease_reads: bool,
+    pub lease_safety_factor: f64,
+    // client sessions idle for longer are dropped, retries of their commands are refused then.
+    // it is part of the replicated state, servers of a cluster need the same timeout
+    pub session_timeout_ms: i64,
+    // followers start an election after not hearing from the leader for a random timeout in the range,
+    // heartbeats go at most a fifth of the minimum apart. followers heard from the leader within the
+    // minimum are sure it is alive
+    pub election_timeout: std::ops::Range<Duration>,
+    pub heartbeat_interval: Duration,
+    // leaders not heard from a majority of voters within the maximum election timeout step down,
+    // a leader partitioned away stops taking commands the rest of the cluster may never see
+    pub check_quorum: bool,
+    pub role: NodeRole,
+    // committed entries of different state machines are applied on this many threads at the same time,
+    // entries of one state machine are still applied in order. 1 applies everything in order on the raft thread
+    pub apply_parallelism: usize,
+    // leaders take commands while less than this many entries are not applied yet, a full queue
+    // refuses them with ProposalQueueFull after waiting for room for at most the proposal queue wait
+    pub max_proposals: u64,
+    pub proposal_queue_wait: Duration,
+    // admin rpcs show the log with what clients wrote, servers without an auth token only answer
+    // them when this is set. it is only a matter of the options: calls are not checked to come over
+    // an authenticated connection, and in-process calls taking the shortcut never authenticate
+    pub admin_without_auth: bool,
+    // subscribers the leader could not notify for longer are dropped with their subscriptions
+    pub subscriber_ttl: Duration,
+    // events the leader keeps for each subscription until the subscriber took them. past it the
+    // oldest are dropped, a subscriber slow to take them resyncs instead of holding up others
+    pub callback_buffer: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OptionsError {
+    EmptyElectionTimeout,
+    HeartbeatTooLong,
+    EmptyProposalQueue,
+    EmptyBatch,
+}
+
+impl Options {
+    // address has to be set
+    pub fn Default() -> Options {
+        Options {
+            storage: Storage::Default(),
+            address: String::new(),
+            service_id: DEFAULT_SERVICE_ID,
+            auth_token: None,
+            compaction: Compaction::Default(),
+            replication: Replication::Default(),
+            pre_vote: true,
+            max_learner_lag: 100,
+            lease_reads: true,
+            lease_safety_factor: 0.9,
+            session_timeout_ms: 10 * 60 * 1000,
+            election_timeout: Duration::from_millis(MIN_ELECTION_TIMEOUT_MS as u64)..Duration::from_millis(MAX_ELECTION_TIMEOUT_MS as u64),
+            heartbeat_interval: Duration::from_millis(HEARTBEAT_INTERVAL_MS as u64),
+            check_quorum: true,
+            role: NodeRole::Data,
+            apply_parallelism: 1,
+            max_proposals: 10_000,
+            proposal_queue_wait: Duration::from_millis(0),
+            admin_without_auth: false,
+            subscriber_ttl: Duration::from_secs(5 * 60),
+            callback_buffer: 1024,
+        }
+    }
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.min_election_timeout_ms() >= self.max_election_timeout_ms() {
+            return Err(OptionsError::EmptyElectionTimeout);
+        }
+        if self.heartbeat_ms() <= 0 || self.heartbeat_ms() * 5 > self.min_election_timeout_ms() {
+            return Err(OptionsError::HeartbeatTooLong);
+        }
+        if self.max_proposals == 0 {
+            return Err(OptionsError::EmptyProposalQueue);
+        }
+        if self.replication.max_batch_entries == 0 {
+            return Err(OptionsError::EmptyBatch);
+        }
+        Ok(())
+    }
+    fn min_election_timeout_ms(&self) -> i64 {
+        duration_to_ms(self.election_timeout.start) as i64
+    }
+    fn max_election_timeout_ms(&self) -> i64 {
+        duration_to_ms(self.election_timeout.end) as i64
+    }
+    fn heartbeat_ms(&self) -> i64 {
+        duration_to_ms(self.heartbeat_interval) as i64
+    }
 }
 
 pub struct RaftService {
     meta: RwLock<RaftMeta>,
     pub id: u64,
     pub options: Options,
+    started_at: i64,
+    // None for servers of their own, groups are checked and batch heartbeats through their multi raft server
+    group: Option<GroupLink>,
+    // elections, heartbeats and leases are timed by it
+    clock: Arc<Clock>,
+    metrics: Arc<RaftMetrics>,
 }
 dispatch_rpc_service_functions!(RaftService);
 
@@ -172,6 +684,7 @@ dispatch_rpc_service_functions!(RaftService);
 enum CheckerAction {
     SendHeartbeat,
     BecomeCandidate,
+    StartElection,
     ExitLoop,
     None
 }
@@ -183,9 +696,9 @@ enum RequestVoteResponse {
 }
 
 macro_rules! get_last_log_info {
-    ($s: expr, $logs: expr) => {{
+    ($s: expr, $meta: expr, $logs: expr) => {{
         let last_log = $logs.iter().next_back();
-        $s.get_log_info_(last_log)
+        $s.get_log_info_(last_log, &$meta.snapshot)
     }};
 }
 
@@ -195,14 +708,145 @@ macro_rules! members_from_meta {
     };
 }
 
+// applies committed entries, results go to the clients proposed them on the leader
 fn check_commit(meta: &mut RwLockWriteGuard<RaftMeta>) {
-    while meta.commit_index > meta.last_applied {
-        meta.last_applied += 1;
-        let last_applied = meta.last_applied;
-        let logs = meta.logs.read();
-        if let Some(entry) = logs.get(&last_applied) {
-            commit_command(meta, &entry);
+    let committed = Instant::now();
+    while meta.commit_index > meta.last_dispatched {
+        halt_on_corruption(meta);
+        meta.last_dispatched += 1;
+        let id = meta.last_dispatched;
+        let entry = meta.logs.read().get(&id).cloned();
+        let proposal = match meta.membership {
+            Membership::Leader(ref leader_meta) => leader_meta.write().proposals.remove(&id),
+            _ => None
         };
+        if let Some(entry) = entry {
+            match meta.applier.clone() {
+                Some(applier) => dispatch_command(meta, &applier, entry, proposal, committed),
+                None => {
+                    let data = commit_command(meta, &entry);
+                    meta.metrics.record_apply(committed.elapsed());
+                    if let Some(proposal) = proposal {
+                        proposal.send(data);
+                    }
+                }
+            }
+        }
+        if meta.applier.is_none() {
+            set_applied(meta, id);
+        }
+    }
+    collect_applied(meta);
+}
+
+// commands of sub state machines go to the applier, the rest is applied in place
+fn dispatch_command(meta: &RwLockWriteGuard<RaftMeta>, applier: &Arc<Applier>, entry: LogEntry,
+                    proposal: Option<Sender<ExecResult>>, committed: Instant) {
+    if let Some(ref session) = entry.session {
+        // a retry of a command still being applied, its response is known once it is done
+        if let Some(id) = applier.in_flight_session(session) {
+            applier.wait(id);
+            record_responses(meta, applier);
+        }
+    }
+    if entry.sm_id == BATCH_SM_ID {
+        // batches are applied in place, on state machines earlier entries may still be applied to
+        applier.wait(entry.id - 1);
+        record_responses(meta, applier);
+    }
+    let leading = emits_events(meta, &entry);
+    let mut ctx = context::current();
+    ctx.trace_id = entry.trace_id;
+    let prepared = context::with_context(ctx.clone(), || {
+        with_bindings!(IS_LEADER: leading, APPLYING_INDEX: entry.id => {
+            meta.state_machine.write().prepare_cmd(&entry)
+        })
+    });
+    match prepared {
+        Ok(sm) => {
+            let (id, sm_id, session) = (entry.id, entry.sm_id, entry.session.clone());
+            let metrics = meta.metrics.clone();
+            applier.dispatch(id, sm_id, session, proposal, move || {
+                let result = context::with_context(ctx, || {
+                    with_bindings!(IS_LEADER: leading, APPLYING_INDEX: id => {
+                        dispatch_sub_cmd(&sm, &entry)
+                    })
+                });
+                metrics.record_apply(committed.elapsed());
+                result
+            });
+        },
+        Err(data) => if let Some(proposal) = proposal {
+            proposal.send(data);
+        }
+    }
+}
+
+fn record_responses(meta: &RwLockWriteGuard<RaftMeta>, applier: &Applier) {
+    let responses = applier.collect();
+    if responses.is_empty() {return;}
+    let mut state_machine = meta.state_machine.write();
+    for (session, response) in responses {
+        state_machine.respond(&session, &response);
+    }
+}
+
+// last applied moves up to the first entry still being applied
+fn collect_applied(meta: &mut RwLockWriteGuard<RaftMeta>) {
+    if let Some(applier) = meta.applier.clone() {
+        record_responses(meta, &applier);
+        let last_dispatched = meta.last_dispatched;
+        let applied = applier.applied(last_dispatched);
+        set_applied(meta, applied);
+    }
+}
+
+fn set_applied(meta: &mut RaftMeta, id: u64) {
+    meta.last_applied = id;
+    meta.applied.set(id);
+}
+
+// waits for every dispatched entry, state machines and sessions are up to the log then
+fn settle_applied(meta: &mut RwLockWriteGuard<RaftMeta>) {
+    if let Some(applier) = meta.applier.clone() {
+        let last_dispatched = meta.last_dispatched;
+        applier.wait(last_dispatched);
+        collect_applied(meta);
+    }
+}
+
+struct Unnotified;
+
+impl Notify for Unnotified {
+    fn notify(&self, _id: usize) {}
+}
+
+// the outcome when the future is done already, otherwise the future to wait for
+fn done_or<F: Future>(future: F) -> Result<Result<F::Item, F::Error>, F> {
+    let mut spawned = executor::spawn(future);
+    match spawned.poll_future_notify(&Arc::new(Unnotified), 0) {
+        Ok(Async::Ready(item)) => Ok(Ok(item)),
+        Ok(Async::NotReady) => Err(spawned.into_inner()),
+        Err(e) => Ok(Err(e))
+    }
+}
+
+fn record_ack(lease: &Mutex<Lease>, follower_id: u64, sent: i64) {
+    let mut lease = lease.lock();
+    let acked = lease.acked.entry(follower_id).or_insert(sent);
+    *acked = max(*acked, sent);
+}
+
+// another leader may be elected in the higher term, stop serving lease reads
+fn revoke_lease(meta: &RwLockWriteGuard<RaftMeta>) {
+    if let Membership::Leader(ref leader_meta) = meta.membership {
+        leader_meta.read().lease.lock().revoked = true;
+    }
+}
+
+fn clear_lease(meta: &RwLockWriteGuard<RaftMeta>) {
+    if let Membership::Leader(ref leader_meta) = meta.membership {
+        leader_meta.read().lease.lock().acked.clear();
     }
 }
 
@@ -210,18 +854,77 @@ fn is_majority (members: u64, granted: u64) -> bool {
     granted >= members / 2
 }
 
+// members counting the server itself
+fn is_quorum(members: u64, granted: u64) -> bool {
+    granted > members / 2
+}
+
+// witnesses keep the id and term of entries for elections, and membership changes to know the voters.
+// everything else becomes a no-op
+fn witness_entry(entry: &LogEntry) -> LogEntry {
+    if entry.sm_id == CONFIG_SM_ID {
+        return entry.clone();
+    }
+    let mut stripped = LogEntry {
+        id: entry.id,
+        term: entry.term,
+        sm_id: NOOP_SM_ID,
+        fn_id: 0,
+        data: Vec::new(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    stripped.checksum = stripped.digest();
+    stripped
+}
+
+// the next entry to apply never reaches the state machines when damaged. the server stops applying,
+// entries up to the one before are dispatched and every try panics again
+fn halt_on_corruption(meta: &RwLockWriteGuard<RaftMeta>) {
+    let next_id = meta.last_dispatched + 1;
+    if let Some(entry) = meta.logs.read().get(&next_id) {
+        if !entry.is_intact() {
+            error!("CORRUPTED RAFT LOG ENTRY {} of term {}, checksum {} but {} expected, stop applying",
+                   entry.id, entry.term, entry.digest(), entry.checksum);
+            panic!("Corrupted raft log entry {} of term {}", entry.id, entry.term);
+        }
+    }
+}
+
 fn commit_command(meta: &RwLockWriteGuard<RaftMeta>, entry: &LogEntry) -> ExecResult {
-    with_bindings!(IS_LEADER: is_leader(meta) => {
-        meta.state_machine.write().commit_cmd(&entry)
+    let mut ctx = context::current();
+    ctx.trace_id = entry.trace_id;
+    context::with_context(ctx, || {
+        with_bindings!(IS_LEADER: emits_events(meta, entry), APPLYING_INDEX: entry.id => {
+            meta.state_machine.write().commit_cmd(&entry)
+        })
     })
 }
 
+// clients are redirected to the leader known to the server
+fn leader_hint(meta: &RwLockWriteGuard<RaftMeta>) -> Option<(u64, String)> {
+    match meta.membership {
+        Membership::Follower if meta.leader_id != 0 => {},
+        _ => return None // candidates and servers left know no leader
+    }
+    members_from_meta!(meta).get(&meta.leader_id)
+        .map(|member| (member.id, member.address.clone()))
+}
+
 fn is_leader(meta: &RwLockWriteGuard<RaftMeta>) -> bool {
     match meta.membership {
         Membership::Leader(_) => {true},
         _ => {false}
     }
 }
+// events of entries are emitted by the leader of their term. a later leader applying one leaves
+// them, the leader before may have emitted them before stepping down
+fn emits_events(meta: &RwLockWriteGuard<RaftMeta>, entry: &LogEntry) -> bool {
+    is_leader(meta) && entry.term == meta.term
+}
+
 fn alter_term(meta: &mut RwLockWriteGuard<RaftMeta>, term: u64) {
     if meta.term != term {
         meta.term = term;
@@ -229,36 +932,231 @@ fn alter_term(meta: &mut RwLockWriteGuard<RaftMeta>, term: u64) {
     }
 }
 
+// entries reach the store of servers on disk before they are answered for
+fn write_store<F>(meta: &RaftMeta, f: F) -> Result<(), ()> where F: FnOnce(&mut Box<LogStore>) -> io::Result<()> {
+    if let Some(ref store) = meta.store {
+        if let Err(e) = f(&mut store.lock()) {
+            error!("Cannot write raft store, {}", e);
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+// the built in stores, servers in memory have none and custom stores are given
+fn open_store(storage: &Storage) -> io::Result<Option<Box<LogStore>>> {
+    let store: Box<LogStore> = match *storage {
+        Storage::MEMORY | Storage::Custom(_) => return Ok(None),
+        Storage::DISK(ref path) => Box::new(FileStore::state_only(path)?),
+        Storage::DiskOptions { ref path, sync_policy } => Box::new(FileStore::open(path, sync_policy)?)
+    };
+    Ok(Some(store))
+}
+
+fn recover(meta: &mut RwLockWriteGuard<RaftMeta>) -> io::Result<()> {
+    let (state, snapshot, entries) = match meta.store {
+        Some(ref store) => {
+            let store = store.lock();
+            let snapshot = store.snapshot()?;
+            // stores may keep some of the entries in the snapshot
+            let first_id = snapshot.as_ref().map(|snapshot| snapshot.last_included_id).unwrap_or(0) + 1;
+            (store.hard_state()?, snapshot, store.entries(first_id..std::u64::MAX)?)
+        },
+        None => return Ok(())
+    };
+    info!("Recovered raft state {:?}", state);
+    meta.term = state.term;
+    meta.vote_for = state.vote_for;
+    let mut snapshot_id = 0;
+    if let Some(snapshot) = snapshot {
+        info!("Recovered raft snapshot up to {}", snapshot.last_included_id);
+        let contents = read_snapshot(&snapshot.data)?;
+        // state machines registered later recover from the sections kept for them
+        meta.state_machine.write().recover_sections(contents.sections);
+        snapshot_id = snapshot.last_included_id;
+        meta.commit_index = snapshot_id;
+        set_applied(meta, snapshot_id);
+        meta.last_dispatched = snapshot_id;
+        meta.snapshot = Some(Arc::new(snapshot));
+    }
+    let last_log_id = max(entries.last().map(|entry| entry.id).unwrap_or(0), snapshot_id);
+    if last_log_id < state.last_log_id {
+        warn!("Raft log ends at {}, entries up to {} were lost", last_log_id, state.last_log_id);
+    }
+    info!("Recovered {} raft log entries", entries.len());
+    let mut logs = meta.logs.write();
+    for entry in entries {
+        meta.log_bytes.fetch_add(entry_bytes(&entry), Ordering::Relaxed);
+        logs.insert(entry.id, entry);
+    }
+    Ok(())
+}
+
+// queries read what is applied
+fn query_ctx(meta: &RaftMeta) -> CommandCtx {
+    CommandCtx {
+        index: meta.last_applied,
+        term: meta.term,
+        leader_time_ms: 0,
+        session: None,
+    }
+}
+
+fn entry_bytes(entry: &LogEntry) -> u64 {
+    entry.data.len() as u64
+}
+
+fn proposal_queue(meta: &RaftMeta) -> u64 {
+    match meta.membership {
+        Membership::Leader(_) => last_log_id(meta).saturating_sub(meta.last_applied),
+        _ => 0
+    }
+}
+
+// entries each follower is behind the last log entry, empty on other servers than the leader
+fn follower_lags(meta: &RaftMeta, last_log_id: u64) -> Vec<(u64, u64)> {
+    match meta.membership {
+        Membership::Leader(ref leader_meta) => leader_meta.read().followers.iter()
+            .map(|(id, follower)| (*id, last_log_id.saturating_sub(follower.status.lock().match_index)))
+            .collect(),
+        _ => Vec::new()
+    }
+}
+
+fn compacted_id(meta: &RaftMeta) -> u64 {
+    meta.snapshot.as_ref().map(|snapshot| snapshot.last_included_id).unwrap_or(0)
+}
+
+fn last_log_id(meta: &RaftMeta) -> u64 {
+    let last_log_id = meta.logs.read().keys().cloned().last();
+    last_log_id.unwrap_or_else(|| compacted_id(meta))
+}
+
+// false when the state cannot be saved, the server should not answer for it
+fn persist_state(meta: &mut RwLockWriteGuard<RaftMeta>) -> bool {
+    let state = HardState {
+        term: meta.term,
+        vote_for: meta.vote_for,
+        last_log_id: last_log_id(meta),
+    };
+    if let Some(ref store) = meta.store {
+        if let Err(e) = store.lock().save_hard_state(&state) {
+            error!("Cannot save raft state {:?}, {}", state, e);
+            return false;
+        }
+    }
+    true
+}
+
 
 impl RaftService {
+    // panics on invalid options, see try_new
     pub fn new(opts: Options) -> Arc<RaftService> {
+        RaftService::with_clock(opts, Arc::new(SystemClock))
+    }
+    // servers on a clock that does not tick are only checked by tick, see clock::ManualClock
+    pub fn with_clock(opts: Options, clock: Arc<Clock>) -> Arc<RaftService> {
+        let transport = Arc::new(RpcTransport::new(&opts.auth_token));
+        RaftService::with_transport(opts, clock, transport)
+    }
+    // members are reached through the transport instead of rpc clients, see testing::SimNetwork
+    pub fn with_transport(opts: Options, clock: Arc<Clock>, transport: Arc<Transport>) -> Arc<RaftService> {
+        match RaftService::try_with_transport(opts, clock, transport) {
+            Ok(service) => service,
+            Err(e) => panic!("Invalid raft options, {:?}", e)
+        }
+    }
+    pub fn try_new(opts: Options) -> Result<Arc<RaftService>, OptionsError> {
+        RaftService::try_with_clock(opts, Arc::new(SystemClock))
+    }
+    pub fn try_with_clock(opts: Options, clock: Arc<Clock>) -> Result<Arc<RaftService>, OptionsError> {
+        let transport = Arc::new(RpcTransport::new(&opts.auth_token));
+        RaftService::try_with_transport(opts, clock, transport)
+    }
+    pub fn try_with_transport(
+        opts: Options, clock: Arc<Clock>, transport: Arc<Transport>
+    ) -> Result<Arc<RaftService>, OptionsError> {
+        let workers = Arc::new(Mutex::new(ThreadPool::new(max(num_cpus::get() * 5, 10))));
+        RaftService::try_new_with(opts, workers, CpuPool::new(num_cpus::get()), None, clock, transport)
+    }
+    fn try_new_with(
+        mut opts: Options, workers: Arc<Mutex<ThreadPool>>, replicator: CpuPool, group: Option<GroupLink>,
+        clock: Arc<Clock>, transport: Arc<Transport>
+    ) -> Result<Arc<RaftService>, OptionsError> {
+        opts.validate()?;
+        let store = match mem::replace(&mut opts.storage, Storage::MEMORY) {
+            Storage::Custom(store) => Some(Mutex::new(store)),
+            storage => {
+                opts.storage = storage;
+                None
+            }
+        };
         let server_address = opts.address.clone();
         let server_id = hash_str(&server_address);
+        let metrics = Arc::new(RaftMetrics::new());
         let server_obj = RaftService {
             meta: RwLock::new(
                 RaftMeta {
-                    term: 0, //TODO: read from persistent state
-                    vote_for: None, //TODO: read from persistent state
-                    timeout: gen_timeout(),
-                    last_checked: get_time(),
+                    term: 0, // recovered in start for servers on disk
+                    vote_for: None,
+                    timeout: gen_timeout(&opts),
+                    last_checked: clock.now(),
                     membership: Membership::Undefined,
                     logs: Arc::new(RwLock::new(BTreeMap::new())), //TODO: read from persistent state
-                    state_machine: RwLock::new(MasterStateMachine::new(opts.service_id)),
+                    state_machine: RwLock::new(MasterStateMachine::new(opts.service_id, opts.session_timeout_ms, transport)),
                     commit_index: 0,
                     last_applied: 0,
+                    last_dispatched: 0,
+                    applier: if opts.apply_parallelism > 1 {
+                        Some(Arc::new(Applier::new(opts.apply_parallelism)))
+                    } else {
+                        None
+                    },
+                    applied: Arc::new(AppliedIndex::new()),
                     leader_id: 0,
-                    workers: Mutex::new(ThreadPool::new(
-                        max(num_cpus::get() * 5, 10)
-                    )),
+                    workers: workers,
+                    replicator: replicator,
+                    store: store,
+                    snapshot: None,
+                    log_bytes: AtomicU64::new(0),
+                    incoming_snapshot: None,
+                    compacting: None,
+                    caught_up_at: 0,
+                    transferring_to: None,
+                    election_now: false,
+                    config_change_id: None,
+                    last_heartbeat: 0,
+                    observers: Observers::new(),
+                    metrics: metrics.clone(),
                 }
             ),
             id: server_id,
             options: opts,
+            started_at: get_time(),
+            group: group,
+            clock: clock,
+            metrics: metrics,
         };
-        Arc::new(server_obj)
+        Ok(Arc::new(server_obj))
     }
     pub fn start(server: &Arc<RaftService>) -> bool {
         let server_address = server.options.address.clone();
+        {
+            let mut meta = server.meta.write();
+            if meta.store.is_none() {
+                match open_store(&server.options.storage) {
+                    Ok(store) => meta.store = store.map(Mutex::new),
+                    Err(e) => {
+                        error!("Cannot open raft store of {}, {}", server_address, e);
+                        return false;
+                    }
+                }
+            }
+            if let Err(e) = recover(&mut meta) {
+                error!("Cannot recover raft server {}, {}", server_address, e);
+                return false;
+            }
+        }
         info!("Waiting for server to be initialized");
         {
             let start_time = get_time();
@@ -266,6 +1164,11 @@ impl RaftService {
             let mut sm = meta.state_machine.write();
             let mut inited = false;
             while get_time() < start_time + 5000 { //waiting for 5 secs
+                // recovered from the snapshot on disk
+                if sm.configs.member_existed(server.id) {
+                    inited = true;
+                    break;
+                }
                 if let Ok(_) = sm.configs.new_member(server_address.clone()) {
                     inited = true;
                     break;
@@ -275,77 +1178,125 @@ impl RaftService {
                 return false;
             }
         }
-        let checker_ref = server.clone();
-        thread::spawn(move ||{
-            let server = checker_ref;
-            loop {
-                let start_time = get_time();
-                let expected_ends = start_time + CHECKER_MS;
-                {
-                    let mut meta = server.meta.write(); //WARNING: Reentering not supported
-                    let action = match meta.membership {
-                        Membership::Leader(_) => {
-                            CheckerAction::SendHeartbeat
-                        },
-                        Membership::Follower | Membership::Candidate => {
-                            let current_time = get_time();
-                            let timeout_time = meta.timeout + meta.last_checked;
-                            let timeout_elapsed = current_time - timeout_time;
-                            if  meta.vote_for == None && timeout_elapsed > 0 { // TODO: in my test sometimes timeout_elapsed may go 1 for no reason, require investigation
-                                //Timeout, require election
-                                //debug!("TIMEOUT!!! GOING TO CANDIDATE!!! {}, {}", server_id, timeout_elapsed);
-                                CheckerAction::BecomeCandidate
-                            } else {
-                                CheckerAction::None
-                            }
-                        },
-                        Membership::Offline => {
-                            CheckerAction::ExitLoop
-                        },
-                        Membership::Undefined => CheckerAction::None
-                    };
-                    match action {
-                        CheckerAction::SendHeartbeat => {
-                            server.send_followers_heartbeat(&mut meta, None);
-                        },
-                        CheckerAction::BecomeCandidate => {
-                            RaftService::become_candidate(server.clone(), &mut meta);
-                        },
-                        CheckerAction::ExitLoop => {
-                            break;
-                        },
-                        CheckerAction::None => {}
-                    }
-                }
-                let end_time = get_time();
-                let time_to_sleep = expected_ends - end_time - 1;
-                if time_to_sleep > 0 {
-                    thread::sleep(Duration::from_millis(time_to_sleep as u64));
-                }
-            }
-        });
         {
             let mut meta = server.meta.write();
-            meta.last_checked = get_time();
+            meta.last_checked = server.clock.now();
+        }
+        if server.group.is_none() && server.clock.ticking() {
+            clock::start_ticking(server);
         }
         return true;
     }
+    // one round of elections and heartbeats at the time of the clock, false once the server is offline.
+    // servers with a ticking clock are checked by the shared timer, groups by their multi raft server
+    pub fn tick(server: &Arc<RaftService>) -> bool {
+        let now = server.clock.now();
+        RaftService::check(server, now)
+    }
+    // one round of the checker, elections and heartbeats. false once the server is offline
+    fn check(server: &Arc<RaftService>, start_time: i64) -> bool {
+        let mut meta = server.meta.write(); //WARNING: Reentering not supported
+        let action = match meta.membership {
+            Membership::Leader(_) => {
+                CheckerAction::SendHeartbeat
+            },
+            Membership::Follower | Membership::Candidate => {
+                let current_time = server.clock.now();
+                let timeout_time = meta.timeout + meta.last_checked;
+                let timeout_elapsed = current_time - timeout_time;
+                let is_voter = meta.state_machine.read().configs.is_voter(server.id);
+                if !is_voter || server.is_witness() {
+                    CheckerAction::None // learners and witnesses follow whoever leads
+                } else if meta.election_now {
+                    CheckerAction::StartElection
+                } else if  meta.vote_for == None && timeout_elapsed > 0 { // TODO: in my test sometimes timeout_elapsed may go 1 for no reason, require investigation
+                    //Timeout, require election
+                    //debug!("TIMEOUT!!! GOING TO CANDIDATE!!! {}, {}", server_id, timeout_elapsed);
+                    CheckerAction::BecomeCandidate
+                } else {
+                    CheckerAction::None
+                }
+            },
+            Membership::Offline => {
+                CheckerAction::ExitLoop
+            },
+            Membership::Undefined => CheckerAction::None
+        };
+        match action {
+            CheckerAction::SendHeartbeat if server.options.check_quorum && !server.quorum_active(&meta) => {
+                warn!("{} lost the quorum in term {}, stepping down", server.id, meta.term);
+                server.step_down(&mut meta);
+            },
+            CheckerAction::SendHeartbeat => {
+                server.check_lagging(&mut meta);
+                // batches not answered are sent again, heartbeats then leave them alone
+                server.pipeline_entries(&meta);
+                if start_time - meta.last_heartbeat >= server.options.heartbeat_ms() {
+                    meta.last_heartbeat = start_time;
+                    server.send_followers_heartbeat(&mut meta, None);
+                }
+                server.advance_commit(&mut meta);
+            },
+            CheckerAction::BecomeCandidate => {
+                if server.options.pre_vote {
+                    RaftService::request_pre_votes(server.clone(), &mut meta);
+                } else {
+                    RaftService::become_candidate(server.clone(), &mut meta);
+                }
+            },
+            CheckerAction::StartElection => {
+                meta.election_now = false;
+                RaftService::become_candidate(server.clone(), &mut meta);
+            },
+            CheckerAction::ExitLoop => {
+                return false;
+            },
+            CheckerAction::None => {}
+        }
+        server.finish_compaction(&mut meta, false);
+        if let Some(ref store) = meta.store {
+            // batched syncs are due even when nothing is appended
+            if let Err(e) = store.lock().sync_due() {
+                error!("Cannot sync raft log, {}", e);
+            }
+        }
+        true
+    }
     pub fn new_server(opts: Options) -> (bool, Arc<RaftService>, Arc<Server>) {
         let address = opts.address.clone();
         let svr_id = opts.service_id;
+        let server = Server::new_with_options(&address, tcp::server::ServerOptions {
+            transport: rpc::default_transport(),
+            auth_token: opts.auth_token.clone(),
+            .. tcp::server::ServerOptions::default()
+        });
         let service = RaftService::new(opts);
-        let server = Server::new(&address);
         Server::listen_and_resume(&server);
         server.register_service(svr_id, &service);
         (RaftService::start(&service), service, server)
     }
-    pub fn bootstrap(&self) {
+    // starts a cluster of the server alone, others join it
+    pub fn bootstrap(&self) -> Result<(), BootstrapError> {
+        if self.is_witness() {
+            warn!("Witness {} cannot bootstrap a cluster", self.id);
+            return Err(BootstrapError::Witness);
+        }
         let mut meta = self.write_meta();
+        if meta.leader_id != 0 {
+            warn!("{} cannot bootstrap a cluster, it has seen leader {}", self.id, meta.leader_id);
+            return Err(BootstrapError::SeenLeader(meta.leader_id));
+        }
         let (last_log_id, _) = {
             let logs = meta.logs.read();
-            get_last_log_info!(self, logs)
+            get_last_log_info!(self, meta, logs)
         };
+        let has_members = members_from_meta!(meta).len() > 1;
+        if last_log_id > 0 || meta.term > 0 || meta.vote_for.is_some() || has_members {
+            warn!("{} cannot bootstrap a cluster, it has state of one", self.id);
+            return Err(BootstrapError::ExistingState);
+        }
         self.become_leader(&mut meta, last_log_id);
+        Ok(())
     }
     pub fn join(&self, servers: &Vec<String>)
         -> Result<Result<(), ()>, ExecError> {
@@ -354,7 +1305,7 @@ impl RaftService {
         if let Ok(client) = client {
             let result = client.execute(
                 CONFIG_SM_ID,
-                &new_member_::new(&self.options.address)
+                &new_member_::new(&self.options.address, &self.member_role())
             );
             let members = client.execute(
                 CONFIG_SM_ID,
@@ -373,10 +1324,60 @@ impl RaftService {
             Err(ExecError::CannotConstructClient)
         }
     }
-    pub fn leave(&self) -> bool {
-        let servers = self.cluster_info().members.iter()
-            .map(|&(_, ref address)|{
-                address.clone()
+    // the server at the address is replicated to as a learner, it can be promoted once caught up
+    pub fn add_learner(&self, address: &String) -> Result<Result<(), ()>, ExecError> {
+        match RaftClient::new(&vec!(self.options.address.clone()), self.options.service_id) {
+            Ok(client) => client.add_learner(address),
+            Err(_) => Err(ExecError::CannotConstructClient)
+        }
+    }
+    // only the leader knows how far behind the learner is
+    pub fn promote_learner(&self, id: u64) -> Result<(), PromoteError> {
+        let address = {
+            let meta = self.meta.read();
+            let (address, is_voter) = match members_from_meta!(meta).get(&id) {
+                Some(member) => (member.address.clone(), member.is_voter()),
+                None => return Err(PromoteError::UnknownMember)
+            };
+            if is_voter {
+                return Err(PromoteError::NotLearner);
+            }
+            let follower = match meta.membership {
+                Membership::Leader(ref leader_meta) => leader_meta.read().followers.get(&id).cloned(),
+                _ => return Err(PromoteError::NotLeader(meta.leader_id))
+            };
+            let match_index = follower.map(|follower| follower.status.lock().match_index).unwrap_or(0);
+            let lag = meta.commit_index.saturating_sub(match_index);
+            if lag > self.options.max_learner_lag {
+                return Err(PromoteError::LaggingBehind(lag));
+            }
+            address
+        };
+        let cmd = new_member_::new(&address, &MemberRole::Voter);
+        let (fn_id, _, data) = cmd.encode();
+        let entry = LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: CONFIG_SM_ID,
+            fn_id: fn_id,
+            data: data.clone(),
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0,
+        };
+        match self.c_command(&entry) {
+            Ok(ClientCmdResponse::Success { data: Ok(_), .. }) => Ok(()),
+            Ok(ClientCmdResponse::NotLeader { leader_hint }) => {
+                Err(PromoteError::NotLeader(leader_hint.map(|(leader_id, _)| leader_id).unwrap_or(0)))
+            },
+            _ => Err(PromoteError::NotCommitted)
+        }
+    }
+    pub fn leave(&self) -> bool {
+        let servers = self.cluster_info().members.iter()
+            .map(|&(_, ref address)|{
+                address.clone()
             }).collect();
         if let Ok(client) = RaftClient::new(&servers, self.options.service_id) {
             client.execute(
@@ -392,11 +1393,57 @@ impl RaftService {
                 return false;
             }
         }
-        meta.membership = Membership::Offline;
-        let mut sm = meta.state_machine.write();
-        sm.clear_subs();
+        self.go_offline(&mut meta);
         return true;
     }
+    // decommissions the server. leaders hand over to the most up to date voter first, so the cluster
+    // does not wait for an election. the server stops campaigning once the removal is committed
+    pub fn leave_cluster(&self) -> Result<(), LeaveError> {
+        if self.is_leader() {
+            let successor = self.successor().ok_or(LeaveError::NoSuccessor)?;
+            self.transfer_leadership(successor).map_err(LeaveError::Transfer)?;
+        }
+        let servers: Vec<String> = self.cluster_info().members.into_iter()
+            .filter(|&(id, _)| id != self.id)
+            .map(|(_, address)| address)
+            .collect();
+        let client = RaftClient::new(&servers, self.options.service_id)
+            .map_err(|_| LeaveError::CannotConstructClient)?;
+        match client.execute(CONFIG_SM_ID, &del_member_::new(&self.options.address)) {
+            Ok(Ok(())) => {},
+            Ok(Err(())) => return Err(LeaveError::NotRemoved(ExecError::Unknown)),
+            Err(e) => return Err(LeaveError::NotRemoved(e))
+        }
+        let mut meta = self.write_meta();
+        self.go_offline(&mut meta);
+        // peers are forgotten, their connections go with them
+        let id = self.id;
+        meta.state_machine.write().configs.members.retain(|member_id, _| *member_id == id);
+        Ok(())
+    }
+    // the checker stops, the server neither campaigns nor answers for the leader
+    fn go_offline(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        meta.membership = Membership::Offline;
+        // clients asking the server for the leader look elsewhere
+        meta.leader_id = 0;
+        self.observe(meta);
+        meta.state_machine.write().clear_subs();
+    }
+    // the voter with data the leader replicated the most to
+    fn successor(&self) -> Option<u64> {
+        let meta = self.meta.read();
+        let leader_meta = match meta.membership {
+            Membership::Leader(ref leader_meta) => leader_meta.read(),
+            _ => return None
+        };
+        let sm = meta.state_machine.read();
+        sm.configs.members.values()
+            .filter(|member| member.id != self.id && member.is_voter() && !member.is_witness())
+            .filter_map(|member| leader_meta.followers.get(&member.id)
+                .map(|follower| (follower.status.lock().match_index, member.id)))
+            .max()
+            .map(|(_, id)| id)
+    }
     pub fn cluster_info(&self) -> ClientClusterInfo {
         let meta = self.meta.read();
         let logs = meta.logs.read();
@@ -406,14 +1453,110 @@ impl RaftService {
         for (id, member) in sm_members.iter(){
             members.push((*id, member.address.clone()))
         }
-        let (last_log_id, last_log_term) = get_last_log_info!(self, logs);
+        let (last_log_id, last_log_term) = get_last_log_info!(self, meta, logs);
+        let follower_lags = follower_lags(&meta, last_log_id);
         ClientClusterInfo{
             members: members,
             last_log_id: last_log_id,
             last_log_term: last_log_term,
             leader_id: meta.leader_id,
+            metrics: self.metrics.snapshot(follower_lags.iter().cloned().collect()),
+            follower_lags: follower_lags,
         }
     }
+    pub fn metrics(&self) -> RaftMetricsSnapshot {
+        let meta = self.meta.read();
+        let last_log_id = {
+            let logs = meta.logs.read();
+            get_last_log_info!(self, meta, logs).0
+        };
+        self.metrics.snapshot(follower_lags(&meta, last_log_id).into_iter().collect())
+    }
+    pub fn status(&self) -> NodeStatus {
+        let meta = self.meta.read();
+        let (last_log_id, last_log_term) = {
+            let logs = meta.logs.read();
+            get_last_log_info!(self, meta, logs)
+        };
+        let sm = meta.state_machine.read();
+        let role = self.role_of(&meta.membership, &sm);
+        let mut members: Vec<MemberStatus> = sm.members().values().map(|member| {
+            let match_index = match meta.membership {
+                Membership::Leader(ref leader_meta) => {
+                    if member.id == self.id {
+                        Some(last_log_id)
+                    } else {
+                        leader_meta.read().followers.get(&member.id)
+                            .map(|follower| follower.status.lock().match_index)
+                    }
+                },
+                _ => None
+            };
+            MemberStatus {
+                id: member.id,
+                address: member.address.clone(),
+                role: member.role,
+                match_index: match_index,
+            }
+        }).collect();
+        members.sort_by_key(|member| member.id);
+        NodeStatus {
+            id: self.id,
+            address: self.options.address.clone(),
+            role: role,
+            term: meta.term,
+            leader_id: meta.leader_id,
+            last_log_id: last_log_id,
+            last_log_term: last_log_term,
+            commit_index: meta.commit_index,
+            last_applied: meta.last_applied,
+            snapshot_id: meta.snapshot.as_ref().map(|snapshot| snapshot.last_included_id),
+            members: members,
+            uptime_ms: get_time() - self.started_at,
+            proposal_queue: proposal_queue(&meta),
+        }
+    }
+    fn role_of(&self, membership: &Membership, sm: &MasterStateMachine) -> Role {
+        match *membership {
+            Membership::Leader(_) => Role::Leader,
+            Membership::Follower if self.is_witness() => Role::Witness,
+            Membership::Follower if sm.configs.is_voter(self.id) => Role::Follower,
+            Membership::Follower => Role::Learner,
+            Membership::Candidate => Role::Candidate,
+            Membership::Offline => Role::Offline,
+            Membership::Undefined => Role::Undefined,
+        }
+    }
+    // observers start with the current role, leader and members, then hear about changes
+    pub fn register_observer(&self, observer: Box<RaftObserver>) {
+        let mut meta = self.write_meta();
+        let observed = self.observed(&meta);
+        meta.observers.register(observer, observed);
+    }
+    fn observed(&self, meta: &RaftMeta) -> Observed {
+        let sm = meta.state_machine.read();
+        let mut members: Vec<MemberStatus> = sm.members().values().map(|member| MemberStatus {
+            id: member.id,
+            address: member.address.clone(),
+            role: member.role,
+            match_index: None,
+        }).collect();
+        members.sort_by_key(|member| member.id);
+        Observed {
+            role: self.role_of(&meta.membership, &sm),
+            term: meta.term,
+            leader_id: meta.leader_id,
+            commit_index: meta.commit_index,
+            snapshot_id: meta.snapshot.as_ref().map(|snapshot| snapshot.last_included_id),
+            members: members,
+        }
+    }
+    // only queues the changes, observers are called on their own thread
+    fn observe(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        if !meta.observers.observing() {return;}
+        let observed = self.observed(meta);
+        meta.observers.update(observed);
+    }
     pub fn num_members(&self) -> usize {
         let meta = self.meta.read();
         let ref members = members_from_meta!(meta);
@@ -424,15 +1567,153 @@ impl RaftService {
         let logs = meta.logs.read();
         logs.len()
     }
+    // compacted entries included
     pub fn last_log_id(&self) -> Option<u64> {
+        let meta = self.meta.read();
+        match last_log_id(&meta) {
+            0 => None,
+            id => Some(id)
+        }
+    }
+    pub fn last_applied(&self) -> u64 {
+        self.meta.read().last_applied
+    }
+    // true once entries up to the index are applied here, false on timeout
+    pub fn wait_applied(&self, index: u64, timeout: Duration) -> bool {
+        let applied = self.meta.read().applied.clone();
+        applied.wait(index, timeout)
+    }
+    pub fn last_snapshot_id(&self) -> Option<u64> {
+        let meta = self.meta.read();
+        meta.snapshot.as_ref().map(|snapshot| snapshot.last_included_id)
+    }
+    // callback subscriptions the server has of every client, only the leader notifies them
+    pub fn num_subscriptions(&self) -> usize {
+        let meta = self.meta.read();
+        let sm = meta.state_machine.read();
+        let subscriptions = sm.configs.subscriptions.read();
+        subscriptions.num_subscriptions()
+    }
+    // bytes of the state machines in the last snapshot
+    pub fn last_snapshot_size(&self) -> usize {
+        let meta = self.meta.read();
+        meta.snapshot.as_ref().map(|snapshot| snapshot.data.len()).unwrap_or(0)
+    }
+    // entries the server keeps in the range, those before it are in the snapshot
+    pub fn dump_log(&self, range: std::ops::Range<u64>) -> Vec<EntryMeta> {
+        if range.start >= range.end {
+            return Vec::new();
+        }
         let meta = self.meta.read();
         let logs = meta.logs.read();
-        logs.keys().cloned().last()
+        logs.range((Included(range.start), Excluded(range.end))).map(|(_, entry)| EntryMeta::of(entry)).collect()
+    }
+    pub fn entry_payload(&self, index: u64) -> Option<LogEntry> {
+        let meta = self.meta.read();
+        let entry = meta.logs.read().get(&index).cloned();
+        entry
+    }
+    // digest of the entries kept in the range, members keeping the same entries have the same one
+    pub fn compare_digest(&self, range: std::ops::Range<u64>) -> u64 {
+        log_digest(&self.dump_log(range))
+    }
+    fn admin_allowed(&self) -> Result<(), AdminError> {
+        if self.options.auth_token.is_some() || self.options.admin_without_auth {
+            Ok(())
+        } else {
+            Err(AdminError::Unauthorized)
+        }
+    }
+    // compact the log up to the last applied entry, regardless of the limits in options. it waits
+    // for state machines snapshotting in the background
+    pub fn compact_now(&self) -> bool {
+        let mut meta = self.write_meta();
+        self.compact(&mut meta, true)
+    }
+    // hands leadership over to the member once it has every entry of the leader
+    pub fn transfer_leadership(&self, target_id: u64) -> Result<(), TransferError> {
+        let (term, rpc) = {
+            let mut meta = self.write_meta();
+            if !is_leader(&meta) {
+                return Err(TransferError::NotLeader(meta.leader_id));
+            }
+            if target_id == self.id {
+                return Ok(());
+            }
+            let rpc = match members_from_meta!(meta).get(&target_id) {
+                Some(member) if member.is_witness() => return Err(TransferError::Witness),
+                Some(member) if member.is_voter() => member.control_rpc.clone(),
+                Some(_) => return Err(TransferError::NotVoter),
+                None => return Err(TransferError::UnknownMember)
+            };
+            meta.transferring_to = Some(target_id);
+            clear_lease(&meta);
+            (meta.term, rpc)
+        };
+        let deadline = get_time() + self.options.max_election_timeout_ms();
+        let mut timeout_sent = false;
+        let mut result = Err(TransferError::Timeout);
+        loop {
+            let (handed_over, follower, last_log_id) = {
+                let meta = self.meta.read();
+                let handed_over = match meta.membership {
+                    Membership::Leader(_) => meta.term != term,
+                    _ => true
+                };
+                let follower = match meta.membership {
+                    Membership::Leader(ref leader_meta) => leader_meta.read().followers.get(&target_id).cloned(),
+                    _ => None
+                };
+                (handed_over, follower, last_log_id(&meta))
+            };
+            if handed_over {
+                result = Ok(());
+                break;
+            }
+            if get_time() > deadline {
+                break;
+            }
+            // heartbeats of the checker bring the target up to date
+            let caught_up = follower.map(|follower| follower.status.lock().match_index >= last_log_id).unwrap_or(false);
+            if caught_up && !timeout_sent {
+                timeout_sent = match rpc.timeout_now(&term, &self.id) {
+                    Ok(Ok(accepted)) => accepted,
+                    _ => false
+                };
+            }
+            thread::sleep(Duration::from_millis(CHECKER_MS as u64));
+        }
+        {
+            let mut meta = self.write_meta();
+            meta.transferring_to = None;
+            // the target may still be campaigning, only heartbeats acked from now on extend the lease
+            clear_lease(&meta);
+        }
+        debug!("Leadership transfer from {} to {}: {:?}", self.id, target_id, result);
+        result
     }
     pub fn leader_id(&self) -> u64 {
         let meta = self.meta.read();
         meta.leader_id
     }
+    pub fn term(&self) -> u64 {
+        let meta = self.meta.read();
+        meta.term
+    }
+    // LeaderLease reads are served without a round of heartbeats
+    pub fn has_lease(&self) -> bool {
+        let meta = self.write_meta();
+        self.lease_valid(&meta)
+    }
+    fn member_role(&self) -> MemberRole {
+        match self.options.role {
+            NodeRole::Data => MemberRole::Voter,
+            NodeRole::Witness => MemberRole::Witness,
+        }
+    }
+    pub fn is_witness(&self) -> bool {
+        self.options.role == NodeRole::Witness
+    }
     pub fn is_leader(&self) -> bool {
         let meta = self.meta.read();
         match meta.membership {
@@ -441,6 +1722,10 @@ impl RaftService {
         }
     }
     pub fn register_state_machine(&self, state_machine: SubStateMachine) {
+        if self.is_witness() {
+            warn!("Witness {} does not keep state machine {}", self.id, state_machine.id());
+            return;
+        }
         let meta = self.meta.read();
         let mut master_sm = meta.state_machine.write();
         master_sm.register(state_machine);
@@ -448,13 +1733,18 @@ impl RaftService {
     fn switch_membership(&self, meta: &mut RwLockWriteGuard<RaftMeta>, membership: Membership) {
         self.reset_last_checked(meta);
         meta.membership = membership;
+        self.observe(meta);
     }
-    fn get_log_info_(&self, log: Option<(&u64, &LogEntry)>) -> (u64, u64) {
+    fn get_log_info_(&self, log: Option<(&u64, &LogEntry)>, snapshot: &Option<Arc<Snapshot>>) -> (u64, u64) {
         match log {
             Some((last_log_id, last_log_item)) => {
                 (*last_log_id, last_log_item.term)
             },
-            None => (0, 0)
+            None => match *snapshot {
+                // all entries are compacted
+                Some(ref snapshot) => (snapshot.last_included_id, snapshot.last_included_term),
+                None => (0, 0)
+            }
         }
     }
     fn insert_leader_follower_meta(
@@ -466,10 +1756,15 @@ impl RaftService {
         // the leader itself will not be consider as a follower when sending heartbeat
         if member_id == self.id {return;}
         leader_meta.followers.entry(member_id).or_insert_with(|| {
-            Arc::new(Mutex::new(FollowerStatus {
-                next_index: last_log_id + 1,
-                match_index: 0
-            }))
+            Arc::new(Follower {
+                status: Mutex::new(FollowerStatus {
+                    next_index: last_log_id + 1,
+                    match_index: 0,
+                    in_flight: 0,
+                    lagging: false,
+                }),
+                heartbeat: Mutex::new(()),
+            })
         });
     }
     fn reload_leader_meta(
@@ -497,15 +1792,23 @@ impl RaftService {
         let term = meta.term;
         alter_term(meta, term + 1);
         meta.vote_for = Some(server.id);
+        if !persist_state(meta) {
+            // without the vote on disk it may vote for another candidate in the same term after restarts
+            meta.term = term;
+            meta.vote_for = None;
+            return;
+        }
+        server.metrics.election_started();
         server.switch_membership(meta, Membership::Candidate);
         let term = meta.term;
         let id = server.id;
         let logs = meta.logs.read();
-        let (last_log_id, last_log_term) = get_last_log_info!(server, logs);
+        let (last_log_id, last_log_term) = get_last_log_info!(server, meta, logs);
         let (tx, rx) = channel();
         let mut members = 0;
         for member in members_from_meta!(meta).values() {
-            let rpc = member.rpc.clone();
+            if !member.is_voter() {continue;}
+            let rpc = member.control_rpc.clone();
             let tx = tx.clone();
             members += 1;
             if member.id == server.id {
@@ -541,6 +1844,7 @@ impl RaftService {
                             granted += 1;
                             if is_majority(members, granted) {
                                 server.become_leader(&mut meta, last_log_id);
+                                server.append_noop(&mut meta);
                                 break;
                             }
                         },
@@ -554,6 +1858,59 @@ impl RaftService {
         });
     }
 
+    // asks for votes without changing the term, the election only starts when it could be won
+    fn request_pre_votes(server: Arc<RaftService>, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        server.reset_last_checked(meta);
+        let term = meta.term;
+        let id = server.id;
+        let (last_log_id, last_log_term) = {
+            let logs = meta.logs.read();
+            get_last_log_info!(server, meta, logs)
+        };
+        let (tx, rx) = channel();
+        let mut members = 0;
+        for member in members_from_meta!(meta).values() {
+            if !member.is_voter() {continue;}
+            let rpc = member.control_rpc.clone();
+            let tx = tx.clone();
+            members += 1;
+            if member.id == server.id {
+                tx.send(true);
+            } else {
+                meta.workers.lock().execute(move||{
+                    if let Ok(Ok(granted)) = rpc.pre_vote(&(term + 1), &id, &last_log_id, &last_log_term) {
+                        tx.send(granted);
+                    }
+                });
+            }
+        }
+        meta.workers.lock().execute(move ||{
+            let mut granted = 0;
+            let mut timeout = 2000;
+            for _ in 0..members {
+                if timeout <= 0 {break;}
+                let curr_time = get_time();
+                if let Ok(true) = rx.recv_timeout(Duration::from_millis(timeout as u64)) {
+                    granted += 1;
+                    if is_quorum(members, granted) {
+                        let mut meta = server.meta.write();
+                        let waiting = match meta.membership {
+                            Membership::Follower | Membership::Candidate => true,
+                            _ => false
+                        };
+                        // a leader may have shown up or the server voted in the meantime
+                        if waiting && meta.term == term && meta.vote_for.is_none() {
+                            RaftService::become_candidate(server.clone(), &mut meta);
+                        }
+                        break;
+                    }
+                }
+                timeout -= get_time() - curr_time;
+            }
+            debug!("PRE VOTES GRANTED: {}/{}", granted, members);
+        });
+    }
+
     fn become_follower(&self, meta: &mut RwLockWriteGuard<RaftMeta>, term: u64, leader_id: u64) {
         alter_term(meta, term);
         meta.leader_id = leader_id;
@@ -561,19 +1918,167 @@ impl RaftService {
     }
 
     fn become_leader(&self, meta: &mut RwLockWriteGuard<RaftMeta>, last_log_id: u64) {
-        let leader_meta = RwLock::new(LeaderMeta::new());
+        let leader_meta = RwLock::new(LeaderMeta::new(self.clock.now()));
         {
             let mut guard = leader_meta.write();
             self.reload_leader_meta(&members_from_meta!(meta), &mut guard, last_log_id);
-            guard.last_updated = get_time();
+            guard.last_updated = self.clock.now();
         }
         meta.leader_id = self.id;
+        meta.config_change_id = None;
+        self.metrics.election_won();
         self.switch_membership(meta, Membership::Leader(leader_meta));
     }
 
+    // a majority of voters answered the leader within the maximum election timeout,
+    // or it has not been leading for that long
+    fn quorum_active(&self, meta: &RwLockWriteGuard<RaftMeta>) -> bool {
+        let leader_meta = match meta.membership {
+            Membership::Leader(ref leader_meta) => leader_meta.read(),
+            _ => return false
+        };
+        let since = self.clock.now() - self.options.max_election_timeout_ms();
+        if leader_meta.elected_at > since {
+            return true;
+        }
+        let lease = leader_meta.lease.lock();
+        let mut voters = 1; // the leader itself
+        let mut answered = 1;
+        for member in members_from_meta!(meta).values() {
+            if member.id == self.id || !member.is_voter() {continue;}
+            voters += 1;
+            if lease.acked.get(&member.id).map(|sent| *sent > since).unwrap_or(false) {
+                answered += 1;
+            }
+        }
+        is_quorum(voters, answered)
+    }
+
+    // clients waiting for entries of the term are told, the entries may still be committed by the next leader
+    fn step_down(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        if let Membership::Leader(ref leader_meta) = meta.membership {
+            for (_, proposal) in leader_meta.write().proposals.drain() {
+                proposal.send(Err(ExecError::LostLeadership)).ok();
+            }
+        }
+        let term = meta.term;
+        self.become_follower(meta, term, 0);
+    }
+
+    // entries of earlier terms cannot be committed by counting replicas, they would wait
+    // for the next command without one of the new term
+    fn append_noop(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        let mut entry = LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: NOOP_SM_ID,
+            fn_id: 0,
+            data: Vec::new(),
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0,
+        };
+        if self.append_log(meta, &mut entry).is_none() {
+            error!("{} cannot append the no-op entry of term {}", self.id, meta.term);
+            return;
+        }
+        self.pipeline_entries(meta);
+        self.advance_commit(meta);
+    }
+
     fn send_followers_heartbeat(&self, meta: &mut RwLockWriteGuard<RaftMeta>, log_id: Option<u64>) -> bool {
+        // nothing waits for the answers without a log id, idle followers can wait for the batch
+        let (rx, members, voters) = self.replicate_to_followers(meta, log_id.is_none());
+        match log_id {
+            Some(log_id) => {
+                if let Membership::Leader(ref leader_meta) = meta.membership{
+                    let mut leader_meta = leader_meta.write();
+                    let mut updated_followers = 0;
+                    let mut timeout = 2000 as i64; // assume client timeout is more than 2s　(5 by default)
+                    for _ in 0..members {
+                        if timeout <= 0 {break;}
+                        if let Ok((is_voter, _, last_matched_id)) = rx.recv_timeout(Duration::from_millis(timeout as u64)) { // adaptive
+                            //println!("{}, {}", last_matched_id, log_id);
+                            // learners do not count for commitment
+                            if is_voter && last_matched_id >= log_id {
+                                updated_followers += 1;
+                                if is_majority(voters, updated_followers) {break;}
+                            }
+                        }
+                        let current_time = get_time();
+                        timeout -= get_time() - current_time;
+                    }
+                    leader_meta.last_updated = self.clock.now();
+                    is_majority(voters, updated_followers)
+                } else {false}
+            },
+            None => true
+        }
+    }
+
+    // a majority of voters answered a round of heartbeats in this term,
+    // no other leader can have committed anything the leader does not have
+    fn confirm_leadership(&self, meta: &mut RwLockWriteGuard<RaftMeta>) -> bool {
+        let started = get_time();
+        let (rx, members, voters) = self.replicate_to_followers(meta, false);
+        let mut acked_voters = 0;
+        for _ in 0..members {
+            // count the leader itself
+            if is_quorum(voters + 1, acked_voters + 1) {break;}
+            let timeout = self.options.max_election_timeout_ms() - (get_time() - started);
+            if timeout <= 0 {break;}
+            if let Ok((is_voter, acked, _)) = rx.recv_timeout(Duration::from_millis(timeout as u64)) {
+                if is_voter && acked {acked_voters += 1;}
+            }
+        }
+        is_quorum(voters + 1, acked_voters + 1)
+    }
+
+    // a quorum of voters acked heartbeats sent within the lease duration. transfers hand leadership
+    // over without waiting for an election timeout, so there is no lease during them and heartbeats
+    // acked before count no more
+    fn lease_valid(&self, meta: &RwLockWriteGuard<RaftMeta>) -> bool {
+        if !self.options.lease_reads || meta.transferring_to.is_some() {
+            return false;
+        }
+        let leader_meta = match meta.membership {
+            Membership::Leader(ref leader_meta) => leader_meta.read(),
+            _ => return false
+        };
+        let lease = leader_meta.lease.lock();
+        if lease.revoked {
+            return false;
+        }
+        let mut voters = 1; // the leader itself
+        let mut acked = Vec::new();
+        for member in members_from_meta!(meta).values() {
+            if member.id == self.id || !member.is_voter() {continue;}
+            voters += 1;
+            if let Some(sent) = lease.acked.get(&member.id) {
+                acked.push(*sent);
+            }
+        }
+        let followers_needed = voters / 2;
+        if followers_needed == 0 {
+            return true;
+        }
+        if acked.len() < followers_needed {
+            return false;
+        }
+        acked.sort_by(|a, b| b.cmp(a));
+        let lease_start = acked[followers_needed - 1];
+        let duration = (self.options.min_election_timeout_ms() as f64 * self.options.lease_safety_factor) as i64;
+        self.clock.now() < lease_start + duration
+    }
+
+    // each follower reports whether it is a voter, whether it answered in this term and its match index.
+    // batched heartbeats of groups are answered once their multi raft server sent the batch
+    fn replicate_to_followers(&self, meta: &mut RwLockWriteGuard<RaftMeta>, batched: bool) -> (Receiver<(bool, bool, u64)>, u64, u64) {
         let (tx, rx) = channel();
         let mut members = 0;
+        let mut voters = 0;
+        let last_id = last_log_id(meta);
         let commit_index = meta.commit_index;
         let term = meta.term;
         let leader_id = meta.leader_id;
@@ -586,7 +2091,16 @@ impl RaftService {
                     if id == self.id {continue;}
                     let tx = tx.clone();
                     let logs = meta.logs.clone();
+                    let snapshot = meta.snapshot.clone();
+                    let chunk_size = self.options.compaction.snapshot_chunk_size;
+                    let is_voter = member.is_voter();
                     let rpc = member.rpc.clone();
+                    let control_rpc = member.control_rpc.clone();
+                    let lease = leader_meta.lease.clone();
+                    let progress = leader_meta.progress.clone();
+                    let clock = self.clock.clone();
+                    let max_batch_bytes = self.options.replication.max_batch_bytes;
+                    let max_batch_entries = self.options.replication.max_batch_entries;
                     let follower = {
                         if let Some(follower) = leader_meta.followers.get(&id) {
                             follower.clone()
@@ -599,19 +2113,132 @@ impl RaftService {
                             continue;
                         }
                     };
+                    self.metrics.heartbeat_sent();
+                    if batched {
+                        if let Some(ref group) = self.group {
+                            let prev = idle_heartbeat(&follower, &logs.read(), &snapshot, last_id);
+                            if let Some((prev_log_id, prev_log_term)) = prev {
+                                let sent = clock.now();
+                                let beat = Heartbeat {
+                                    group_id: group.id,
+                                    term: term,
+                                    leader_id: leader_id,
+                                    prev_log_id: prev_log_id,
+                                    prev_log_term: prev_log_term,
+                                    leader_commit: commit_index,
+                                };
+                                group.heartbeats.push(&member.address, beat, Box::new(move |answer: Option<(u64, AppendEntriesResult)>| {
+                                    let acked = match answer {
+                                        Some((follower_term, AppendEntriesResult::TermOut(_))) => {
+                                            if follower_term > term {
+                                                lease.lock().revoked = true;
+                                            }
+                                            false
+                                        },
+                                        Some((_, AppendEntriesResult::Ok)) => {
+                                            follower.status.lock().matched(prev_log_id);
+                                            true
+                                        },
+                                        Some((_, AppendEntriesResult::LogMismatch)) => {
+                                            follower.status.lock().retry_from(prev_log_id);
+                                            true
+                                        },
+                                        Some((_, AppendEntriesResult::Corrupted)) => true,
+                                        None => false
+                                    };
+                                    if acked {
+                                        record_ack(&lease, id, sent);
+                                    }
+                                    progress.answer();
+                                    let match_index = follower.status.lock().match_index;
+                                    tx.send((is_voter, acked, match_index));
+                                }));
+                                members += 1;
+                                if is_voter {voters += 1;}
+                                continue;
+                            }
+                        }
+                    }
                     workers.execute(move||{
-                        let mut follower = follower.lock();
+                        // one heartbeat worker at a time, batches of the pipeline go along
+                        let _heartbeat = match follower.heartbeat.try_lock() {
+                            Some(heartbeat) => heartbeat,
+                            None => {
+                                // the last one is still sending a snapshot or a batch, do not pile up behind it.
+                                // the follower still hears from the leader on the control connection
+                                let match_index = follower.status.lock().match_index;
+                                let (prev_log_id, prev_log_term) = keepalive_prev(&logs.read(), &snapshot, match_index);
+                                let sent = clock.now();
+                                let keepalive_result = control_rpc.append_entries(
+                                    &term,
+                                    &leader_id,
+                                    &prev_log_id,
+                                    &prev_log_term,
+                                    &None,
+                                    &min(commit_index, match_index)
+                                );
+                                // the worker holding the lock moves the follower on, answers only tell it is alive
+                                let acked = match keepalive_result {
+                                    Ok(Ok((follower_term, AppendEntriesResult::TermOut(_)))) => {
+                                        if follower_term > term {
+                                            lease.lock().revoked = true;
+                                        }
+                                        false
+                                    },
+                                    Ok(Ok(_)) => {
+                                        record_ack(&lease, id, sent);
+                                        true
+                                    },
+                                    _ => false
+                                };
+                                tx.send((is_voter, acked, match_index));
+                                return;
+                            }
+                        };
                         let mut is_retry = false;
-                        let logs = logs.read();
+                        let mut acked = false;
                         loop {
-                            let entries: Option<LogEntries> = { // extract logs to send to follower
-                                let list: LogEntries = logs.range(
-                                    (Included(&follower.next_index), Unbounded)
-                                ).map(|(_, entry)| entry.clone()).collect(); //TODO: avoid clone entry
-                                if list.is_empty() {None} else {Some(list)}
+                            let (next_index, match_index, pipelined) = {
+                                let status = follower.status.lock();
+                                (status.next_index, status.match_index, status.in_flight > 0)
+                            };
+                            if let Some(ref snapshot) = snapshot {
+                                if next_index <= snapshot.last_included_id {
+                                    // entries the follower needs are compacted
+                                    let sent = clock.now();
+                                    let install_result = send_snapshot(
+                                        &rpc, term, leader_id, snapshot, chunk_size
+                                    );
+                                    match install_result {
+                                        Some(_) => {
+                                            debug!("snapshot installed, {}", snapshot.last_included_id);
+                                            acked = true;
+                                            record_ack(&lease, id, sent);
+                                            follower.status.lock().matched(snapshot.last_included_id);
+                                            continue;
+                                        },
+                                        _ => {break;} // retry will happened in next heartbeat
+                                    }
+                                }
+                            }
+                            let (entries, follower_last_log_id, follower_last_log_term) = {
+                                let logs = logs.read();
+                                // entries the pipeline sent are on their way, only heartbeat from what the follower has
+                                // assumed log ids are sequence of integers
+                                let follower_last_log_id = if pipelined {match_index} else {next_index - 1};
+                                let entries = if pipelined {
+                                    None
+                                } else {
+                                    batch_entries(&logs, next_index, max_batch_bytes, max_batch_entries)
+                                };
+                                match prev_log_info(&logs, &snapshot, follower_last_log_id) {
+                                    Some((prev_id, prev_term)) => (entries, prev_id, prev_term),
+                                    None if pipelined => break, // compacted meanwhile, the next heartbeat finds out
+                                    None => panic!("Cannot find old logs for follower, first_id: {}, follower_last: {}")
+                                }
                             };
                             if is_retry && entries.is_none() { // break when retry and there is no entry
-                                debug!("stop retry when entry is empty, {}", follower.next_index);
+                                debug!("stop retry when entry is empty, {}", next_index);
                                 break;
                             }
                             let last_entries_id = match &entries { // get last entry id
@@ -620,29 +2247,10 @@ impl RaftService {
                                 },
                                 &None => None
                             };
-                            let (follower_last_log_id, follower_last_log_term) = { // extract follower last log info
-                                // assumed log ids are sequence of integers
-                                let follower_last_log_id = follower.next_index - 1;
-                                if follower_last_log_id == 0 || logs.is_empty() {
-                                    (0, 0) // 0 represents there is no logs in the leader
-                                } else {
-                                    // detect cleaned logs
-                                    let (first_log_id, _) = logs.iter().next().unwrap();
-                                    if *first_log_id > follower_last_log_id {
-                                        panic!("TODO: deal with snapshot or other situations may remove old logs {}, {}", *first_log_id, follower_last_log_id)
-                                    }
-                                    let follower_last_entry = logs.get(&follower_last_log_id);
-                                    match follower_last_entry {
-                                        Some(entry) => {
-                                            (entry.id, entry.term)
-                                        },
-                                        None => {
-                                            panic!("Cannot find old logs for follower, first_id: {}, follower_last: {}");
-                                        }
-                                    }
-                                }
-                            };
-                            let append_result = rpc.append_entries(
+                            // heartbeats without entries never wait behind batches of the pipeline
+                            let lane = if entries.is_none() {&control_rpc} else {&rpc};
+                            let sent = clock.now();
+                            let append_result = lane.append_entries(
                                 &term,
                                 &leader_id,
                                 &follower_last_log_id,
@@ -655,16 +2263,29 @@ impl RaftService {
                                     match result {
                                         AppendEntriesResult::Ok => {
                                             debug!("log updated");
-                                            if let Some(last_entries_id) = last_entries_id {
-                                                follower.next_index = last_entries_id + 1;
-                                                follower.match_index = last_entries_id;
-                                            }
+                                            acked = true;
+                                            record_ack(&lease, id, sent);
+                                            // the follower agrees with the leader up to the last entry sent
+                                            let matched_id = last_entries_id.unwrap_or(follower_last_log_id);
+                                            follower.status.lock().matched(matched_id);
                                         },
                                         AppendEntriesResult::LogMismatch => {
-                                            debug!("log mismatch, {}", follower.next_index);
-                                            follower.next_index -= 1;
+                                            debug!("log mismatch, {}", next_index);
+                                            acked = true;
+                                            record_ack(&lease, id, sent);
+                                            follower.status.lock().retry_from(follower_last_log_id);
+                                        },
+                                        AppendEntriesResult::Corrupted => {
+                                            // sent again with the next heartbeat
+                                            error!("entries to {} from {} were corrupted", id, next_index);
+                                            acked = true;
+                                            record_ack(&lease, id, sent);
+                                            break;
                                         },
                                         AppendEntriesResult::TermOut(actual_leader_id) => {
+                                            if follower_term > term {
+                                                lease.lock().revoked = true;
+                                            }
 //                                            let actual_leader = actual_leader_id.clone();
 //                                            println!(
 //                                                "term out, new term from follower is {} but this leader is {}",
@@ -676,39 +2297,230 @@ impl RaftService {
                                 },
                                 _ => {break;} // retry will happened in next heartbeat
                             }
+                            if pipelined {break;}
                             is_retry = true;
                         } // append entries to followers
-                        tx.send(follower.match_index);
+                        progress.answer();
+                        let match_index = follower.status.lock().match_index;
+                        tx.send((is_voter, acked, match_index));
                     });
                     members += 1;
+                    if is_voter {voters += 1;}
                 }
             }
         }
-        match log_id {
-            Some(log_id) => {
-                if let Membership::Leader(ref leader_meta) = meta.membership{
-                    let mut leader_meta = leader_meta.write();
-                    let mut updated_followers = 0;
-                    let mut timeout = 2000 as i64; // assume client timeout is more than 2s　(5 by default)
-                    for _ in 0..members {
-                        if timeout <= 0 {break;}
-                        if let Ok(last_matched_id) = rx.recv_timeout(Duration::from_millis(timeout as u64)) { // adaptive
-                            //println!("{}, {}", last_matched_id, log_id);
-                            if last_matched_id >= log_id {
-                                updated_followers += 1;
-                                if is_majority(members, updated_followers) {break;}
+        (rx, members, voters)
+    }
+
+    // followers have the first election timeout of the leader to report what they have
+    fn check_lagging(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        let max_lag = match self.options.replication.max_follower_lag {
+            Some(max_lag) => max_lag,
+            None => return
+        };
+        let last_log_id = last_log_id(meta);
+        let mut lagging = Vec::new();
+        if let Membership::Leader(ref leader_meta) = meta.membership {
+            let leader_meta = leader_meta.read();
+            if self.clock.now() - leader_meta.elected_at < self.options.max_election_timeout_ms() {
+                return;
+            }
+            for (id, follower) in leader_meta.followers.iter() {
+                let mut status = follower.status.lock();
+                let lag = last_log_id.saturating_sub(status.match_index);
+                if !status.lagging && lag > max_lag {
+                    status.lagging = true;
+                    lagging.push((*id, lag));
+                } else if status.lagging && lag <= max_lag / 2 {
+                    debug!("Follower {} caught up, {} entries behind", id, lag);
+                    status.lagging = false;
+                }
+            }
+        }
+        if lagging.is_empty() {return;}
+        for &(id, lag) in &lagging {
+            warn!("Follower {} is {} entries behind, stop pipelining to it", id, lag);
+            meta.observers.follower_lagging(id, lag);
+        }
+        // entries it misses are not kept for it, it installs the snapshot
+        self.compact(meta, false);
+    }
+
+    // sends new entries to followers without waiting for the batches sent before to be answered
+    fn pipeline_entries(&self, meta: &RwLockWriteGuard<RaftMeta>) {
+        let leader_meta = match meta.membership {
+            Membership::Leader(ref leader_meta) => leader_meta.read(),
+            _ => return
+        };
+        let replication = self.options.replication;
+        let compacted_id = compacted_id(meta);
+        let term = meta.term;
+        let leader_id = meta.leader_id;
+        let commit_index = meta.commit_index;
+        let logs = meta.logs.read();
+        for member in members_from_meta!(meta).values() {
+            let id = member.id;
+            if id == self.id {continue;}
+            let follower = match leader_meta.followers.get(&id) {
+                Some(follower) => follower.clone(),
+                None => continue
+            };
+            loop {
+                let (entries, prev_log_id, prev_log_term) = {
+                    let mut status = follower.status.lock();
+                    if status.in_flight >= replication.max_in_flight || status.lagging {break;}
+                    // heartbeats install the snapshot first
+                    if status.next_index <= compacted_id {break;}
+                    let prev_log_id = status.next_index - 1;
+                    let prev_log_term = match prev_log_info(&logs, &meta.snapshot, prev_log_id) {
+                        Some((_, prev_log_term)) => prev_log_term,
+                        None => break
+                    };
+                    let entries = match batch_entries(
+                        &logs, status.next_index, replication.max_batch_bytes, replication.max_batch_entries
+                    ) {
+                        Some(entries) => entries,
+                        None => break
+                    };
+                    status.next_index = entries.iter().last().unwrap().id + 1;
+                    status.in_flight += 1;
+                    (entries, prev_log_id, prev_log_term)
+                };
+                let first_id = entries[0].id;
+                let last_id = entries.iter().last().unwrap().id;
+                let rpc = member.rpc.clone();
+                let lease = leader_meta.lease.clone();
+                let progress = leader_meta.progress.clone();
+                let follower = follower.clone();
+                let clock = self.clock.clone();
+                // local followers are called on the replicator, not by the leader holding its lock
+                meta.replicator.spawn_fn(move || {
+                    let sent = clock.now();
+                    rpc.append_entries_async(
+                        &term,
+                        &leader_id,
+                        &prev_log_id,
+                        &prev_log_term,
+                        &Some(entries),
+                        &commit_index
+                    ).then(move |append_result| {
+                        {
+                            let mut status = follower.status.lock();
+                            status.in_flight -= 1;
+                            match append_result {
+                                Ok(Ok((_, AppendEntriesResult::Ok))) => {
+                                    record_ack(&lease, id, sent);
+                                    status.matched(last_id);
+                                },
+                                Ok(Ok((_, AppendEntriesResult::LogMismatch))) => {
+                                    // the follower is missing the entry before, or a batch before is still on its way
+                                    record_ack(&lease, id, sent);
+                                    status.retry_from(prev_log_id);
+                                },
+                                Ok(Ok((follower_term, AppendEntriesResult::TermOut(_)))) => {
+                                    if follower_term > term {
+                                        lease.lock().revoked = true;
+                                    }
+                                    status.retry_from(first_id);
+                                },
+                                _ => status.retry_from(first_id) // not answered, send again
                             }
                         }
-                        let current_time = get_time();
-                        timeout -= get_time() - current_time;
-                    }
-                    leader_meta.last_updated = get_time();
-                    is_majority(members, updated_followers)
-                } else {false}
-            },
-            None => true
+                        progress.answer();
+                        Ok::<(), ()>(())
+                    })
+                }).forget();
+            }
+        }
+    }
+
+    // commits up to the highest entry of the term a quorum of voters has,
+    // results go to the clients proposed the entries
+    fn advance_commit(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        // entries being applied finish whether or not more are committed
+        collect_applied(meta);
+        let quorum_id = {
+            let leader_meta = match meta.membership {
+                Membership::Leader(ref leader_meta) => leader_meta.read(),
+                _ => return
+            };
+            let mut matched = Vec::new();
+            for member in members_from_meta!(meta).values() {
+                if !member.is_voter() {continue;}
+                if member.id == self.id {
+                    matched.push(last_log_id(meta));
+                } else if let Some(follower) = leader_meta.followers.get(&member.id) {
+                    matched.push(follower.status.lock().match_index);
+                } else {
+                    matched.push(0);
+                }
+            }
+            if matched.is_empty() {return;}
+            matched.sort_by(|a, b| b.cmp(a));
+            matched[matched.len() / 2]
+        };
+        if quorum_id <= meta.commit_index {return;}
+        // entries of earlier terms are committed along with one of this term
+        let in_term = meta.logs.read().get(&quorum_id).map(|entry| entry.term == meta.term).unwrap_or(false);
+        if !in_term {return;}
+        self.record_commit_latency(meta, quorum_id);
+        meta.commit_index = quorum_id;
+        check_commit(meta);
+        self.observe(meta);
+        self.config_committed(meta, quorum_id);
+        self.check_compaction(meta);
+    }
+
+    // entries of earlier leaders were timed by their clocks, only those of this term are recorded
+    fn record_commit_latency(&self, meta: &RwLockWriteGuard<RaftMeta>, quorum_id: u64) {
+        let now = self.clock.now();
+        let logs = meta.logs.read();
+        for (_, entry) in logs.range((Excluded(meta.commit_index), Included(quorum_id))) {
+            if entry.term == meta.term {
+                self.metrics.record_commit(Duration::from_millis(max(now - entry.time, 0) as u64));
+            }
         }
     }
+    // every other member answered with a version that applies batches, versions are cached by the clients
+    fn batches_supported(&self) -> bool {
+        let peers: Vec<Arc<Peer>> = {
+            let meta = self.meta.read();
+            let sm = meta.state_machine.read();
+            sm.configs.members.values()
+                .filter(|member| member.id != self.id)
+                .map(|member| member.control_rpc.clone())
+                .collect()
+        };
+        peers.iter().all(|peer| peer.server_version().map(|version| version >= BATCH_VERSION).unwrap_or(false))
+    }
+    // the command was not appended to the log
+    fn refuse(&self, response: ClientCmdResponse) -> Result<ClientCmdResponse, ()> {
+        self.metrics.proposal_rejected();
+        Ok(response)
+    }
+    // Some for committed and None for not committed
+    fn wait_committed(&self, log_id: u64, result: &Receiver<ExecResult>, progress: &Progress) -> Option<ExecResult> {
+        let deadline = get_time() + 2000; // assume client timeout is more than 2s　(5 by default)
+        loop {
+            let seen = progress.answers();
+            match result.try_recv() {
+                Ok(data) => return Some(data),
+                Err(TryRecvError::Disconnected) => return None, // no longer the leader
+                Err(TryRecvError::Empty) => {}
+            }
+            if get_time() > deadline {break;}
+            progress.wait(seen, Duration::from_millis(CHECKER_MS as u64));
+            let mut meta = self.write_meta();
+            self.advance_commit(&mut meta);
+            // batches not answered are sent again
+            self.pipeline_entries(&meta);
+        }
+        let meta = self.write_meta();
+        if let Membership::Leader(ref leader_meta) = meta.membership {
+            leader_meta.write().proposals.remove(&log_id);
+        }
+        result.try_recv().ok()
+    }
     //check term number, return reject = false if server term is stale
     fn check_term(&self, meta: &mut RwLockWriteGuard<RaftMeta>, remote_term: u64, leader_id: u64) -> bool {
         if remote_term > meta.term {
@@ -720,68 +2532,72 @@ impl RaftService {
     }
     fn reset_last_checked(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
         //println!("elapsed: {}, id: {}, term: {}", get_time() - meta.last_checked, self.id, meta.term);
-        meta.last_checked = get_time();
-        meta.timeout = gen_timeout();
+        meta.last_checked = self.clock.now();
+        meta.timeout = gen_timeout(&self.options);
     }
-    fn append_log(&self, meta: &RwLockWriteGuard<RaftMeta>, entry: &mut LogEntry) -> (u64, u64) {
+    // None when the entry cannot be written to disk
+    fn append_log(&self, meta: &RwLockWriteGuard<RaftMeta>, entry: &mut LogEntry) -> Option<(u64, u64)> {
         let mut logs = meta.logs.write();
-        let (last_log_id, last_log_term) = get_last_log_info!(self, logs);
+        let (last_log_id, last_log_term) = get_last_log_info!(self, meta, logs);
         let new_log_id = last_log_id + 1;
         let new_log_term = meta.term;
         entry.term = new_log_term;
         entry.id = new_log_id;
+        entry.time = self.clock.now();
+        entry.checksum = entry.digest();
+        if write_store(meta, |store| store.append(&[entry.clone()])).is_err() {
+            return None;
+        }
+        meta.log_bytes.fetch_add(entry_bytes(entry), Ordering::Relaxed);
         logs.insert(entry.id, entry.clone());
-        (new_log_id, new_log_term)
+        Some((new_log_id, new_log_term))
     }
-    fn try_sync_log_to_followers(
-        &self, meta: &mut RwLockWriteGuard<RaftMeta>,
-        entry: &LogEntry, new_log_id: u64
-    ) -> Option<ExecResult> {
-        if self.send_followers_heartbeat(meta, Some(new_log_id)) {
-            meta.commit_index = new_log_id;
-            Some(commit_command(meta, entry))
-        } else {
-            None
+    // the new configuration takes effect when committed, on the leader as on the followers
+    fn config_committed(&self, meta: &mut RwLockWriteGuard<RaftMeta>, log_id: u64) {
+        match meta.config_change_id {
+            Some(change_id) if change_id <= log_id => {},
+            _ => return
         }
-    }
-    fn try_sync_config_to_followers(
-        &self, meta: &mut RwLockWriteGuard<RaftMeta>,
-        entry: &LogEntry, new_log_id: u64
-    ) -> ExecResult {
-        // this will force followers to commit the changes
-        meta.commit_index = new_log_id;
-        let data = commit_command(&meta, &entry);
-        let t = get_time();
-        if let Membership::Leader(ref leader_meta) = meta.membership {//  ||| TODO: New member should install newest snapshot
+        meta.config_change_id = None;
+        if !meta.state_machine.read().configs.is_voter(self.id) {
+            // removed from the cluster, like followers it will not campaign again
+            debug!("{} removed from the cluster, stepping down", self.id);
+            meta.leader_id = 0;
+            self.switch_membership(meta, Membership::Follower);
+            return;
+        }
+        if let Membership::Leader(ref leader_meta) = meta.membership {
             let mut leader_meta = leader_meta.write();
-            self.reload_leader_meta( //                                   |||       and logs to get updated first before leader
-                &members_from_meta!(meta), //                             |||       add it to member list in configuration
-                &mut leader_meta, new_log_id
-            );
+            self.reload_leader_meta(&members_from_meta!(meta), &mut leader_meta, log_id);
         }
-        self.send_followers_heartbeat(meta, Some(new_log_id));
-        data
     }
-}
-
-impl Service for RaftService {
-    fn append_entries(
-        &self,
+    fn append_entries_(
+        &self, meta: &mut RwLockWriteGuard<RaftMeta>,
         term: &u64, leader_id: &u64,
         prev_log_id: &u64, prev_log_term: &u64,
         entries: &Option<LogEntries>,
         leader_commit: &u64
-    ) -> Result<(u64, AppendEntriesResult), ()>  {
-        let mut meta = self.write_meta();
-        self.reset_last_checked(&mut meta);
-        let term_ok = self.check_term(&mut meta, *term, *leader_id); // RI, 1
+    ) -> Result<(u64, AppendEntriesResult), ()> {
+        self.reset_last_checked(meta);
+        let term_ok = self.check_term(meta, *term, *leader_id); // RI, 1
         let result = if term_ok {
             if let Membership::Candidate = meta.membership {
                 debug!("SWITCH FROM CANDIDATE BACK TO FOLLOWER {}", self.id);
-                self.become_follower(&mut meta, *term, *leader_id);
+                self.become_follower(meta, *term, *leader_id);
             }
-            if *prev_log_id > 0 {
-                check_commit(&mut meta);
+            if let Membership::Undefined = meta.membership {
+                // added by the leader without joining, like learners
+                self.become_follower(meta, *term, *leader_id);
+            }
+            if let Some(ref entries) = *entries {
+                if let Some(entry) = entries.iter().find(|entry| !entry.is_intact()) {
+                    error!("{} received corrupted raft log entry {} of term {} from {}", self.id, entry.id, entry.term, leader_id);
+                    return Ok((meta.term, AppendEntriesResult::Corrupted));
+                }
+            }
+            let compacted_id = compacted_id(meta);
+            if *prev_log_id > compacted_id { // compacted entries are committed, they always match
+                check_commit(meta);
                 let mut logs = meta.logs.write();
                 //RI, 2
                 let contains_prev_log = logs.contains_key(prev_log_id);
@@ -802,8 +2618,11 @@ impl Service for RaftService {
                         (Included(prev_log_id), Unbounded)
                     ).map(|(id, _)| *id).collect();
                     for id in ids_to_del {
-                        logs.remove(&id);
+                        if let Some(entry) = logs.remove(&id) {
+                            meta.log_bytes.fetch_sub(entry_bytes(&entry), Ordering::Relaxed);
+                        }
                     }
+                    write_store(meta, |store| store.truncate_after(*prev_log_id - 1))?;
                     return Ok((
                         meta.term,
                         AppendEntriesResult::LogMismatch
@@ -815,27 +2634,271 @@ impl Service for RaftService {
                 let mut logs = meta.logs.write();
                 let mut leader_commit = *leader_commit;
                 if let Some(ref entries) = *entries { // entry not empty
+                    let mut new_entries = Vec::new();
                     for entry in entries {
                         let entry_id = entry.id;
                         let sm_id = entry.sm_id;
-                        logs.entry(entry_id).or_insert(entry.clone());// RI, 4
+                        if entry_id > compacted_id && !logs.contains_key(&entry_id) { // RI, 4
+                            let entry = if self.is_witness() {witness_entry(entry)} else {entry.clone()};
+                            logs.insert(entry_id, entry.clone());
+                            new_entries.push(entry);
+                        }
                         last_new_entry = max(last_new_entry, entry_id);
                     }
+                    if write_store(meta, |store| store.append(&new_entries)).is_err() {
+                        // not answered, the leader sends them again
+                        for entry in new_entries {
+                            logs.remove(&entry.id);
+                        }
+                        return Err(());
+                    }
+                    for entry in new_entries {
+                        meta.log_bytes.fetch_add(entry_bytes(&entry), Ordering::Relaxed);
+                    }
                 } else if !logs.is_empty() {
                     last_new_entry = logs.values().last().unwrap().id;
                 }
             }
             if *leader_commit > meta.commit_index { //RI, 5
                 meta.commit_index = min(*leader_commit, last_new_entry);
-                check_commit(&mut meta);
+                check_commit(meta);
+                self.observe(meta);
+                self.check_compaction(meta);
+            }
+            collect_applied(meta);
+            if meta.last_applied >= *leader_commit {
+                meta.caught_up_at = self.clock.now();
             }
             Ok((meta.term, AppendEntriesResult::Ok))
         } else {
             Ok((meta.term, AppendEntriesResult::TermOut(meta.leader_id))) // term mismatch
         };
-        self.reset_last_checked(&mut meta);
+        self.reset_last_checked(meta);
         return result;
     }
+    fn check_compaction(&self, meta: &mut RwLockWriteGuard<RaftMeta>) {
+        let compaction = self.options.compaction;
+        let over_entries = compaction.max_entries
+            .map(|max_entries| meta.logs.read().len() as u64 > max_entries)
+            .unwrap_or(false);
+        let over_bytes = compaction.max_bytes
+            .map(|max_bytes| meta.log_bytes.load(Ordering::Relaxed) > max_bytes)
+            .unwrap_or(false);
+        if over_entries || over_bytes {
+            self.compact(meta, false);
+        }
+    }
+    // snapshot the state machines at the last applied entry, then drop the log up to it. state
+    // machines taking their time snapshot on other threads unless waited for, the log is dropped
+    // once they are done. false when there is nothing to compact or it is not done yet
+    fn compact(&self, meta: &mut RwLockWriteGuard<RaftMeta>, wait: bool) -> bool {
+        if meta.compacting.is_some() {
+            // one snapshot at a time
+            if !wait {return false;}
+            self.finish_compaction(meta, true);
+        }
+        settle_applied(meta);
+        let last_applied = meta.last_applied;
+        if last_applied <= compacted_id(meta) {
+            return false;
+        }
+        let last_applied_term = match meta.logs.read().get(&last_applied) {
+            Some(entry) => entry.term,
+            None => return false
+        };
+        let sections = meta.state_machine.read().sections_async()
+            .map(move |sections| write_snapshot(last_applied, last_applied_term, &sections));
+        let data = match done_or(sections) {
+            Ok(data) => data,
+            Err(sections) => if wait {
+                sections.wait()
+            } else {
+                let (tx, rx) = channel();
+                meta.workers.lock().execute(move || {
+                    tx.send(sections.wait()).ok();
+                });
+                debug!("Snapshot of {} up to {} is taken in the background", self.id, last_applied);
+                meta.compacting = Some(Compacting {
+                    last_included_id: last_applied,
+                    last_included_term: last_applied_term,
+                    data: Mutex::new(rx),
+                });
+                return false;
+            }
+        };
+        self.compacted(meta, last_applied, last_applied_term, data)
+    }
+    // the snapshot taken in the background replaces the log once it is saved
+    fn finish_compaction(&self, meta: &mut RwLockWriteGuard<RaftMeta>, wait: bool) -> bool {
+        let data = match meta.compacting {
+            Some(ref compacting) => {
+                let received = compacting.data.lock();
+                let data = if wait {
+                    received.recv().unwrap_or(Err(()))
+                } else {
+                    match received.try_recv() {
+                        Ok(data) => data,
+                        Err(TryRecvError::Empty) => return false,
+                        Err(TryRecvError::Disconnected) => Err(())
+                    }
+                };
+                data
+            },
+            None => return false
+        };
+        let compacting = meta.compacting.take().unwrap();
+        self.compacted(meta, compacting.last_included_id, compacting.last_included_term, data)
+    }
+    fn compacted(
+        &self, meta: &mut RwLockWriteGuard<RaftMeta>,
+        last_included_id: u64, last_included_term: u64, data: Result<Vec<u8>, ()>
+    ) -> bool {
+        let data = match data {
+            Ok(data) => data,
+            Err(()) => {
+                debug!("Log of {} not compacted, some state machine cannot snapshot", self.id);
+                return false;
+            }
+        };
+        // a snapshot from the leader replaced the log while this one was taken
+        if last_included_id <= compacted_id(meta) {
+            return false;
+        }
+        let snapshot = Snapshot {
+            last_included_id: last_included_id,
+            last_included_term: last_included_term,
+            data: data,
+        };
+        if write_store(meta, |store| store.save_snapshot(&snapshot)).is_err() {
+            return false;
+        }
+        {
+            let mut logs = meta.logs.write();
+            let remaining = logs.split_off(&(last_included_id + 1));
+            let compacted = mem::replace(&mut *logs, remaining);
+            let compacted_bytes: u64 = compacted.values().map(entry_bytes).sum();
+            meta.log_bytes.fetch_sub(compacted_bytes, Ordering::Relaxed);
+        }
+        // on failure the snapshot still covers them, only disk space is wasted
+        write_store(meta, |store| store.truncate_before(last_included_id + 1)).ok();
+        debug!("Log of {} compacted up to {}, snapshot {} bytes", self.id, last_included_id, snapshot.data.len());
+        meta.snapshot = Some(Arc::new(snapshot));
+        self.observe(meta);
+        true
+    }
+    // replaces the state machines and the log up to the last included entry of the snapshot
+    fn install(&self, meta: &mut RwLockWriteGuard<RaftMeta>, snapshot: Snapshot) -> Result<(), ()> {
+        let contents = match read_snapshot(&snapshot.data) {
+            Ok(ref contents) if (contents.last_included_id, contents.last_included_term) !=
+                (snapshot.last_included_id, snapshot.last_included_term) => {
+                error!("Raft snapshot up to {} is of another entry", snapshot.last_included_id);
+                return Err(());
+            },
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Cannot install raft snapshot up to {}, {}", snapshot.last_included_id, e);
+                return Err(());
+            }
+        };
+        // commands being applied would land on the state machines recovered from the snapshot
+        settle_applied(meta);
+        write_store(meta, |store| store.save_snapshot(&snapshot))?;
+        let last_included_id = snapshot.last_included_id;
+        let retain = {
+            let mut logs = meta.logs.write();
+            // entries after the snapshot are kept when the log agrees with it
+            let retain = logs.get(&last_included_id)
+                .map(|entry| entry.term == snapshot.last_included_term)
+                .unwrap_or(false);
+            if retain {
+                let remaining = logs.split_off(&(last_included_id + 1));
+                *logs = remaining;
+            } else {
+                logs.clear();
+            }
+            meta.log_bytes.store(logs.values().map(entry_bytes).sum(), Ordering::Relaxed);
+            retain
+        };
+        write_store(meta, |store| {
+            if retain {
+                store.truncate_before(last_included_id + 1)
+            } else {
+                store.truncate_after(0)
+            }
+        })?;
+        // sections of state machines the server does not have are kept for the next snapshot
+        meta.state_machine.write().recover_sections(contents.sections);
+        meta.commit_index = max(meta.commit_index, last_included_id);
+        set_applied(meta, last_included_id);
+        meta.last_dispatched = last_included_id;
+        meta.snapshot = Some(Arc::new(snapshot));
+        self.observe(meta);
+        Ok(())
+    }
+    // a chunk at offset 0 starts a new transfer, chunks out of order discard the current one
+    fn receive_snapshot_chunk(
+        &self, meta: &mut RwLockWriteGuard<RaftMeta>,
+        term: u64, leader_id: u64, last_included_id: u64, last_included_term: u64,
+        offset: u64, data: &Vec<u8>
+    ) -> Result<(), ()> {
+        if offset == 0 {
+            // the previous transfer has to release its temp file first
+            meta.incoming_snapshot = None;
+            let temp_path = match self.options.storage {
+                Storage::DiskOptions { ref path, .. } => Some(Path::new(path).join("snapshot.partial")),
+                _ => None
+            };
+            match IncomingSnapshot::new(leader_id, term, last_included_id, last_included_term, temp_path) {
+                Ok(incoming) => meta.incoming_snapshot = Some(incoming),
+                Err(e) => {
+                    error!("Cannot receive raft snapshot from {}, {}", leader_id, e);
+                    return Err(());
+                }
+            }
+        }
+        let continued = match meta.incoming_snapshot {
+            Some(ref incoming) => incoming.continued_by(leader_id, term, last_included_id, last_included_term, offset),
+            None => false
+        };
+        if !continued {
+            debug!("Snapshot chunk at {} from {} out of order, transfer discarded", offset, leader_id);
+            meta.incoming_snapshot = None;
+            return Err(());
+        }
+        let appended = meta.incoming_snapshot.as_mut().unwrap().append(data);
+        if let Err(e) = appended {
+            error!("Cannot receive raft snapshot from {}, {}", leader_id, e);
+            meta.incoming_snapshot = None;
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
+impl Service for RaftService {
+    fn append_entries(
+        &self,
+        term: &u64, leader_id: &u64,
+        prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<LogEntries>,
+        leader_commit: &u64
+    ) -> Result<(u64, AppendEntriesResult), ()>  {
+        let mut meta = self.write_meta();
+        let result = self.append_entries_(
+            &mut meta, term, leader_id, prev_log_id, prev_log_term, entries, leader_commit
+        )?;
+        if entries.as_ref().map(|entries| entries.is_empty()).unwrap_or(true) {
+            self.metrics.heartbeat_received();
+        }
+        match result.1 {
+            AppendEntriesResult::Ok => {},
+            _ => self.metrics.append_rejected()
+        }
+        if !persist_state(&mut meta) {
+            return Err(());
+        }
+        Ok(result)
+    }
 
     fn request_vote(
         &self,
@@ -843,16 +2906,23 @@ impl Service for RaftService {
         last_log_id: &u64, last_log_term: &u64
     ) -> Result<((u64, u64), bool), ()> {
         let mut meta = self.write_meta();
-        let vote_for = meta.vote_for;
-        let mut vote_granted = false;
         if *term > meta.term {
+            revoke_lease(&meta);
             check_commit(&mut meta);
+            // the term is taken before voting in it, the vote is saved with the term it is for
+            self.become_follower(&mut meta, *term, 0);
+        }
+        let vote_for = meta.vote_for;
+        let mut vote_granted = false;
+        if *term == meta.term {
             let logs = meta.logs.read();
             let conf_sm = &meta.state_machine.read().configs;
-            let candidate_valid = conf_sm.member_existed(*candidate_id);
+            // learners neither vote nor get voted for
+            let candidate_valid = conf_sm.is_voter(*candidate_id) && !conf_sm.is_witness(*candidate_id) &&
+                conf_sm.is_voter(self.id);
             debug!("{} VOTE FOR: {}, valid: {}", self.id, candidate_id, candidate_valid);
             if (vote_for.is_none() || vote_for.unwrap() == *candidate_id) && candidate_valid{
-                let (last_id, last_term) = get_last_log_info!(self, logs);
+                let (last_id, last_term) = get_last_log_info!(self, meta, logs);
                 if *last_log_id >= last_id && *last_log_term >= last_term {
                     vote_granted = true;
                 } else {
@@ -860,8 +2930,8 @@ impl Service for RaftService {
                 }
             } else {
                 debug!(
-                    "{} VOTE FOR: {}, not granted due to voted for {}",
-                    self.id, candidate_id, vote_for.unwrap()
+                    "{} VOTE FOR: {}, not granted, voted for {:?}, valid: {}",
+                    self.id, candidate_id, vote_for, candidate_valid
                 );
             }
         } else {
@@ -870,36 +2940,169 @@ impl Service for RaftService {
         if vote_granted {
             meta.vote_for = Some(*candidate_id);
         }
+        if !persist_state(&mut meta) {
+            return Err(());
+        }
         debug!("{} VOTE FOR: {}, granted: {}", self.id, candidate_id, vote_granted);
         Ok(((meta.term, meta.leader_id), vote_granted))
     }
 
+    // nothing changes, the server only tells if it would vote for the candidate in the term
+    fn pre_vote(
+        &self,
+        term: &u64, candidate_id: &u64,
+        last_log_id: &u64, last_log_term: &u64
+    ) -> Result<bool, ()> {
+        let meta = self.read_meta();
+        // servers still hearing from the leader keep it
+        let has_leader = match meta.membership {
+            Membership::Leader(_) => true,
+            Membership::Follower => self.clock.now() - meta.last_checked < self.options.min_election_timeout_ms(),
+            _ => false
+        };
+        if *term <= meta.term || has_leader {
+            debug!("{} PRE VOTE FOR: {}, not granted, has leader: {}", self.id, candidate_id, has_leader);
+            return Ok(false);
+        }
+        let voters = {
+            let configs = &meta.state_machine.read().configs;
+            configs.is_voter(*candidate_id) && !configs.is_witness(*candidate_id) && configs.is_voter(self.id)
+        };
+        if !voters {
+            return Ok(false);
+        }
+        let logs = meta.logs.read();
+        let (last_id, last_term) = get_last_log_info!(self, meta, logs);
+        // the log with the later last term is more up to date, the longer one when the terms are the same
+        Ok((*last_log_term, *last_log_id) >= (last_term, last_id))
+    }
+
+    fn timeout_now(&self, term: &u64, leader_id: &u64) -> Result<bool, ()> {
+        let mut meta = self.write_meta();
+        let accepted = match meta.membership {
+            Membership::Follower => meta.term == *term && meta.leader_id == *leader_id,
+            _ => false
+        };
+        if accepted {
+            debug!("{} takes over from leader {} in term {}", self.id, leader_id, term);
+            meta.election_now = true;
+        }
+        Ok(accepted)
+    }
+
     fn install_snapshot(
         &self,
         term: &u64, leader_id: &u64, last_included_index: &u64,
-        last_included_term: &u64, data: &Vec<u8>, done: &bool
+        last_included_term: &u64, offset: &u64, data: &Vec<u8>, checksum: &u32, done: &bool
     ) -> Result<u64, ()> {
         let mut meta = self.write_meta();
+        self.reset_last_checked(&mut meta);
         let term_ok = self.check_term(&mut meta, *term, *leader_id);
         if term_ok {
-            check_commit(&mut meta);
+            if let Membership::Candidate = meta.membership {
+                self.become_follower(&mut meta, *term, *leader_id);
+            }
+            self.receive_snapshot_chunk(
+                &mut meta, *term, *leader_id, *last_included_index,
+                *last_included_term, *offset, data
+            )?;
+            if *done {
+                let incoming = meta.incoming_snapshot.take().unwrap();
+                let snapshot = match incoming.finish(*checksum) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        error!("Cannot receive raft snapshot from {}, {}", leader_id, e);
+                        return Err(());
+                    }
+                };
+                check_commit(&mut meta);
+                // followers already applied the entries have nothing to install
+                if meta.last_applied < *last_included_index {
+                    self.install(&mut meta, snapshot)?;
+                    self.metrics.snapshot_installed();
+                }
+            }
+        }
+        if !persist_state(&mut meta) {
+            return Err(());
         }
         Ok(meta.term)
     }
 
     fn c_command(&self, entry: &LogEntry) -> Result<ClientCmdResponse, ()> {
+        let max_bytes = self.options.replication.entry_limit();
+        if entry_bytes(entry) > max_bytes {
+            // it would not fit in a batch to followers
+            return self.refuse(ClientCmdResponse::EntryTooLarge { max_bytes: max_bytes });
+        }
+        if entry.sm_id == BATCH_SM_ID && !self.batches_supported() {
+            return self.refuse(ClientCmdResponse::BatchUnsupported);
+        }
+        let room_deadline = get_time() + duration_to_ms(self.options.proposal_queue_wait) as i64;
+        while get_time() < room_deadline && !context::current().is_expired() &&
+            proposal_queue(&self.meta.read()) >= self.options.max_proposals {
+            thread::sleep(Duration::from_millis(CHECKER_MS as u64));
+        }
         let mut meta = self.write_meta();
         let mut entry = entry.clone();
         if !is_leader(&meta) {
-            return Ok(ClientCmdResponse::NotLeader(meta.leader_id));
-        }
-        let (new_log_id, new_log_term) = self.append_log(&meta, &mut entry);
-        let mut data = match entry.sm_id {
-            // special treats for membership changes
-            CONFIG_SM_ID => Some(self.try_sync_config_to_followers(&mut meta, &entry, new_log_id)),
-            _ => self.try_sync_log_to_followers(&mut meta, &entry, new_log_id)
-        }; // Some for committed and None for not committed
-        if let Some(data) = data {
+            return self.refuse(ClientCmdResponse::NotLeader { leader_hint: leader_hint(&meta) });
+        }
+        if meta.transferring_to.is_some() {
+            // entries proposed now may not reach the target before it takes over
+            return self.refuse(ClientCmdResponse::NotCommitted);
+        }
+        if context::current().is_expired() {
+            // the client has given up, do not replicate commands it believes failed
+            return self.refuse(ClientCmdResponse::DeadlineExceeded);
+        }
+        if entry.trace_id.is_none() {
+            // entries proposed without one take the trace id from the request header
+            entry.trace_id = context::current_trace_id();
+        }
+        if let Some(ref mut session) = entry.session {
+            session.time = self.clock.now();
+        }
+        let is_config_change = entry.sm_id == CONFIG_SM_ID &&
+            (entry.fn_id == hash_ident!(new_member_) as u64 || entry.fn_id == hash_ident!(del_member_) as u64);
+        if is_config_change {
+            if let Some(change_id) = meta.config_change_id {
+                // a majority may have the last change by now
+                self.send_followers_heartbeat(&mut meta, Some(change_id));
+                self.advance_commit(&mut meta);
+                if meta.config_change_id.is_some() {
+                    return self.refuse(ClientCmdResponse::ConfigChangeInProgress);
+                }
+            }
+        }
+        if proposal_queue(&meta) >= self.options.max_proposals {
+            return self.refuse(ClientCmdResponse::ProposalQueueFull);
+        }
+        let (new_log_id, new_log_term) = match self.append_log(&meta, &mut entry) {
+            Some(log_info) => log_info,
+            None => return self.refuse(ClientCmdResponse::NotCommitted)
+        };
+        self.metrics.proposal_accepted();
+        if is_config_change {
+            meta.config_change_id = Some(new_log_id);
+        }
+        let (tx, rx) = channel();
+        let progress = match meta.membership {
+            Membership::Leader(ref leader_meta) => {
+                let mut leader_meta = leader_meta.write();
+                leader_meta.proposals.insert(new_log_id, tx);
+                leader_meta.progress.clone()
+            },
+            _ => return Ok(ClientCmdResponse::NotCommitted)
+        };
+        self.pipeline_entries(&meta);
+        // clusters of one voter commit right away
+        self.advance_commit(&mut meta);
+        mem::drop(meta);
+        let data = self.wait_committed(new_log_id, &rx, &progress);
+        if let Some(Err(ExecError::LostLeadership)) = data {
+            Ok(ClientCmdResponse::LostLeadership)
+        } else if let Some(data) = data {
             Ok(ClientCmdResponse::Success{
                 data: data,
                 last_log_id: new_log_id,
@@ -910,25 +3113,99 @@ impl Service for RaftService {
         }
     }
     fn c_query(&self, entry: &LogEntry) -> Result<ClientQryResponse, ()> {
+        if self.is_witness() {
+            // nothing to read from, the client asks another member
+            return Ok(ClientQryResponse::LeftBehind);
+        }
         let mut meta = self.meta.read();
         let logs = meta.logs.read();
-        let (last_log_id, last_log_term) = get_last_log_info!(self, logs);
+        let (last_log_id, last_log_term) = get_last_log_info!(self, meta, logs);
         if entry.term > last_log_term || entry.id > last_log_id {
             Ok(ClientQryResponse::LeftBehind)
         } else {
             Ok(ClientQryResponse::Success{
-                data: meta.state_machine.read().exec_qry(&entry),
+                data: meta.state_machine.read().exec_qry(&query_ctx(&meta), entry),
                 last_log_id: last_log_id,
                 last_log_term: last_log_term,
             })
         }
     }
+    fn c_leader_query(&self, entry: &LogEntry, consistency: &Consistency) -> Result<ClientReadResponse, ()> {
+        let mut meta = self.write_meta();
+        if !is_leader(&meta) {
+            return Ok(ClientReadResponse::NotLeader { leader_hint: leader_hint(&meta) });
+        }
+        let leased = *consistency == Consistency::LeaderLease && self.lease_valid(&meta);
+        if !leased && !self.confirm_leadership(&mut meta) {
+            return Ok(ClientReadResponse::NotConfirmed);
+        }
+        // read index is the commit index, apply up to it before reading
+        check_commit(&mut meta);
+        settle_applied(&mut meta);
+        let (last_log_id, last_log_term) = {
+            let logs = meta.logs.read();
+            get_last_log_info!(self, meta, logs)
+        };
+        Ok(ClientReadResponse::Success{
+            data: meta.state_machine.read().exec_qry(&query_ctx(&meta), entry),
+            last_log_id: last_log_id,
+            last_log_term: last_log_term,
+        })
+    }
+    fn c_bounded_query(&self, entry: &LogEntry, max_lag_ms: &u64) -> Result<ClientReadResponse, ()> {
+        if self.is_witness() {
+            return Ok(ClientReadResponse::TooStale { lag: std::u64::MAX });
+        }
+        let meta = self.meta.read();
+        let lag = match meta.membership {
+            Membership::Leader(_) => 0,
+            _ => max(self.clock.now() - meta.caught_up_at, 0) as u64
+        };
+        if lag > *max_lag_ms {
+            return Ok(ClientReadResponse::TooStale { lag: lag });
+        }
+        let logs = meta.logs.read();
+        let (last_log_id, last_log_term) = get_last_log_info!(self, meta, logs);
+        Ok(ClientReadResponse::Success{
+            data: meta.state_machine.read().exec_qry(&query_ctx(&meta), entry),
+            last_log_id: last_log_id,
+            last_log_term: last_log_term,
+        })
+    }
     fn c_server_cluster_info(&self) -> Result<ClientClusterInfo, ()> {
         Ok(self.cluster_info())
     }
+    fn c_status(&self) -> Result<NodeStatus, ()> {
+        Ok(self.status())
+    }
     fn c_put_offline(&self) -> Result<bool, ()> {
         Ok(self.leave())
     }
+    fn c_transfer_leadership(&self, target_id: &u64) -> Result<Result<(), TransferError>, ()> {
+        Ok(self.transfer_leadership(*target_id))
+    }
+    fn c_promote_learner(&self, id: &u64) -> Result<Result<(), PromoteError>, ()> {
+        Ok(self.promote_learner(*id))
+    }
+    fn c_wait_applied(&self, index: &u64, timeout_ms: &u64) -> Result<u64, ()> {
+        // no longer than the client waits for the answer
+        let timeout = match context::current().remaining() {
+            Some(remaining) => min(remaining, Duration::from_millis(*timeout_ms)),
+            None => Duration::from_millis(*timeout_ms)
+        };
+        let applied = self.meta.read().applied.clone();
+        applied.wait(*index, timeout);
+        Ok(applied.get())
+    }
+    fn c_dump_log(&self, from: &u64, to: &u64) -> Result<Result<Vec<EntryMeta>, AdminError>, ()> {
+        Ok(self.admin_allowed().map(|_| self.dump_log(*from..*to)))
+    }
+    fn c_entry_payload(&self, index: &u64) -> Result<Result<LogEntry, AdminError>, ()> {
+        Ok(self.admin_allowed().and_then(|_| self.entry_payload(*index).ok_or(AdminError::EntryNotFound)))
+    }
+    fn c_log_digest(&self, from: &u64, to: &u64) -> Result<Result<u64, AdminError>, ()> {
+        Ok(self.admin_allowed().map(|_| self.compare_digest(*from..*to)))
+    }
 }
 
 pub struct RaftStateMachine {
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use parking_lot::{Mutex, RwLock};
+use threadpool::ThreadPool;
+use futures_cpupool::CpuPool;
+use num_cpus;
+use bifrost_hasher::hash_str;
+use rpc::{self, Server};
+use utils::time::get_time;
+use super::{RaftService, Options, OptionsError, AppendEntriesResult, CHECKER_MS};
+use super::Service as RaftRpc;
+use super::clock::SystemClock;
+use super::transport::RpcTransport;
+use super::state_machine::configs::CONTROL_CLIENT_POOL;
+use self::multi_rpc::*;
+
+// empty append entries of a group, the leader has nothing to send to the follower
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Heartbeat {
+    pub group_id: u64,
+    pub term: u64,
+    pub leader_id: u64,
+    pub prev_log_id: u64,
+    pub prev_log_term: u64,
+    pub leader_commit: u64,
+}
+
+mod multi_rpc {
+    use super::Heartbeat;
+    use raft::AppendEntriesResult;
+    service! {
+        // answers in the order of the heartbeats, None for groups the server does not host
+        rpc heartbeats(beats: Vec<Heartbeat>) -> Vec<Option<(u64, AppendEntriesResult)>>;
+    }
+}
+
+// called with the term and answer of the follower, None when the batch was not answered
+type Answered = Box<Fn(Option<(u64, AppendEntriesResult)>) + Send>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeartbeatStats {
+    // batches sent, one for each peer in a round
+    pub frames: u64,
+    pub beats: u64,
+}
+
+// heartbeats of the groups waiting for the end of the round, by the address of the follower
+pub struct Heartbeats {
+    pending: Mutex<HashMap<String, Vec<(Heartbeat, Answered)>>>,
+    frames: AtomicU64,
+    beats: AtomicU64,
+    // proving the auth token of the multi raft server, the shared control pool without one
+    clients: Option<Arc<rpc::ClientPool>>,
+}
+
+impl Heartbeats {
+    fn new(auth_token: &Option<String>) -> Heartbeats {
+        Heartbeats {
+            pending: Mutex::new(HashMap::new()),
+            frames: AtomicU64::new(0),
+            beats: AtomicU64::new(0),
+            clients: auth_token.as_ref().map(|token| Arc::new(rpc::ClientPool::with_auth_token(token))),
+        }
+    }
+    pub fn push(&self, address: &String, beat: Heartbeat, answered: Answered) {
+        self.pending.lock().entry(address.clone()).or_insert_with(|| Vec::new()).push((beat, answered));
+    }
+    // a frame for each peer, on the control connection like heartbeats of single servers
+    fn flush(&self, service_id: u64, workers: &Mutex<ThreadPool>) {
+        let pending = mem::replace(&mut *self.pending.lock(), HashMap::new());
+        if pending.is_empty() {return;}
+        let workers = workers.lock();
+        for (address, batch) in pending {
+            self.frames.fetch_add(1, Ordering::Relaxed);
+            self.beats.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            let clients = self.clients.clone();
+            workers.execute(move || {
+                let (beats, answered): (Vec<Heartbeat>, Vec<Answered>) = batch.into_iter().unzip();
+                let client = match clients {
+                    Some(ref clients) => clients.get(&address),
+                    None => CONTROL_CLIENT_POOL.get(&address)
+                };
+                let answers = match client {
+                    Ok(client) => match SyncServiceClient::new(service_id, &client).heartbeats(&beats) {
+                        Ok(Ok(answers)) => answers,
+                        _ => Vec::new()
+                    },
+                    Err(_) => Vec::new()
+                };
+                let mut answers = answers.into_iter();
+                for answered in answered {
+                    answered(answers.next().and_then(|answer| answer));
+                }
+            });
+        }
+    }
+    fn stats(&self) -> HeartbeatStats {
+        HeartbeatStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            beats: self.beats.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// what a group keeps of the multi raft server hosting it
+pub struct GroupLink {
+    pub id: u64,
+    pub heartbeats: Arc<Heartbeats>,
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    Exists,
+    InvalidOptions(OptionsError),
+    // the group could not recover or register itself
+    NotStarted,
+}
+
+// service id the group is registered with, clients of the group use it
+pub fn group_service_id(service_id: u64, group_id: u64) -> u64 {
+    hash_str(&format!("{}-{}", service_id, group_id))
+}
+
+// many raft groups on one server. each group has its own log, state machines and members, and
+// runs as a raft service of its own service id. the groups share the threads of the server and
+// one checker, heartbeats of groups with nothing to send go to each peer in one frame a round
+pub struct MultiRaft {
+    server: Arc<Server>,
+    address: String,
+    service_id: u64,
+    groups: RwLock<BTreeMap<u64, Arc<RaftService>>>,
+    workers: Arc<Mutex<ThreadPool>>,
+    replicator: CpuPool,
+    heartbeats: Arc<Heartbeats>,
+}
+
+impl MultiRaft {
+    // registered on the server with the service id, it takes the same id on every server
+    pub fn new(server: &Arc<Server>, address: &String, service_id: u64) -> Arc<MultiRaft> {
+        MultiRaft::with_auth_token(server, address, service_id, &None)
+    }
+    // for servers started with the auth token, groups have it in their options as well
+    pub fn with_auth_token(
+        server: &Arc<Server>, address: &String, service_id: u64, auth_token: &Option<String>
+    ) -> Arc<MultiRaft> {
+        let multi = Arc::new(MultiRaft {
+            server: server.clone(),
+            address: address.clone(),
+            service_id: service_id,
+            groups: RwLock::new(BTreeMap::new()),
+            workers: Arc::new(Mutex::new(ThreadPool::new(max_workers()))),
+            replicator: CpuPool::new(num_cpus::get()),
+            heartbeats: Arc::new(Heartbeats::new(auth_token)),
+        });
+        server.register_service(service_id, &multi);
+        let checker_ref = Arc::downgrade(&multi);
+        thread::Builder::new()
+            .name(String::from("multi raft checker"))
+            .spawn(move || check_groups(checker_ref))
+            .unwrap();
+        multi
+    }
+    // address and service id of the options are the ones of the server and the group
+    pub fn add_group(&self, group_id: u64, mut options: Options) -> Result<Arc<RaftService>, GroupError> {
+        if self.groups.read().contains_key(&group_id) {
+            return Err(GroupError::Exists);
+        }
+        let service_id = group_service_id(self.service_id, group_id);
+        options.address = self.address.clone();
+        options.service_id = service_id;
+        let link = GroupLink {
+            id: group_id,
+            heartbeats: self.heartbeats.clone(),
+        };
+        let transport = Arc::new(RpcTransport::new(&options.auth_token));
+        let group = RaftService::try_new_with(
+            options, self.workers.clone(), self.replicator.clone(), Some(link), Arc::new(SystemClock), transport
+        ).map_err(GroupError::InvalidOptions)?;
+        self.server.register_service(service_id, &group);
+        if !RaftService::start(&group) {
+            self.server.remove_service(service_id);
+            return Err(GroupError::NotStarted);
+        }
+        let mut groups = self.groups.write();
+        if groups.contains_key(&group_id) {
+            self.server.remove_service(service_id);
+            return Err(GroupError::Exists);
+        }
+        groups.insert(group_id, group.clone());
+        Ok(group)
+    }
+    pub fn group(&self, group_id: u64) -> Option<Arc<RaftService>> {
+        self.groups.read().get(&group_id).cloned()
+    }
+    pub fn group_ids(&self) -> Vec<u64> {
+        self.groups.read().keys().cloned().collect()
+    }
+    // the group stops here, it stays a member of its cluster until removed from it
+    pub fn remove_group(&self, group_id: u64) -> Option<Arc<RaftService>> {
+        let group = self.groups.write().remove(&group_id);
+        if group.is_some() {
+            self.server.remove_service(group_service_id(self.service_id, group_id));
+        }
+        group
+    }
+    pub fn group_service_id(&self, group_id: u64) -> u64 {
+        group_service_id(self.service_id, group_id)
+    }
+    pub fn heartbeat_stats(&self) -> HeartbeatStats {
+        self.heartbeats.stats()
+    }
+}
+
+impl Service for MultiRaft {
+    fn heartbeats(&self, beats: &Vec<Heartbeat>) -> Result<Vec<Option<(u64, AppendEntriesResult)>>, ()> {
+        let groups = self.groups.read();
+        Ok(beats.iter().map(|beat| {
+            groups.get(&beat.group_id).and_then(|group| group.append_entries(
+                &beat.term,
+                &beat.leader_id,
+                &beat.prev_log_id,
+                &beat.prev_log_term,
+                &None,
+                &beat.leader_commit
+            ).ok())
+        }).collect())
+    }
+}
+dispatch_rpc_service_functions!(MultiRaft);
+
+fn max_workers() -> usize {
+    ::std::cmp::max(num_cpus::get() * 5, 10)
+}
+
+// every group in turn, then the heartbeats of the round. stops with the multi raft server
+fn check_groups(multi: Weak<MultiRaft>) {
+    loop {
+        let start_time = get_time();
+        let checker_ms = {
+            let multi = match multi.upgrade() {
+                Some(multi) => multi,
+                None => return
+            };
+            let groups: Vec<Arc<RaftService>> = multi.groups.read().values().cloned().collect();
+            let mut checker_ms = CHECKER_MS;
+            for group in &groups {
+                // offline groups are left until removed
+                RaftService::tick(group);
+                checker_ms = ::std::cmp::min(checker_ms, group.options.heartbeat_ms());
+            }
+            multi.heartbeats.flush(multi.service_id, &multi.workers);
+            checker_ms
+        };
+        let time_to_sleep = start_time + checker_ms - get_time() - 1;
+        if time_to_sleep > 0 {
+            thread::sleep(Duration::from_millis(time_to_sleep as u64));
+        }
+    }
+}
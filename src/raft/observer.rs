@@ -0,0 +1,150 @@
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use parking_lot::Mutex;
+use super::{Role, MemberStatus};
+
+// hears about changes of the local server. callbacks run one at a time on a thread of their own,
+// after the change happened, the server does not wait for them
+pub trait RaftObserver: Send {
+    fn on_role_change(&mut self, _role: Role, _term: u64) {}
+    // None when the server knows no leader
+    fn on_leader_change(&mut self, _leader_id: Option<u64>) {}
+    fn on_commit(&mut self, _index: u64) {}
+    fn on_snapshot(&mut self, _index: u64) {}
+    fn on_membership_change(&mut self, _members: Vec<MemberStatus>) {}
+    // leaders tell when a follower falls too far behind, with the entries it is behind
+    fn on_follower_lagging(&mut self, _id: u64, _lag: u64) {}
+}
+
+// what observers are told about, members are sorted by id and without match index
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observed {
+    pub role: Role,
+    pub term: u64,
+    pub leader_id: u64,
+    pub commit_index: u64,
+    pub snapshot_id: Option<u64>,
+    pub members: Vec<MemberStatus>,
+}
+
+enum Event {
+    // observers start with the current role, leader and members
+    Registered(Box<RaftObserver>, Observed),
+    RoleChange(Role, u64),
+    LeaderChange(Option<u64>),
+    Commit(u64),
+    Snapshot(u64),
+    MembershipChange(Vec<MemberStatus>),
+    FollowerLagging(u64, u64),
+}
+
+pub struct Observers {
+    // the thread starts with the first observer
+    events: Mutex<Option<Sender<Event>>>,
+    observed: Option<Observed>,
+}
+
+fn leader(leader_id: u64) -> Option<u64> {
+    if leader_id == 0 { None } else { Some(leader_id) }
+}
+
+fn changes(previous: &Observed, current: &Observed) -> Vec<Event> {
+    let mut events = Vec::new();
+    // a leader stepped down and elected again in between is still a change
+    if current.role != previous.role || (current.role == Role::Leader && current.term != previous.term) {
+        events.push(Event::RoleChange(current.role, current.term));
+    }
+    if current.leader_id != previous.leader_id {
+        events.push(Event::LeaderChange(leader(current.leader_id)));
+    }
+    if current.members != previous.members {
+        events.push(Event::MembershipChange(current.members.clone()));
+    }
+    if current.snapshot_id != previous.snapshot_id {
+        if let Some(snapshot_id) = current.snapshot_id {
+            events.push(Event::Snapshot(snapshot_id));
+        }
+    }
+    if current.commit_index > previous.commit_index {
+        events.push(Event::Commit(current.commit_index));
+    }
+    events
+}
+
+fn notify(events: Receiver<Event>) {
+    let mut observers: Vec<Box<RaftObserver>> = Vec::new();
+    while let Ok(event) = events.recv() {
+        match event {
+            Event::Registered(mut observer, current) => {
+                observer.on_role_change(current.role, current.term);
+                observer.on_leader_change(leader(current.leader_id));
+                observer.on_membership_change(current.members);
+                observers.push(observer);
+            },
+            Event::RoleChange(role, term) => for observer in observers.iter_mut() {
+                observer.on_role_change(role, term);
+            },
+            Event::LeaderChange(leader_id) => for observer in observers.iter_mut() {
+                observer.on_leader_change(leader_id);
+            },
+            Event::Commit(index) => for observer in observers.iter_mut() {
+                observer.on_commit(index);
+            },
+            Event::Snapshot(index) => for observer in observers.iter_mut() {
+                observer.on_snapshot(index);
+            },
+            Event::MembershipChange(members) => for observer in observers.iter_mut() {
+                observer.on_membership_change(members.clone());
+            },
+            Event::FollowerLagging(id, lag) => for observer in observers.iter_mut() {
+                observer.on_follower_lagging(id, lag);
+            },
+        }
+    }
+}
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers {
+            events: Mutex::new(None),
+            observed: None,
+        }
+    }
+    pub fn register(&mut self, observer: Box<RaftObserver>, current: Observed) {
+        {
+            let mut events = self.events.lock();
+            if events.is_none() {
+                let (tx, rx) = channel();
+                thread::Builder::new()
+                    .name(String::from("raft observers"))
+                    .spawn(move || notify(rx))
+                    .unwrap();
+                *events = Some(tx);
+            }
+            events.as_ref().unwrap().send(Event::Registered(observer, current.clone())).ok();
+        }
+        self.observed = Some(current);
+    }
+    // observers hear about what changed since the last update
+    pub fn update(&mut self, current: Observed) {
+        if self.observed.as_ref() == Some(&current) {
+            return;
+        }
+        if let Some(ref events) = *self.events.lock() {
+            if let Some(ref previous) = self.observed {
+                for event in changes(previous, &current) {
+                    events.send(event).ok();
+                }
+            }
+        }
+        self.observed = Some(current);
+    }
+    pub fn follower_lagging(&self, id: u64, lag: u64) {
+        if let Some(ref events) = *self.events.lock() {
+            events.send(Event::FollowerLagging(id, lag)).ok();
+        }
+    }
+    pub fn observing(&self) -> bool {
+        self.observed.is_some()
+    }
+}
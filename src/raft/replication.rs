@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::collections::Bound::{Included, Unbounded};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, Condvar};
+use super::{LogEntry, Snapshot, entry_bytes};
+
+// entries from the id on, no more than max_entries and max_bytes in total but at least one.
+// entries larger than max_bytes go alone, they were proposed before the limit was lowered
+pub fn batch_entries(
+    logs: &BTreeMap<u64, LogEntry>, from: u64, max_bytes: u64, max_entries: usize
+) -> Option<Vec<LogEntry>> {
+    let mut bytes = 0;
+    let mut batch = Vec::new();
+    for (_, entry) in logs.range((Included(&from), Unbounded)) {
+        let size = entry_bytes(entry);
+        if !batch.is_empty() && (bytes + size > max_bytes || batch.len() >= max_entries) {break;}
+        bytes += size;
+        batch.push(entry.clone());
+    }
+    if batch.is_empty() {None} else {Some(batch)}
+}
+
+// id and term of the entry before the ones sent to the follower
+pub fn prev_log_info(
+    logs: &BTreeMap<u64, LogEntry>,
+    snapshot: &Option<Arc<Snapshot>>,
+    prev_log_id: u64
+) -> Option<(u64, u64)> {
+    if let Some(ref snapshot) = *snapshot {
+        if snapshot.last_included_id == prev_log_id {
+            return Some((snapshot.last_included_id, snapshot.last_included_term)); // the follower has the snapshot
+        }
+    }
+    if prev_log_id == 0 || logs.is_empty() {
+        return Some((0, 0)); // 0 represents there is no logs in the leader
+    }
+    logs.get(&prev_log_id).map(|entry| (entry.id, entry.term))
+}
+
+// where empty append entries to the follower start when it is busy with a snapshot or a batch.
+// its match index may be compacted meanwhile, the snapshot before it still keeps it from electing
+pub fn keepalive_prev(
+    logs: &BTreeMap<u64, LogEntry>,
+    snapshot: &Option<Arc<Snapshot>>,
+    match_index: u64
+) -> (u64, u64) {
+    match (prev_log_info(logs, snapshot, match_index), snapshot) {
+        (Some(prev), _) => prev,
+        (None, &Some(ref snapshot)) => (snapshot.last_included_id, snapshot.last_included_term),
+        (None, &None) => (0, 0)
+    }
+}
+
+// counts answers from followers, proposals wait on it for their entries to be committed
+pub struct Progress {
+    answers: Mutex<u64>,
+    answered: Condvar,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress {
+            answers: Mutex::new(0),
+            answered: Condvar::new(),
+        }
+    }
+    pub fn answers(&self) -> u64 {
+        *self.answers.lock()
+    }
+    pub fn answer(&self) {
+        *self.answers.lock() += 1;
+        self.answered.notify_all();
+    }
+    // returns when there are answers after the ones seen, or on timeout
+    pub fn wait(&self, seen: u64, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut answers = self.answers.lock();
+        while *answers == seen {
+            if self.answered.wait_until(&mut answers, deadline).timed_out() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(id: u64, size: usize) -> LogEntry {
+        LogEntry {
+            id: id,
+            term: 1,
+            sm_id: 0,
+            fn_id: 0,
+            data: vec![0; size],
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0,
+        }
+    }
+
+    #[test]
+    fn batches_up_to_max_bytes() {
+        let mut logs = BTreeMap::new();
+        for id in 1..11 {
+            logs.insert(id, entry(id, 10));
+        }
+        logs.insert(11, entry(11, 100));
+        let ids = |batch: Option<Vec<LogEntry>>| -> Vec<u64> {
+            batch.unwrap().iter().map(|entry| entry.id).collect()
+        };
+        assert_eq!(ids(batch_entries(&logs, 1, 35, 100)), vec![1, 2, 3]);
+        assert_eq!(ids(batch_entries(&logs, 9, 35, 100)), vec![9, 10]);
+        // entries larger than the limit go alone
+        assert_eq!(ids(batch_entries(&logs, 11, 35, 100)), vec![11]);
+        assert!(batch_entries(&logs, 12, 35, 100).is_none());
+        // no more entries than the limit, however small
+        assert_eq!(ids(batch_entries(&logs, 1, 1000, 4)), vec![1, 2, 3, 4]);
+        assert_eq!(ids(batch_entries(&logs, 10, 1000, 4)), vec![10, 11]);
+        assert_eq!(ids(batch_entries(&logs, 1, 1000, 0)), vec![1]);
+        assert_eq!(prev_log_info(&logs, &None, 5), Some((5, 1)));
+        assert_eq!(prev_log_info(&logs, &None, 0), Some((0, 0)));
+        assert_eq!(prev_log_info(&logs, &None, 20), None);
+    }
+}
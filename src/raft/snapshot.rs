@@ -0,0 +1,306 @@
+use std::cmp::max;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use byteorder::{ByteOrder, LittleEndian};
+use crc::{crc32, Hasher32};
+use super::Snapshot;
+use super::transport::Peer;
+
+pub static DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+// snapshots are [magic][format version][header length][header] and the sections after it,
+// [sm id][length][crc32][data] each, to the end. the header starts with the last included
+// id and term, readers skip the fields later versions add after them
+const MAGIC: &'static [u8] = b"BFSS";
+pub const FORMAT_VERSION: u32 = 1;
+const HEADER_BYTES: usize = 16;
+const SECTION_HEAD_BYTES: usize = 20;
+
+// the state of one state machine in the snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub sm_id: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotContents {
+    pub last_included_id: u64,
+    pub last_included_term: u64,
+    // in the order they were written, including those of state machines the server does not know
+    pub sections: Vec<Section>,
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Raft snapshot {}", reason))
+}
+
+fn put_u32(data: &mut Vec<u8>, n: u32) {
+    let mut buf = [0u8; 4];
+    LittleEndian::write_u32(&mut buf, n);
+    data.extend_from_slice(&buf);
+}
+
+fn put_u64(data: &mut Vec<u8>, n: u64) {
+    let mut buf = [0u8; 8];
+    LittleEndian::write_u64(&mut buf, n);
+    data.extend_from_slice(&buf);
+}
+
+pub fn write_snapshot(last_included_id: u64, last_included_term: u64, sections: &[Section]) -> Vec<u8> {
+    let size = MAGIC.len() + 8 + HEADER_BYTES +
+        sections.iter().map(|section| SECTION_HEAD_BYTES + section.data.len()).sum::<usize>();
+    let mut data = Vec::with_capacity(size);
+    data.extend_from_slice(MAGIC);
+    put_u32(&mut data, FORMAT_VERSION);
+    put_u32(&mut data, HEADER_BYTES as u32);
+    put_u64(&mut data, last_included_id);
+    put_u64(&mut data, last_included_term);
+    for section in sections {
+        put_u64(&mut data, section.sm_id);
+        put_u64(&mut data, section.data.len() as u64);
+        put_u32(&mut data, crc32::checksum_ieee(&section.data));
+        data.extend_from_slice(&section.data);
+    }
+    data
+}
+
+// InvalidData when the snapshot is not of this format, truncated, or any section is corrupted
+pub fn read_snapshot(data: &[u8]) -> io::Result<SnapshotContents> {
+    let header_start = MAGIC.len() + 8;
+    if data.len() < header_start || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid(String::from("is not of a known format")));
+    }
+    let version = LittleEndian::read_u32(&data[MAGIC.len()..]);
+    let header_len = LittleEndian::read_u32(&data[MAGIC.len() + 4..]) as usize;
+    if version == 0 || header_len < HEADER_BYTES || data.len() - header_start < header_len {
+        return Err(invalid(format!("header of version {} is truncated", version)));
+    }
+    let last_included_id = LittleEndian::read_u64(&data[header_start..]);
+    let last_included_term = LittleEndian::read_u64(&data[header_start + 8..]);
+    let mut sections = Vec::new();
+    let mut pos = header_start + header_len;
+    while pos < data.len() {
+        if data.len() - pos < SECTION_HEAD_BYTES {
+            return Err(invalid(format!("up to {} is truncated", last_included_id)));
+        }
+        let sm_id = LittleEndian::read_u64(&data[pos..]);
+        let len = LittleEndian::read_u64(&data[pos + 8..]);
+        let checksum = LittleEndian::read_u32(&data[pos + 16..]);
+        let start = pos + SECTION_HEAD_BYTES;
+        if ((data.len() - start) as u64) < len {
+            return Err(invalid(format!("up to {} is truncated in state machine {}", last_included_id, sm_id)));
+        }
+        let end = start + len as usize;
+        if crc32::checksum_ieee(&data[start..end]) != checksum {
+            return Err(invalid(format!("up to {} is corrupted in state machine {}", last_included_id, sm_id)));
+        }
+        sections.push(Section {
+            sm_id: sm_id,
+            data: data[start..end].to_vec(),
+        });
+        pos = end;
+    }
+    Ok(SnapshotContents {
+        last_included_id: last_included_id,
+        last_included_term: last_included_term,
+        sections: sections,
+    })
+}
+
+// sends the snapshot in chunks from offset 0, the term of the follower when it took all of them
+pub fn send_snapshot(
+    rpc: &Peer, term: u64, leader_id: u64,
+    snapshot: &Snapshot, chunk_size: usize
+) -> Option<u64> {
+    let checksum = crc32::checksum_ieee(&snapshot.data);
+    let chunk_size = max(chunk_size, 1);
+    let num_chunks = max((snapshot.data.len() + chunk_size - 1) / chunk_size, 1);
+    let mut follower_term = 0;
+    for i in 0..num_chunks {
+        let offset = i * chunk_size;
+        let end = ::std::cmp::min(offset + chunk_size, snapshot.data.len());
+        let done = i + 1 == num_chunks;
+        let res = rpc.install_snapshot(
+            &term,
+            &leader_id,
+            &snapshot.last_included_id,
+            &snapshot.last_included_term,
+            &(offset as u64),
+            &snapshot.data[offset..end].to_vec(),
+            &checksum,
+            &done
+        );
+        match res {
+            Ok(Ok(remote_term)) if remote_term <= term => follower_term = remote_term,
+            // the follower is gone, has a newer term, or lost the start of the transfer
+            _ => return None
+        }
+    }
+    Some(follower_term)
+}
+
+enum Buffer {
+    Memory(Vec<u8>),
+    File(PathBuf, File),
+}
+
+// a snapshot received in chunks, it is only installed when all chunks arrived in order
+pub struct IncomingSnapshot {
+    pub leader_id: u64,
+    pub term: u64,
+    pub last_included_id: u64,
+    pub last_included_term: u64,
+    received: u64,
+    digest: crc32::Digest,
+    buffer: Buffer,
+}
+
+impl IncomingSnapshot {
+    // chunks are buffered in the temp file when given, otherwise in memory
+    pub fn new(
+        leader_id: u64, term: u64, last_included_id: u64, last_included_term: u64,
+        temp_path: Option<PathBuf>
+    ) -> io::Result<IncomingSnapshot> {
+        let buffer = match temp_path {
+            Some(path) => {
+                let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+                Buffer::File(path, file)
+            },
+            None => Buffer::Memory(Vec::new())
+        };
+        Ok(IncomingSnapshot {
+            leader_id: leader_id,
+            term: term,
+            last_included_id: last_included_id,
+            last_included_term: last_included_term,
+            received: 0,
+            digest: crc32::Digest::new(crc32::IEEE),
+            buffer: buffer,
+        })
+    }
+    // the chunk continues this transfer
+    pub fn continued_by(&self, leader_id: u64, term: u64, last_included_id: u64, last_included_term: u64, offset: u64) -> bool {
+        self.leader_id == leader_id && self.term == term &&
+            self.last_included_id == last_included_id &&
+            self.last_included_term == last_included_term &&
+            self.received == offset
+    }
+    pub fn append(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match self.buffer {
+            Buffer::Memory(ref mut data) => data.extend_from_slice(chunk),
+            Buffer::File(_, ref mut file) => file.write_all(chunk)?
+        }
+        self.digest.write(chunk);
+        self.received += chunk.len() as u64;
+        Ok(())
+    }
+    // the whole snapshot, InvalidData when it does not match the checksum from the leader
+    pub fn finish(mut self, checksum: u32) -> io::Result<Snapshot> {
+        if self.digest.sum32() != checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Snapshot up to {} does not match its checksum", self.last_included_id)
+            ));
+        }
+        let data = match self.buffer {
+            Buffer::Memory(ref mut data) => ::std::mem::replace(data, Vec::new()),
+            Buffer::File(ref path, _) => {
+                let mut data = Vec::with_capacity(self.received as usize);
+                File::open(path)?.read_to_end(&mut data)?;
+                data
+            }
+        };
+        Ok(Snapshot {
+            last_included_id: self.last_included_id,
+            last_included_term: self.last_included_term,
+            data: data,
+        })
+    }
+}
+
+impl Drop for IncomingSnapshot {
+    fn drop(&mut self) {
+        if let Buffer::File(ref path, _) = self.buffer {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn chunks_in_order() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let checksum = crc32::checksum_ieee(&data);
+        let temp_path = env::temp_dir().join("bifrost-raft-snapshot-unit.partial");
+        for temp_path in vec![None, Some(temp_path.clone())] {
+            let mut incoming = IncomingSnapshot::new(1, 2, 30, 2, temp_path).unwrap();
+            incoming.append(&data[..60]).unwrap();
+            assert!(incoming.continued_by(1, 2, 30, 2, 60));
+            // the leader restarted the transfer or another leader took over
+            assert!(!incoming.continued_by(1, 2, 30, 2, 0));
+            assert!(!incoming.continued_by(3, 3, 30, 2, 60));
+            incoming.append(&data[60..]).unwrap();
+            assert_eq!(incoming.finish(checksum).unwrap().data, data);
+        }
+        assert!(!temp_path.exists());
+        let mut incoming = IncomingSnapshot::new(1, 2, 30, 2, None).unwrap();
+        incoming.append(&data[..99]).unwrap();
+        assert_eq!(incoming.finish(checksum).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn sections() -> Vec<Section> {
+        vec![
+            Section { sm_id: 12, data: vec![1, 2, 3] },
+            Section { sm_id: 1, data: Vec::new() },
+            Section { sm_id: 0, data: vec![9; 100] },
+        ]
+    }
+
+    #[test]
+    fn sections_round_trip() {
+        let data = write_snapshot(30, 2, &sections());
+        let contents = read_snapshot(&data).unwrap();
+        assert_eq!((contents.last_included_id, contents.last_included_term), (30, 2));
+        assert_eq!(contents.sections, sections());
+        assert!(read_snapshot(&write_snapshot(0, 0, &[])).unwrap().sections.is_empty());
+    }
+
+    #[test]
+    fn newer_header_fields_skipped() {
+        let data = write_snapshot(30, 2, &sections());
+        let header_end = MAGIC.len() + 8 + HEADER_BYTES;
+        // a later version with a field of 8 bytes more in the header
+        let mut newer = data[..header_end].to_vec();
+        LittleEndian::write_u32(&mut newer[MAGIC.len()..], FORMAT_VERSION + 1);
+        LittleEndian::write_u32(&mut newer[MAGIC.len() + 4..], HEADER_BYTES as u32 + 8);
+        newer.extend_from_slice(&[7; 8]);
+        newer.extend_from_slice(&data[header_end..]);
+        let contents = read_snapshot(&newer).unwrap();
+        assert_eq!((contents.last_included_id, contents.last_included_term), (30, 2));
+        assert_eq!(contents.sections, sections());
+    }
+
+    #[test]
+    fn corrupted_sections() {
+        let data = write_snapshot(30, 2, &sections());
+        let invalid = |data: &[u8]| read_snapshot(data).err().unwrap().kind() == io::ErrorKind::InvalidData;
+        // opaque data of the state machines before the format
+        assert!(invalid(&[1, 2, 3]));
+        let mut magic = data.clone();
+        magic[0] ^= 1;
+        assert!(invalid(&magic));
+        let mut flipped = data.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert!(invalid(&flipped));
+        for len in vec![MAGIC.len() + 8 + HEADER_BYTES - 1, MAGIC.len() + 8 + HEADER_BYTES + 4, data.len() - 1] {
+            assert!(invalid(&data[..len]), "truncated to {} bytes", len);
+        }
+    }
+}
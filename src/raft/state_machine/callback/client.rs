@@ -1,38 +1,166 @@
 use std::boxed::FnBox;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use parking_lot::RwLock;
+use std::panic::{self, AssertUnwindSafe};
+use std::any::Any;
+use std::cmp::max;
+use parking_lot::{RwLock, Mutex};
+use threadpool::ThreadPool;
+use num_cpus;
 use super::*;
 use rpc::Server;
+use rpc::context;
 use utils::time::get_time;
 
+pub type SubFn = Arc<Fn(Vec<u8>) + Send + Sync>;
+pub type ResyncFn = Arc<Fn() + Send + Sync>;
+
+type Task = Box<FnBox() + Send>;
+// closures of each subscription waiting to run, in the order the events were raised
+type TaskQueues = Arc<Mutex<HashMap<u64, VecDeque<Task>>>>;
+
 pub struct SubscriptionService {
-    pub subs: RwLock<HashMap<SubKey, Vec<Box<Fn(Vec<u8>) + Send + Sync>>>>,
+    // closures of each key with the ids of their subscriptions
+    pub subs: RwLock<HashMap<SubKey, Vec<(u64, SubFn)>>>,
     pub server_address: String,
-    pub session_id: u64
+    pub session_id: u64,
+    // last event taken for each subscription, deliveries of a subscription are taken one at a time
+    taken: Mutex<HashMap<u64, EventSeq>>,
+    // called when events of the subscription may have been lost, its state should be fetched again
+    resyncs: RwLock<HashMap<u64, ResyncFn>>,
+    // closures run there, one of each subscription at a time. a slow one holds up only its own
+    // subscription and a panicking one only loses its event
+    pool: Mutex<ThreadPool>,
+    queues: TaskQueues,
 }
 
 impl Service for SubscriptionService {
     fn notify(&self, key: &SubKey, data: &Vec<u8>) -> Result<(), ()> {
         let subs = self.subs.read();
         if let Some(sub_fns) = subs.get(&key) {
-            for fun in sub_fns {
-                fun(data.clone());
+            for &(sub_id, ref fun) in sub_fns {
+                self.run(sub_id, vec![event_task(fun, context::current_trace_id(), data)]);
             }
         }
         Ok(())
     }
+    // events are taken once queued for the closure, it may not have run yet
+    fn deliver(&self, key: &SubKey, sub_id: &u64, events: &Events) -> Result<Option<EventSeq>, ()> {
+        let mut taken = self.taken.lock();
+        let mut last = taken.get(sub_id).cloned();
+        let contiguous = events.dropped == 0 && match (events.acked, last) {
+            (Some(acked), Some(last)) => acked <= last,
+            (None, Some(_)) => false,
+            (_, None) => true
+        };
+        let mut tasks: Vec<Task> = Vec::new();
+        if !contiguous {
+            debug!("Events of subscription {} lost, last taken {:?}, {} dropped", sub_id, last, events.dropped);
+            if let Some(resync) = self.resyncs.read().get(sub_id).cloned() {
+                tasks.push(Box::new(move || resync()));
+            }
+        }
+        let sub_fn = self.subs.read().get(key)
+            .and_then(|sub_fns| sub_fns.iter().find(|&&(id, _)| id == *sub_id).map(|&(_, ref fun)| fun.clone()));
+        for &(seq, trace_id, ref data) in &events.events {
+            // redelivered, or raised before the events the subscriber took
+            if last.map(|last| seq <= last).unwrap_or(false) {continue;}
+            if let Some(ref fun) = sub_fn {
+                tasks.push(event_task(fun, trace_id, data));
+            }
+            last = Some(seq);
+        }
+        self.run(*sub_id, tasks);
+        if let Some(last) = last {
+            taken.insert(*sub_id, last);
+        }
+        Ok(last)
+    }
 }
 dispatch_rpc_service_functions!(SubscriptionService);
 
+fn event_task(fun: &SubFn, trace_id: Option<u64>, data: &Vec<u8>) -> Task {
+    let (fun, data) = (fun.clone(), data.clone());
+    Box::new(move || {
+        let mut ctx = context::current();
+        ctx.trace_id = trace_id;
+        context::with_context(ctx, || fun(data))
+    })
+}
+
+// runs the tasks queued for the subscription until none is left
+fn run_tasks(queues: TaskQueues, sub_id: u64) {
+    loop {
+        let task = {
+            let mut queues = queues.lock();
+            let next = queues.get_mut(&sub_id).and_then(|tasks| tasks.pop_front());
+            match next {
+                Some(task) => task,
+                None => {
+                    queues.remove(&sub_id);
+                    return;
+                }
+            }
+        };
+        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(move || task())) {
+            error!("Closure of subscription {} panicked, {}", sub_id, panic_message(&e));
+        }
+    }
+}
+
+fn panic_message(e: &Box<Any + Send>) -> String {
+    if let Some(msg) = e.downcast_ref::<&str>() {
+        return msg.to_string();
+    }
+    e.downcast_ref::<String>().cloned().unwrap_or_else(|| String::from("unknown panic"))
+}
+
 impl SubscriptionService {
     pub fn initialize(server: &Arc<Server>) -> Arc<SubscriptionService> {
         let service = Arc::new(SubscriptionService {
             subs: RwLock::new(HashMap::new()),
             server_address: server.address().clone(),
-            session_id: get_time() as u64
+            session_id: get_time() as u64,
+            taken: Mutex::new(HashMap::new()),
+            resyncs: RwLock::new(HashMap::new()),
+            pool: Mutex::new(ThreadPool::new(max(num_cpus::get() * 2, 4))),
+            queues: Arc::new(Mutex::new(HashMap::new())),
         });
         server.register_service(DEFAULT_SERVICE_ID, &service);
         return service;
     }
-}
\ No newline at end of file
+    // a queue without tasks has nothing running for it, the first task starts one
+    fn run(&self, sub_id: u64, tasks: Vec<Task>) {
+        if tasks.is_empty() {return;}
+        let start = {
+            let mut queues = self.queues.lock();
+            let start = !queues.contains_key(&sub_id);
+            queues.entry(sub_id).or_insert_with(|| VecDeque::new()).extend(tasks);
+            start
+        };
+        if start {
+            let queues = self.queues.clone();
+            self.pool.lock().execute(move || run_tasks(queues, sub_id));
+        }
+    }
+    // the closure of the subscription is dropped, notifications for it are ignored from now on
+    pub fn remove(&self, key: &SubKey, sub_id: u64) {
+        let mut subs = self.subs.write();
+        let emptied = match subs.get_mut(key) {
+            Some(sub_fns) => {
+                sub_fns.retain(|&(id, _)| id != sub_id);
+                sub_fns.is_empty()
+            },
+            None => false
+        };
+        if emptied {
+            subs.remove(key);
+        }
+        self.taken.lock().remove(&sub_id);
+        self.resyncs.write().remove(&sub_id);
+    }
+    // replaces the one set before
+    pub fn on_resync(&self, sub_id: u64, resync: ResyncFn) {
+        self.resyncs.write().insert(sub_id, resync);
+    }
+}
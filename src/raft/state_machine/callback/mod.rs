@@ -2,9 +2,93 @@ pub mod client;
 pub mod server;
 //                (server_id, raft_sid, sm_id, fn_id, pattern_id)
 pub type SubKey = (u64, u64, u64, u64);
+// log index of the entry applied when the event was raised, and the order of the event in the entry
+pub type EventSeq = (u64, u32);
+
+use byteorder::{ByteOrder, BigEndian};
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_SM_CALLBACK_DEFAULT_SERVICE) as u64;
 
+// events of a subscription the subscriber did not acknowledge yet, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Events {
+    // last event the subscriber acknowledged, None when the leader does not know of one.
+    // a new leader knows none, subscribers having seen events before resync
+    pub acked: Option<EventSeq>,
+    // events dropped from the buffer since the last delivery, before the subscriber got them
+    pub dropped: u64,
+    // with the trace id of the entry raising them
+    pub events: Vec<(EventSeq, Option<u64>, Vec<u8>)>,
+}
+
+// where events of a sub are emitted, declared with def sub or def replica_sub
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emission {
+    // once, by the leader applying the entry raising it
+    Leader,
+    // on every server applying the entry, for internal subscriptions of the server. subscribers
+    // elsewhere still only get it from the leader
+    Replica,
+}
+
+// keys of events as filters compare them, strings by their bytes so prefixes of them match
+pub trait FilterKey {
+    fn filter_bytes(&self) -> Vec<u8>;
+}
+
+impl FilterKey for String {
+    fn filter_bytes(&self) -> Vec<u8> {self.as_bytes().to_vec()}
+}
+
+impl FilterKey for Vec<u8> {
+    fn filter_bytes(&self) -> Vec<u8> {self.clone()}
+}
+
+impl FilterKey for u64 {
+    fn filter_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 8];
+        BigEndian::write_u64(&mut bytes, *self);
+        bytes
+    }
+}
+
+impl FilterKey for u32 {
+    fn filter_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 4];
+        BigEndian::write_u32(&mut bytes, *self);
+        bytes
+    }
+}
+
+// sub functions whose events carry a key, declared with raft_sub_keys!
+pub trait KeyedSub {
+    type Key: FilterKey;
+}
+
+// checked by the leader before sending an event, subscriptions with one get keyed events only
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SubFilter {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+    // id of a predicate the state machine registered on its callback, and the argument for it
+    Predicate(u64, Vec<u8>),
+}
+
+impl SubFilter {
+    pub fn key<K: FilterKey>(key: &K) -> SubFilter {
+        SubFilter::Key(key.filter_bytes())
+    }
+    pub fn prefix<K: FilterKey>(prefix: &K) -> SubFilter {
+        SubFilter::Prefix(prefix.filter_bytes())
+    }
+    pub fn predicate(id: u64, arg: Vec<u8>) -> SubFilter {
+        SubFilter::Predicate(id, arg)
+    }
+}
+
 service! {
+    version 1;
     rpc notify(key: SubKey, data: Vec<u8>);
-}
\ No newline at end of file
+    // answered with the last event the subscriber took, earlier ones are not sent again
+    rpc deliver(key: SubKey, sub_id: u64, events: Events) -> Option<EventSeq>;
+}
@@ -1,48 +1,166 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Weak};
 use std::any::Any;
-use parking_lot::{RwLock};
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+use parking_lot::{RwLock, Mutex, Condvar};
 use bifrost_hasher::{hash_str, hash_bytes};
-use raft::{RaftService, IS_LEADER};
+use raft::{RaftService, IS_LEADER, APPLYING_INDEX};
 use rpc;
+use rpc::{RPCError, RPCRequestError};
 use utils::bincode;
 use serde;
+use utils::time::{get_time, duration_to_ms};
+use rpc::context;
 use super::super::{OpType};
 use super::super::super::RaftMsg;
 use super::*;
 
+// failed deliveries are tried again that often, or with the next event
+const DELIVERY_RETRY_MS: u64 = 500;
+
+#[derive(Default)]
+struct Outbox {
+    // events were raised since the thread last looked
+    wanted: bool,
+    started: bool,
+    // the subscriber was removed, its thread ends
+    closed: bool,
+}
+
+// the connection comes from the pool for each delivery, a broken one is dialed again. events go
+// out from a thread of the subscriber, one slow to take them holds up no other and no entry
 pub struct Subscriber {
     pub session_id: u64,
-    pub client: Arc<AsyncServiceClient>
+    pub address: String,
+    // ms since the first notification the subscriber did not get, None once it got one again
+    unreachable_since: Mutex<Option<i64>>,
+    outbox: Arc<(Mutex<Outbox>, Condvar)>,
+}
+
+fn subscriber_client(address: &String) -> Result<Arc<AsyncServiceClient>, ()> {
+    rpc::DEFAULT_CLIENT_POOL.get(address)
+        .map(|client| AsyncServiceClient::new(DEFAULT_SERVICE_ID, &client))
+        .map_err(|_| ())
+}
+
+impl Subscriber {
+    fn new(session_id: u64, address: &String) -> Subscriber {
+        Subscriber {
+            session_id: session_id,
+            address: address.clone(),
+            unreachable_since: Mutex::new(None),
+            outbox: Arc::new((Mutex::new(Outbox::default()), Condvar::new())),
+        }
+    }
+    pub fn client(&self) -> Result<Arc<AsyncServiceClient>, ()> {
+        subscriber_client(&self.address)
+    }
+    // the thread of the subscriber starts with its first events
+    fn wake(&self, suber_id: u64, subscriptions: &Arc<RwLock<Subscriptions>>) {
+        let &(ref outbox, ref changed) = &*self.outbox;
+        let mut outbox = outbox.lock();
+        outbox.wanted = true;
+        if !outbox.started {
+            outbox.started = true;
+            start_delivery(suber_id, Arc::downgrade(subscriptions), self.outbox.clone());
+        }
+        changed.notify_one();
+    }
+    fn close(&self) {
+        let &(ref outbox, ref changed) = &*self.outbox;
+        outbox.lock().closed = true;
+        changed.notify_one();
+    }
+    fn notified(&self, reached: bool) {
+        let mut since = self.unreachable_since.lock();
+        if reached {
+            *since = None;
+        } else if since.is_none() {
+            *since = Some(get_time());
+        }
+    }
+    fn unreachable_for(&self, now: i64) -> i64 {
+        self.unreachable_since.lock().map(|since| now - since).unwrap_or(0)
+    }
+}
+
+// events raised for a subscription the subscriber did not take yet, only leaders keep them
+struct EventBuffer {
+    events: VecDeque<(EventSeq, Option<u64>, Vec<u8>)>,
+    acked: Option<EventSeq>,
+    // since the subscriber was last told
+    dropped: u64,
+}
+
+impl EventBuffer {
+    fn new() -> EventBuffer {
+        EventBuffer {
+            events: VecDeque::new(),
+            acked: None,
+            dropped: 0,
+        }
+    }
+    // the oldest events are dropped to keep at most the capacity
+    fn push(&mut self, seq: EventSeq, trace_id: Option<u64>, data: Vec<u8>, capacity: usize) {
+        self.events.push_back((seq, trace_id, data));
+        while self.events.len() > cmp::max(capacity, 1) {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+    }
+    fn pending(&self) -> Option<Events> {
+        if self.events.is_empty() && self.dropped == 0 {return None;}
+        Some(Events {
+            acked: self.acked,
+            dropped: self.dropped,
+            events: self.events.iter().cloned().collect(),
+        })
+    }
+    // the subscriber got the events, those up to the last it took are not sent again. events
+    // dropped meanwhile are told with the next delivery
+    fn taken(&mut self, delivered: &Events, last: Option<EventSeq>) {
+        self.dropped -= cmp::min(self.dropped, delivered.dropped);
+        if let Some(last) = last {
+            while self.events.front().map(|&(seq, _, _)| seq <= last).unwrap_or(false) {
+                self.events.pop_front();
+            }
+            self.acked = Some(last);
+        }
+    }
 }
 
 pub struct Subscriptions {
-    next_id: u64,
     subscribers: HashMap<u64, Subscriber>,
     suber_subs: HashMap<u64, HashSet<u64>>, //suber_id -> sub_id
     subscriptions: HashMap<SubKey, HashSet<u64>>, // key -> sub_id
     sub_suber: HashMap<u64, u64>,
     sub_to_key: HashMap<u64, SubKey>, //sub_id -> sub_key
+    buffers: HashMap<u64, EventBuffer>, //sub_id -> events not taken
+    filters: HashMap<u64, SubFilter>, //sub_id -> filter
 }
 
 impl Subscriptions {
 
     pub fn new() -> Subscriptions {
         Subscriptions {
-            next_id: 0,
             subscribers: HashMap::new(),
             suber_subs: HashMap::new(),
             subscriptions: HashMap::new(),
             sub_suber: HashMap::new(),
             sub_to_key: HashMap::new(),
+            buffers: HashMap::new(),
+            filters: HashMap::new(),
         }
     }
 
-    pub fn subscribe(&mut self, key: SubKey, address: &String, session_id: u64) -> Result<u64, ()> {
-        let sub_service_id = DEFAULT_SERVICE_ID;
+    // ids come from the clients, subscribing again with the id of a subscription of the same
+    // session changes nothing but the filter
+    pub fn subscribe(&mut self, key: SubKey, address: &String, session_id: u64, sub_id: u64,
+                     filter: Option<SubFilter>) -> Result<u64, ()> {
         let suber_id = hash_str(address);
         let suber_exists = self.subscribers.contains_key(&suber_id);
-        let sub_id = self.next_id;
         let require_reload_suber = if suber_exists {
             let suber_session_id = self.subscribers.get(&suber_id).unwrap().session_id;
             let session_match = suber_session_id == session_id;
@@ -52,26 +170,37 @@ impl Subscriptions {
             } else {false}
         } else {true};
         if !self.subscribers.contains_key(&suber_id) {
-            self.subscribers.insert(suber_id, Subscriber {
-                session_id,
-                client: {
-                    if let Ok(client) = rpc::DEFAULT_CLIENT_POOL.get(address) {
-                        AsyncServiceClient::new(sub_service_id, &client)
-                    } else {
-                        return Err(());
-                    }
-                }
-            });
+            self.subscribers.insert(suber_id, Subscriber::new(session_id, address));
+        }
+        if let Some(existing) = self.sub_to_key.get(&sub_id) {
+            if *existing != key {return Err(());}
         }
         self.suber_subs.entry(suber_id).or_insert_with(|| HashSet::new()).insert(sub_id);
         self.subscriptions.entry(key).or_insert_with(|| HashSet::new()).insert(sub_id);
         self.sub_to_key.insert(sub_id, key);
         self.sub_suber.insert(sub_id, suber_id);
-
-        self.next_id += 1;
+        match filter {
+            Some(filter) => self.filters.insert(sub_id, filter),
+            None => self.filters.remove(&sub_id)
+        };
         Ok(sub_id)
     }
 
+    // ids of the subscriptions of the subscriber session
+    pub fn subscribed(&self, address: &String, session_id: u64) -> Vec<u64> {
+        let suber_id = hash_str(address);
+        match self.subscribers.get(&suber_id) {
+            Some(suber) if suber.session_id == session_id => {
+                let mut ids: Vec<u64> = self.suber_subs.get(&suber_id)
+                    .map(|ids| ids.iter().cloned().collect())
+                    .unwrap_or_else(|| Vec::new());
+                ids.sort();
+                ids
+            },
+            _ => Vec::new()
+        }
+    }
+
     pub fn remove_subscriber(&mut self, suber_id: u64) {
         let suber_subs = if let Some(sub_ids) = self.suber_subs.get(&suber_id) {
             sub_ids.iter().cloned().collect()
@@ -79,32 +208,175 @@ impl Subscriptions {
         for subs_id in suber_subs {
             self.remove_subscription(subs_id)
         }
-        self.subscribers.remove(&suber_id);
+        if let Some(subscriber) = self.subscribers.remove(&suber_id) {
+            subscriber.close();
+        }
         self.suber_subs.remove(&suber_id);
     }
 
+    // removing one not there is fine, cancels may be retried
     pub fn remove_subscription(&mut self, id: u64) {
+        self.buffers.remove(&id);
+        self.filters.remove(&id);
         let sub_key = self.sub_to_key.remove(&id);
         if let Some(sub_key) = sub_key {
-            if let Some(ref mut sub_subers) = self.subscriptions.get_mut(&sub_key) {
-                sub_subers.remove(&id);
-                self.sub_suber.remove(&id);
+            let emptied = match self.subscriptions.get_mut(&sub_key) {
+                Some(sub_subers) => {
+                    sub_subers.remove(&id);
+                    sub_subers.is_empty()
+                },
+                None => false
+            };
+            if emptied {
+                self.subscriptions.remove(&sub_key);
+            }
+        }
+        if let Some(suber_id) = self.sub_suber.remove(&id) {
+            if let Some(suber_subs) = self.suber_subs.get_mut(&suber_id) {
+                suber_subs.remove(&id);
+            }
+        }
+    }
+
+    // subscribers not reached for longer than the ttl are dropped with their subscriptions,
+    // clients checking on them register them again once back
+    pub fn remove_unreachable(&mut self, ttl_ms: i64) -> Vec<u64> {
+        let now = get_time();
+        let unreachable: Vec<u64> = self.subscribers.iter()
+            .filter(|&(_, suber)| suber.unreachable_for(now) > ttl_ms)
+            .map(|(id, _)| *id)
+            .collect();
+        for suber_id in &unreachable {
+            self.remove_subscriber(*suber_id);
+        }
+        unreachable
+    }
+
+    pub fn num_subscriptions(&self) -> usize {
+        self.sub_to_key.len()
+    }
+
+    // address of the subscriber with the events of its subscriptions to send, None once removed
+    fn outgoing(&self, suber_id: u64) -> Option<(String, Vec<(u64, SubKey, Events)>)> {
+        let subscriber = match self.subscribers.get(&suber_id) {
+            Some(subscriber) => subscriber,
+            None => return None
+        };
+        let mut outgoing = Vec::new();
+        if let Some(sub_ids) = self.suber_subs.get(&suber_id) {
+            for sub_id in sub_ids {
+                let pending = self.buffers.get(sub_id).and_then(|buffer| buffer.pending());
+                if let (Some(events), Some(key)) = (pending, self.sub_to_key.get(sub_id)) {
+                    outgoing.push((*sub_id, *key, events));
+                }
+            }
+        }
+        Some((subscriber.address.clone(), outgoing))
+    }
+
+    fn delivered(&mut self, suber_id: u64, results: Vec<(u64, Events, Result<Option<EventSeq>, RPCError>)>) {
+        if let Some(subscriber) = self.subscribers.get(&suber_id) {
+            subscriber.notified(results.iter().all(|&(_, _, ref res)| res.is_ok()));
+        }
+        for (sub_id, events, res) in results {
+            match res {
+                Ok(last) => if let Some(buffer) = self.buffers.get_mut(&sub_id) {
+                    buffer.taken(&events, last);
+                },
+                Err(e) => debug!("Cannot deliver events of subscription {}, {:?}", sub_id, e)
             }
         }
     }
 }
 
+fn start_delivery(suber_id: u64, subscriptions: Weak<RwLock<Subscriptions>>, outbox: Arc<(Mutex<Outbox>, Condvar)>) {
+    thread::Builder::new()
+        .name(format!("callback delivery {}", suber_id))
+        .spawn(move || deliver_events(suber_id, subscriptions, outbox))
+        .unwrap();
+}
+
+// events of the subscriber go out one delivery at a time, those not taken are sent again
+fn deliver_events(suber_id: u64, subscriptions: Weak<RwLock<Subscriptions>>, outbox: Arc<(Mutex<Outbox>, Condvar)>) {
+    loop {
+        {
+            let &(ref outbox, ref changed) = &*outbox;
+            let mut outbox = outbox.lock();
+            let retry_at = Instant::now() + Duration::from_millis(DELIVERY_RETRY_MS);
+            while !outbox.wanted && !outbox.closed {
+                if changed.wait_until(&mut outbox, retry_at).timed_out() {break;}
+            }
+            if outbox.closed {return;}
+            outbox.wanted = false;
+        }
+        let subscriptions = match subscriptions.upgrade() {
+            Some(subscriptions) => subscriptions,
+            None => return
+        };
+        let (address, outgoing) = match subscriptions.read().outgoing(suber_id) {
+            Some(outgoing) => outgoing,
+            None => return
+        };
+        if outgoing.is_empty() {continue;}
+        let client = match subscriber_client(&address) {
+            Ok(client) => client,
+            Err(()) => {
+                let failed = outgoing.into_iter()
+                    .map(|(sub_id, _, events)| (sub_id, events, Err(RPCError::TimeoutError)))
+                    .collect();
+                subscriptions.write().delivered(suber_id, failed);
+                continue;
+            }
+        };
+        let requests = outgoing.into_iter()
+            .map(|(sub_id, key, events)| {
+                let req = client.deliver(&key, &sub_id, &events);
+                (sub_id, key, events, req)
+            })
+            .collect::<Vec<_>>();
+        let results = requests.into_iter()
+            .map(|(sub_id, key, events, req)| {
+                let res = match req.wait() {
+                    // subscribers before sequence numbers get the events once, without acking
+                    Err(RPCError::RequestError(RPCRequestError::VersionMismatch { .. })) =>
+                        notify_each(&client, &key, &events),
+                    res => res
+                };
+                (sub_id, events, res)
+            })
+            .collect::<Vec<_>>();
+        subscriptions.write().delivered(suber_id, results);
+    }
+}
+
+fn notify_each(client: &AsyncServiceClient, key: &SubKey, events: &Events) -> Result<Option<EventSeq>, RPCError> {
+    let mut last = None;
+    for &(seq, trace_id, ref data) in &events.events {
+        let mut ctx = context::current();
+        ctx.trace_id = trace_id;
+        context::with_context(ctx, || client.notify(key, data).wait())?;
+        last = Some(seq);
+    }
+    Ok(last)
+}
+
 
 // used for raft services to subscribe directly from state machine instances
 pub struct InternalSubscription {
     action: Box<Fn(&Any) + Sync + Send>
 }
 
+// takes the key of the event and the argument of the filter
+pub type SubPredicate = Box<Fn(&[u8], &[u8]) -> bool + Send + Sync>;
+
 pub struct SMCallback {
     pub subscriptions: Arc<RwLock<Subscriptions>>,
     pub raft_service: Arc<RaftService>,
     pub internal_subs: RwLock<HashMap<u64, Vec<InternalSubscription>>>,
     pub sm_id: u64,
+    predicates: RwLock<HashMap<u64, SubPredicate>>,
+    // last event raised by the state machine
+    last_seq: Mutex<EventSeq>,
 }
 
 
@@ -115,7 +387,8 @@ pub enum NotifyError {
     CannotFindSubscription,
     CannotFindSubscribers,
     CannotFindSubscriber,
-    CannotCastInternalSub
+    CannotCastInternalSub,
+    SubscriberUnreachable,
 }
 
 impl SMCallback {
@@ -127,15 +400,74 @@ impl SMCallback {
             subscriptions: subs,
             raft_service: raft_service.clone(),
             sm_id: state_machine_id,
-            internal_subs: RwLock::new(HashMap::new())
+            internal_subs: RwLock::new(HashMap::new()),
+            last_seq: Mutex::new((0, 0)),
+            predicates: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // events are numbered by the entry raising them, in the order raised for the entry
+    fn next_seq(&self) -> EventSeq {
+        let mut last_seq = self.last_seq.lock();
+        let index = APPLYING_INDEX.get();
+        *last_seq = if index > last_seq.0 {
+            (index, 0)
+        } else {
+            (last_seq.0, last_seq.1 + 1)
+        };
+        *last_seq
+    }
+
+    // for SubFilter::Predicate filters of subscriptions to the state machine with the id
+    pub fn register_predicate<F>(&self, id: u64, predicate: F)
+        where F: Fn(&[u8], &[u8]) -> bool + Send + Sync + 'static
+    {
+        self.predicates.write().insert(id, Box::new(predicate));
+    }
+
+    fn passes(&self, filter: &SubFilter, event_key: &Option<Vec<u8>>) -> bool {
+        let key = match *event_key {
+            Some(ref key) => key,
+            None => return false
+        };
+        match *filter {
+            SubFilter::Key(ref expected) => key == expected,
+            SubFilter::Prefix(ref prefix) => key.starts_with(prefix),
+            SubFilter::Predicate(id, ref arg) => match self.predicates.read().get(&id) {
+                Some(predicate) => predicate(key.as_slice(), arg.as_slice()),
+                None => {
+                    warn!("Predicate {} of subscription filter not registered", id);
+                    false
+                }
+            }
         }
     }
 
     pub fn notify<R>(&self, msg: &RaftMsg<R>, data: R)
-        -> Result<(usize, Vec<NotifyError>, Vec<Result<Result<(), ()>, rpc::RPCError>>), NotifyError>
+        -> Result<(usize, Vec<NotifyError>), NotifyError>
+        where R: serde::Serialize + Send + Sync + Clone + Any + 'static
+    {
+        self.send(msg, None, data)
+    }
+
+    // subscriptions with filters only get the event when its key passes them
+    pub fn notify_keyed<M, R>(&self, msg: &M, key: &M::Key, data: R)
+        -> Result<(usize, Vec<NotifyError>), NotifyError>
+        where M: RaftMsg<R> + KeyedSub,
+              R: serde::Serialize + Send + Sync + Clone + Any + 'static
+    {
+        self.send(msg, Some(key.filter_bytes()), data)
+    }
+
+    // the event is buffered for the subscriptions and their subscribers are woken to send it,
+    // nothing waits on them
+    fn send<R>(&self, msg: &RaftMsg<R>, event_key: Option<Vec<u8>>, data: R)
+        -> Result<(usize, Vec<NotifyError>), NotifyError>
         where R: serde::Serialize + Send + Sync + Clone + Any + 'static
     {
-        if !IS_LEADER.get() {return Err(NotifyError::IsNotLeader);}
+        let leading = IS_LEADER.get();
+        if !leading && msg.emission() == Emission::Leader {return Err(NotifyError::IsNotLeader);}
+        // events carry the trace id of the committing entry, subscribers see it in their closures
         let (fn_id, op_type, pattern_data) = msg.encode();
         match op_type {
             OpType::SUBSCRIBE => {
@@ -143,43 +475,62 @@ impl SMCallback {
                 let raft_sid = self.raft_service.options.service_id;
                 let sm_id = self.sm_id;
                 let key = (raft_sid, sm_id, fn_id, pattern_id);
-                let internal_subs = self.internal_subs.read();
-                let svr_subs = self.subscriptions.read();
+                {
+                    let internal_subs = self.internal_subs.read();
+                    if let Some(internal_subs) = internal_subs.get(&pattern_id) {
+                        for is in internal_subs {
+                            (is.action)(&data)
+                        }
+                    }
+                }
+                // subscribers elsewhere get events from the leader only
+                if !leading {return Ok((0, Vec::new()));}
+                let seq = self.next_seq();
+                let trace_id = context::current_trace_id();
+                let data = bincode::serialize(&data);
+                let capacity = self.raft_service.options.callback_buffer;
+                let ttl_ms = duration_to_ms(self.raft_service.options.subscriber_ttl) as i64;
+                let mut svr_subs = self.subscriptions.write();
+                let svr_subs = &mut *svr_subs;
                 debug!("Subs key: {:?}", svr_subs.subscriptions.keys());
                 debug!("Looking for: {:?}", &key);
-                if let Some(internal_subs) = internal_subs.get(&pattern_id) {
-                    for is in internal_subs {
-                        (is.action)(&data)
+                let sub_ids: Vec<u64> = match svr_subs.subscriptions.get(&key) {
+                    Some(sub_ids) => sub_ids.iter().cloned().collect(),
+                    None => return Err(NotifyError::CannotFindSubscription)
+                };
+                let mut errors = Vec::new();
+                let mut notified = 0;
+                for sub_id in &sub_ids {
+                    if let Some(filter) = svr_subs.filters.get(sub_id) {
+                        if !self.passes(filter, &event_key) {continue;}
                     }
-                }
-                if let Some(sub_ids) = svr_subs.subscriptions.get(&key) {
-                    let data = bincode::serialize(&data);
-                    let sub_result: Vec<_> = sub_ids.iter().map(|sub_id| {
-                        if let Some(subscriber_id) = svr_subs.sub_suber.get(&sub_id) {
-                            if let Some(subscriber) = svr_subs.subscribers.get(&subscriber_id) {
-                                let client = &subscriber.client;
-                                Ok(client.notify(&key, &data))
-                            } else {
-                                Err(NotifyError::CannotFindSubscriber)
-                            }
-                        } else {
-                            Err(NotifyError::CannotFindSubscribers)
+                    let suber_id = match svr_subs.sub_suber.get(sub_id) {
+                        Some(suber_id) => *suber_id,
+                        None => {
+                            errors.push(NotifyError::CannotFindSubscribers);
+                            continue;
                         }
-                    }).collect();
-                    let errors = sub_result.iter()
-                        .filter(|r| r.is_err())
-                        .map(|r| {if let &Err(e) = r { Some(e) } else { None }})
-                        .map(|o| o.unwrap())
-                        .collect::<Vec<NotifyError>>();
-                    let response = sub_result.into_iter()
-                        .filter(|r| r.is_ok())
-                        .map(|r| r.unwrap())
-                        .map(|req| req.wait())
-                        .collect::<Vec<_>>();
-                    return Ok((sub_ids.len(), errors, response));
-                } else {
-                    return Err(NotifyError::CannotFindSubscription)
+                    };
+                    let subscriber = match svr_subs.subscribers.get(&suber_id) {
+                        Some(subscriber) => subscriber,
+                        None => {
+                            errors.push(NotifyError::CannotFindSubscriber);
+                            continue;
+                        }
+                    };
+                    svr_subs.buffers
+                        .entry(*sub_id)
+                        .or_insert_with(|| EventBuffer::new())
+                        .push(seq, trace_id, data.clone(), capacity);
+                    subscriber.wake(suber_id, &self.subscriptions);
+                    notified += 1;
+                }
+                let now = get_time();
+                if svr_subs.subscribers.values().any(|suber| suber.unreachable_for(now) > ttl_ms) {
+                    let removed = svr_subs.remove_unreachable(ttl_ms);
+                    warn!("Dropped subscribers {:?} unreachable for over {}ms", removed, ttl_ms);
                 }
+                return Ok((notified, errors));
             },
             _ => {
                 return Err(NotifyError::OpTypeNotSubscribe)
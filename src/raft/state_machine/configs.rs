@@ -1,20 +1,47 @@
-use raft::SyncServiceClient;
+use raft::transport::{Peer, Transport};
 use rpc;
 use super::*;
-use super::callback::SubKey;
+use super::callback::{SubKey, SubFilter};
 use super::callback::server::Subscriptions;
 use bifrost_hasher::hash_str;
 use std::sync::Arc;
 use parking_lot::{RwLock};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use utils::bincode;
 
 pub const CONFIG_SM_ID: u64 = 1;
 
+lazy_static! {
+    // connections of their own for heartbeats and elections, they never queue behind entries or snapshots
+    pub static ref CONTROL_CLIENT_POOL: rpc::ClientPool = rpc::ClientPool::new();
+}
+
+// learners are replicated to, but never vote and do not count for commitment.
+// witnesses vote and count, but keep no state machine data and never lead
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberRole {
+    Voter,
+    Learner,
+    Witness,
+}
+
 pub struct RaftMember {
-    pub rpc: Arc<SyncServiceClient>,
+    // entries and snapshots, the leader pipelines entries through it without waiting on the follower
+    pub rpc: Arc<Peer>,
+    // empty append entries, votes and leadership transfers go here
+    pub control_rpc: Arc<Peer>,
     pub address: String,
     pub id: u64,
+    pub role: MemberRole,
+}
+
+impl RaftMember {
+    pub fn is_voter(&self) -> bool {
+        self.role == MemberRole::Voter || self.role == MemberRole::Witness
+    }
+    pub fn is_witness(&self) -> bool {
+        self.role == MemberRole::Witness
+    }
 }
 
 pub struct Configures {
@@ -22,9 +49,10 @@ pub struct Configures {
     // keep it in arc lock for reference in callback server.rs
     pub subscriptions: Arc<RwLock<Subscriptions>>,
     service_id: u64,
+    transport: Arc<Transport>,
 }
 
-pub type MemberConfigSnapshot = HashSet<String>;
+pub type MemberConfigSnapshot = HashMap<String, MemberRole>;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSnapshot {
@@ -33,31 +61,43 @@ pub struct ConfigSnapshot {
 }
 
 raft_state_machine! {
-    def cmd new_member_(address: String);
+    // adds the member, or changes the role of an existing one
+    def cmd new_member_(address: String, role: MemberRole);
     def cmd del_member_(address: String);
     def qry member_address() -> Vec<String>;
 
-    def cmd subscribe(key: SubKey, address: String, session_id: u64) -> u64;
+    // sub_id is made by the client, registering it again is fine
+    def cmd subscribe(key: SubKey, address: String, session_id: u64, sub_id: u64) -> u64;
+    // events of the subscription are only sent when their key passes the filter
+    def cmd subscribe_filtered(key: SubKey, address: String, session_id: u64, sub_id: u64, filter: SubFilter) -> u64;
+    def cmd unsubscribe(sub_id: u64);
+    def qry subscribed(address: String, session_id: u64) -> Vec<u64>;
 }
 
 impl StateMachineCmds for Configures {
-    fn new_member_(&mut self, address: String) -> Result<(), ()> {
+    fn new_member_(&mut self, address: String, role: MemberRole) -> Result<(), ()> {
         let addr = address.clone();
         let id = hash_str(&addr);
-        if !self.members.contains_key(&id) {
-            match rpc::DEFAULT_CLIENT_POOL.get(&address) {
-                Ok(client) => {
-                    self.members.insert(id, RaftMember {
-                        rpc: SyncServiceClient::new(self.service_id, &client),
-                        address,
-                        id,
-                    });
-                    return Ok(());
-                },
-                Err(_) => {}
+        if let Some(member) = self.members.get_mut(&id) {
+            if member.role != role {
+                member.role = role;
+                return Ok(());
             }
+            return Err(());
+        }
+        match self.transport.connect(&address, self.service_id) {
+            Some((rpc, control_rpc)) => {
+                self.members.insert(id, RaftMember {
+                    rpc,
+                    control_rpc,
+                    address,
+                    id,
+                    role,
+                });
+                Ok(())
+            },
+            None => Err(())
         }
-        Err(())
     }
     fn del_member_(&mut self, address: String) -> Result<(),()> {
         let hash = hash_str(&address);
@@ -71,9 +111,20 @@ impl StateMachineCmds for Configures {
         }
         Ok(members)
     }
-    fn subscribe(&mut self, key: SubKey, address: String, session_id: u64) -> Result<u64, ()> {
+    fn subscribe(&mut self, key: SubKey, address: String, session_id: u64, sub_id: u64) -> Result<u64, ()> {
         let mut subs = self.subscriptions.write();
-        subs.subscribe(key, &address, session_id)
+        subs.subscribe(key, &address, session_id, sub_id, None)
+    }
+    fn subscribe_filtered(&mut self, key: SubKey, address: String, session_id: u64, sub_id: u64, filter: SubFilter) -> Result<u64, ()> {
+        let mut subs = self.subscriptions.write();
+        subs.subscribe(key, &address, session_id, sub_id, Some(filter))
+    }
+    fn unsubscribe(&mut self, sub_id: u64) -> Result<(), ()> {
+        self.subscriptions.write().remove_subscription(sub_id);
+        Ok(())
+    }
+    fn subscribed(&self, address: String, session_id: u64) -> Result<Vec<u64>, ()> {
+        Ok(self.subscriptions.read().subscribed(&address, session_id))
     }
 }
 
@@ -81,10 +132,10 @@ impl StateMachineCtl for Configures {
     raft_sm_complete!();
     fn snapshot(&self) -> Option<Vec<u8>> {
         let mut snapshot = ConfigSnapshot{
-            members: HashSet::with_capacity(self.members.len()),
+            members: HashMap::with_capacity(self.members.len()),
         };
         for (_, member) in self.members.iter() {
-            snapshot.members.insert(member.address.clone());
+            snapshot.members.insert(member.address.clone(), member.role);
         }
         Some(bincode::serialize(&snapshot))
     }
@@ -96,29 +147,29 @@ impl StateMachineCtl for Configures {
 }
 
 impl Configures {
-    pub fn new(service_id: u64) -> Configures {
+    pub fn new(service_id: u64, transport: Arc<Transport>) -> Configures {
         Configures {
             members: HashMap::new(),
             service_id: service_id,
-            subscriptions: Arc::new(RwLock::new(Subscriptions::new()))
+            subscriptions: Arc::new(RwLock::new(Subscriptions::new())),
+            transport: transport,
         }
     }
     fn recover_members (&mut self, snapshot: &MemberConfigSnapshot) {
-        let mut curr_members: MemberConfigSnapshot = HashSet::with_capacity(self.members.len());
-        for (_, member) in self.members.iter() {
-            curr_members.insert(member.address.clone());
-        }
-        let to_del = curr_members.difference(snapshot);
-        let to_add = snapshot.difference(&curr_members);
+        let to_del: Vec<String> = self.members.values()
+            .filter(|member| !snapshot.contains_key(&member.address))
+            .map(|member| member.address.clone())
+            .collect();
         for addr in to_del {
-            self.del_member(addr.clone());
+            self.del_member(addr);
         }
-        for addr in to_add {
-            self.new_member(addr.clone());
+        for (addr, role) in snapshot.iter() {
+            // members already in the same role are kept as they are
+            self.new_member_(addr.clone(), *role);
         }
     }
     pub fn new_member(&mut self, address: String) -> Result<(),()> {
-        self.new_member_(address)
+        self.new_member_(address, MemberRole::Voter)
     }
     pub fn del_member(&mut self, address: String) -> Result<(),()> {
         self.del_member_(address)
@@ -126,4 +177,10 @@ impl Configures {
     pub fn member_existed(&self, id: u64) -> bool {
         self.members.contains_key(&id)
     }
+    pub fn is_voter(&self, id: u64) -> bool {
+        self.members.get(&id).map(|member| member.is_voter()).unwrap_or(false)
+    }
+    pub fn is_witness(&self, id: u64) -> bool {
+        self.members.get(&id).map(|member| member.is_witness()).unwrap_or(false)
+    }
 }
\ No newline at end of file
@@ -6,30 +6,37 @@ macro_rules! raft_return_type {
     ($out: ty, $error: ty) => {::std::result::Result<$out, $error>};
 }
 
+// functions declared with ctx first, like def cmd set(ctx, v: u64), take the command context before the arguments
 #[macro_export]
 macro_rules! raft_trait_fn {
-    (qry $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
-        fn $fn_name(&self, $($arg:$in_),*) -> raft_return_type!($out, $error);
+    (qry [$( $ctx:ident )*] $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
+        fn $fn_name(&self, $($ctx: $crate::raft::state_machine::CommandCtx,)* $($arg:$in_),*) -> raft_return_type!($out, $error);
     };
-    (cmd $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
-        fn $fn_name(&mut self, $($arg:$in_),*) -> raft_return_type!($out, $error);
+    (cmd [$( $ctx:ident )*] $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
+        fn $fn_name(&mut self, $($ctx: $crate::raft::state_machine::CommandCtx,)* $($arg:$in_),*) -> raft_return_type!($out, $error);
     };
-    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {}
+    (sub [$( $ctx:ident )*] $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {};
+    (replica_sub [$( $ctx:ident )*] $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {}
 }
 
 #[macro_export]
 macro_rules! raft_client_fn {
     (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
         pub fn $fn_name<F>(&self, f: F, $($arg:$in_),* )
-        -> Result<Result<u64, SubscriptionError>, ExecError>
+        -> Result<Result<SubscriptionHandle, SubscriptionError>, ExecError>
         where F: Fn(raft_return_type!($out, $error)) + 'static + Send + Sync {
+            let sm_id = self.sm_id;
+            let client = &self.client;
             self.client.subscribe(
-                self.sm_id,
+                sm_id,
                 $fn_name::new($($arg,)*),
                 f
-            )
+            ).map(|subscribed| subscribed.map(|sub_id| SubscriptionHandle::new(client, sm_id, sub_id)))
         }
     };
+    (replica_sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
+        raft_client_fn!(sub $fn_name( $( $arg : $in_ ),* ) -> $out | $error);
+    };
     ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
         pub fn $fn_name(&self, $($arg:$in_),*)
         -> Result<raft_return_type!($out, $error), ExecError> {
@@ -41,140 +48,287 @@ macro_rules! raft_client_fn {
     };
 }
 
+// commands of SMClient::with_index, answered with the index of the command in the log as well
+#[macro_export]
+macro_rules! raft_indexed_client_fn {
+    (cmd $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {
+        pub fn $fn_name(&self, $($arg:$in_),*)
+        -> Result<(raft_return_type!($out, $error), u64), ExecError> {
+            self.client.execute_with_index(
+                self.sm_id,
+                &$fn_name::new($($arg,)*)
+            )
+        }
+    };
+    ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty) => {};
+}
+
 #[macro_export]
 macro_rules! raft_fn_op_type {
     (qry) => {$crate::raft::state_machine::OpType::QUERY};
     (cmd) => {$crate::raft::state_machine::OpType::COMMAND};
     (sub) => {$crate::raft::state_machine::OpType::SUBSCRIBE};
+    (replica_sub) => {$crate::raft::state_machine::OpType::SUBSCRIBE};
+}
+
+// def sub events are emitted by the leader only, def replica_sub ones on every server applying them
+#[macro_export]
+macro_rules! raft_fn_emission {
+    (replica_sub) => {$crate::raft::state_machine::callback::Emission::Replica};
+    ($others:ident) => {$crate::raft::state_machine::callback::Emission::Leader};
+}
+
+#[macro_export]
+macro_rules! raft_call_fn {
+    ([ctx] $s: ident $fn_name:ident $c: ident ( $( $arg:ident ),* )) => {$s.$fn_name(*$c, $($arg),*)};
+    ([] $s: ident $fn_name:ident $c: ident ( $( $arg:ident ),* )) => {$s.$fn_name($($arg),*)};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_fn {
-    ($fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {{
-        let decoded: ($($in_,)*) = $crate::utils::bincode::deserialize($d);
-        let ($($arg,)*) = decoded;
-        let f_result = $s.$fn_name($($arg),*);
-        Some($crate::utils::bincode::serialize(&f_result))
+    ([$codec:ty] [$( $ctx:ident )*] $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {{
+        let decoded: Result<($($in_,)*), _> = <$codec as $crate::utils::codec::WireCodec>::decode($d);
+        match decoded {
+            Ok(($($arg,)*)) => {
+                let f_result = raft_call_fn!([$( $ctx )*] $s $fn_name $c ( $( $arg ),* ));
+                match <$codec as $crate::utils::codec::WireCodec>::try_encode(&f_result) {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        error!("Cannot encode return of {}, {:?}", stringify!($fn_name), e);
+                        None
+                    }
+                }
+            },
+            Err(e) => {
+                error!("Cannot decode arguments for {}, {:?}", stringify!($fn_name), e);
+                None
+            }
+        }
     }};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_cmd {
-    (cmd $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (cmd [$codec:ty] [$( $ctx:ident )*] $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
+        raft_dispatch_fn!([$codec] [$( $ctx )*] $fn_name $s $c $d( $( $arg : $in_ ),* ))
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
+    ($others:ident [$codec:ty] [$( $ctx:ident )*] $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_qry {
-    (qry $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (qry [$codec:ty] [$( $ctx:ident )*] $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
+        raft_dispatch_fn!([$codec] [$( $ctx )*] $fn_name $s $c $d( $( $arg : $in_ ),* ))
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
+    ($others:ident [$codec:ty] [$( $ctx:ident )*] $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
 macro_rules! raft_sm_complete {
     () => {
-        fn fn_dispatch_cmd(&mut self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {self.dispatch_cmd_(fn_id, data)}
-        fn fn_dispatch_qry(&self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {self.dispatch_qry_(fn_id, data)}
+        fn fn_dispatch_cmd(&mut self, ctx: &$crate::raft::state_machine::CommandCtx, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
+            self.dispatch_cmd_(ctx, fn_id, data)
+        }
+        fn fn_dispatch_qry(&self, ctx: &$crate::raft::state_machine::CommandCtx, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
+            self.dispatch_qry_(ctx, fn_id, data)
+        }
         fn op_type(&mut self, fn_id: u64) -> Option<$crate::raft::state_machine::OpType> {self.op_type_(fn_id)}
     };
 }
 
+// sub functions whose events carry a key of the type, invoked next to raft_state_machine!.
+// state machines raise them with SMCallback::notify_keyed, subscribers filter them with
+// SMClient::subscribe_where
+#[macro_export]
+macro_rules! raft_sub_keys {
+    ($( $fn_name:ident : $key:ty; )*) => {
+        $(
+            impl $crate::raft::state_machine::callback::KeyedSub for commands::$fn_name {
+                type Key = $key;
+            }
+        )*
+    };
+}
+
 #[macro_export]
 macro_rules! raft_state_machine {
     (
+        codec $codec:ty;
         $(
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)* $(| $error:ty)*;
+            def $smt:ident $fn_name:ident $args:tt $(-> $out:ty)* $(| $error:ty)*;
         )*
     ) => {
-        raft_state_machine! {{
-            $(
-                $(#[$attr])*
-                def $smt $fn_name( $( $arg : $in_ ),* ) $(-> $out)* $(| $error)*;
-            )*
-        }}
+        raft_state_machine! {
+            @codec [$codec]
+            {
+                $(
+                    $(#[$attr])*
+                    def $smt $fn_name $args $(-> $out)* $(| $error)*;
+                )*
+            }
+        }
     };
     (
+        $(
+            $(#[$attr:meta])*
+            def $smt:ident $fn_name:ident $args:tt $(-> $out:ty)* $(| $error:ty)*;
+        )*
+    ) => {
+        raft_state_machine! {
+            @codec [$crate::utils::codec::DefaultCodec]
+            {
+                $(
+                    $(#[$attr])*
+                    def $smt $fn_name $args $(-> $out)* $(| $error)*;
+                )*
+            }
+        }
+    };
+    (
+        @codec [$codec:ty]
         {
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ); // No return, no error
+            def $smt:ident $fn_name:ident $args:tt; // No return, no error
 
             $( $unexpanded:tt )*
         }
         $( $expanded:tt )*
     ) => {
         raft_state_machine! {
+            @codec [$codec]
             { $( $unexpanded )* }
 
             $( $expanded )*
 
             $(#[$attr])*
-            def $smt $fn_name( $( $arg : $in_ ),* ) -> () | ();
+            def $smt $fn_name $args -> () | ();
         }
     };
     (
+        @codec [$codec:ty]
         {
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty; //return, no error
+            def $smt:ident $fn_name:ident $args:tt -> $out:ty; //return, no error
 
             $( $unexpanded:tt )*
         }
         $( $expanded:tt )*
     ) => {
         raft_state_machine! {
+            @codec [$codec]
             { $( $unexpanded )* }
 
             $( $expanded )*
 
             $(#[$attr])*
-            def $smt $fn_name( $( $arg : $in_ ),* ) -> $out | ();
+            def $smt $fn_name $args -> $out | ();
         }
     };
     (
+        @codec [$codec:ty]
         {
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) | $error:ty; //no return, error
+            def $smt:ident $fn_name:ident $args:tt | $error:ty; //no return, error
 
             $( $unexpanded:tt )*
         }
         $( $expanded:tt )*
     ) => {
         raft_state_machine! {
+            @codec [$codec]
             { $( $unexpanded )* }
 
             $( $expanded )*
 
             $(#[$attr])*
-            def $smt $fn_name( $( $arg : $in_ ),* ) -> () | $error;
+            def $smt $fn_name $args -> () | $error;
         }
     };
     (
+        @codec [$codec:ty]
         {
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty; //return, error
+            def $smt:ident $fn_name:ident $args:tt -> $out:ty | $error:ty; //return, error
 
             $( $unexpanded:tt )*
         }
         $( $expanded:tt )*
     ) => {
         raft_state_machine! {
+            @codec [$codec]
             { $( $unexpanded )* }
 
             $( $expanded )*
 
             $(#[$attr])*
-            def $smt $fn_name( $( $arg : $in_ ),* ) -> $out | $error;
+            def $smt $fn_name $args -> $out | $error;
         }
     };
     (
-        {} // all expanded
+        @codec [$codec:ty]
+        {} // returns and errors expanded
         $(
+            $(#[$attr:meta])*
+            def $smt:ident $fn_name:ident $args:tt -> $out:ty | $error:ty;
+        )*
+    ) => {
+        raft_state_machine! {
+            @ctx [$codec]
+            {
+                $(
+                    $(#[$attr])*
+                    def $smt $fn_name $args -> $out | $error;
+                )*
+            }
+        }
+    };
+    (
+        @ctx [$codec:ty]
+        {
+            $(#[$attr:meta])*
+            def $smt:ident $fn_name:ident ( ctx $(, $arg:ident : $in_:ty )* ) -> $out:ty | $error:ty; // takes the context
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        raft_state_machine! {
+            @ctx [$codec]
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+
+            $(#[$attr])*
+            def $smt $fn_name [ctx] ( $( $arg : $in_ ),* ) -> $out | $error;
+        }
+    };
+    (
+        @ctx [$codec:ty]
+        {
             $(#[$attr:meta])*
             def $smt:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty;
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        raft_state_machine! {
+            @ctx [$codec]
+            { $( $unexpanded )* }
+
+            $( $expanded )*
+
+            $(#[$attr])*
+            def $smt $fn_name [] ( $( $arg : $in_ ),* ) -> $out | $error;
+        }
+    };
+    (
+        @ctx [$codec:ty]
+        {} // all expanded
+        $(
+            $(#[$attr:meta])*
+            def $smt:ident $fn_name:ident [$( $ctx:ident )*] ( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty;
         )*
     ) => {
         pub mod commands {
@@ -192,15 +346,19 @@ macro_rules! raft_state_machine {
                             &self.data
                         )
                     }
-                    fn decode_return(&self, data: &Vec<u8>) -> raft_return_type!($out, $error) {
-                        $crate::utils::bincode::deserialize(data)
+                    fn decode_return(&self, data: &Vec<u8>)
+                        -> Result<raft_return_type!($out, $error), $crate::utils::codec::CodecError> {
+                        <$codec as $crate::utils::codec::WireCodec>::decode(data)
+                    }
+                    fn emission(&self) -> $crate::raft::state_machine::callback::Emission {
+                        raft_fn_emission!($smt)
                     }
                 }
                 impl $fn_name {
                     pub fn new($($arg:&$in_),*) -> $fn_name {
                         let req_data = ($($arg,)*);
                         $fn_name {
-                            data: $crate::utils::bincode::serialize(&req_data)
+                            data: <$codec as $crate::utils::codec::WireCodec>::encode(&req_data)
                         }
                     }
                 }
@@ -209,7 +367,7 @@ macro_rules! raft_state_machine {
         pub trait StateMachineCmds: $crate::raft::state_machine::StateMachineCtl {
            $(
                 $(#[$attr])*
-                raft_trait_fn!($smt $fn_name( $( $arg : $in_ ),* ) -> $out | $error);
+                raft_trait_fn!($smt [$( $ctx )*] $fn_name( $( $arg : $in_ ),* ) -> $out | $error);
            )*
            fn op_type_(&self, fn_id: u64) -> Option<$crate::raft::state_machine::OpType> {
                 match fn_id as usize {
@@ -222,10 +380,12 @@ macro_rules! raft_state_machine {
                    }
                 }
            }
-           fn dispatch_cmd_(&mut self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
+           // state machines without functions taking the context leave it
+           #[allow(unused_variables)]
+           fn dispatch_cmd_(&mut self, ctx: &$crate::raft::state_machine::CommandCtx, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
                match fn_id as usize {
                    $(hash_ident!($fn_name) => {
-                        raft_dispatch_cmd!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                        raft_dispatch_cmd!($smt [$codec] [$( $ctx )*] $fn_name self ctx data( $( $arg : $in_ ),* ))
                    }),*
                    _ => {
                        debug!("Undefined function id: {}", fn_id);
@@ -233,10 +393,11 @@ macro_rules! raft_state_machine {
                    }
                }
            }
-           fn dispatch_qry_(&self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
+           #[allow(unused_variables)]
+           fn dispatch_qry_(&self, ctx: &$crate::raft::state_machine::CommandCtx, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>> {
                match fn_id as usize {
                    $(hash_ident!($fn_name) => {
-                        raft_dispatch_qry!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                        raft_dispatch_qry!($smt [$codec] [$( $ctx )*] $fn_name self ctx data( $( $arg : $in_ ),* ))
                    }),*
                    _ => {
                        debug!("Undefined function id: {}", fn_id);
@@ -247,8 +408,10 @@ macro_rules! raft_state_machine {
         }
         pub mod client {
             use std::sync::Arc;
+            use std::time::Duration;
             use $crate::raft::state_machine::master::ExecError;
-            use $crate::raft::client::{RaftClient, SubscriptionError};
+            use $crate::raft::client::{RaftClient, SubscriptionError, SubscriptionHandle};
+            use $crate::raft::state_machine::callback::{KeyedSub, SubFilter};
             use self::commands::*;
             use super::*;
 
@@ -267,6 +430,37 @@ macro_rules! raft_state_machine {
                         sm_id: sm_id
                     }
                }
+               // events of the keyed sub only come when their key passes the filter
+               pub fn subscribe_where<M, R, F>(&self, msg: M, filter: SubFilter, f: F)
+               -> Result<Result<SubscriptionHandle, SubscriptionError>, ExecError>
+               where M: $crate::raft::RaftMsg<R> + KeyedSub + 'static,
+                     F: Fn(R) + 'static + Send + Sync {
+                    let sm_id = self.sm_id;
+                    let client = &self.client;
+                    self.client.subscribe_filtered(sm_id, msg, Some(filter), f)
+                        .map(|subscribed| subscribed.map(|sub_id| SubscriptionHandle::new(client, sm_id, sub_id)))
+               }
+               // commands answering with their index in the log as well
+               pub fn with_index(&self) -> IndexedSMClient {
+                    IndexedSMClient {
+                        client: self.client.clone(),
+                        sm_id: self.sm_id
+                    }
+               }
+               // true once every member applied entries up to the index
+               pub fn wait_applied(&self, index: u64, timeout: Duration) -> bool {
+                    self.client.wait_applied(index, timeout)
+               }
+            }
+
+            pub struct IndexedSMClient {
+                client: Arc<RaftClient>,
+                sm_id: u64
+            }
+            impl IndexedSMClient {
+               $(
+                  raft_indexed_client_fn!($smt $fn_name( $( $arg : &$in_ ),* ) -> $out | $error);
+               )*
             }
         }
     };
@@ -1,10 +1,17 @@
 use super::super::*;
 use super::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use parking_lot::RwLock;
 use self::configs::{Configures, RaftMember, CONFIG_SM_ID};
+use self::sessions::Sessions;
+use super::super::snapshot::{Section, read_snapshot, write_snapshot};
+use super::super::transport::Transport;
+use futures::{future, Future};
+use std::cmp::max;
 use utils::bincode;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ExecError {
     SmNotFound,
     FnNotFound,
@@ -12,7 +19,26 @@ pub enum ExecError {
     CannotConstructClient,
     NotCommitted,
     Unknown,
-    TooManyRetry,
+    // transient failures were retried as many times as the policy allows
+    TooManyRetry { attempts: u32 },
+    // return value cannot be decoded by the state machine codec
+    DecodeError,
+    // the command was not proposed because its request deadline had passed
+    DeadlineExceeded,
+    // another membership change is not committed yet, only one is in flight at a time
+    ConfigChangeInProgress,
+    // the session of the command is gone or it was answered before, it may have been applied
+    SessionExpired,
+    // the leader stepped down before the command was committed, it may still be applied
+    LostLeadership,
+    // the leader has too many entries not applied yet, the command was not proposed
+    ProposalQueueFull,
+    // the payload of the command is larger than servers take, it was not proposed
+    EntryTooLarge { max_bytes: u64 },
+    // some member may not apply batches of commands, the batch was not proposed
+    BatchUnsupported,
+    // no member claimed leadership until the client gave up, the cluster may be in an election
+    NoLeader,
 }
 
 pub enum RegisterResult {
@@ -24,14 +50,23 @@ pub enum RegisterResult {
 pub type ExecOk = Vec<u8>;
 pub type ExecResult = Result<ExecOk, ExecError>;
 pub type SubStateMachine = Box<StateMachineCtl>;
-pub type SnapshotDataItem = (u64, Vec<u8>);
-pub type SnapshotDataItems = Vec<SnapshotDataItem>;
+
+// entries leaders append when elected carry nothing to apply, they commit entries of earlier terms
+pub const NOOP_SM_ID: u64 = 0;
+// entries of client batches, the data is the (sm id, fn id, data) of each command and the response
+// the ExecResult of each
+pub const BATCH_SM_ID: u64 = hash_ident!(BIFROST_RAFT_BATCH_SM) as u64;
 
 raft_state_machine! {}
 
 pub struct MasterStateMachine {
-    subs: HashMap<u64, SubStateMachine>,
-    pub configs: Configures
+    // shared with the threads applying commands of them when applying in parallel
+    subs: HashMap<u64, Arc<RwLock<SubStateMachine>>>,
+    pub configs: Configures,
+    sessions: Sessions,
+    // sections of state machines not registered here, from snapshots of newer servers or taken
+    // before the state machine registered. they go into the next snapshot as they are
+    unknown: BTreeMap<u64, Vec<u8>>,
 }
 
 impl StateMachineCmds for MasterStateMachine {}
@@ -39,30 +74,37 @@ impl StateMachineCmds for MasterStateMachine {}
 impl StateMachineCtl for MasterStateMachine {
     raft_sm_complete!();
     fn snapshot(&self) -> Option<Vec<u8>> {
-        let mut sms: SnapshotDataItems = Vec::with_capacity(self.subs.len());
-        for (sm_id, smc) in self.subs.iter() {
-            let sub_snapshot = smc.snapshot();
-            if let Some(snapshot) = sub_snapshot {
-                sms.push((*sm_id, snapshot));
-            }
-        }
-        sms.push((self.configs.id(), self.configs.snapshot().unwrap()));
-        let data = bincode::serialize(&sms);
-        Some(data)
+        self.sections().map(|sections| write_snapshot(0, 0, &sections))
     }
     fn recover(&mut self, data: Vec<u8>) {
-        let mut sms: SnapshotDataItems = bincode::deserialize(&data);
-        for (sm_id, snapshot) in sms {
-            if let Some(sm) = self.subs.get_mut(&sm_id) {
-                sm.recover(snapshot);
-            } else if sm_id == self.configs.id() {
-                self.configs.recover(snapshot);
-            }
+        match read_snapshot(&data) {
+            Ok(contents) => self.recover_sections(contents.sections),
+            Err(e) => error!("Cannot recover state machines, {}", e)
         }
     }
     fn id(&self) -> u64 {0}
 }
 
+// snapshot of one state machine in the snapshot of the master
+pub fn sub_snapshot(data: &Vec<u8>, sm_id: u64) -> Option<Vec<u8>> {
+    read_snapshot(data).ok().and_then(|contents| {
+        contents.sections.into_iter().find(|section| section.sm_id == sm_id).map(|section| section.data)
+    })
+}
+
+pub fn command_ctx(entry: &LogEntry) -> CommandCtx {
+    CommandCtx {
+        index: entry.id,
+        term: entry.term,
+        leader_time_ms: max(entry.time, 0) as u64,
+        session: entry.session.map(|session| session.id),
+    }
+}
+
+pub fn dispatch_sub_cmd(sm: &RwLock<SubStateMachine>, entry: &LogEntry) -> ExecResult {
+    parse_output(sm.write().as_mut().fn_dispatch_cmd(&command_ctx(entry), entry.fn_id, &entry.data))
+}
+
 fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
     if let Some(d) = r {
         Ok(d)
@@ -72,45 +114,159 @@ fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
 }
 
 impl MasterStateMachine {
-    pub fn new(service_id: u64) -> MasterStateMachine {
+    pub fn new(service_id: u64, session_timeout_ms: i64, transport: Arc<Transport>) -> MasterStateMachine {
         let mut msm = MasterStateMachine {
             subs: HashMap::new(),
-            configs: Configures::new(service_id)
+            configs: Configures::new(service_id, transport),
+            sessions: Sessions::new(session_timeout_ms),
+            unknown: BTreeMap::new(),
         };
         msm
     }
 
     pub fn register(&mut self, smc: SubStateMachine) -> RegisterResult {
         let id = smc.id();
-        if id < 2 {return RegisterResult::RESERVED}
+        if id < 2 || id == BATCH_SM_ID {return RegisterResult::RESERVED}
         if self.subs.contains_key(&id) {return RegisterResult::EXISTED};
-        self.subs.insert(id, smc);
+        let mut smc = smc;
+        // the state before the snapshot is never replayed from the log
+        if let Some(data) = self.unknown.remove(&id) {
+            smc.recover(data);
+        }
+        self.subs.insert(id, Arc::new(RwLock::new(smc)));
         RegisterResult::OK
     }
 
+    // a section for each state machine, None when any of them cannot snapshot, its state would
+    // be lost with the log
+    pub fn sections(&self) -> Option<Vec<Section>> {
+        self.sections_async().wait().ok()
+    }
+
+    // sections as of now, those of the sub state machines may come later from other threads
+    pub fn sections_async(&self) -> Box<Future<Item = Vec<Section>, Error = ()> + Send> {
+        let mut sub_ids: Vec<u64> = self.subs.keys().cloned().collect();
+        sub_ids.sort();
+        let forks: Vec<SnapshotFuture> = sub_ids.iter()
+            .map(|sm_id| self.subs[sm_id].read().snapshot_async())
+            .collect();
+        let mut own = Vec::with_capacity(self.unknown.len() + 2);
+        own.push(Section { sm_id: self.configs.id(), data: self.configs.snapshot().unwrap() });
+        // client sessions go as the state of the master itself
+        own.push(Section { sm_id: self.id(), data: self.sessions.snapshot() });
+        for (sm_id, data) in self.unknown.iter() {
+            own.push(Section { sm_id: *sm_id, data: data.clone() });
+        }
+        Box::new(future::join_all(forks).map(move |datas| {
+            let mut sections: Vec<Section> = sub_ids.into_iter().zip(datas)
+                .map(|(sm_id, data)| Section { sm_id: sm_id, data: data })
+                .collect();
+            sections.extend(own);
+            sections
+        }))
+    }
+
+    pub fn recover_sections(&mut self, sections: Vec<Section>) {
+        let master_id = self.id();
+        // what was kept from the last snapshot is replaced by this one
+        self.unknown.clear();
+        for section in sections {
+            if let Some(sm) = self.subs.get(&section.sm_id) {
+                sm.write().recover(section.data);
+                continue;
+            }
+            if section.sm_id == self.configs.id() {
+                self.configs.recover(section.data);
+            } else if section.sm_id == master_id {
+                self.sessions.recover(section.data);
+            } else {
+                self.unknown.insert(section.sm_id, section.data);
+            }
+        }
+    }
+
     pub fn members(&self) -> &HashMap<u64, RaftMember> {
         &self.configs.members
     }
 
     pub fn commit_cmd(&mut self, entry: &LogEntry) -> ExecResult {
+        if let Some(ref session) = entry.session {
+            if let Some(response) = self.sessions.applied(session) {
+                return response;
+            }
+        }
+        let response = self.dispatch_cmd(entry);
+        if let Some(ref session) = entry.session {
+            self.sessions.apply(session, &response);
+        }
+        response
+    }
+    // the state machine to apply the command on another thread, or the response when it was
+    // applied here for it is not of any sub state machine, or when it should not be applied
+    pub fn prepare_cmd(&mut self, entry: &LogEntry) -> Result<Arc<RwLock<SubStateMachine>>, ExecResult> {
+        let sm = match self.subs.get(&entry.sm_id) {
+            Some(sm) => sm.clone(),
+            None => return Err(self.commit_cmd(entry))
+        };
+        if let Some(ref session) = entry.session {
+            if let Some(response) = self.sessions.applied(session) {
+                return Err(response);
+            }
+            self.sessions.begin(session);
+        }
+        Ok(sm)
+    }
+    // response of a command prepared in prepare_cmd, for retries in the session
+    pub fn respond(&mut self, session: &ClientSession, response: &ExecResult) {
+        self.sessions.respond(session, response);
+    }
+    fn dispatch_cmd(&mut self, entry: &LogEntry) -> ExecResult {
         match entry.sm_id {
+            NOOP_SM_ID => Ok(Vec::new()),
             CONFIG_SM_ID => {
-                parse_output(self.configs.fn_dispatch_cmd(entry.fn_id, &entry.data))
+                parse_output(self.configs.fn_dispatch_cmd(&command_ctx(entry), entry.fn_id, &entry.data))
             }
-            _ => if let Some(sm) = self.subs.get_mut(&entry.sm_id) {
-                parse_output(sm.as_mut().fn_dispatch_cmd(entry.fn_id, &entry.data))
+            BATCH_SM_ID => self.dispatch_batch(entry),
+            _ => if let Some(sm) = self.subs.get(&entry.sm_id) {
+                dispatch_sub_cmd(sm, entry)
             } else {
                 Err(ExecError::SmNotFound)
             }
         }
     }
-    pub fn exec_qry(&self, entry: &LogEntry) -> ExecResult {
+    // commands are applied in the order of the batch, at the index of it. the session is of the
+    // batch, the commands in it have none
+    fn dispatch_batch(&mut self, entry: &LogEntry) -> ExecResult {
+        let commands: Vec<(u64, u64, Vec<u8>)> = match bincode::try_deserialize(&entry.data) {
+            Some(commands) => commands,
+            None => return Err(ExecError::DecodeError)
+        };
+        let responses: Vec<ExecResult> = commands.into_iter().map(|(sm_id, fn_id, data)| {
+            match sm_id {
+                // membership changes go one at a time, batches do not nest
+                CONFIG_SM_ID | BATCH_SM_ID => Err(ExecError::SmNotFound),
+                _ => self.dispatch_cmd(&LogEntry {
+                    id: entry.id,
+                    term: entry.term,
+                    sm_id: sm_id,
+                    fn_id: fn_id,
+                    data: data,
+                    trace_id: entry.trace_id,
+                    session: None,
+                    time: entry.time,
+                    checksum: 0,
+                })
+            }
+        }).collect();
+        Ok(bincode::serialize(&responses))
+    }
+    pub fn exec_qry(&self, ctx: &CommandCtx, entry: &LogEntry) -> ExecResult {
         match entry.sm_id {
             CONFIG_SM_ID => {
-                parse_output(self.configs.fn_dispatch_qry(entry.fn_id, &entry.data))
+                parse_output(self.configs.fn_dispatch_qry(ctx, entry.fn_id, &entry.data))
             }
             _ => if let Some(sm) = self.subs.get(&entry.sm_id) {
-                parse_output(sm.fn_dispatch_qry(entry.fn_id, &entry.data))
+                parse_output(sm.read().fn_dispatch_qry(ctx, entry.fn_id, &entry.data))
             } else {
                 Err(ExecError::SmNotFound)
             }
@@ -1,4 +1,5 @@
 use std::any::Any;
+use futures::{future, Future};
 
 pub enum Storage {
     MEMORY,
@@ -11,12 +12,32 @@ pub enum OpType {
     SUBSCRIBE
 }
 
+// where a command is in the log, the same on every server applying it.
+// queries get the last applied entry and the current term, without time and session
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommandCtx {
+    pub index: u64,
+    pub term: u64,
+    // clock of the leader when it appended the command
+    pub leader_time_ms: u64,
+    pub session: Option<u64>,
+}
+
+// the state of a state machine for a snapshot, Err when it cannot snapshot
+pub type SnapshotFuture = Box<Future<Item = Vec<u8>, Error = ()> + Send>;
+
 pub trait StateMachineCtl: Sync + Send + Any {
     fn id(&self) -> u64;
     fn snapshot(&self) -> Option<Vec<u8>>;
+    // called between commands, the future may finish on another thread while later commands
+    // are applied but has to give the state as of the call, from a copy or a fork of it.
+    // the default snapshots right away, applying waits for it
+    fn snapshot_async(&self) -> SnapshotFuture {
+        Box::new(future::result(self.snapshot().ok_or(())))
+    }
     fn recover(&mut self, data: Vec<u8>);
-    fn fn_dispatch_qry(&self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>>;
-    fn fn_dispatch_cmd(&mut self, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>>;
+    fn fn_dispatch_qry(&self, ctx: &CommandCtx, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>>;
+    fn fn_dispatch_cmd(&mut self, ctx: &CommandCtx, fn_id: u64, data: &Vec<u8>) -> Option<Vec<u8>>;
     fn op_type(&mut self, fn_id: u64) -> Option<OpType>;
 }
 
@@ -28,4 +49,5 @@ pub trait OpTypes {
 pub mod macros;
 pub mod master;
 pub mod configs;
+pub mod sessions;
 pub mod callback;
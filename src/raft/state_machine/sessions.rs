@@ -0,0 +1,125 @@
+use std::cmp::max;
+use std::collections::{HashMap, BTreeMap};
+use raft::ClientSession;
+use super::master::{ExecResult, ExecError};
+use utils::bincode;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Session {
+    // responses of sequences the client has not got yet
+    responses: BTreeMap<u64, ExecResult>,
+    acked: u64,
+    last_active: i64,
+}
+
+// commands applied for each client session, retries get the response of the first one.
+// everything here is replicated, servers must agree on the timeout
+pub struct Sessions {
+    sessions: HashMap<u64, Session>,
+    timeout_ms: i64,
+}
+
+impl Sessions {
+    pub fn new(timeout_ms: i64) -> Sessions {
+        Sessions {
+            sessions: HashMap::new(),
+            timeout_ms: timeout_ms
+        }
+    }
+    // the response when the command should not be applied, for it was applied before or
+    // it cannot be told any more
+    pub fn applied(&mut self, session: &ClientSession) -> Option<ExecResult> {
+        self.expire(session.time);
+        match self.sessions.get_mut(&session.id) {
+            Some(applied) => {
+                applied.last_active = max(applied.last_active, session.time);
+                if session.seq <= applied.acked {
+                    return Some(Err(ExecError::SessionExpired));
+                }
+                applied.responses.get(&session.seq).cloned()
+            },
+            // new sessions have no answers yet
+            None if session.acked > 0 => Some(Err(ExecError::SessionExpired)),
+            None => None
+        }
+    }
+    pub fn apply(&mut self, session: &ClientSession, response: &ExecResult) {
+        self.begin(session);
+        self.respond(session, response);
+    }
+    // the command of the session is being applied, the response comes later in respond
+    pub fn begin(&mut self, session: &ClientSession) {
+        let applied = self.sessions.entry(session.id).or_insert_with(|| Session {
+            responses: BTreeMap::new(),
+            acked: 0,
+            last_active: session.time,
+        });
+        applied.last_active = max(applied.last_active, session.time);
+        applied.acked = max(applied.acked, session.acked);
+        let unacked = applied.responses.split_off(&(applied.acked + 1));
+        applied.responses = unacked;
+    }
+    // the client may have got it from another server, or the session expired in the meantime
+    pub fn respond(&mut self, session: &ClientSession, response: &ExecResult) {
+        if let Some(applied) = self.sessions.get_mut(&session.id) {
+            if session.seq > applied.acked {
+                applied.responses.insert(session.seq, response.clone());
+            }
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+    // by the time of the entries, not the clocks of servers
+    fn expire(&mut self, now: i64) {
+        let timeout_ms = self.timeout_ms;
+        self.sessions.retain(|_, session| now - session.last_active <= timeout_ms);
+    }
+    pub fn snapshot(&self) -> Vec<u8> {
+        bincode::serialize(&self.sessions)
+    }
+    pub fn recover(&mut self, data: Vec<u8>) {
+        self.sessions = bincode::deserialize(&data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(id: u64, seq: u64, acked: u64, time: i64) -> ClientSession {
+        ClientSession { id, seq, acked, time }
+    }
+
+    #[test]
+    fn responds_once_per_sequence() {
+        let mut sessions = Sessions::new(1000);
+        assert!(sessions.applied(&session(1, 1, 0, 0)).is_none());
+        sessions.apply(&session(1, 1, 0, 0), &Ok(vec![1]));
+        assert!(sessions.applied(&session(1, 2, 0, 10)).is_none());
+        sessions.apply(&session(1, 2, 0, 10), &Ok(vec![2]));
+        // retries, even out of order
+        assert_eq!(sessions.applied(&session(1, 2, 0, 20)).unwrap().unwrap(), vec![2]);
+        assert_eq!(sessions.applied(&session(1, 1, 0, 20)).unwrap().unwrap(), vec![1]);
+        // the client got both, their responses are gone
+        sessions.apply(&session(1, 3, 2, 30), &Ok(vec![3]));
+        match sessions.applied(&session(1, 1, 2, 40)) {
+            Some(Err(ExecError::SessionExpired)) => {},
+            other => panic!("expect session expired, got {:?}", other)
+        }
+        assert_eq!(sessions.applied(&session(1, 3, 2, 40)).unwrap().unwrap(), vec![3]);
+
+        // idle sessions expire, their clients cannot be told apart from duplicates
+        assert!(sessions.applied(&session(2, 1, 0, 2000)).is_none());
+        assert_eq!(sessions.len(), 0);
+        match sessions.applied(&session(1, 4, 3, 2000)) {
+            Some(Err(ExecError::SessionExpired)) => {},
+            other => panic!("expect session expired, got {:?}", other)
+        }
+
+        sessions.apply(&session(3, 1, 0, 2000), &Ok(vec![1]));
+        let mut recovered = Sessions::new(1000);
+        recovered.recover(sessions.snapshot());
+        assert_eq!(recovered.applied(&session(3, 1, 0, 2010)).unwrap().unwrap(), vec![1]);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::collections::Bound::{Included, Excluded};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use super::{LogEntry, Snapshot};
+use super::disk::{HardState, StateFile, load_snapshot, save_snapshot};
+use super::wal::{LogFile, SyncPolicy};
+
+// where a server keeps its log, term, vote and snapshot across restarts. the server keeps
+// the log in memory as well, it reads from the store only when it starts.
+// every change returns after it is durable, the server answers for it right after
+pub trait LogStore: Send {
+    // entries come in the order of their ids, after the last entry in the store
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()>;
+    // entries kept with ids in the range, in the order of their ids
+    fn entries(&self, range: Range<u64>) -> io::Result<Vec<LogEntry>>;
+    // removes the entries after the id, they conflict with the leader
+    fn truncate_after(&mut self, id: u64) -> io::Result<()>;
+    // the entries before the id are in a snapshot now, stores may keep some of them for a while
+    fn truncate_before(&mut self, id: u64) -> io::Result<()>;
+    // None when the entry is not kept
+    fn term(&self, id: u64) -> io::Result<Option<u64>>;
+    // saved after every answer of the server, mostly unchanged
+    fn save_hard_state(&mut self, state: &HardState) -> io::Result<()>;
+    // the initial state when the store never saved one
+    fn hard_state(&self) -> io::Result<HardState>;
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()>;
+    // the last snapshot saved
+    fn snapshot(&self) -> io::Result<Option<Snapshot>>;
+    // called periodically, for stores syncing appends in batches
+    fn sync_due(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+fn out_of_order(id: u64, last_id: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Raft log entry {} appended after {}", id, last_id)
+    )
+}
+
+// keeps everything in memory, lost with the process
+pub struct MemoryStore {
+    entries: BTreeMap<u64, LogEntry>,
+    state: HardState,
+    snapshot: Option<Snapshot>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            entries: BTreeMap::new(),
+            state: HardState::default(),
+            snapshot: None,
+        }
+    }
+}
+
+impl LogStore for MemoryStore {
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut last_id = self.entries.keys().cloned().next_back().unwrap_or(0);
+        for entry in entries {
+            if entry.id <= last_id {
+                return Err(out_of_order(entry.id, last_id));
+            }
+            last_id = entry.id;
+        }
+        for entry in entries {
+            self.entries.insert(entry.id, entry.clone());
+        }
+        Ok(())
+    }
+    fn entries(&self, range: Range<u64>) -> io::Result<Vec<LogEntry>> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        Ok(self.entries.range((Included(range.start), Excluded(range.end))).map(|(_, entry)| entry.clone()).collect())
+    }
+    fn truncate_after(&mut self, id: u64) -> io::Result<()> {
+        self.entries.split_off(&(id + 1));
+        Ok(())
+    }
+    fn truncate_before(&mut self, id: u64) -> io::Result<()> {
+        let remaining = self.entries.split_off(&id);
+        self.entries = remaining;
+        Ok(())
+    }
+    fn term(&self, id: u64) -> io::Result<Option<u64>> {
+        Ok(self.entries.get(&id).map(|entry| entry.term))
+    }
+    fn save_hard_state(&mut self, state: &HardState) -> io::Result<()> {
+        self.state = *state;
+        Ok(())
+    }
+    fn hard_state(&self) -> io::Result<HardState> {
+        Ok(self.state)
+    }
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+    fn snapshot(&self) -> io::Result<Option<Snapshot>> {
+        Ok(self.snapshot.clone())
+    }
+}
+
+// term and vote in the state file, the log in segment files and the snapshot in a file of its own
+pub struct FileStore {
+    state: StateFile,
+    // None when only term and vote are kept, the log stays in memory
+    log: Option<LogFile>,
+    snapshot_path: Option<PathBuf>,
+}
+
+impl FileStore {
+    // everything in the directory
+    pub fn open<P: AsRef<Path>>(dir: P, sync_policy: SyncPolicy) -> io::Result<FileStore> {
+        let dir = dir.as_ref();
+        let (state, _) = StateFile::open(dir.join("state"))?;
+        let (log, _) = LogFile::open(dir, sync_policy)?;
+        Ok(FileStore {
+            state: state,
+            log: Some(log),
+            snapshot_path: Some(dir.join("snapshot")),
+        })
+    }
+    // term and vote in the file, nothing else
+    pub fn state_only<P: AsRef<Path>>(path: P) -> io::Result<FileStore> {
+        let (state, _) = StateFile::open(path)?;
+        Ok(FileStore {
+            state: state,
+            log: None,
+            snapshot_path: None,
+        })
+    }
+}
+
+impl LogStore for FileStore {
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        match self.log {
+            Some(ref mut log) => log.append(entries),
+            None => Ok(())
+        }
+    }
+    fn entries(&self, range: Range<u64>) -> io::Result<Vec<LogEntry>> {
+        match self.log {
+            Some(ref log) => log.read(range),
+            None => Ok(Vec::new())
+        }
+    }
+    fn truncate_after(&mut self, id: u64) -> io::Result<()> {
+        match self.log {
+            Some(ref mut log) => log.truncate(id + 1),
+            None => Ok(())
+        }
+    }
+    fn truncate_before(&mut self, id: u64) -> io::Result<()> {
+        // segments are removed as a whole
+        match self.log {
+            Some(ref mut log) if id > 0 => log.compact(id - 1),
+            _ => Ok(())
+        }
+    }
+    fn term(&self, id: u64) -> io::Result<Option<u64>> {
+        Ok(self.entries(id..id + 1)?.first().map(|entry| entry.term))
+    }
+    fn save_hard_state(&mut self, state: &HardState) -> io::Result<()> {
+        self.state.save(state)
+    }
+    fn hard_state(&self) -> io::Result<HardState> {
+        Ok(self.state.saved())
+    }
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        match self.snapshot_path {
+            Some(ref path) => save_snapshot(path, snapshot),
+            None => Ok(())
+        }
+    }
+    fn snapshot(&self) -> io::Result<Option<Snapshot>> {
+        match self.snapshot_path {
+            Some(ref path) => load_snapshot(path),
+            None => Ok(None)
+        }
+    }
+    fn sync_due(&mut self) -> io::Result<()> {
+        match self.log {
+            Some(ref mut log) => log.sync_due(),
+            None => Ok(())
+        }
+    }
+}
+
+// for stores of other crates to check themselves against what the server expects
+pub mod tests {
+    use super::*;
+    use super::super::snapshot::{Section, write_snapshot};
+
+    fn entry(id: u64, term: u64) -> LogEntry {
+        let mut entry = LogEntry {
+            id: id,
+            term: term,
+            sm_id: 1,
+            fn_id: 2,
+            data: vec![id as u8; 16],
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0
+        };
+        entry.checksum = entry.digest();
+        entry
+    }
+
+    fn ids(store: &LogStore, range: Range<u64>) -> Vec<u64> {
+        store.entries(range).unwrap().iter().map(|entry| entry.id).collect()
+    }
+
+    // the store has to be empty, panics on the first difference
+    pub fn verify_log_store(store: &mut LogStore) {
+        assert_eq!(store.hard_state().unwrap(), HardState::default());
+        assert!(store.snapshot().unwrap().is_none());
+        assert!(ids(store, 0..::std::u64::MAX).is_empty());
+        assert_eq!(store.term(1).unwrap(), None);
+
+        store.append(&[entry(1, 1), entry(2, 1), entry(3, 2)]).unwrap();
+        store.append(&[]).unwrap();
+        assert!(store.append(&[entry(3, 2)]).is_err(), "entry appended twice");
+        assert!(store.append(&[entry(5, 2), entry(4, 2)]).is_err(), "entries appended out of order");
+        assert_eq!(ids(store, 0..::std::u64::MAX), vec![1, 2, 3]);
+        assert_eq!(ids(store, 2..3), vec![2]);
+        assert!(ids(store, 3..3).is_empty());
+        assert!(ids(store, 4..10).is_empty());
+        let read = store.entries(3..4).unwrap();
+        assert_eq!((read[0].term, read[0].sm_id, read[0].fn_id), (2, 1, 2));
+        assert_eq!(read[0].data, entry(3, 2).data);
+        assert!(read[0].is_intact());
+        assert_eq!(store.term(2).unwrap(), Some(1));
+        assert_eq!(store.term(3).unwrap(), Some(2));
+        assert_eq!(store.term(4).unwrap(), None);
+
+        // entries conflicting with a new leader are replaced
+        store.truncate_after(1).unwrap();
+        assert_eq!(ids(store, 0..::std::u64::MAX), vec![1]);
+        assert_eq!(store.term(2).unwrap(), None);
+        store.append(&[entry(2, 3), entry(3, 3), entry(4, 3)]).unwrap();
+        assert_eq!(store.term(3).unwrap(), Some(3));
+        store.truncate_after(4).unwrap();
+        assert_eq!(ids(store, 0..::std::u64::MAX), vec![1, 2, 3, 4]);
+
+        // compacted entries may stay for a while, those after are kept
+        store.truncate_before(3).unwrap();
+        assert_eq!(ids(store, 3..::std::u64::MAX), vec![3, 4]);
+        assert!(ids(store, 0..3).iter().all(|id| *id < 3));
+        store.append(&[entry(5, 3)]).unwrap();
+        assert_eq!(ids(store, 3..::std::u64::MAX), vec![3, 4, 5]);
+
+        // a snapshot the log does not agree with replaces all of it
+        store.truncate_after(0).unwrap();
+        assert!(ids(store, 0..::std::u64::MAX).is_empty());
+        store.append(&[entry(10, 4)]).unwrap();
+        assert_eq!(ids(store, 0..::std::u64::MAX), vec![10]);
+        assert_eq!(store.term(10).unwrap(), Some(4));
+
+        let state = HardState { term: 4, vote_for: Some(42), last_log_id: 10 };
+        store.save_hard_state(&state).unwrap();
+        store.save_hard_state(&state).unwrap();
+        assert_eq!(store.hard_state().unwrap(), state);
+
+        // snapshots are in the format of raft::snapshot, stores may read the id and term from it
+        for &(id, ref sm_data) in &[(9, vec![1u8, 2, 3]), (10, vec![4u8; 1024])] {
+            let data = write_snapshot(id, 4, &[Section { sm_id: 12, data: sm_data.clone() }]);
+            store.save_snapshot(&Snapshot {
+                last_included_id: id,
+                last_included_term: 4,
+                data: data.clone(),
+            }).unwrap();
+            let snapshot = store.snapshot().unwrap().unwrap();
+            assert_eq!((snapshot.last_included_id, snapshot.last_included_term), (id, 4));
+            assert_eq!(snapshot.data, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::tests::verify_log_store;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn memory_store() {
+        verify_log_store(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = env::temp_dir().join("bifrost-raft-file-store");
+        fs::remove_dir_all(&dir).ok();
+        verify_log_store(&mut FileStore::open(&dir, SyncPolicy::EveryCommit).unwrap());
+        // everything is still there after reopening
+        let store = FileStore::open(&dir, SyncPolicy::EveryCommit).unwrap();
+        assert_eq!(store.hard_state().unwrap().term, 4);
+        assert_eq!(store.term(10).unwrap(), Some(4));
+        assert_eq!(store.snapshot().unwrap().unwrap().last_included_id, 10);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, HashMap};
+use std::cmp::{min, max};
+use std::mem;
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+use futures::Future;
+use futures::sync::oneshot;
+use parking_lot::Mutex;
+use bifrost_hasher::hash_bytes;
+use utils::bincode;
+use rpc::RPCError;
+use super::{RaftService, Options, LogEntry, AppendEntriesResult, Membership, Service, NOOP_SM_ID, SERVICE_VERSION, is_leader};
+use super::clock::ManualClock;
+use super::transport::{Peer, Transport};
+
+// servers waiting for an answer give up after this long, like rpc clients on their timeout
+const CALL_TIMEOUT_MS: u64 = 5000;
+const STEP_MS: i64 = 10;
+
+// what happens to the messages from one server to another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkFaults {
+    // chance of each message to be lost, requests and answers alike
+    pub drop_rate: f64,
+    // steps for a message to arrive, each takes between them so later messages may arrive first
+    pub min_latency: u64,
+    pub max_latency: u64,
+}
+
+impl LinkFaults {
+    // everything arrives at the next step, in order
+    pub fn Default() -> LinkFaults {
+        LinkFaults {
+            drop_rate: 0.0,
+            min_latency: 1,
+            max_latency: 1,
+        }
+    }
+}
+
+// runs once on the receiving server, the sender is answered by then or never
+type Delivery = Box<FnMut(&Arc<RaftService>) + Send>;
+
+struct Message {
+    from: usize,
+    to: usize,
+    deliver: Delivery,
+}
+
+struct WireState {
+    step: u64,
+    seed: u64,
+    // links without faults of their own have the default ones
+    faults: HashMap<(usize, usize), LinkFaults>,
+    default_faults: LinkFaults,
+    // messages only go between servers of the same group
+    groups: Vec<usize>,
+    down: Vec<bool>,
+    // messages sent on each link, what happens to a message depends on the seed and its number only
+    sent: HashMap<(usize, usize), u64>,
+    // by the step they arrive in and the order they were sent
+    queue: BTreeMap<(u64, u64), Message>,
+    seq: u64,
+}
+
+impl WireState {
+    fn connected(&self, from: usize, to: usize) -> bool {
+        !self.down[from] && !self.down[to] && self.groups[from] == self.groups[to]
+    }
+}
+
+struct Wires {
+    state: Mutex<WireState>,
+    addresses: HashMap<String, usize>,
+}
+
+// the same for the same seed, link, message and purpose
+fn chance(seed: u64, from: usize, to: usize, number: u64, purpose: u64) -> f64 {
+    let hash = hash_bytes(&bincode::serialize(&(seed, from as u64, to as u64, number, purpose)));
+    (hash % 1_000_000) as f64 / 1_000_000.0
+}
+
+impl Wires {
+    fn send(&self, from: usize, to: usize, deliver: Delivery) {
+        let mut state = self.state.lock();
+        let number = {
+            let sent = state.sent.entry((from, to)).or_insert(0);
+            *sent += 1;
+            *sent
+        };
+        if !state.connected(from, to) {return;}
+        let faults = state.faults.get(&(from, to)).cloned().unwrap_or(state.default_faults);
+        if chance(state.seed, from, to, number, 0) < faults.drop_rate {return;}
+        let spread = faults.max_latency.saturating_sub(faults.min_latency) + 1;
+        let latency = faults.min_latency + (chance(state.seed, from, to, number, 1) * spread as f64) as u64;
+        let due = state.step + max(min(latency, faults.max_latency), 1);
+        state.seq += 1;
+        let seq = state.seq;
+        state.queue.insert((due, seq), Message {
+            from: from,
+            to: to,
+            deliver: deliver,
+        });
+    }
+}
+
+// the other member as seen by one server of the simulation, both connections are the same link
+struct SimPeer {
+    wires: Arc<Wires>,
+    from: usize,
+    to: usize,
+}
+
+impl SimPeer {
+    // the request and its answer cross the link on their own, either may be lost
+    fn request<R, F, A>(&self, call: F, answered: A)
+        where R: Send + 'static,
+              F: FnOnce(&Arc<RaftService>) -> R + Send + 'static,
+              A: FnOnce(R) + Send + 'static {
+        let wires = self.wires.clone();
+        let (from, to) = (self.from, self.to);
+        let mut request = Some((call, answered));
+        self.wires.send(from, to, Box::new(move |server: &Arc<RaftService>| {
+            if let Some((call, answered)) = request.take() {
+                let mut answer = Some((answered, call(server)));
+                wires.send(to, from, Box::new(move |_: &Arc<RaftService>| {
+                    if let Some((answered, res)) = answer.take() {
+                        answered(res);
+                    }
+                }));
+            }
+        }));
+    }
+    fn call<R, F>(&self, call: F) -> Result<Result<R, ()>, RPCError>
+        where R: Send + 'static, F: FnOnce(&Arc<RaftService>) -> Result<R, ()> + Send + 'static {
+        let (tx, rx) = channel();
+        self.request(call, move |res| {tx.send(res).ok();});
+        // a lost message drops the sender with it
+        rx.recv_timeout(Duration::from_millis(CALL_TIMEOUT_MS)).map_err(|_| RPCError::TimeoutError)
+    }
+}
+
+impl Peer for SimPeer {
+    fn append_entries(
+        &self, term: &u64, leader_id: &u64, prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<Vec<LogEntry>>, leader_commit: &u64
+    ) -> Result<Result<(u64, AppendEntriesResult), ()>, RPCError> {
+        let (term, leader_id, prev_log_id, prev_log_term, entries, leader_commit) =
+            (*term, *leader_id, *prev_log_id, *prev_log_term, entries.clone(), *leader_commit);
+        self.call(move |server| server.append_entries(
+            &term, &leader_id, &prev_log_id, &prev_log_term, &entries, &leader_commit
+        ))
+    }
+    fn append_entries_async(
+        &self, term: &u64, leader_id: &u64, prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<Vec<LogEntry>>, leader_commit: &u64
+    ) -> Box<Future<Item = Result<(u64, AppendEntriesResult), ()>, Error = RPCError>> {
+        let (term, leader_id, prev_log_id, prev_log_term, entries, leader_commit) =
+            (*term, *leader_id, *prev_log_id, *prev_log_term, entries.clone(), *leader_commit);
+        let (tx, rx) = oneshot::channel();
+        self.request(
+            move |server| server.append_entries(
+                &term, &leader_id, &prev_log_id, &prev_log_term, &entries, &leader_commit
+            ),
+            move |res| {tx.send(res).ok();}
+        );
+        Box::new(rx.map_err(|_| RPCError::TimeoutError))
+    }
+    fn request_vote(
+        &self, term: &u64, candidate_id: &u64, last_log_id: &u64, last_log_term: &u64
+    ) -> Result<Result<((u64, u64), bool), ()>, RPCError> {
+        let (term, candidate_id, last_log_id, last_log_term) = (*term, *candidate_id, *last_log_id, *last_log_term);
+        self.call(move |server| server.request_vote(&term, &candidate_id, &last_log_id, &last_log_term))
+    }
+    fn pre_vote(
+        &self, term: &u64, candidate_id: &u64, last_log_id: &u64, last_log_term: &u64
+    ) -> Result<Result<bool, ()>, RPCError> {
+        let (term, candidate_id, last_log_id, last_log_term) = (*term, *candidate_id, *last_log_id, *last_log_term);
+        self.call(move |server| server.pre_vote(&term, &candidate_id, &last_log_id, &last_log_term))
+    }
+    fn timeout_now(&self, term: &u64, leader_id: &u64) -> Result<Result<bool, ()>, RPCError> {
+        let (term, leader_id) = (*term, *leader_id);
+        self.call(move |server| server.timeout_now(&term, &leader_id))
+    }
+    fn install_snapshot(
+        &self, term: &u64, leader_id: &u64, last_included_index: &u64, last_included_term: &u64,
+        offset: &u64, data: &Vec<u8>, checksum: &u32, done: &bool
+    ) -> Result<Result<u64, ()>, RPCError> {
+        let (term, leader_id, last_included_index, last_included_term, offset, data, checksum, done) =
+            (*term, *leader_id, *last_included_index, *last_included_term, *offset, data.clone(), *checksum, *done);
+        self.call(move |server| server.install_snapshot(
+            &term, &leader_id, &last_included_index, &last_included_term, &offset, &data, &checksum, &done
+        ))
+    }
+    // every server of the simulation runs this build
+    fn server_version(&self) -> Result<u32, RPCError> {
+        Ok(SERVICE_VERSION)
+    }
+}
+
+struct SimTransport {
+    wires: Arc<Wires>,
+    from: usize,
+}
+
+impl Transport for SimTransport {
+    fn connect(&self, address: &String, _service_id: u64) -> Option<(Arc<Peer>, Arc<Peer>)> {
+        let to = match self.wires.addresses.get(address) {
+            Some(to) => *to,
+            None => return None
+        };
+        let peer = Arc::new(SimPeer {
+            wires: self.wires.clone(),
+            from: self.from,
+            to: to,
+        });
+        Some((peer.clone() as Arc<Peer>, peer as Arc<Peer>))
+    }
+}
+
+// servers of one cluster on a manual clock, their messages go through links the test breaks at will.
+// every step moves the clock, delivers the messages due and checks the servers that are up.
+// what happens to a message only depends on the seed and how many went on its link before,
+// how the threads of the servers interleave is still up to the system
+pub struct SimNetwork {
+    wires: Arc<Wires>,
+    clock: Arc<ManualClock>,
+    servers: Vec<Arc<RaftService>>,
+    // the leader of each term seen so far
+    leaders: Mutex<BTreeMap<u64, u64>>,
+    // the term of each entry seen committed so far
+    committed: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl SimNetwork {
+    pub fn new(size: usize, seed: u64) -> SimNetwork {
+        SimNetwork::with_options(size, seed, |_| Options::Default())
+    }
+    // addresses of the options are set by the simulation
+    pub fn with_options<F>(size: usize, seed: u64, options: F) -> SimNetwork where F: Fn(usize) -> Options {
+        let addresses: Vec<String> = (0..size).map(|i| format!("sim-{}", i)).collect();
+        let wires = Arc::new(Wires {
+            state: Mutex::new(WireState {
+                step: 0,
+                seed: seed,
+                faults: HashMap::new(),
+                default_faults: LinkFaults::Default(),
+                groups: vec![0; size],
+                down: vec![false; size],
+                sent: HashMap::new(),
+                queue: BTreeMap::new(),
+                seq: 0,
+            }),
+            addresses: addresses.iter().cloned().enumerate().map(|(i, address)| (address, i)).collect(),
+        });
+        let clock = Arc::new(ManualClock::new());
+        let servers: Vec<Arc<RaftService>> = addresses.iter().enumerate().map(|(i, address)| {
+            let mut opts = options(i);
+            opts.address = address.clone();
+            let transport = Arc::new(SimTransport {
+                wires: wires.clone(),
+                from: i,
+            });
+            let server = RaftService::with_transport(opts, clock.clone(), transport);
+            {
+                // all of them are members from the start, none bootstraps or joins
+                let meta = server.meta.write();
+                let mut sm = meta.state_machine.write();
+                for address in &addresses {
+                    sm.configs.new_member(address.clone()).ok();
+                }
+            }
+            assert!(RaftService::start(&server));
+            server
+        }).collect();
+        SimNetwork {
+            wires: wires,
+            clock: clock,
+            servers: servers,
+            leaders: Mutex::new(BTreeMap::new()),
+            committed: Mutex::new(BTreeMap::new()),
+        }
+    }
+    pub fn server(&self, server: usize) -> &Arc<RaftService> {
+        &self.servers[server]
+    }
+    pub fn size(&self) -> usize {
+        self.servers.len()
+    }
+    pub fn clock(&self) -> &Arc<ManualClock> {
+        &self.clock
+    }
+    pub fn steps(&self) -> u64 {
+        self.wires.state.lock().step
+    }
+    pub fn step(&self) {
+        self.clock.advance(STEP_MS);
+        let arrived = {
+            let mut state = self.wires.state.lock();
+            state.step += 1;
+            let next = state.step + 1;
+            let later = state.queue.split_off(&(next, 0));
+            mem::replace(&mut state.queue, later)
+        };
+        for (_, mut message) in arrived {
+            // the link may have broken since the message was sent
+            let connected = self.wires.state.lock().connected(message.from, message.to);
+            if connected {
+                (message.deliver)(&self.servers[message.to]);
+            }
+        }
+        for (i, server) in self.servers.iter().enumerate() {
+            if !self.is_down(i) {
+                RaftService::tick(server);
+            }
+        }
+        // the threads of the servers send what the step started
+        thread::sleep(Duration::from_millis(1));
+    }
+    // steps, each followed by verify
+    pub fn run(&self, steps: u64) {
+        for _ in 0..steps {
+            self.step();
+            self.verify();
+        }
+    }
+    // false when it did not happen within the steps
+    pub fn run_until<F>(&self, max_steps: u64, done: F) -> bool where F: Fn(&SimNetwork) -> bool {
+        for _ in 0..max_steps {
+            if done(self) {return true;}
+            self.step();
+            self.verify();
+        }
+        done(self)
+    }
+    pub fn set_faults(&self, from: usize, to: usize, faults: LinkFaults) {
+        self.wires.state.lock().faults.insert((from, to), faults);
+    }
+    // for links without faults of their own
+    pub fn set_default_faults(&self, faults: LinkFaults) {
+        self.wires.state.lock().default_faults = faults;
+    }
+    pub fn clear_faults(&self) {
+        let mut state = self.wires.state.lock();
+        state.faults.clear();
+        state.default_faults = LinkFaults::Default();
+    }
+    // servers only reach those of their group, servers in no group are alone.
+    // messages on the way between groups are lost
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut state = self.wires.state.lock();
+        let size = state.groups.len();
+        state.groups = (0..size).map(|server| size + server).collect();
+        for (group, servers) in groups.iter().enumerate() {
+            for server in servers.iter() {
+                state.groups[*server] = group;
+            }
+        }
+    }
+    pub fn heal(&self) {
+        let mut state = self.wires.state.lock();
+        let size = state.groups.len();
+        state.groups = vec![0; size];
+    }
+    // the server is no longer checked and loses all messages, like a stopped process
+    pub fn crash(&self, server: usize) {
+        self.wires.state.lock().down[server] = true;
+    }
+    // goes on with the state it had, like a process restarted from its store
+    pub fn recover(&self, server: usize) {
+        self.wires.state.lock().down[server] = false;
+    }
+    pub fn is_down(&self, server: usize) -> bool {
+        self.wires.state.lock().down[server]
+    }
+    // the server up and leading in the highest term
+    pub fn leader(&self) -> Option<usize> {
+        let mut leader = None;
+        for (i, server) in self.servers.iter().enumerate() {
+            if self.is_down(i) {continue;}
+            let meta = server.meta.read();
+            if let Membership::Leader(_) = meta.membership {
+                let higher = leader.map(|(_, term)| meta.term > term).unwrap_or(true);
+                if higher {
+                    leader = Some((i, meta.term));
+                }
+            }
+        }
+        leader.map(|(i, _)| i)
+    }
+    pub fn commit_index(&self, server: usize) -> u64 {
+        self.servers[server].meta.read().commit_index
+    }
+    // an entry of no state machine, appended when the server leads. the index of it
+    pub fn propose(&self, server: usize, data: Vec<u8>) -> Option<u64> {
+        if self.is_down(server) {return None;}
+        let server = &self.servers[server];
+        let meta = server.write_meta();
+        if !is_leader(&meta) {return None;}
+        let mut entry = LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: NOOP_SM_ID,
+            fn_id: 0,
+            data: data,
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0,
+        };
+        let appended = server.append_log(&meta, &mut entry).map(|(id, _)| id);
+        server.pipeline_entries(&meta);
+        appended
+    }
+    // ids and terms of the entries the server has, from the index on
+    pub fn log(&self, server: usize, from: u64) -> Vec<(u64, u64)> {
+        let meta = self.servers[server].meta.read();
+        let logs = meta.logs.read();
+        logs.values().filter(|entry| entry.id >= from).map(|entry| (entry.id, entry.term)).collect()
+    }
+    // election safety, log matching and committed entries staying as they are.
+    // panics on the first violation, with what was seen in earlier calls
+    pub fn verify(&self) {
+        let mut logs = Vec::with_capacity(self.servers.len());
+        for (i, server) in self.servers.iter().enumerate() {
+            let meta = server.meta.read();
+            if let Membership::Leader(_) = meta.membership {
+                let mut leaders = self.leaders.lock();
+                let leader = *leaders.entry(meta.term).or_insert(server.id);
+                assert_eq!(leader, server.id, "server {} leads in term {} of another leader", i, meta.term);
+            }
+            let entries: BTreeMap<u64, (u64, u32)> = meta.logs.read().values()
+                .map(|entry| (entry.id, (entry.term, entry.checksum)))
+                .collect();
+            {
+                let mut committed = self.committed.lock();
+                for (id, &(term, _)) in entries.iter().take_while(|&(id, _)| *id <= meta.commit_index) {
+                    let committed_term = *committed.entry(*id).or_insert(term);
+                    assert_eq!(committed_term, term, "server {} committed entry {} of another term", i, id);
+                }
+            }
+            logs.push(entries);
+        }
+        for a in 0..logs.len() {
+            for b in (a + 1)..logs.len() {
+                // entries up to the last one with the same term in both are the same
+                let matched = logs[a].iter().rev()
+                    .filter(|&(id, &(term, _))| logs[b].get(id).map(|&(other, _)| other == term).unwrap_or(false))
+                    .map(|(id, _)| *id)
+                    .next();
+                if let Some(matched) = matched {
+                    for (id, entry) in logs[a].iter().take_while(|&(id, _)| *id <= matched) {
+                        if let Some(other) = logs[b].get(id) {
+                            assert_eq!(entry, other, "servers {} and {} differ at {}, before {}", a, b, id, matched);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SimNetwork {
+    // servers waiting for answers give up right away
+    fn drop(&mut self) {
+        let queue = {
+            let mut state = self.wires.state.lock();
+            for down in state.down.iter_mut() {
+                *down = true;
+            }
+            mem::replace(&mut state.queue, BTreeMap::new())
+        };
+        drop(queue);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chances_of_messages() {
+        assert_eq!(chance(1, 0, 1, 5, 0), chance(1, 0, 1, 5, 0));
+        assert!(chance(1, 0, 1, 5, 0) < 1.0);
+        let dropped = (0..10_000).filter(|number| chance(7, 1, 2, *number, 0) < 0.2).count();
+        assert!(dropped > 1_500 && dropped < 2_500, "{} of 10000 dropped", dropped);
+    }
+}
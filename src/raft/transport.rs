@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use futures::Future;
+use rpc::{self, RPCError};
+use super::{LogEntry, AppendEntriesResult, SyncServiceClient, AsyncServiceClient};
+use super::state_machine::configs::CONTROL_CLIENT_POOL;
+
+// the rpcs members call on each other, with the answers of the generated service clients
+pub trait Peer: Send + Sync {
+    fn append_entries(
+        &self, term: &u64, leader_id: &u64, prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<Vec<LogEntry>>, leader_commit: &u64
+    ) -> Result<Result<(u64, AppendEntriesResult), ()>, RPCError>;
+    // the leader pipelines entries without waiting on the follower
+    fn append_entries_async(
+        &self, term: &u64, leader_id: &u64, prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<Vec<LogEntry>>, leader_commit: &u64
+    ) -> Box<Future<Item = Result<(u64, AppendEntriesResult), ()>, Error = RPCError>>;
+    fn request_vote(
+        &self, term: &u64, candidate_id: &u64, last_log_id: &u64, last_log_term: &u64
+    ) -> Result<Result<((u64, u64), bool), ()>, RPCError>;
+    fn pre_vote(
+        &self, term: &u64, candidate_id: &u64, last_log_id: &u64, last_log_term: &u64
+    ) -> Result<Result<bool, ()>, RPCError>;
+    fn timeout_now(&self, term: &u64, leader_id: &u64) -> Result<Result<bool, ()>, RPCError>;
+    fn install_snapshot(
+        &self, term: &u64, leader_id: &u64, last_included_index: &u64, last_included_term: &u64,
+        offset: &u64, data: &Vec<u8>, checksum: &u32, done: &bool
+    ) -> Result<Result<u64, ()>, RPCError>;
+    // SERVICE_VERSION of the member, asked once for each connection
+    fn server_version(&self) -> Result<u32, RPCError>;
+}
+
+// how a server reaches the other members, connected to when they are added
+pub trait Transport: Send + Sync {
+    // the connection of entries and snapshots, then the one of heartbeats and elections.
+    // None when the member cannot be reached
+    fn connect(&self, address: &String, service_id: u64) -> Option<(Arc<Peer>, Arc<Peer>)>;
+}
+
+// members over the rpc client pools. servers of a cluster with an auth token reach the members
+// through pools of their own, connections of which prove the token
+pub struct RpcTransport {
+    pools: Option<(rpc::ClientPool, rpc::ClientPool)>,
+}
+
+impl RpcTransport {
+    pub fn new(auth_token: &Option<String>) -> RpcTransport {
+        RpcTransport {
+            pools: auth_token.as_ref().map(|token| {
+                (rpc::ClientPool::with_auth_token(token), rpc::ClientPool::with_auth_token(token))
+            })
+        }
+    }
+}
+
+pub struct RpcPeer {
+    sync_client: Arc<SyncServiceClient>,
+    async_client: Arc<AsyncServiceClient>,
+}
+
+impl RpcPeer {
+    pub fn new(service_id: u64, client: &Arc<rpc::RPCClient>) -> Arc<RpcPeer> {
+        Arc::new(RpcPeer {
+            sync_client: SyncServiceClient::new(service_id, client),
+            async_client: AsyncServiceClient::new(service_id, client),
+        })
+    }
+}
+
+impl Transport for RpcTransport {
+    fn connect(&self, address: &String, service_id: u64) -> Option<(Arc<Peer>, Arc<Peer>)> {
+        let (clients, control_clients) = match self.pools {
+            Some((ref clients, ref control_clients)) => (clients, control_clients),
+            None => (&*rpc::DEFAULT_CLIENT_POOL, &*CONTROL_CLIENT_POOL)
+        };
+        let client = match clients.get(address) {
+            Ok(client) => client,
+            Err(_) => return None
+        };
+        // sharing the connection of entries is still better than no member
+        let control_client = control_clients.get(address).unwrap_or(client.clone());
+        Some((RpcPeer::new(service_id, &client), RpcPeer::new(service_id, &control_client)))
+    }
+}
+
+impl Peer for RpcPeer {
+    fn append_entries(
+        &self, term: &u64, leader_id: &u64, prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<Vec<LogEntry>>, leader_commit: &u64
+    ) -> Result<Result<(u64, AppendEntriesResult), ()>, RPCError> {
+        self.sync_client.append_entries(term, leader_id, prev_log_id, prev_log_term, entries, leader_commit)
+    }
+    fn append_entries_async(
+        &self, term: &u64, leader_id: &u64, prev_log_id: &u64, prev_log_term: &u64,
+        entries: &Option<Vec<LogEntry>>, leader_commit: &u64
+    ) -> Box<Future<Item = Result<(u64, AppendEntriesResult), ()>, Error = RPCError>> {
+        self.async_client.append_entries(term, leader_id, prev_log_id, prev_log_term, entries, leader_commit)
+    }
+    fn request_vote(
+        &self, term: &u64, candidate_id: &u64, last_log_id: &u64, last_log_term: &u64
+    ) -> Result<Result<((u64, u64), bool), ()>, RPCError> {
+        self.sync_client.request_vote(term, candidate_id, last_log_id, last_log_term)
+    }
+    fn pre_vote(
+        &self, term: &u64, candidate_id: &u64, last_log_id: &u64, last_log_term: &u64
+    ) -> Result<Result<bool, ()>, RPCError> {
+        self.sync_client.pre_vote(term, candidate_id, last_log_id, last_log_term)
+    }
+    fn timeout_now(&self, term: &u64, leader_id: &u64) -> Result<Result<bool, ()>, RPCError> {
+        self.sync_client.timeout_now(term, leader_id)
+    }
+    fn install_snapshot(
+        &self, term: &u64, leader_id: &u64, last_included_index: &u64, last_included_term: &u64,
+        offset: &u64, data: &Vec<u8>, checksum: &u32, done: &bool
+    ) -> Result<Result<u64, ()>, RPCError> {
+        self.sync_client.install_snapshot(
+            term, leader_id, last_included_index, last_included_term, offset, data, checksum, done
+        )
+    }
+    fn server_version(&self) -> Result<u32, RPCError> {
+        self.sync_client.server_version()
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::BTreeMap;
+use std::collections::Bound::{Included, Excluded, Unbounded};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use utils::bincode;
+use super::LogEntry;
+
+// segments are rolled once they grow over this size
+pub static SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+// [data length][crc32 of data]
+const RECORD_HEADER: usize = 8 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // appended entries reach the disk before the server answers for them
+    EveryCommit,
+    // synced at most once in this many milliseconds, a crash can lose the entries appended in between
+    Batched(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    // id of the first entry in the segment, also the name of the segment file
+    pub segment: u64,
+    pub offset: u64,
+}
+
+struct Segment {
+    file: File,
+    first_id: u64,
+    size: u64,
+}
+
+// append only log of raft entries, split in segment files named by their first entry id.
+// records are [data length][crc32][bincode of the entry]
+pub struct LogFile {
+    dir: PathBuf,
+    policy: SyncPolicy,
+    // first entry id of each segment to its size
+    segments: BTreeMap<u64, u64>,
+    active: Option<Segment>,
+    index: BTreeMap<u64, Position>,
+    unsynced: bool,
+    last_sync: Instant,
+}
+
+fn corrupted(path: &Path, offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Raft log segment {} is corrupted at {}", path.display(), offset)
+    )
+}
+
+fn segment_path(dir: &Path, first_id: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", first_id))
+}
+
+fn segment_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "log").unwrap_or(false) {
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+// data of the complete record at the position and whether its crc matches
+fn record_at(data: &[u8], pos: usize) -> Option<(&[u8], bool)> {
+    if data.len() - pos < RECORD_HEADER {
+        return None;
+    }
+    let len = LittleEndian::read_u64(&data[pos..]) as usize;
+    let checksum = LittleEndian::read_u32(&data[pos + 8..]);
+    let start = pos + RECORD_HEADER;
+    if data.len() - start < len {
+        return None;
+    }
+    let record = &data[start..start + len];
+    Some((record, crc32::checksum_ieee(record) == checksum))
+}
+
+// entries of the segment with their offsets, the length of its valid records,
+// and whether anything follows them. records are only torn at the end, a damaged one
+// followed by a valid record or an entry failing its own checksum is corruption
+fn read_segment(path: &Path) -> io::Result<(Vec<(u64, LogEntry)>, u64, bool)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let record = match record_at(&data, pos) {
+            Some((record, true)) => record,
+            Some((record, false)) => {
+                let next = pos + RECORD_HEADER + record.len();
+                if next < data.len() && record_at(&data, next).map(|(_, valid)| valid).unwrap_or(false) {
+                    return Err(corrupted(path, pos as u64));
+                }
+                break;
+            },
+            None => break
+        };
+        let decoded: Option<LogEntry> = bincode::try_deserialize(record);
+        match decoded {
+            Some(ref entry) if !entry.is_intact() => return Err(corrupted(path, pos as u64)),
+            Some(entry) => entries.push((pos as u64, entry)),
+            None => break
+        }
+        pos += RECORD_HEADER + record.len();
+    }
+    Ok((entries, pos as u64, pos < data.len()))
+}
+
+fn encode_record(entry: &LogEntry, buf: &mut Vec<u8>) {
+    let data = bincode::serialize(entry);
+    let mut header = [0u8; RECORD_HEADER];
+    LittleEndian::write_u64(&mut header, data.len() as u64);
+    LittleEndian::write_u32(&mut header[8..], crc32::checksum_ieee(&data));
+    buf.extend_from_slice(&header);
+    buf.extend(data);
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+impl LogFile {
+    // entries in the log. a record torn by a crash in the middle of a write can only be
+    // the last one, it is cut off. damages anywhere else fail the recovery
+    pub fn open<P: AsRef<Path>>(dir: P, policy: SyncPolicy) -> io::Result<(LogFile, Vec<LogEntry>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let ids = segment_ids(&dir)?;
+        let mut segments = BTreeMap::new();
+        let mut index = BTreeMap::new();
+        let mut entries = Vec::new();
+        let mut last_id = 0;
+        for (i, first_id) in ids.iter().enumerate() {
+            let path = segment_path(&dir, *first_id);
+            let (records, valid_len, torn) = read_segment(&path)?;
+            if torn {
+                if i + 1 < ids.len() {
+                    return Err(corrupted(&path, valid_len));
+                }
+                warn!("Discarding torn record at {} of raft log segment {}", valid_len, path.display());
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+            }
+            for (offset, entry) in records {
+                if entry.id <= last_id {
+                    return Err(corrupted(&path, offset));
+                }
+                last_id = entry.id;
+                index.insert(entry.id, Position { segment: *first_id, offset: offset });
+                entries.push(entry);
+            }
+            segments.insert(*first_id, valid_len);
+        }
+        let active = match segments.iter().next_back() {
+            Some((first_id, size)) => Some(Segment {
+                file: OpenOptions::new().append(true).open(segment_path(&dir, *first_id))?,
+                first_id: *first_id,
+                size: *size,
+            }),
+            None => None
+        };
+        Ok((LogFile {
+            dir: dir,
+            policy: policy,
+            segments: segments,
+            active: active,
+            index: index,
+            unsynced: false,
+            last_sync: Instant::now(),
+        }, entries))
+    }
+    pub fn last_id(&self) -> Option<u64> {
+        self.index.keys().cloned().next_back()
+    }
+    // where the entry is kept on disk
+    pub fn position(&self, id: u64) -> Option<Position> {
+        self.index.get(&id).cloned()
+    }
+    // entries in the log with ids in the range, read back from the segments
+    pub fn read(&self, range: Range<u64>) -> io::Result<Vec<LogEntry>> {
+        let mut entries = Vec::new();
+        if range.start >= range.end {
+            return Ok(entries);
+        }
+        let mut opened: Option<(u64, File)> = None;
+        for (id, position) in self.index.range((Included(range.start), Excluded(range.end))) {
+            let path = segment_path(&self.dir, position.segment);
+            if opened.as_ref().map(|&(segment, _)| segment != position.segment).unwrap_or(true) {
+                opened = Some((position.segment, File::open(&path)?));
+            }
+            let file = &mut opened.as_mut().unwrap().1;
+            file.seek(SeekFrom::Start(position.offset))?;
+            let mut header = [0u8; RECORD_HEADER];
+            file.read_exact(&mut header)?;
+            let mut record = vec![0u8; LittleEndian::read_u64(&header) as usize];
+            file.read_exact(&mut record)?;
+            if LittleEndian::read_u32(&header[8..]) != crc32::checksum_ieee(&record) {
+                return Err(corrupted(&path, position.offset));
+            }
+            let decoded: Option<LogEntry> = bincode::try_deserialize(&record);
+            match decoded {
+                Some(entry) if entry.id == *id && entry.is_intact() => entries.push(entry),
+                _ => return Err(corrupted(&path, position.offset))
+            }
+        }
+        Ok(entries)
+    }
+    fn roll(&mut self, first_id: u64) -> io::Result<()> {
+        if self.active.is_some() {
+            self.sync()?;
+        }
+        let path = segment_path(&self.dir, first_id);
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        sync_dir(&self.dir)?;
+        self.segments.insert(first_id, 0);
+        self.active = Some(Segment {
+            file: file,
+            first_id: first_id,
+            size: 0,
+        });
+        Ok(())
+    }
+    // entries must come in the order of their ids, after the entries already in the log
+    pub fn append<'a, I>(&mut self, entries: I) -> io::Result<()> where I: IntoIterator<Item = &'a LogEntry> {
+        let mut last_id = self.last_id().unwrap_or(0);
+        let mut buf = Vec::new();
+        let mut positions = Vec::new();
+        let mut first_id = None;
+        for entry in entries {
+            if entry.id <= last_id {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Raft log entry {} appended after {}", entry.id, last_id)
+                ));
+            }
+            last_id = entry.id;
+            if first_id.is_none() {
+                first_id = Some(entry.id);
+            }
+            positions.push((entry.id, buf.len() as u64));
+            encode_record(entry, &mut buf);
+        }
+        let first_id = match first_id {
+            Some(id) => id,
+            None => return Ok(())
+        };
+        let need_roll = match self.active {
+            Some(ref segment) => segment.size >= SEGMENT_BYTES,
+            None => true
+        };
+        if need_roll {
+            self.roll(first_id)?;
+        }
+        {
+            let segment = self.active.as_mut().unwrap();
+            if let Err(e) = segment.file.write_all(&buf) {
+                // a partial record in the middle would hide the entries appended after it
+                segment.file.set_len(segment.size)?;
+                return Err(e);
+            }
+            for (id, offset) in positions {
+                self.index.insert(id, Position { segment: segment.first_id, offset: segment.size + offset });
+            }
+            segment.size += buf.len() as u64;
+            self.segments.insert(segment.first_id, segment.size);
+        }
+        self.unsynced = true;
+        match self.policy {
+            SyncPolicy::EveryCommit => self.sync(),
+            SyncPolicy::Batched(_) => self.sync_due()
+        }
+    }
+    // removes the entry with the id and all entries after it, synced before returning
+    pub fn truncate(&mut self, from_id: u64) -> io::Result<()> {
+        let position = match self.index.range((Included(from_id), Unbounded)).next() {
+            Some((_, position)) => *position,
+            None => return Ok(())
+        };
+        let removed: Vec<u64> = self.segments.range((Included(position.segment + 1), Unbounded))
+            .map(|(first_id, _)| *first_id)
+            .collect();
+        for first_id in removed {
+            self.segments.remove(&first_id);
+            fs::remove_file(segment_path(&self.dir, first_id))?;
+        }
+        let path = segment_path(&self.dir, position.segment);
+        if position.offset == 0 {
+            self.segments.remove(&position.segment);
+            fs::remove_file(&path)?;
+            self.active = None;
+        } else {
+            let file = OpenOptions::new().append(true).open(&path)?;
+            file.set_len(position.offset)?;
+            file.sync_all()?;
+            self.segments.insert(position.segment, position.offset);
+            self.active = Some(Segment {
+                file: file,
+                first_id: position.segment,
+                size: position.offset,
+            });
+        }
+        sync_dir(&self.dir)?;
+        self.index.split_off(&from_id);
+        // the segment before the removed one takes the appends
+        if self.active.is_none() {
+            if let Some((first_id, size)) = self.segments.iter().next_back().map(|(id, size)| (*id, *size)) {
+                self.active = Some(Segment {
+                    file: OpenOptions::new().append(true).open(segment_path(&self.dir, first_id))?,
+                    first_id: first_id,
+                    size: size,
+                });
+            }
+        }
+        Ok(())
+    }
+    // removes the segments holding only entries up to the id, they are in a snapshot now.
+    // the last segment is always kept for appends
+    pub fn compact(&mut self, up_to_id: u64) -> io::Result<()> {
+        let first_ids: Vec<u64> = self.segments.keys().cloned().collect();
+        for pair in first_ids.windows(2) {
+            let (first_id, next_first_id) = (pair[0], pair[1]);
+            if next_first_id > up_to_id + 1 {
+                break;
+            }
+            fs::remove_file(segment_path(&self.dir, first_id))?;
+            self.segments.remove(&first_id);
+            let remaining = self.index.split_off(&next_first_id);
+            self.index = remaining;
+        }
+        Ok(())
+    }
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            if let Some(ref segment) = self.active {
+                segment.file.sync_data()?;
+            }
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+    // sync when the batch interval has passed, called periodically for batched sync policy
+    pub fn sync_due(&mut self) -> io::Result<()> {
+        let interval = match self.policy {
+            SyncPolicy::EveryCommit => 0,
+            SyncPolicy::Batched(ms) => ms
+        };
+        if self.unsynced && self.last_sync.elapsed() >= Duration::from_millis(interval) {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("Cannot sync raft log in {}, {}", self.dir.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn entry(id: u64) -> LogEntry {
+        let mut entry = LogEntry {
+            id: id,
+            term: 1,
+            sm_id: 0,
+            fn_id: 0,
+            data: vec![id as u8; 16],
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0
+        };
+        entry.checksum = entry.digest();
+        entry
+    }
+
+    fn clean_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn ids(entries: &Vec<LogEntry>) -> Vec<u64> {
+        entries.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn append_and_recover() {
+        let dir = clean_dir("bifrost-raft-wal-recover");
+        {
+            let (mut log, entries) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+            assert!(entries.is_empty());
+            log.append(&vec![entry(1), entry(2)]).unwrap();
+            log.append(&vec![entry(3)]).unwrap();
+            assert!(log.append(&vec![entry(3)]).is_err());
+            assert_eq!(log.position(1), Some(Position { segment: 1, offset: 0 }));
+        }
+        let (log, entries) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+        assert_eq!(ids(&entries), vec![1, 2, 3]);
+        assert_eq!(log.last_id(), Some(3));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn torn_final_record() {
+        let dir = clean_dir("bifrost-raft-wal-torn");
+        {
+            let (mut log, _) = LogFile::open(&dir, SyncPolicy::Batched(10)).unwrap();
+            log.append(&vec![entry(1), entry(2), entry(3)]).unwrap();
+        }
+        // the write of the last record did not finish
+        let path = segment_path(&dir, 1);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+        {
+            let (mut log, entries) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+            assert_eq!(ids(&entries), vec![1, 2]);
+            log.append(&vec![entry(3), entry(4)]).unwrap();
+        }
+        // garbage after the last record
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1u8, 2, 3]).unwrap();
+        let (_, entries) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+        assert_eq!(ids(&entries), vec![1, 2, 3, 4]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn corrupted_entries_refused() {
+        let dir = clean_dir("bifrost-raft-wal-corrupted");
+        {
+            let (mut log, _) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+            log.append(&vec![entry(1), entry(2), entry(3)]).unwrap();
+        }
+        // a bit flipped in the data of the first record, the ones after are intact
+        let path = segment_path(&dir, 1);
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let original = data.clone();
+        data[RECORD_HEADER + 20] ^= 1;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_eq!(LogFile::open(&dir, SyncPolicy::EveryCommit).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // the record is fine, the entry was moved to another index
+        let mut moved = entry(4);
+        moved.id = 5;
+        let mut data = original;
+        encode_record(&moved, &mut data);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_eq!(LogFile::open(&dir, SyncPolicy::EveryCommit).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn truncate_on_conflict() {
+        let dir = clean_dir("bifrost-raft-wal-truncate");
+        {
+            let (mut log, _) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+            log.append(&vec![entry(1), entry(2), entry(3), entry(4)]).unwrap();
+            log.truncate(3).unwrap();
+            assert_eq!(log.last_id(), Some(2));
+            assert_eq!(log.position(3), None);
+            log.append(&vec![entry(3)]).unwrap();
+            log.truncate(1).unwrap();
+            assert_eq!(log.last_id(), None);
+            log.append(&vec![entry(1), entry(2)]).unwrap();
+        }
+        let (log, entries) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+        assert_eq!(ids(&entries), vec![1, 2]);
+        assert_eq!(ids(&log.read(0..10).unwrap()), vec![1, 2]);
+        assert_eq!(ids(&log.read(2..3).unwrap()), vec![2]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn compact_segments() {
+        let dir = clean_dir("bifrost-raft-wal-compact");
+        {
+            let (mut log, _) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+            log.append(&vec![entry(1), entry(2)]).unwrap();
+            // segments are only rolled when full
+            log.roll(3).unwrap();
+            log.append(&vec![entry(3), entry(4)]).unwrap();
+            log.roll(5).unwrap();
+            log.append(&vec![entry(5)]).unwrap();
+            log.compact(3).unwrap();
+            assert_eq!(log.position(2), None);
+            assert_eq!(log.position(3), Some(Position { segment: 3, offset: 0 }));
+            log.compact(10).unwrap();
+            assert_eq!(log.position(4), None);
+            assert_eq!(segment_ids(&dir).unwrap(), vec![5]);
+        }
+        let (_, entries) = LogFile::open(&dir, SyncPolicy::EveryCommit).unwrap();
+        assert_eq!(ids(&entries), vec![5]);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
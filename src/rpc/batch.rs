@@ -0,0 +1,58 @@
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
+
+pub static BATCH_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_BATCH) as u64;
+
+// [count][len, data] * count, all numbers are u64 in little endian
+pub fn pack(frames: Vec<Vec<u8>>) -> Vec<u8> {
+    let total_len = frames.iter().fold(8, |len, frame| len + 8 + frame.len());
+    let mut buf = Vec::with_capacity(total_len);
+    let mut num_bytes = [0u8; 8];
+    LittleEndian::write_u64(&mut num_bytes, frames.len() as u64);
+    buf.extend_from_slice(&num_bytes);
+    for frame in frames {
+        LittleEndian::write_u64(&mut num_bytes, frame.len() as u64);
+        buf.extend_from_slice(&num_bytes);
+        buf.extend(frame);
+    }
+    buf
+}
+
+// (start, end) of each frame, None for truncated or inconsistent batches
+fn frame_ranges(data: &[u8]) -> Option<Vec<(usize, usize)>> {
+    if data.len() < 8 {
+        return None;
+    }
+    let count = LittleEndian::read_u64(data) as usize;
+    let mut pos = 8;
+    let mut ranges = Vec::new();
+    for _ in 0..count {
+        if data.len() < pos + 8 {
+            return None;
+        }
+        let len = LittleEndian::read_u64(&data[pos..]) as usize;
+        pos += 8;
+        if data.len() - pos < len {
+            return None;
+        }
+        ranges.push((pos, pos + len));
+        pos += len;
+    }
+    if pos != data.len() {
+        return None;
+    }
+    Some(ranges)
+}
+
+pub fn unpack(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    frame_ranges(data).map(|ranges| {
+        ranges.into_iter().map(|(start, end)| data[start..end].to_vec()).collect()
+    })
+}
+
+// frames share the buffer of the batch
+pub fn unpack_bytes(data: &Bytes) -> Option<Vec<Bytes>> {
+    frame_ranges(data).map(|ranges| {
+        ranges.into_iter().map(|(start, end)| data.slice(start, end)).collect()
+    })
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use parking_lot::Mutex;
+use futures::sync::oneshot;
+use utils::time::get_time;
+
+// carries the cancel id of the request to skip
+pub static CANCEL_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_CANCEL) as u64;
+
+// cancellations arrived after the request finished are forgotten after this
+static CANCELLATION_TTL_MS: i64 = 60 * 1000;
+static PURGE_THRESHOLD: usize = 1024;
+
+pub struct CancelHandle {
+    pub cancel_id: u64,
+    signal: oneshot::Sender<()>,
+}
+
+impl CancelHandle {
+    pub fn new(cancel_id: u64) -> (CancelHandle, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        (CancelHandle {
+            cancel_id: cancel_id,
+            signal: tx
+        }, rx)
+    }
+    // fails the future with RPCError::Cancelled, dropping the handle will not cancel
+    pub fn cancel(self) {
+        self.signal.send(()).ok();
+    }
+}
+
+// cancel ids received by the server, with the time they arrived
+pub struct Cancellations {
+    ids: Mutex<HashMap<u64, i64>>,
+}
+
+impl Cancellations {
+    pub fn new() -> Cancellations {
+        Cancellations {
+            ids: Mutex::new(HashMap::new())
+        }
+    }
+    pub fn insert(&self, cancel_id: u64) {
+        let now = get_time();
+        let mut ids = self.ids.lock();
+        if ids.len() >= PURGE_THRESHOLD {
+            ids.retain(|_, arrived| now - *arrived < CANCELLATION_TTL_MS);
+        }
+        ids.insert(cancel_id, now);
+    }
+    // true if the request was cancelled before dispatch
+    pub fn take(&self, cancel_id: u64) -> bool {
+        self.ids.lock().remove(&cancel_id).is_some()
+    }
+}
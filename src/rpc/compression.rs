@@ -0,0 +1,86 @@
+use snap;
+use bytes::Bytes;
+use byteorder::{ByteOrder, LittleEndian};
+use utils::u8vec::prepend_u64;
+
+pub static COMPRESSED_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_COMPRESSED) as u64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Snappy,
+}
+
+// the setting only decides how a side sends, frames tell whether they are compressed.
+// requests are wrapped under COMPRESSED_SERVICE_ID, responses have a bit in the status byte
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    // payloads no larger than the threshold will be sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            algorithm: Algorithm::Snappy,
+            threshold: 64 * 1024,
+        }
+    }
+}
+
+// true when the payload has been compressed
+pub fn compress_body(compression: &Option<Compression>, data: Vec<u8>) -> (bool, Vec<u8>) {
+    let compression = match compression {
+        &Some(ref c) => c,
+        &None => return (false, data)
+    };
+    if data.len() > compression.threshold {
+        match compression.algorithm {
+            Algorithm::Snappy => {
+                match snap::Encoder::new().compress_vec(&data) {
+                    Ok(compressed) => return (true, compressed),
+                    Err(e) => warn!("Cannot compress payload, sending it plain, {:?}", e)
+                }
+            }
+        }
+    }
+    (false, data)
+}
+
+pub fn decompress_body(compressed: bool, data: Vec<u8>) -> Option<Vec<u8>> {
+    if compressed {
+        snap::Decoder::new().decompress_vec(&data).ok()
+    } else {
+        Some(data)
+    }
+}
+
+// compressed request frames are wrapped under the reserved service id, frames of clients
+// without compression are left as they are
+pub fn compress(compression: &Option<Compression>, frame: Vec<u8>) -> Vec<u8> {
+    match compress_body(compression, frame) {
+        (true, compressed) => prepend_u64(COMPRESSED_SERVICE_ID, compressed),
+        (false, frame) => frame
+    }
+}
+
+fn is_compressed(frame: &[u8]) -> bool {
+    frame.len() >= 8 && LittleEndian::read_u64(frame) == COMPRESSED_SERVICE_ID
+}
+
+pub fn decompress(frame: Vec<u8>) -> Option<Vec<u8>> {
+    if is_compressed(&frame) {
+        snap::Decoder::new().decompress_vec(&frame[8..]).ok()
+    } else {
+        Some(frame)
+    }
+}
+
+// plain frames are passed on without copying
+pub fn decompress_bytes(frame: Bytes) -> Option<Bytes> {
+    if is_compressed(&frame) {
+        snap::Decoder::new().decompress_vec(&frame[8..]).ok().map(Bytes::from)
+    } else {
+        Some(frame)
+    }
+}
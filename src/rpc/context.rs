@@ -0,0 +1,127 @@
+use std::cmp::{min, max};
+use std::time::Duration;
+use utils::bincode;
+use utils::time::{get_time, duration_to_ms};
+use utils::u8vec::*;
+use bytes::Bytes;
+
+// wraps frames carrying a request context: [context length][context][inner service id][data]
+pub static CONTEXT_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_CONTEXT) as u64;
+
+// clocks of the client and server may not agree, deadlines passed by less than this are not expired yet
+pub static CLOCK_SKEW_TOLERANCE_MS: i64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RequestContext {
+    pub trace_id: Option<u64>,
+    // milliseconds since epoch, see utils::time::get_time
+    deadline: Option<i64>,
+    // set for cancellable requests, the server skips the request when cancelled before dispatch
+    pub cancel_id: Option<u64>,
+}
+
+def_bindings! {
+    bind val CURRENT_CONTEXT: RequestContext = RequestContext::default();
+}
+
+impl RequestContext {
+    pub fn with_trace_id(trace_id: u64) -> RequestContext {
+        RequestContext {
+            trace_id: Some(trace_id),
+            .. RequestContext::default()
+        }
+    }
+    pub fn deadline(&self) -> Option<i64> {
+        self.deadline
+    }
+    // an earlier deadline of the context is kept
+    pub fn set_deadline(&mut self, deadline: i64) {
+        self.deadline = Some(match self.deadline {
+            Some(current) => min(current, deadline),
+            None => deadline
+        });
+    }
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.set_deadline(get_time() + duration_to_ms(timeout) as i64);
+    }
+    // zero when the deadline has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            Duration::from_millis(max(deadline - get_time(), 0) as u64)
+        })
+    }
+    pub fn is_expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => get_time() - deadline > CLOCK_SKEW_TOLERANCE_MS,
+            None => false
+        }
+    }
+    // empty contexts are not sent, so older servers can still serve the request
+    pub fn is_empty(&self) -> bool {
+        *self == RequestContext::default()
+    }
+}
+
+// context of the request served or sent by this thread
+pub fn current() -> RequestContext {
+    CURRENT_CONTEXT.get()
+}
+
+pub fn current_trace_id() -> Option<u64> {
+    current().trace_id
+}
+
+// requests sent from f carry the context, the previous context is restored afterwards
+pub fn with_context<R, F>(ctx: RequestContext, f: F) -> R where F: FnOnce() -> R {
+    let prev = CURRENT_CONTEXT.get();
+    CURRENT_CONTEXT.set(ctx);
+    let r = f();
+    if prev.is_empty() {
+        CURRENT_CONTEXT.del();
+    } else {
+        CURRENT_CONTEXT.set(prev);
+    }
+    r
+}
+
+pub fn with_trace_id<R, F>(trace_id: u64, f: F) -> R where F: FnOnce() -> R {
+    let mut ctx = current();
+    ctx.trace_id = Some(trace_id);
+    with_context(ctx, f)
+}
+
+// requests sent from f inherit the deadline, unless the current one is earlier
+pub fn with_deadline<R, F>(deadline: i64, f: F) -> R where F: FnOnce() -> R {
+    let mut ctx = current();
+    ctx.set_deadline(deadline);
+    with_context(ctx, f)
+}
+
+pub fn with_timeout<R, F>(timeout: Duration, f: F) -> R where F: FnOnce() -> R {
+    with_deadline(get_time() + duration_to_ms(timeout) as i64, f)
+}
+
+pub fn wrap(ctx: &RequestContext, svr_id: u64, data: Vec<u8>) -> Vec<u8> {
+    let ctx_data = bincode::serialize(ctx);
+    let mut frame = prepend_u64(ctx_data.len() as u64, ctx_data);
+    frame.extend(prepend_u64(svr_id, data));
+    frame
+}
+
+pub fn unwrap(data: Bytes) -> Option<(RequestContext, u64, Bytes)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let (ctx_len, mut data) = split_u64_head(data);
+    let ctx_len = ctx_len as usize;
+    if data.len() < 8 || data.len() - 8 < ctx_len {
+        return None;
+    }
+    let ctx_data = data.split_to(ctx_len);
+    let ctx = match bincode::try_deserialize(&ctx_data) {
+        Some(ctx) => ctx,
+        None => return None
+    };
+    let (svr_id, data) = split_u64_head(data);
+    Some((ctx, svr_id, data))
+}
@@ -0,0 +1,13 @@
+pub static HEALTH_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_HEALTH) as u64;
+
+pub static PING_FN_ID: u64 = 1;
+pub static ECHO_FN_ID: u64 = 2;
+// bincode encoded metrics::MetricsSnapshot
+pub static METRICS_FN_ID: u64 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingInfo {
+    pub server_id: u64,
+    pub uptime_ms: u64,
+    pub services: Vec<u64>,
+}
@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use parking_lot::RwLock;
+use super::{RPCError, RPCRequestError};
+
+// upper bounds of latency buckets in microseconds, slower calls fall in the last bucket
+pub static LATENCY_BUCKETS_US: [u64; 16] = [
+    100, 250, 500, 1000, 2500, 5000, 10000, 25000,
+    50000, 100000, 250000, 500000, 1000000, 2500000, 5000000, 10000000
+];
+
+// one counter for each kind of error, request errors and client side errors share the names
+static ERROR_KINDS: [&'static str; 21] = [
+    "FunctionIdNotFound", "ServiceIdNotFound", "Rejected", "ServiceError",
+    "MalformedRequest", "MalformedResponse", "BadRequestData", "ResponseTooLarge",
+    "DeadlineExceeded", "Cancelled", "Unauthorized", "VersionMismatch", "StreamClosed", "Other",
+    "IOError", "TimeoutError", "DecodeError", "PayloadTooLarge", "ChecksumMismatch", "StreamOutOfOrder",
+    "Unknown"
+];
+
+pub fn request_error_kind(e: &RPCRequestError) -> &'static str {
+    match e {
+        &RPCRequestError::FunctionIdNotFound => "FunctionIdNotFound",
+        &RPCRequestError::ServiceIdNotFound => "ServiceIdNotFound",
+        &RPCRequestError::Rejected => "Rejected",
+        &RPCRequestError::ServiceError(_) => "ServiceError",
+        &RPCRequestError::MalformedRequest => "MalformedRequest",
+        &RPCRequestError::MalformedResponse => "MalformedResponse",
+        &RPCRequestError::BadRequestData => "BadRequestData",
+        &RPCRequestError::ResponseTooLarge => "ResponseTooLarge",
+        &RPCRequestError::DeadlineExceeded => "DeadlineExceeded",
+        &RPCRequestError::Cancelled => "Cancelled",
+        &RPCRequestError::Unauthorized => "Unauthorized",
+        &RPCRequestError::VersionMismatch { .. } => "VersionMismatch",
+        &RPCRequestError::StreamClosed => "StreamClosed",
+        &RPCRequestError::Other => "Other",
+    }
+}
+
+pub fn error_kind(e: &RPCError) -> &'static str {
+    match e {
+        &RPCError::RequestError(ref e) => request_error_kind(e),
+        &RPCError::IOError(_) => "IOError",
+        &RPCError::TimeoutError => "TimeoutError",
+        &RPCError::DecodeError(_) => "DecodeError",
+        &RPCError::PayloadTooLarge => "PayloadTooLarge",
+        &RPCError::ChecksumMismatch => "ChecksumMismatch",
+        &RPCError::Cancelled => "Cancelled",
+        &RPCError::StreamOutOfOrder { .. } => "StreamOutOfOrder",
+    }
+}
+
+fn duration_us(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000 + duration.subsec_nanos() as u64 / 1000
+}
+
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum_us: u64,
+    // counts for LATENCY_BUCKETS_US, with one more bucket for the slower calls
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..LATENCY_BUCKETS_US.len() + 1).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+    pub fn record(&self, elapsed: Duration) {
+        let us = duration_us(elapsed);
+        let bucket = LATENCY_BUCKETS_US.iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+            buckets: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    // upper bound of the bucket holding the percentile, None for no calls or the overflow bucket
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let target = (self.count as f64 * p / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += *count;
+            if seen >= target {
+                return LATENCY_BUCKETS_US.get(i).cloned();
+            }
+        }
+        None
+    }
+}
+
+// counters are updated without locking, the latency map is only written for new services
+pub struct Metrics {
+    requests: AtomicU64,
+    errors: Vec<AtomicU64>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    latencies: RwLock<HashMap<u64, Arc<Histogram>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetricsSnapshot {
+    pub requests: u64,
+    // error kind to count, kinds never happened are left out
+    pub errors: BTreeMap<String, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // service id to latency histogram
+    pub latencies: BTreeMap<u64, HistogramSnapshot>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: AtomicU64::new(0),
+            errors: ERROR_KINDS.iter().map(|_| AtomicU64::new(0)).collect(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            latencies: RwLock::new(HashMap::new()),
+        }
+    }
+    pub fn record_request(&self, error: Option<&'static str>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(kind) = error {
+            let slot = ERROR_KINDS.iter().position(|k| *k == kind).unwrap_or(ERROR_KINDS.len() - 1);
+            self.errors[slot].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub fn record_bytes(&self, bytes_in: usize, bytes_out: usize) {
+        self.bytes_in.fetch_add(bytes_in as u64, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out as u64, Ordering::Relaxed);
+    }
+    pub fn record_latency(&self, svr_id: u64, elapsed: Duration) {
+        let histogram = self.latencies.read().get(&svr_id).cloned();
+        let histogram = match histogram {
+            Some(histogram) => histogram,
+            None => self.latencies.write()
+                .entry(svr_id)
+                .or_insert_with(|| Arc::new(Histogram::new()))
+                .clone()
+        };
+        histogram.record(elapsed);
+    }
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: ERROR_KINDS.iter()
+                .zip(self.errors.iter())
+                .map(|(kind, count)| (kind.to_string(), count.load(Ordering::Relaxed)))
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            latencies: self.latencies.read().iter()
+                .map(|(svr_id, histogram)| (*svr_id, histogram.snapshot()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.snapshot().percentile(50.0), None);
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(2));
+        }
+        histogram.record(Duration::from_secs(60));
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 10);
+        assert_eq!(snapshot.percentile(50.0), Some(2500));
+        assert_eq!(snapshot.percentile(90.0), Some(2500));
+        // beyond the last bucket
+        assert_eq!(snapshot.percentile(100.0), None);
+    }
+}
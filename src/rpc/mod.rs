@@ -1,21 +1,62 @@
 #[macro_use]
 pub mod proto;
+pub mod compression;
+pub mod batch;
+pub mod health;
+pub mod context;
+pub mod cancel;
+pub mod metrics;
+pub mod stream;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::io;
-use std::time::Duration;
-use parking_lot::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::cmp::max;
+use parking_lot::{Mutex, RwLock, Condvar};
 use std::thread;
 use tcp;
 use utils::time;
 use utils::u8vec::*;
-use futures::Future;
+use utils::bincode;
+use utils::codec::{WireCodec, CodecError};
+use futures::{Future, Stream, future};
+use serde::de::DeserializeOwned;
+use futures::sync::oneshot;
 use bifrost_hasher::hash_str;
+use rand;
 use DISABLE_SHORTCUT;
 
+pub use bytes::Bytes;
+
+use self::compression::Compression;
+use self::batch::BATCH_SERVICE_ID;
+use self::health::{HEALTH_SERVICE_ID, PING_FN_ID, ECHO_FN_ID, METRICS_FN_ID, PingInfo};
+use self::context::{RequestContext, CONTEXT_SERVICE_ID};
+use self::cancel::{CancelHandle, Cancellations, CANCEL_SERVICE_ID};
+use self::metrics::{Metrics, MetricsSnapshot};
+use tcp::events::{ConnectHandler, DisconnectHandler};
+use self::stream::{StreamingService, Streams, ResponseStream, STREAM_SERVICE_ID, OPEN_FN_ID, NEXT_FN_ID, CLOSE_FN_ID};
+
 lazy_static! {
     pub static ref DEFAULT_CLIENT_POOL: ClientPool = ClientPool::new();
+    // servers with middlewares, in which service shortcuts have to go through server dispatch
+    static ref MIDDLEWARE_SERVERS: RwLock<HashSet<u64>> = RwLock::new(HashSet::new());
+    static ref DEFAULT_TRANSPORT: RwLock<tcp::TransportOptions> = RwLock::new(tcp::TransportOptions::Plain);
+}
+
+// reserved function id of every service, answered with the version in the service! definition
+pub static VERSION_FN_ID: u64 = hash_ident!(BIFROST_RPC_SERVICE_VERSION) as u64;
+
+// used by servers and clients created without explicit transport, including the client pools.
+// it should be set before starting any server or client
+pub fn set_default_transport(transport: tcp::TransportOptions) {
+    *DEFAULT_TRANSPORT.write() = transport;
+}
+
+pub fn default_transport() -> tcp::TransportOptions {
+    DEFAULT_TRANSPORT.read().clone()
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,165 +64,1089 @@ pub enum RPCRequestError {
     FunctionIdNotFound,
     ServiceIdNotFound,
     Other,
+    // new kinds of errors go after the others, peers tell them by the status byte, see encode_res
+    Rejected,
+    // serialized error from the service function
+    ServiceError(Vec<u8>),
+    MalformedRequest,
+    MalformedResponse,
+    // request arguments cannot be decoded by the service codec
+    BadRequestData,
+    // the response exceeds the frame size limit of the server
+    ResponseTooLarge,
+    // the request has expired before dispatch
+    DeadlineExceeded,
+    // the client cancelled the request before dispatch
+    Cancelled,
+    // the connection did not prove the auth token of the server
+    Unauthorized,
+    // the function does not exist on the server, which has an older version of the service
+    VersionMismatch { server: u32, client: u32 },
+    // the stream is unknown to the server, or aborted by its handler
+    StreamClosed,
 }
 
+// how local service clients reach a service registered on the server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShortcutPolicy {
+    // in the caller thread unless shortcuts are disabled or the server has middlewares
+    Default,
+    // in the caller thread, even bypassing middlewares of the server
+    Always,
+    // serialized and dispatched by the server like for remote clients
+    Never,
+}
+
+pub enum MiddlewareDecision {
+    Continue,
+    Reject(RPCRequestError),
+}
+
+// (service_id, request data)
+pub type Middleware = Box<Fn(u64, &[u8]) -> MiddlewareDecision + Send + Sync>;
+// (service_id, dispatch result, elapsed)
+pub type PostMiddleware = Box<Fn(u64, &Result<Vec<u8>, RPCRequestError>, Duration) + Send + Sync>;
+
 #[derive(Debug)]
 pub enum RPCError {
     IOError(io::Error),
     RequestError(RPCRequestError),
+    TimeoutError,
+    DecodeError(CodecError),
+    // the request exceeds the frame size limit of the client
+    PayloadTooLarge,
+    // the request or response frame was corrupted on the way, the request can be retried
+    ChecksumMismatch,
+    // cancelled through the CancelHandle
+    Cancelled,
+    // a chunk of the stream was lost between the pulls
+    StreamOutOfOrder { expected: u64, received: u64 },
 }
 
 pub trait RPCService: Sync + Send {
-    fn dispatch(&self, data: Vec<u8>) -> Result<Vec<u8>, RPCRequestError>;
-    fn register_shortcut_service(&self, service_ptr: usize, server_id: u64, service_id: u64);
+    // the request is a slice of the received frame, see utils::u8vec::split_u64_head
+    fn dispatch(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError>;
+    // the context is bound to the serving thread, services can read it from context::current
+    fn dispatch_with_ctx(&self, ctx: RequestContext, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+        context::with_context(ctx, || self.dispatch(data))
+    }
+    // service_ptr comes from Arc::into_raw of the service, the reference is taken over by the implementation
+    fn register_shortcut_service(&self, service_ptr: usize, server_id: u64, service_id: u64, policy: ShortcutPolicy);
+    fn unregister_shortcut_service(&self, server_id: u64, service_id: u64);
 }
 
 pub struct Server {
     services: RwLock<HashMap<u64, Arc<RPCService>>>,
+    streaming_services: RwLock<HashMap<u64, Arc<StreamingService>>>,
+    streams: Streams,
+    middlewares: RwLock<Vec<Middleware>>,
+    post_middlewares: RwLock<Vec<PostMiddleware>>,
+    compression: RwLock<Option<Compression>>,
+    cancellations: Cancellations,
+    metrics: Metrics,
+    options: tcp::server::ServerOptions,
+    started: Instant,
+    // addresses served besides the primary one, like an internal cluster interface
+    listeners: RwLock<Vec<String>>,
     pub address: String,
     pub server_id: u64
 }
 
+pub struct ServerHandle {
+    shutdown: Vec<oneshot::Sender<()>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    // listeners still running
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+// failed connects are remembered for this long before the address is dialed again
+pub static DEFAULT_CONNECT_COOLDOWN_MS: u64 = 500;
+
 pub struct ClientPool {
-    clients: Mutex<HashMap<String, Arc<RPCClient>>>
+    inner: Arc<PoolInner>,
 }
 
-fn encode_res(res: Result<Vec<u8>, RPCRequestError>) -> Vec<u8> {
-    match res {
-        Ok(vec) => {
-            [0u8; 1].iter().cloned().chain(vec.into_iter()).collect()
-        },
-        Err(e) => {
-            let err_id = match e {
-                RPCRequestError::FunctionIdNotFound => 1u8,
-                RPCRequestError::ServiceIdNotFound => 2u8,
-                _ => 255u8
-            };
-            vec!(err_id)
+struct PoolInner {
+    clients: Mutex<HashMap<String, Vec<Arc<RPCClient>>>>,
+    // one connect at a time for each address, so callers waiting for a down peer share the failure
+    connecting: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    // time of the failure, error kind and message
+    failures: Mutex<HashMap<String, (Instant, io::ErrorKind, String)>>,
+    cooldown: RwLock<Duration>,
+    attempts: AtomicUsize,
+    capacity: usize,
+    cursor: AtomicUsize,
+    auth_token: Option<String>,
+}
+
+// status code 0 is followed by the response body, otherwise it is the code of the error. only service
+// errors and version mismatches have a body after it, peers of other versions still tell the errors
+// apart, unknown codes are decoded as Other.
+// the high bit of the status byte is set when the body is compressed, whatever the setting of the client
+static COMPRESSED_STATUS: u8 = 0x80;
+
+fn encode_res(res: Result<Vec<u8>, RPCRequestError>, compression: &Option<Compression>) -> Vec<u8> {
+    let (status, body) = match res {
+        Ok(vec) => (0u8, vec),
+        Err(e) => match e {
+            RPCRequestError::FunctionIdNotFound => (1u8, vec![]),
+            RPCRequestError::ServiceIdNotFound => (2u8, vec![]),
+            RPCRequestError::Rejected => (3u8, vec![]),
+            RPCRequestError::ServiceError(data) => (4u8, data),
+            RPCRequestError::MalformedRequest => (5u8, vec![]),
+            RPCRequestError::BadRequestData => (6u8, vec![]),
+            RPCRequestError::DeadlineExceeded => (7u8, vec![]),
+            RPCRequestError::Cancelled => (8u8, vec![]),
+            RPCRequestError::Unauthorized => (9u8, vec![]),
+            RPCRequestError::VersionMismatch { server, client } => (10u8, bincode::serialize(&(server, client))),
+            RPCRequestError::StreamClosed => (11u8, vec![]),
+            RPCRequestError::ResponseTooLarge => (12u8, vec![]),
+            RPCRequestError::MalformedResponse => (13u8, vec![]),
+            RPCRequestError::Other => (127u8, vec![]),
         }
-    }
+    };
+    let (compressed, body) = compression::compress_body(compression, body);
+    let mut res = Vec::with_capacity(1 + body.len());
+    res.push(if compressed { status | COMPRESSED_STATUS } else { status });
+    res.extend_from_slice(&body);
+    res
 }
 
-fn decode_res(res: io::Result<Vec<u8>>) -> Result<Vec<u8>, RPCError> {
+fn decode_res<B: AsRef<[u8]>>(res: io::Result<B>) -> Result<Vec<u8>, RPCError> {
     match res {
         Ok(res) => {
-            if res[0] == 0u8 {
-                Ok(res.into_iter().skip(1).collect())
-            } else {
-                match res[0] {
-                    1u8 => Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)),
-                    2u8 => Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)),
-                    _ => Err(RPCError::RequestError(RPCRequestError::Other)),
-                }
+            let res = res.as_ref();
+            if res.is_empty() {
+                // truncated frame or misbehaving server
+                return Err(RPCError::RequestError(RPCRequestError::MalformedResponse));
             }
+            let status = res[0] & !COMPRESSED_STATUS;
+            let compressed = res[0] & COMPRESSED_STATUS != 0;
+            let body = || compression::decompress_body(compressed, res[1..].to_vec());
+            let e = match status {
+                0u8 => return body().ok_or(RPCError::RequestError(RPCRequestError::MalformedResponse)),
+                1u8 => RPCRequestError::FunctionIdNotFound,
+                2u8 => RPCRequestError::ServiceIdNotFound,
+                3u8 => RPCRequestError::Rejected,
+                4u8 => match body() {
+                    Some(body) => RPCRequestError::ServiceError(body),
+                    None => RPCRequestError::MalformedResponse
+                },
+                5u8 => RPCRequestError::MalformedRequest,
+                6u8 => RPCRequestError::BadRequestData,
+                7u8 => RPCRequestError::DeadlineExceeded,
+                8u8 => RPCRequestError::Cancelled,
+                9u8 => RPCRequestError::Unauthorized,
+                10u8 => match body().and_then(|body| bincode::try_deserialize::<(u32, u32)>(&body)) {
+                    Some((server, client)) => RPCRequestError::VersionMismatch { server: server, client: client },
+                    None => RPCRequestError::MalformedResponse
+                },
+                11u8 => RPCRequestError::StreamClosed,
+                12u8 => RPCRequestError::ResponseTooLarge,
+                13u8 => RPCRequestError::MalformedResponse,
+                _ => RPCRequestError::Other,
+            };
+            Err(RPCError::RequestError(e))
         },
-        Err(e) => Err(RPCError::IOError(e))
+        Err(e) => {
+            if e.kind() == io::ErrorKind::TimedOut {
+                Err(RPCError::TimeoutError)
+            } else if tcp::framed::is_checksum_mismatch(&e) {
+                Err(RPCError::ChecksumMismatch)
+            } else {
+                Err(RPCError::IOError(e))
+            }
+        }
     }
 }
 
 impl Server {
     pub fn new(address: &String) -> Arc<Server> {
+        Server::new_with_transport(address, default_transport())
+    }
+    pub fn new_with_transport(address: &String, transport: tcp::TransportOptions) -> Arc<Server> {
+        Server::new_with_options(address, tcp::server::ServerOptions {
+            transport: transport,
+            .. tcp::server::ServerOptions::default()
+        })
+    }
+    pub fn new_with_options(address: &String, options: tcp::server::ServerOptions) -> Arc<Server> {
         Arc::new(Server {
             services: RwLock::new(HashMap::new()),
+            streaming_services: RwLock::new(HashMap::new()),
+            streams: Streams::new(),
+            middlewares: RwLock::new(Vec::new()),
+            post_middlewares: RwLock::new(Vec::new()),
+            compression: RwLock::new(None),
+            cancellations: Cancellations::new(),
+            metrics: Metrics::new(),
+            options: options,
+            started: Instant::now(),
+            listeners: RwLock::new(Vec::new()),
             address: address.clone(),
             server_id: hash_str(address)
         })
     }
+    // the server id stays derived from the primary address.
+    // listeners have to be added before the server started listening
+    pub fn add_listener(&self, address: &String) {
+        self.listeners.write().push(address.clone());
+    }
+    // primary address first
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addresses = vec![self.address.clone()];
+        addresses.extend(self.listeners.read().iter().cloned());
+        addresses
+    }
+    // additional listeners are served in background threads
     pub fn listen(server: &Arc<Server>) {
-        let address = &server.address;
+        for address in server.listeners.read().iter() {
+            let address = address.clone();
+            let server = server.clone();
+            thread::spawn(move || {
+                if let Err(e) = Server::listen_on(&server, &address, None) {
+                    panic!("Cannot listen on {}, {:?}", address, e);
+                }
+            });
+        }
+        if let Err(e) = Server::listen_with_signal(server, None) {
+            panic!("Cannot listen on {}, {:?}", server.address, e);
+        }
+    }
+    // serves the primary address only
+    pub fn listen_with_signal(server: &Arc<Server>, shutdown: Option<tcp::server::ShutdownSignal>) -> io::Result<()> {
+        Server::listen_on(server, &server.address, shutdown)
+    }
+    fn listen_on(server: &Arc<Server>, address: &String, shutdown: Option<tcp::server::ShutdownSignal>) -> io::Result<()> {
+        let options = server.options.clone();
+        let rejecting = server.clone();
         let server = server.clone();
-        tcp::server::Server::new(address, Box::new(move |data| {
-            let (svr_id, data) = extract_u64_head(data);
-            let svr_map = server.services.read();
-            let service = svr_map.get(&svr_id);
-            let res = match service {
-                Some(service) => {
-                    encode_res(service.dispatch(data))
-                },
-                None => encode_res(Err(RPCRequestError::ServiceIdNotFound) as Result<Vec<u8>, RPCRequestError>)
+        tcp::server::Server::new_with_auth(address, Box::new(move |data| {
+            server.handle_frame(data)
+        }), Some(Box::new(move |data| {
+            rejecting.reject_frame(data)
+        })), shutdown, &options)
+    }
+    // frames from unauthenticated connections, never dispatched
+    fn reject_frame(&self, data: Bytes) -> Bytes {
+        let e = RPCRequestError::Unauthorized;
+        self.metrics.record_request(Some(metrics::request_error_kind(&e)));
+        let res = encode_res(Err(e), &*self.compression.read());
+        self.metrics.record_bytes(data.len(), res.len());
+        Bytes::from(res)
+    }
+    fn handle_frame(&self, data: Bytes) -> Bytes {
+        let compression = *self.compression.read();
+        let bytes_in = data.len();
+        let (svr_id, res) = match compression::decompress_bytes(data) {
+            Some(ref data) if data.len() < 8 => {
+                // no room for the service id
+                (0, Err(RPCRequestError::MalformedRequest))
+            },
+            Some(data) => {
+                let (svr_id, data) = split_u64_head(data);
+                (svr_id, self.route(svr_id, data, RequestContext::default()))
+            },
+            None => (0, Err(RPCRequestError::MalformedRequest))
+        };
+        //println!("SVR RPC: {} - {}ms", svr_id, time::get_time() - t);
+        let error = res.as_ref().err().map(metrics::request_error_kind);
+        let res = encode_res(res, &compression);
+        let (error, res) = if res.len() > self.options.max_frame_size {
+            warn!("Response of {} bytes for service {} exceeds the frame size limit", res.len(), svr_id);
+            let e = RPCRequestError::ResponseTooLarge;
+            (Some(metrics::request_error_kind(&e)), encode_res(Err(e), &compression))
+        } else {
+            (error, res)
+        };
+        // a batch counts as one request, calls in it are only seen in the latencies
+        self.metrics.record_request(error);
+        self.metrics.record_bytes(bytes_in, res.len());
+        Bytes::from(res)
+    }
+    fn route(&self, svr_id: u64, data: Bytes, ctx: RequestContext) -> Result<Vec<u8>, RPCRequestError> {
+        if svr_id == CONTEXT_SERVICE_ID {
+            match context::unwrap(data) {
+                Some((ctx, svr_id, data)) => self.route(svr_id, data, ctx),
+                None => Err(RPCRequestError::MalformedRequest)
+            }
+        } else if svr_id == BATCH_SERVICE_ID {
+            self.dispatch_batch(data, &ctx)
+        } else if svr_id == HEALTH_SERVICE_ID {
+            self.dispatch_health(data)
+        } else if svr_id == STREAM_SERVICE_ID {
+            self.dispatch_stream(data, &ctx)
+        } else if svr_id == CANCEL_SERVICE_ID {
+            if data.len() < 8 {
+                return Err(RPCRequestError::MalformedRequest);
+            }
+            self.cancellations.insert(split_u64_head(data).0);
+            Ok(vec![])
+        } else {
+            self.dispatch(svr_id, data, &ctx)
+        }
+    }
+    // each call in the batch is dispatched and encoded on its own, so they can fail separately
+    fn dispatch_batch(&self, data: Bytes, ctx: &RequestContext) -> Result<Vec<u8>, RPCRequestError> {
+        if data.len() < 8 {
+            return Err(RPCRequestError::MalformedRequest);
+        }
+        let (svr_id, data) = split_u64_head(data);
+        let reqs = match batch::unpack_bytes(&data) {
+            Some(reqs) => reqs,
+            None => return Err(RPCRequestError::MalformedRequest)
+        };
+        let res = reqs
+            .into_iter()
+            .map(|req| encode_res(self.dispatch(svr_id, req, ctx), &None))
+            .collect();
+        Ok(batch::pack(res))
+    }
+    // built-in service for probing, does not go through middlewares
+    fn dispatch_health(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+        if data.len() < 8 {
+            return Err(RPCRequestError::MalformedRequest);
+        }
+        let (fn_id, body) = split_u64_head(data);
+        if fn_id == PING_FN_ID {
+            let uptime = self.started.elapsed();
+            let mut services: Vec<u64> = self.services.read().keys().cloned().collect();
+            services.sort();
+            Ok(bincode::serialize(&PingInfo {
+                server_id: self.server_id,
+                uptime_ms: uptime.as_secs() * 1000 + uptime.subsec_nanos() as u64 / 1000000,
+                services: services,
+            }))
+        } else if fn_id == ECHO_FN_ID {
+            Ok(body.to_vec())
+        } else if fn_id == METRICS_FN_ID {
+            Ok(bincode::serialize(&self.metrics_snapshot()))
+        } else {
+            Err(RPCRequestError::FunctionIdNotFound)
+        }
+    }
+    // streams are opened like requests to other services, pulling and closing them do not go through middlewares
+    fn dispatch_stream(&self, data: Bytes, ctx: &RequestContext) -> Result<Vec<u8>, RPCRequestError> {
+        if data.len() < 16 {
+            return Err(RPCRequestError::MalformedRequest);
+        }
+        let (fn_id, body) = split_u64_head(data);
+        if fn_id == OPEN_FN_ID {
+            let (svr_id, body) = split_u64_head(body);
+            self.admit(svr_id, &body, ctx)?;
+            let service = match self.streaming_services.read().get(&svr_id) {
+                Some(service) => service.clone(),
+                None => return Err(RPCRequestError::ServiceIdNotFound)
             };
-            //println!("SVR RPC: {} - {}ms", svr_id, time::get_time() - t);
-            res
-        }));
+            let (stream_id, sink) = self.streams.open();
+            let ctx = ctx.clone();
+            thread::spawn(move || {
+                context::with_context(ctx, || service.dispatch(body.to_vec(), sink));
+            });
+            Ok(prepend_u64(stream_id, vec![]))
+        } else if fn_id == NEXT_FN_ID {
+            self.streams.next(split_u64_head(body).0)
+        } else if fn_id == CLOSE_FN_ID {
+            self.streams.close(split_u64_head(body).0);
+            Ok(vec![])
+        } else {
+            Err(RPCRequestError::FunctionIdNotFound)
+        }
     }
-    pub fn listen_and_resume(server: &Arc<Server>) {
-        let server = server.clone();
-        thread::spawn(move|| {
-            let server = server;
-            Server::listen(&server);
-        });
+    // deadline and middlewares, checked before dispatch
+    fn admit(&self, svr_id: u64, data: &[u8], ctx: &RequestContext) -> Result<(), RPCRequestError> {
+        if ctx.is_expired() {
+            // the client has given up on it
+            return Err(RPCRequestError::DeadlineExceeded);
+        }
+        for middleware in self.middlewares.read().iter() {
+            if let MiddlewareDecision::Reject(e) = middleware(svr_id, data) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    fn dispatch(&self, svr_id: u64, data: Bytes, ctx: &RequestContext) -> Result<Vec<u8>, RPCRequestError> {
+        self.admit(svr_id, &data, ctx)?;
+        if let Some(cancel_id) = ctx.cancel_id {
+            if self.cancellations.take(cancel_id) {
+                return Err(RPCRequestError::Cancelled);
+            }
+        }
+        let start = Instant::now();
+        let service = self.services.read().get(&svr_id).cloned();
+        let res = match service {
+            Some(service) => service.dispatch_with_ctx(ctx.clone(), data),
+            None => Err(RPCRequestError::ServiceIdNotFound)
+        };
+        let elapsed = start.elapsed();
+        self.metrics.record_latency(svr_id, elapsed);
+        for post_middleware in self.post_middlewares.read().iter() {
+            post_middleware(svr_id, &res, elapsed);
+        }
+        res
+    }
+    // middlewares run in registration order before dispatch, the first rejection short-circuits the request
+    pub fn register_middleware(&self, middleware: Middleware) {
+        self.middlewares.write().push(middleware);
+        MIDDLEWARE_SERVERS.write().insert(self.server_id);
+    }
+    pub fn register_post_middleware(&self, middleware: PostMiddleware) {
+        self.post_middlewares.write().push(middleware);
+        MIDDLEWARE_SERVERS.write().insert(self.server_id);
+    }
+    // responses are compressed with the setting, requests are understood whatever the setting of the client
+    pub fn set_compression(&self, compression: Option<Compression>) {
+        *self.compression.write() = compression;
+    }
+    // the server keeps running when the handle is dropped, call shutdown to stop it.
+    // every address is served in its own thread
+    pub fn listen_and_resume(server: &Arc<Server>) -> ServerHandle {
+        let addresses = server.addresses();
+        let running = Arc::new((Mutex::new(addresses.len()), Condvar::new()));
+        let mut shutdown = Vec::new();
+        let mut threads = Vec::new();
+        for address in addresses {
+            let server = server.clone();
+            let (tx, rx) = oneshot::channel();
+            let running_ref = running.clone();
+            threads.push(thread::spawn(move|| {
+                let server = server;
+                if let Err(e) = Server::listen_on(&server, &address, Some(rx)) {
+                    error!("Server on {} stopped with error, {:?}", address, e);
+                }
+                let &(ref lock, ref cvar) = &*running_ref;
+                *lock.lock() -= 1;
+                cvar.notify_all();
+            }));
+            shutdown.push(tx);
+        }
+        ServerHandle {
+            shutdown: shutdown,
+            threads: threads,
+            running: running,
+        }
+    }
+    fn register_shortcut(&self, service_id: u64, service: &Arc<RPCService>, policy: ShortcutPolicy) {
+        let enabled = match policy {
+            ShortcutPolicy::Default => !DISABLE_SHORTCUT,
+            ShortcutPolicy::Always => true,
+            ShortcutPolicy::Never => false,
+        };
+        if enabled {
+            // the data pointer without vtable, the service macro casts it back to its own type
+            let service_ptr = Arc::into_raw(service.clone()) as *const u8 as usize;
+            service.register_shortcut_service(service_ptr, self.server_id, service_id, policy);
+        } else if policy == ShortcutPolicy::Default {
+            println!("SERVICE SHORTCUT DISABLED");
+        }
     }
     pub fn register_service<T>(&self, service_id: u64,  service: &Arc<T>)
     where T: RPCService + Sized + 'static{
-        let service = service.clone();
-        if !DISABLE_SHORTCUT {
-            let service_ptr = Arc::into_raw(service.clone()) as usize;
-            service.register_shortcut_service(service_ptr, self.server_id, service_id);
-        } else {
-            println!("SERVICE SHORTCUT DISABLED");
+        self.register_service_with_shortcut(service_id, service, ShortcutPolicy::Default);
+    }
+    pub fn register_service_with_shortcut<T>(&self, service_id: u64, service: &Arc<T>, policy: ShortcutPolicy)
+    where T: RPCService + Sized + 'static {
+        self.register_services_with_shortcut(vec![(service_id, service.clone() as Arc<RPCService>)], policy);
+    }
+    // all of the services become visible to server dispatch at once,
+    // shortcuts are registered one by one in the meantime
+    pub fn register_services(&self, services: Vec<(u64, Arc<RPCService>)>) {
+        self.register_services_with_shortcut(services, ShortcutPolicy::Default);
+    }
+    pub fn register_services_with_shortcut(&self, services: Vec<(u64, Arc<RPCService>)>, policy: ShortcutPolicy) {
+        let mut registered = self.services.write();
+        for (service_id, service) in services {
+            if let Some(old) = registered.get(&service_id) {
+                old.unregister_shortcut_service(self.server_id, service_id);
+            }
+            self.register_shortcut(service_id, &service, policy);
+            registered.insert(service_id, service);
         }
-        self.services.write().insert(service_id, service);
+    }
+    // requests for the id are served by the old service until it is swapped, never by none of them.
+    // local clients missing the shortcut in between fall back to server dispatch
+    pub fn replace_service(&self, service_id: u64, service: Arc<RPCService>) -> Option<Arc<RPCService>> {
+        let mut services = self.services.write();
+        if let Some(old) = services.get(&service_id) {
+            old.unregister_shortcut_service(self.server_id, service_id);
+        }
+        self.register_shortcut(service_id, &service, ShortcutPolicy::Default);
+        services.insert(service_id, service)
+    }
+    // streaming services have their own ids, they are not reachable by send and service clients
+    pub fn register_streaming_service(&self, service_id: u64, service: Arc<StreamingService>) {
+        self.streaming_services.write().insert(service_id, service);
+    }
+    pub fn remove_streaming_service(&self, service_id: u64) {
+        self.streaming_services.write().remove(&service_id);
     }
     pub fn remove_service(&self, service_id: u64) {
-        self.services.write().remove(&service_id);
+        self.remove_services(vec![service_id]);
+    }
+    pub fn remove_services(&self, service_ids: Vec<u64>) {
+        let mut services = self.services.write();
+        for service_id in service_ids {
+            if let Some(service) = services.remove(&service_id) {
+                service.unregister_shortcut_service(self.server_id, service_id);
+            }
+        }
     }
     pub fn address(&self) -> &String {
         &self.address
     }
+    // requests dropped for failing the frame checksum
+    pub fn corrupted_frames(&self) -> usize {
+        self.options.corrupted_frames.load(Ordering::Relaxed)
+    }
+    // requests served over the network, local service shortcuts are not counted
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+    // peers connected and disconnected on all listeners, unix sockets and shortcut calls are not reported
+    pub fn set_connection_listener(&self, on_connect: ConnectHandler, on_disconnect: DisconnectHandler) {
+        self.options.events.on_connect(on_connect);
+        self.options.events.on_disconnect(on_disconnect);
+    }
+}
+
+// shortcuts of the server should not be found by local clients after it is gone
+impl Drop for Server {
+    fn drop(&mut self) {
+        for (service_id, service) in self.services.read().iter() {
+            service.unregister_shortcut_service(self.server_id, *service_id);
+        }
+    }
+}
+
+impl ServerHandle {
+    // stop accepting connections, drain in-flight requests and join the listener threads
+    pub fn shutdown(self) {
+        for signal in self.shutdown {
+            signal.send(()).ok();
+        }
+        for thread in self.threads {
+            thread.join().ok();
+        }
+    }
+    // block until all listeners of the server stopped
+    pub fn wait(&self) {
+        let &(ref lock, ref cvar) = &*self.running;
+        let mut running = lock.lock();
+        while *running > 0 {
+            cvar.wait(&mut running);
+        }
+    }
+}
+
+pub fn has_middlewares(server_id: u64) -> bool {
+    MIDDLEWARE_SERVERS.read().contains(&server_id)
+}
+
+// used by service client stubs, older servers return service errors inside of the success body
+pub fn decode_service_res<C, T, E>(res: Result<Vec<u8>, RPCError>) -> Result<Result<T, E>, RPCError>
+    where C: WireCodec, T: DeserializeOwned, E: DeserializeOwned {
+    match res {
+        Ok(res_bytes) => C::decode(&res_bytes).map_err(RPCError::DecodeError),
+        Err(RPCError::RequestError(RPCRequestError::ServiceError(err_bytes))) => {
+            C::decode(&err_bytes).map(Err).map_err(RPCError::DecodeError)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+// requests sent in a thread with context are wrapped in a context frame,
+// the timeout of the request becomes the deadline
+fn frame(svr_id: u64, data: Vec<u8>, compression: &Option<Compression>, timeout: Option<Duration>) -> Vec<u8> {
+    let mut ctx = context::current();
+    if let Some(timeout) = timeout {
+        ctx.set_timeout(timeout);
+    }
+    let (svr_id, data) = if ctx.is_empty() {
+        (svr_id, data)
+    } else {
+        (CONTEXT_SERVICE_ID, context::wrap(&ctx, svr_id, data))
+    };
+    compression::compress(compression, prepend_u64(svr_id, data))
+}
+
+// decode the response and account the call in the client metrics
+fn record_call(
+    registry: &Metrics, svr_id: u64, start: Instant, bytes_out: usize, res: io::Result<Bytes>
+) -> Result<Vec<u8>, RPCError> {
+    let bytes_in = res.as_ref().map(|res| res.len()).unwrap_or(0);
+    let res = decode_res(res);
+    registry.record_latency(svr_id, start.elapsed());
+    registry.record_request(res.as_ref().err().map(metrics::error_kind));
+    registry.record_bytes(bytes_in, bytes_out);
+    res
+}
+
+// counts a request for the client until dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::Relaxed);
+        InFlight(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub struct RPCClient {
-    client: Mutex<tcp::client::Client>,
+    client: tcp::client::Client,
+    connected: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+    compression: RwLock<Option<Compression>>,
+    metrics: Arc<Metrics>,
     pub server_id: u64,
     pub address: String
 }
 
 impl RPCClient {
+    fn frame(
+        &self, svr_id: u64, data: Vec<u8>,
+        compression: &Option<Compression>, timeout: Option<Duration>
+    ) -> Result<Vec<u8>, RPCError> {
+        let req = frame(svr_id, data, compression, timeout);
+        if req.len() > self.client.max_frame_size() {
+            Err(RPCError::PayloadTooLarge)
+        } else {
+            Ok(req)
+        }
+    }
     pub fn send(&self, svr_id: u64, data: Vec<u8>) -> Result<Vec<u8>, RPCError> {
-        decode_res(self.client.lock().send(prepend_u64(svr_id, data)))
+        let compression = *self.compression.read();
+        let req = self.frame(svr_id, data, &compression, self.client.default_timeout())?;
+        let _in_flight = InFlight::new(&self.in_flight);
+        let start = Instant::now();
+        let bytes_out = req.len();
+        let res = self.client.send(req);
+        record_call(&self.metrics, svr_id, start, bytes_out, res)
+    }
+    pub fn send_with_timeout(&self, svr_id: u64, data: Vec<u8>, timeout: Duration) -> Result<Vec<u8>, RPCError> {
+        let compression = *self.compression.read();
+        let req = self.frame(svr_id, data, &compression, Some(timeout))?;
+        let _in_flight = InFlight::new(&self.in_flight);
+        let start = Instant::now();
+        let bytes_out = req.len();
+        let res = self.client.send_with_timeout(req, timeout);
+        record_call(&self.metrics, svr_id, start, bytes_out, res)
+    }
+    // the trace id is visible to the service through context::current_trace_id
+    pub fn send_traced(&self, svr_id: u64, trace_id: u64, data: Vec<u8>) -> Result<Vec<u8>, RPCError> {
+        context::with_trace_id(trace_id, || self.send(svr_id, data))
+    }
+    // requests are packed in one frame, the outer result fails only when the whole batch failed
+    pub fn send_batch(&self, svr_id: u64, reqs: Vec<Vec<u8>>) -> Result<Vec<Result<Vec<u8>, RPCError>>, RPCError> {
+        let compression = *self.compression.read();
+        let body = prepend_u64(svr_id, batch::pack(reqs));
+        let req = self.frame(BATCH_SERVICE_ID, body, &compression, self.client.default_timeout())?;
+        let packed = {
+            let _in_flight = InFlight::new(&self.in_flight);
+            let start = Instant::now();
+            let bytes_out = req.len();
+            let res = self.client.send(req);
+            record_call(&self.metrics, BATCH_SERVICE_ID, start, bytes_out, res)?
+        };
+        match batch::unpack(&packed) {
+            Some(res) => Ok(res.into_iter().map(|res| decode_res(Ok(res))).collect()),
+            None => Err(RPCError::RequestError(RPCRequestError::MalformedResponse))
+        }
+    }
+    // requests are compressed with the setting, the server does not need the same one
+    pub fn set_compression(&self, compression: Option<Compression>) {
+        *self.compression.write() = compression;
+    }
+    // default timeout for both send and send_async, None for waiting until connection timeout
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        self.client.set_default_timeout(timeout);
     }
     pub fn send_async(&self, svr_id: u64, data: Vec<u8>) -> Box<Future<Item = Vec<u8>, Error = RPCError>> {
-        Box::new(self.client.lock()
-            .send_async(prepend_u64(svr_id, data))
-            .then(move |res| decode_res(res)))
+        let compression = *self.compression.read();
+        let req = match self.frame(svr_id, data, &compression, self.client.default_timeout()) {
+            Ok(req) => req,
+            Err(e) => return Box::new(future::err(e))
+        };
+        let in_flight = InFlight::new(&self.in_flight);
+        let registry = self.metrics.clone();
+        let start = Instant::now();
+        let bytes_out = req.len();
+        Box::new(self.client
+            .send_async(req)
+            .then(move |res| {
+                drop(in_flight);
+                record_call(&registry, svr_id, start, bytes_out, res)
+            }))
+    }
+    // the future fails with RPCError::Cancelled when cancelled through the handle.
+    // cancelled or dropped before response, the server is asked to skip the request if not yet dispatched
+    pub fn send_async_cancellable(&self, svr_id: u64, data: Vec<u8>)
+        -> (Box<Future<Item = Vec<u8>, Error = RPCError>>, CancelHandle) {
+        let cancel_id = rand::random::<u64>();
+        let (handle, cancelled) = CancelHandle::new(cancel_id);
+        let compression = *self.compression.read();
+        let mut ctx = context::current();
+        ctx.cancel_id = Some(cancel_id);
+        let req = context::with_context(ctx, || {
+            self.frame(svr_id, data, &compression, self.client.default_timeout())
+        });
+        let req = match req {
+            Ok(req) => req,
+            Err(e) => return (Box::new(future::err(e)), handle)
+        };
+        let cancel_msg = compression::compress(&compression, prepend_u64(CANCEL_SERVICE_ID, prepend_u64(cancel_id, vec![])));
+        let in_flight = InFlight::new(&self.in_flight);
+        let registry = self.metrics.clone();
+        let start = Instant::now();
+        let bytes_out = req.len();
+        let res = self.client
+            .send_async_with_cancel(req, cancel_msg)
+            .then(move |res| {
+                drop(in_flight);
+                record_call(&registry, svr_id, start, bytes_out, res)
+            });
+        let cancelled = cancelled.then(|signal| -> Box<Future<Item = Vec<u8>, Error = RPCError>> {
+            match signal {
+                Ok(()) => Box::new(future::err(RPCError::Cancelled)),
+                Err(_) => Box::new(future::empty()) // handle dropped
+            }
+        });
+        // the request future is dropped when cancelled
+        let future = res.select(cancelled).map(|(res, _)| res).map_err(|(e, _)| e);
+        (Box::new(future), handle)
+    }
+    // chunks yielded by the streaming service in order, see stream::collect for reassembly
+    pub fn send_stream(client: &Arc<RPCClient>, svr_id: u64, data: Vec<u8>) -> Box<Stream<Item = Vec<u8>, Error = RPCError>> {
+        Box::new(ResponseStream::open(client, svr_id, data))
+    }
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+    pub fn ping(&self) -> Result<PingInfo, RPCError> {
+        let res = self.send(HEALTH_SERVICE_ID, prepend_u64(PING_FN_ID, vec![]))?;
+        bincode::try_deserialize(&res).ok_or(RPCError::RequestError(RPCRequestError::MalformedResponse))
+    }
+    pub fn list_services(&self) -> Result<Vec<u64>, RPCError> {
+        self.ping().map(|info| info.services)
+    }
+    // round trip the data for latency probing
+    pub fn echo(&self, data: Vec<u8>) -> Result<Vec<u8>, RPCError> {
+        self.send(HEALTH_SERVICE_ID, prepend_u64(ECHO_FN_ID, data))
+    }
+    // requests sent and waiting for response
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+    // calls made by this client, requests failed before sending are not counted
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+    // fires once for each connection lost, with the address the client dialed
+    pub fn set_disconnect_handler(&self, handler: DisconnectHandler) {
+        self.client.on_disconnect(handler);
+    }
+    // metrics of the server, as returned by Server::metrics_snapshot
+    pub fn server_metrics(&self) -> Result<MetricsSnapshot, RPCError> {
+        let res = self.send(HEALTH_SERVICE_ID, prepend_u64(METRICS_FN_ID, vec![]))?;
+        bincode::try_deserialize(&res).ok_or(RPCError::RequestError(RPCRequestError::MalformedResponse))
     }
     pub fn new(addr: &String) -> io::Result<Arc<RPCClient>> {
-        let client = tcp::client::Client::connect(addr)?;
-        Ok(Arc::new(RPCClient {
-            server_id: client.server_id,
-            client: Mutex::new(client),
-            address: addr.clone()
-        }))
+        RPCClient::with_transport(addr, &default_transport())
     }
     pub fn with_timeout(addr: &String, timeout: Duration) -> io::Result<Arc<RPCClient>> {
-        let client = tcp::client::Client::connect_with_timeout(addr, timeout)?;
-        Ok(Arc::new(RPCClient {
+        RPCClient::with_options(addr, tcp::client::ClientOptions {
+            timeout: timeout,
+            tls: default_transport().connector().cloned(),
+            .. tcp::client::ClientOptions::default()
+        })
+    }
+    pub fn with_transport(addr: &String, transport: &tcp::TransportOptions) -> io::Result<Arc<RPCClient>> {
+        RPCClient::with_options(addr, tcp::client::ClientOptions {
+            tls: transport.connector().cloned(),
+            .. tcp::client::ClientOptions::default()
+        })
+    }
+    pub fn with_options(addr: &String, options: tcp::client::ClientOptions) -> io::Result<Arc<RPCClient>> {
+        let client = tcp::client::Client::connect_with_options(addr, options)?;
+        Ok(RPCClient::from_tcp(addr, client))
+    }
+    fn from_tcp(addr: &String, client: tcp::client::Client) -> Arc<RPCClient> {
+        Arc::new(RPCClient {
             server_id: client.server_id,
-            client: Mutex::new(client),
+            connected: client.connection_flag(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            compression: RwLock::new(None),
+            metrics: Arc::new(Metrics::new()),
+            client: client,
             address: addr.clone()
-        }))
+        })
     }
 }
 
 impl ClientPool {
     pub fn new() -> ClientPool {
+        ClientPool::with_capacity_per_host(1)
+    }
+
+    // up to n connections for each address
+    pub fn with_capacity_per_host(n: usize) -> ClientPool {
+        ClientPool::with_capacity_and_token(n, None)
+    }
+
+    // connections of the pool prove the token to servers started with it
+    pub fn with_auth_token(token: &String) -> ClientPool {
+        ClientPool::with_capacity_and_token(1, Some(token.clone()))
+    }
+
+    fn with_capacity_and_token(n: usize, auth_token: Option<String>) -> ClientPool {
         ClientPool {
-            clients: Mutex::new(HashMap::new())
+            inner: Arc::new(PoolInner {
+                clients: Mutex::new(HashMap::new()),
+                connecting: Mutex::new(HashMap::new()),
+                failures: Mutex::new(HashMap::new()),
+                cooldown: RwLock::new(Duration::from_millis(DEFAULT_CONNECT_COOLDOWN_MS)),
+                attempts: AtomicUsize::new(0),
+                capacity: max(n, 1),
+                cursor: AtomicUsize::new(0),
+                auth_token: auth_token,
+            })
         }
     }
 
+    // zero for dialing on every get
+    pub fn set_connect_cooldown(&self, cooldown: Duration) {
+        *self.inner.cooldown.write() = cooldown;
+    }
+
+    // new connections are opened until the address reached the capacity,
+    // then the connection with least in-flight requests is picked, ties are taken in turns.
+    // after a failed connect, the error is returned without dialing until the cooldown expired
     pub fn get(&self, addr: &String) -> io::Result<Arc<RPCClient>> {
-        let mut clients = self.clients.lock();
-        if clients.contains_key(addr) {
-            Ok(clients.get(addr).unwrap().clone())
-        } else {
-            let client = RPCClient::new(addr);
-            if let Ok(client) = client {
-                clients.insert(addr.clone(), client.clone());
+        self.inner.get(addr)
+    }
+
+    // pooled clients and failures in cooldown are resolved right away, connects run in their own thread
+    pub fn get_async(&self, addr: &String) -> Box<Future<Item = Arc<RPCClient>, Error = io::Error>> {
+        match self.inner.cached(addr) {
+            Ok(Some(client)) => return Box::new(future::finished(client)),
+            Err(e) => return Box::new(future::err(e)),
+            Ok(None) => {}
+        }
+        let inner = self.inner.clone();
+        let addr = addr.clone();
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            tx.send(inner.get(&addr)).ok();
+        });
+        Box::new(rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Connecting thread died"))
+        }))
+    }
+
+    // the failure of the address is forgotten as well
+    pub fn invalidate(&self, addr: &String) {
+        self.inner.clients.lock().remove(addr);
+        self.inner.failures.lock().remove(addr);
+    }
+
+    // drop only this connection, others to the same address are kept
+    pub fn invalidate_client(&self, client: &Arc<RPCClient>) {
+        let mut clients = self.inner.clients.lock();
+        if let Some(conns) = clients.get_mut(&client.address) {
+            conns.retain(|c| !Arc::ptr_eq(c, client));
+        }
+    }
+
+    pub fn connections(&self, addr: &String) -> usize {
+        self.inner.clients.lock().get(addr).map(|conns| conns.len()).unwrap_or(0)
+    }
+
+    // connects tried by the pool, failed or not
+    pub fn connect_attempts(&self) -> usize {
+        self.inner.attempts.load(Ordering::Relaxed)
+    }
+}
+
+impl PoolInner {
+    fn get(&self, addr: &String) -> io::Result<Arc<RPCClient>> {
+        if let Some(client) = self.cached(addr)? {
+            return Ok(client);
+        }
+        let connecting = self.connecting.lock()
+            .entry(addr.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        let _connecting = connecting.lock();
+        // connected or failed by another caller while waiting
+        if let Some(client) = self.cached(addr)? {
+            return Ok(client);
+        }
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        let connected = RPCClient::with_options(addr, tcp::client::ClientOptions {
+            tls: default_transport().connector().cloned(),
+            auth_token: self.auth_token.clone(),
+            .. tcp::client::ClientOptions::default()
+        });
+        match connected {
+            Ok(client) => {
+                self.failures.lock().remove(addr);
+                self.clients.lock().entry(addr.clone()).or_insert_with(|| Vec::new()).push(client.clone());
                 Ok(client)
-            } else {
-                Err(client.err().unwrap())
+            },
+            Err(e) => {
+                debug!("Cannot connect to {}, {:?}", addr, e);
+                self.failures.lock().insert(addr.clone(), (Instant::now(), e.kind(), format!("{}", e)));
+                Err(e)
+            }
+        }
+    }
+
+    // a pooled client, the failure in cooldown, or None for connecting a new one
+    fn cached(&self, addr: &String) -> io::Result<Option<Arc<RPCClient>>> {
+        {
+            let mut failures = self.failures.lock();
+            if let Some(&(failed, kind, ref message)) = failures.get(addr) {
+                if failed.elapsed() < *self.cooldown.read() {
+                    return Err(io::Error::new(kind, format!("{} (connect in cooldown)", message)));
+                }
+            }
+            // expired, the address can be dialed again
+            failures.remove(addr);
+        }
+        let mut clients = self.clients.lock();
+        let conns = match clients.get_mut(addr) {
+            Some(conns) => conns,
+            None => return Ok(None)
+        };
+        let conns_num = conns.len();
+        conns.retain(|client| client.is_connected());
+        if conns.len() < conns_num {
+            // connection broken, evict it and reconnect
+            debug!("Evicting {} broken connections to {}", conns_num - conns.len(), addr);
+        }
+        if conns.len() >= self.capacity {
+            let cursor = self.cursor.fetch_add(1, Ordering::Relaxed);
+            let len = conns.len();
+            let client = (0..len)
+                .map(|i| &conns[cursor.wrapping_add(i) % len])
+                .min_by_key(|client| client.in_flight())
+                .unwrap();
+            return Ok(Some(client.clone()));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn malformed_response() {
+        match decode_res(Ok(Vec::<u8>::new())) {
+            Err(RPCError::RequestError(RPCRequestError::MalformedResponse)) => {},
+            other => panic!("{:?}", other)
+        }
+        // single status byte from older servers
+        match decode_res(Ok(vec![2u8])) {
+            Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)) => {},
+            other => panic!("{:?}", other)
+        }
+        match decode_res(Ok(vec![0u8])) {
+            Ok(body) => assert!(body.is_empty()),
+            other => panic!("{:?}", other)
+        }
+        // codes of errors newer than the client
+        match decode_res(Ok(vec![200u8, 1, 2])) {
+            Err(RPCError::RequestError(RPCRequestError::Other)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn error_codes() {
+        let errors = vec![
+            RPCRequestError::FunctionIdNotFound, RPCRequestError::ServiceIdNotFound, RPCRequestError::Other,
+            RPCRequestError::Rejected, RPCRequestError::ServiceError(vec![1, 2, 3]),
+            RPCRequestError::MalformedRequest, RPCRequestError::MalformedResponse,
+            RPCRequestError::BadRequestData, RPCRequestError::ResponseTooLarge,
+            RPCRequestError::DeadlineExceeded, RPCRequestError::Cancelled, RPCRequestError::Unauthorized,
+            RPCRequestError::VersionMismatch { server: 1, client: 2 }, RPCRequestError::StreamClosed,
+        ];
+        for e in errors {
+            let expected = format!("{:?}", e);
+            match decode_res(Ok(encode_res(Err(e), &None))) {
+                Err(RPCError::RequestError(decoded)) => assert_eq!(format!("{:?}", decoded), expected),
+                other => panic!("{:?}", other)
             }
         }
+        // only the code, like from peers without bodies for errors
+        assert_eq!(encode_res(Err(RPCRequestError::ResponseTooLarge), &None), vec![12u8]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn compressed_status() {
+        let compression = Some(Compression { threshold: 0, ..Compression::default() });
+        let body = vec![7u8; 1024];
+        let res = encode_res(Ok(body.clone()), &compression);
+        assert_eq!(res[0], COMPRESSED_STATUS);
+        assert!(res.len() < body.len());
+        // decoded by the status byte, the client does not need the setting
+        assert_eq!(decode_res(Ok(res)).unwrap(), body);
+        match decode_res(Ok(encode_res(Err(RPCRequestError::ServiceError(body.clone())), &compression))) {
+            Err(RPCError::RequestError(RPCRequestError::ServiceError(data))) => assert_eq!(data, body),
+            other => panic!("{:?}", other)
+        }
+        // errors without bodies are never compressed
+        assert_eq!(encode_res(Err(RPCRequestError::Cancelled), &compression), vec![8u8]);
+    }
+
+    #[test]
+    fn compressed_request() {
+        let compression = Some(Compression { threshold: 0, ..Compression::default() });
+        let req = prepend_u64(1, vec![7u8; 1024]);
+        // frames of clients without compression are the same as before compression existed
+        assert_eq!(frame(1, vec![7u8; 1024], &None, None), req);
+        let compressed = frame(1, vec![7u8; 1024], &compression, None);
+        assert!(compressed.len() < req.len());
+        assert_eq!(compression::decompress(compressed.clone()).unwrap(), req);
+        let server = Server::new(&String::from("127.0.0.1:1361"));
+        match decode_res(Ok(server.handle_frame(Bytes::from(compressed)))) {
+            Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)) => {},
+            other => panic!("{:?}", other)
+        }
+        let corrupted = prepend_u64(compression::COMPRESSED_SERVICE_ID, vec![0xffu8; 16]);
+        match decode_res(Ok(server.handle_frame(Bytes::from(corrupted)))) {
+            Err(RPCError::RequestError(RPCRequestError::MalformedRequest)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn malformed_request() {
+        let server = Server::new(&String::from("127.0.0.1:1360"));
+        for frame in vec![vec![], vec![1u8, 2, 3]] {
+            match decode_res(Ok(server.handle_frame(Bytes::from(frame)))) {
+                Err(RPCError::RequestError(RPCRequestError::MalformedRequest)) => {},
+                other => panic!("{:?}", other)
+            }
+        }
+        match decode_res(Ok(server.handle_frame(Bytes::from(prepend_u64(1, vec![]))))) {
+            Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)) => {},
+            other => panic!("{:?}", other)
+        }
+        // context length beyond the frame
+        let frame = prepend_u64(CONTEXT_SERVICE_ID, prepend_u64(1024, vec![]));
+        match decode_res(Ok(server.handle_frame(Bytes::from(frame)))) {
+            Err(RPCError::RequestError(RPCRequestError::MalformedRequest)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+}
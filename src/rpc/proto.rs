@@ -4,35 +4,100 @@ use bincode;
 macro_rules! dispatch_rpc_service_functions {
     ($s:ty) => {
         impl $crate::rpc::RPCService for $s {
-            fn dispatch(&self, data: Vec<u8>) -> Result<Vec<u8>, $crate::rpc::RPCRequestError> {
+            fn dispatch(&self, data: $crate::rpc::Bytes) -> Result<Vec<u8>, $crate::rpc::RPCRequestError> {
                 self.inner_dispatch(data)
             }
-            fn register_shortcut_service(&self, service_ptr: usize, server_id: u64, service_id: u64) {
+            fn register_shortcut_service(
+                &self, service_ptr: usize, server_id: u64, service_id: u64, policy: $crate::rpc::ShortcutPolicy
+            ) {
                 let mut cbs = RPC_SVRS.write();
-                let service = unsafe {Arc::from_raw(service_ptr as *const $s)};
-                cbs.insert((server_id, service_id), service);
+                // takes back the reference leaked by Arc::into_raw, only a weak one is kept
+                let service: Arc<Service> = unsafe {Arc::from_raw(service_ptr as *const $s)};
+                cbs.insert((server_id, service_id), (Arc::downgrade(&service), policy));
+            }
+            fn unregister_shortcut_service(&self, server_id: u64, service_id: u64) {
+                RPC_SVRS.write().remove(&(server_id, service_id));
             }
         }
     };
 }
 
 // this macro expansion design took credits from tarpc by Google Inc.
+// an optional `codec Type;` line before the functions selects the WireCodec, bincode by default.
+// an optional `version N;` line goes first, 0 by default. it should be raised when functions are added,
+// so clients can tell an older server from a function that does not exist
 #[macro_export]
 macro_rules! service {
     (
+        version $version:expr;
+        codec $codec:ty;
         $(
             $(#[$attr:meta])*
             rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)* $(| $error:ty)*;
         )*
     ) => {
-        service! {{
-            $(
-                $(#[$attr])*
-                rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)* $(| $error)*;
-            )*
-        }}
+        service! {
+            @codec [$codec] @version [$version]
+            {
+                $(
+                    $(#[$attr])*
+                    rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)* $(| $error)*;
+                )*
+            }
+        }
+    };
+    (
+        version $version:expr;
+        $(
+            $(#[$attr:meta])*
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)* $(| $error:ty)*;
+        )*
+    ) => {
+        service! {
+            @codec [$crate::utils::codec::DefaultCodec] @version [$version]
+            {
+                $(
+                    $(#[$attr])*
+                    rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)* $(| $error)*;
+                )*
+            }
+        }
     };
     (
+        codec $codec:ty;
+        $(
+            $(#[$attr:meta])*
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)* $(| $error:ty)*;
+        )*
+    ) => {
+        service! {
+            @codec [$codec] @version [0]
+            {
+                $(
+                    $(#[$attr])*
+                    rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)* $(| $error)*;
+                )*
+            }
+        }
+    };
+    (
+        $(
+            $(#[$attr:meta])*
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)* $(| $error:ty)*;
+        )*
+    ) => {
+        service! {
+            @codec [$crate::utils::codec::DefaultCodec] @version [0]
+            {
+                $(
+                    $(#[$attr])*
+                    rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)* $(| $error)*;
+                )*
+            }
+        }
+    };
+    (
+        @codec [$codec:ty] @version [$version:expr]
         {
             $(#[$attr:meta])*
             rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ); // No return, no error
@@ -42,6 +107,7 @@ macro_rules! service {
         $( $expanded:tt )*
     ) => {
         service! {
+            @codec [$codec] @version [$version]
             { $( $unexpanded )* }
 
             $( $expanded )*
@@ -51,6 +117,7 @@ macro_rules! service {
         }
     };
     (
+        @codec [$codec:ty] @version [$version:expr]
         {
             $(#[$attr:meta])*
             rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty; //return, no error
@@ -60,6 +127,7 @@ macro_rules! service {
         $( $expanded:tt )*
     ) => {
         service! {
+            @codec [$codec] @version [$version]
             { $( $unexpanded )* }
 
             $( $expanded )*
@@ -69,6 +137,7 @@ macro_rules! service {
         }
     };
     (
+        @codec [$codec:ty] @version [$version:expr]
         {
             $(#[$attr:meta])*
             rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) | $error:ty; //no return, error
@@ -78,6 +147,7 @@ macro_rules! service {
         $( $expanded:tt )*
     ) => {
         service! {
+            @codec [$codec] @version [$version]
             { $( $unexpanded )* }
 
             $( $expanded )*
@@ -87,6 +157,7 @@ macro_rules! service {
         }
     };
     (
+        @codec [$codec:ty] @version [$version:expr]
         {
             $(#[$attr:meta])*
             rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty | $error:ty; //return, error
@@ -96,6 +167,7 @@ macro_rules! service {
         $( $expanded:tt )*
     ) => {
         service! {
+            @codec [$codec] @version [$version]
             { $( $unexpanded )* }
 
             $( $expanded )*
@@ -105,6 +177,7 @@ macro_rules! service {
         }
     };
     (
+        @codec [$codec:ty] @version [$version:expr]
         {} // all expanded
         $(
             $(#[$attr:meta])*
@@ -119,24 +192,45 @@ macro_rules! service {
         use $crate::utils::u8vec::*;
         use futures::{Future, future};
 
+        // services are owned by their servers, entries outliving them are never upgraded
         lazy_static! {
             pub static ref RPC_SVRS:
-            ::parking_lot::RwLock<::std::collections::BTreeMap<(u64, u64), Arc<Service>>>
+            ::parking_lot::RwLock<::std::collections::BTreeMap<(u64, u64), (::std::sync::Weak<Service>, ShortcutPolicy)>>
             = ::parking_lot::RwLock::new(::std::collections::BTreeMap::new());
         }
 
+        pub const SERVICE_VERSION: u32 = $version;
+
         pub trait Service: RPCService {
            $(
                 $(#[$attr])*
                 fn $fn_name(&self, $($arg:&$in_),*) -> std::result::Result<$out, $error>;
            )*
-           fn inner_dispatch(&self, data: Vec<u8>) -> Result<Vec<u8>, RPCRequestError> {
-               let (func_id, body) = extract_u64_head(data);
+           fn inner_dispatch(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+               if data.len() < 8 {
+                   return Err(RPCRequestError::MalformedRequest);
+               }
+               let (func_id, body) = split_u64_head(data);
+               if func_id == VERSION_FN_ID {
+                   return Ok(<$codec as $crate::utils::codec::WireCodec>::encode(&SERVICE_VERSION));
+               }
                match func_id as usize {
                    $(hash_ident!($fn_name) => {
-                       let ($($arg,)*) : ($($in_,)*) = $crate::utils::bincode::deserialize(&body);
+                       let decoded: Result<($($in_,)*), _> = <$codec as $crate::utils::codec::WireCodec>::decode(&body);
+                       let ($($arg,)*) = match decoded {
+                           Ok(args) => args,
+                           Err(_) => return Err(RPCRequestError::BadRequestData)
+                       };
                        let f_result = self.$fn_name($(&$arg,)*);
-                       Ok($crate::utils::bincode::serialize(&f_result))
+                       match f_result {
+                           // success body stays as the serialized result for older clients
+                           Err(e) => match <$codec as $crate::utils::codec::WireCodec>::try_encode(&e) {
+                               Ok(data) => Err(RPCRequestError::ServiceError(data)),
+                               Err(_) => Err(RPCRequestError::Other)
+                           },
+                           f_result => <$codec as $crate::utils::codec::WireCodec>::try_encode(&f_result)
+                               .map_err(|_| RPCRequestError::Other)
+                       }
                    }),*
                    _ => {
                        Err(RPCRequestError::FunctionIdNotFound)
@@ -144,15 +238,59 @@ macro_rules! service {
                }
            }
         }
+        type VersionCache = Arc<::parking_lot::Mutex<Option<u32>>>;
+
+        // asked once for each client of the service, servers before versioning are taken as version 0
+        fn fetch_server_version(
+            server_id: u64, service_id: u64, client: &Arc<RPCClient>, cache: &VersionCache
+        ) -> Box<Future<Item = u32, Error = RPCError>> {
+            if let Some(version) = *cache.lock() {
+                return Box::new(future::finished(version));
+            }
+            if get_local(server_id, service_id).is_some() {
+                return Box::new(future::finished(SERVICE_VERSION));
+            }
+            let cache = cache.clone();
+            Box::new(client.send_async(service_id, prepend_u64(VERSION_FN_ID, vec![]))
+                .then(move |res| -> Result<u32, RPCError> {
+                    let version = match res {
+                        Ok(res_bytes) => <$codec as $crate::utils::codec::WireCodec>::decode(&res_bytes)
+                            .map_err(RPCError::DecodeError)?,
+                        Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)) => 0,
+                        Err(e) => return Err(e)
+                    };
+                    *cache.lock() = Some(version);
+                    Ok(version)
+                }))
+        }
+
+        // a function missing on a server older than the client fails with VersionMismatch
+        fn function_not_found<T: 'static>(
+            server_id: u64, service_id: u64, client: &Arc<RPCClient>, cache: &VersionCache
+        ) -> Box<Future<Item = T, Error = RPCError>> {
+            Box::new(fetch_server_version(server_id, service_id, client, cache).then(|version| {
+                Err(RPCError::RequestError(match version {
+                    Ok(server) if server < SERVICE_VERSION => RPCRequestError::VersionMismatch {
+                        server: server,
+                        client: SERVICE_VERSION
+                    },
+                    _ => RPCRequestError::FunctionIdNotFound
+                }))
+            }))
+        }
+
         pub struct SyncServiceClient {
             pub service_id: u64,
             pub server_id: u64,
             pub client: Arc<RPCClient>,
+            server_version: VersionCache,
         }
         pub fn get_local(server_id: u64, service_id: u64) -> Option<Arc<Service>> {
             let svrs = RPC_SVRS.read();
             match svrs.get(&(server_id, service_id)) {
-                Some(s) => Some(s.clone()),
+                // middlewares can only see serialized requests in server dispatch
+                Some(&(_, ShortcutPolicy::Default)) if has_middlewares(server_id) => None,
+                Some(&(ref s, _)) => s.upgrade(),
                 _ => None
             }
         }
@@ -165,22 +303,27 @@ macro_rules! service {
                         Ok(local.$fn_name($($arg),*))
                     } else {
                         let req_data = ($($arg,)*);
-                        let req_data_bytes = $crate::utils::bincode::serialize(&req_data);
+                        let req_data_bytes = <$codec as $crate::utils::codec::WireCodec>::encode(&req_data);
                         let req_bytes = prepend_u64(hash_ident!($fn_name) as u64, req_data_bytes);
                         let res_bytes = self.client.send(self.service_id, req_bytes);
-                        if let Ok(res_bytes) = res_bytes {
-                            Ok($crate::utils::bincode::deserialize(&res_bytes))
-                        } else {
-                            Err(res_bytes.err().unwrap())
+                        match decode_service_res::<$codec, _, _>(res_bytes) {
+                            Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)) => {
+                                function_not_found(self.server_id, self.service_id, &self.client, &self.server_version).wait()
+                            },
+                            res => res
                         }
                     }
                 }
            )*
+           pub fn server_version(&self) -> Result<u32, RPCError> {
+                fetch_server_version(self.server_id, self.service_id, &self.client, &self.server_version).wait()
+           }
            pub fn new(service_id: u64, client: &Arc<RPCClient>) -> Arc<SyncServiceClient> {
                 Arc::new(SyncServiceClient{
                     service_id: service_id,
                     server_id:client.server_id,
-                    client: client.clone()
+                    client: client.clone(),
+                    server_version: Arc::new(::parking_lot::Mutex::new(None)),
                 })
            }
         }
@@ -188,6 +331,7 @@ macro_rules! service {
             pub service_id: u64,
             pub server_id: u64,
             pub client: Arc<RPCClient>,
+            server_version: VersionCache,
         }
         impl AsyncServiceClient {
            $(
@@ -198,24 +342,32 @@ macro_rules! service {
                         Box::new(future::finished(local.$fn_name($($arg),*)))
                     } else {
                         let req_data = ($($arg,)*);
-                        let req_data_bytes = $crate::utils::bincode::serialize(&req_data);
+                        let req_data_bytes = <$codec as $crate::utils::codec::WireCodec>::encode(&req_data);
                         let req_bytes = prepend_u64(hash_ident!($fn_name) as u64, req_data_bytes);
                         let res_bytes = self.client.send_async(self.service_id, req_bytes);
-                        Box::new(res_bytes.then(|res_bytes| -> Result<std::result::Result<$out, $error>, RPCError> {
-                            if let Ok(res_bytes) = res_bytes {
-                                Ok($crate::utils::bincode::deserialize(&res_bytes))
-                            } else {
-                                Err(res_bytes.err().unwrap())
+                        let (server_id, service_id) = (self.server_id, self.service_id);
+                        let client = self.client.clone();
+                        let server_version = self.server_version.clone();
+                        Box::new(res_bytes.then(move |res_bytes| -> Box<Future<Item = std::result::Result<$out, $error>, Error = RPCError>> {
+                            match decode_service_res::<$codec, _, _>(res_bytes) {
+                                Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)) => {
+                                    function_not_found(server_id, service_id, &client, &server_version)
+                                },
+                                res => Box::new(future::result(res))
                             }
                         }))
                     }
                 }
            )*
+           pub fn server_version(&self) -> Box<Future<Item = u32, Error = RPCError>> {
+                fetch_server_version(self.server_id, self.service_id, &self.client, &self.server_version)
+           }
            pub fn new(service_id: u64, client: &Arc<RPCClient>) -> Arc<AsyncServiceClient> {
                 Arc::new(AsyncServiceClient{
                     service_id: service_id,
                     server_id:client.server_id,
-                    client: client.clone()
+                    client: client.clone(),
+                    server_version: Arc::new(::parking_lot::Mutex::new(None)),
                 })
            }
         }
@@ -224,6 +376,7 @@ macro_rules! service {
 
 mod syntax_test {
     service! {
+        version 2;
         rpc test(a: u32, b: u32) -> bool;
         rpc test2(a: u32);
         rpc test3(a: u32, b: u32, c: u32, d: u32);
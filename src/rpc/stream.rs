@@ -0,0 +1,359 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, Condvar};
+use futures::{Future, Stream, Poll, Async};
+use byteorder::{ByteOrder, LittleEndian};
+use utils::u8vec::*;
+use rand;
+use super::{RPCClient, RPCError, RPCRequestError};
+
+// the client opens a stream and pulls its chunks one request at a time,
+// so large responses never have to fit in one frame
+pub static STREAM_SERVICE_ID: u64 = hash_ident!(BIFROST_RPC_STREAM) as u64;
+
+// [service id][request data] -> [stream id]
+pub static OPEN_FN_ID: u64 = 1;
+// [stream id] -> chunk
+pub static NEXT_FN_ID: u64 = 2;
+// [stream id] -> empty
+pub static CLOSE_FN_ID: u64 = 3;
+
+// chunks buffered for each stream, the handler blocks on sending when the client falls behind
+static STREAM_BUFFER_CHUNKS: usize = 16;
+// a pull waits this long for the handler, then returns an empty chunk for the client to pull again
+static STREAM_POLL_MS: u64 = 1000;
+// streams not pulled for this long are aborted
+static STREAM_IDLE_MS: u64 = 30 * 1000;
+
+// flag byte of a chunk, set when the client should pull for more
+const CONTINUATION: u8 = 1;
+
+pub trait StreamingService: Sync + Send {
+    // runs in its own thread, the stream ends when the sink is finished.
+    // dropping the sink unfinished fails the stream on the client
+    fn dispatch(&self, data: Vec<u8>, sink: ResponseSink);
+}
+
+struct StreamBuffer {
+    chunks: VecDeque<Vec<u8>>,
+    next_seq: u64,
+    finished: bool,
+    aborted: bool,
+    last_pull: Instant,
+}
+
+struct StreamState {
+    buffer: Mutex<StreamBuffer>,
+    changed: Condvar,
+}
+
+pub struct ResponseSink {
+    state: Arc<StreamState>,
+    finished: bool,
+}
+
+impl ResponseSink {
+    // fails with StreamClosed when the client closed the stream or stopped pulling
+    pub fn send(&self, chunk: Vec<u8>) -> Result<(), RPCRequestError> {
+        if chunk.is_empty() {
+            // empty chunks are kept for the pull timeout
+            return Ok(());
+        }
+        let mut buffer = self.state.buffer.lock();
+        loop {
+            if buffer.aborted {
+                return Err(RPCRequestError::StreamClosed);
+            }
+            if buffer.chunks.len() < STREAM_BUFFER_CHUNKS {
+                buffer.chunks.push_back(chunk);
+                self.state.changed.notify_all();
+                return Ok(());
+            }
+            if buffer.last_pull.elapsed() > Duration::from_millis(STREAM_IDLE_MS) {
+                buffer.aborted = true;
+                return Err(RPCRequestError::StreamClosed);
+            }
+            self.state.changed.wait_for(&mut buffer, Duration::from_millis(STREAM_POLL_MS));
+        }
+    }
+    pub fn send_chunked(&self, data: &[u8], chunk_size: usize) -> Result<(), RPCRequestError> {
+        for chunk in data.chunks(chunk_size) {
+            self.send(chunk.to_vec())?;
+        }
+        Ok(())
+    }
+    pub fn finish(mut self) {
+        self.finished = true;
+        self.state.buffer.lock().finished = true;
+        self.state.changed.notify_all();
+    }
+}
+
+impl Drop for ResponseSink {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.state.buffer.lock().aborted = true;
+            self.state.changed.notify_all();
+        }
+    }
+}
+
+// [flags][seq][data]
+fn encode_chunk(seq: u64, more: bool, data: Vec<u8>) -> Vec<u8> {
+    let flags = if more {CONTINUATION} else {0};
+    [flags; 1].iter().cloned().chain(prepend_u64(seq, data).into_iter()).collect()
+}
+
+// streams opened on the server, by stream id
+pub struct Streams {
+    streams: Mutex<HashMap<u64, Arc<StreamState>>>,
+}
+
+impl Streams {
+    pub fn new() -> Streams {
+        Streams {
+            streams: Mutex::new(HashMap::new())
+        }
+    }
+    pub fn open(&self) -> (u64, ResponseSink) {
+        let stream_id = rand::random::<u64>();
+        let state = Arc::new(StreamState {
+            buffer: Mutex::new(StreamBuffer {
+                chunks: VecDeque::new(),
+                next_seq: 0,
+                finished: false,
+                aborted: false,
+                last_pull: Instant::now(),
+            }),
+            changed: Condvar::new(),
+        });
+        let mut streams = self.streams.lock();
+        // abandoned by clients
+        streams.retain(|_, state| {
+            let mut buffer = state.buffer.lock();
+            if buffer.last_pull.elapsed() > Duration::from_millis(STREAM_IDLE_MS) {
+                buffer.aborted = true;
+                state.changed.notify_all();
+                false
+            } else {
+                true
+            }
+        });
+        streams.insert(stream_id, state.clone());
+        (stream_id, ResponseSink {
+            state: state,
+            finished: false,
+        })
+    }
+    pub fn next(&self, stream_id: u64) -> Result<Vec<u8>, RPCRequestError> {
+        let state = match self.streams.lock().get(&stream_id) {
+            Some(state) => state.clone(),
+            None => return Err(RPCRequestError::StreamClosed)
+        };
+        let res = {
+            let mut buffer = state.buffer.lock();
+            buffer.last_pull = Instant::now();
+            if buffer.chunks.is_empty() && !buffer.finished && !buffer.aborted {
+                state.changed.wait_for(&mut buffer, Duration::from_millis(STREAM_POLL_MS));
+            }
+            if let Some(chunk) = buffer.chunks.pop_front() {
+                let seq = buffer.next_seq;
+                buffer.next_seq += 1;
+                state.changed.notify_all();
+                return Ok(encode_chunk(seq, true, chunk));
+            }
+            if buffer.aborted {
+                Err(RPCRequestError::StreamClosed)
+            } else if buffer.finished {
+                Ok(encode_chunk(buffer.next_seq, false, vec![]))
+            } else {
+                // the handler is still working on the next chunk
+                return Ok(encode_chunk(buffer.next_seq, true, vec![]));
+            }
+        };
+        self.streams.lock().remove(&stream_id);
+        res
+    }
+    pub fn close(&self, stream_id: u64) {
+        if let Some(state) = self.streams.lock().remove(&stream_id) {
+            state.buffer.lock().aborted = true;
+            state.changed.notify_all();
+        }
+    }
+}
+
+enum Chunk {
+    Data(Vec<u8>),
+    // nothing yet, pull again
+    Pending,
+    End,
+}
+
+fn read_chunk(expected_seq: u64, res: Vec<u8>) -> Result<Chunk, RPCError> {
+    if res.len() < 9 {
+        return Err(RPCError::RequestError(RPCRequestError::MalformedResponse));
+    }
+    let more = res[0] & CONTINUATION != 0;
+    let seq = LittleEndian::read_u64(&res[1..9]);
+    if seq != expected_seq {
+        // a pull was lost or served twice, the chunk in between is gone
+        return Err(RPCError::StreamOutOfOrder { expected: expected_seq, received: seq });
+    }
+    let data: Vec<u8> = res.into_iter().skip(9).collect();
+    Ok(match (more, data.is_empty()) {
+        (true, true) => Chunk::Pending,
+        (true, false) => Chunk::Data(data),
+        (false, _) => Chunk::End
+    })
+}
+
+// chunks of the response in order, dropping the stream before its end closes it on the server
+pub struct ResponseStream {
+    client: Arc<RPCClient>,
+    stream_id: Option<u64>,
+    seq: u64,
+    pending: Option<Box<Future<Item = Vec<u8>, Error = RPCError>>>,
+}
+
+impl ResponseStream {
+    pub fn open(client: &Arc<RPCClient>, svr_id: u64, data: Vec<u8>) -> ResponseStream {
+        let req = prepend_u64(OPEN_FN_ID, prepend_u64(svr_id, data));
+        ResponseStream {
+            client: client.clone(),
+            stream_id: None,
+            seq: 0,
+            pending: Some(client.send_async(STREAM_SERVICE_ID, req)),
+        }
+    }
+    fn pull(&self, stream_id: u64) -> Box<Future<Item = Vec<u8>, Error = RPCError>> {
+        self.client.send_async(STREAM_SERVICE_ID, prepend_u64(NEXT_FN_ID, prepend_u64(stream_id, vec![])))
+    }
+}
+
+impl Stream for ResponseStream {
+    type Item = Vec<u8>;
+    type Error = RPCError;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, RPCError> {
+        loop {
+            let res = match self.pending {
+                Some(ref mut pending) => pending.poll(),
+                None => return Ok(Async::Ready(None))
+            };
+            let res = match res {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(res)) => res,
+                Err(e) => {
+                    self.pending = None;
+                    return Err(e);
+                }
+            };
+            let stream_id = match self.stream_id {
+                Some(stream_id) => stream_id,
+                None => {
+                    if res.len() < 8 {
+                        self.pending = None;
+                        return Err(RPCError::RequestError(RPCRequestError::MalformedResponse));
+                    }
+                    let stream_id = extract_u64_head(res).0;
+                    self.stream_id = Some(stream_id);
+                    self.pending = Some(self.pull(stream_id));
+                    continue;
+                }
+            };
+            match read_chunk(self.seq, res) {
+                Ok(Chunk::Data(data)) => {
+                    self.seq += 1;
+                    self.pending = Some(self.pull(stream_id));
+                    return Ok(Async::Ready(Some(data)));
+                },
+                Ok(Chunk::Pending) => {
+                    self.pending = Some(self.pull(stream_id));
+                },
+                Ok(Chunk::End) => {
+                    self.pending = None;
+                    self.stream_id = None;
+                    return Ok(Async::Ready(None));
+                },
+                Err(e) => {
+                    self.pending = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        if let Some(stream_id) = self.stream_id {
+            // not ended yet or failed, nobody waits for the result
+            self.client.send_async(STREAM_SERVICE_ID, prepend_u64(CLOSE_FN_ID, prepend_u64(stream_id, vec![])));
+        }
+    }
+}
+
+// reassemble the chunks into one buffer
+pub fn collect<S>(stream: S) -> Box<Future<Item = Vec<u8>, Error = RPCError>>
+    where S: Stream<Item = Vec<u8>, Error = RPCError> + 'static {
+    Box::new(stream.fold(Vec::new(), |mut data, chunk| {
+        data.extend(chunk);
+        Ok::<Vec<u8>, RPCError>(data)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks_in_order() {
+        let streams = Streams::new();
+        let (stream_id, sink) = streams.open();
+        sink.send(vec![1u8, 2]).unwrap();
+        sink.send(vec![3u8]).unwrap();
+        sink.finish();
+        match read_chunk(0, streams.next(stream_id).unwrap()) {
+            Ok(Chunk::Data(data)) => assert_eq!(data, vec![1u8, 2]),
+            _ => panic!()
+        }
+        // the second chunk delivered without the client expecting it
+        match read_chunk(0, streams.next(stream_id).unwrap()) {
+            Err(RPCError::StreamOutOfOrder { expected: 0, received: 1 }) => {},
+            _ => panic!()
+        }
+        match read_chunk(2, streams.next(stream_id).unwrap()) {
+            Ok(Chunk::End) => {},
+            _ => panic!()
+        }
+        match streams.next(stream_id) {
+            Err(RPCRequestError::StreamClosed) => {},
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn aborted_by_handler() {
+        let streams = Streams::new();
+        let (stream_id, sink) = streams.open();
+        sink.send(vec![1u8]).unwrap();
+        drop(sink);
+        assert!(streams.next(stream_id).is_ok());
+        match streams.next(stream_id) {
+            Err(RPCRequestError::StreamClosed) => {},
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn closed_by_client() {
+        let streams = Streams::new();
+        let (stream_id, sink) = streams.open();
+        streams.close(stream_id);
+        match sink.send(vec![1u8]) {
+            Err(RPCRequestError::StreamClosed) => {},
+            _ => panic!()
+        }
+    }
+}
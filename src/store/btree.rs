@@ -0,0 +1,206 @@
+// a replicated map in the order of its keys, like def_store_btree_map!(services <String, String>).
+// ranges start at the from key and end before the to key. maps with string keys can also be
+// looked up by prefix, with def_store_btree_map!(services <String, String>, string_keys).
+// pages are continued after the last key returned, writes between them do not shift the pages
+#[macro_export]
+macro_rules! def_store_btree_map {
+    ($m: ident <$kt: ty, $vt: ty>, string_keys) => {
+        def_store_btree_map!(@store $m <$kt, $vt>, {
+            // entries with keys starting with p, in order
+            def qry prefix(p: String, limit: u64) -> Vec<($kt, $vt)>;
+            // the next page of prefix, after the last key of the one before
+            def qry prefix_after(p: String, after: String, limit: u64) -> Vec<($kt, $vt)>;
+        }, {
+            fn prefix(&self, p: String, limit: u64) -> Result<Vec<($kt, $vt)>, ()> {
+                Ok(self.collect(self.map.range((Included(p.clone()), Unbounded)), limit, |k| k.starts_with(&p)))
+            }
+            fn prefix_after(&self, p: String, after: String, limit: u64) -> Result<Vec<($kt, $vt)>, ()> {
+                Ok(self.collect(self.map.range((Excluded(after), Unbounded)), limit, |k| k.starts_with(&p)))
+            }
+        });
+    };
+    ($m: ident <$kt: ty, $vt: ty>) => {
+        def_store_btree_map!(@store $m <$kt, $vt>, {}, {});
+    };
+    (@store $m: ident <$kt: ty, $vt: ty>, { $( $defs: tt )* }, { $( $fns: tt )* }) => {
+        pub mod $m {
+            use $crate::raft::state_machine::StateMachineCtl;
+            use $crate::raft::state_machine::callback::server::SMCallback;
+            use $crate::raft::RaftService;
+            use $crate::store::hash_str;
+            use std::collections::BTreeMap;
+            use std::collections::Bound::{Included, Excluded, Unbounded};
+            use std::collections::btree_map::Range;
+            use std::sync::{Arc};
+            use super::*;
+            pub struct OrderedMap {
+                map: BTreeMap<$kt, $vt>,
+                callback: Option<SMCallback>,
+                pub id: u64
+            }
+            raft_state_machine! {
+                def qry get(k: $kt) -> Option<$vt>;
+                def cmd insert(k: $kt, v: $vt) -> Option<$vt>;
+                def cmd insert_if_absent(k: $kt, v: $vt) -> $vt;
+                def cmd remove(k: $kt) -> Option<$vt>;
+                // the value is only replaced while it is still the old one, answered whether it was
+                def cmd update(k: $kt, old: $vt, new: $vt) -> bool;
+
+                def qry is_empty() -> bool;
+                def qry len() -> u64;
+                def cmd clear();
+
+                def qry keys() -> Vec<$kt>;
+                def qry entries() -> Vec<($kt, $vt)>;
+                def qry first() -> Option<($kt, $vt)>;
+                def qry last() -> Option<($kt, $vt)>;
+                // from the from key up to, but not including, the to key. none for either is unbounded
+                def qry range(from: Option<$kt>, to: Option<$kt>, limit: u64) -> Vec<($kt, $vt)>;
+                // the next page of range, after the last key of the one before
+                def qry range_after(after: $kt, to: Option<$kt>, limit: u64) -> Vec<($kt, $vt)>;
+                $( $defs )*
+
+                def qry contains_key(k: $kt) -> bool;
+
+                // keyed, SubFilter::prefix subscriptions get the events of a part of the key space
+                def sub on_inserted() -> ($kt, $vt);
+                def sub on_key_inserted(k: $kt) -> $vt;
+                def sub on_removed() -> ($kt, $vt);
+                def sub on_key_removed(k: $kt) -> $vt;
+                // key, old value and new value
+                def sub on_updated() -> ($kt, $vt, $vt);
+                def sub on_key_updated(k: $kt) -> ($vt, $vt);
+            }
+            raft_sub_keys! {
+                on_inserted: $kt;
+                on_removed: $kt;
+                on_updated: $kt;
+            }
+            impl StateMachineCmds for OrderedMap {
+                fn get(&self, k: $kt) -> Result<Option<$vt>, ()> {
+                    Ok(self.map.get(&k).cloned())
+                }
+                fn insert(&mut self, k: $kt, v: $vt) -> Result<Option<$vt>, ()> {
+                    Ok(self.put(k, v))
+                }
+                fn insert_if_absent(&mut self, k: $kt, v: $vt) -> Result<$vt, ()> {
+                    if let Some(v) = self.map.get(&k) {
+                        return Ok(v.clone())
+                    }
+                    self.put(k, v.clone()); Ok(v)
+                }
+                fn remove(&mut self, k: $kt) -> Result<Option<$vt>, ()> {
+                    let res = self.map.remove(&k);
+                    if let Some(ref v) = res {
+                        if let Some(ref callback) = self.callback {
+                            callback.notify_keyed(&commands::on_removed::new(), &k, Ok((k.clone(), v.clone())));
+                            callback.notify(&commands::on_key_removed::new(&k), Ok(v.clone()));
+                        }
+                    }
+                    Ok(res)
+                }
+                fn update(&mut self, k: $kt, old: $vt, new: $vt) -> Result<bool, ()> {
+                    match self.map.get_mut(&k) {
+                        Some(v) => if *v != old {
+                            return Ok(false);
+                        } else {
+                            *v = new.clone();
+                        },
+                        None => return Ok(false)
+                    }
+                    if let Some(ref callback) = self.callback {
+                        callback.notify_keyed(&commands::on_updated::new(), &k, Ok((k.clone(), old.clone(), new.clone())));
+                        callback.notify(&commands::on_key_updated::new(&k), Ok((old, new)));
+                    }
+                    Ok(true)
+                }
+                fn is_empty(&self) -> Result<bool, ()> {
+                    Ok(self.map.is_empty())
+                }
+                fn len(&self) -> Result<u64, ()> {
+                    Ok(self.map.len() as u64)
+                }
+                fn clear(&mut self) -> Result<(), ()> {
+                    Ok(self.map.clear())
+                }
+                fn keys(&self) -> Result<Vec<$kt>, ()> {
+                    Ok(self.map.keys().cloned().collect())
+                }
+                fn entries(&self) -> Result<Vec<($kt, $vt)>, ()> {
+                    Ok(self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                }
+                fn first(&self) -> Result<Option<($kt, $vt)>, ()> {
+                    Ok(self.map.iter().next().map(|(k, v)| (k.clone(), v.clone())))
+                }
+                fn last(&self) -> Result<Option<($kt, $vt)>, ()> {
+                    Ok(self.map.iter().next_back().map(|(k, v)| (k.clone(), v.clone())))
+                }
+                fn range(&self, from: Option<$kt>, to: Option<$kt>, limit: u64) -> Result<Vec<($kt, $vt)>, ()> {
+                    let from = match from {
+                        Some(from) => Included(from),
+                        None => Unbounded
+                    };
+                    Ok(self.collect(self.map.range((from, Unbounded)), limit, |k| Self::before(k, &to)))
+                }
+                fn range_after(&self, after: $kt, to: Option<$kt>, limit: u64) -> Result<Vec<($kt, $vt)>, ()> {
+                    Ok(self.collect(self.map.range((Excluded(after), Unbounded)), limit, |k| Self::before(k, &to)))
+                }
+                $( $fns )*
+                fn contains_key(&self, k: $kt) -> Result<bool, ()> {
+                    Ok(self.map.contains_key(&k))
+                }
+            }
+            impl StateMachineCtl for OrderedMap {
+                raft_sm_complete!();
+                fn snapshot(&self) -> Option<Vec<u8>> {
+                    Some($crate::utils::bincode::serialize(&self.map))
+                }
+                fn recover(&mut self, data: Vec<u8>) {
+                    self.map = $crate::utils::bincode::deserialize(&data);
+                }
+                fn id(&self) -> u64 {self.id}
+            }
+            impl OrderedMap {
+                pub fn new(id: u64) -> OrderedMap {
+                    OrderedMap {
+                        map: BTreeMap::new(),
+                        callback: None,
+                        id: id,
+                    }
+                }
+                pub fn new_by_name(name: &String) -> OrderedMap {
+                    OrderedMap::new(hash_str(name))
+                }
+                pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
+                    self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
+                }
+                fn put(&mut self, k: $kt, v: $vt) -> Option<$vt> {
+                    if let Some(ref callback) = self.callback {
+                        callback.notify_keyed(&commands::on_inserted::new(), &k, Ok((k.clone(), v.clone())));
+                        callback.notify(&commands::on_key_inserted::new(&k), Ok(v.clone()));
+                    }
+                    self.map.insert(k, v)
+                }
+                fn before(k: &$kt, to: &Option<$kt>) -> bool {
+                    match *to {
+                        Some(ref to) => k < to,
+                        None => true
+                    }
+                }
+                // entries of the range in order while their keys are in it, at most limit of them.
+                // bounds are only checked here, ranges of the map panic when they end before they start
+                fn collect<'a, F>(&self, range: Range<'a, $kt, $vt>, limit: u64, in_range: F) -> Vec<($kt, $vt)>
+                    where F: Fn(&$kt) -> bool
+                {
+                    range.take_while(|&(k, _)| in_range(k))
+                        .take(limit as usize)
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect()
+                }
+            }
+        }
+    };
+}
+
+def_store_btree_map!(string_string_btreemap <String, String>, string_keys);
+def_store_btree_map!(u64_u8vec_btreemap <u64, Vec<u8>>);
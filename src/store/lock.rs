@@ -0,0 +1,215 @@
+use raft::{RaftService, RaftMsg};
+use raft::state_machine::{StateMachineCtl, CommandCtx};
+use raft::state_machine::callback::server::SMCallback;
+use raft::state_machine::master::ExecError;
+use store::ttl::{Ticker, ChangeReason};
+use utils::time::get_time;
+use utils::bincode;
+use bifrost_hasher::hash_str;
+use parking_lot::{Mutex, Condvar};
+use std::cmp::min;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// waiters try again this long after the lease they saw would end, in case no event woke them
+static WAIT_SLACK_MS: u64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Holder {
+    pub owner: u64,
+    // log index of the acquisition, later holders always have larger ones
+    pub token: u64,
+    pub lease_ms: u64,
+    // by the clock of the leader
+    pub expires_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LockError {
+    LockHeld { owner: u64, remaining_ms: u64 },
+    // released, expired or acquired by another one since the token was given
+    NotHolder,
+}
+
+// held by one owner for a lease. leases expire by the time the leader appended the commands and
+// by ticks it proposes, see init_expiry. fencing tokens let other systems refuse stale holders
+pub struct Lock {
+    pub id: u64,
+    holder: Option<Holder>,
+    callback: Option<SMCallback>,
+    ticker: Option<Ticker>,
+}
+
+raft_state_machine! {
+    // answered the fencing token. the owner holding it already extends the lease and keeps the token
+    def cmd acquire(ctx, owner_id: u64, lease_ms: u64) -> u64 | LockError;
+    // extends the lease by as long as it was acquired for
+    def cmd renew(ctx, owner_id: u64, token: u64) -> () | LockError;
+    def cmd release(ctx, owner_id: u64, token: u64) -> () | LockError;
+    def cmd expire_tick(ctx);
+    // none when the lease expired, even before a tick released it
+    def qry holder() -> Option<Holder>;
+    // owner and token of the holder, acquirers waiting can try again
+    def sub on_released() -> (u64, u64, ChangeReason);
+}
+
+impl StateMachineCmds for Lock {
+    fn acquire(&mut self, ctx: CommandCtx, owner_id: u64, lease_ms: u64) -> Result<u64, LockError> {
+        let now = ctx.leader_time_ms;
+        self.expire(now);
+        if let Some(ref mut holder) = self.holder {
+            if holder.owner != owner_id {
+                return Err(LockError::LockHeld {
+                    owner: holder.owner,
+                    remaining_ms: holder.expires_ms.saturating_sub(now)
+                });
+            }
+            holder.lease_ms = lease_ms;
+            holder.expires_ms = now + lease_ms;
+            return Ok(holder.token);
+        }
+        self.holder = Some(Holder {
+            owner: owner_id,
+            token: ctx.index,
+            lease_ms: lease_ms,
+            expires_ms: now + lease_ms,
+        });
+        self.schedule();
+        Ok(ctx.index)
+    }
+    fn renew(&mut self, ctx: CommandCtx, owner_id: u64, token: u64) -> Result<(), LockError> {
+        let now = ctx.leader_time_ms;
+        self.expire(now);
+        if let Some(ref mut holder) = self.holder {
+            if holder.owner == owner_id && holder.token == token {
+                holder.expires_ms = now + holder.lease_ms;
+                return Ok(());
+            }
+        }
+        Err(LockError::NotHolder)
+    }
+    fn release(&mut self, ctx: CommandCtx, owner_id: u64, token: u64) -> Result<(), LockError> {
+        self.expire(ctx.leader_time_ms);
+        match self.holder {
+            Some(holder) if holder.owner == owner_id && holder.token == token => {},
+            _ => return Err(LockError::NotHolder)
+        }
+        self.free(ChangeReason::Command);
+        Ok(())
+    }
+    fn expire_tick(&mut self, ctx: CommandCtx) -> Result<(), ()> {
+        self.expire(ctx.leader_time_ms);
+        Ok(())
+    }
+    fn holder(&self) -> Result<Option<Holder>, ()> {
+        // queries have no time of the leader, they go by the clock of the replica
+        match self.holder {
+            Some(holder) if holder.expires_ms as i64 > get_time() => Ok(Some(holder)),
+            _ => Ok(None)
+        }
+    }
+}
+
+impl StateMachineCtl for Lock {
+    raft_sm_complete!();
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(bincode::serialize(&self.holder))
+    }
+    fn recover(&mut self, data: Vec<u8>) {
+        self.holder = bincode::deserialize(&data);
+        self.schedule();
+    }
+    fn id(&self) -> u64 {self.id}
+}
+
+impl Lock {
+    pub fn new(id: u64) -> Lock {
+        Lock {
+            id: id,
+            holder: None,
+            callback: None,
+            ticker: None,
+        }
+    }
+    pub fn new_by_name(name: &String) -> Lock {
+        Lock::new(hash_str(name))
+    }
+    pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
+        self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
+    }
+    // leases of holders that never release are only ended by ticks, proposed by the leader every
+    // tick_ms. every replica should start it
+    pub fn init_expiry(&mut self, raft_service: &Arc<RaftService>, tick_ms: u64) {
+        let tick = commands::expire_tick::new();
+        let (fn_id, _, data) = tick.encode();
+        self.ticker = Some(Ticker::start(raft_service, self.id, fn_id, data.clone(), tick_ms));
+        self.schedule();
+    }
+    fn expire(&mut self, now: u64) {
+        match self.holder {
+            Some(holder) if holder.expires_ms <= now => {},
+            _ => return
+        }
+        self.free(ChangeReason::Expired);
+    }
+    fn free(&mut self, reason: ChangeReason) {
+        if let Some(holder) = self.holder.take() {
+            if let Some(ref callback) = self.callback {
+                callback.notify(&commands::on_released::new(), Ok((holder.owner, holder.token, reason)));
+            }
+        }
+        self.schedule();
+    }
+    fn schedule(&self) {
+        if let Some(ref ticker) = self.ticker {
+            ticker.set_pending(self.holder.is_some());
+        }
+    }
+}
+
+impl client::SMClient {
+    // acquires once the holder releases or its lease expires, until the timeout. clients with a
+    // subscription service are woken by on_released, see RaftClient::prepare_subscription, others
+    // try again when the lease they saw would end
+    pub fn acquire_wait(&self, owner_id: &u64, lease_ms: &u64, timeout: Duration) -> Result<Result<u64, LockError>, ExecError> {
+        let deadline = Instant::now() + timeout;
+        let released = Arc::new((Mutex::new(false), Condvar::new()));
+        let released_ref = released.clone();
+        let subscription = self.on_released(move |_| {
+            let &(ref flag, ref cvar) = &*released_ref;
+            *flag.lock() = true;
+            cvar.notify_all();
+        });
+        let res = self.acquire_until(owner_id, lease_ms, deadline, &released);
+        if let Ok(Ok(handle)) = subscription {
+            if let Err(e) = handle.cancel() {
+                debug!("Cannot cancel the subscription of lock {}, {:?}", handle.sm_id(), e);
+            }
+        }
+        res
+    }
+    fn acquire_until(
+        &self, owner_id: &u64, lease_ms: &u64, deadline: Instant, released: &Arc<(Mutex<bool>, Condvar)>
+    ) -> Result<Result<u64, LockError>, ExecError> {
+        loop {
+            let remaining_ms = match self.acquire(owner_id, lease_ms)? {
+                Err(LockError::LockHeld { owner, remaining_ms }) => {
+                    if Instant::now() >= deadline {
+                        return Ok(Err(LockError::LockHeld { owner: owner, remaining_ms: remaining_ms }));
+                    }
+                    remaining_ms
+                },
+                res => return Ok(res)
+            };
+            let retry = min(Instant::now() + Duration::from_millis(remaining_ms + WAIT_SLACK_MS), deadline);
+            let &(ref flag, ref cvar) = &**released;
+            let mut flag = flag.lock();
+            while !*flag {
+                if cvar.wait_until(&mut flag, retry).timed_out() {
+                    break;
+                }
+            }
+            *flag = false;
+        }
+    }
+}
@@ -1,24 +1,70 @@
+// operations of a transaction, see transaction in def_store_hash_map
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TxnOp<K, V> {
+    // passes while the key has the value, or is absent for none
+    Compare(K, Option<V>),
+    Put(K, V),
+    Delete(K),
+}
+
+// for each operation, the values put and deleted ones replaced
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TxnResult<V> {
+    Compared,
+    Put(Option<V>),
+    Deleted(Option<V>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxnAborted {
+    // positions of the compares that did not pass
+    pub failed: Vec<u64>,
+}
+
 #[macro_export]
 macro_rules! def_store_hash_map {
     ($m: ident <$kt: ty, $vt: ty>) => {
         pub mod $m {
-            use $crate::raft::state_machine::StateMachineCtl;
+            use $crate::raft::state_machine::{StateMachineCtl, CommandCtx};
             use $crate::raft::state_machine::callback::server::SMCallback;
-            use $crate::raft::RaftService;
-            use bifrost_hasher::hash_str;
-            use std::collections::HashMap;
+            use $crate::raft::{RaftService, RaftMsg};
+            use $crate::raft::state_machine::master::ExecError;
+            use $crate::store::map::{TxnOp, TxnResult, TxnAborted};
+            use $crate::store::hash_str;
+            use $crate::store::ttl::{Ticker, ChangeReason};
+            use $crate::utils::time::get_time;
+            use std::collections::{HashMap, BTreeSet};
             use std::sync::{Arc};
             use super::*;
             pub struct Map {
                 map: HashMap<$kt, $vt>,
+                // when entries inserted with a time to live expire, by the clock of the leader
+                expiries: HashMap<$kt, u64>,
+                deadlines: BTreeSet<(u64, $kt)>,
                 callback: Option<SMCallback>,
+                ticker: Option<Ticker>,
                 pub id: u64
             }
             raft_state_machine! {
+                // expired entries are gone, even before a tick removed them
                 def qry get(k: $kt) -> Option<$vt>;
-                def cmd insert(k: $kt, v: $vt) -> Option<$vt>;
-                def cmd insert_if_absent(k: $kt, v: $vt) -> $vt;
-                def cmd remove(k: $kt) -> Option<$vt>;
+                def cmd insert(ctx, k: $kt, v: $vt) -> Option<$vt>;
+                // removed by the first tick after the time to live, see init_expiry
+                def cmd insert_with_ttl(ctx, k: $kt, v: $vt, ttl_ms: u64) -> Option<$vt>;
+                def cmd insert_if_absent(ctx, k: $kt, v: $vt) -> $vt;
+                def cmd remove(ctx, k: $kt) -> Option<$vt>;
+                // the value is only replaced while it is still the old one, answered whether it was
+                def cmd update(ctx, k: $kt, old: $vt, new: $vt) -> bool;
+                // removes the entries expired by the time the leader appended it
+                def cmd expire_tick(ctx);
+                // the compares are all evaluated first, other operations are applied in order only
+                // if every one passed. see SMClient::txn
+                def cmd transaction(ctx, ops: Vec<TxnOp<$kt, $vt>>) -> Vec<TxnResult<$vt>> | TxnAborted;
+                // many keys by one query or in one log entry, answered in their order like get,
+                // insert and remove. see SMClient::get_many, insert_many and remove_many
+                def qry get_batch(keys: Vec<$kt>) -> Vec<Option<$vt>>;
+                def cmd insert_batch(ctx, entries: Vec<($kt, $vt)>) -> Vec<Option<$vt>>;
+                def cmd remove_batch(ctx, keys: Vec<$kt>) -> Vec<Option<$vt>>;
 
                 def qry is_empty() -> bool;
                 def qry len() -> u64;
@@ -27,45 +73,110 @@ macro_rules! def_store_hash_map {
                 def qry keys() -> Vec<$kt>;
                 def qry values() -> Vec<$vt>;
                 def qry entries() -> Vec<($kt, $vt)>;
+                // in the order of the keys, so pages of one version of the map do not overlap
+                def qry entries_paged(offset: u64, limit: u64) -> Vec<($kt, $vt)>;
                 def qry clone() -> HashMap<$kt, $vt>;
 
                 def qry contains_key(k: $kt) -> bool;
 
                 def sub on_inserted() -> ($kt, $vt);
                 def sub on_key_inserted(k: $kt) -> $vt;
-                def sub on_removed() -> ($kt, $vt);
-                def sub on_key_removed(k: $kt) -> $vt;
+                def sub on_removed() -> ($kt, $vt, ChangeReason);
+                def sub on_key_removed(k: $kt) -> ($vt, ChangeReason);
+                // key, old value and new value
+                def sub on_updated() -> ($kt, $vt, $vt);
+                def sub on_key_updated(k: $kt) -> ($vt, $vt);
+            }
+            raft_sub_keys! {
+                on_inserted: $kt;
+                on_removed: $kt;
+                on_updated: $kt;
             }
             impl StateMachineCmds for Map {
                 fn get(&self, k: $kt) -> Result<Option<$vt>, ()> {
-                    Ok(
-                        if let Some(v) = self.map.get(&k) {
-                            Some(v.clone())
-                        } else {None}
-                    )
+                    Ok(self.live_value(&k))
                 }
-                fn insert(&mut self, k: $kt, v: $vt) -> Result<Option<$vt>, ()> {
-                    if let Some(ref callback) = self.callback {
-                        callback.notify(&commands::on_inserted::new(), Ok((k.clone(), v.clone())));
-                        callback.notify(&commands::on_key_inserted::new(&k), Ok(v.clone()));
-                    }
-                    Ok(self.map.insert(k, v))
+                fn insert(&mut self, ctx: CommandCtx, k: $kt, v: $vt) -> Result<Option<$vt>, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    self.set_expiry(&k, None);
+                    Ok(self.put(k, v))
                 }
-                fn insert_if_absent(&mut self, k: $kt, v: $vt) -> Result<$vt, ()> {
+                fn insert_with_ttl(&mut self, ctx: CommandCtx, k: $kt, v: $vt, ttl_ms: u64) -> Result<Option<$vt>, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    self.set_expiry(&k, Some(ctx.leader_time_ms + ttl_ms));
+                    Ok(self.put(k, v))
+                }
+                fn insert_if_absent(&mut self, ctx: CommandCtx, k: $kt, v: $vt) -> Result<$vt, ()> {
+                    self.expire(ctx.leader_time_ms);
                     if let Some(v) = self.map.get(&k) {
                         return Ok(v.clone())
                     }
-                    self.insert(k, v.clone()); Ok(v)
+                    self.put(k, v.clone()); Ok(v)
+                }
+                fn remove(&mut self, ctx: CommandCtx, k: $kt) -> Result<Option<$vt>, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    Ok(self.delete(&k))
                 }
-                fn remove(&mut self, k: $kt) -> Result<Option<$vt>, ()> {
-                    let res = self.map.remove(&k);
+                fn expire_tick(&mut self, ctx: CommandCtx) -> Result<(), ()> {
+                    self.expire(ctx.leader_time_ms);
+                    Ok(())
+                }
+                fn transaction(&mut self, ctx: CommandCtx, ops: Vec<TxnOp<$kt, $vt>>) -> Result<Vec<TxnResult<$vt>>, TxnAborted> {
+                    self.expire(ctx.leader_time_ms);
+                    let failed: Vec<u64> = ops.iter().enumerate()
+                        .filter(|&(_, op)| match *op {
+                            TxnOp::Compare(ref k, ref expected) => self.map.get(k) != expected.as_ref(),
+                            _ => false
+                        })
+                        .map(|(i, _)| i as u64)
+                        .collect();
+                    if !failed.is_empty() {
+                        return Err(TxnAborted { failed: failed });
+                    }
+                    let mut results = Vec::with_capacity(ops.len());
+                    for op in ops {
+                        results.push(match op {
+                            TxnOp::Compare(..) => TxnResult::Compared,
+                            TxnOp::Put(k, v) => {
+                                self.set_expiry(&k, None);
+                                TxnResult::Put(self.put(k, v))
+                            },
+                            TxnOp::Delete(k) => TxnResult::Deleted(self.delete(&k))
+                        });
+                    }
+                    Ok(results)
+                }
+                fn get_batch(&self, keys: Vec<$kt>) -> Result<Vec<Option<$vt>>, ()> {
+                    Ok(keys.iter().map(|k| self.live_value(k)).collect())
+                }
+                fn insert_batch(&mut self, ctx: CommandCtx, entries: Vec<($kt, $vt)>) -> Result<Vec<Option<$vt>>, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    let mut results = Vec::with_capacity(entries.len());
+                    for (k, v) in entries {
+                        self.set_expiry(&k, None);
+                        results.push(self.put(k, v));
+                    }
+                    Ok(results)
+                }
+                fn remove_batch(&mut self, ctx: CommandCtx, keys: Vec<$kt>) -> Result<Vec<Option<$vt>>, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    Ok(keys.iter().map(|k| self.delete(k)).collect())
+                }
+                fn update(&mut self, ctx: CommandCtx, k: $kt, old: $vt, new: $vt) -> Result<bool, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    match self.map.get_mut(&k) {
+                        Some(v) => if *v != old {
+                            return Ok(false);
+                        } else {
+                            *v = new.clone();
+                        },
+                        None => return Ok(false)
+                    }
                     if let Some(ref callback) = self.callback {
-                        if let Some(ref v) = res {
-                            callback.notify(&commands::on_removed::new(), Ok((k.clone(), v.clone())));
-                            callback.notify(&commands::on_key_removed::new(&k), Ok(v.clone()));
-                        }
+                        callback.notify_keyed(&commands::on_updated::new(), &k, Ok((k.clone(), old.clone(), new.clone())));
+                        callback.notify(&commands::on_key_updated::new(&k), Ok((old, new)));
                     }
-                    Ok(res)
+                    Ok(true)
                 }
                 fn is_empty(&self) -> Result<bool, ()> {
                     Ok(self.map.is_empty())
@@ -74,6 +185,9 @@ macro_rules! def_store_hash_map {
                     Ok(self.map.len() as u64)
                 }
                 fn clear(&mut self) -> Result<(), ()> {
+                    self.expiries.clear();
+                    self.deadlines.clear();
+                    self.schedule();
                     Ok(self.map.clear())
                 }
                 fn keys(&self) -> Result<Vec<$kt>, ()> {
@@ -89,20 +203,33 @@ macro_rules! def_store_hash_map {
                     }
                     Ok(r)
                 }
+                fn entries_paged(&self, offset: u64, limit: u64) -> Result<Vec<($kt, $vt)>, ()> {
+                    let mut keys: Vec<&$kt> = self.map.keys().collect();
+                    keys.sort();
+                    Ok(keys.into_iter()
+                        .skip(offset as usize)
+                        .take(limit as usize)
+                        .map(|k| (k.clone(), self.map[k].clone()))
+                        .collect())
+                }
                 fn clone(&self) -> Result<HashMap<$kt, $vt>, ()> {
                     Ok(self.map.clone())
                 }
                 fn contains_key(&self, k: $kt) -> Result<bool, ()> {
-                    Ok(self.map.contains_key(&k))
+                    Ok(self.map.contains_key(&k) && self.live(&k))
                 }
             }
             impl StateMachineCtl for Map {
                 raft_sm_complete!();
                 fn snapshot(&self) -> Option<Vec<u8>> {
-                    Some($crate::utils::bincode::serialize(&self.map))
+                    Some($crate::utils::bincode::serialize(&(&self.map, &self.deadlines)))
                 }
                 fn recover(&mut self, data: Vec<u8>) {
-                    self.map = $crate::utils::bincode::deserialize(&data);
+                    let (map, deadlines): (HashMap<$kt, $vt>, BTreeSet<(u64, $kt)>) = $crate::utils::bincode::deserialize(&data);
+                    self.map = map;
+                    self.expiries = deadlines.iter().map(|&(deadline, ref k)| (k.clone(), deadline)).collect();
+                    self.deadlines = deadlines;
+                    self.schedule();
                 }
                 fn id(&self) -> u64 {self.id}
             }
@@ -110,7 +237,10 @@ macro_rules! def_store_hash_map {
                 pub fn new(id: u64) -> Map {
                     Map {
                         map: HashMap::new(),
+                        expiries: HashMap::new(),
+                        deadlines: BTreeSet::new(),
                         callback: None,
+                        ticker: None,
                         id: id,
                     }
                 }
@@ -120,6 +250,120 @@ macro_rules! def_store_hash_map {
                 pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
                     self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
                 }
+                // entries inserted with a time to live are only removed by ticks, proposed by the
+                // leader every tick_ms. every replica should start it
+                pub fn init_expiry(&mut self, raft_service: &Arc<RaftService>, tick_ms: u64) {
+                    let tick = commands::expire_tick::new();
+                    let (fn_id, _, data) = tick.encode();
+                    self.ticker = Some(Ticker::start(raft_service, self.id, fn_id, data.clone(), tick_ms));
+                    self.schedule();
+                }
+                fn put(&mut self, k: $kt, v: $vt) -> Option<$vt> {
+                    if let Some(ref callback) = self.callback {
+                        callback.notify_keyed(&commands::on_inserted::new(), &k, Ok((k.clone(), v.clone())));
+                        callback.notify(&commands::on_key_inserted::new(&k), Ok(v.clone()));
+                    }
+                    self.map.insert(k, v)
+                }
+                fn delete(&mut self, k: &$kt) -> Option<$vt> {
+                    self.set_expiry(k, None);
+                    let res = self.map.remove(k);
+                    if let Some(ref v) = res {
+                        self.notify_removed(k, v, ChangeReason::Command);
+                    }
+                    res
+                }
+                fn notify_removed(&self, k: &$kt, v: &$vt, reason: ChangeReason) {
+                    if let Some(ref callback) = self.callback {
+                        callback.notify_keyed(&commands::on_removed::new(), k, Ok((k.clone(), v.clone(), reason)));
+                        callback.notify(&commands::on_key_removed::new(k), Ok((v.clone(), reason)));
+                    }
+                }
+                fn live_value(&self, k: &$kt) -> Option<$vt> {
+                    match self.map.get(k) {
+                        Some(v) if self.live(k) => Some(v.clone()),
+                        _ => None
+                    }
+                }
+                // queries have no time of the leader, they go by the clock of the replica
+                fn live(&self, k: &$kt) -> bool {
+                    match self.expiries.get(k) {
+                        Some(&deadline) => deadline as i64 > get_time(),
+                        None => true
+                    }
+                }
+                fn set_expiry(&mut self, k: &$kt, deadline: Option<u64>) {
+                    if let Some(old) = self.expiries.remove(k) {
+                        self.deadlines.remove(&(old, k.clone()));
+                    }
+                    if let Some(deadline) = deadline {
+                        self.expiries.insert(k.clone(), deadline);
+                        self.deadlines.insert((deadline, k.clone()));
+                    }
+                    self.schedule();
+                }
+                // entries expired by now are removed, earliest first
+                fn expire(&mut self, now: u64) {
+                    loop {
+                        let due = match self.deadlines.iter().next() {
+                            Some(&(deadline, ref k)) if deadline <= now => k.clone(),
+                            _ => break
+                        };
+                        self.set_expiry(&due, None);
+                        if let Some(v) = self.map.remove(&due) {
+                            self.notify_removed(&due, &v, ChangeReason::Expired);
+                        }
+                    }
+                }
+                fn schedule(&self) {
+                    if let Some(ref ticker) = self.ticker {
+                        ticker.set_pending(!self.deadlines.is_empty());
+                    }
+                }
+            }
+            // operations collected for one transaction command
+            pub struct Txn<'a> {
+                client: &'a client::SMClient,
+                ops: Vec<TxnOp<$kt, $vt>>,
+            }
+            impl <'a> Txn<'a> {
+                pub fn compare(mut self, k: &$kt, expected: &$vt) -> Txn<'a> {
+                    self.ops.push(TxnOp::Compare(k.clone(), Some(expected.clone())));
+                    self
+                }
+                pub fn compare_absent(mut self, k: &$kt) -> Txn<'a> {
+                    self.ops.push(TxnOp::Compare(k.clone(), None));
+                    self
+                }
+                pub fn put(mut self, k: &$kt, v: &$vt) -> Txn<'a> {
+                    self.ops.push(TxnOp::Put(k.clone(), v.clone()));
+                    self
+                }
+                pub fn delete(mut self, k: &$kt) -> Txn<'a> {
+                    self.ops.push(TxnOp::Delete(k.clone()));
+                    self
+                }
+                pub fn commit(self) -> Result<Result<Vec<TxnResult<$vt>>, TxnAborted>, ExecError> {
+                    self.client.transaction(&self.ops)
+                }
+            }
+            impl client::SMClient {
+                pub fn txn(&self) -> Txn {
+                    Txn {
+                        client: self,
+                        ops: Vec::new(),
+                    }
+                }
+                pub fn get_many(&self, keys: &[$kt]) -> Result<Result<Vec<Option<$vt>>, ()>, ExecError> {
+                    self.get_batch(&keys.to_vec())
+                }
+                // one log entry, the inserted and removed events are raised for every key
+                pub fn insert_many(&self, entries: &[($kt, $vt)]) -> Result<Result<Vec<Option<$vt>>, ()>, ExecError> {
+                    self.insert_batch(&entries.to_vec())
+                }
+                pub fn remove_many(&self, keys: &[$kt]) -> Result<Result<Vec<Option<$vt>>, ()>, ExecError> {
+                    self.remove_batch(&keys.to_vec())
+                }
             }
         }
     };
@@ -1,3 +1,11 @@
+// store macros expand in crates that may not depend on the hasher
+pub use bifrost_hasher::hash_str;
+
+pub mod ttl;
 pub mod value;
 pub mod number;
-pub mod map;
\ No newline at end of file
+pub mod map;
+pub mod btree;
+pub mod set;
+pub mod queue;
+pub mod lock;
\ No newline at end of file
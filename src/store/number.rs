@@ -1,12 +1,61 @@
+// arithmetic of the numbers, none when the result can not be represented
+pub trait Checked: Sized {
+    fn checked_add(self, n: Self) -> Option<Self>;
+    fn checked_sub(self, n: Self) -> Option<Self>;
+    fn checked_mul(self, n: Self) -> Option<Self>;
+    fn checked_div(self, n: Self) -> Option<Self>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum NumberError {
+    Overflow,
+    DivideByZero,
+}
+
+macro_rules! impl_checked_int {
+    ($($t: ty),*) => {$(
+        impl Checked for $t {
+            fn checked_add(self, n: $t) -> Option<$t> {<$t>::checked_add(self, n)}
+            fn checked_sub(self, n: $t) -> Option<$t> {<$t>::checked_sub(self, n)}
+            fn checked_mul(self, n: $t) -> Option<$t> {<$t>::checked_mul(self, n)}
+            fn checked_div(self, n: $t) -> Option<$t> {<$t>::checked_div(self, n)}
+        }
+    )*};
+}
+
+// floats overflow when finite operands give an infinite result
+macro_rules! impl_checked_float {
+    ($($t: ty),*) => {$(
+        impl Checked for $t {
+            fn checked_add(self, n: $t) -> Option<$t> {finite(self, n, self + n)}
+            fn checked_sub(self, n: $t) -> Option<$t> {finite(self, n, self - n)}
+            fn checked_mul(self, n: $t) -> Option<$t> {finite(self, n, self * n)}
+            fn checked_div(self, n: $t) -> Option<$t> {finite(self, n, self / n)}
+        }
+    )*};
+}
+
+fn finite<T: Into<f64> + Copy>(a: T, b: T, res: T) -> Option<T> {
+    if res.into().is_infinite() && !a.into().is_infinite() && !b.into().is_infinite() {
+        None
+    } else {
+        Some(res)
+    }
+}
+
+impl_checked_int!(i8, i16, i32, i64, u8, u16, u32, u64);
+impl_checked_float!(f32, f64);
+
 #[macro_export]
 macro_rules! def_store_number {
     ($m: ident, $t: ty) => {
         pub mod $m {
-            use raft::state_machine::StateMachineCtl;
-            use bifrost_hasher::hash_str;
+            use $crate::raft::state_machine::StateMachineCtl;
+            use $crate::store::hash_str;
             use $crate::raft::state_machine::callback::server::SMCallback;
             use $crate::raft::RaftService;
             use std::sync::{Arc};
+            use $crate::store::number::{Checked, NumberError};
             pub struct Number {
                 pub num: $t,
                 pub id: u64,
@@ -16,25 +65,29 @@ macro_rules! def_store_number {
                 def cmd set(n: $t);
                 def qry get() -> $t;
 
-                def cmd get_and_add(n: $t) -> $t;
-                def cmd add_and_get(n: $t) -> $t;
+                def cmd add(n: $t) -> $t | NumberError;
+                def cmd sub(n: $t) -> $t | NumberError;
+
+                def cmd get_and_add(n: $t) -> $t | NumberError;
+                def cmd add_and_get(n: $t) -> $t | NumberError;
 
-                def cmd get_and_minus(n: $t) -> $t;
-                def cmd minus_and_get(n: $t) -> $t;
+                def cmd get_and_minus(n: $t) -> $t | NumberError;
+                def cmd minus_and_get(n: $t) -> $t | NumberError;
 
-                def cmd get_and_incr() -> $t;
-                def cmd incr_and_get() -> $t;
+                def cmd get_and_incr() -> $t | NumberError;
+                def cmd incr_and_get() -> $t | NumberError;
 
-                def cmd get_and_decr() -> $t;
-                def cmd decr_and_get() -> $t;
+                def cmd get_and_decr() -> $t | NumberError;
+                def cmd decr_and_get() -> $t | NumberError;
 
-                def cmd get_and_multiply(n: $t) -> $t;
-                def cmd multiply_and_get(n: $t) -> $t;
+                def cmd get_and_multiply(n: $t) -> $t | NumberError;
+                def cmd multiply_and_get(n: $t) -> $t | NumberError;
 
-                def cmd get_and_divide(n: $t) -> $t;
-                def cmd divide_and_get(n: $t) -> $t;
+                def cmd get_and_divide(n: $t) -> $t | NumberError;
+                def cmd divide_and_get(n: $t) -> $t | NumberError;
 
-                def cmd compare_and_swap(original: $t, n: $t) -> $t;
+                // the original number when swapped, the current one otherwise
+                def cmd compare_and_swap(original: $t, n: $t) -> $t | $t;
                 def cmd swap(n: $t) -> $t;
 
                 def sub on_changed() -> ($t, $t);
@@ -51,63 +104,65 @@ macro_rules! def_store_number {
                 fn get(&self) -> Result<$t, ()> {
                     Ok(self.num)
                 }
-                fn get_and_add(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on + n);
-                    Ok(on)
+                fn add(&mut self, n: $t) -> Result<$t, NumberError> {
+                    self.add_and_get(n)
                 }
-                fn add_and_get(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on + n);
+                fn sub(&mut self, n: $t) -> Result<$t, NumberError> {
+                    self.minus_and_get(n)
+                }
+                fn get_and_add(&mut self, n: $t) -> Result<$t, NumberError> {
+                    let res = Checked::checked_add(self.num, n);
+                    self.change(res)
+                }
+                fn add_and_get(&mut self, n: $t) -> Result<$t, NumberError> {
+                    self.get_and_add(n)?;
                     Ok(self.num)
                 }
-                fn get_and_minus(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on - n);
-                    Ok(on)
+                fn get_and_minus(&mut self, n: $t) -> Result<$t, NumberError> {
+                    let res = Checked::checked_sub(self.num, n);
+                    self.change(res)
                 }
-                fn minus_and_get(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on - n);
+                fn minus_and_get(&mut self, n: $t) -> Result<$t, NumberError> {
+                    self.get_and_minus(n)?;
                     Ok(self.num)
                 }
-                fn get_and_incr(&mut self) -> Result<$t, ()> {
+                fn get_and_incr(&mut self) -> Result<$t, NumberError> {
                     self.get_and_add(1 as $t)
                 }
-                fn incr_and_get(&mut self) -> Result<$t, ()> {
+                fn incr_and_get(&mut self) -> Result<$t, NumberError> {
                     self.add_and_get(1 as $t)
                 }
-                fn get_and_decr(&mut self) -> Result<$t, ()> {
+                fn get_and_decr(&mut self) -> Result<$t, NumberError> {
                     self.get_and_minus(1 as $t)
                 }
-                fn decr_and_get(&mut self) -> Result<$t, ()> {
+                fn decr_and_get(&mut self) -> Result<$t, NumberError> {
                     self.minus_and_get(1 as $t)
                 }
-                fn get_and_multiply(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on * n);
-                    Ok(on)
+                fn get_and_multiply(&mut self, n: $t) -> Result<$t, NumberError> {
+                    let res = Checked::checked_mul(self.num, n);
+                    self.change(res)
                 }
-                fn multiply_and_get(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on * n);
+                fn multiply_and_get(&mut self, n: $t) -> Result<$t, NumberError> {
+                    self.get_and_multiply(n)?;
                     Ok(self.num)
                 }
-                fn get_and_divide(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on / n);
-                    Ok(on)
+                fn get_and_divide(&mut self, n: $t) -> Result<$t, NumberError> {
+                    if n == 0 as $t {
+                        return Err(NumberError::DivideByZero);
+                    }
+                    let res = Checked::checked_div(self.num, n);
+                    self.change(res)
                 }
-                fn divide_and_get(&mut self, n: $t) -> Result<$t, ()> {
-                    let on = self.num;
-                    self.set(on / n);
+                fn divide_and_get(&mut self, n: $t) -> Result<$t, NumberError> {
+                    self.get_and_divide(n)?;
                     Ok(self.num)
                 }
-                fn compare_and_swap(&mut self, original: $t, n: $t) -> Result<$t, ()> {
+                fn compare_and_swap(&mut self, original: $t, n: $t) -> Result<$t, $t> {
                     let on = self.num;
-                    if on == original {
-                        self.set(n);
+                    if on != original {
+                        return Err(on);
                     }
+                    self.set(n);
                     Ok(on)
                 }
                 fn swap(&mut self, n: $t) -> Result<$t, ()> {
@@ -137,6 +192,17 @@ macro_rules! def_store_number {
                 pub fn new_by_name(name: &String, num: $t) -> Number {
                     Number::new(hash_str(name), num)
                 }
+                // sets the number when it could be computed, returns the original one
+                fn change(&mut self, n: Option<$t>) -> Result<$t, NumberError> {
+                    match n {
+                        Some(n) => {
+                            let on = self.num;
+                            self.set(n);
+                            Ok(on)
+                        },
+                        None => Err(NumberError::Overflow)
+                    }
+                }
                 pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
                     self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
                 }
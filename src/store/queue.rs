@@ -0,0 +1,140 @@
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum QueueError {
+    // as many items as the queue holds wait or are leased
+    QueueFull,
+}
+
+// items are popped with a lease and hidden from other consumers until it expires, unless acked.
+// leases expire by the time the leader appended the command, every replica returns the same items
+#[macro_export]
+macro_rules! def_store_queue {
+    ($m: ident <$t: ty>) => {
+        pub mod $m {
+            use $crate::raft::state_machine::{StateMachineCtl, CommandCtx};
+            use $crate::raft::state_machine::callback::server::SMCallback;
+            use $crate::raft::RaftService;
+            use $crate::store::queue::QueueError;
+            use $crate::store::hash_str;
+            use std::collections::{VecDeque, BTreeMap};
+            use std::sync::{Arc};
+            pub struct Queue {
+                // message ids and items waiting, the head first
+                ready: VecDeque<(u64, $t)>,
+                // popped items by message id, with the consumer and the time their lease expires
+                leased: BTreeMap<u64, (u64, u64, $t)>,
+                last_id: u64,
+                max_len: u64,
+                callback: Option<SMCallback>,
+                pub id: u64
+            }
+            raft_state_machine! {
+                // answered the message id of the item
+                def cmd push(ctx, item: $t) -> u64 | QueueError;
+                // the item at the head, hidden from other consumers for lease_ms
+                def cmd pop_timeout_lease(ctx, consumer_id: u64, lease_ms: u64) -> Option<(u64, $t)>;
+                // the item is done with, false when its lease expired already
+                def cmd ack(ctx, msg_id: u64) -> bool;
+                // the item goes back to the head, false when its lease expired already
+                def cmd nack(ctx, msg_id: u64) -> bool;
+
+                // items waiting and leased
+                def qry len() -> u64;
+                def qry peek() -> Option<(u64, $t)>;
+
+                def sub on_pushed() -> (u64, $t);
+            }
+            impl StateMachineCmds for Queue {
+                fn push(&mut self, ctx: CommandCtx, item: $t) -> Result<u64, QueueError> {
+                    self.expire(ctx.leader_time_ms);
+                    if self.ready.len() + self.leased.len() >= self.max_len as usize {
+                        return Err(QueueError::QueueFull);
+                    }
+                    self.last_id += 1;
+                    let msg_id = self.last_id;
+                    if let Some(ref callback) = self.callback {
+                        callback.notify(&commands::on_pushed::new(), Ok((msg_id, item.clone())));
+                    }
+                    self.ready.push_back((msg_id, item));
+                    Ok(msg_id)
+                }
+                fn pop_timeout_lease(&mut self, ctx: CommandCtx, consumer_id: u64, lease_ms: u64) -> Result<Option<(u64, $t)>, ()> {
+                    let now = ctx.leader_time_ms;
+                    self.expire(now);
+                    Ok(self.ready.pop_front().map(|(msg_id, item)| {
+                        self.leased.insert(msg_id, (consumer_id, now + lease_ms, item.clone()));
+                        (msg_id, item)
+                    }))
+                }
+                fn ack(&mut self, ctx: CommandCtx, msg_id: u64) -> Result<bool, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    Ok(self.leased.remove(&msg_id).is_some())
+                }
+                fn nack(&mut self, ctx: CommandCtx, msg_id: u64) -> Result<bool, ()> {
+                    self.expire(ctx.leader_time_ms);
+                    match self.leased.remove(&msg_id) {
+                        Some((_, _, item)) => {
+                            self.ready.push_front((msg_id, item));
+                            Ok(true)
+                        },
+                        None => Ok(false)
+                    }
+                }
+                fn len(&self) -> Result<u64, ()> {
+                    Ok((self.ready.len() + self.leased.len()) as u64)
+                }
+                fn peek(&self) -> Result<Option<(u64, $t)>, ()> {
+                    Ok(self.ready.front().cloned())
+                }
+            }
+            impl StateMachineCtl for Queue {
+                raft_sm_complete!();
+                fn snapshot(&self) -> Option<Vec<u8>> {
+                    let ready: Vec<&(u64, $t)> = self.ready.iter().collect();
+                    Some($crate::utils::bincode::serialize(&(ready, &self.leased, self.last_id)))
+                }
+                fn recover(&mut self, data: Vec<u8>) {
+                    let (ready, leased, last_id): (Vec<(u64, $t)>, BTreeMap<u64, (u64, u64, $t)>, u64)
+                        = $crate::utils::bincode::deserialize(&data);
+                    self.ready = ready.into_iter().collect();
+                    self.leased = leased;
+                    self.last_id = last_id;
+                }
+                fn id(&self) -> u64 {self.id}
+            }
+            impl Queue {
+                pub fn new(id: u64, max_len: u64) -> Queue {
+                    Queue {
+                        ready: VecDeque::new(),
+                        leased: BTreeMap::new(),
+                        last_id: 0,
+                        max_len: max_len,
+                        callback: None,
+                        id: id,
+                    }
+                }
+                pub fn new_by_name(name: &String, max_len: u64) -> Queue {
+                    Queue::new(hash_str(name), max_len)
+                }
+                pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
+                    self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
+                }
+                // items of the leases expired by now go back to the head, in the order they were pushed
+                fn expire(&mut self, now: u64) {
+                    let expired: Vec<u64> = self.leased.iter()
+                        .filter(|&(_, &(_, expires, _))| expires <= now)
+                        .map(|(msg_id, _)| *msg_id)
+                        .collect();
+                    for msg_id in expired.into_iter().rev() {
+                        if let Some((consumer_id, _, item)) = self.leased.remove(&msg_id) {
+                            debug!("Lease of message {} by consumer {} expired", msg_id, consumer_id);
+                            self.ready.push_front((msg_id, item));
+                        }
+                    }
+                }
+            }
+        }
+    };
+}
+
+def_store_queue!(string_queue <String>);
+def_store_queue!(u8vec_queue <Vec<u8>>);
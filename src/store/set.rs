@@ -0,0 +1,133 @@
+#[macro_export]
+macro_rules! def_store_hash_set {
+    ($m: ident <$t: ty>) => {
+        pub mod $m {
+            use $crate::raft::state_machine::StateMachineCtl;
+            use $crate::raft::state_machine::callback::server::SMCallback;
+            use $crate::raft::RaftService;
+            use $crate::store::hash_str;
+            use std::collections::HashSet;
+            use std::sync::{Arc};
+            use super::*;
+            pub struct Set {
+                set: HashSet<$t>,
+                callback: Option<SMCallback>,
+                pub id: u64
+            }
+            raft_state_machine! {
+                // answered whether the element was not in the set
+                def cmd insert(v: $t) -> bool;
+                // answered whether the element was in the set
+                def cmd remove(v: $t) -> bool;
+                def cmd clear();
+
+                def qry contains(v: $t) -> bool;
+                def qry is_empty() -> bool;
+                def qry len() -> u64;
+                def qry to_vec() -> Vec<$t>;
+                // in the order of the elements, so pages of one version of the set do not overlap
+                def qry to_vec_paged(offset: u64, limit: u64) -> Vec<$t>;
+
+                // elements of the given ones that are in the set, and that are not
+                def qry intersection_with(vals: Vec<$t>) -> Vec<$t>;
+                def qry difference_with(vals: Vec<$t>) -> Vec<$t>;
+
+                def sub on_inserted() -> $t;
+                def sub on_removed() -> $t;
+            }
+            raft_sub_keys! {
+                on_inserted: $t;
+                on_removed: $t;
+            }
+            impl StateMachineCmds for Set {
+                fn insert(&mut self, v: $t) -> Result<bool, ()> {
+                    if !self.set.insert(v.clone()) {
+                        return Ok(false);
+                    }
+                    if let Some(ref callback) = self.callback {
+                        callback.notify_keyed(&commands::on_inserted::new(), &v, Ok(v.clone()));
+                    }
+                    Ok(true)
+                }
+                fn remove(&mut self, v: $t) -> Result<bool, ()> {
+                    if !self.set.remove(&v) {
+                        return Ok(false);
+                    }
+                    if let Some(ref callback) = self.callback {
+                        callback.notify_keyed(&commands::on_removed::new(), &v, Ok(v.clone()));
+                    }
+                    Ok(true)
+                }
+                fn clear(&mut self) -> Result<(), ()> {
+                    let removed = self.sorted();
+                    self.set.clear();
+                    if let Some(ref callback) = self.callback {
+                        for v in removed {
+                            callback.notify_keyed(&commands::on_removed::new(), &v, Ok(v.clone()));
+                        }
+                    }
+                    Ok(())
+                }
+                fn contains(&self, v: $t) -> Result<bool, ()> {
+                    Ok(self.set.contains(&v))
+                }
+                fn is_empty(&self) -> Result<bool, ()> {
+                    Ok(self.set.is_empty())
+                }
+                fn len(&self) -> Result<u64, ()> {
+                    Ok(self.set.len() as u64)
+                }
+                fn to_vec(&self) -> Result<Vec<$t>, ()> {
+                    Ok(self.set.iter().cloned().collect())
+                }
+                fn to_vec_paged(&self, offset: u64, limit: u64) -> Result<Vec<$t>, ()> {
+                    Ok(self.sorted().into_iter()
+                        .skip(offset as usize)
+                        .take(limit as usize)
+                        .collect())
+                }
+                fn intersection_with(&self, vals: Vec<$t>) -> Result<Vec<$t>, ()> {
+                    Ok(vals.into_iter().filter(|v| self.set.contains(v)).collect())
+                }
+                fn difference_with(&self, vals: Vec<$t>) -> Result<Vec<$t>, ()> {
+                    Ok(vals.into_iter().filter(|v| !self.set.contains(v)).collect())
+                }
+            }
+            impl StateMachineCtl for Set {
+                raft_sm_complete!();
+                // sorted, replicas with the same elements take the same snapshot
+                fn snapshot(&self) -> Option<Vec<u8>> {
+                    Some($crate::utils::bincode::serialize(&self.sorted()))
+                }
+                fn recover(&mut self, data: Vec<u8>) {
+                    let vals: Vec<$t> = $crate::utils::bincode::deserialize(&data);
+                    self.set = vals.into_iter().collect();
+                }
+                fn id(&self) -> u64 {self.id}
+            }
+            impl Set {
+                pub fn new(id: u64) -> Set {
+                    Set {
+                        set: HashSet::new(),
+                        callback: None,
+                        id: id,
+                    }
+                }
+                pub fn new_by_name(name: &String) -> Set {
+                    Set::new(hash_str(name))
+                }
+                pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
+                    self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
+                }
+                fn sorted(&self) -> Vec<$t> {
+                    let mut vals: Vec<$t> = self.set.iter().cloned().collect();
+                    vals.sort();
+                    vals
+                }
+            }
+        }
+    };
+}
+
+def_store_hash_set!(string_hashset <String>);
+def_store_hash_set!(u64_hashset <u64>);
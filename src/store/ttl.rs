@@ -0,0 +1,75 @@
+use raft::{RaftService, LogEntry, Service};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// why an entry was removed or a value changed, raised with the events of them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChangeReason {
+    Command,
+    // its time to live was over
+    Expired,
+}
+
+// the leader proposes the tick command of a store every interval while entries of it may expire.
+// replicas expire entries when the tick is applied, by the time the leader appended it, so they all
+// expire the same ones at the same place in the log instead of each one looking at its own clock
+pub struct Ticker {
+    pending: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Ticker {
+    pub fn start(raft_service: &Arc<RaftService>, sm_id: u64, fn_id: u64, data: Vec<u8>, interval_ms: u64) -> Ticker {
+        let pending = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let service = Arc::downgrade(raft_service);
+        let (pending_ref, closed_ref) = (pending.clone(), closed.clone());
+        thread::spawn(move || tick(service, sm_id, fn_id, data, interval_ms, pending_ref, closed_ref));
+        Ticker {
+            pending: pending,
+            closed: closed,
+        }
+    }
+    // no ticks are proposed while nothing may expire
+    pub fn set_pending(&self, pending: bool) {
+        self.pending.store(pending, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+fn tick(
+    service: Weak<RaftService>, sm_id: u64, fn_id: u64, data: Vec<u8>, interval_ms: u64,
+    pending: Arc<AtomicBool>, closed: Arc<AtomicBool>
+) {
+    while !closed.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(interval_ms));
+        let service = match service.upgrade() {
+            Some(service) => service,
+            None => return
+        };
+        if !pending.load(Ordering::Relaxed) || !service.is_leader() {
+            continue;
+        }
+        let res = service.c_command(&LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: sm_id,
+            fn_id: fn_id,
+            data: data.clone(),
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0
+        });
+        if res.is_err() {
+            debug!("Cannot propose the tick of state machine {}", sm_id);
+        }
+    }
+}
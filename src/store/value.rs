@@ -1,51 +1,150 @@
+// values that can be extended by another one, like strings
+pub trait Appendable {
+    fn append_value(&mut self, suffix: Self);
+    fn value_len(&self) -> u64;
+}
+
+impl Appendable for String {
+    fn append_value(&mut self, suffix: String) {self.push_str(&suffix)}
+    fn value_len(&self) -> u64 {self.len() as u64}
+}
+
+impl<T> Appendable for Vec<T> {
+    fn append_value(&mut self, mut suffix: Vec<T>) {self.append(&mut suffix)}
+    fn value_len(&self) -> u64 {self.len() as u64}
+}
+
+// a replicated value of any type that serializes, compares and clones, like
+// def_store_value!(config, Config). strings and vectors can also be appended to, with
+// def_store_value!(string, String, appendable)
 #[macro_export]
 macro_rules! def_store_value {
-    ($m: ident, $t: ty) => {
+    ($m: ident, $t: ty, appendable) => {
+        def_store_value!(@store $m, $t, {
+            // answered the value with the suffix
+            def cmd append(ctx, suffix: $t) -> $t;
+            def qry len() -> u64;
+        }, {
+            fn append(&mut self, ctx: CommandCtx, suffix: $t) -> Result<$t, ()> {
+                self.expire(ctx);
+                let mut v = self.val.clone();
+                $crate::store::value::Appendable::append_value(&mut v, suffix);
+                self.set(ctx, v)?;
+                Ok(self.val.clone())
+            }
+            fn len(&self) -> Result<u64, ()> {
+                Ok($crate::store::value::Appendable::value_len(self.current()))
+            }
+        });
+    };
+    (@store $m: ident, $t: ty, { $( $defs: tt )* }, { $( $fns: tt )* }) => {
         pub mod $m {
-            use bifrost_hasher::hash_str;
-            use $crate::raft::state_machine::StateMachineCtl;
+            use $crate::raft::state_machine::{StateMachineCtl, CommandCtx};
             use $crate::raft::state_machine::callback::server::SMCallback;
-            use $crate::raft::RaftService;
+            use $crate::raft::{RaftService, RaftMsg};
+            use $crate::store::hash_str;
+            use $crate::store::ttl::{Ticker, ChangeReason};
+            use $crate::utils::time::get_time;
             use std::sync::{Arc};
+            use super::*;
             pub struct Value {
                 pub val: $t,
                 pub id: u64,
+                // log index of the last change, 0 before the first one
+                pub revision: u64,
+                // the value it was created with, it is again once the value set with a time to live expires
+                initial: $t,
+                deadline: Option<u64>,
+                absent: bool,
                 callback: Option<SMCallback>,
+                ticker: Option<Ticker>,
             }
             raft_state_machine! {
-                def cmd set(v: $t);
+                def cmd set(ctx, v: $t);
+                // the initial value again by the first tick after the time to live, see init_expiry
+                def cmd set_with_ttl(ctx, v: $t, ttl_ms: u64);
+                // answers the initial value when expired, even before a tick changed it
                 def qry get() -> $t;
-                def sub on_changed() -> ($t, $t);
+                // only replaced while it is the expected one, the actual one otherwise
+                def cmd compare_and_set(ctx, expected: $t, new: $t) -> () | $t;
+                // only set when it never was or expired, answered whether it is set now
+                def cmd set_if_absent(ctx, v: $t) -> bool;
+                // changes the value back when it expired by the time the leader appended it
+                def cmd expire_tick(ctx);
+                def qry revision() -> u64;
+                $( $defs )*
+                // old value, new value and the revision of the change, raised when they differ
+                def sub on_changed() -> ($t, $t, u64, ChangeReason);
             }
             impl StateMachineCmds for Value {
-                fn set(&mut self, v: $t) -> Result<(),()> {
-                    if let Some(ref callback) = self.callback {
-                        let old = self.val.clone();
-                        callback.notify(&commands::on_changed::new(), Ok((old, v.clone())));
+                fn set(&mut self, ctx: CommandCtx, v: $t) -> Result<(),()> {
+                    self.expire(ctx);
+                    self.deadline = None;
+                    self.absent = false;
+                    self.change(ctx, v, ChangeReason::Command);
+                    self.schedule();
+                    Ok(())
+                }
+                fn set_with_ttl(&mut self, ctx: CommandCtx, v: $t, ttl_ms: u64) -> Result<(),()> {
+                    self.set(ctx, v)?;
+                    self.deadline = Some(ctx.leader_time_ms + ttl_ms);
+                    self.schedule();
+                    Ok(())
+                }
+                fn compare_and_set(&mut self, ctx: CommandCtx, expected: $t, new: $t) -> Result<(), $t> {
+                    self.expire(ctx);
+                    if self.val != expected {
+                        return Err(self.val.clone());
                     }
-                    self.val = v;
+                    self.set(ctx, new).unwrap();
+                    Ok(())
+                }
+                fn set_if_absent(&mut self, ctx: CommandCtx, v: $t) -> Result<bool, ()> {
+                    self.expire(ctx);
+                    if !self.absent {
+                        return Ok(false);
+                    }
+                    self.set(ctx, v)?;
+                    Ok(true)
+                }
+                fn expire_tick(&mut self, ctx: CommandCtx) -> Result<(), ()> {
+                    self.expire(ctx);
                     Ok(())
                 }
                 fn get(&self) -> Result<$t, ()> {
-                    Ok(self.val.clone())
+                    Ok(self.current().clone())
+                }
+                fn revision(&self) -> Result<u64, ()> {
+                    Ok(self.revision)
                 }
+                $( $fns )*
             }
             impl StateMachineCtl for Value {
                 raft_sm_complete!();
                 fn snapshot(&self) -> Option<Vec<u8>> {
-                    Some($crate::utils::bincode::serialize(&self.val))
+                    Some($crate::utils::bincode::serialize(&(&self.val, self.revision, self.deadline, self.absent)))
                 }
                 fn recover(&mut self, data: Vec<u8>) {
-                    self.val = $crate::utils::bincode::deserialize(&data);
+                    let (val, revision, deadline, absent): ($t, u64, Option<u64>, bool) = $crate::utils::bincode::deserialize(&data);
+                    self.val = val;
+                    self.revision = revision;
+                    self.deadline = deadline;
+                    self.absent = absent;
+                    self.schedule();
                 }
                 fn id(&self) -> u64 {self.id}
             }
             impl Value {
                 pub fn new(id: u64, val: $t) -> Value {
                     Value {
+                        initial: val.clone(),
                         val: val,
                         id: id,
+                        revision: 0,
+                        deadline: None,
+                        absent: true,
                         callback: None,
+                        ticker: None,
                     }
                 }
                 pub fn new_by_name(name: &String, val: $t) -> Value {
@@ -54,9 +153,53 @@ macro_rules! def_store_value {
                 pub fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
                     self.callback = Some(SMCallback::new(self.id(), raft_service.clone()));
                 }
+                // values set with a time to live are only changed back by ticks, proposed by the
+                // leader every tick_ms. every replica should start it
+                pub fn init_expiry(&mut self, raft_service: &Arc<RaftService>, tick_ms: u64) {
+                    let tick = commands::expire_tick::new();
+                    let (fn_id, _, data) = tick.encode();
+                    self.ticker = Some(Ticker::start(raft_service, self.id, fn_id, data.clone(), tick_ms));
+                    self.schedule();
+                }
+                fn change(&mut self, ctx: CommandCtx, v: $t, reason: ChangeReason) {
+                    if v != self.val {
+                        if let Some(ref callback) = self.callback {
+                            let old = self.val.clone();
+                            callback.notify(&commands::on_changed::new(), Ok((old, v.clone(), ctx.index, reason)));
+                        }
+                    }
+                    self.val = v;
+                    self.revision = ctx.index;
+                }
+                fn expire(&mut self, ctx: CommandCtx) {
+                    match self.deadline {
+                        Some(deadline) if deadline <= ctx.leader_time_ms => {},
+                        _ => return
+                    }
+                    self.deadline = None;
+                    self.absent = true;
+                    let initial = self.initial.clone();
+                    self.change(ctx, initial, ChangeReason::Expired);
+                    self.schedule();
+                }
+                // queries have no time of the leader, they go by the clock of the replica
+                fn current(&self) -> &$t {
+                    match self.deadline {
+                        Some(deadline) if deadline as i64 <= get_time() => &self.initial,
+                        _ => &self.val
+                    }
+                }
+                fn schedule(&self) {
+                    if let Some(ref ticker) = self.ticker {
+                        ticker.set_pending(self.deadline.is_some());
+                    }
+                }
             }
         }
     };
+    ($m: ident, $t: ty) => {
+        def_store_value!(@store $m, $t, {}, {});
+    };
 }
 
-def_store_value!(string, String);
\ No newline at end of file
+def_store_value!(string, String, appendable);
\ No newline at end of file
@@ -0,0 +1,51 @@
+use std::io;
+use futures::Future;
+use tokio_core::io::{Io, read_exact, write_all};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand;
+
+// the server sends a random nonce on accept, the client answers the HMAC-SHA256 of it keyed by the token.
+// both sides have to be configured with the token, the handshake is not negotiated
+pub const NONCE_LEN: usize = 16;
+pub const MAC_LEN: usize = 32;
+
+pub fn mac(token: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), token.as_bytes());
+    hmac.input(nonce);
+    hmac.result().code().to_vec()
+}
+
+// resolves to the socket and whether the peer proved the token
+pub fn accept<S>(socket: S, token: &str) -> Box<Future<Item = (S, bool), Error = io::Error>>
+    where S: Io + 'static {
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let expected = mac(token, &nonce);
+    Box::new(write_all(socket, nonce)
+        .and_then(|(socket, _)| read_exact(socket, [0u8; MAC_LEN]))
+        .map(move |(socket, answer)| (socket, fixed_time_eq(&expected, &answer))))
+}
+
+pub fn connect<S>(socket: S, token: &str) -> Box<Future<Item = S, Error = io::Error>>
+    where S: Io + 'static {
+    let token = token.to_string();
+    Box::new(read_exact(socket, [0u8; NONCE_LEN])
+        .and_then(move |(socket, nonce)| write_all(socket, mac(&token, &nonce)))
+        .map(|(socket, _)| socket))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mac_depends_on_token_and_nonce() {
+        let nonce = [1u8; NONCE_LEN];
+        assert_eq!(mac("secret", &nonce).len(), MAC_LEN);
+        assert_eq!(mac("secret", &nonce), mac("secret", &nonce));
+        assert!(mac("secret", &nonce) != mac("other", &nonce));
+        assert!(mac("secret", &nonce) != mac("secret", &[2u8; NONCE_LEN]));
+    }
+}
@@ -1,92 +1,493 @@
 use std::io;
+use std::cmp::min;
+use std::thread;
 use std::time::Duration;
+use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::net::SocketAddr;
 
-use futures::{Future};
+use futures::{Future, Stream, Async, Poll, future};
+use futures::sync::{mpsc, oneshot};
+use parking_lot::Mutex;
+use bytes::Bytes;
 
 use tokio_service::Service;
+use tokio_core::io::Io;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Core;
-use tokio_proto::TcpClient;
+use tokio_proto::BindClient;
 use tokio_proto::multiplex::{ClientService};
 use tokio_middleware::Timeout;
 use tokio_timer::Timer;
+use tokio_tls::{TlsConnectorExt, TlsStream};
+use tokio_uds::UnixStream;
 
 use tcp::proto::BytesClientProto;
-use tcp::shortcut;
+use tcp::framed::{self, DEFAULT_MAX_FRAME_SIZE};
+use tcp::{auth, shortcut, tls};
+use tcp::events::{ConnectionEvents, ConnectHandler, DisconnectHandler, Watched};
 use bifrost_hasher::hash_str;
-use super::STANDALONE_ADDRESS;
+use super::{STANDALONE_ADDRESS, unix_socket_path};
 use DISABLE_SHORTCUT;
 
-pub type ResFuture = Future<Item = Vec<u8>, Error = io::Error>;
+pub type ResFuture = Future<Item = Bytes, Error = io::Error>;
+
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub timeout: Duration,
+    pub reconnect: Option<ReconnectPolicy>,
+    pub tls: Option<tls::ConnectorConfig>,
+    // requests larger than this are failed before sending, the server should have the same limit
+    pub max_frame_size: usize,
+    // CRC32 on frames, only when the server enabled it as well
+    pub checksum: bool,
+    // has to be set exactly when the server requires authentication
+    pub auth_token: Option<String>,
+    // connect and disconnect of every connection dialed, including reconnects. shared by clones of the options
+    pub events: Arc<ConnectionEvents>,
+    // a request failed by the broken connection is sent once more after reconnecting.
+    // only for idempotent requests, the server may have served it before the connection broke
+    pub resend_on_reconnect: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            timeout: Duration::from_secs(5),
+            reconnect: None,
+            tls: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            checksum: false,
+            auth_token: None,
+            events: ConnectionEvents::new(),
+            resend_on_reconnect: false,
+        }
+    }
+}
+
+enum Transport {
+    Plain(ClientService<Watched<TcpStream>, BytesClientProto>),
+    Tls(ClientService<Watched<TlsStream<TcpStream>>, BytesClientProto>),
+    Unix(ClientService<UnixStream, BytesClientProto>),
+}
 
 pub struct ClientCore {
-    inner: ClientService<TcpStream, BytesClientProto>,
+    inner: Transport,
+}
+
+type ResSender = oneshot::Sender<io::Result<Bytes>>;
+// request, timeout for the request, the slot for response, and the message to send when cancelled
+type Submission = (Bytes, Option<Duration>, ResSender, Option<Bytes>);
+type Submitter = mpsc::UnboundedSender<Submission>;
+
+// fills the response slot, or drops the call when the caller dropped the other end.
+// resolves to true when the call was cancelled
+struct Pending {
+    call: Box<ResFuture>,
+    res_tx: Option<ResSender>,
+}
+
+impl Future for Pending {
+    type Item = bool;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<bool, ()> {
+        let res = match self.call.poll() {
+            Ok(Async::Ready(res)) => Ok(res),
+            Ok(Async::NotReady) => {
+                return match self.res_tx.as_mut().map(|res_tx| res_tx.poll_cancel()) {
+                    Some(Ok(Async::NotReady)) => Ok(Async::NotReady),
+                    _ => Ok(Async::Ready(true))
+                };
+            },
+            Err(e) => Err(e)
+        };
+        if let Some(res_tx) = self.res_tx.take() {
+            res_tx.send(res).ok();
+        }
+        Ok(Async::Ready(false))
+    }
 }
 
+// callers only hold the submitter, the connection is driven by its own reactor thread
+// so requests from different threads are pipelined in the multiplexed transport
 pub struct Client {
-    client: Option<(Timeout<ClientCore>, Core)>,
+    submitter: Arc<Mutex<Option<Submitter>>>,
+    address: String,
+    options: ClientOptions,
+    timer: Timer,
+    default_timeout: Mutex<Option<Duration>>,
+    connected: Arc<AtomicBool>,
     pub server_id: u64,
 }
 
 impl Service for ClientCore {
 
-    type Request = Vec<u8>;
-    type Response = Vec<u8>;
+    type Request = Bytes;
+    type Response = Bytes;
     type Error = io::Error;
     // Again for simplicity, we are just going to box a future
     type Future = Box<Future<Item = Self::Response, Error = io::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        self.inner.call(req).boxed()
+        // frames failed the checksum come out as errors of the request
+        match self.inner {
+            Transport::Plain(ref client) => client.call(Ok(req)).and_then(|res| res).boxed(),
+            Transport::Tls(ref client) => client.call(Ok(req)).and_then(|res| res).boxed(),
+            Transport::Unix(ref client) => client.call(Ok(req)).and_then(|res| res).boxed(),
+        }
+    }
+}
+
+fn authenticate<S>(socket: S, auth_token: &Option<String>) -> Box<Future<Item = S, Error = io::Error>>
+    where S: Io + 'static {
+    match *auth_token {
+        Some(ref token) => auth::connect(socket, token),
+        None => Box::new(future::finished(socket))
+    }
+}
+
+// the authentication handshake has to finish in the connect timeout
+fn connect_core(address: &String, options: &ClientOptions, timer: &Timer) -> io::Result<(Timeout<ClientCore>, Core)> {
+    let mut core = Core::new()?;
+    let proto = BytesClientProto {
+        max_frame_size: options.max_frame_size,
+        checksum: options.checksum,
+    };
+    if let Some(path) = unix_socket_path(address) {
+        if options.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("TLS is not supported on unix socket {}", address)));
+        }
+        let handle = core.handle();
+        let socket = UnixStream::connect(path, &handle)?;
+        let socket = core.run(timer.timeout(authenticate(socket, &options.auth_token), options.timeout))?;
+        let client = Timeout::new(
+            ClientCore {
+                inner: Transport::Unix(proto.bind_client(&handle, socket)),
+            },
+            timer.clone(),
+            options.timeout);
+        return Ok((client, core));
+    }
+    let socket_address: SocketAddr = address.parse().unwrap();
+    let auth_token = options.auth_token.clone();
+    let events = options.events.clone();
+    let inner = match options.tls {
+        Some(ref config) => {
+            let connector = tls::connector(config)?;
+            let domain = config.domain.clone();
+            let handle = core.handle();
+            let future = TcpStream::connect(&socket_address, &handle)
+                .and_then(move |socket| {
+                    connector.connect_async(&domain, socket).map_err(tls::handshake_error)
+                })
+                .and_then(move |socket| authenticate(socket, &auth_token))
+                .map(move |socket| {
+                    Transport::Tls(proto.bind_client(&handle, Watched::new(socket, socket_address, &events)))
+                });
+            // the handshake has to finish in the connect timeout
+            core.run(timer.timeout(future, options.timeout))?
+        },
+        None => {
+            let handle = core.handle();
+            let future = TcpStream::connect(&socket_address, &handle)
+                .and_then(move |socket| authenticate(socket, &auth_token))
+                .map(move |socket| {
+                    Transport::Plain(proto.bind_client(&handle, Watched::new(socket, socket_address, &events)))
+                });
+            if options.auth_token.is_some() {
+                // a server not expecting the handshake never sends the nonce
+                core.run(timer.timeout(future, options.timeout))?
+            } else {
+                core.run(future)?
+            }
+        }
+    };
+    let client = Timeout::new(
+        ClientCore {
+            inner: inner,
+        },
+        timer.clone(),
+        options.timeout);
+    Ok((client, core))
+}
+
+// connect in a new reactor thread, which lives until all submitters are dropped
+fn dial(address: &String, options: &ClientOptions, timer: &Timer) -> io::Result<Submitter> {
+    let (ready_tx, ready_rx) = channel();
+    let address = address.clone();
+    let options = options.clone();
+    let timer = timer.clone();
+    thread::Builder::new()
+        .name(format!("bifrost-client-{}", address))
+        .spawn(move || {
+            let (client, mut core) = match connect_core(&address, &options, &timer) {
+                Ok(connected) => connected,
+                Err(e) => {
+                    ready_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            let (submitter, submissions) = mpsc::unbounded();
+            ready_tx.send(Ok(submitter)).ok();
+            let handle = core.handle();
+            let client = Rc::new(client);
+            let served = submissions.for_each(move |(req, timeout, res_tx, cancel_msg): Submission| {
+                let future: Box<ResFuture> = match timeout {
+                    Some(timeout) => Box::new(timer.timeout(client.call(req), timeout)),
+                    None => Box::new(client.call(req))
+                };
+                let client = client.clone();
+                let handle_ref = handle.clone();
+                let pending = Pending {
+                    call: future,
+                    res_tx: Some(res_tx),
+                };
+                handle.spawn(pending.then(move |cancelled| -> Result<(), ()> {
+                    if let (Ok(true), Some(cancel_msg)) = (cancelled, cancel_msg) {
+                        // tell the server to skip the request, nobody waits for the result
+                        handle_ref.spawn(client.call(cancel_msg).then(|_| Ok(())));
+                    }
+                    Ok(())
+                }));
+                Ok(())
+            });
+            core.run(served).ok();
+            debug!("Client reactor for {} stopped", address);
+        })?;
+    match ready_rx.recv() {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Client reactor died when connecting"))
     }
 }
 
 impl Client {
-    pub fn connect_with_timeout (address: &String, timeout: Duration) -> io::Result<Client> {
+    pub fn connect_with_options(address: &String, options: ClientOptions) -> io::Result<Client> {
         let server_id = hash_str(address);
-        let client = {
+        let timer = Timer::default();
+        let submitter = {
             if !DISABLE_SHORTCUT && shortcut::is_local(server_id) {
                 None
             } else {
                 if address.eq(&STANDALONE_ADDRESS) {
                     return Err(io::Error::new(io::ErrorKind::Other, "STANDALONE server is not found"))
                 }
-                let mut core = Core::new()?;
-                let socket_address = address.parse().unwrap();
-                let future = Box::new(TcpClient::new(BytesClientProto)
-                    .connect(&socket_address, &core.handle())
-                    .map(|c| Timeout::new(
-                        ClientCore {
-                            inner: c,
-                        },
-                        Timer::default(),
-                        timeout)));
-                Some((core.run(future)?, core))
+                Some(dial(address, &options, &timer)?)
             }
         };
         Ok(Client {
-            client: client,
+            submitter: Arc::new(Mutex::new(submitter)),
+            address: address.clone(),
+            options: options,
+            timer: timer,
+            default_timeout: Mutex::new(None),
+            connected: Arc::new(AtomicBool::new(true)),
             server_id: server_id,
         })
     }
+    pub fn connect_with_timeout (address: &String, timeout: Duration) -> io::Result<Client> {
+        Client::connect_with_options(address, ClientOptions {
+            timeout: timeout,
+            .. ClientOptions::default()
+        })
+    }
     pub fn connect (address: &String) -> io::Result<Client> {
-        Client::connect_with_timeout(address, Duration::from_secs(5))
-    }
-    pub fn send(&mut self, msg: Vec<u8>) -> io::Result<Vec<u8>> {
-        if let Some((ref client, ref mut core)) = self.client {
-            let future = client.call(msg);
-            core.run(future)
-        } else {
-            shortcut::call(self.server_id, msg)
+        Client::connect_with_options(address, ClientOptions::default())
+    }
+    pub fn connect_tls (address: &String, connector_config: &tls::ConnectorConfig) -> io::Result<Client> {
+        Client::connect_with_options(address, ClientOptions {
+            tls: Some(connector_config.clone()),
+            .. ClientOptions::default()
+        })
+    }
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.default_timeout.lock() = timeout;
+    }
+    pub fn default_timeout(&self) -> Option<Duration> {
+        *self.default_timeout.lock()
+    }
+    // the handlers also see connections dialed after they were set, like reconnects
+    pub fn on_connect(&self, handler: ConnectHandler) {
+        self.options.events.on_connect(handler);
+    }
+    pub fn on_disconnect(&self, handler: DisconnectHandler) {
+        self.options.events.on_disconnect(handler);
+    }
+    pub fn max_frame_size(&self) -> usize {
+        self.options.max_frame_size
+    }
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+    // shared flag for observing connection state without locking the client
+    pub fn connection_flag(&self) -> Arc<AtomicBool> {
+        self.connected.clone()
+    }
+    fn ensure_connected(&self) -> io::Result<Option<Submitter>> {
+        ensure_connected(&self.submitter, &self.connected, &self.address, &self.options, &self.timer)
+    }
+    fn submit(&self, msg: Bytes, timeout: Option<Duration>, cancel_msg: Option<Bytes>) -> Box<ResFuture> {
+        if msg.len() > self.options.max_frame_size {
+            // failing the encoder would break the connection for other requests
+            return Box::new(future::err(framed::frame_too_large(msg.len() as u64, self.options.max_frame_size)));
+        }
+        let submitter = match self.ensure_connected() {
+            Ok(Some(submitter)) => submitter,
+            Ok(None) => return shortcut::call_async(self.server_id, msg),
+            Err(e) => return Box::new(future::err(e))
+        };
+        if !self.options.resend_on_reconnect || self.options.reconnect.is_none() {
+            return send_to(&submitter, &self.connected, msg, timeout, cancel_msg);
         }
+        let sent = send_to(&submitter, &self.connected, msg.clone(), timeout, cancel_msg.clone());
+        let submitter = self.submitter.clone();
+        let connected = self.connected.clone();
+        let address = self.address.clone();
+        let options = self.options.clone();
+        let timer = self.timer.clone();
+        Box::new(sent.or_else(move |e| -> Box<ResFuture> {
+            if !is_broken_connection(&e) {
+                return Box::new(future::err(e));
+            }
+            debug!("Resending request to {} after reconnect, {:?}", address, e);
+            // redialed in a thread of its own, the thread polling the future is not held by the backoff
+            let (tx, rx) = oneshot::channel();
+            {
+                let connected = connected.clone();
+                thread::spawn(move || {
+                    tx.send(ensure_connected(&submitter, &connected, &address, &options, &timer)).ok();
+                });
+            }
+            Box::new(rx.then(move |res| -> Box<ResFuture> {
+                match res {
+                    Ok(Ok(Some(submitter))) => send_to(&submitter, &connected, msg, timeout, cancel_msg),
+                    _ => Box::new(future::err(e))
+                }
+            }))
+        }))
+    }
+    // requests can be given as Vec<u8>, which is taken over without copying
+    pub fn send<M: Into<Bytes>>(&self, msg: M) -> io::Result<Bytes> {
+        self.send_async(msg).wait()
+    }
+    // dropping the timed out future also drops the response slot in the multiplexer,
+    // late responses for the request id will be discarded instead of matching other calls
+    pub fn send_with_timeout<M: Into<Bytes>>(&self, msg: M, timeout: Duration) -> io::Result<Bytes> {
+        self.submit(msg.into(), Some(timeout), None).wait()
     }
-    pub fn send_async(&mut self, msg: Vec<u8>) -> Box<ResFuture> {
-        if let Some((ref client, _)) = self.client {
-            Box::new(client.call(msg))
-        } else {
-            shortcut::call_async(self.server_id, msg)
+    // dropping the future cancels the request, its response will be discarded
+    pub fn send_async<M: Into<Bytes>>(&self, msg: M) -> Box<ResFuture> {
+        let timeout = *self.default_timeout.lock();
+        self.submit(msg.into(), timeout, None)
+    }
+    // the cancel message is sent after the request when the future was dropped before response.
+    // shortcut requests are served before returning and cannot be cancelled
+    pub fn send_async_with_cancel<M: Into<Bytes>>(&self, msg: M, cancel_msg: M) -> Box<ResFuture> {
+        let timeout = *self.default_timeout.lock();
+        self.submit(msg.into(), timeout, Some(cancel_msg.into()))
+    }
+}
+
+// any io error other than timeout and checksum mismatch is considered as a broken connection
+fn is_broken_connection(e: &io::Error) -> bool {
+    e.kind() != io::ErrorKind::TimedOut && !framed::is_checksum_mismatch(e)
+}
+
+fn check_connection(connected: &Arc<AtomicBool>, res: io::Result<Bytes>) -> io::Result<Bytes> {
+    if let Err(ref e) = res {
+        if is_broken_connection(e) {
+            connected.store(false, Ordering::Relaxed);
         }
     }
+    res
+}
+
+fn send_to(
+    submitter: &Submitter, connected: &Arc<AtomicBool>,
+    msg: Bytes, timeout: Option<Duration>, cancel_msg: Option<Bytes>
+) -> Box<ResFuture> {
+    let connected = connected.clone();
+    let (res_tx, res_rx) = oneshot::channel();
+    if submitter.unbounded_send((msg, timeout, res_tx, cancel_msg)).is_err() {
+        connected.store(false, Ordering::Relaxed);
+        return Box::new(future::err(io::Error::new(io::ErrorKind::NotConnected, "Client reactor stopped")));
+    }
+    Box::new(res_rx.then(move |res| {
+        let res = match res {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed before response"))
+        };
+        check_connection(&connected, res)
+    }))
 }
 
-unsafe impl Send for Client {}
\ No newline at end of file
+// redial a broken connection with exponential backoff when reconnect policy was set.
+// requests that failed with the old connection have been failed to the caller,
+// they are only resent with resend_on_reconnect in the options.
+// returns the submitter to use, None for shortcut.
+// the lock is only held to look at and replace the submitter, callers do not queue behind the backoff.
+// the connection flag is only changed to true under it
+fn ensure_connected(
+    submitter: &Mutex<Option<Submitter>>, connected: &Arc<AtomicBool>,
+    address: &String, options: &ClientOptions, timer: &Timer
+) -> io::Result<Option<Submitter>> {
+    {
+        let current = submitter.lock();
+        if current.is_none() || connected.load(Ordering::Relaxed) {
+            return Ok(current.clone());
+        }
+    }
+    let policy = match options.reconnect {
+        Some(ref policy) => policy.clone(),
+        None => return Ok(submitter.lock().clone()) // let the broken connection report errors
+    };
+    let mut delay = policy.base_delay;
+    let mut last_error = None;
+    for attempt in 0..policy.max_attempts {
+        if attempt > 0 {
+            thread::sleep(delay);
+            delay = min(delay * 2, policy.max_delay);
+            // reconnected by another caller meanwhile
+            let current = submitter.lock();
+            if connected.load(Ordering::Relaxed) {
+                return Ok(current.clone());
+            }
+        }
+        match dial(address, options, timer) {
+            Ok(new_submitter) => {
+                let mut current = submitter.lock();
+                // the connection of a caller who dialed at the same time and was first is kept
+                if !connected.load(Ordering::Relaxed) {
+                    debug!("Reconnected to {} after {} attempts", address, attempt + 1);
+                    *current = Some(new_submitter);
+                    connected.store(true, Ordering::Relaxed);
+                }
+                return Ok(current.clone());
+            },
+            Err(e) => {
+                debug!("Reconnect to {} failed, attempt {}, {:?}", address, attempt + 1, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Cannot reconnect")))
+}
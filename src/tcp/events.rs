@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use parking_lot::RwLock;
+use futures::Async;
+use tokio_core::io::Io;
+
+pub type ConnectHandler = Box<Fn(SocketAddr) + Send + Sync>;
+pub type DisconnectHandler = Box<Fn(SocketAddr, io::Error) + Send + Sync>;
+
+// observers of connections over tcp, unix socket connections and shortcut calls have no events.
+// handlers run in the reactor thread of the connection, they should not block
+pub struct ConnectionEvents {
+    on_connect: RwLock<Option<ConnectHandler>>,
+    on_disconnect: RwLock<Option<DisconnectHandler>>,
+}
+
+impl ConnectionEvents {
+    pub fn new() -> Arc<ConnectionEvents> {
+        Arc::new(ConnectionEvents {
+            on_connect: RwLock::new(None),
+            on_disconnect: RwLock::new(None),
+        })
+    }
+    // replaces the previous handler
+    pub fn on_connect(&self, handler: ConnectHandler) {
+        *self.on_connect.write() = Some(handler);
+    }
+    pub fn on_disconnect(&self, handler: DisconnectHandler) {
+        *self.on_disconnect.write() = Some(handler);
+    }
+    pub fn connected(&self, peer: SocketAddr) {
+        if let Some(ref handler) = *self.on_connect.read() {
+            handler(peer);
+        }
+    }
+    pub fn disconnected(&self, peer: SocketAddr, e: io::Error) {
+        if let Some(ref handler) = *self.on_disconnect.read() {
+            handler(peer, e);
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConnectionEvents {{ on_connect: {}, on_disconnect: {} }}",
+               self.on_connect.read().is_some(), self.on_disconnect.read().is_some())
+    }
+}
+
+// the socket of an established connection, the disconnect event fires once when it is dropped
+// with the error ended the connection
+pub struct Watched<S> {
+    inner: S,
+    peer: SocketAddr,
+    events: Arc<ConnectionEvents>,
+    error: Option<io::Error>,
+}
+
+impl <S> Watched<S> {
+    pub fn new(inner: S, peer: SocketAddr, events: &Arc<ConnectionEvents>) -> Watched<S> {
+        events.connected(peer);
+        Watched {
+            inner: inner,
+            peer: peer,
+            events: events.clone(),
+            error: None,
+        }
+    }
+    fn watch<T>(&mut self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(ref e) = res {
+            if e.kind() != io::ErrorKind::WouldBlock && self.error.is_none() {
+                self.error = Some(io::Error::new(e.kind(), format!("{}", e)));
+            }
+        }
+        res
+    }
+}
+
+impl <S: Read> Read for Watched<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.inner.read(buf);
+        if let Ok(0) = res {
+            if !buf.is_empty() && self.error.is_none() {
+                self.error = Some(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by peer"));
+            }
+        }
+        self.watch(res)
+    }
+}
+
+impl <S: Write> Write for Watched<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.inner.write(buf);
+        self.watch(res)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.inner.flush();
+        self.watch(res)
+    }
+}
+
+impl <S: Io> Io for Watched<S> {
+    fn poll_read(&mut self) -> Async<()> {
+        self.inner.poll_read()
+    }
+    fn poll_write(&mut self) -> Async<()> {
+        self.inner.poll_write()
+    }
+}
+
+impl <S> Drop for Watched<S> {
+    fn drop(&mut self) {
+        let e = self.error.take().unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed locally")
+        });
+        self.events.disconnected(self.peer, e);
+    }
+}
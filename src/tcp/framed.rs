@@ -1,39 +1,186 @@
 use tokio_core::io::{Io, Codec, EasyBuf, Framed};
-use std::{io, str};
+use std::{io, str, fmt, error};
 use byteorder::{ByteOrder, LittleEndian};
+use crc::{crc32, Hasher32};
+use bytes::Bytes;
 
-pub struct BytesCodec;
+// frames larger than this fail the connection instead of being buffered
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// payloads of corrupted frames are replaced by the error, so only that request fails
+// the payload is copied out of the read buffer once, slicing it afterwards is free
+pub type Frame = io::Result<Bytes>;
+
+pub struct BytesCodec {
+    pub max_frame_size: usize,
+    // append CRC32 of the frame, both sides of the connection have to agree on it
+    pub checksum: bool,
+}
+
+#[derive(Debug)]
+pub struct ChecksumMismatch;
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame checksum mismatch")
+    }
+}
+
+impl error::Error for ChecksumMismatch {
+    fn description(&self) -> &str {
+        "frame checksum mismatch"
+    }
+}
+
+impl BytesCodec {
+    pub fn new(max_frame_size: usize, checksum: bool) -> BytesCodec {
+        BytesCodec {
+            max_frame_size: max_frame_size,
+            checksum: checksum
+        }
+    }
+}
+
+impl Default for BytesCodec {
+    fn default() -> BytesCodec {
+        BytesCodec::new(DEFAULT_MAX_FRAME_SIZE, false)
+    }
+}
+
+pub fn frame_too_large(len: u64, max_frame_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Frame of {} bytes exceeds the limit of {} bytes", len, max_frame_size)
+    )
+}
+
+pub fn checksum_mismatch() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ChecksumMismatch)
+}
+
+pub fn is_checksum_mismatch(e: &io::Error) -> bool {
+    match e.get_ref() {
+        Some(inner) => inner.is::<ChecksumMismatch>(),
+        None => false
+    }
+}
+
+// covers the header, so a damaged request id does not deliver the payload to another request
+fn checksum(header: &[u8], data: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(header);
+    digest.write(data);
+    digest.sum32()
+}
 
 impl Codec for BytesCodec {
 
-    type In = (u64, Vec<u8>);
-    type Out = (u64, Vec<u8>);
+    type In = (u64, Frame);
+    type Out = (u64, Frame);
 
+    // [request id][len][data], followed by [crc32] when checksum is enabled
     fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Self::In>, io::Error> {
         let buf_len = buf.as_ref().len();
         if buf_len >= 8 * 2 {
             let mid = LittleEndian::read_u64(buf.as_ref());
             let len = LittleEndian::read_u64(&buf.as_ref()[8..16]);
-            if buf_len as u64 >= 8 * 2 + len {
-                buf.drain_to(16);
-                let data = Vec::from(buf.drain_to(len as usize).as_slice());
-                return Ok(Some((mid, data)))
+            if len > self.max_frame_size as u64 {
+                // reject before the payload is buffered
+                return Err(frame_too_large(len, self.max_frame_size));
+            }
+            let trailer_len = if self.checksum {4} else {0};
+            if buf_len as u64 >= 8 * 2 + len + trailer_len {
+                let header = buf.drain_to(16);
+                let data = Bytes::from(buf.drain_to(len as usize).as_slice());
+                if !self.checksum {
+                    return Ok(Some((mid, Ok(data))));
+                }
+                let actual = LittleEndian::read_u32(buf.drain_to(4).as_slice());
+                let expected = checksum(header.as_slice(), &data);
+                if actual == expected {
+                    return Ok(Some((mid, Ok(data))));
+                }
+                if data.is_empty() && actual == !expected {
+                    debug!("Peer reported checksum mismatch for request {}", mid);
+                } else {
+                    warn!("Dropping frame with checksum mismatch for request {}", mid);
+                }
+                return Ok(Some((mid, Err(checksum_mismatch()))));
             }
         }
         return Ok(None);
     }
 
+    // error frames are sent back empty with the inverted checksum, which the peer reports as mismatch
     fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
         let (mid, msg) = msg;
+        let (msg, failed) = match msg {
+            Ok(msg) => (msg, false),
+            Err(_) => (Bytes::new(), true)
+        };
         let len = msg.len();
-        let mut mid_bytes = [0u8; 8];
-        let mut len_bytes = [0u8; 8];
-        LittleEndian::write_u64(&mut mid_bytes, mid as u64);
-        LittleEndian::write_u64(&mut len_bytes, len as u64);
-        buf.reserve_exact(len + 8 * 2);
-        buf.extend_from_slice(&mid_bytes);
-        buf.extend_from_slice(&len_bytes);
-        buf.extend_from_slice(msg.as_slice());
+        if len > self.max_frame_size {
+            return Err(frame_too_large(len as u64, self.max_frame_size));
+        }
+        let mut header = [0u8; 16];
+        LittleEndian::write_u64(&mut header[..8], mid as u64);
+        LittleEndian::write_u64(&mut header[8..], len as u64);
+        buf.reserve_exact(len + 8 * 2 + 4);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(&msg);
+        if self.checksum {
+            let mut sum = checksum(&header, &msg);
+            if failed {
+                sum = !sum;
+            }
+            let mut sum_bytes = [0u8; 4];
+            LittleEndian::write_u32(&mut sum_bytes, sum);
+            buf.extend_from_slice(&sum_bytes);
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encoded(codec: &mut BytesCodec, len: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        codec.encode((1, Ok(Bytes::from(vec![7u8; len]))), &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn frame_size_limit() {
+        let mut codec = BytesCodec::new(16, false);
+        let (mid, data) = codec.decode(&mut EasyBuf::from(encoded(&mut codec, 16))).unwrap().unwrap();
+        assert_eq!(mid, 1);
+        assert_eq!(data.unwrap().len(), 16);
+        let oversized = encoded(&mut BytesCodec::new(17, false), 17);
+        assert_eq!(codec.decode(&mut EasyBuf::from(oversized)).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let mut buf = Vec::new();
+        assert!(codec.encode((1, Ok(Bytes::from(vec![0u8; 16]))), &mut buf).is_ok());
+        assert!(codec.encode((1, Ok(Bytes::from(vec![0u8; 17]))), &mut buf).is_err());
+    }
+
+    #[test]
+    fn checksum_detects_flipped_byte() {
+        let mut codec = BytesCodec::new(DEFAULT_MAX_FRAME_SIZE, true);
+        let frame = encoded(&mut codec, 32);
+        let (_, data) = codec.decode(&mut EasyBuf::from(frame.clone())).unwrap().unwrap();
+        assert_eq!(data.unwrap(), vec![7u8; 32]);
+        for pos in vec![0, 20, frame.len() - 1] {
+            let mut corrupted = frame.clone();
+            corrupted[pos] ^= 0x10;
+            let (_, data) = codec.decode(&mut EasyBuf::from(corrupted)).unwrap().unwrap();
+            assert!(is_checksum_mismatch(&data.err().unwrap()));
+        }
+        // mismatch reported back by the peer
+        let mut buf = Vec::new();
+        codec.encode((1, Err(checksum_mismatch())), &mut buf).unwrap();
+        let (mid, data) = codec.decode(&mut EasyBuf::from(buf)).unwrap().unwrap();
+        assert_eq!(mid, 1);
+        assert!(is_checksum_mismatch(&data.err().unwrap()));
+    }
+}
@@ -5,10 +5,49 @@ pub mod server;
 pub mod proto;
 pub mod client;
 pub mod shortcut;
+pub mod tls;
+pub mod auth;
+pub mod events;
 
 pub static STANDALONE_ADDRESS: &'static str = "STANDALONE";
+pub static UNIX_SCHEME: &'static str = "unix://";
 
 lazy_static! {
     pub static ref STANDALONE_ADDRESS_STRING: String = String::from(STANDALONE_ADDRESS);
     pub static ref STANDALONE_SERVER_ID: u64 = hash_str(&STANDALONE_ADDRESS_STRING);
-}
\ No newline at end of file
+}
+
+// path of addresses like unix:///path/to.sock. there is no TLS on unix sockets,
+// servers and clients with TLS configured fail on them
+pub fn unix_socket_path(address: &str) -> Option<&str> {
+    if address.starts_with(UNIX_SCHEME) {
+        Some(&address[UNIX_SCHEME.len()..])
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TransportOptions {
+    Plain,
+    // a node serving and calling peers over TLS needs both configs
+    Tls {
+        acceptor: Option<tls::AcceptorConfig>,
+        connector: Option<tls::ConnectorConfig>,
+    },
+}
+
+impl TransportOptions {
+    pub fn acceptor(&self) -> Option<&tls::AcceptorConfig> {
+        match self {
+            &TransportOptions::Tls { acceptor: Some(ref config), .. } => Some(config),
+            _ => None
+        }
+    }
+    pub fn connector(&self) -> Option<&tls::ConnectorConfig> {
+        match self {
+            &TransportOptions::Tls { connector: Some(ref config), .. } => Some(config),
+            _ => None
+        }
+    }
+}
@@ -4,29 +4,38 @@ use std::io::{self, ErrorKind, Write};
 use tokio_proto::multiplex::{ServerProto, ClientProto};
 use tokio_core::io::{Io, Framed};
 
-use tcp::framed::BytesCodec;
+use tcp::framed::{BytesCodec, Frame};
 
-pub struct BytesServerProto;
-pub struct BytesClientProto;
+#[derive(Clone, Copy)]
+pub struct BytesServerProto {
+    pub max_frame_size: usize,
+    pub checksum: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct BytesClientProto {
+    pub max_frame_size: usize,
+    pub checksum: bool,
+}
 
 impl<T: Io + 'static> ServerProto<T> for BytesServerProto {
-    type Request = Vec<u8>;
-    type Response = Vec<u8>;
+    type Request = Frame;
+    type Response = Frame;
     type Transport = Framed<T, BytesCodec>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(BytesCodec))
+        Ok(io.framed(BytesCodec::new(self.max_frame_size, self.checksum)))
     }
 }
 
 impl<T: Io + 'static> ClientProto<T> for BytesClientProto {
-    type Request = Vec<u8>;
-    type Response = Vec<u8>;
+    type Request = Frame;
+    type Response = Frame;
     type Transport = Framed<T, BytesCodec>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(BytesCodec))
+        Ok(io.framed(BytesCodec::new(self.max_frame_size, self.checksum)))
     }
-}
\ No newline at end of file
+}
@@ -1,60 +1,387 @@
 use std::io::{self};
+use std::fmt;
 use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
 
-use tokio_proto::TcpServer;
+use tokio_proto::BindServer;
+use tokio_core::io::Io;
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::TcpListener;
+use tokio_uds::UnixListener;
 use tokio_service::{Service, NewService};
-use futures::{future, Future, BoxFuture};
+use tokio_tls::TlsAcceptorExt;
+use futures::{future, Future, Stream, BoxFuture};
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+use num_cpus;
+use bytes::Bytes;
+use parking_lot::Mutex;
 
 use tcp::proto::BytesServerProto;
-use tcp::shortcut;
-use super::STANDALONE_ADDRESS;
+use tcp::framed::{DEFAULT_MAX_FRAME_SIZE, Frame};
+use tcp::{auth, shortcut, tls};
+use tcp::events::{ConnectionEvents, Watched};
+use super::{STANDALONE_ADDRESS, TransportOptions, unix_socket_path};
 
-pub type ServerCallback = Box<Fn(Vec<u8>) -> Vec<u8> + Send + Sync>;
+pub type ServerCallback = Box<Fn(Bytes) -> Bytes + Send + Sync>;
+pub type ShutdownSignal = oneshot::Receiver<()>;
 
+static SHUTDOWN_GRACE_MS: u64 = 1000;
+static DISPATCH_THREADS_PER_CPU: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    pub transport: TransportOptions,
+    // connections sending larger frames are closed
+    pub max_frame_size: usize,
+    // CRC32 on frames, clients have to enable it as well
+    pub checksum: bool,
+    // counts requests dropped for checksum mismatch, shared by clones of the options
+    pub corrupted_frames: Arc<AtomicUsize>,
+    // connections have to prove the token on connect, see tcp::auth. shortcut calls are not checked
+    pub auth_token: Option<String>,
+    // connect and disconnect of authenticated tcp connections, shared by clones of the options
+    pub events: Arc<ConnectionEvents>,
+    // threads callbacks run on, for the pool started by the first server with the options
+    pub dispatch_threads: usize,
+    // shared by clones of the options, listeners of one rpc server dispatch on the same threads
+    pub dispatch_pool: Arc<DispatchPool>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            transport: TransportOptions::Plain,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            checksum: false,
+            corrupted_frames: Arc::new(AtomicUsize::new(0)),
+            auth_token: None,
+            events: ConnectionEvents::new(),
+            dispatch_threads: num_cpus::get() * DISPATCH_THREADS_PER_CPU,
+            dispatch_pool: DispatchPool::new(),
+        }
+    }
+}
+
+// the pool callbacks run in, started on first use and kept for the servers after
+pub struct DispatchPool {
+    pool: Mutex<Option<CpuPool>>,
+}
+
+impl DispatchPool {
+    pub fn new() -> Arc<DispatchPool> {
+        Arc::new(DispatchPool {
+            pool: Mutex::new(None),
+        })
+    }
+    fn get(&self, threads: usize) -> CpuPool {
+        let mut pool = self.pool.lock();
+        if pool.is_none() {
+            *pool = Some(CpuPool::new(threads));
+        }
+        pool.as_ref().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for DispatchPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DispatchPool {{ started: {} }}", self.pool.lock().is_some())
+    }
+}
+
+// callbacks run in the pool, so slow requests on one connection do not block the others
 pub struct Server {
-    callback: Arc<ServerCallback>
+    callback: Arc<ServerCallback>,
+    in_flight: Arc<AtomicUsize>,
+    corrupted_frames: Arc<AtomicUsize>,
+    pool: CpuPool,
 }
 
 pub struct NewServer {
-    callback: Arc<ServerCallback>
+    callback: Arc<ServerCallback>,
+    reject: Option<Arc<ServerCallback>>,
+    in_flight: Arc<AtomicUsize>,
+    corrupted_frames: Arc<AtomicUsize>,
+    events: Arc<ConnectionEvents>,
+    pool: CpuPool,
 }
 
 impl Service for Server {
-    type Request = Vec<u8>;
-    type Response = Vec<u8>;
+    type Request = Frame;
+    type Response = Frame;
     type Error = io::Error;
-    type Future = BoxFuture<Vec<u8>, io::Error>;
+    type Future = BoxFuture<Frame, io::Error>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        future::finished((self.callback) (req)).boxed()
+        let req = match req {
+            Ok(req) => req,
+            Err(e) => {
+                // reply the error so the client fails the request instead of waiting for it
+                self.corrupted_frames.fetch_add(1, Ordering::Relaxed);
+                return future::finished(Err(e)).boxed();
+            }
+        };
+        let callback = self.callback.clone();
+        let in_flight = InFlight::new(&self.in_flight);
+        self.pool.spawn_fn(move || {
+            Ok::<Frame, io::Error>(Ok(callback(req)))
+        }).then(move |res| {
+            // the response is handed to the connection on the reactor, it is not in flight anymore
+            drop(in_flight);
+            res
+        }).boxed()
+    }
+}
+
+// counts the request until its response future resolved or is dropped with the connection
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::Relaxed);
+        InFlight(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
 impl NewService for NewServer {
 
-    type Request = Vec<u8>;
-    type Response = Vec<u8>;
+    type Request = Frame;
+    type Response = Frame;
     type Error = io::Error;
     type Instance = Server;
 
     fn new_service(&self) -> io::Result<Self::Instance> {
         Ok(Server{
-          callback: self.callback.clone()
+          callback: self.callback.clone(),
+          in_flight: self.in_flight.clone(),
+          corrupted_frames: self.corrupted_frames.clone(),
+          pool: self.pool.clone(),
         })
     }
 }
 
+impl NewServer {
+    // frames of connections failed authentication go to the reject callback, or the connection is closed
+    fn authenticated_service(&self, authenticated: bool) -> Option<Server> {
+        let callback = if authenticated {
+            self.callback.clone()
+        } else {
+            match self.reject {
+                Some(ref reject) => reject.clone(),
+                None => return None
+            }
+        };
+        Some(Server {
+            callback: callback,
+            in_flight: self.in_flight.clone(),
+            corrupted_frames: self.corrupted_frames.clone(),
+            pool: self.pool.clone(),
+        })
+    }
+}
+
+// connections without peer address are from unix sockets, they are not watched
+fn bind_connection<S>(
+    handle: &Handle, proto: BytesServerProto, socket: S, service: Server,
+    peer: Option<SocketAddr>, events: &Arc<ConnectionEvents>
+) where S: Io + 'static {
+    match peer {
+        Some(peer) => proto.bind_server(handle, Watched::new(socket, peer, events), service),
+        None => proto.bind_server(handle, socket, service)
+    }
+}
+
+// the socket file left by a crashed server is removed, a socket still accepting or any
+// other file at the path is not
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path)));
+    }
+    fs::remove_file(path)
+}
+
+fn serve_connection<S>(
+    handle: &Handle, proto: BytesServerProto, socket: S, peer: Option<SocketAddr>,
+    new_server: &Rc<NewServer>, auth_token: &Option<String>
+) where S: Io + 'static {
+    let token = match *auth_token {
+        Some(ref token) => token,
+        None => {
+            if let Some(service) = new_server.authenticated_service(true) {
+                bind_connection(handle, proto, socket, service, peer, &new_server.events);
+            }
+            return;
+        }
+    };
+    let handle_ref = handle.clone();
+    let new_server = new_server.clone();
+    handle.spawn(auth::accept(socket, token)
+        .map(move |(socket, authenticated)| {
+            match new_server.authenticated_service(authenticated) {
+                // rejected connections are not reported
+                Some(service) => if authenticated {
+                    bind_connection(&handle_ref, proto, socket, service, peer, &new_server.events)
+                } else {
+                    proto.bind_server(&handle_ref, socket, service)
+                },
+                None => debug!("Closing connection failed authentication")
+            }
+        })
+        .map_err(|e| {
+            debug!("Authentication handshake failed, {:?}", e);
+        }));
+}
+
 impl Server {
     pub fn new(addr: &String, callback: ServerCallback) {
+        if let Err(e) = Server::new_with_shutdown(addr, callback, None) {
+            panic!("Cannot start server on {}, {:?}", addr, e);
+        }
+    }
+    // serve until the shutdown signal fires, dropping the sender will not stop the server.
+    // after the signal, new connections are refused and in-flight requests are given
+    // a bounded grace period to finish before this function returns
+    pub fn new_with_shutdown(addr: &String, callback: ServerCallback, shutdown: Option<ShutdownSignal>) -> io::Result<()> {
+        Server::new_with_transport(addr, callback, shutdown, &TransportOptions::Plain)
+    }
+    pub fn new_tls(addr: &String, acceptor_config: &tls::AcceptorConfig, callback: ServerCallback) -> io::Result<()> {
+        let transport = TransportOptions::Tls {
+            acceptor: Some(acceptor_config.clone()),
+            connector: None
+        };
+        Server::new_with_transport(addr, callback, None, &transport)
+    }
+    pub fn new_with_transport(
+        addr: &String, callback: ServerCallback,
+        shutdown: Option<ShutdownSignal>, transport: &TransportOptions
+    ) -> io::Result<()> {
+        Server::new_with_options(addr, callback, shutdown, &ServerOptions {
+            transport: transport.clone(),
+            .. ServerOptions::default()
+        })
+    }
+    pub fn new_with_options(
+        addr: &String, callback: ServerCallback,
+        shutdown: Option<ShutdownSignal>, options: &ServerOptions
+    ) -> io::Result<()> {
+        Server::new_with_auth(addr, callback, None, shutdown, options)
+    }
+    // with auth token set, frames of unauthenticated connections are answered by the reject callback.
+    // without the reject callback those connections are closed
+    pub fn new_with_auth(
+        addr: &String, callback: ServerCallback, reject: Option<ServerCallback>,
+        shutdown: Option<ShutdownSignal>, options: &ServerOptions
+    ) -> io::Result<()> {
+        let proto = BytesServerProto {
+            max_frame_size: options.max_frame_size,
+            checksum: options.checksum,
+        };
+        if options.transport.acceptor().is_some() && unix_socket_path(addr).is_some() {
+            // serving plain would leave the peers thinking they are encrypted
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("TLS is not supported on unix socket {}", addr)));
+        }
+        if let Some(path) = unix_socket_path(addr) {
+            // before taking the shortcut of a server still serving on it
+            remove_stale_socket(path)?;
+        }
+        let acceptor = match options.transport.acceptor() {
+            Some(config) => Some(tls::acceptor(config)?),
+            None => None
+        };
         let callback_ref = Arc::new(callback);
         shortcut::register_server(addr, &callback_ref);
-        let new_server = NewServer {
-            callback: callback_ref
+        if addr.eq(&STANDALONE_ADDRESS) {
+            if let Some(signal) = shutdown {
+                if signal.wait().is_ok() {
+                    shortcut::unregister_server(addr);
+                }
+            }
+            return Ok(());
+        }
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let new_server = Rc::new(NewServer {
+            callback: callback_ref,
+            reject: reject.map(Arc::new),
+            in_flight: in_flight.clone(),
+            corrupted_frames: options.corrupted_frames.clone(),
+            events: options.events.clone(),
+            pool: options.dispatch_pool.get(options.dispatch_threads),
+        });
+        let auth_token = options.auth_token.clone();
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let unix_path = unix_socket_path(addr);
+        let connections: Box<Future<Item = (), Error = io::Error>> = match unix_path {
+            Some(path) => {
+                let listener = UnixListener::bind(path, &handle)?;
+                Box::new(listener.incoming().for_each(move |(socket, _)| {
+                    serve_connection(&handle, proto, socket, None, &new_server, &auth_token);
+                    Ok(())
+                }))
+            },
+            None => {
+                let socket_addr: SocketAddr = addr.parse().unwrap();
+                let listener = TcpListener::bind(&socket_addr, &handle)?;
+                Box::new(listener.incoming().for_each(move |(socket, peer)| {
+                    match acceptor {
+                        Some(ref acceptor) => {
+                            // finish the handshake without blocking other connections
+                            let handle_ref = handle.clone();
+                            let new_server = new_server.clone();
+                            let auth_token = auth_token.clone();
+                            handle.spawn(acceptor.accept_async(socket)
+                                .map(move |socket| {
+                                    // the token is proven inside of the encrypted stream
+                                    serve_connection(&handle_ref, proto, socket, Some(peer), &new_server, &auth_token);
+                                })
+                                .map_err(move |e| {
+                                    warn!("TLS handshake with {} failed, {:?}", peer, tls::handshake_error(e));
+                                }));
+                        },
+                        None => {
+                            serve_connection(&handle, proto, socket, Some(peer), &new_server, &auth_token);
+                        }
+                    }
+                    Ok(())
+                }))
+            }
         };
-        if !addr.eq(&STANDALONE_ADDRESS) {
-            let socket_addr: SocketAddr = addr.parse().unwrap();
-            TcpServer::new(BytesServerProto, socket_addr).serve(new_server);
+        let served = match shutdown {
+            Some(signal) => {
+                let signal = signal
+                    .or_else(|_| future::empty()) // handle dropped, serve forever
+                    .map_err(|_: oneshot::Canceled| io::Error::new(io::ErrorKind::Other, "Shutdown signal failed"));
+                core.run(connections.select(signal).map(|_| ()).map_err(|(e, _)| e))
+            },
+            None => core.run(connections)
+        };
+        // the listener has been dropped with the accepting future
+        shortcut::unregister_server(addr);
+        if let Some(path) = unix_path {
+            fs::remove_file(path).ok();
+        }
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_GRACE_MS);
+        while in_flight.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            core.turn(Some(Duration::from_millis(10)));
         }
+        core.turn(Some(Duration::from_millis(10))); // flush responses
+        served
     }
-}
\ No newline at end of file
+}
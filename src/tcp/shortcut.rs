@@ -5,6 +5,7 @@ use bifrost_hasher::hash_str;
 use parking_lot::RwLock;
 use tcp::server::ServerCallback;
 use futures::{future, BoxFuture};
+use bytes::Bytes;
 
 lazy_static! {
     pub static ref TCP_CALLBACKS: RwLock<BTreeMap<u64, Arc<ServerCallback>>> = RwLock::new(BTreeMap::new());
@@ -16,14 +17,20 @@ pub fn register_server(server_address: &String, callback: &Arc<ServerCallback>)
     servers_cbs.insert(server_id, callback.clone());
 }
 
-pub fn call_async(server_id: u64, data: Vec<u8>) -> BoxFuture<Vec<u8>, Error> {
+pub fn unregister_server(server_address: &String) {
+    let mut servers_cbs = TCP_CALLBACKS.write();
+    let server_id = hash_str(server_address);
+    servers_cbs.remove(&server_id);
+}
+
+pub fn call_async(server_id: u64, data: Bytes) -> BoxFuture<Bytes, Error> {
     Box::new(match call(server_id, data) {
         Ok(data) => future::finished(data),
         Err(e) => future::err(e)
     })
 }
 
-pub fn call(server_id: u64, data: Vec<u8>) -> Result<Vec<u8>> {
+pub fn call(server_id: u64, data: Bytes) -> Result<Bytes> {
     let callback = {
         let server_cbs = TCP_CALLBACKS.read();
         match server_cbs.get(&server_id) {
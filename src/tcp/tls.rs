@@ -0,0 +1,42 @@
+use std::io;
+use native_tls::{self, TlsAcceptor, TlsConnector, Pkcs12, Certificate};
+
+// server identity in PKCS#12 format
+#[derive(Clone, Debug)]
+pub struct AcceptorConfig {
+    pub pkcs12: Vec<u8>,
+    pub password: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectorConfig {
+    // the name to verify server certificates against
+    pub domain: String,
+    // extra trusted roots in DER format, for self-signed certificates
+    pub root_certificates: Vec<Vec<u8>>,
+}
+
+fn config_error(e: native_tls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid TLS config, {}", e))
+}
+
+// certificate verification failures end up here
+pub fn handshake_error(e: io::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("TLS handshake failed, {}", e))
+}
+
+pub fn acceptor(config: &AcceptorConfig) -> io::Result<TlsAcceptor> {
+    let identity = Pkcs12::from_der(&config.pkcs12, &config.password).map_err(config_error)?;
+    TlsAcceptor::builder(identity)
+        .and_then(|builder| builder.build())
+        .map_err(config_error)
+}
+
+pub fn connector(config: &ConnectorConfig) -> io::Result<TlsConnector> {
+    let mut builder = TlsConnector::builder().map_err(config_error)?;
+    for der in &config.root_certificates {
+        let cert = Certificate::from_der(der).map_err(config_error)?;
+        builder.add_root_certificate(cert).map_err(config_error)?;
+    }
+    builder.build().map_err(config_error)
+}
@@ -15,4 +15,10 @@ pub fn deserialize<'a, T>(data: & 'a Vec<u8>) -> T
         Ok(data) => data,
         Err(e) => {panic!("Cannot deserialize: {:?}, data len: {}", e, data.len())}
     }
-}
\ No newline at end of file
+}
+
+// for bytes from remote, which may not be produced by the same version
+pub fn try_deserialize<'a, T>(data: & 'a [u8]) -> Option<T>
+    where T: serde::Deserialize<'a> {
+    bincode::deserialize(data).ok()
+}
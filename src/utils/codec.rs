@@ -0,0 +1,38 @@
+use bincode;
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CodecError {
+    Encode(String),
+    Decode(String),
+}
+
+// wire format for service and state machine functions arguments and returns.
+// implement this to plug in other formats, all peers have to agree on the codec
+pub trait WireCodec {
+    fn encode<T>(obj: &T) -> Vec<u8> where T: Serialize;
+    fn decode<'a, T>(data: &'a [u8]) -> Result<T, CodecError> where T: Deserialize<'a>;
+    // for encoding on the server side, where panics would take the server down
+    fn try_encode<T>(obj: &T) -> Result<Vec<u8>, CodecError> where T: Serialize {
+        Ok(Self::encode(obj))
+    }
+}
+
+pub struct Bincode;
+
+impl WireCodec for Bincode {
+    fn encode<T>(obj: &T) -> Vec<u8> where T: Serialize {
+        match Self::try_encode(obj) {
+            Ok(data) => data,
+            Err(e) => {panic!("Cannot serialize: {:?}", e)}
+        }
+    }
+    fn try_encode<T>(obj: &T) -> Result<Vec<u8>, CodecError> where T: Serialize {
+        bincode::serialize(obj, bincode::Infinite).map_err(|e| CodecError::Encode(format!("{:?}", e)))
+    }
+    fn decode<'a, T>(data: &'a [u8]) -> Result<T, CodecError> where T: Deserialize<'a> {
+        bincode::deserialize(data).map_err(|e| CodecError::Decode(format!("{:?}, data len: {}", e, data.len())))
+    }
+}
+
+pub type DefaultCodec = Bincode;
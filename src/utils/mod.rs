@@ -3,4 +3,6 @@ pub mod u8vec;
 #[macro_use]
 pub mod bindings;
 pub mod math;
-pub mod bincode;
\ No newline at end of file
+pub mod bincode;
+pub mod codec;
+pub mod vclock;
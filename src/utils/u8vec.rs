@@ -1,14 +1,50 @@
 use byteorder::{ByteOrder, LittleEndian};
+use bytes::Bytes;
 
 pub fn prepend_u64 (num: u64, vec: Vec<u8>) -> Vec<u8> {
-    let mut s_id_vec = [0u8; 8].to_vec();
-    LittleEndian::write_u64(&mut s_id_vec, num);
-    let data_iter = s_id_vec.into_iter().chain(vec.into_iter());
-    data_iter.collect()
+    let mut data = Vec::with_capacity(8 + vec.len());
+    let mut num_bytes = [0u8; 8];
+    LittleEndian::write_u64(&mut num_bytes, num);
+    data.extend_from_slice(&num_bytes);
+    data.extend_from_slice(&vec);
+    data
 }
 
-pub fn extract_u64_head(vec: Vec<u8>) -> (u64, Vec<u8>) {
+pub fn extract_u64_head(mut vec: Vec<u8>) -> (u64, Vec<u8>) {
     let num = LittleEndian::read_u64(&vec);
-    let vec: Vec<u8> = vec.into_iter().skip(8).collect();
+    // moves the rest in place instead of reallocating
+    vec.drain(..8);
     (num, vec)
-}
\ No newline at end of file
+}
+
+// the rest shares the buffer, nothing is copied
+pub fn split_u64_head(mut data: Bytes) -> (u64, Bytes) {
+    let head = data.split_to(8);
+    (LittleEndian::read_u64(&head), data)
+}
+
+// seven bits in each byte from the lowest, the highest bit is set on all but the last one
+pub fn write_varint(mut num: u64, data: &mut Vec<u8>) {
+    while num >= 0x80 {
+        data.push((num as u8) | 0x80);
+        num >>= 7;
+    }
+    data.push(num as u8);
+}
+
+// answered none when the data ends before the number does or it does not fit in u64
+pub fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut num = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = match data.get(*pos) {
+            Some(byte) => *byte as u64,
+            None => return None
+        };
+        if shift == 63 && byte > 1 {return None;}
+        *pos += 1;
+        num |= (byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {return Some(num);}
+        shift += 7;
+    }
+}
@@ -0,0 +1,36 @@
+// vector clocks of u64 server ids, like the hash_str of server addresses, and values versioned by
+// them. encoded clocks are compact enough for headers, see VectorClock::encode
+pub use vector_clock::{Relation, ServerVectorClock, StandardVectorClock as VectorClock};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub clock: VectorClock,
+}
+
+impl <T> Versioned<T> {
+    pub fn new(value: T, clock: VectorClock) -> Versioned<T> {
+        Versioned {
+            value: value,
+            clock: clock,
+        }
+    }
+    // a new version of the value written by the server, after this one
+    pub fn update(&mut self, server: u64, value: T) {
+        self.clock.inc(server);
+        self.value = value;
+    }
+    // the later one of the two versions. values of concurrent ones are resolved by the hook, the
+    // clock of either answer is after both of them
+    pub fn reconcile<F>(self, other: Versioned<T>, resolve: F) -> Versioned<T>
+        where F: FnOnce(T, T) -> T {
+        match self.clock.relation(&other.clock) {
+            Relation::Before => other,
+            Relation::After | Relation::Equal => self,
+            Relation::Concurrent => {
+                let clock = self.clock.merge(&other.clock);
+                Versioned::new(resolve(self.value, other.value), clock)
+            }
+        }
+    }
+}
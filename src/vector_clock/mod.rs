@@ -2,6 +2,8 @@ use std::collections::{BTreeMap};
 use std::cmp::Ordering;
 use parking_lot::RwLock;
 use bifrost_hasher::hash_str;
+use utils::codec::CodecError;
+use utils::u8vec::{write_varint, read_varint};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum Relation {
@@ -58,6 +60,11 @@ impl <S: Ord + Eq + Copy>VectorClock<S> {
         self.clone()
     }
 
+    // the counter of the server, 0 for those it never heard of
+    pub fn get(&self, server: &S) -> u64 {
+        *self.map.get(server).unwrap_or(&0)
+    }
+
     pub fn happened_before(&self, clock_b: &VectorClock<S>) -> bool {
         let mut a_lt_b = false;
         for (server, ai) in self.map.iter() {
@@ -109,6 +116,12 @@ impl <S: Ord + Eq + Copy>VectorClock<S> {
             if *ba < *bc {*ba = *bc}
         }
     }
+    // the clock with the larger counter of the two for each server, after both of them
+    pub fn merge(&self, clock_b: &VectorClock<S>) -> VectorClock<S> {
+        let mut merged = self.clone();
+        merged.merge_with(clock_b);
+        merged
+    }
     pub fn learn_from(&mut self, clock_b: &VectorClock<S>) {
         // learn_from only insert missing servers into the clock
         for (server, bc) in clock_b.map.iter() {
@@ -117,6 +130,44 @@ impl <S: Ord + Eq + Copy>VectorClock<S> {
     }
 }
 
+impl VectorClock<u64> {
+    // the number of servers, then each server with its counter in the order of servers, all of them
+    // varint encoded. small enough for headers of rpc, clocks equal are encoded the same
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_varint(self.map.len() as u64, &mut data);
+        for (server, counter) in self.map.iter() {
+            write_varint(*server, &mut data);
+            write_varint(*counter, &mut data);
+        }
+        data
+    }
+    pub fn decode(data: &[u8]) -> Result<StandardVectorClock, CodecError> {
+        let mut pos = 0;
+        let mut clock = VectorClock::new();
+        let servers = read_varint(data, &mut pos).ok_or(CodecError::Decode(String::from("no number of servers")))?;
+        let mut last_server = None;
+        for _ in 0..servers {
+            let server = read_varint(data, &mut pos).ok_or(CodecError::Decode(String::from("no server")))?;
+            let counter = read_varint(data, &mut pos).ok_or(CodecError::Decode(String::from("no counter")))?;
+            // clocks are only encoded in order, without servers they never heard of
+            match last_server {
+                Some(last) if last >= server => return Err(CodecError::Decode(format!("server {} out of order", server))),
+                _ => {}
+            }
+            if counter == 0 {
+                return Err(CodecError::Decode(format!("no counter for server {}", server)));
+            }
+            clock.map.insert(server, counter);
+            last_server = Some(server);
+        }
+        if pos != data.len() {
+            return Err(CodecError::Decode(format!("{} bytes after the clock", data.len() - pos)));
+        }
+        Ok(clock)
+    }
+}
+
 pub struct ServerVectorClock {
     server: u64,
     clock: RwLock<VectorClock<u64>>
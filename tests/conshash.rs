@@ -5,22 +5,43 @@ use bifrost::raft::state_machine::callback::client::SubscriptionService;
 use bifrost::membership::server::Membership;
 use bifrost::membership::member::MemberService;
 use bifrost::membership::client::ObserverClient;
-use bifrost::conshash::{ConsistentHashing, CHError};
-use bifrost::conshash::weights::Weights;
+use bifrost::membership::FailureDetection;
+use bifrost::conshash::{ConsistentHashing, CHError, RingChange, Action, Ring, Transfer, DEFAULT_VIRTUAL_NODES, WEIGHT_TAG};
+use bifrost_hasher::hash_str;
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::HashMap;
+use parking_lot::Mutex;
+use rand;
 
-use raft::wait;
+use raft::{wait, options};
+
+fn membership_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let raft_service = RaftService::new(Options {
+        service_id: 0,
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(addr);
+    Membership::new(&server, &raft_service);
+    server.register_service(0, &raft_service);
+    Server::listen_and_resume(&server);
+    RaftService::start(&raft_service);
+    raft_service.bootstrap();
+    (raft_service, server)
+}
+
+// within a fifth of the expected count, virtual nodes do not split the ring exactly by weight
+fn assert_near(count: u64, expected: u64) {
+    assert!(count > expected * 4 / 5 && count < expected * 6 / 5, "{} keys, expected about {}", count, expected);
+}
 
 #[test]
 fn primary() {
     let addr = String::from("127.0.0.1:2200");
     let raft_service = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: addr.clone(),
         service_id: 0,
+        ..options(Storage::Default(), &addr)
     });
     let server = Server::new(&addr);
     let heartbeat_service = Membership::new(&server, &raft_service);
@@ -64,8 +85,6 @@ fn primary() {
 
     member1_svr.join_group(&group_3).unwrap().unwrap();
 
-    let weight_service = Weights::new(&raft_service);
-
     let ch1 = ConsistentHashing::new(&group_1, &wild_raft_client).unwrap();
     let ch2 = ConsistentHashing::new(&group_2, &wild_raft_client).unwrap();
     let ch3 = ConsistentHashing::new(&group_3, &wild_raft_client).unwrap();
@@ -73,40 +92,33 @@ fn primary() {
     ch1.set_weight(&server_1, 1);
     ch1.set_weight(&server_2, 2);
     ch1.set_weight(&server_3, 3);
-
-    ch2.set_weight(&server_1, 1);
-    ch2.set_weight(&server_2, 1);
-
-    ch3.set_weight(&server_1, 1);
+    // weights are of the members, in every group of them
 
     ch1.init_table().unwrap();
     ch2.init_table().unwrap();
     ch3.init_table().unwrap();
 
     let ch1_server_node_changes_count = Arc::new(AtomicUsize::new(0));
-    let ch1_server_node_changes_count_clone = Arc::new(AtomicUsize::new(0)).clone();
-    ch1.watch_server_nodes_range_changed(&server_2, move |r| {
-        println!("---------------->");
+    let ch1_server_node_changes_count_clone = ch1_server_node_changes_count.clone();
+    ch1.watch_server_nodes_range_changed(&server_2, move |_| {
         ch1_server_node_changes_count_clone.fetch_add(1, Ordering::Relaxed);
     });
 
     let ch2_server_node_changes_count = Arc::new(AtomicUsize::new(0));
-    let ch2_server_node_changes_count_clone = Arc::new(AtomicUsize::new(0)).clone();
-    ch2.watch_server_nodes_range_changed(&server_2, move |r| {
-        println!("---------------->");
+    let ch2_server_node_changes_count_clone = ch2_server_node_changes_count.clone();
+    ch2.watch_server_nodes_range_changed(&server_2, move |_| {
         ch2_server_node_changes_count_clone.fetch_add(1, Ordering::Relaxed);
     });
 
     let ch3_server_node_changes_count = Arc::new(AtomicUsize::new(0));
-    let ch3_server_node_changes_count_clone = Arc::new(AtomicUsize::new(0)).clone();
-    ch3.watch_server_nodes_range_changed(&server_2, move |r| {
-        println!("---------------->");
+    let ch3_server_node_changes_count_clone = ch3_server_node_changes_count.clone();
+    ch3.watch_server_nodes_range_changed(&server_2, move |_| {
         ch3_server_node_changes_count_clone.fetch_add(1, Ordering::Relaxed);
     });
 
-    assert_eq!(ch1.nodes_count(), 2047);
-    assert_eq!(ch2.nodes_count(), 2048);
-    assert_eq!(ch3.nodes_count(), 2048);
+    assert_eq!(ch1.nodes_count() as u64, 6 * DEFAULT_VIRTUAL_NODES);
+    assert_eq!(ch2.nodes_count() as u64, 3 * DEFAULT_VIRTUAL_NODES);
+    assert_eq!(ch3.nodes_count() as u64, DEFAULT_VIRTUAL_NODES);
 
     let mut ch_1_mapping: HashMap<String, u64> = HashMap::new();
     for i in 0..30000 {
@@ -114,9 +126,9 @@ fn primary() {
         let server = ch1.get_server_by_string(&k).unwrap();
         *ch_1_mapping.entry(server.clone()).or_insert(0) += 1;
     }
-    assert_eq!(ch_1_mapping.get(&server_1).unwrap(), &5060);
-    assert_eq!(ch_1_mapping.get(&server_2).unwrap(), &9777);
-    assert_eq!(ch_1_mapping.get(&server_3).unwrap(), &15163); // hard coded due to constant
+    assert_near(*ch_1_mapping.get(&server_1).unwrap(), 5000);
+    assert_near(*ch_1_mapping.get(&server_2).unwrap(), 10000);
+    assert_near(*ch_1_mapping.get(&server_3).unwrap(), 15000);
 
     let mut ch_2_mapping: HashMap<String, u64> = HashMap::new();
     for i in 0..30000 {
@@ -124,8 +136,8 @@ fn primary() {
         let server = ch2.get_server_by_string(&k).unwrap();
         *ch_2_mapping.entry(server.clone()).or_insert(0) += 1;
     }
-    assert_eq!(ch_2_mapping.get(&server_1).unwrap(), &14757);
-    assert_eq!(ch_2_mapping.get(&server_2).unwrap(), &15243);
+    assert_near(*ch_2_mapping.get(&server_1).unwrap(), 10000);
+    assert_near(*ch_2_mapping.get(&server_2).unwrap(), 20000);
 
     let mut ch_3_mapping: HashMap<String, u64> = HashMap::new();
     for i in 0..30000 {
@@ -143,8 +155,9 @@ fn primary() {
         let server = ch1.get_server_by_string(&k).unwrap();
         *ch_1_mapping.entry(server.clone()).or_insert(0) += 1;
     }
-    assert_eq!(ch_1_mapping.get(&server_2).unwrap(), &11849);
-    assert_eq!(ch_1_mapping.get(&server_3).unwrap(), &18151);
+    assert_near(*ch_1_mapping.get(&server_2).unwrap(), 12000);
+    assert_near(*ch_1_mapping.get(&server_3).unwrap(), 18000);
+    assert_eq!(ch_1_mapping.values().sum::<u64>(), 30000);
 
     let mut ch_2_mapping: HashMap<String, u64> = HashMap::new();
     for i in 0..30000 {
@@ -159,9 +172,234 @@ fn primary() {
         assert!(ch3.get_server_by_string(&k).is_none()); // no member
     }
 
-//    wait();
-//    wait();
-//    assert_eq!(ch1_server_node_changes_count.load(Ordering::Relaxed), 1);
-//    assert_eq!(ch2_server_node_changes_count.load(Ordering::Relaxed), 1);
-//    assert_eq!(ch3_server_node_changes_count.load(Ordering::Relaxed), 0);
+    // server 2 took keys of server 1 in the first two groups, it is not in the third
+    assert_eq!(ch1_server_node_changes_count.load(Ordering::Relaxed), 1);
+    assert_eq!(ch2_server_node_changes_count.load(Ordering::Relaxed), 1);
+    assert_eq!(ch3_server_node_changes_count.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn weighted_ring() {
+    let addr = String::from("127.0.0.1:1751");
+    let (_raft_service, server) = membership_service(&addr);
+    let group = String::from("weighted_ring");
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    client.new_group(&group).unwrap().unwrap();
+
+    let interval = FailureDetection::Default().heartbeat_interval;
+    let weight = |w: &str| {
+        let mut meta = HashMap::new();
+        meta.insert(String::from(WEIGHT_TAG), String::from(w));
+        meta
+    };
+    let names = vec!(String::from("heavy"), String::from("medium"), String::from("light"));
+    let members: Vec<_> = vec!(weight("4"), weight("2"), HashMap::new()).iter().zip(names.iter()).map(|(meta, name)| {
+        let member_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+        let member = MemberService::new_with_meta(name, &member_raft_client, meta, interval);
+        member.join_group(&group).unwrap().unwrap();
+        member
+    }).collect();
+
+    let ch = ConsistentHashing::new_client(&group, &raft_client).unwrap();
+    assert_eq!(ch.nodes_count() as u64, 7 * DEFAULT_VIRTUAL_NODES);
+    let mut mapping: HashMap<String, u64> = HashMap::new();
+    for i in 0..70000 {
+        let (id, server) = ch.get_server(&format!("k - {}", i)).unwrap();
+        assert_eq!(id, hash_str(&server));
+        *mapping.entry(server).or_insert(0) += 1;
+    }
+    // members without the tag weigh 1
+    assert_near(*mapping.get(&names[0]).unwrap(), 40000);
+    assert_near(*mapping.get(&names[1]).unwrap(), 20000);
+    assert_near(*mapping.get(&names[2]).unwrap(), 10000);
+
+    // replicas of keys are on different servers, starting from the one they belong to
+    for i in 0..1000 {
+        let key = format!("k - {}", i);
+        let replicas = ch.nodes_for(&key, 2);
+        assert_eq!(replicas.len(), 2);
+        assert_eq!(replicas[0], ch.get_server(&key).unwrap());
+        assert!(replicas[0].0 != replicas[1].0);
+        assert_eq!(ch.nodes_for(&key, 5).len(), 3);
+    }
+    assert!(ch.nodes_for(&String::from("k - 0"), 0).is_empty());
+
+    // weighing more only moves keys to the member
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let changes_ref = changes.clone();
+    ch.on_ring_changed(move |change: &RingChange| changes_ref.lock().push(change.clone()));
+    let before: Vec<u64> = (0..70000).map(|i| ch.get_server(&format!("k - {}", i)).unwrap().0).collect();
+    assert!(ch.set_weight(&names[2], 4));
+    wait();
+    assert_eq!(ch.nodes_count() as u64, 10 * DEFAULT_VIRTUAL_NODES);
+    let light = hash_str(&names[2]);
+    let changes = changes.lock();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].action, Action::Reweighted);
+    assert!(changes[0].moved.iter().all(|range| range.to == Some(light) && range.from != Some(light)));
+    let mut moved = 0;
+    for i in 0..70000 {
+        let id = ch.get_server(&format!("k - {}", i)).unwrap().0;
+        if id != before[i] {
+            assert_eq!(id, light);
+            moved += 1;
+        }
+    }
+    // from a tenth to four tenths of the ring
+    assert_near(moved, 18000);
+    drop(members);
+}
+
+#[test]
+fn minimal_movement() {
+    let addr = String::from("127.0.0.1:1752");
+    let (_raft_service, server) = membership_service(&addr);
+    let group = String::from("minimal_movement");
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    client.new_group(&group).unwrap().unwrap();
+    let members: Vec<_> = (0..10).map(|i| {
+        let member_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+        let member = MemberService::new(&format!("server{}", i), &member_raft_client);
+        member.join_group(&group).unwrap().unwrap();
+        member
+    }).collect();
+
+    let ch = ConsistentHashing::new_with_virtual_nodes(&group, &raft_client, 128).unwrap();
+    ch.init_table().unwrap();
+    assert_eq!(ch.nodes_count(), 10 * 128);
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let changes_ref = changes.clone();
+    ch.on_ring_changed(move |change: &RingChange| changes_ref.lock().push(change.clone()));
+    let keys: Vec<String> = (0..10000).map(|i| format!("k - {}", i)).collect();
+    let before: Vec<u64> = keys.iter().map(|key| ch.get_server(key).unwrap().0).collect();
+
+    let leaving = hash_str(&String::from("server3"));
+    members[3].leave().unwrap().unwrap();
+    wait();
+    assert_eq!(ch.nodes_count(), 9 * 128);
+    let changes = changes.lock();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].action, Action::Left);
+    assert_eq!(changes[0].member.id, leaving);
+    let moved_ranges = &changes[0].moved;
+    assert!(moved_ranges.iter().all(|range| range.from == Some(leaving) && range.to.is_some() && range.to != Some(leaving)));
+
+    // only keys of the member that left moved, and all of them are in the ranges reported
+    let mut moved = 0;
+    for (key, old) in keys.iter().zip(before.iter()) {
+        let new = ch.get_server(key).unwrap().0;
+        let hash = hash_str(key);
+        let in_moved_ranges = moved_ranges.iter().any(|moved| moved.range.contains(hash));
+        assert_eq!(*old == leaving, new != *old);
+        assert_eq!(in_moved_ranges, new != *old);
+        if new != *old {
+            moved += 1;
+        }
+    }
+    assert_near(moved, 1000);
+}
+
+// the transfers cover exactly the hashes whose server changed, with the servers before and after
+fn assert_transfers(old_ring: &Ring, new_ring: &Ring, transfers: &Vec<Transfer>) {
+    for pair in transfers.windows(2) {
+        assert!(pair[0].range.end < pair[1].range.start);
+    }
+    // hashes at the start of every range of both rings, and right before them
+    let mut hashes: Vec<u64> = vec!(0, u64::max_value());
+    for (start, _) in old_ring.nodes().into_iter().chain(new_ring.nodes().into_iter()) {
+        hashes.push(start);
+        hashes.push(start.wrapping_sub(1));
+    }
+    for _ in 0..1000 {
+        hashes.push(rand::random::<u64>());
+    }
+    for hash in hashes {
+        let transfer = transfers.iter().find(|transfer| transfer.range.contains(hash));
+        match (old_ring.server_of(hash), new_ring.server_of(hash), transfer) {
+            (Some(from), Some(to), Some(transfer)) => {
+                assert!(from != to);
+                assert_eq!((transfer.from, transfer.to), (from, to));
+            },
+            (Some(from), Some(to), None) => assert_eq!(from, to),
+            (_, _, transfer) => assert!(transfer.is_none())
+        }
+    }
+}
+
+#[test]
+fn ring_diff() {
+    for _ in 0..50 {
+        let mut servers: Vec<(u64, u64)> = (0..(rand::random::<u64>() % 8 + 1))
+            .map(|_| (rand::random::<u64>(), rand::random::<u64>() % 64 + 1))
+            .collect();
+        let old_ring = Ring::of_servers(&servers);
+        // some leave, some are reweighted and some join
+        servers.retain(|_| rand::random::<u64>() % 4 != 0);
+        for server in servers.iter_mut() {
+            if rand::random::<bool>() {
+                server.1 = rand::random::<u64>() % 64 + 1;
+            }
+        }
+        for _ in 0..(rand::random::<u64>() % 3) {
+            servers.push((rand::random::<u64>(), rand::random::<u64>() % 64 + 1));
+        }
+        let new_ring = Ring::of_servers(&servers);
+        assert_transfers(&old_ring, &new_ring, &ConsistentHashing::diff(&old_ring, &new_ring));
+        assert!(ConsistentHashing::diff(&new_ring, &new_ring).is_empty());
+    }
+    // nothing to hand off from or to an empty ring
+    let ring = Ring::of_servers(&vec!((1, 10), (2, 10)));
+    assert!(ConsistentHashing::diff(&Ring::new(), &ring).is_empty());
+    assert!(ConsistentHashing::diff(&ring, &Ring::new()).is_empty());
+    // transfers between the same servers next to each other are merged
+    let transfers = ConsistentHashing::diff(&Ring::of_servers(&vec!((1, 1))), &Ring::of_servers(&vec!((2, 1))));
+    assert_eq!(transfers.len(), 1);
+    assert_eq!((transfers[0].range.start, transfers[0].range.end), (0, u64::max_value()));
+}
+
+#[test]
+fn rebalance_on_join() {
+    let addr = String::from("127.0.0.1:1753");
+    let (_raft_service, server) = membership_service(&addr);
+    let group = String::from("rebalance_on_join");
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    client.new_group(&group).unwrap().unwrap();
+    let member_of = |name: &str| {
+        let member_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+        let member = MemberService::new(&String::from(name), &member_raft_client);
+        member.join_group(&group).unwrap().unwrap();
+        member
+    };
+    let _members: Vec<_> = (0..4).map(|i| member_of(&format!("server{}", i))).collect();
+
+    let ch = ConsistentHashing::new_with_virtual_nodes(&group, &raft_client, 64).unwrap();
+    ch.init_table().unwrap();
+    let plans = Arc::new(Mutex::new(Vec::new()));
+    let plans_ref = plans.clone();
+    ch.on_rebalance_needed(move |transfers: &Vec<Transfer>| plans_ref.lock().push(transfers.clone()));
+    let old_ring = ch.ring();
+    let keys: Vec<String> = (0..10000).map(|i| format!("k - {}", i)).collect();
+    let before: Vec<u64> = keys.iter().map(|key| ch.get_server(key).unwrap().0).collect();
+
+    let _joined = member_of("server4");
+    wait();
+    let new_ring = ch.ring();
+    let joined = hash_str(&String::from("server4"));
+    let plans = plans.lock();
+    assert_eq!(plans.len(), 1);
+    let transfers = &plans[0];
+    // existing servers only hand off to the one that joined
+    assert!(transfers.iter().all(|transfer| transfer.to == joined && transfer.from != joined));
+    assert_eq!(*transfers, ConsistentHashing::diff(&old_ring, &new_ring));
+    assert_transfers(&old_ring, &new_ring, transfers);
+    for (key, old) in keys.iter().zip(before.iter()) {
+        let moved = ch.get_server(key).unwrap().0 != *old;
+        assert_eq!(transfers.iter().any(|transfer| transfer.range.contains_key(key)), moved);
+    }
 }
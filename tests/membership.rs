@@ -1,23 +1,27 @@
 use bifrost::rpc::*;
 use bifrost::raft::*;
+use bifrost::membership::FailureDetection;
 use bifrost::membership::server::Membership;
 use bifrost::membership::member::MemberService;
-use bifrost::membership::client::ObserverClient;
+use bifrost::membership::client::{ObserverClient, Member, MemberStatus, DepartureReason};
 use bifrost::raft::client::RaftClient;
 use bifrost::raft::state_machine::callback::client::SubscriptionService;
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
 
-use raft::wait;
+use raft::{wait, options};
 
 #[test]
 fn primary() {
     let addr = String::from("127.0.0.1:2100");
     let raft_service = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: addr.clone(),
         service_id: 0,
+        ..options(Storage::Default(), &addr)
     });
     let server = Server::new(&addr);
     let heartbeat_service = Membership::new(&server, &raft_service);
@@ -167,4 +171,331 @@ fn primary() {
     assert_eq!(group_member_left_count.load(Ordering::Relaxed), 2);
     assert_eq!(group_member_online_count.load(Ordering::Relaxed), 0);
     assert_eq!(group_member_offline_count.load(Ordering::Relaxed), 1);
-}
\ No newline at end of file
+}
+
+#[test]
+fn failure_detection() {
+    let addr = String::from("127.0.0.1:1747");
+    let raft_service = RaftService::new(Options {
+        service_id: 0,
+        ..options(Storage::Default(), &addr)
+    });
+    let interval = Duration::from_millis(100);
+    let server = Server::new(&addr);
+    Membership::new_with_detection(&server, &raft_service, FailureDetection {
+        heartbeat_interval: interval,
+        suspect_after: 3,
+        dead_after: 8,
+        ..FailureDetection::Default()
+    });
+    server.register_service(0, &raft_service);
+    Server::listen_and_resume(&server);
+    RaftService::start(&raft_service);
+    raft_service.bootstrap();
+
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let record = |events: &Arc<Mutex<Vec<(String, MemberStatus, bool)>>>| {
+            let events = events.clone();
+            move |res: Result<(Member, u64), ()>| {
+                if let Ok((member, _)) = res {
+                    events.lock().push((member.address, member.status, member.online));
+                }
+            }
+        };
+        client.on_member_suspected(record(&events)).unwrap().unwrap();
+        client.on_member_dead(record(&events)).unwrap().unwrap();
+        client.on_member_recovered(record(&events)).unwrap().unwrap();
+    }
+
+    let member1_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member1_svr = MemberService::new_with_interval(&String::from("server1"), &member1_raft_client, interval);
+    let member2_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member2_svr = MemberService::new_with_interval(&String::from("server2"), &member2_raft_client, interval);
+    let (member1, member2) = (member1_svr.get_server_id(), member2_svr.get_server_id());
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.member_status(member1).unwrap().unwrap(), Some(MemberStatus::Alive));
+    assert_eq!(client.member_status(member2).unwrap().unwrap(), Some(MemberStatus::Alive));
+
+    // suspected after 3 heartbeats missed, dead after 8. the last one was up to an interval before
+    // it stopped, the leader looks every interval
+    member1_svr.close();
+    let stopped = Instant::now();
+    let mut suspected_at = None;
+    let dead_at;
+    loop {
+        let elapsed = stopped.elapsed();
+        assert!(elapsed < Duration::from_secs(5));
+        match client.member_status(member1).unwrap().unwrap().unwrap() {
+            MemberStatus::Alive => {},
+            MemberStatus::Suspected => if suspected_at.is_none() {suspected_at = Some(elapsed)},
+            MemberStatus::Dead => {
+                dead_at = elapsed;
+                break;
+            }
+        }
+        assert_eq!(client.member_status(member2).unwrap().unwrap(), Some(MemberStatus::Alive));
+        thread::sleep(Duration::from_millis(20));
+    }
+    let suspected_at = suspected_at.unwrap();
+    assert!(suspected_at >= Duration::from_millis(200) && suspected_at < Duration::from_millis(700));
+    assert!(dead_at >= Duration::from_millis(700) && dead_at < Duration::from_millis(1200));
+    let roster = client.roster().unwrap().unwrap().0;
+    assert_eq!(roster.len(), 2);
+    for member in roster {
+        let expected = if member.id == member1 {MemberStatus::Dead} else {MemberStatus::Alive};
+        assert_eq!(member.status, expected);
+        assert_eq!(member.online, expected == MemberStatus::Alive);
+    }
+    assert_eq!(client.all_members(true).unwrap().unwrap().0.len(), 1);
+
+    // heartbeats again under the same address
+    let restarted_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let restarted_svr = MemberService::new_with_interval(&String::from("server1"), &restarted_raft_client, interval);
+    wait();
+    assert_eq!(client.member_status(member1).unwrap().unwrap(), Some(MemberStatus::Alive));
+    assert_eq!(client.all_members(true).unwrap().unwrap().0.len(), 2);
+    let server1 = String::from("server1");
+    assert_eq!(*events.lock(), vec!(
+        (server1.clone(), MemberStatus::Suspected, true),
+        (server1.clone(), MemberStatus::Dead, false),
+        (server1.clone(), MemberStatus::Alive, true)
+    ));
+    restarted_svr.close();
+}
+
+#[test]
+fn member_meta() {
+    let addr = String::from("127.0.0.1:1748");
+    let raft_service = RaftService::new(Options {
+        service_id: 0,
+        ..options(Storage::Default(), &addr)
+    });
+    let server = Server::new(&addr);
+    Membership::new(&server, &raft_service);
+    server.register_service(0, &raft_service);
+    Server::listen_and_resume(&server);
+    RaftService::start(&raft_service);
+    raft_service.bootstrap();
+
+    let s = |val: &str| String::from(val);
+    let meta = |zone: &str, role: &str| -> HashMap<String, String> {
+        vec!((s("zone"), s(zone)), (s("role"), s(role)), (s("weight"), s("1"))).into_iter().collect()
+    };
+    let interval = FailureDetection::Default().heartbeat_interval;
+    let member1_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member1_svr = MemberService::new_with_meta(&s("server1"), &member1_raft_client, &meta("a", "storage"), interval);
+    let member2_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member2_svr = MemberService::new_with_meta(&s("server2"), &member2_raft_client, &meta("b", "storage"), interval);
+    let member3_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member3_svr = MemberService::new_with_meta(&s("server3"), &member3_raft_client, &meta("a", "compute"), interval);
+
+    // another client watching the metadata
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    let any_changes = Arc::new(Mutex::new(Vec::new()));
+    let member2_changes = Arc::new(Mutex::new(Vec::new()));
+    let any_changes_ref = any_changes.clone();
+    let member2_changes_ref = member2_changes.clone();
+    client.on_any_member_meta_changed(move |res| {
+        if let Ok((member, key, old, _)) = res {
+            let new = member.meta.get(&key).cloned();
+            any_changes_ref.lock().push((member.address, key, old, new));
+        }
+    }).unwrap().unwrap();
+    client.on_member_meta_changed(move |res| {
+        if let Ok((member, key, _, _)) = res {
+            member2_changes_ref.lock().push((key.clone(), member.meta.get(&key).cloned()));
+        }
+    }, member2_svr.get_server_id()).unwrap().unwrap();
+
+    let addresses = |members: Vec<Member>| -> Vec<String> {
+        let mut addresses: Vec<String> = members.into_iter().map(|member| member.address).collect();
+        addresses.sort();
+        addresses
+    };
+    assert_eq!(addresses(client.members_with_tag(&s("role"), &s("storage")).unwrap().unwrap().0), vec!(s("server1"), s("server2")));
+    assert_eq!(addresses(client.members_with_tag(&s("zone"), &s("a")).unwrap().unwrap().0), vec!(s("server1"), s("server3")));
+    assert!(client.members_with_tag(&s("zone"), &s("c")).unwrap().unwrap().0.is_empty());
+
+    member2_svr.update_meta(&s("role"), &s("compute")).unwrap().unwrap();
+    // the same value again is no change
+    member2_svr.update_meta(&s("role"), &s("compute")).unwrap().unwrap();
+    member3_svr.update_meta(&s("weight"), &s("4")).unwrap().unwrap();
+    member1_svr.remove_meta(&s("zone")).unwrap().unwrap();
+    member2_svr.update_meta(&s("rack"), &s("r7")).unwrap().unwrap();
+
+    assert_eq!(addresses(client.members_with_tag(&s("role"), &s("storage")).unwrap().unwrap().0), vec!(s("server1")));
+    assert_eq!(addresses(client.members_with_tag(&s("role"), &s("compute")).unwrap().unwrap().0), vec!(s("server2"), s("server3")));
+    assert_eq!(addresses(client.members_with_tag(&s("zone"), &s("a")).unwrap().unwrap().0), vec!(s("server3")));
+    let roster = client.roster().unwrap().unwrap().0;
+    let member3 = roster.iter().find(|member| member.address == s("server3")).unwrap();
+    assert_eq!(member3.meta.get(&s("weight")), Some(&s("4")));
+    assert_eq!(client.sm_client.update_meta(&0, &s("role"), &s("storage")).unwrap(), Err(()));
+
+    wait();
+    assert_eq!(*any_changes.lock(), vec!(
+        (s("server2"), s("role"), Some(s("storage")), Some(s("compute"))),
+        (s("server3"), s("weight"), Some(s("1")), Some(s("4"))),
+        (s("server1"), s("zone"), Some(s("a")), None),
+        (s("server2"), s("rack"), None, Some(s("r7")))
+    ));
+    assert_eq!(*member2_changes.lock(), vec!(
+        (s("role"), Some(s("compute"))),
+        (s("rack"), Some(s("r7")))
+    ));
+}
+
+#[test]
+fn dead_members_leave_groups() {
+    let addr = String::from("127.0.0.1:1749");
+    let raft_service = RaftService::new(Options {
+        service_id: 0,
+        ..options(Storage::Default(), &addr)
+    });
+    let interval = Duration::from_millis(100);
+    let server = Server::new(&addr);
+    Membership::new_with_detection(&server, &raft_service, FailureDetection {
+        heartbeat_interval: interval,
+        suspect_after: 2,
+        dead_after: 5,
+        leave_groups_when_dead: true,
+        ..FailureDetection::Default()
+    });
+    server.register_service(0, &raft_service);
+    Server::listen_and_resume(&server);
+    RaftService::start(&raft_service);
+    raft_service.bootstrap();
+
+    let s = |val: &str| String::from(val);
+    let (frontends, storages) = (s("frontends"), s("storages"));
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    let member_raft_clients: Vec<_> = (0..3).map(|_| RaftClient::new(&vec!(addr.clone()), 0).unwrap()).collect();
+    let members: Vec<_> = (0..3)
+        .map(|i| MemberService::new_with_interval(&format!("server{}", i + 1), &member_raft_clients[i], interval))
+        .collect();
+    client.join_group(&frontends, &s("server1")).unwrap().unwrap();
+    client.join_group(&frontends, &s("server2")).unwrap().unwrap();
+    client.join_group(&storages, &s("server2")).unwrap().unwrap();
+    client.join_group(&storages, &s("server3")).unwrap().unwrap();
+    assert_eq!(client.join_group(&storages, &s("unknown")).unwrap(), Err(()));
+
+    let left = Arc::new(Mutex::new(Vec::new()));
+    for group in &[frontends.clone(), storages.clone()] {
+        let left = left.clone();
+        let group_name = group.clone();
+        client.on_group_member_left(move |res| {
+            if let Ok((member, _)) = res {left.lock().push((group_name.clone(), member.address));}
+        }, group).unwrap().unwrap();
+    }
+    let addresses = |group: &String| -> Vec<String> {
+        let mut addresses: Vec<String> = client.group_members(group, false).unwrap().unwrap().0
+            .into_iter().map(|member| member.address).collect();
+        addresses.sort();
+        addresses
+    };
+    let mut groups: Vec<(String, u64)> = client.all_groups().unwrap().unwrap().0
+        .into_iter().map(|group| (group.name, group.members)).collect();
+    groups.sort();
+    assert_eq!(groups, vec!((frontends.clone(), 2), (storages.clone(), 2)));
+    assert_eq!(addresses(&frontends), vec!(s("server1"), s("server2")));
+    assert_eq!(addresses(&storages), vec!(s("server2"), s("server3")));
+
+    // the member in both groups dies and leaves them both
+    members[1].close();
+    wait();
+    assert_eq!(client.member_status(members[1].get_server_id()).unwrap().unwrap(), Some(MemberStatus::Dead));
+    assert_eq!(addresses(&frontends), vec!(s("server1")));
+    assert_eq!(addresses(&storages), vec!(s("server3")));
+    assert_eq!(client.group_leader(&frontends).unwrap().unwrap().0.unwrap().address, s("server1"));
+    let mut left_groups = left.lock().clone();
+    left_groups.sort();
+    assert_eq!(left_groups, vec!((frontends.clone(), s("server2")), (storages.clone(), s("server2"))));
+
+    // and joins them again when it recovers
+    let restarted_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let restarted = MemberService::new_with_interval(&s("server2"), &restarted_raft_client, interval);
+    wait();
+    assert_eq!(addresses(&frontends), vec!(s("server1"), s("server2")));
+    assert_eq!(addresses(&storages), vec!(s("server2"), s("server3")));
+    restarted.close();
+}
+
+#[test]
+fn leave_or_crash() {
+    let addr = String::from("127.0.0.1:1750");
+    let raft_service = RaftService::new(Options {
+        service_id: 0,
+        ..options(Storage::Default(), &addr)
+    });
+    let interval = Duration::from_millis(100);
+    let server = Server::new(&addr);
+    Membership::new_with_detection(&server, &raft_service, FailureDetection {
+        heartbeat_interval: interval,
+        suspect_after: 2,
+        dead_after: 5,
+        leave_groups_when_dead: false,
+        departure_retention: Duration::from_secs(2),
+    });
+    server.register_service(0, &raft_service);
+    Server::listen_and_resume(&server);
+    RaftService::start(&raft_service);
+    raft_service.bootstrap();
+
+    let s = |val: &str| String::from(val);
+    let raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let client = ObserverClient::new(&raft_client);
+    RaftClient::prepare_subscription(&server);
+    let left = Arc::new(Mutex::new(Vec::new()));
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let (left_ref, dead_ref) = (left.clone(), dead.clone());
+    client.on_any_member_left(move |res| {
+        if let Ok((member, _)) = res {left_ref.lock().push(member.address);}
+    }).unwrap().unwrap();
+    client.on_member_dead(move |res| {
+        if let Ok((member, _)) = res {dead_ref.lock().push(member.address);}
+    }).unwrap().unwrap();
+
+    let member1_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member1_svr = MemberService::new_with_interval(&s("server1"), &member1_raft_client, interval);
+    let member2_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let member2_svr = MemberService::new_with_interval(&s("server2"), &member2_raft_client, interval);
+    let (member1, member2) = (member1_svr.get_server_id(), member2_svr.get_server_id());
+    thread::sleep(Duration::from_millis(300));
+
+    // server1 shuts down as planned and stops its heartbeats after, server2 crashes
+    client.leave(&s("server1")).unwrap().unwrap();
+    member1_svr.close();
+    member2_svr.close();
+    wait();
+    assert_eq!(*left.lock(), vec!(s("server1")));
+    assert_eq!(*dead.lock(), vec!(s("server2")));
+    assert_eq!(client.member_status(member1).unwrap().unwrap(), None);
+    assert_eq!(client.member_status(member2).unwrap().unwrap(), Some(MemberStatus::Dead));
+    assert_eq!(client.leave(&s("server1")).unwrap(), Err(()));
+
+    // the reasons are kept for observers that come later
+    let left_departure = client.departure(member1).unwrap().unwrap().unwrap();
+    let dead_departure = client.departure(member2).unwrap().unwrap().unwrap();
+    assert_eq!((left_departure.member.address, left_departure.reason), (s("server1"), DepartureReason::Left));
+    assert_eq!((dead_departure.member.address, dead_departure.reason), (s("server2"), DepartureReason::Dead));
+    assert!(left_departure.at_ms < dead_departure.at_ms);
+
+    // until the retention is over, or the member is alive again
+    thread::sleep(Duration::from_secs(2));
+    let restarted_raft_client = RaftClient::new(&vec!(addr.clone()), 0).unwrap();
+    let restarted = MemberService::new_with_interval(&s("server2"), &restarted_raft_client, interval);
+    wait();
+    assert_eq!(client.member_status(member2).unwrap().unwrap(), Some(MemberStatus::Alive));
+    assert!(client.departure(member1).unwrap().unwrap().is_none());
+    assert!(client.departure(member2).unwrap().unwrap().is_none());
+    assert_eq!(*left.lock(), vec!(s("server1")));
+    assert_eq!(*dead.lock(), vec!(s("server2")));
+    restarted.close();
+}
@@ -0,0 +1,79 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use std::u64;
+use super::{wait, options};
+
+fn admin_service(addr: &String, admin_without_auth: bool) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        admin_without_auth: admin_without_auth,
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("admin"), 0)));
+    (service, server)
+}
+
+#[test]
+fn replicas_compared() {
+    let s1_addr = String::from("127.0.0.1:1697");
+    let s2_addr = String::from("127.0.0.1:1698");
+    let s3_addr = String::from("127.0.0.1:1699");
+    let s4_addr = String::from("127.0.0.1:1701");
+    let sm_id = U32::Number::new_by_name(&String::from("admin"), 0).id;
+    let (service1, _server1) = admin_service(&s1_addr, true);
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = admin_service(&s2_addr, true);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3) = admin_service(&s3_addr, true);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..10 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    wait();
+
+    let last_log_id = service1.last_log_id();
+    let log = service1.dump_log(0..u64::MAX);
+    assert_eq!(log.last().unwrap().index, last_log_id);
+    assert!(log.windows(2).all(|pair| pair[0].index + 1 == pair[1].index));
+    assert_eq!(service2.dump_log(0..u64::MAX), log);
+    assert_eq!(service3.dump_log(0..u64::MAX), log);
+    assert_eq!(service1.dump_log(last_log_id - 2..last_log_id), log[log.len() - 3..log.len() - 1].to_vec());
+    assert!(service1.dump_log(last_log_id + 1..u64::MAX).is_empty());
+    let entry = service2.entry_payload(last_log_id).unwrap();
+    assert_eq!((entry.sm_id, entry.data.len() as u64), (sm_id, log.last().unwrap().size));
+    assert!(service2.entry_payload(last_log_id + 1).is_none());
+    assert_eq!(service3.compare_digest(0..u64::MAX), service1.compare_digest(0..u64::MAX));
+
+    let replicas = client.verify_replicas(0..u64::MAX);
+    assert!(replicas.consistent(), "{:?}", replicas);
+    assert_eq!(replicas.digests.len(), 3);
+
+    // a member without the start of the log stands out
+    assert!(service3.compact_now());
+    let replicas = client.verify_replicas(0..u64::MAX);
+    assert_eq!(replicas.mismatching, vec![service3.id]);
+    // and agrees on entries it received after
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 11);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 12);
+    wait();
+    assert_eq!(service3.dump_log(last_log_id + 1..u64::MAX).len(), 2);
+    assert!(client.verify_replicas(last_log_id + 1..u64::MAX).consistent());
+
+    // members not serving admin rpcs cannot be compared
+    let (service4, _server4) = admin_service(&s4_addr, false);
+    service4.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let replicas = client.verify_replicas(last_log_id + 1..u64::MAX);
+    assert_eq!(replicas.unreachable, vec![service4.id]);
+    assert!(replicas.mismatching.is_empty());
+}
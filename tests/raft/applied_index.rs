@@ -0,0 +1,66 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::utils::time::get_time;
+use std::thread;
+use std::time::Duration;
+use super::{wait, local_value, number_service};
+
+#[test]
+fn reads_wait_for_writes_of_other_clients() {
+    let s1_addr = String::from("127.0.0.1:1687");
+    let s2_addr = String::from("127.0.0.1:1688");
+    let s3_addr = String::from("127.0.0.1:1689");
+    let sm_id = U32::Number::new_by_name(&String::from("applied_index"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "applied_index");
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = number_service(&s2_addr, "applied_index");
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "applied_index");
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let writer = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let writer_sm = SMClient::new(sm_id, &writer);
+    let mut last_index = 0;
+    for i in 0..10 {
+        let (value, index) = writer_sm.with_index().incr_and_get().unwrap();
+        assert_eq!(value.unwrap(), i + 1);
+        assert!(index > last_index);
+        last_index = index;
+    }
+    assert!(service1.last_applied() >= last_index);
+
+    // another client reading from any member sees the writes once it waited for them
+    let servers = vec!(s1_addr.clone(), s2_addr.clone(), s3_addr.clone());
+    let reader = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+    reader.read_consistency(Consistency::Stale);
+    let reader_sm = SMClient::new(sm_id, &reader);
+    assert!(reader_sm.wait_applied(last_index, Duration::from_secs(5)));
+    for service in &[&service1, &service2, &service3] {
+        assert!(service.last_applied() >= last_index);
+        assert_eq!(local_value(service, sm_id), 10);
+    }
+    for _ in 0..3 {
+        assert_eq!(reader_sm.get().unwrap().unwrap(), 10);
+    }
+    assert!(reader.wait_applied_on(service2.id, last_index, Duration::from_secs(1)));
+    assert!(service3.wait_applied(last_index, Duration::from_millis(0)));
+
+    // waiters wake up when the entry is applied
+    let waiting = {
+        let service2 = service2.clone();
+        thread::spawn(move || service2.wait_applied(last_index + 1, Duration::from_secs(5)))
+    };
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(writer_sm.incr_and_get().unwrap().unwrap(), 11);
+    assert!(waiting.join().unwrap());
+    assert_eq!(local_value(&service2, sm_id), 11);
+
+    // entries not proposed yet are not waited for longer than the timeout
+    let started_at = get_time();
+    assert!(!reader.wait_applied(last_index + 1000, Duration::from_millis(300)));
+    assert!(get_time() - started_at >= 290);
+    assert!(!service1.wait_applied(last_index + 1000, Duration::from_millis(10)));
+}
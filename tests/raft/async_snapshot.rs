@@ -0,0 +1,113 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::{StateMachineCtl, SnapshotFuture};
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use futures::Future;
+use futures::sync::oneshot;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use super::{wait, options};
+
+const SNAPSHOT_MS: i64 = 2000;
+
+// takes seconds to turn its value into a snapshot, on a thread of its own
+pub struct Slow {
+    value: u64,
+    // values the snapshots were taken of
+    forked: Arc<Mutex<Vec<u64>>>,
+}
+
+raft_state_machine! {
+    def cmd set(value: u64);
+    def qry get() -> u64;
+}
+
+impl StateMachineCmds for Slow {
+    fn set(&mut self, value: u64) -> Result<(), ()> {
+        self.value = value;
+        Ok(())
+    }
+    fn get(&self) -> Result<u64, ()> {
+        Ok(self.value)
+    }
+}
+
+impl StateMachineCtl for Slow {
+    raft_sm_complete!();
+    fn snapshot(&self) -> Option<Vec<u8>> { Some(self.value.to_string().into_bytes()) }
+    fn snapshot_async(&self) -> SnapshotFuture {
+        let value = self.value;
+        self.forked.lock().push(value);
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(SNAPSHOT_MS as u64));
+            tx.send(value.to_string().into_bytes()).ok();
+        });
+        Box::new(rx.map_err(|_| ()))
+    }
+    fn recover(&mut self, data: Vec<u8>) {
+        self.value = String::from_utf8(data).unwrap().parse().unwrap();
+    }
+    fn id(&self) -> u64 {16}
+}
+
+fn slow_service(addr: &String) -> (Arc<RaftService>, Arc<Server>, Arc<Mutex<Vec<u64>>>) {
+    let service = RaftService::new(Options {
+        compaction: Compaction {
+            max_entries: Some(20),
+            ..Compaction::Default()
+        },
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    let forked = Arc::new(Mutex::new(Vec::new()));
+    service.register_state_machine(Box::new(Slow { value: 0, forked: forked.clone() }));
+    (service, server, forked)
+}
+
+#[test]
+fn writes_go_on_during_snapshot() {
+    let s1_addr = String::from("127.0.0.1:1695");
+    let s2_addr = String::from("127.0.0.1:1696");
+    let (service1, _server1, forked1) = slow_service(&s1_addr);
+    service1.bootstrap().unwrap();
+    let (service2, _server2, _) = slow_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let raft_client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = client::SMClient::new(16, &raft_client);
+    let mut value = 0;
+    // until the log is long enough to be compacted
+    while forked1.lock().is_empty() {
+        value += 1;
+        sm_client.set(&value).unwrap().unwrap();
+        assert!(value < 1000, "log never compacted");
+    }
+    let started_at = get_time();
+    let value_at_start = value;
+    while service1.last_snapshot_id().is_none() {
+        let write_started_at = get_time();
+        value += 1;
+        sm_client.set(&value).unwrap().unwrap();
+        assert_eq!(sm_client.get().unwrap().unwrap(), value);
+        // applying would wait for the whole snapshot otherwise
+        assert!(get_time() - write_started_at < SNAPSHOT_MS / 2, "write waited for the snapshot");
+        assert!(get_time() - started_at < 30_000, "snapshot never finished");
+    }
+    assert!(get_time() - started_at >= SNAPSHOT_MS / 2);
+    assert!(value - value_at_start > 10, "{} writes during the snapshot", value - value_at_start);
+
+    // the snapshot is of the state it was asked for, the log after it is kept
+    let forked = forked1.lock()[0];
+    assert!(forked <= value_at_start);
+    assert!(service1.last_snapshot_id().unwrap() < service1.last_log_id());
+    assert!(service1.num_logs() as u64 >= value - value_at_start);
+    assert_eq!(sm_client.get().unwrap().unwrap(), value);
+}
@@ -0,0 +1,63 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::StateMachineCtl;
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use std::thread;
+use std::time::Duration;
+use super::{wait, options};
+
+// applies a command every 200ms
+pub struct Sluggish;
+
+raft_state_machine! {
+    def cmd crawl();
+}
+
+impl StateMachineCmds for Sluggish {
+    fn crawl(&mut self) -> Result<(), ()> {
+        thread::sleep(Duration::from_millis(200));
+        Ok(())
+    }
+}
+
+impl StateMachineCtl for Sluggish {
+    raft_sm_complete!();
+    fn snapshot(&self) -> Option<Vec<u8>> { None }
+    fn recover(&mut self, _data: Vec<u8>) {}
+    fn id(&self) -> u64 {13}
+}
+
+#[test]
+fn full_queue_refuses_commands() {
+    let addr = String::from("127.0.0.1:1676");
+    let service = RaftService::new(Options {
+        // applied off the raft thread, entries pile up while the state machine crawls
+        apply_parallelism: 2,
+        max_proposals: 2,
+        ..options(Storage::Default(), &addr)
+    });
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(Sluggish));
+    service.bootstrap().unwrap();
+    wait();
+
+    let raft_client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    let started_at = get_time();
+    let proposing: Vec<_> = (0..8).map(|_| {
+        let raft_client = raft_client.clone();
+        thread::spawn(move || client::SMClient::new(13, &raft_client).crawl())
+    }).collect();
+    thread::sleep(Duration::from_millis(100));
+    assert!(service.status().proposal_queue <= 2);
+    let results: Vec<_> = proposing.into_iter().map(|proposal| proposal.join().unwrap()).collect();
+    // refused right away instead of waiting for 8 commands to be applied one after another
+    assert!(get_time() - started_at < 8 * 200);
+    assert!(results.iter().any(|result| result.is_ok()));
+    assert!(results.iter().any(|result| *result == Err(ExecError::ProposalQueueFull)));
+    assert_eq!(service.status().proposal_queue, 0);
+}
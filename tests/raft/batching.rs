@@ -0,0 +1,65 @@
+use bifrost::raft::*;
+use bifrost::raft::client::{RaftClient, ClientOptions, Batching};
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::store::number::U32;
+use futures::Future;
+use std::time::{Duration, Instant};
+use super::{wait, local_value, number_service};
+
+#[test]
+fn batches_of_commands() {
+    let s1_addr = String::from("127.0.0.1:1711");
+    let s2_addr = String::from("127.0.0.1:1712");
+    let s3_addr = String::from("127.0.0.1:1713");
+    let sm_id = U32::Number::new_by_name(&String::from("batching"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "batching");
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = number_service(&s2_addr, "batching");
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "batching");
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let count = 500;
+    let servers = vec!(s1_addr.clone());
+    let unbatched = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+    let started = Instant::now();
+    for i in 0..count {
+        let value = unbatched.execute(sm_id, &U32::commands::incr_and_get::new()).unwrap().unwrap();
+        assert_eq!(value, i + 1);
+    }
+    let unbatched_elapsed = started.elapsed();
+
+    let batched = RaftClient::with_options(&servers, DEFAULT_SERVICE_ID, ClientOptions {
+        batching: Some(Batching {
+            max_delay: Duration::from_millis(2),
+            max_batch: 100,
+        }),
+        ..ClientOptions::Default()
+    }).unwrap();
+    let started = Instant::now();
+    let pending: Vec<_> = (0..count)
+        .map(|_| batched.execute_async(sm_id, U32::commands::incr_and_get::new()))
+        .collect();
+    let mut values: Vec<u32> = pending.into_iter().map(|pending| pending.wait().unwrap().unwrap()).collect();
+    let batched_elapsed = started.elapsed();
+    values.sort();
+    assert_eq!(values, (count + 1..2 * count + 1).collect::<Vec<u32>>());
+    assert!(
+        batched_elapsed * 2 < unbatched_elapsed,
+        "batched {:?}, unbatched {:?}", batched_elapsed, unbatched_elapsed
+    );
+    // far fewer entries than commands
+    assert!(service1.metrics().proposals_accepted < 2 * count as u64);
+
+    // errors of a command go to its caller only
+    let missing = batched.execute_async(sm_id + 1, U32::commands::incr_and_get::new());
+    let found = batched.execute_async(sm_id, U32::commands::incr_and_get::new());
+    assert_eq!(missing.wait().err(), Some(ExecError::SmNotFound));
+    assert_eq!(found.wait().unwrap().unwrap(), 2 * count + 1);
+    // blocking calls go in batches as well
+    assert_eq!(batched.execute(sm_id, &U32::commands::incr_and_get::new()).unwrap().unwrap(), 2 * count + 2);
+    wait();
+    assert_eq!(local_value(&service2, sm_id), 2 * count + 2);
+    assert_eq!(local_value(&service3, sm_id), 2 * count + 2);
+}
@@ -0,0 +1,34 @@
+use bifrost::raft::*;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use super::{wait, options};
+
+fn raft_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    (service, server)
+}
+
+#[test]
+fn bootstrap_once_then_join() {
+    let s1_addr = String::from("127.0.0.1:1671");
+    let s2_addr = String::from("127.0.0.1:1672");
+    let (service1, _server1) = raft_service(&s1_addr);
+    assert_eq!(service1.bootstrap(), Ok(()));
+    assert!(service1.is_leader());
+    assert_eq!(service1.bootstrap(), Err(BootstrapError::SeenLeader(service1.id)));
+
+    let (service2, _server2) = raft_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+    assert_eq!(service1.num_members(), 2);
+    assert_eq!(service2.num_members(), 2);
+    assert!(service1.is_leader());
+    assert_eq!(service2.leader_id(), service1.id);
+    // joined servers are in the cluster already
+    assert_eq!(service2.bootstrap(), Err(BootstrapError::SeenLeader(service1.id)));
+    assert!(!service2.is_leader());
+}
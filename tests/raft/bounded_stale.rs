@@ -0,0 +1,78 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::store::number::U32::commands::get;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use std::time::Duration;
+use super::{wait, local_value};
+
+// clients reach the servers by this id, the cluster by the default one
+const CLIENT_SERVICE_ID: u64 = 1626;
+
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let (service, server) = super::number_service(addr, "bounded");
+    server.register_service(CLIENT_SERVICE_ID, &service);
+    (service, server)
+}
+
+#[test]
+fn stale_follower_falls_back_to_leader() {
+    let s1_addr = String::from("127.0.0.1:1626");
+    let s2_addr = String::from("127.0.0.1:1627");
+    let s3_addr = String::from("127.0.0.1:1628");
+    let sm_id = U32::Number::new_by_name(&String::from("bounded"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr);
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, server3) = number_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+
+    let max_lag = Duration::from_millis(500);
+    let client = RaftClient::new(&vec!(s1_addr.clone()), CLIENT_SERVICE_ID).unwrap();
+    client.read_consistency(Consistency::BoundedStale(max_lag));
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..10 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    wait();
+    for _ in 0..10 {
+        assert_eq!(sm_client.get().unwrap().unwrap(), 10);
+    }
+    assert_eq!(client.query_on(service3.id, sm_id, &get::new(), max_lag).unwrap().unwrap(), 10);
+
+    // heartbeats of the leader no longer reach service3, clients still do
+    server3.remove_service(DEFAULT_SERVICE_ID);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 11);
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 10);
+
+    let msg = get::new();
+    let (fn_id, _, data) = msg.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    match service3.c_bounded_query(&entry, &500).unwrap() {
+        ClientReadResponse::TooStale { lag } => assert!(lag > 500),
+        other => panic!("expect too stale, got {:?}", other)
+    }
+    match service1.c_bounded_query(&entry, &0).unwrap() {
+        ClientReadResponse::Success { .. } => {},
+        other => panic!("expect success, got {:?}", other)
+    }
+    // served by the leader instead of the stale value of service3
+    assert_eq!(client.query_on(service3.id, sm_id, &msg, max_lag).unwrap().unwrap(), 11);
+    for _ in 0..10 {
+        assert_eq!(sm_client.get().unwrap().unwrap(), 11);
+    }
+}
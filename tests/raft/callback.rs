@@ -6,7 +6,7 @@ use bifrost::raft::state_machine::callback::client::SubscriptionService;
 use bifrost::raft::state_machine::StateMachineCtl;
 use bifrost::rpc::Server;
 
-use super::wait;
+use super::{wait, options};
 
 use std::thread;
 use std::sync::Arc;
@@ -41,11 +41,7 @@ impl StateMachineCtl for Trigger {
 fn dummy() {
     println!("TESTING CALLBACK");
     let addr = String::from("127.0.0.1:2110");
-    let raft_service = RaftService::new(Options{
-        storage: Storage::Default(),
-        address: addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
     let server = Server::new(&addr);
     let dummy_sm = Trigger {
         count: 0,
@@ -88,3 +84,171 @@ fn dummy() {
     assert_eq!(counter.load(Ordering::Relaxed), loops);
     assert_eq!(sumer.load(Ordering::Relaxed), expected_sum);
 }
+
+mod trace {
+    use bifrost::raft::*;
+    use bifrost::raft::client::RaftClient;
+    use bifrost::raft::state_machine::callback::server::SMCallback;
+    use bifrost::raft::state_machine::StateMachineCtl;
+    use bifrost::rpc::Server;
+    use bifrost::rpc::context;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    use super::super::{wait, options};
+
+    pub struct Tracer {
+        callback: SMCallback
+    }
+
+    raft_state_machine! {
+        def cmd trace();
+        def sub on_traced() -> Option<u64>;
+    }
+
+    impl StateMachineCmds for Tracer {
+        fn trace(&mut self) -> Result<(), ()> {
+            self.callback.notify(&commands::on_traced::new(), Ok(context::current_trace_id()));
+            Ok(())
+        }
+    }
+
+    impl StateMachineCtl for Tracer {
+        raft_sm_complete!();
+        fn snapshot(&self) -> Option<Vec<u8>> { None }
+        fn recover(&mut self, data: Vec<u8>) {}
+        fn id(&self) -> u64 {11}
+    }
+
+    #[test]
+    fn trace_id_in_notifications() {
+        let addr = String::from("127.0.0.1:2120");
+        let raft_service = RaftService::new(options(Storage::Default(), &addr));
+        let server = Server::new(&addr);
+        let tracer = Tracer {
+            callback: SMCallback::new(11, raft_service.clone())
+        };
+        let sm_id = tracer.id();
+        server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+        Server::listen_and_resume(&server);
+        RaftService::start(&raft_service);
+        raft_service.register_state_machine(Box::new(tracer));
+        raft_service.bootstrap();
+
+        wait();
+
+        let raft_client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+        let sm_client = client::SMClient::new(sm_id, &raft_client);
+        // (trace id committed by the state machine, trace id of the notification request)
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+        RaftClient::prepare_subscription(&server);
+        sm_client.on_traced(move |res| {
+            received_clone.lock().push((res.unwrap(), context::current_trace_id()));
+        }).unwrap();
+
+        context::with_trace_id(99, || sm_client.trace().unwrap()).unwrap();
+        sm_client.trace().unwrap().unwrap();
+
+        wait();
+
+        assert_eq!(*received.lock(), vec![(Some(99), Some(99)), (None, None)]);
+    }
+}
+
+mod emission {
+    use bifrost::raft::*;
+    use bifrost::raft::client::RaftClient;
+    use bifrost::raft::state_machine::callback::server::SMCallback;
+    use bifrost::raft::state_machine::StateMachineCtl;
+    use bifrost::rpc::Server;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::super::{wait, options};
+
+    pub struct Counter {
+        count: u64,
+        callback: SMCallback
+    }
+
+    raft_state_machine! {
+        def cmd bump();
+        def sub on_bumped() -> u64;
+        def replica_sub on_applied() -> u64;
+    }
+
+    impl StateMachineCmds for Counter {
+        fn bump(&mut self) -> Result<(), ()> {
+            self.count += 1;
+            self.callback.notify(&commands::on_bumped::new(), Ok(self.count));
+            self.callback.notify(&commands::on_applied::new(), Ok(self.count));
+            Ok(())
+        }
+    }
+
+    impl StateMachineCtl for Counter {
+        raft_sm_complete!();
+        fn snapshot(&self) -> Option<Vec<u8>> { None }
+        fn recover(&mut self, _: Vec<u8>) {}
+        fn id(&self) -> u64 {12}
+    }
+
+    // events of on_bumped from every server go to bumped, those of on_applied to applied
+    fn counter_service(addr: &String, bumped: &Arc<AtomicUsize>) -> (Arc<RaftService>, Arc<Server>, Arc<AtomicUsize>) {
+        let service = RaftService::new(options(Storage::Default(), addr));
+        let counter = Counter {
+            count: 0,
+            callback: SMCallback::new(12, service.clone())
+        };
+        let applied = Arc::new(AtomicUsize::new(0));
+        let bumped_ref = bumped.clone();
+        let applied_ref = applied.clone();
+        counter.callback.internal_subscribe(&commands::on_bumped::new(), move |_| {
+            bumped_ref.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+        counter.callback.internal_subscribe(&commands::on_applied::new(), move |_| {
+            applied_ref.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+        let server = Server::new(addr);
+        server.register_service(DEFAULT_SERVICE_ID, &service);
+        Server::listen_and_resume(&server);
+        assert!(RaftService::start(&service));
+        service.register_state_machine(Box::new(counter));
+        (service, server, applied)
+    }
+
+    #[test]
+    fn leader_and_replica_events() {
+        let s1_addr = String::from("127.0.0.1:1730");
+        let s2_addr = String::from("127.0.0.1:1731");
+        let s3_addr = String::from("127.0.0.1:1732");
+        let bumped = Arc::new(AtomicUsize::new(0));
+        let (service1, _server1, applied1) = counter_service(&s1_addr, &bumped);
+        service1.bootstrap().unwrap();
+        let (service2, _server2, applied2) = counter_service(&s2_addr, &bumped);
+        service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+        let (service3, _server3, applied3) = counter_service(&s3_addr, &bumped);
+        service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+        wait();
+
+        let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+        let sm_client = client::SMClient::new(12, &client);
+        for _ in 0..5 {
+            sm_client.bump().unwrap().unwrap();
+        }
+        // the next leader applied the entries of the old one, it does not emit them again
+        service1.transfer_leadership(service2.id).unwrap();
+        wait();
+        assert!(service2.is_leader());
+        for _ in 0..5 {
+            sm_client.bump().unwrap().unwrap();
+        }
+        wait();
+
+        assert_eq!(bumped.load(Ordering::SeqCst), 10);
+        assert_eq!(applied1.load(Ordering::SeqCst), 10);
+        assert_eq!(applied2.load(Ordering::SeqCst), 10);
+        assert_eq!(applied3.load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,100 @@
+use bifrost::raft::*;
+use bifrost::store::number::U32;
+use bifrost::rpc::{Server, RPCService, RPCRequestError, ShortcutPolicy, Bytes};
+use bifrost::utils::time::get_time;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use super::{wait, options};
+
+// refuses every request of the raft service once cut
+struct Partitioned {
+    service: Arc<RaftService>,
+    cut: AtomicBool,
+}
+
+impl RPCService for Partitioned {
+    fn dispatch(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+        if self.cut.load(Ordering::SeqCst) {
+            return Err(RPCRequestError::Rejected);
+        }
+        self.service.dispatch(data)
+    }
+    fn register_shortcut_service(&self, _service_ptr: usize, _server_id: u64, _service_id: u64, _policy: ShortcutPolicy) {}
+    fn unregister_shortcut_service(&self, _server_id: u64, _service_id: u64) {}
+}
+
+fn partitioned_service(addr: &String, check_quorum: bool) -> (Arc<RaftService>, Arc<Partitioned>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        check_quorum: check_quorum,
+        ..options(Storage::Default(), addr)
+    });
+    let partitioned = Arc::new(Partitioned { service: service.clone(), cut: AtomicBool::new(false) });
+    let server = Server::new(addr);
+    server.register_service_with_shortcut(DEFAULT_SERVICE_ID, &partitioned, ShortcutPolicy::Never);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("check_quorum"), 0)));
+    (service, partitioned, server)
+}
+
+// cuts the leader off both followers, returns how long it kept leading and the response of
+// a command proposed meanwhile
+fn isolate_leader(addrs: &[&str], check_quorum: bool) -> (i64, ClientCmdResponse) {
+    let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+    let (service1, _, _server1) = partitioned_service(&addrs[0], check_quorum);
+    service1.bootstrap();
+    let (service2, partitioned2, _server2) = partitioned_service(&addrs[1], check_quorum);
+    service2.join(&vec!(addrs[0].clone())).unwrap();
+    let (service3, partitioned3, _server3) = partitioned_service(&addrs[2], check_quorum);
+    service3.join(&vec!(addrs[0].clone())).unwrap();
+    wait();
+    assert!(service1.is_leader());
+
+    partitioned2.cut.store(true, Ordering::SeqCst);
+    partitioned3.cut.store(true, Ordering::SeqCst);
+    let cut_at = get_time();
+    let proposing = service1.clone();
+    let proposal = thread::spawn(move || {
+        let cmd = U32::commands::incr_and_get::new();
+        let (fn_id, _, data) = cmd.encode();
+        proposing.c_command(&LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: U32::Number::new_by_name(&String::from("check_quorum"), 0).id,
+            fn_id: fn_id,
+            data: data,
+            trace_id: None,
+            session: None,
+            time: 0,
+            checksum: 0,
+        }).unwrap()
+    });
+    while service1.is_leader() && get_time() - cut_at < 3000 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let leading = get_time() - cut_at;
+    (leading, proposal.join().unwrap())
+}
+
+#[test]
+fn isolated_leader_steps_down() {
+    let (leading, response) = isolate_leader(&["127.0.0.1:1655", "127.0.0.1:1656", "127.0.0.1:1657"], true);
+    // within about the maximum election timeout
+    assert!(leading < 1000, "kept leading for {}ms", leading);
+    match response {
+        ClientCmdResponse::LostLeadership => {},
+        other => panic!("expect lost leadership, got {:?}", other)
+    }
+}
+
+#[test]
+fn isolated_leader_without_check_quorum() {
+    let (leading, response) = isolate_leader(&["127.0.0.1:1658", "127.0.0.1:1659", "127.0.0.1:1660"], false);
+    assert!(leading >= 3000);
+    match response {
+        ClientCmdResponse::NotCommitted => {},
+        other => panic!("expect not committed, got {:?}", other)
+    }
+}
@@ -0,0 +1,84 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::{StateMachineCtl, CommandCtx};
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use super::{wait, options};
+
+// remembers the context of every command it applied
+pub struct Stamps {
+    stamped: Arc<Mutex<Vec<CommandCtx>>>,
+}
+
+raft_state_machine! {
+    def cmd stamp(ctx) -> u64;
+    def qry applied(ctx) -> u64;
+}
+
+impl StateMachineCmds for Stamps {
+    fn stamp(&mut self, ctx: CommandCtx) -> Result<u64, ()> {
+        self.stamped.lock().push(ctx);
+        Ok(ctx.index)
+    }
+    fn applied(&self, ctx: CommandCtx) -> Result<u64, ()> {
+        Ok(ctx.index)
+    }
+}
+
+impl StateMachineCtl for Stamps {
+    raft_sm_complete!();
+    fn snapshot(&self) -> Option<Vec<u8>> { None }
+    fn recover(&mut self, _data: Vec<u8>) {}
+    fn id(&self) -> u64 {15}
+}
+
+fn stamping_service(addr: &String) -> (Arc<RaftService>, Arc<Server>, Arc<Mutex<Vec<CommandCtx>>>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    let stamped = Arc::new(Mutex::new(Vec::new()));
+    service.register_state_machine(Box::new(Stamps { stamped: stamped.clone() }));
+    (service, server, stamped)
+}
+
+#[test]
+fn same_context_on_every_replica() {
+    let s1_addr = String::from("127.0.0.1:1684");
+    let s2_addr = String::from("127.0.0.1:1685");
+    let s3_addr = String::from("127.0.0.1:1686");
+    let (service1, _server1, stamped1) = stamping_service(&s1_addr);
+    service1.bootstrap().unwrap();
+    let (service2, _server2, stamped2) = stamping_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3, stamped3) = stamping_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let raft_client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = client::SMClient::new(15, &raft_client);
+    let started_at = get_time();
+    let mut indexes = Vec::new();
+    for _ in 0..5 {
+        indexes.push(sm_client.stamp().unwrap().unwrap());
+    }
+    let finished_at = get_time();
+    assert!(indexes.windows(2).all(|pair| pair[0] < pair[1]));
+    // queries see what is applied
+    assert!(sm_client.applied().unwrap().unwrap() >= indexes[4]);
+    wait();
+
+    let stamped = stamped1.lock().clone();
+    assert_eq!(stamped.iter().map(|ctx| ctx.index).collect::<Vec<_>>(), indexes);
+    for ctx in &stamped {
+        assert_eq!(ctx.term, service1.term());
+        assert!(ctx.leader_time_ms >= started_at as u64 && ctx.leader_time_ms <= finished_at as u64);
+    }
+    assert!(stamped.windows(2).all(|pair| pair[0].leader_time_ms <= pair[1].leader_time_ms));
+    // followers applied the commands with the time of the leader
+    assert_eq!(*stamped2.lock(), stamped);
+    assert_eq!(*stamped3.lock(), stamped);
+}
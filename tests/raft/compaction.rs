@@ -0,0 +1,133 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::disk::load_snapshot;
+use bifrost::raft::snapshot::read_snapshot;
+use bifrost::raft::wal::SyncPolicy;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use super::{wait, local_value, options};
+
+static MAX_ENTRIES: u64 = 50;
+
+fn compacting_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        compaction: Compaction {
+            max_entries: Some(MAX_ENTRIES),
+            max_bytes: None,
+            // snapshots of the late node arrive in many chunks
+            snapshot_chunk_size: 16,
+        },
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("compaction"), 0)));
+    (service, server)
+}
+
+#[test]
+fn bounded_log() {
+    let s1_addr = String::from("127.0.0.1:1598");
+    let s2_addr = String::from("127.0.0.1:1599");
+    let s3_addr = String::from("127.0.0.1:1601");
+    let sm_id = U32::Number::new_by_name(&String::from("compaction"), 0).id;
+    let (service1, _server1) = compacting_service(&s1_addr);
+    service1.bootstrap();
+    let (service2, _server2) = compacting_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..2000 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+        assert!(service1.num_logs() as u64 <= MAX_ENTRIES + 1);
+        assert!(service2.num_logs() as u64 <= MAX_ENTRIES * 2);
+    }
+    assert!(service1.last_snapshot_id().unwrap() > 1900);
+    assert!(service1.last_snapshot_size() > 0);
+
+    // the entries it needs are long gone from the log of the leader
+    let (service3, _server3) = compacting_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+    assert!(service3.last_snapshot_id().is_some());
+    assert_eq!(service3.last_log_id(), service1.last_log_id());
+    assert_eq!(local_value(&service1, sm_id), 2000);
+    assert_eq!(local_value(&service3, sm_id), 2000);
+
+    assert!(service1.compact_now());
+    assert_eq!(service1.num_logs(), 0);
+    assert_eq!(service1.last_log_id(), service1.last_snapshot_id());
+    // nothing applied since
+    assert!(!service1.compact_now());
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 2001);
+    assert!(service1.compact_now());
+}
+
+fn numbers_service(addr: &String, storage: Storage, names: &[&str]) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(storage, addr));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    for name in names {
+        service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from(*name), 0)));
+    }
+    (service, server)
+}
+
+#[test]
+fn unknown_sections_kept() {
+    let s1_addr = String::from("127.0.0.1:1693");
+    let s2_addr = String::from("127.0.0.1:1694");
+    let log_dir = env::temp_dir().join("bifrost-raft-sections-1694");
+    fs::remove_dir_all(&log_dir).ok();
+    let log_dir = log_dir.to_str().unwrap().to_string();
+    let known_id = U32::Number::new_by_name(&String::from("sections-known"), 0).id;
+    let newer_id = U32::Number::new_by_name(&String::from("sections-newer"), 0).id;
+    let (service1, _server1) = numbers_service(&s1_addr, Storage::Default(), &["sections-known", "sections-newer"]);
+    service1.bootstrap();
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let known = SMClient::new(known_id, &client);
+    let newer = SMClient::new(newer_id, &client);
+    for i in 0..5 {
+        assert_eq!(known.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    for i in 0..7 {
+        assert_eq!(newer.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    assert!(service1.compact_now());
+
+    // a server of an older version, without one of the state machines
+    let storage = Storage::DiskOptions {
+        path: log_dir.clone(),
+        sync_policy: SyncPolicy::EveryCommit,
+    };
+    let (service2, _server2) = numbers_service(&s2_addr, storage, &["sections-known"]);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+    assert!(service2.last_snapshot_id().is_some());
+    assert_eq!(local_value(&service2, known_id), 5);
+    assert_eq!(known.incr_and_get().unwrap().unwrap(), 6);
+    wait();
+
+    // its own snapshot carries the section it cannot read
+    assert!(service2.compact_now());
+    let snapshot = load_snapshot(Path::new(&log_dir).join("snapshot")).unwrap().unwrap();
+    let contents = read_snapshot(&snapshot.data).unwrap();
+    assert_eq!(contents.last_included_id, snapshot.last_included_id);
+    assert!(contents.sections.iter().any(|section| section.sm_id == known_id));
+    assert!(contents.sections.iter().any(|section| section.sm_id == newer_id));
+    // and recovers the state machine from it once it knows it
+    service2.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("sections-newer"), 0)));
+    assert_eq!(local_value(&service2, newer_id), 7);
+    assert_eq!(local_value(&service2, known_id), 6);
+    fs::remove_dir_all(&log_dir).ok();
+}
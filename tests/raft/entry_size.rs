@@ -0,0 +1,57 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::store::value::string;
+use bifrost::store::value::string::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use super::{wait, options};
+
+const MAX_ENTRY_BYTES: u64 = 16 * 1024;
+
+fn bounded_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        replication: Replication {
+            max_batch_bytes: 64 * 1024,
+            max_batch_entries: 4,
+            max_entry_bytes: MAX_ENTRY_BYTES,
+            ..Replication::Default()
+        },
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string::Value::new_by_name(&String::from("entry size"), String::new())));
+    (service, server)
+}
+
+#[test]
+fn large_entries_refused() {
+    let s1_addr = String::from("127.0.0.1:1702");
+    let s2_addr = String::from("127.0.0.1:1703");
+    let sm_id = string::Value::new_by_name(&String::from("entry size"), String::new()).id;
+    let (service1, _server1) = bounded_service(&s1_addr);
+    service1.bootstrap().unwrap();
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    // more entries than go in a batch for the follower to catch up on
+    for i in 0..40 {
+        sm_client.set(&format!("{}", i)).unwrap().unwrap();
+    }
+    let fits = String::from_utf8(vec![b'a'; 10 * 1024]).unwrap();
+    sm_client.set(&fits).unwrap().unwrap();
+
+    let last_log_id = service1.last_log_id();
+    let too_large = String::from_utf8(vec![b'b'; 20 * 1024]).unwrap();
+    assert_eq!(sm_client.set(&too_large), Err(ExecError::EntryTooLarge { max_bytes: MAX_ENTRY_BYTES }));
+    assert_eq!(service1.last_log_id(), last_log_id, "the command was proposed");
+    assert_eq!(sm_client.get().unwrap().unwrap(), fits);
+
+    let (service2, _server2) = bounded_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+    assert_eq!(service2.last_log_id(), service1.last_log_id());
+    assert_eq!(service2.entry_payload(last_log_id).unwrap().data, service1.entry_payload(last_log_id).unwrap().data);
+}
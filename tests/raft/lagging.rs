@@ -0,0 +1,103 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::observer::RaftObserver;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::{Server, RPCService, RPCRequestError, ShortcutPolicy, Bytes};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use super::{wait, local_value, options};
+
+// refuses 9 of every 10 requests while degraded
+struct Degraded {
+    service: Arc<RaftService>,
+    degraded: AtomicBool,
+    requests: AtomicUsize,
+}
+
+impl RPCService for Degraded {
+    fn dispatch(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+        let request = self.requests.fetch_add(1, Ordering::SeqCst);
+        if self.degraded.load(Ordering::SeqCst) && request % 10 != 0 {
+            return Err(RPCRequestError::Rejected);
+        }
+        self.service.dispatch(data)
+    }
+    fn register_shortcut_service(&self, _service_ptr: usize, _server_id: u64, _service_id: u64, _policy: ShortcutPolicy) {}
+    fn unregister_shortcut_service(&self, _server_id: u64, _service_id: u64) {}
+}
+
+struct LagRecorder {
+    lagging: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl RaftObserver for LagRecorder {
+    fn on_follower_lagging(&mut self, id: u64, lag: u64) {
+        self.lagging.lock().push((id, lag));
+    }
+}
+
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Degraded>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        replication: Replication {
+            max_follower_lag: Some(20),
+            ..Replication::Default()
+        },
+        ..options(Storage::Default(), addr)
+    });
+    let degraded = Arc::new(Degraded {
+        service: service.clone(),
+        degraded: AtomicBool::new(false),
+        requests: AtomicUsize::new(0),
+    });
+    let server = Server::new(addr);
+    server.register_service_with_shortcut(DEFAULT_SERVICE_ID, &degraded, ShortcutPolicy::Never);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("lagging"), 0)));
+    (service, degraded, server)
+}
+
+#[test]
+fn degraded_follower_catches_up() {
+    let s1_addr = String::from("127.0.0.1:1677");
+    let s2_addr = String::from("127.0.0.1:1678");
+    let s3_addr = String::from("127.0.0.1:1679");
+    let sm_id = U32::Number::new_by_name(&String::from("lagging"), 0).id;
+    let (service1, _, _server1) = number_service(&s1_addr);
+    let lagging = Arc::new(Mutex::new(Vec::new()));
+    service1.register_observer(Box::new(LagRecorder { lagging: lagging.clone() }));
+    service1.bootstrap().unwrap();
+    let (service2, _, _server2) = number_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, degraded3, _server3) = number_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    degraded3.degraded.store(true, Ordering::SeqCst);
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..300 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    assert!(service1.is_leader());
+    {
+        let lagging = lagging.lock();
+        assert!(!lagging.is_empty());
+        assert!(lagging.iter().all(|&(id, lag)| id == service3.id && lag > 20), "{:?}", *lagging);
+    }
+    // the leader compacted for it, it catches up from the snapshot
+    assert!(service1.status().snapshot_id.is_some());
+    assert_eq!(service1.cluster_info().follower_lag(service2.id).map(|lag| lag <= 20), Some(true));
+
+    // back to pipelining once healthy
+    degraded3.degraded.store(false, Ordering::SeqCst);
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 300);
+    assert_eq!(service1.cluster_info().follower_lag(service3.id), Some(0));
+    assert_eq!(service1.cluster_info().follower_lag(service2.id), Some(0));
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 301);
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 301);
+}
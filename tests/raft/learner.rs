@@ -0,0 +1,70 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use bifrost_hasher::hash_str;
+use std::sync::Arc;
+use std::u64;
+use super::{wait, local_value, options, number_service_with};
+
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(Options {
+        max_learner_lag: 10,
+        ..options(Storage::Default(), addr)
+    }, "learner")
+}
+
+#[test]
+fn learner_catches_up_before_promotion() {
+    let s1_addr = String::from("127.0.0.1:1610");
+    let s2_addr = String::from("127.0.0.1:1611");
+    let s3_addr = String::from("127.0.0.1:1612");
+    let s4_addr = String::from("127.0.0.1:1613");
+    let sm_id = U32::Number::new_by_name(&String::from("learner"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr);
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..200 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+
+    // started without joining, the leader brings it up to date
+    let (service3, _server3) = number_service(&s3_addr);
+    service2.add_learner(&s3_addr).unwrap().unwrap();
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 200);
+    assert_eq!(service3.leader_id(), service1.id);
+    let term = service1.term();
+    // learners neither vote nor get voted for
+    let ((_, _), granted) = service2.request_vote(&(term + 1), &service3.id, &u64::MAX, &u64::MAX).unwrap();
+    assert!(!granted);
+    let ((_, _), granted) = service3.request_vote(&(term + 1), &service2.id, &u64::MAX, &u64::MAX).unwrap();
+    assert!(!granted);
+    assert_eq!(service1.transfer_leadership(service3.id), Err(TransferError::NotVoter));
+
+    // a learner that never answers stays too far behind
+    let server4 = Server::new(&s4_addr);
+    Server::listen_and_resume(&server4);
+    client.add_learner(&s4_addr).unwrap().unwrap();
+    wait();
+    match service1.promote_learner(hash_str(&s4_addr)) {
+        Err(PromoteError::LaggingBehind(lag)) => assert!(lag > 10),
+        other => panic!("expect lagging behind, got {:?}", other)
+    }
+
+    assert_eq!(service2.promote_learner(service3.id), Err(PromoteError::NotLeader(service1.id)));
+    assert_eq!(service1.promote_learner(42), Err(PromoteError::UnknownMember));
+    client.promote_learner(service3.id).unwrap();
+    assert_eq!(service1.promote_learner(service3.id), Err(PromoteError::NotLearner));
+    wait();
+    let ((_, _), granted) = service2.request_vote(&(term + 1), &service3.id, &u64::MAX, &u64::MAX).unwrap();
+    assert!(granted);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 201);
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 201);
+}
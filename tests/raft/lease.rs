@@ -0,0 +1,72 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use super::{wait, options, number_service_with};
+
+fn number_service(addr: &String, lease_reads: bool) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(Options {
+        lease_reads: lease_reads,
+        ..options(Storage::Default(), addr)
+    }, "lease")
+}
+
+#[test]
+fn lease_follows_leadership() {
+    let s1_addr = String::from("127.0.0.1:1622");
+    let s2_addr = String::from("127.0.0.1:1623");
+    let s3_addr = String::from("127.0.0.1:1624");
+    let sm_id = U32::Number::new_by_name(&String::from("lease"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, true);
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, true);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, true);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+    // heartbeats of the checker keep extending it
+    assert!(service1.has_lease());
+    assert!(!service2.has_lease());
+    assert!(!service3.has_lease());
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    client.read_consistency(Consistency::LeaderLease);
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..50 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+        assert_eq!(sm_client.get().unwrap().unwrap(), i + 1);
+    }
+
+    // the old leader gives up its lease with the leadership
+    client.transfer_leadership(service2.id).unwrap();
+    assert!(!service1.has_lease());
+    assert_eq!(sm_client.get().unwrap().unwrap(), 50);
+    wait();
+    assert!(!service1.has_lease());
+    assert!(service2.has_lease());
+
+    // a higher term revokes it right away, reads are confirmed by heartbeats for the rest of the term
+    let term = service2.term();
+    service2.request_vote(&(term + 1), &service3.id, &0, &0).unwrap();
+    assert!(!service2.has_lease());
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 51);
+    assert_eq!(sm_client.get().unwrap().unwrap(), 51);
+}
+
+#[test]
+fn lease_disabled() {
+    let addr = String::from("127.0.0.1:1625");
+    let sm_id = U32::Number::new_by_name(&String::from("lease"), 0).id;
+    let (service, _server) = number_service(&addr, false);
+    service.bootstrap();
+    wait();
+    assert!(service.is_leader());
+    assert!(!service.has_lease());
+    let client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    client.read_consistency(Consistency::LeaderLease);
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 1);
+    assert_eq!(sm_client.get().unwrap().unwrap(), 1);
+}
@@ -0,0 +1,81 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use super::{wait, options, number_service_with};
+
+const HEARTBEAT_MS: i64 = 200;
+
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(Options {
+        election_timeout: Duration::from_millis(1000)..Duration::from_millis(2000),
+        heartbeat_interval: Duration::from_millis(HEARTBEAT_MS as u64),
+        ..options(Storage::Default(), addr)
+    }, "leave")
+}
+
+#[test]
+fn leader_leaves_under_load() {
+    let s1_addr = String::from("127.0.0.1:1673");
+    let s2_addr = String::from("127.0.0.1:1674");
+    let s3_addr = String::from("127.0.0.1:1675");
+    let sm_id = U32::Number::new_by_name(&String::from("leave"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr);
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = number_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3) = number_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let writing = {
+        let stopped = stopped.clone();
+        let servers = vec!(s1_addr.clone(), s2_addr.clone(), s3_addr.clone());
+        thread::spawn(move || {
+            let client = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+            let sm_client = SMClient::new(sm_id, &client);
+            let mut last_write = get_time();
+            let mut longest_gap = 0;
+            let mut writes = 0;
+            while !stopped.load(Ordering::SeqCst) {
+                sm_client.incr_and_get().unwrap().unwrap();
+                let now = get_time();
+                longest_gap = ::std::cmp::max(longest_gap, now - last_write);
+                last_write = now;
+                writes += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            (writes, longest_gap)
+        })
+    };
+    thread::sleep(Duration::from_millis(500));
+    assert!(service1.is_leader());
+    service1.leave_cluster().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    stopped.store(true, Ordering::SeqCst);
+    let (writes, longest_gap) = writing.join().unwrap();
+    assert!(writes > 0);
+    assert!(longest_gap < HEARTBEAT_MS, "writes stopped for {}ms", longest_gap);
+
+    let leader = if service2.is_leader() {&service2} else {&service3};
+    assert!(leader.is_leader());
+    assert_eq!(leader.num_members(), 2);
+    assert_eq!(service1.status().role, Role::Offline);
+    // the departed server stays out of elections
+    let term = service1.term();
+    wait();
+    assert_eq!(service1.term(), term);
+    assert!(leader.is_leader());
+    assert_eq!(status_members(leader), vec!(service2.id.min(service3.id), service2.id.max(service3.id)));
+}
+
+fn status_members(service: &Arc<RaftService>) -> Vec<u64> {
+    service.status().members.iter().map(|member| member.id).collect()
+}
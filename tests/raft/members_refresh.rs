@@ -0,0 +1,61 @@
+use bifrost::raft::*;
+use bifrost::raft::client::{RaftClient, ClientOptions};
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use super::{wait, local_value, number_service};
+
+#[test]
+fn client_outlives_seeds() {
+    let s1_addr = String::from("127.0.0.1:1707");
+    let s2_addr = String::from("127.0.0.1:1708");
+    let s3_addr = String::from("127.0.0.1:1709");
+    let s4_addr = String::from("127.0.0.1:1710");
+    let sm_id = U32::Number::new_by_name(&String::from("members_refresh"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "members_refresh");
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = number_service(&s2_addr, "members_refresh");
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let seeds = vec!(s1_addr.clone(), s2_addr.clone());
+    let client = RaftClient::with_options(&seeds, DEFAULT_SERVICE_ID, ClientOptions {
+        members_refresh: Some(Duration::from_millis(200)),
+        ..ClientOptions::Default()
+    }).unwrap();
+    assert_eq!(client.members().len(), 2);
+    let changes = Arc::new(AtomicUsize::new(0));
+    let changes_ref = changes.clone();
+    client.on_members_changed(move |_| {changes_ref.fetch_add(1, Ordering::SeqCst);});
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 1);
+
+    // members joined later are found without a request failing
+    let (service3, _server3) = number_service(&s3_addr, "members_refresh");
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service4, _server4) = number_service(&s4_addr, "members_refresh");
+    service4.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+    let mut expected = vec!(
+        (service1.id, s1_addr.clone()), (service2.id, s2_addr.clone()),
+        (service3.id, s3_addr.clone()), (service4.id, s4_addr.clone())
+    );
+    expected.sort();
+    assert_eq!(client.members(), expected);
+    assert!(changes.load(Ordering::SeqCst) >= 1);
+
+    // none of the servers the client was created with is left
+    service1.leave_cluster().unwrap();
+    service2.leave_cluster().unwrap();
+    wait();
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 2);
+    assert!(client.leader_id() == service3.id || client.leader_id() == service4.id);
+    wait();
+    let mut expected = vec!((service3.id, s3_addr.clone()), (service4.id, s4_addr.clone()));
+    expected.sort();
+    assert_eq!(client.members(), expected);
+    assert_eq!(local_value(&service3, sm_id), 2);
+    assert_eq!(local_value(&service4, sm_id), 2);
+}
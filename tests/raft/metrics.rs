@@ -0,0 +1,95 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use super::{wait, options};
+
+fn metrics_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("metrics"), 0)));
+    (service, server)
+}
+
+#[test]
+fn counters_move() {
+    let s1_addr = String::from("127.0.0.1:1704");
+    let s2_addr = String::from("127.0.0.1:1705");
+    let s3_addr = String::from("127.0.0.1:1706");
+    let sm_id = U32::Number::new_by_name(&String::from("metrics"), 0).id;
+    let (service1, _server1) = metrics_service(&s1_addr);
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = metrics_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3) = metrics_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let leader = service1.metrics();
+    assert_eq!(leader.elections_won, 1);
+    assert!(leader.heartbeats_sent > 0);
+    assert!(service2.metrics().heartbeats_received > 0);
+    assert_eq!(leader.replication_lag.len(), 2);
+    assert!(service2.metrics().replication_lag.is_empty());
+
+    // a burst of writes
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..50 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    wait();
+    let leader = service1.metrics();
+    assert!(leader.proposals_accepted >= 50);
+    assert!(leader.commit_latency.count >= 50);
+    assert!(leader.apply_latency.count >= 50);
+    assert_eq!(leader.replication_lag.get(&service2.id), Some(&0));
+    assert_eq!(leader.replication_lag.get(&service3.id), Some(&0));
+    let follower = service3.metrics();
+    assert!(follower.apply_latency.count >= 50);
+    assert_eq!(follower.proposals_accepted, 0);
+
+    // followers refuse commands
+    let cmd = U32::commands::incr_and_get::new();
+    let (fn_id, _, data) = cmd.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    match service2.c_command(&entry).unwrap() {
+        ClientCmdResponse::NotLeader { .. } => {},
+        other => panic!("expect not leader, got {:?}", other)
+    }
+    assert_eq!(service2.metrics().proposals_rejected, 1);
+
+    // a forced election
+    let before = service2.metrics();
+    service1.transfer_leadership(service2.id).unwrap();
+    wait();
+    let after = service2.metrics();
+    assert!(after.elections_started > before.elections_started);
+    assert_eq!(after.elections_won, before.elections_won + 1);
+    assert!(after.heartbeats_sent > before.heartbeats_sent);
+    assert_eq!(after.replication_lag.len(), 2);
+    assert!(service1.metrics().replication_lag.is_empty());
+
+    // one call for the metrics of every member
+    let scraped = client.scrape_metrics();
+    assert_eq!(scraped.len(), 3);
+    assert_eq!(scraped[&service2.id].elections_won, after.elections_won);
+    assert!(scraped[&service1.id].proposals_accepted >= 50);
+    let info = service2.cluster_info();
+    assert_eq!(info.metrics().elections_won, after.elections_won);
+}
@@ -1,8 +1,95 @@
+use bifrost::raft::*;
+use bifrost::rpc::Server;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::commands::get;
+use std::sync::Arc;
 use std::{thread, time};
 
 mod primary;
 mod callback;
+mod recovery;
+mod compaction;
+mod pre_vote;
+mod transfer;
+mod learner;
+mod reconfiguration;
+mod read_index;
+mod lease;
+mod bounded_stale;
+mod pipelining;
+mod slow_follower;
+mod session;
+mod redirect;
+mod retry;
+mod status;
+mod timing;
+mod check_quorum;
+mod noop;
+mod witness;
+mod parallel_apply;
+mod observer;
+mod bootstrap;
+mod leave;
+mod backpressure;
+mod lagging;
+mod priority;
+mod command_ctx;
+mod applied_index;
+mod multi;
+mod async_snapshot;
+mod admin;
+mod entry_size;
+mod simulation;
+mod metrics;
+mod members_refresh;
+mod batching;
+mod no_leader;
 
 pub fn wait() {
     thread::sleep(time::Duration::from_secs(2))
-}
\ No newline at end of file
+}
+
+// options of a test server, everything else is the default
+pub fn options(storage: Storage, address: &String) -> Options {
+    Options {
+        storage: storage,
+        address: address.clone(),
+        ..Options::Default()
+    }
+}
+
+// started raft server on the address of the options, with a u32 number state machine of the name
+pub fn number_service_with(opts: Options, sm_name: &str) -> (Arc<RaftService>, Arc<Server>) {
+    let server = Server::new(&opts.address);
+    let service = RaftService::new(opts);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from(sm_name), 0)));
+    (service, server)
+}
+
+pub fn number_service(addr: &String, sm_name: &str) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(options(Storage::Default(), addr), sm_name)
+}
+
+// value of the u32 number in the state machine of the server, without going through the leader
+pub fn local_value(service: &Arc<RaftService>, sm_id: u64) -> u32 {
+    let msg = get::new();
+    let (fn_id, _, data) = msg.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    match service.c_query(&entry).unwrap() {
+        ClientQryResponse::Success { data, .. } => msg.decode_return(&data.unwrap()).unwrap().unwrap(),
+        ClientQryResponse::LeftBehind => panic!("left behind")
+    }
+}
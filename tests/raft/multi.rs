@@ -0,0 +1,88 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::multi::{MultiRaft, GroupError};
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use std::time::Duration;
+use super::{wait, local_value};
+
+const MULTI_SERVICE_ID: u64 = 1690;
+const GROUPS: u64 = 100;
+
+fn group_options() -> Options {
+    Options {
+        election_timeout: Duration::from_millis(500)..Duration::from_millis(1000),
+        heartbeat_interval: Duration::from_millis(50),
+        ..Options::Default()
+    }
+}
+
+fn multi_server(addr: &String) -> Arc<MultiRaft> {
+    let server = Server::new(addr);
+    Server::listen_and_resume(&server);
+    MultiRaft::new(&server, addr, MULTI_SERVICE_ID)
+}
+
+fn number_group(multi: &Arc<MultiRaft>, group_id: u64) -> Arc<RaftService> {
+    let group = multi.add_group(group_id, group_options()).unwrap();
+    group.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("multi"), 0)));
+    group
+}
+
+#[test]
+fn hundred_groups_on_three_servers() {
+    let addrs: Vec<String> = (1690..1693).map(|port| format!("127.0.0.1:{}", port)).collect();
+    let sm_id = U32::Number::new_by_name(&String::from("multi"), 0).id;
+    let multis: Vec<Arc<MultiRaft>> = addrs.iter().map(multi_server).collect();
+    for group_id in 0..GROUPS {
+        // leaders are spread over the servers
+        let first = (group_id % 3) as usize;
+        number_group(&multis[first], group_id).bootstrap().unwrap();
+        for i in 1..3 {
+            let group = number_group(&multis[(first + i) % 3], group_id);
+            group.join(&vec!(addrs[first].clone())).unwrap().unwrap();
+        }
+    }
+    match multis[0].add_group(1, group_options()) {
+        Err(GroupError::Exists) => {},
+        _ => panic!("group added twice")
+    }
+    assert_eq!(multis[1].group_ids(), (0..GROUPS).collect::<Vec<_>>());
+
+    for group_id in 0..GROUPS {
+        let client = RaftClient::new(&addrs, multis[0].group_service_id(group_id)).unwrap();
+        let sm_client = SMClient::new(sm_id, &client);
+        sm_client.set(&(group_id as u32)).unwrap().unwrap();
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), group_id as u32 + 1);
+    }
+    wait();
+    for group_id in 0..GROUPS {
+        let members: Vec<Arc<RaftService>> = multis.iter().map(|multi| multi.group(group_id).unwrap()).collect();
+        // every group has a log and members of its own
+        for member in &members {
+            assert_eq!(local_value(member, sm_id), group_id as u32 + 1);
+            assert_eq!(member.num_members(), 3);
+        }
+        assert_eq!(members.iter().filter(|member| member.is_leader()).count(), 1);
+    }
+    for multi in &multis {
+        let leading = multi.group_ids().iter().filter(|id| multi.group(**id).unwrap().is_leader()).count();
+        assert!(leading > 0);
+        // heartbeats of the groups it leads go to each peer together
+        let stats = multi.heartbeat_stats();
+        assert!(stats.frames > 0);
+        assert!(stats.beats > stats.frames * 10, "{:?}", stats);
+    }
+
+    // the group goes on without the member stopped
+    let leader = multis.iter().position(|multi| multi.group(0).unwrap().is_leader()).unwrap();
+    let stopped = (leader + 1) % 3;
+    assert!(multis[stopped].remove_group(0).is_some());
+    assert!(multis[stopped].group(0).is_none());
+    let client = RaftClient::new(&vec!(addrs[leader].clone()), multis[0].group_service_id(0)).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 2);
+    assert!(multis[leader].group(0).unwrap().is_leader());
+}
@@ -0,0 +1,66 @@
+use bifrost::raft::*;
+use bifrost::raft::client::{RaftClient, ClientOptions, RetryPolicy, NoLeader};
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use std::time::Duration;
+use super::{wait, options, number_service_with};
+
+// slow elections leave a gap without a leader the test can see
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(Options {
+        election_timeout: Duration::from_secs(3)..Duration::from_secs(4),
+        heartbeat_interval: Duration::from_millis(100),
+        ..options(Storage::Default(), addr)
+    }, "no_leader")
+}
+
+#[test]
+fn no_leader_during_election() {
+    let s1_addr = String::from("127.0.0.1:1714");
+    let s2_addr = String::from("127.0.0.1:1715");
+    let s3_addr = String::from("127.0.0.1:1716");
+    let sm_id = U32::Number::new_by_name(&String::from("no_leader"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr);
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = number_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let (service3, _server3) = number_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let servers = vec!(s1_addr.clone(), s2_addr.clone(), s3_addr.clone());
+    let client = RaftClient::with_options(&servers, DEFAULT_SERVICE_ID, ClientOptions {
+        retry: RetryPolicy {
+            max_retries: 5,
+            base_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(100),
+            deadline: Duration::from_millis(500),
+        },
+        ..ClientOptions::Default()
+    }).unwrap();
+    assert_eq!(client.wait_for_leader(Duration::from_secs(1)), Ok((service1.id, s1_addr.clone())));
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 1);
+
+    // the others wait out the election timeout before electing one of them
+    assert!(service1.leave());
+    match sm_client.incr_and_get() {
+        Err(ExecError::NoLeader) => {},
+        other => panic!("expect no leader, got {:?}", other)
+    }
+    assert_eq!(client.wait_for_leader(Duration::from_millis(100)), Err(NoLeader));
+
+    let (leader_id, leader_addr) = client.wait_for_leader(Duration::from_secs(10)).unwrap();
+    if leader_id == service2.id {
+        assert_eq!(leader_addr, s2_addr);
+        assert!(service2.is_leader());
+    } else {
+        assert_eq!(leader_id, service3.id);
+        assert_eq!(leader_addr, s3_addr);
+        assert!(service3.is_leader());
+    }
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 2);
+}
@@ -0,0 +1,56 @@
+use bifrost::raft::*;
+use bifrost::store::number::U32;
+use super::{wait, local_value, number_service};
+
+#[test]
+fn new_leader_commits_earlier_terms() {
+    let s1_addr = String::from("127.0.0.1:1661");
+    let s2_addr = String::from("127.0.0.1:1662");
+    let s3_addr = String::from("127.0.0.1:1663");
+    let sm_id = U32::Number::new_by_name(&String::from("noop"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "noop");
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, "noop");
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "noop");
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    // the leader dies after replicating an entry to both followers, before telling them it is committed
+    let _dead = service1.read_meta();
+    let status = service2.status();
+    let cmd = U32::commands::incr_and_get::new();
+    let (fn_id, _, data) = cmd.encode();
+    let mut entry = LogEntry {
+        id: status.last_log_id + 1,
+        term: status.term,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data,
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    entry.checksum = entry.digest();
+    let entries = vec!(entry);
+    for follower in &[&service2, &service3] {
+        let (_, result) = follower.append_entries(
+            &status.term, &service1.id, &status.last_log_id, &status.last_log_term,
+            &Some(entries.clone()), &status.commit_index
+        ).unwrap();
+        match result {
+            AppendEntriesResult::Ok => {},
+            other => panic!("expect appended, got {:?}", other)
+        }
+        assert_eq!(local_value(follower, sm_id), 0);
+    }
+
+    // no client writes to the new leader, its no-op entry commits the one of the old term
+    wait();
+    let leader = if service2.is_leader() {&service2} else {&service3};
+    assert!(leader.is_leader());
+    assert!(leader.term() > status.term);
+    assert_eq!(local_value(&service2, sm_id), 1);
+    assert_eq!(local_value(&service3, sm_id), 1);
+}
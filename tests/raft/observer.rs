@@ -0,0 +1,91 @@
+use bifrost::raft::*;
+use bifrost::raft::observer::RaftObserver;
+use bifrost::rpc::Server;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use super::{wait, options};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Role(Role, u64),
+    Leader(Option<u64>),
+    Commit(u64),
+    Members(usize),
+}
+
+struct Recorder {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl RaftObserver for Recorder {
+    fn on_role_change(&mut self, role: Role, term: u64) {
+        self.events.lock().push(Event::Role(role, term));
+    }
+    fn on_leader_change(&mut self, leader_id: Option<u64>) {
+        self.events.lock().push(Event::Leader(leader_id));
+    }
+    fn on_commit(&mut self, index: u64) {
+        self.events.lock().push(Event::Commit(index));
+    }
+    fn on_membership_change(&mut self, members: Vec<MemberStatus>) {
+        self.events.lock().push(Event::Members(members.len()));
+    }
+}
+
+fn observed_service(addr: &String) -> (Arc<RaftService>, Arc<Server>, Arc<Mutex<Vec<Event>>>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    service.register_observer(Box::new(Recorder { events: events.clone() }));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    (service, server, events)
+}
+
+#[test]
+fn leader_failover_notifications() {
+    let s1_addr = String::from("127.0.0.1:1668");
+    let s2_addr = String::from("127.0.0.1:1669");
+    let s3_addr = String::from("127.0.0.1:1670");
+    let (service1, server1, events1) = observed_service(&s1_addr);
+    service1.bootstrap();
+    let (service2, _server2, events2) = observed_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3, events3) = observed_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    // registered before start, the first event is the undefined role
+    assert_eq!(events1.lock()[0], Event::Role(Role::Undefined, 0));
+    assert!(events1.lock().contains(&Event::Role(Role::Leader, service1.term())));
+    assert!(events1.lock().contains(&Event::Members(3)));
+    for events in &[&events2, &events3] {
+        let events = events.lock();
+        assert!(events.contains(&Event::Leader(Some(service1.id))));
+        assert!(events.contains(&Event::Members(3)));
+        // commits only go up
+        let commits: Vec<u64> = events.iter().filter_map(|event| match *event {
+            Event::Commit(index) => Some(index),
+            _ => None
+        }).collect();
+        assert!(!commits.is_empty());
+        assert!(commits.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    server1.remove_service(DEFAULT_SERVICE_ID);
+    let _dead = service1.read_meta();
+    events2.lock().clear();
+    events3.lock().clear();
+    wait();
+    let (leader, leader_events, follower_events) = if service2.is_leader() {
+        (&service2, &events2, &events3)
+    } else {
+        (&service3, &events3, &events2)
+    };
+    assert!(leader.is_leader());
+    assert!(leader_events.lock().contains(&Event::Role(Role::Leader, leader.term())));
+    assert!(leader_events.lock().contains(&Event::Leader(Some(leader.id))));
+    assert!(follower_events.lock().contains(&Event::Leader(Some(leader.id))));
+    assert!(!follower_events.lock().contains(&Event::Role(Role::Leader, leader.term())));
+}
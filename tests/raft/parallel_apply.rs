@@ -0,0 +1,73 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::StateMachineCtl;
+use bifrost::store::number::U32;
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use super::{wait, options};
+
+// takes a second for every command
+pub struct Slow {
+    applied: u64,
+}
+
+raft_state_machine! {
+    def cmd crawl() -> u64;
+}
+
+impl StateMachineCmds for Slow {
+    fn crawl(&mut self) -> Result<u64, ()> {
+        thread::sleep(Duration::from_secs(1));
+        self.applied += 1;
+        Ok(self.applied)
+    }
+}
+
+impl StateMachineCtl for Slow {
+    raft_sm_complete!();
+    fn snapshot(&self) -> Option<Vec<u8>> { None }
+    fn recover(&mut self, _data: Vec<u8>) {}
+    fn id(&self) -> u64 {12}
+}
+
+#[test]
+fn slow_state_machine_not_blocking_others() {
+    let addr = String::from("127.0.0.1:1667");
+    let service = RaftService::new(Options {
+        apply_parallelism: 2,
+        ..options(Storage::Default(), &addr)
+    });
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    let number = U32::Number::new_by_name(&String::from("parallel_apply"), 0);
+    let number_id = number.id;
+    service.register_state_machine(Box::new(Slow { applied: 0 }));
+    service.register_state_machine(Box::new(number));
+    service.bootstrap();
+    wait();
+
+    let raft_client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    let slow_client = client::SMClient::new(12, &raft_client);
+    let crawling = thread::spawn(move || {
+        let started_at = get_time();
+        assert_eq!(slow_client.crawl().unwrap().unwrap(), 1);
+        get_time() - started_at
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // entries after the slow one are applied while it is still being applied
+    let number_client = U32::client::SMClient::new(number_id, &raft_client);
+    let started_at = get_time();
+    for i in 0..10 {
+        assert_eq!(number_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    let incr_time = get_time() - started_at;
+    assert!(incr_time < 500, "increments took {}ms", incr_time);
+    assert!(crawling.join().unwrap() >= 1000);
+    assert_eq!(number_client.get().unwrap().unwrap(), 10);
+}
@@ -0,0 +1,63 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use std::cmp::max;
+use std::sync::Arc;
+use std::thread;
+use super::{wait, local_value, options, number_service_with};
+
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(Options {
+        replication: Replication {
+            max_in_flight: 8,
+            max_batch_bytes: 64 * 1024,
+            ..Replication::Default()
+        },
+        ..options(Storage::Default(), addr)
+    }, "pipelining")
+}
+
+#[test]
+fn concurrent_proposals() {
+    let s1_addr = String::from("127.0.0.1:1629");
+    let s2_addr = String::from("127.0.0.1:1630");
+    let s3_addr = String::from("127.0.0.1:1631");
+    let sm_id = U32::Number::new_by_name(&String::from("pipelining"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr);
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let num_threads = 50;
+    let num_entries = 50000;
+    let started = get_time();
+    let writers: Vec<_> = (0..num_threads).map(|_| {
+        let client = client.clone();
+        thread::spawn(move || {
+            let sm_client = SMClient::new(sm_id, &client);
+            (0..num_entries / num_threads).map(|_| {
+                sm_client.incr_and_get().unwrap().unwrap()
+            }).collect::<Vec<u32>>()
+        })
+    }).collect();
+    let mut results: Vec<u32> = writers.into_iter().flat_map(|writer| writer.join().unwrap()).collect();
+    let elapsed = get_time() - started;
+    println!("committed {} entries in {}ms, {} per second", num_entries, elapsed, num_entries as i64 * 1000 / max(elapsed, 1));
+
+    // every increment took effect once, in an order all clients agree on
+    results.sort();
+    assert_eq!(results, (1..num_entries + 1).collect::<Vec<u32>>());
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.get().unwrap().unwrap(), num_entries);
+    wait();
+    assert_eq!(local_value(&service1, sm_id), num_entries);
+    assert_eq!(local_value(&service2, sm_id), num_entries);
+    assert_eq!(local_value(&service3, sm_id), num_entries);
+}
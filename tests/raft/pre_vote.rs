@@ -0,0 +1,44 @@
+use bifrost::raft::*;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use super::{wait, options};
+
+fn pre_voting_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    (service, server)
+}
+
+#[test]
+fn partitioned_server_rejoins() {
+    let s1_addr = String::from("127.0.0.1:1604");
+    let s2_addr = String::from("127.0.0.1:1605");
+    let s3_addr = String::from("127.0.0.1:1606");
+    let (service1, _server1) = pre_voting_service(&s1_addr);
+    service1.bootstrap();
+    let (service2, _server2) = pre_voting_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, server3) = pre_voting_service(&s3_addr);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+    let term = service1.term();
+    assert_eq!(service3.term(), term);
+
+    // heartbeats of the leader no longer reach service3, it times out many times
+    server3.remove_service(DEFAULT_SERVICE_ID);
+    wait();
+    // pre votes were refused by servers hearing from the leader
+    assert_eq!(service3.term(), term);
+    assert!(!service3.is_leader());
+
+    server3.register_service(DEFAULT_SERVICE_ID, &service3);
+    wait();
+    assert!(service1.is_leader());
+    assert_eq!(service1.term(), term);
+    assert_eq!(service2.term(), term);
+    assert_eq!(service3.term(), term);
+    assert_eq!(service3.leader_id(), service1.id);
+}
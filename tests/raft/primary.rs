@@ -2,15 +2,11 @@ use bifrost::raft::*;
 use bifrost::raft::state_machine::master::ExecError;
 use bifrost::rpc::Server;
 use std::fs::File;
-use super::wait;
+use super::{wait, options};
 
 #[test]
 fn startup(){
-    let (success, _, _) = RaftService::new_server(Options {
-        storage: Storage::Default(),
-        address: String::from("127.0.0.1:2000"),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let (success, _, _) = RaftService::new_server(options(Storage::Default(), &String::from("127.0.0.1:2000")));
     assert!(success);
 }
 
@@ -19,22 +15,14 @@ fn server_membership(){
     let s1_addr = String::from("127.0.0.1:2001");
     let s2_addr = String::from("127.0.0.1:2002");
     let s3_addr = String::from("127.0.0.1:2003");
-    let service1 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s1_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let service1 = RaftService::new(options(Storage::Default(), &s1_addr));
     let server1 = Server::new(&s1_addr);
     server1.register_service(DEFAULT_SERVICE_ID, &service1);
     Server::listen_and_resume(&server1);
     assert!(RaftService::start(&service1));
     service1.bootstrap();
     assert_eq!(service1.num_members(), 1);
-    let service2 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s2_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let service2 = RaftService::new(options(Storage::Default(), &s2_addr));
     let server2 = Server::new(&s2_addr);
     server2.register_service(DEFAULT_SERVICE_ID, &service2);
     Server::listen_and_resume(&server2);
@@ -49,11 +37,7 @@ fn server_membership(){
     assert!(join_result.is_ok());
     assert_eq!(service1.num_members(), 2);
     assert_eq!(service2.num_members(), 2);
-    let service3 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s3_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let service3 = RaftService::new(options(Storage::Default(), &s3_addr));
     let server3 = Server::new(&s3_addr);
     server3.register_service(DEFAULT_SERVICE_ID, &service3);
     Server::listen_and_resume(&server3);
@@ -91,31 +75,11 @@ fn log_replication(){
     let s3_addr = String::from("127.0.0.1:2006");
     let s4_addr = String::from("127.0.0.1:2007");
     let s5_addr = String::from("127.0.0.1:2008");
-    let service1 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s1_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
-    let service2 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s2_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
-    let service3 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s3_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
-    let service4 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s4_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
-    let service5 = RaftService::new(Options {
-        storage: Storage::Default(),
-        address: s5_addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let service1 = RaftService::new(options(Storage::Default(), &s1_addr));
+    let service2 = RaftService::new(options(Storage::Default(), &s2_addr));
+    let service3 = RaftService::new(options(Storage::Default(), &s3_addr));
+    let service4 = RaftService::new(options(Storage::Default(), &s4_addr));
+    let service5 = RaftService::new(options(Storage::Default(), &s5_addr));
 
 
     let server1 = Server::new(&s1_addr);
@@ -184,3 +148,44 @@ fn log_replication(){
     assert_eq!(service4.leader_id(), service1.id);
     assert_eq!(service5.leader_id(), service1.id);
 }
+
+#[test]
+fn expired_command(){
+    use bifrost::raft::client::RaftClient;
+    use bifrost::raft::state_machine::configs::{CONFIG_SM_ID, MemberRole};
+    use bifrost::raft::state_machine::configs::commands::new_member_;
+    use bifrost::rpc::context;
+    use bifrost::utils::time::get_time;
+
+    let addr = String::from("127.0.0.1:2130");
+    let service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.bootstrap();
+    wait();
+    let num_logs = service.num_logs();
+    let cmd = new_member_::new(&String::from("127.0.0.1:2131"), &MemberRole::Voter);
+    let expired = get_time() - 10000;
+    match context::with_deadline(expired, || service.c_command(&LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: CONFIG_SM_ID,
+        fn_id: cmd.encode().0,
+        data: cmd.data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0
+    })) {
+        Ok(ClientCmdResponse::DeadlineExceeded) => {},
+        other => panic!("expect deadline exceeded, got {:?}", other)
+    }
+    let client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    match context::with_deadline(expired, || client.execute(CONFIG_SM_ID, &cmd)) {
+        Err(ExecError::DeadlineExceeded) => {},
+        other => panic!("expect deadline exceeded, got {:?}", other)
+    }
+    assert_eq!(service.num_logs(), num_logs);
+}
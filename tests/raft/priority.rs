@@ -0,0 +1,119 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::StateMachineCtl;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use bifrost::utils::time::get_time;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, Shutdown};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use super::{local_value, options};
+
+const SNAPSHOT_BYTES: usize = 4 * 1024 * 1024;
+const BYTES_PER_SEC: usize = 1024 * 1024;
+
+// snapshots of megabytes
+pub struct Blob {
+    data: Vec<u8>,
+}
+
+raft_state_machine! {
+    def qry size() -> u64;
+}
+
+impl StateMachineCmds for Blob {
+    fn size(&self) -> Result<u64, ()> {
+        Ok(self.data.len() as u64)
+    }
+}
+
+impl StateMachineCtl for Blob {
+    raft_sm_complete!();
+    fn snapshot(&self) -> Option<Vec<u8>> { Some(self.data.clone()) }
+    fn recover(&mut self, data: Vec<u8>) { self.data = data; }
+    fn id(&self) -> u64 {14}
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let read = match from.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read
+        };
+        if to.write_all(&buf[..read]).is_err() {break;}
+        thread::sleep(Duration::from_millis((read * 1000 / BYTES_PER_SEC) as u64));
+    }
+    to.shutdown(Shutdown::Both).ok();
+}
+
+// a slow link in front of the server, every connection has the bandwidth of its own
+fn throttled_proxy(listen: &String, upstream: &String) {
+    let listener = TcpListener::bind(listen.as_str()).unwrap();
+    let upstream = upstream.clone();
+    thread::spawn(move || for incoming in listener.incoming() {
+        let inbound = match incoming {
+            Ok(inbound) => inbound,
+            Err(_) => continue
+        };
+        let outbound = match TcpStream::connect(upstream.as_str()) {
+            Ok(outbound) => outbound,
+            Err(_) => continue
+        };
+        let (inbound_back, outbound_back) = (inbound.try_clone().unwrap(), outbound.try_clone().unwrap());
+        thread::spawn(move || pipe(inbound, outbound));
+        thread::spawn(move || pipe(outbound_back, inbound_back));
+    });
+}
+
+fn blob_service(addr: &String, listen: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        pre_vote: false,
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(listen);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(Blob { data: vec![7; SNAPSHOT_BYTES] }));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("priority"), 0)));
+    (service, server)
+}
+
+#[test]
+fn no_elections_during_snapshot_transfer() {
+    let s1_addr = String::from("127.0.0.1:1680");
+    let s2_addr = String::from("127.0.0.1:1681");
+    let s2_proxy = String::from("127.0.0.1:1682");
+    let sm_id = U32::Number::new_by_name(&String::from("priority"), 0).id;
+    let (service1, _server1) = blob_service(&s1_addr, &s1_addr);
+    service1.bootstrap().unwrap();
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..10 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    assert!(service1.compact_now());
+    assert!(service1.last_snapshot_size() >= SNAPSHOT_BYTES);
+
+    // the leader reaches the follower only through the slow link, the snapshot takes seconds
+    throttled_proxy(&s2_proxy, &s2_addr);
+    let (service2, _server2) = blob_service(&s2_proxy, &s2_addr);
+    let term = service1.term();
+    let started_at = get_time();
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    while local_value(&service2, sm_id) != 10 {
+        assert!(service1.is_leader());
+        assert_eq!(service1.term(), term);
+        assert!(get_time() - started_at < 30_000, "snapshot was not installed");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(get_time() - started_at > 1000, "the link was not slow");
+    assert!(service1.is_leader());
+    assert_eq!(service1.term(), term);
+    assert_eq!(service2.term(), term);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 11);
+}
@@ -0,0 +1,60 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::store::number::U32::commands::get;
+use super::number_service;
+
+#[test]
+fn read_after_write_on_another_client() {
+    let s1_addr = String::from("127.0.0.1:1619");
+    let s2_addr = String::from("127.0.0.1:1620");
+    let s3_addr = String::from("127.0.0.1:1621");
+    let sm_id = U32::Number::new_by_name(&String::from("read index"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "read index");
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, "read index");
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "read index");
+    service3.join(&vec!(s1_addr.clone(), s2_addr.clone())).unwrap();
+
+    let writer = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    // only knows a follower at first
+    let reader = RaftClient::new(&vec!(s3_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let leased_reader = RaftClient::new(&vec!(s2_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    leased_reader.read_consistency(Consistency::LeaderLease);
+    let writer_sm = SMClient::new(sm_id, &writer);
+    let reader_sm = SMClient::new(sm_id, &reader);
+    let leased_sm = SMClient::new(sm_id, &leased_reader);
+    for i in 0..50 {
+        assert_eq!(writer_sm.incr_and_get().unwrap().unwrap(), i + 1);
+        assert_eq!(reader_sm.get().unwrap().unwrap(), i + 1);
+        assert_eq!(leased_sm.get().unwrap().unwrap(), i + 1);
+    }
+    assert_eq!(reader.leader_id(), service1.id);
+
+    // followers redirect reads to the leader
+    let msg = get::new();
+    let (fn_id, _, data) = msg.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    match service3.c_leader_query(&entry, &Consistency::Linearizable).unwrap() {
+        ClientReadResponse::NotLeader { leader_hint } => assert_eq!(leader_hint, Some((service1.id, s1_addr.clone()))),
+        other => panic!("expect not leader, got {:?}", other)
+    }
+    match service1.c_leader_query(&entry, &Consistency::Linearizable).unwrap() {
+        ClientReadResponse::Success { data, .. } => {
+            assert_eq!(msg.decode_return(&data.unwrap()).unwrap().unwrap(), 50)
+        },
+        other => panic!("expect success, got {:?}", other)
+    }
+}
@@ -0,0 +1,83 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use super::{wait, local_value, number_service};
+
+fn add_member(client: &Arc<RaftClient>, addr: &String) {
+    loop {
+        match client.add_member(addr) {
+            Ok(Ok(())) => return,
+            // the previous change is still on its way to a majority
+            Err(ExecError::ConfigChangeInProgress) => thread::yield_now(),
+            other => panic!("cannot add {}, {:?}", addr, other)
+        }
+    }
+}
+
+#[test]
+fn grow_while_writing() {
+    let addrs: Vec<String> = (1614..1619).map(|port| format!("127.0.0.1:{}", port)).collect();
+    let sm_id = U32::Number::new_by_name(&String::from("reconfiguration"), 0).id;
+    let mut services = Vec::new();
+    let mut servers = Vec::new();
+    for addr in addrs.iter() {
+        let (service, server) = number_service(addr, "reconfiguration");
+        services.push(service);
+        servers.push(server);
+    }
+    services[0].bootstrap();
+    services[1].join(&vec!(addrs[0].clone())).unwrap().unwrap();
+    services[2].join(&vec!(addrs[0].clone())).unwrap().unwrap();
+    let client = RaftClient::new(&vec!(addrs[0].clone()), DEFAULT_SERVICE_ID).unwrap();
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let client = client.clone();
+        let stopped = stopped.clone();
+        thread::spawn(move || {
+            let sm_client = SMClient::new(sm_id, &client);
+            let mut values = Vec::new();
+            let mut failures = 0;
+            while !stopped.load(Ordering::Relaxed) {
+                match sm_client.incr_and_get() {
+                    Ok(Ok(value)) => values.push(value),
+                    _ => failures += 1
+                }
+            }
+            (values, failures)
+        })
+    };
+    add_member(&client, &addrs[3]);
+    add_member(&client, &addrs[4]);
+    wait();
+    stopped.store(true, Ordering::Relaxed);
+    let (values, failures) = writer.join().unwrap();
+    wait();
+
+    assert_eq!(services[0].num_members(), 5);
+    assert!(services[0].is_leader());
+    // every increment was applied once, in order
+    for pair in values.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+    let last = *values.last().unwrap();
+    assert!(last as usize >= values.len() && last as usize <= values.len() + failures);
+    for service in services.iter() {
+        assert_eq!(local_value(service, sm_id), last);
+        assert_eq!(service.leader_id(), services[0].id);
+    }
+
+    // the removed member no longer campaigns
+    client.remove_member(services[4].id).unwrap().unwrap();
+    wait();
+    wait();
+    assert_eq!(services[0].num_members(), 4);
+    assert!(services[0].is_leader());
+    assert_eq!(services[4].term(), services[0].term());
+    assert!(!services[4].is_leader());
+}
@@ -0,0 +1,153 @@
+use bifrost::raft::*;
+use bifrost::raft::wal::SyncPolicy;
+use bifrost::raft::disk::HardState;
+use bifrost::raft::storage::{LogStore, MemoryStore};
+use bifrost::raft::storage::tests::verify_log_store;
+use bifrost::rpc::Server;
+use parking_lot::Mutex;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use std::u64;
+use super::{wait, options};
+
+#[test]
+fn vote_survives_restart() {
+    let s1_addr = String::from("127.0.0.1:1594");
+    let s2_addr = String::from("127.0.0.1:1595");
+    let state_path = env::temp_dir().join("bifrost-raft-recovery-1595");
+    fs::remove_file(&state_path).ok();
+    let state_path = state_path.to_str().unwrap().to_string();
+    let service1 = RaftService::new(options(Storage::Default(), &s1_addr));
+    let server1 = Server::new(&s1_addr);
+    server1.register_service(DEFAULT_SERVICE_ID, &service1);
+    Server::listen_and_resume(&server1);
+    assert!(RaftService::start(&service1));
+    service1.bootstrap();
+    let service2 = RaftService::new(options(Storage::DISK(state_path.clone()), &s2_addr));
+    let server2 = Server::new(&s2_addr);
+    server2.register_service(DEFAULT_SERVICE_ID, &service2);
+    Server::listen_and_resume(&server2);
+    assert!(RaftService::start(&service2));
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+    // vote for service1 in the term after the one service2 is in
+    let vote_term = service2.term() + 1;
+    let ((term, _), granted) = service2.request_vote(&vote_term, &service1.id, &u64::MAX, &u64::MAX).unwrap();
+    assert!(granted);
+    assert_eq!(term, vote_term);
+
+    // crash, the state of service2 is only left on disk
+    server2.remove_service(DEFAULT_SERVICE_ID);
+    drop(service2);
+
+    let recovered = RaftService::new(options(Storage::DISK(state_path.clone()), &s2_addr));
+    assert!(RaftService::start(&recovered));
+    // the term of the vote is recovered with it
+    assert_eq!(recovered.term(), vote_term);
+    // another member of the configuration asks for the same term
+    let ((_, _), granted) = recovered.request_vote(&vote_term, &recovered.id, &u64::MAX, &u64::MAX).unwrap();
+    assert!(!granted);
+
+    // servers in memory forget the vote
+    let forgetful = RaftService::new(options(Storage::Default(), &s2_addr));
+    assert!(RaftService::start(&forgetful));
+    let ((_, _), granted) = forgetful.request_vote(&vote_term, &forgetful.id, &u64::MAX, &u64::MAX).unwrap();
+    assert!(granted);
+    fs::remove_file(&state_path).ok();
+}
+
+#[test]
+fn log_survives_restart() {
+    let s1_addr = String::from("127.0.0.1:1596");
+    let s2_addr = String::from("127.0.0.1:1597");
+    let log_dir = env::temp_dir().join("bifrost-raft-recovery-1596");
+    fs::remove_dir_all(&log_dir).ok();
+    let log_dir = log_dir.to_str().unwrap().to_string();
+    let storage = || Storage::DiskOptions {
+        path: log_dir.clone(),
+        sync_policy: SyncPolicy::EveryCommit,
+    };
+    let service1 = RaftService::new(options(storage(), &s1_addr));
+    let server1 = Server::new(&s1_addr);
+    server1.register_service(DEFAULT_SERVICE_ID, &service1);
+    Server::listen_and_resume(&server1);
+    assert!(RaftService::start(&service1));
+    service1.bootstrap();
+    let service2 = RaftService::new(options(Storage::Default(), &s2_addr));
+    let server2 = Server::new(&s2_addr);
+    server2.register_service(DEFAULT_SERVICE_ID, &service2);
+    Server::listen_and_resume(&server2);
+    assert!(RaftService::start(&service2));
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+    let num_logs = service1.num_logs();
+    let last_log_id = service1.last_log_id();
+    assert!(num_logs > 0);
+
+    // crash in the middle of writing the next record
+    server1.remove_service(DEFAULT_SERVICE_ID);
+    drop(service1);
+    let last_segment = fs::read_dir(&log_dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map(|ext| ext == "log").unwrap_or(false))
+        .max()
+        .unwrap();
+    OpenOptions::new().append(true).open(&last_segment).unwrap()
+        .write_all(&[64u8, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+
+    let recovered = RaftService::new(options(storage(), &s1_addr));
+    assert!(RaftService::start(&recovered));
+    assert_eq!(recovered.num_logs(), num_logs);
+    assert_eq!(recovered.last_log_id(), last_log_id);
+    // it was in a cluster, starting another one would split it
+    assert_eq!(recovered.bootstrap(), Err(BootstrapError::ExistingState));
+    fs::remove_dir_all(&log_dir).ok();
+}
+
+// outlives the servers using it, like a database the application keeps open
+struct SharedStore(Arc<Mutex<MemoryStore>>);
+
+impl LogStore for SharedStore {
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> { self.0.lock().append(entries) }
+    fn entries(&self, range: Range<u64>) -> io::Result<Vec<LogEntry>> { self.0.lock().entries(range) }
+    fn truncate_after(&mut self, id: u64) -> io::Result<()> { self.0.lock().truncate_after(id) }
+    fn truncate_before(&mut self, id: u64) -> io::Result<()> { self.0.lock().truncate_before(id) }
+    fn term(&self, id: u64) -> io::Result<Option<u64>> { self.0.lock().term(id) }
+    fn save_hard_state(&mut self, state: &HardState) -> io::Result<()> { self.0.lock().save_hard_state(state) }
+    fn hard_state(&self) -> io::Result<HardState> { self.0.lock().hard_state() }
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> { self.0.lock().save_snapshot(snapshot) }
+    fn snapshot(&self) -> io::Result<Option<Snapshot>> { self.0.lock().snapshot() }
+}
+
+#[test]
+fn custom_store_survives_restart() {
+    verify_log_store(&mut SharedStore(Arc::new(Mutex::new(MemoryStore::new()))));
+
+    let addr = String::from("127.0.0.1:1683");
+    let store = Arc::new(Mutex::new(MemoryStore::new()));
+    let options = |store: &Arc<Mutex<MemoryStore>>| options(Storage::Custom(Box::new(SharedStore(store.clone()))), &addr);
+    let service = RaftService::new(options(&store));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.bootstrap().unwrap();
+    let num_logs = service.num_logs();
+    let last_log_id = service.last_log_id();
+    let term = service.term();
+    assert!(num_logs > 0);
+    assert_eq!(store.lock().hard_state().unwrap().term, term);
+    assert_eq!(store.lock().entries(1..u64::MAX).unwrap().len(), num_logs);
+
+    server.remove_service(DEFAULT_SERVICE_ID);
+    drop(service);
+    let recovered = RaftService::new(options(&store));
+    assert!(RaftService::start(&recovered));
+    assert_eq!(recovered.num_logs(), num_logs);
+    assert_eq!(recovered.last_log_id(), last_log_id);
+    assert_eq!(recovered.term(), term);
+    assert_eq!(recovered.bootstrap(), Err(BootstrapError::ExistingState));
+}
@@ -0,0 +1,52 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use super::{wait, local_value, number_service};
+
+#[test]
+fn client_of_follower_finds_leader() {
+    let s1_addr = String::from("127.0.0.1:1639");
+    let s2_addr = String::from("127.0.0.1:1640");
+    let s3_addr = String::from("127.0.0.1:1641");
+    let sm_id = U32::Number::new_by_name(&String::from("redirect"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "redirect");
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, "redirect");
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "redirect");
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    // followers point to the leader
+    let cmd = U32::commands::set::new(&1);
+    let (fn_id, _, data) = cmd.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data,
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    match service3.c_command(&entry).unwrap() {
+        ClientCmdResponse::NotLeader { leader_hint } => assert_eq!(leader_hint, Some((service1.id, s1_addr.clone()))),
+        other => panic!("expect not leader, got {:?}", other)
+    }
+
+    let client = RaftClient::new(&vec!(s3_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    sm_client.set(&42).unwrap().unwrap();
+    assert_eq!(client.leader_id(), service1.id);
+    assert_eq!(sm_client.get().unwrap().unwrap(), 42);
+
+    // the cached leader is dropped once it redirects the client
+    service1.transfer_leadership(service2.id).unwrap();
+    sm_client.set(&43).unwrap().unwrap();
+    assert_eq!(client.leader_id(), service2.id);
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 43);
+}
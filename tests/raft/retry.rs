@@ -0,0 +1,63 @@
+use bifrost::raft::*;
+use bifrost::raft::client::{RaftClient, ClientOptions, RetryPolicy};
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use std::time::Duration;
+use super::{wait, local_value, number_service};
+
+#[test]
+fn commands_wait_out_election() {
+    let s1_addr = String::from("127.0.0.1:1642");
+    let s2_addr = String::from("127.0.0.1:1643");
+    let s3_addr = String::from("127.0.0.1:1644");
+    let sm_id = U32::Number::new_by_name(&String::from("retry"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "retry");
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, "retry");
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "retry");
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    let servers = vec!(s1_addr.clone(), s2_addr.clone(), s3_addr.clone());
+    let client = RaftClient::with_options(&servers, DEFAULT_SERVICE_ID, ClientOptions {
+        retry: RetryPolicy {
+            max_retries: 50,
+            base_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(200),
+            deadline: Duration::from_secs(10),
+        },
+        sessions: true,
+        ..ClientOptions::Default()
+    }).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 1);
+
+    // no leader until service2 or service3 times out and wins an election
+    assert!(service1.leave());
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 2);
+    assert!(client.leader_id() == service2.id || client.leader_id() == service3.id);
+    wait();
+    assert_eq!(local_value(&service2, sm_id), 2);
+    assert_eq!(local_value(&service3, sm_id), 2);
+
+    // a client that does not retry gives up at once
+    let impatient = RaftClient::with_options(&servers, DEFAULT_SERVICE_ID, ClientOptions {
+        retry: RetryPolicy {
+            max_retries: 0,
+            base_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(200),
+            deadline: Duration::from_secs(10),
+        },
+        sessions: true,
+        ..ClientOptions::Default()
+    }).unwrap();
+    let leader = if service2.is_leader() {&service2} else {&service3};
+    let follower = if service2.is_leader() {&service3} else {&service2};
+    leader.transfer_leadership(follower.id).unwrap();
+    match SMClient::new(sm_id, &impatient).incr_and_get() {
+        Err(ExecError::TooManyRetry { attempts }) => assert_eq!(attempts, 1),
+        other => panic!("expect too many retry, got {:?}", other)
+    }
+}
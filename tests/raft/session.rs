@@ -0,0 +1,112 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::master::ExecError;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::{Server, RPCService, RPCRequestError, ShortcutPolicy, Bytes};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use super::{wait, local_value, options};
+
+// the leader answers nothing to the next request and hands over leadership
+struct FailingOver {
+    service: Arc<RaftService>,
+    failover_to: Mutex<Option<u64>>,
+}
+
+impl RPCService for FailingOver {
+    fn dispatch(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+        let response = self.service.dispatch(data);
+        if let Some(target) = self.failover_to.lock().take() {
+            self.service.transfer_leadership(target).ok();
+            return Err(RPCRequestError::Rejected);
+        }
+        response
+    }
+    fn register_shortcut_service(&self, _service_ptr: usize, _server_id: u64, _service_id: u64, _policy: ShortcutPolicy) {}
+    fn unregister_shortcut_service(&self, _server_id: u64, _service_id: u64) {}
+}
+
+fn number_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("session"), 0)));
+    let server = Server::new(addr);
+    (service, server)
+}
+
+#[test]
+fn retry_after_failover_applied_once() {
+    let s1_addr = String::from("127.0.0.1:1635");
+    let s2_addr = String::from("127.0.0.1:1636");
+    let s3_addr = String::from("127.0.0.1:1637");
+    let sm_id = U32::Number::new_by_name(&String::from("session"), 0).id;
+    let (service1, server1) = number_service(&s1_addr);
+    let failing_over = Arc::new(FailingOver {
+        service: service1.clone(),
+        failover_to: Mutex::new(None),
+    });
+    server1.register_service_with_shortcut(DEFAULT_SERVICE_ID, &failing_over, ShortcutPolicy::Never);
+    Server::listen_and_resume(&server1);
+    service1.bootstrap();
+    let (service2, server2) = number_service(&s2_addr);
+    server2.register_service(DEFAULT_SERVICE_ID, &service2);
+    Server::listen_and_resume(&server2);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, server3) = number_service(&s3_addr);
+    server3.register_service(DEFAULT_SERVICE_ID, &service3);
+    Server::listen_and_resume(&server3);
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 1);
+
+    // committed by service1, the client only hears from service2 after retrying
+    *failing_over.failover_to.lock() = Some(service2.id);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 2);
+    assert_eq!(service2.leader_id(), service2.id);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 3);
+    wait();
+    assert_eq!(local_value(&service1, sm_id), 3);
+    assert_eq!(local_value(&service2, sm_id), 3);
+    assert_eq!(local_value(&service3, sm_id), 3);
+}
+
+#[test]
+fn duplicate_entries_applied_once() {
+    let addr = String::from("127.0.0.1:1638");
+    let (service, server) = number_service(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    service.bootstrap();
+    let sm_id = U32::Number::new_by_name(&String::from("session"), 0).id;
+    let cmd = U32::commands::incr_and_get::new();
+    let (fn_id, _, data) = cmd.encode();
+    let entry = |seq: u64, acked: u64| LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: Some(ClientSession { id: 42, seq, acked, time: 0 }),
+        time: 0,
+        checksum: 0,
+    };
+    let commit = |entry: LogEntry| match service.c_command(&entry).unwrap() {
+        ClientCmdResponse::Success { data, .. } => data,
+        other => panic!("expect success, got {:?}", other)
+    };
+    let value = |data: Vec<u8>| -> u32 { cmd.decode_return(&data).unwrap().unwrap() };
+    assert_eq!(value(commit(entry(1, 0)).unwrap()), 1);
+    assert_eq!(value(commit(entry(1, 0)).unwrap()), 1);
+    assert_eq!(value(commit(entry(2, 1)).unwrap()), 2);
+    assert_eq!(local_value(&service, sm_id), 2);
+    // the client got the first response, it may not ask for it again
+    match commit(entry(1, 1)) {
+        Err(ExecError::SessionExpired) => {},
+        other => panic!("expect session expired, got {:?}", other)
+    }
+}
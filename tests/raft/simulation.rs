@@ -0,0 +1,131 @@
+use bifrost::raft::testing::{SimNetwork, LinkFaults};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+fn elected(sim: &SimNetwork) -> usize {
+    assert!(sim.run_until(2000, |sim| sim.leader().is_some()), "no leader elected");
+    sim.leader().unwrap()
+}
+
+#[test]
+fn leader_crash_during_replication() {
+    let sim = SimNetwork::new(5, 1);
+    let leader = elected(&sim);
+    for i in 0..10 {
+        sim.propose(leader, vec![i]).unwrap();
+    }
+    let last = sim.propose(leader, vec![10]).unwrap();
+    assert!(sim.run_until(500, |sim| sim.commit_index(leader) >= last), "entries not committed");
+    let committed = sim.commit_index(leader);
+
+    // the next entries reach some of the followers before the leader is gone
+    for (i, follower) in (0..5).filter(|follower| *follower != leader).enumerate() {
+        let latency = i as u64 + 1;
+        sim.set_faults(leader, follower, LinkFaults { min_latency: latency, max_latency: latency, ..LinkFaults::Default() });
+    }
+    for i in 11..20 {
+        sim.propose(leader, vec![i]).unwrap();
+    }
+    sim.run(2);
+    sim.crash(leader);
+    sim.clear_faults();
+    let new_leader = elected(&sim);
+    assert!(new_leader != leader);
+    assert!(sim.server(new_leader).term() > sim.server(leader).term());
+    assert_eq!(&sim.log(new_leader, 1)[..committed as usize], &sim.log(leader, 1)[..committed as usize]);
+
+    let last = sim.propose(new_leader, vec![20]).unwrap();
+    sim.recover(leader);
+    assert!(
+        sim.run_until(2000, |sim| (0..5).all(|server| sim.commit_index(server) >= last)),
+        "old leader did not catch up"
+    );
+    assert_eq!(sim.leader(), Some(new_leader));
+    assert_eq!(sim.log(leader, 1), sim.log(new_leader, 1));
+}
+
+#[test]
+fn symmetric_partition_and_heal() {
+    let sim = SimNetwork::new(5, 2);
+    let leader = elected(&sim);
+    let minority: Vec<usize> = vec![leader, (leader + 1) % 5];
+    let majority: Vec<usize> = (0..5).filter(|server| !minority.contains(server)).collect();
+    let term = sim.server(leader).term();
+    sim.partition(&[&minority[..], &majority[..]]);
+
+    // the old leader cannot commit, the others elect a leader of their own
+    let lost = sim.propose(leader, b"lost".to_vec()).unwrap();
+    assert!(sim.run_until(2000, |sim| sim.leader().map(|leader| majority.contains(&leader)).unwrap_or(false)));
+    let new_leader = sim.leader().unwrap();
+    assert!(sim.server(new_leader).term() > term);
+    let kept = sim.propose(new_leader, b"kept".to_vec()).unwrap();
+    assert!(sim.run_until(500, |sim| sim.commit_index(new_leader) >= kept));
+    assert!(sim.commit_index(leader) < lost);
+    sim.run(100);
+    // checking the quorum, the old leader stepped down
+    assert!(!sim.server(leader).is_leader());
+
+    sim.heal();
+    assert!(
+        sim.run_until(2000, |sim| (0..5).all(|server| sim.commit_index(server) >= kept)),
+        "minority did not catch up"
+    );
+    let log = sim.log(new_leader, 1);
+    for server in 0..5 {
+        assert_eq!(sim.log(server, 1), log);
+    }
+    // the entry of the old leader was replaced
+    assert!(!log.contains(&(lost, term)));
+}
+
+#[test]
+fn random_schedule() {
+    let seed = 42;
+    let sim = SimNetwork::new(5, seed);
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, seed as u32]);
+    let mut proposed = 0;
+    for _ in 0..10_000 {
+        let roll = rng.gen::<f64>();
+        if roll < 0.3 {
+            if let Some(leader) = sim.leader() {
+                if sim.propose(leader, vec![rng.gen::<u8>()]).is_some() {
+                    proposed += 1;
+                }
+            }
+        } else if roll < 0.31 {
+            let groups: Vec<usize> = (0..5).map(|_| rng.gen_range(0, 2)).collect();
+            let first: Vec<usize> = (0..5).filter(|server| groups[*server] == 0).collect();
+            let second: Vec<usize> = (0..5).filter(|server| groups[*server] == 1).collect();
+            sim.partition(&[&first[..], &second[..]]);
+        } else if roll < 0.32 {
+            sim.heal();
+        } else if roll < 0.325 {
+            let down = (0..5).filter(|server| sim.is_down(*server)).count();
+            if down < 2 {
+                sim.crash(rng.gen_range(0, 5));
+            }
+        } else if roll < 0.335 {
+            sim.recover(rng.gen_range(0, 5));
+        } else if roll < 0.34 {
+            let max_latency = rng.gen_range(1, 6);
+            sim.set_default_faults(LinkFaults {
+                drop_rate: rng.gen::<f64>() * 0.2,
+                min_latency: 1,
+                max_latency: max_latency,
+            });
+        }
+        sim.step();
+        sim.verify();
+    }
+    assert!(proposed > 0);
+
+    // a healthy cluster again commits on every server
+    sim.heal();
+    sim.clear_faults();
+    for server in 0..5 {
+        sim.recover(server);
+    }
+    sim.run(500);
+    let leader = elected(&sim);
+    let last = sim.propose(leader, vec![0]).unwrap();
+    assert!(sim.run_until(3000, |sim| (0..5).all(|server| sim.commit_index(server) >= last)));
+}
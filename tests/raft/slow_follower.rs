@@ -0,0 +1,71 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::{Server, RPCService, RPCRequestError, ShortcutPolicy, Bytes};
+use bifrost::utils::time::get_time;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use super::{wait, local_value, options};
+
+// answers every request of the raft service after a delay
+struct SlowService {
+    service: Arc<RaftService>,
+    delay: Duration,
+}
+
+impl RPCService for SlowService {
+    fn dispatch(&self, data: Bytes) -> Result<Vec<u8>, RPCRequestError> {
+        thread::sleep(self.delay);
+        self.service.dispatch(data)
+    }
+    fn register_shortcut_service(&self, _service_ptr: usize, _server_id: u64, _service_id: u64, _policy: ShortcutPolicy) {}
+    fn unregister_shortcut_service(&self, _server_id: u64, _service_id: u64) {}
+}
+
+fn number_service(addr: &String, delay: Option<Duration>) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let server = Server::new(addr);
+    match delay {
+        Some(delay) => {
+            let slow = Arc::new(SlowService { service: service.clone(), delay });
+            server.register_service_with_shortcut(DEFAULT_SERVICE_ID, &slow, ShortcutPolicy::Never);
+        },
+        None => server.register_service(DEFAULT_SERVICE_ID, &service)
+    }
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(U32::Number::new_by_name(&String::from("slow_follower"), 0)));
+    (service, server)
+}
+
+#[test]
+fn commit_with_fast_majority() {
+    let s1_addr = String::from("127.0.0.1:1632");
+    let s2_addr = String::from("127.0.0.1:1633");
+    let s3_addr = String::from("127.0.0.1:1634");
+    let sm_id = U32::Number::new_by_name(&String::from("slow_follower"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, None);
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, None);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, Some(Duration::from_millis(500)));
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..20 {
+        let started = get_time();
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+        // the leader and service2 are a quorum, service3 does not hold the commit back
+        let elapsed = get_time() - started;
+        assert!(elapsed < 250, "commit took {}ms", elapsed);
+    }
+    assert_eq!(service1.leader_id(), service1.id);
+
+    // answers of the slow follower still count, it catches up in the end
+    wait();
+    assert_eq!(local_value(&service3, sm_id), 20);
+}
@@ -0,0 +1,62 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::configs::MemberRole;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use super::{wait, number_service};
+
+#[test]
+fn node_status() {
+    let s1_addr = String::from("127.0.0.1:1645");
+    let s2_addr = String::from("127.0.0.1:1646");
+    let s3_addr = String::from("127.0.0.1:1647");
+    let sm_id = U32::Number::new_by_name(&String::from("status"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "status");
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, "status");
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "status");
+    service1.add_learner(&s3_addr).unwrap().unwrap();
+
+    let client = RaftClient::new(&vec!(s2_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for _ in 0..10 {
+        sm_client.incr_and_get().unwrap().unwrap();
+    }
+    wait();
+
+    let leader = service1.status();
+    assert_eq!(leader.id, service1.id);
+    assert_eq!(leader.address, s1_addr);
+    assert_eq!(leader.role, Role::Leader);
+    assert_eq!(leader.term, service1.term());
+    assert_eq!(leader.leader_id, service1.id);
+    assert_eq!(Some(leader.last_log_id), service1.last_log_id());
+    assert_eq!(leader.commit_index, leader.last_log_id);
+    assert_eq!(leader.last_applied, leader.commit_index);
+    assert!(leader.snapshot_id.is_none());
+    assert!(leader.uptime_ms > 0);
+    let ids: Vec<u64> = leader.members.iter().map(|member| member.id).collect();
+    let mut expected = vec!(service1.id, service2.id, service3.id);
+    expected.sort();
+    assert_eq!(ids, expected);
+    for member in leader.members.iter() {
+        // all caught up with the leader
+        assert_eq!(member.match_index, Some(leader.last_log_id));
+        let role = if member.id == service3.id {MemberRole::Learner} else {MemberRole::Voter};
+        assert_eq!(member.role, role);
+    }
+
+    let follower = service2.status();
+    assert_eq!(follower.role, Role::Follower);
+    assert_eq!(follower.term, leader.term);
+    assert_eq!(follower.leader_id, service1.id);
+    assert_eq!(follower.commit_index, leader.commit_index);
+    assert!(follower.members.iter().all(|member| member.match_index.is_none()));
+    assert_eq!(service3.status().role, Role::Learner);
+
+    // asked over rpc, the leader answers first
+    let status = client.cluster_status().unwrap();
+    assert_eq!(status.id, service1.id);
+    assert_eq!(status.role, Role::Leader);
+}
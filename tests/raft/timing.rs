@@ -0,0 +1,77 @@
+use bifrost::raft::*;
+use bifrost::raft::clock::ManualClock;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use std::time::Duration;
+use super::wait;
+
+fn timed_service(addr: &String, election_timeout: Option<(u64, u64)>, clock: &Arc<ManualClock>) -> (Arc<RaftService>, Arc<Server>) {
+    let mut options = Options {
+        address: addr.clone(),
+        ..Options::Default()
+    };
+    if let Some((min, max)) = election_timeout {
+        options.election_timeout = Duration::from_millis(min)..Duration::from_millis(max);
+        options.heartbeat_interval = Duration::from_millis(100);
+    }
+    let service = RaftService::with_clock(options, clock.clone());
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    (service, server)
+}
+
+// the leader is not checked for a while, returns whether followers held an election.
+// only the clock decides when servers time out, however long the test takes
+fn elected_during_pause(addrs: &[&str], election_timeout: Option<(u64, u64)>) -> bool {
+    let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+    let clock = Arc::new(ManualClock::new());
+    let (service1, _server1) = timed_service(&addrs[0], election_timeout, &clock);
+    service1.bootstrap();
+    let (service2, _server2) = timed_service(&addrs[1], election_timeout, &clock);
+    service2.join(&vec!(addrs[0].clone())).unwrap();
+    let (service3, _server3) = timed_service(&addrs[2], election_timeout, &clock);
+    service3.join(&vec!(addrs[0].clone())).unwrap();
+    // the clock stood still, nobody timed out while joining
+    RaftService::tick(&service1);
+    wait();
+    let term = service1.term();
+    assert!(service1.is_leader());
+    assert_eq!(service2.term(), term);
+    assert_eq!(service3.term(), term);
+
+    // two seconds without heartbeats of the leader, service2 is the one to time out first
+    for _ in 0..200 {
+        clock.advance(10);
+        RaftService::tick(&service2);
+    }
+    // votes are asked for on other threads
+    wait();
+    service2.term() > term || service3.term() > term
+}
+
+#[test]
+fn slow_timeouts_outlast_pause() {
+    assert!(!elected_during_pause(&["127.0.0.1:1648", "127.0.0.1:1649", "127.0.0.1:1650"], Some((5000, 6000))));
+}
+
+#[test]
+fn default_timeouts_elect_during_pause() {
+    assert!(elected_during_pause(&["127.0.0.1:1651", "127.0.0.1:1652", "127.0.0.1:1653"], None));
+}
+
+#[test]
+fn invalid_timing() {
+    let options = |min: u64, max: u64, heartbeat: u64| Options {
+        address: String::from("127.0.0.1:1654"),
+        election_timeout: Duration::from_millis(min)..Duration::from_millis(max),
+        heartbeat_interval: Duration::from_millis(heartbeat),
+        ..Options::Default()
+    };
+    assert!(options(50, 100, 10).validate().is_ok());
+    assert!(options(1000, 2000, 200).validate().is_ok());
+    assert_eq!(RaftService::try_new(options(500, 200, 10)).err(), Some(OptionsError::EmptyElectionTimeout));
+    assert_eq!(RaftService::try_new(options(200, 500, 100)).err(), Some(OptionsError::HeartbeatTooLong));
+    assert_eq!(RaftService::try_new(options(200, 500, 0)).err(), Some(OptionsError::HeartbeatTooLong));
+}
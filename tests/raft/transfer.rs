@@ -0,0 +1,44 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use super::{wait, number_service};
+
+#[test]
+fn transfer_keeps_committed_entries() {
+    let s1_addr = String::from("127.0.0.1:1607");
+    let s2_addr = String::from("127.0.0.1:1608");
+    let s3_addr = String::from("127.0.0.1:1609");
+    let sm_id = U32::Number::new_by_name(&String::from("transfer"), 0).id;
+    let (service1, _server1) = number_service(&s1_addr, "transfer");
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, "transfer");
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (service3, _server3) = number_service(&s3_addr, "transfer");
+    service3.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..100 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    let term = service1.term();
+    assert_eq!(service2.transfer_leadership(service3.id), Err(TransferError::NotLeader(service1.id)));
+    assert_eq!(service1.transfer_leadership(42), Err(TransferError::UnknownMember));
+
+    client.transfer_leadership(service2.id).unwrap();
+    assert!(service2.is_leader());
+    assert!(service2.term() > term);
+    wait();
+    assert!(!service1.is_leader());
+    assert_eq!(service1.leader_id(), service2.id);
+    assert_eq!(service3.leader_id(), service2.id);
+
+    assert_eq!(sm_client.get().unwrap().unwrap(), 100);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 101);
+    // and back, through the service itself
+    service2.transfer_leadership(service1.id).unwrap();
+    assert!(service1.is_leader());
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 102);
+}
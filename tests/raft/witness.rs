@@ -0,0 +1,69 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::configs::MemberRole;
+use bifrost::store::number::U32;
+use bifrost::store::number::U32::client::SMClient;
+use bifrost::rpc::Server;
+use std::sync::Arc;
+use super::{wait, local_value, options, number_service_with};
+
+fn number_service(addr: &String, role: NodeRole) -> (Arc<RaftService>, Arc<Server>) {
+    number_service_with(Options {
+        role: role,
+        ..options(Storage::Default(), addr)
+    }, "witness")
+}
+
+#[test]
+fn survives_data_server_failure() {
+    let s1_addr = String::from("127.0.0.1:1664");
+    let s2_addr = String::from("127.0.0.1:1665");
+    let s3_addr = String::from("127.0.0.1:1666");
+    let sm_id = U32::Number::new_by_name(&String::from("witness"), 0).id;
+    let (service1, server1) = number_service(&s1_addr, NodeRole::Data);
+    service1.bootstrap();
+    let (service2, _server2) = number_service(&s2_addr, NodeRole::Data);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    let (witness, _server3) = number_service(&s3_addr, NodeRole::Witness);
+    witness.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..10 {
+        assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), i + 1);
+    }
+    wait();
+    let status = service1.status();
+    let witness_status = status.members.iter().find(|member| member.id == witness.id).unwrap();
+    assert_eq!(witness_status.role, MemberRole::Witness);
+    assert_eq!(witness.status().role, Role::Witness);
+    // the witness has the entries, not what they do
+    assert_eq!(witness.last_log_id(), service1.last_log_id());
+    match witness.c_query(&LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: 0,
+        data: Vec::new(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    }).unwrap() {
+        ClientQryResponse::LeftBehind => {},
+        other => panic!("expect witness refusing the read, got {:?}", other)
+    }
+    assert_eq!(service1.transfer_leadership(witness.id), Err(TransferError::Witness));
+
+    // the leader fails, the other data server is elected with the vote of the witness
+    server1.remove_service(DEFAULT_SERVICE_ID);
+    let _dead = service1.read_meta();
+    wait();
+    assert!(service2.is_leader());
+    assert_eq!(witness.leader_id(), service2.id);
+    let client = RaftClient::new(&vec!(s2_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    assert_eq!(sm_client.incr_and_get().unwrap().unwrap(), 11);
+    assert_eq!(local_value(&service2, sm_id), 11);
+}
@@ -152,4 +152,1731 @@ mod multi_server {
             id += 1;
         }
     }
-}
\ No newline at end of file
+}
+mod timeout_service {
+    use std::thread;
+
+    service! {
+        rpc sleep(ms: u64);
+    }
+
+    pub struct SleepServer;
+
+    impl Service for SleepServer {
+        fn sleep(&self, ms: &u64) -> Result<(), ()> {
+            thread::sleep(Duration::from_millis(*ms));
+            Ok(())
+        }
+    }
+    dispatch_rpc_service_functions!(SleepServer);
+
+    // shortcut calls are executed in the caller thread and cannot time out,
+    // dialing another address of the server takes tcp
+    #[test]
+    fn request_timeout () {
+        let server_addr = String::from("0.0.0.0:1310");
+        let addr = String::from("127.0.0.1:1310");
+        {
+            let server = Server::new(&server_addr);
+            server.register_service(0, &Arc::new(SleepServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let service_client = SyncServiceClient::new(0, &client);
+        service_client.sleep(&10).unwrap().unwrap();
+        client.set_default_timeout(Some(Duration::from_millis(200)));
+        match service_client.sleep(&1000) {
+            Err(RPCError::TimeoutError) => {},
+            r => panic!("expect timeout, got {:?}", r)
+        }
+        // late response from the timed out call should not be matched to this one
+        service_client.sleep(&10).unwrap().unwrap();
+    }
+}
+
+mod client_pool {
+    use std::thread;
+
+    service! {
+        rpc id() -> u64;
+    }
+
+    pub struct IdServer;
+
+    impl Service for IdServer {
+        fn id(&self) -> Result<u64, ()> {
+            Ok(42)
+        }
+    }
+    dispatch_rpc_service_functions!(IdServer);
+
+    #[test]
+    fn invalidate () {
+        let addr = String::from("127.0.0.1:1320");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(IdServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let pool = ClientPool::new();
+        let client_a = pool.get(&addr).unwrap();
+        let client_b = pool.get(&addr).unwrap();
+        assert!(Arc::ptr_eq(&client_a, &client_b));
+        assert!(client_a.is_connected());
+        pool.invalidate(&addr);
+        let client_c = pool.get(&addr).unwrap();
+        assert!(!Arc::ptr_eq(&client_a, &client_c));
+        let service_client = SyncServiceClient::new(0, &client_c);
+        assert_eq!(service_client.id().unwrap().unwrap(), 42);
+    }
+    #[test]
+    fn connect_cooldown () {
+        let addr = String::from("127.0.0.1:1550");
+        let pool = ClientPool::new();
+        pool.set_connect_cooldown(Duration::from_millis(1000));
+        assert!(pool.get(&addr).is_err());
+        assert_eq!(pool.connect_attempts(), 1);
+        let server = Server::new(&addr);
+        server.register_service(0, &Arc::new(IdServer));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(200));
+        // still in cooldown, the failure is returned without dialing
+        assert!(pool.get(&addr).is_err());
+        assert!(pool.get_async(&addr).wait().is_err());
+        assert_eq!(pool.connect_attempts(), 1);
+        thread::sleep(Duration::from_millis(1000));
+        let client = pool.get_async(&addr).wait().unwrap();
+        assert_eq!(pool.connect_attempts(), 2);
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        assert!(Arc::ptr_eq(&client, &pool.get(&addr).unwrap()));
+    }
+    #[test]
+    fn concurrent_connect_to_down_address () {
+        let addr = String::from("127.0.0.1:1551");
+        let pool = Arc::new(ClientPool::new());
+        pool.set_connect_cooldown(Duration::from_secs(10));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let pool = pool.clone();
+            let addr = addr.clone();
+            thread::spawn(move || pool.get(&addr).is_err())
+        }).collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        assert_eq!(pool.connect_attempts(), 1);
+        pool.invalidate(&addr);
+        assert!(pool.get(&addr).is_err());
+        assert_eq!(pool.connect_attempts(), 2);
+    }
+    #[test]
+    fn fan_out () {
+        let addr = String::from("127.0.0.1:1430");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(IdServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let pool = ClientPool::with_capacity_per_host(3);
+        let conns: Vec<_> = (0..3).map(|_| pool.get(&addr).unwrap()).collect();
+        assert!(!Arc::ptr_eq(&conns[0], &conns[1]));
+        assert!(!Arc::ptr_eq(&conns[1], &conns[2]));
+        assert!(!Arc::ptr_eq(&conns[0], &conns[2]));
+        assert_eq!(pool.connections(&addr), 3);
+        // reached capacity, idle connections are taken in turns
+        let next_a = pool.get(&addr).unwrap();
+        let next_b = pool.get(&addr).unwrap();
+        assert!(conns.iter().any(|c| Arc::ptr_eq(c, &next_a)));
+        assert!(!Arc::ptr_eq(&next_a, &next_b));
+        pool.invalidate_client(&conns[0]);
+        assert_eq!(pool.connections(&addr), 2);
+        let client = pool.get(&addr).unwrap();
+        assert!(!Arc::ptr_eq(&client, &conns[0]));
+        assert_eq!(pool.connections(&addr), 3);
+        assert_eq!(SyncServiceClient::new(0, &conns[1]).id().unwrap().unwrap(), 42);
+    }
+}
+
+mod shutdown {
+    use bifrost::rpc::*;
+    use bifrost::tcp::server::ServerOptions;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use std::net::TcpListener;
+    use super::client_pool::{IdServer, SyncServiceClient};
+    use super::timeout_service::{SleepServer, SyncServiceClient as SleepServiceClient};
+
+    #[test]
+    fn rebind_after_shutdown () {
+        let addr = String::from("127.0.0.1:1330");
+        let server = Server::new(&addr);
+        server.register_service(0, &Arc::new(IdServer));
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        handle.shutdown();
+        let listener = TcpListener::bind(addr.as_str());
+        assert!(listener.is_ok());
+    }
+
+    #[test]
+    fn multiple_listeners () {
+        let primary = String::from("127.0.0.1:1510");
+        let internal = String::from("127.0.0.1:1511");
+        let server = Server::new(&primary);
+        server.register_service(0, &Arc::new(IdServer));
+        server.add_listener(&internal);
+        assert_eq!(server.addresses(), vec![primary.clone(), internal.clone()]);
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        for addr in vec![&primary, &internal] {
+            let client = RPCClient::new(addr).unwrap();
+            assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+            assert_eq!(client.ping().unwrap().server_id, server.server_id);
+        }
+        handle.shutdown();
+        assert!(TcpListener::bind(primary.as_str()).is_ok());
+        assert!(TcpListener::bind(internal.as_str()).is_ok());
+    }
+
+    // listeners of one server dispatch on the same threads, on all interfaces to be dialed over tcp
+    #[test]
+    fn listeners_share_dispatch_threads () {
+        let server = Server::new_with_options(&String::from("0.0.0.0:1758"), ServerOptions {
+            dispatch_threads: 1,
+            .. ServerOptions::default()
+        });
+        server.register_service(0, &Arc::new(SleepServer));
+        server.add_listener(&String::from("0.0.0.0:1759"));
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let first = RPCClient::new(&String::from("127.0.0.1:1758")).unwrap();
+        let second = RPCClient::new(&String::from("127.0.0.1:1759")).unwrap();
+        let slow = thread::spawn(move || SleepServiceClient::new(0, &first).sleep(&1000));
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        SleepServiceClient::new(0, &second).sleep(&0).unwrap().unwrap();
+        // the only thread was taken by the call on the other listener
+        assert!(start.elapsed() > Duration::from_millis(500));
+        assert!(slow.join().unwrap().unwrap().is_ok());
+        handle.shutdown();
+    }
+
+    // requests being served when the shutdown is signaled still get their responses
+    #[test]
+    fn drain_in_flight () {
+        // shortcuts are found by the address of the server, dialing another address of it takes tcp
+        let server_addr = String::from("0.0.0.0:1754");
+        let addr = String::from("127.0.0.1:1754");
+        let server = Server::new(&server_addr);
+        server.register_service(0, &Arc::new(SleepServer));
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let caller = thread::spawn(move || SleepServiceClient::new(0, &client).sleep(&500));
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+        assert!(caller.join().unwrap().unwrap().is_ok());
+    }
+
+    // the pooled client of a restarted server is replaced, without disabling shortcuts
+    #[test]
+    fn pool_reconnect_over_tcp () {
+        let server_addr = String::from("0.0.0.0:1755");
+        let addr = String::from("127.0.0.1:1755");
+        let start_server = || {
+            let server = Server::new(&server_addr);
+            server.register_service(0, &Arc::new(IdServer));
+            let handle = Server::listen_and_resume(&server);
+            thread::sleep(Duration::from_millis(500));
+            handle
+        };
+        let handle = start_server();
+        let client = DEFAULT_CLIENT_POOL.get(&addr).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        handle.shutdown();
+        let _handle = start_server();
+        // the old connection is broken by the restart, its first call finds out
+        assert!(SyncServiceClient::new(0, &client).id().is_err());
+        assert!(!client.is_connected());
+        let reconnected = DEFAULT_CLIENT_POOL.get(&addr).unwrap();
+        assert!(!Arc::ptr_eq(&client, &reconnected));
+        assert_eq!(SyncServiceClient::new(0, &reconnected).id().unwrap().unwrap(), 42);
+    }
+}
+
+mod reconnect {
+    use bifrost::rpc::*;
+    use bifrost::tcp::client::{ClientOptions, ReconnectPolicy};
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    // the request failed by the restart is sent again over the new connection
+    #[test]
+    fn resend_after_restart () {
+        let server_addr = String::from("0.0.0.0:1756");
+        let addr = String::from("127.0.0.1:1756");
+        let start_server = || {
+            let server = Server::new(&server_addr);
+            server.register_service(0, &Arc::new(IdServer));
+            let handle = Server::listen_and_resume(&server);
+            thread::sleep(Duration::from_millis(500));
+            handle
+        };
+        let handle = start_server();
+        let connect = |resend: bool| RPCClient::with_options(&addr, ClientOptions {
+            reconnect: Some(ReconnectPolicy::default()),
+            resend_on_reconnect: resend,
+            .. ClientOptions::default()
+        }).unwrap();
+        let resending = connect(true);
+        let failing = connect(false);
+        assert_eq!(SyncServiceClient::new(0, &resending).id().unwrap().unwrap(), 42);
+        assert_eq!(SyncServiceClient::new(0, &failing).id().unwrap().unwrap(), 42);
+        handle.shutdown();
+        let _handle = start_server();
+        assert_eq!(SyncServiceClient::new(0, &resending).id().unwrap().unwrap(), 42);
+        assert!(resending.is_connected());
+        // without resending, the caller sees the broken connection and the next request redials
+        assert!(SyncServiceClient::new(0, &failing).id().is_err());
+        assert_eq!(SyncServiceClient::new(0, &failing).id().unwrap().unwrap(), 42);
+    }
+}
+
+mod middleware {
+    use bifrost::rpc::*;
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    #[test]
+    fn reject_and_observe () {
+        let addr = String::from("127.0.0.1:1340");
+        let pre_count = Arc::new(AtomicUsize::new(0));
+        let post_count = Arc::new(AtomicUsize::new(0));
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(IdServer));
+            server.register_service(1, &Arc::new(IdServer));
+            let pre_count = pre_count.clone();
+            let post_count = post_count.clone();
+            server.register_middleware(Box::new(move |svr_id: u64, _: &[u8]| {
+                pre_count.fetch_add(1, Ordering::Relaxed);
+                if svr_id == 1 {
+                    MiddlewareDecision::Reject(RPCRequestError::Rejected)
+                } else {
+                    MiddlewareDecision::Continue
+                }
+            }));
+            server.register_post_middleware(Box::new(move |_: u64, res: &Result<Vec<u8>, RPCRequestError>, _: Duration| {
+                assert!(res.is_ok());
+                post_count.fetch_add(1, Ordering::Relaxed);
+            }));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        match SyncServiceClient::new(1, &client).id() {
+            Err(RPCError::RequestError(RPCRequestError::Rejected)) => {},
+            other => panic!("expect rejection, got {:?}", other)
+        }
+        assert_eq!(pre_count.load(Ordering::Relaxed), 2);
+        assert_eq!(post_count.load(Ordering::Relaxed), 1);
+    }
+}
+
+mod service_error {
+    use std::thread;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub enum QuotaError {
+        Exceeded(u64),
+    }
+
+    service! {
+        rpc take(amount: u64) -> u64 | QuotaError;
+    }
+
+    struct QuotaServer;
+
+    impl Service for QuotaServer {
+        fn take(&self, amount: &u64) -> Result<u64, QuotaError> {
+            if *amount > 10 {
+                Err(QuotaError::Exceeded(10))
+            } else {
+                Ok(10 - amount)
+            }
+        }
+    }
+    dispatch_rpc_service_functions!(QuotaServer);
+
+    #[test]
+    fn structured_error () {
+        let addr = String::from("127.0.0.1:1350");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(QuotaServer));
+            // force requests through server dispatch instead of the service shortcut
+            server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let service_client = SyncServiceClient::new(0, &client);
+        assert_eq!(service_client.take(&3).unwrap(), Ok(7));
+        assert_eq!(service_client.take(&11).unwrap(), Err(QuotaError::Exceeded(10)));
+        let async_client = AsyncServiceClient::new(0, &client);
+        assert_eq!(async_client.take(&12).wait().unwrap(), Err(QuotaError::Exceeded(10)));
+    }
+}
+
+mod compression {
+    use std::thread;
+    use bifrost::rpc::compression::{self, Compression};
+
+    service! {
+        rpc echo(data: Vec<u8>) -> Vec<u8>;
+    }
+
+    struct EchoServer;
+
+    impl Service for EchoServer {
+        fn echo(&self, data: &Vec<u8>) -> Result<Vec<u8>, ()> {
+            Ok(data.clone())
+        }
+    }
+    dispatch_rpc_service_functions!(EchoServer);
+
+    #[test]
+    fn large_snapshot () {
+        let addr = String::from("127.0.0.1:1370");
+        let compression = Some(Compression::default());
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(EchoServer));
+            server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+            server.set_compression(compression);
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let payload: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let compressed = compression::compress(&compression, payload.clone());
+        assert!(compressed.len() < payload.len());
+        assert_eq!(compression::decompress(compressed).unwrap(), payload);
+        let client = RPCClient::new(&addr).unwrap();
+        client.set_compression(compression);
+        let service_client = SyncServiceClient::new(0, &client);
+        assert_eq!(service_client.echo(&payload).unwrap().unwrap(), payload);
+    }
+
+    // over tcp, clients with and without compression talk to the compressing server
+    #[test]
+    fn mixed_settings () {
+        let server_addr = String::from("0.0.0.0:1757");
+        let addr = String::from("127.0.0.1:1757");
+        let compression = Some(Compression::default());
+        let server = Server::new(&server_addr);
+        server.register_service(0, &Arc::new(EchoServer));
+        server.set_compression(compression);
+        let _handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let payload: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        for setting in vec![None, compression] {
+            let client = RPCClient::new(&addr).unwrap();
+            client.set_compression(setting);
+            let service_client = SyncServiceClient::new(0, &client);
+            assert_eq!(service_client.echo(&payload).unwrap().unwrap(), payload);
+            // both ways compressed, the frames are much smaller than the payload
+            let metrics = client.metrics_snapshot();
+            assert!(metrics.bytes_in < payload.len() as u64 / 2);
+            if setting.is_some() {
+                assert!(metrics.bytes_out < payload.len() as u64 / 2);
+            }
+        }
+    }
+}
+
+mod tls {
+    use bifrost::rpc::*;
+    use bifrost::tcp::{self, TransportOptions};
+    use bifrost::tcp::tls::{AcceptorConfig, ConnectorConfig};
+    use std::io;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    fn acceptor_config() -> AcceptorConfig {
+        AcceptorConfig {
+            pkcs12: include_bytes!("fixtures/tls/identity.p12").to_vec(),
+            password: String::from("bifrost"),
+        }
+    }
+
+    fn connector_config(trusted: bool) -> ConnectorConfig {
+        ConnectorConfig {
+            domain: String::from("localhost"),
+            root_certificates: if trusted {
+                vec![include_bytes!("fixtures/tls/root.der").to_vec()]
+            } else {
+                vec![]
+            },
+        }
+    }
+
+    // shortcuts skip the transport, the address returned dials another address of the server to take tcp
+    fn start_server(port: u16) -> String {
+        let transport = TransportOptions::Tls {
+            acceptor: Some(acceptor_config()),
+            connector: None,
+        };
+        let server = Server::new_with_transport(&format!("0.0.0.0:{}", port), transport);
+        server.register_service(0, &Arc::new(IdServer));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        format!("127.0.0.1:{}", port)
+    }
+
+    #[test]
+    fn round_trip () {
+        let addr = start_server(1380);
+        let transport = TransportOptions::Tls {
+            acceptor: None,
+            connector: Some(connector_config(true)),
+        };
+        let client = RPCClient::with_transport(&addr, &transport).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+    }
+
+    #[test]
+    fn untrusted_certificate () {
+        let addr = start_server(1381);
+        match tcp::client::Client::connect_tls(&addr, &connector_config(false)) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("self-signed certificate should not be trusted")
+        }
+    }
+}
+
+mod unix_socket {
+    use bifrost::rpc::*;
+    use bifrost::tcp::{self, TransportOptions};
+    use bifrost::tcp::tls::{AcceptorConfig, ConnectorConfig};
+    use std::fs;
+    use std::io;
+    use std::thread;
+    use std::path::Path;
+    use std::os::unix::net::UnixListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    #[test]
+    fn round_trip_and_cleanup () {
+        let path = "/tmp/bifrost-rpc-test.sock";
+        let addr = format!("unix://{}", path);
+        let server = Server::new(&addr);
+        server.register_service(0, &Arc::new(IdServer));
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        assert!(Path::new(path).exists());
+        let client = DEFAULT_CLIENT_POOL.get(&addr).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        handle.shutdown();
+        assert!(!Path::new(path).exists());
+    }
+
+    // only sockets nothing accepts on are taken over
+    #[test]
+    fn stale_socket () {
+        let path = "/tmp/bifrost-rpc-stale-test.sock";
+        let addr = format!("unix://{}", path);
+        let serve = || tcp::server::Server::new_with_shutdown(&addr, Box::new(|data: Bytes| data), None);
+        fs::remove_file(path).ok();
+        fs::File::create(path).unwrap();
+        match serve() {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {},
+            other => panic!("expect address in use, got {:?}", other)
+        }
+        assert!(Path::new(path).exists());
+        fs::remove_file(path).unwrap();
+        // the socket file stays after the listener is gone, like the one of a crashed server
+        drop(UnixListener::bind(path).unwrap());
+        let server = Server::new(&addr);
+        server.register_service(0, &Arc::new(IdServer));
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        match serve() {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {},
+            other => panic!("expect address in use, got {:?}", other)
+        }
+        let client = DEFAULT_CLIENT_POOL.get(&addr).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        handle.shutdown();
+    }
+
+    // rather than serving or dialing in plain
+    #[test]
+    fn refuse_tls () {
+        let path = "/tmp/bifrost-rpc-tls-test.sock";
+        let addr = format!("unix://{}", path);
+        let transport = TransportOptions::Tls {
+            acceptor: Some(AcceptorConfig {
+                pkcs12: include_bytes!("fixtures/tls/identity.p12").to_vec(),
+                password: String::from("bifrost"),
+            }),
+            connector: None,
+        };
+        match tcp::server::Server::new_with_transport(&addr, Box::new(|data: Bytes| data), None, &transport) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {},
+            other => panic!("expect refusal, got {:?}", other)
+        }
+        assert!(!Path::new(path).exists());
+        let connector = ConnectorConfig {
+            domain: String::from("localhost"),
+            root_certificates: vec![include_bytes!("fixtures/tls/root.der").to_vec()],
+        };
+        match tcp::client::Client::connect_tls(&addr, &connector) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {},
+            Err(e) => panic!("expect refusal, got {:?}", e),
+            Ok(_) => panic!("expect refusal, connected")
+        }
+    }
+}
+
+mod codec {
+    use bifrost::rpc::*;
+    use bifrost::utils::codec::{WireCodec, CodecError, Bincode};
+    use serde::{Serialize, Deserialize};
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // bincode with a leading tag byte, unreadable for the default codec
+    pub struct Tagged;
+
+    impl WireCodec for Tagged {
+        fn encode<T>(obj: &T) -> Vec<u8> where T: Serialize {
+            let mut data = vec![0xEEu8];
+            data.extend(Bincode::encode(obj));
+            data
+        }
+        fn decode<'a, T>(data: &'a [u8]) -> Result<T, CodecError> where T: Deserialize<'a> {
+            match data.first() {
+                Some(&0xEEu8) => Bincode::decode(&data[1..]),
+                _ => Err(CodecError::Decode(String::from("missing tag")))
+            }
+        }
+    }
+
+    mod tagged {
+        service! {
+            codec super::Tagged;
+            rpc concat(a: String, b: String) -> String;
+        }
+
+        pub struct ConcatServer;
+
+        impl Service for ConcatServer {
+            fn concat(&self, a: &String, b: &String) -> Result<String, ()> {
+                Ok(format!("{}{}", a, b))
+            }
+        }
+        dispatch_rpc_service_functions!(ConcatServer);
+    }
+
+    mod plain {
+        service! {
+            rpc concat(a: String, b: String) -> String;
+        }
+    }
+
+    #[test]
+    fn custom_codec_and_mismatch () {
+        let addr = String::from("127.0.0.1:1390");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(tagged::ConcatServer));
+            server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let tagged_client = tagged::SyncServiceClient::new(0, &client);
+        let res = tagged_client.concat(&String::from("bif"), &String::from("rost"));
+        assert_eq!(res.unwrap().unwrap(), String::from("bifrost"));
+        let plain_client = plain::SyncServiceClient::new(0, &client);
+        match plain_client.concat(&String::from("bif"), &String::from("rost")) {
+            Err(RPCError::RequestError(RPCRequestError::BadRequestData)) => {},
+            other => panic!("expect bad request data, got {:?}", other)
+        }
+    }
+}
+
+mod bad_data {
+    use bifrost::rpc::*;
+    use bifrost::utils::u8vec::prepend_u64;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    mod server {
+        service! {
+            rpc echo(text: String) -> String;
+        }
+
+        pub struct EchoServer;
+
+        impl Service for EchoServer {
+            fn echo(&self, text: &String) -> Result<String, ()> {
+                Ok(text.clone())
+            }
+        }
+        dispatch_rpc_service_functions!(EchoServer);
+    }
+
+    // same function with return type longer than the server response
+    mod client {
+        service! {
+            rpc echo(text: String) -> (u64, u64, u64);
+        }
+    }
+
+    #[test]
+    fn garbage_request_and_response () {
+        let addr = String::from("127.0.0.1:1530");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(server::EchoServer));
+            server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let garbage = prepend_u64(hash_ident!(echo) as u64, vec![0xFFu8; 3]);
+        match client.send(0, garbage) {
+            Err(RPCError::RequestError(RPCRequestError::BadRequestData)) => {},
+            other => panic!("expect bad request data, got {:?}", other)
+        }
+        // server should survive the garbage
+        let server_client = server::SyncServiceClient::new(0, &client);
+        assert_eq!(server_client.echo(&String::from("alive")).unwrap().unwrap(), String::from("alive"));
+        let mismatched_client = client::SyncServiceClient::new(0, &client);
+        match mismatched_client.echo(&String::new()) {
+            Err(RPCError::DecodeError(_)) => {},
+            other => panic!("expect decode error, got {:?}", other)
+        }
+    }
+}
+
+mod batch {
+    use bifrost::rpc::*;
+    use bifrost::rpc::batch::{pack, unpack};
+    use bifrost::utils::bincode;
+    use bifrost::utils::u8vec::prepend_u64;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    mod echo {
+        service! {
+            rpc echo(text: String) -> String;
+        }
+
+        pub struct EchoServer;
+
+        impl Service for EchoServer {
+            fn echo(&self, text: &String) -> Result<String, ()> {
+                Ok(text.clone())
+            }
+        }
+        dispatch_rpc_service_functions!(EchoServer);
+    }
+
+    fn echo_req(text: &str) -> Vec<u8> {
+        prepend_u64(hash_ident!(echo) as u64, bincode::serialize(&(String::from(text),)))
+    }
+
+    #[test]
+    fn packing () {
+        let frames = vec![vec![1u8, 2, 3], vec![], vec![4u8]];
+        let packed = pack(frames.clone());
+        assert_eq!(unpack(&packed).unwrap(), frames);
+        assert!(unpack(&packed[..packed.len() - 1]).is_none());
+        assert!(unpack(&[]).is_none());
+    }
+
+    #[test]
+    fn partial_errors () {
+        let addr = String::from("127.0.0.1:1410");
+        {
+            let server = Server::new(&addr);
+            server.register_service(1, &Arc::new(echo::EchoServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let reqs = vec![
+            echo_req("a"),
+            prepend_u64(42, vec![]), // no such function
+            prepend_u64(hash_ident!(echo) as u64, vec![0xFFu8]), // garbage arguments
+            echo_req("b"),
+        ];
+        let res = client.send_batch(1, reqs).unwrap();
+        assert_eq!(res.len(), 4);
+        let decode = |res: &Result<Vec<u8>, RPCError>| -> Result<String, ()> {
+            bincode::deserialize(res.as_ref().unwrap())
+        };
+        assert_eq!(decode(&res[0]), Ok(String::from("a")));
+        match res[1] {
+            Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)) => {},
+            ref other => panic!("expect function not found, got {:?}", other)
+        }
+        match res[2] {
+            Err(RPCError::RequestError(RPCRequestError::BadRequestData)) => {},
+            ref other => panic!("expect bad request data, got {:?}", other)
+        }
+        assert_eq!(decode(&res[3]), Ok(String::from("b")));
+        let res = client.send_batch(2, vec![echo_req("c")]).unwrap();
+        match res[0] {
+            Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)) => {},
+            ref other => panic!("expect service not found, got {:?}", other)
+        }
+    }
+}
+
+mod concurrency {
+    use bifrost::rpc::*;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use super::timeout_service::{SleepServer, SyncServiceClient};
+
+    #[test]
+    fn overlapping_calls () {
+        let addr = String::from("127.0.0.1:1420");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(SleepServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let sleep_ms = 200;
+        let start = Instant::now();
+        let threads: Vec<_> = (0..100).map(|_| {
+            let client = client.clone();
+            thread::spawn(move || {
+                SyncServiceClient::new(0, &client).sleep(&sleep_ms).unwrap().unwrap();
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // calls on one client are not serialized, 100 sequential calls would take 20s
+        assert!(start.elapsed() < Duration::from_millis(sleep_ms * 10));
+    }
+}
+
+mod health {
+    use bifrost::rpc::*;
+    use bifrost_hasher::hash_str;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::IdServer;
+
+    #[test]
+    fn ping_and_echo () {
+        let addr = String::from("127.0.0.1:1440");
+        {
+            let server = Server::new(&addr);
+            server.register_service(7, &Arc::new(IdServer));
+            server.register_service(3, &Arc::new(IdServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let info = client.ping().unwrap();
+        assert_eq!(info.server_id, hash_str(&addr));
+        assert!(info.uptime_ms >= 500);
+        assert_eq!(info.services, vec![3, 7]);
+        assert_eq!(client.list_services().unwrap(), vec![3, 7]);
+        assert_eq!(client.echo(vec![1u8, 2, 3]).unwrap(), vec![1u8, 2, 3]);
+    }
+}
+
+mod tracing {
+    use bifrost::rpc::*;
+    use bifrost::rpc::context;
+    use bifrost::utils::bincode;
+    use bifrost::utils::u8vec::prepend_u64;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    service! {
+        rpc trace() -> Option<u64>;
+    }
+
+    pub struct TraceServer;
+
+    impl Service for TraceServer {
+        fn trace(&self) -> Result<Option<u64>, ()> {
+            Ok(context::current_trace_id())
+        }
+    }
+    dispatch_rpc_service_functions!(TraceServer);
+
+    #[test]
+    fn propagation () {
+        let addr = String::from("127.0.0.1:1450");
+        let serialized_addr = String::from("127.0.0.1:1451");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(TraceServer));
+            Server::listen_and_resume(&server);
+            // middleware forces requests through serialization and server dispatch
+            let server = Server::new(&serialized_addr);
+            server.register_service(0, &Arc::new(TraceServer));
+            server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        for addr in vec![&addr, &serialized_addr] {
+            let client = RPCClient::new(addr).unwrap();
+            let service_client = SyncServiceClient::new(0, &client);
+            assert_eq!(service_client.trace().unwrap().unwrap(), None);
+            let traced = context::with_trace_id(7, || service_client.trace().unwrap().unwrap());
+            assert_eq!(traced, Some(7));
+            assert_eq!(context::current_trace_id(), None);
+            // the serving thread does not keep the context
+            assert_eq!(service_client.trace().unwrap().unwrap(), None);
+            let res = client.send_traced(0, 8, prepend_u64(hash_ident!(trace) as u64, bincode::serialize(&()))).unwrap();
+            assert_eq!(bincode::deserialize::<Result<Option<u64>, ()>>(&res), Ok(Some(8)));
+        }
+    }
+}
+
+mod frame_limit {
+    use bifrost::rpc::*;
+    use bifrost::tcp::client::ClientOptions;
+    use bifrost::tcp::server::ServerOptions;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::IdServer;
+
+    const LIMIT: usize = 1024;
+    // service id and echo function id in front of the echoed data
+    const ECHO_OVERHEAD: usize = 16;
+
+    fn start(addr: &String) {
+        let server = Server::new_with_options(addr, ServerOptions {
+            max_frame_size: LIMIT,
+            .. ServerOptions::default()
+        });
+        server.register_service(0, &Arc::new(IdServer));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+    }
+
+    fn client(addr: &String, max_frame_size: usize) -> Arc<RPCClient> {
+        RPCClient::with_options(addr, ClientOptions {
+            max_frame_size: max_frame_size,
+            .. ClientOptions::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn exact_and_one_over () {
+        let addr = String::from("127.0.0.1:1460");
+        start(&addr);
+        let client = client(&addr, LIMIT);
+        let data = vec![1u8; LIMIT - ECHO_OVERHEAD];
+        assert_eq!(client.echo(data.clone()).unwrap(), data);
+        match client.echo(vec![1u8; LIMIT - ECHO_OVERHEAD + 1]) {
+            Err(RPCError::PayloadTooLarge) => {},
+            other => panic!("expect payload too large, got {:?}", other)
+        }
+        // the connection is not affected
+        assert_eq!(client.echo(vec![2u8]).unwrap(), vec![2u8]);
+    }
+
+    // frames are only limited on the wire, dialing another address of the server takes tcp
+    #[test]
+    fn oversized_to_server () {
+        let addr = String::from("127.0.0.1:1461");
+        start(&String::from("0.0.0.0:1461"));
+        let client = client(&addr, LIMIT * 4);
+        match client.echo(vec![1u8; LIMIT - ECHO_OVERHEAD + 1]) {
+            Err(RPCError::IOError(_)) => {},
+            other => panic!("expect connection failure, got {:?}", other)
+        }
+        // only the offending connection is closed
+        assert!(RPCClient::new(&addr).unwrap().ping().is_ok());
+    }
+}
+
+mod checksum {
+    use bifrost::rpc::*;
+    use bifrost::tcp::framed::{BytesCodec, DEFAULT_MAX_FRAME_SIZE, is_checksum_mismatch};
+    use bifrost::tcp::client::ClientOptions;
+    use bifrost::tcp::server::ServerOptions;
+    use bifrost::utils::bincode;
+    use bifrost::utils::u8vec::prepend_u64;
+    use tokio_core::io::{Codec, EasyBuf};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    fn codec() -> BytesCodec {
+        BytesCodec::new(DEFAULT_MAX_FRAME_SIZE, true)
+    }
+
+    fn round_trip(stream: &mut TcpStream, frame: Vec<u8>) -> (u64, Vec<u8>, bool) {
+        stream.write_all(&frame).unwrap();
+        let mut header = [0u8; 16];
+        stream.read_exact(&mut header).unwrap();
+        let len = bincode::deserialize::<u64>(&header[8..].to_vec()) as usize;
+        let mut rest = vec![0u8; len + 4];
+        stream.read_exact(&mut rest).unwrap();
+        let buf: Vec<u8> = header.iter().cloned().chain(rest.into_iter()).collect();
+        let (mid, res) = codec().decode(&mut EasyBuf::from(buf)).unwrap().unwrap();
+        match res {
+            Ok(data) => (mid, data.to_vec(), false),
+            Err(e) => (mid, vec![], is_checksum_mismatch(&e))
+        }
+    }
+
+    #[test]
+    fn flipped_byte () {
+        let addr = String::from("127.0.0.1:1470");
+        let server = Server::new_with_options(&addr, ServerOptions {
+            checksum: true,
+            .. ServerOptions::default()
+        });
+        server.register_service(0, &Arc::new(IdServer));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let req = prepend_u64(0, prepend_u64(hash_ident!(id) as u64, bincode::serialize(&())));
+        let mut frame = Vec::new();
+        codec().encode((1, Ok(Bytes::from(req.clone()))), &mut frame).unwrap();
+        let mut corrupted = frame.clone();
+        let last_payload_byte = corrupted.len() - 5;
+        corrupted[last_payload_byte] ^= 0x01;
+
+        let mut stream = TcpStream::connect(addr.as_str()).unwrap();
+        let (mid, _, mismatch) = round_trip(&mut stream, corrupted);
+        assert_eq!(mid, 1);
+        assert!(mismatch);
+        assert_eq!(server.corrupted_frames(), 1);
+        // the connection survives and serves intact frames
+        let mut frame = Vec::new();
+        codec().encode((2, Ok(Bytes::from(req))), &mut frame).unwrap();
+        let (mid, res, mismatch) = round_trip(&mut stream, frame);
+        assert_eq!(mid, 2);
+        assert!(!mismatch);
+        assert_eq!(res[0], 0); // status byte of success
+        assert_eq!(bincode::deserialize::<Result<u64, ()>>(&res[1..].to_vec()), Ok(42));
+        assert_eq!(server.corrupted_frames(), 1);
+
+        let client = RPCClient::with_options(&addr, ClientOptions {
+            checksum: true,
+            .. ClientOptions::default()
+        }).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+    }
+}
+
+mod deadline {
+    use bifrost::rpc::*;
+    use bifrost::rpc::context;
+    use bifrost::utils::time::get_time;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    service! {
+        rpc deadline() -> Option<i64>;
+        rpc remaining() -> Option<u64>;
+    }
+
+    pub struct DeadlineServer;
+
+    impl Service for DeadlineServer {
+        fn deadline(&self) -> Result<Option<i64>, ()> {
+            Ok(context::current().deadline())
+        }
+        fn remaining(&self) -> Result<Option<u64>, ()> {
+            Ok(context::current().remaining().map(|remaining| remaining.as_secs() * 1000 + remaining.subsec_nanos() as u64 / 1000000))
+        }
+    }
+    dispatch_rpc_service_functions!(DeadlineServer);
+
+    #[test]
+    fn propagation () {
+        let addr = String::from("127.0.0.1:1480");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(DeadlineServer));
+            // serialize requests, the timeout of the client only exists in the frame
+            server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let service_client = SyncServiceClient::new(0, &client);
+        assert_eq!(service_client.deadline().unwrap().unwrap(), None);
+
+        let deadline = get_time() + 5000;
+        assert_eq!(context::with_deadline(deadline, || service_client.deadline()).unwrap().unwrap(), Some(deadline));
+        // the earlier deadline wins
+        let earlier = context::with_deadline(deadline, || {
+            context::with_deadline(deadline - 1000, || service_client.deadline())
+        });
+        assert_eq!(earlier.unwrap().unwrap(), Some(deadline - 1000));
+
+        client.set_default_timeout(Some(Duration::from_secs(2)));
+        let remaining = service_client.remaining().unwrap().unwrap().unwrap();
+        assert!(remaining > 0 && remaining <= 2000);
+        client.set_default_timeout(None);
+
+        // skew within tolerance is served with no time left
+        let skewed = context::with_deadline(get_time() - 10, || service_client.remaining());
+        assert_eq!(skewed.unwrap().unwrap(), Some(0));
+        match context::with_deadline(get_time() - 10000, || service_client.remaining()) {
+            Err(RPCError::RequestError(RPCRequestError::DeadlineExceeded)) => {},
+            other => panic!("expect deadline exceeded, got {:?}", other)
+        }
+    }
+}
+
+// shortcut calls are served before the future is returned and cannot be cancelled,
+// the server is dialed by another of its addresses to take tcp
+mod cancellation {
+    use bifrost::rpc::*;
+    use bifrost::utils::bincode;
+    use bifrost::utils::u8vec::prepend_u64;
+    use futures::Future;
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    service! {
+        rpc delayed_echo(ms: u64, text: String) -> String;
+    }
+
+    pub struct DelayedEchoServer {
+        served: AtomicUsize
+    }
+
+    impl Service for DelayedEchoServer {
+        fn delayed_echo(&self, ms: &u64, text: &String) -> Result<String, ()> {
+            self.served.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(*ms));
+            Ok(text.clone())
+        }
+    }
+    dispatch_rpc_service_functions!(DelayedEchoServer);
+
+    fn req(ms: u64, text: &str) -> Vec<u8> {
+        prepend_u64(hash_ident!(delayed_echo) as u64, bincode::serialize(&(ms, String::from(text))))
+    }
+
+    #[test]
+    fn cancel_slow_call () {
+        let addr = String::from("127.0.0.1:1490");
+        let service = Arc::new(DelayedEchoServer { served: AtomicUsize::new(0) });
+        {
+            let server = Server::new(&String::from("0.0.0.0:1490"));
+            server.register_service(0, &service);
+            // hold requests for service 1 before dispatch, so they can be cancelled in time
+            server.register_middleware(Box::new(|svr_id: u64, _: &[u8]| {
+                if svr_id == 1 {
+                    thread::sleep(Duration::from_millis(500));
+                }
+                MiddlewareDecision::Continue
+            }));
+            server.register_service(1, &service);
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+
+        let (slow, handle) = client.send_async_cancellable(0, req(1000, "slow"));
+        thread::sleep(Duration::from_millis(100));
+        handle.cancel();
+        match slow.wait() {
+            Err(RPCError::Cancelled) => {},
+            other => panic!("expect cancelled, got {:?}", other)
+        }
+        assert_eq!(client.in_flight(), 0);
+        // the late response of the slow call must not be taken as this one
+        let res = client.send(0, req(1500, "second")).unwrap();
+        assert_eq!(bincode::deserialize::<Result<String, ()>>(&res), Ok(String::from("second")));
+
+        // dropping the future cancels as well
+        drop(client.send_async(0, req(1000, "dropped")));
+        assert_eq!(client.in_flight(), 0);
+
+        // cancelled before dispatch, the service is not called
+        thread::sleep(Duration::from_millis(200));
+        let served = service.served.load(Ordering::Relaxed);
+        let (held, handle) = client.send_async_cancellable(1, req(0, "held"));
+        thread::sleep(Duration::from_millis(100));
+        handle.cancel();
+        assert!(held.wait().is_err());
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(service.served.load(Ordering::Relaxed), served);
+    }
+}
+
+mod metrics {
+    use bifrost::rpc::*;
+    use bifrost::utils::u8vec::prepend_u64;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    service! {
+        rpc even(n: u64) -> u64 | String;
+    }
+
+    pub struct EvenServer;
+
+    impl Service for EvenServer {
+        fn even(&self, n: &u64) -> Result<u64, String> {
+            if n % 2 == 0 { Ok(*n) } else { Err(format!("{} is odd", n)) }
+        }
+    }
+    dispatch_rpc_service_functions!(EvenServer);
+
+    #[test]
+    fn counting () {
+        let addr = String::from("127.0.0.1:1520");
+        let server = Server::new(&addr);
+        server.register_service(0, &Arc::new(EvenServer));
+        // local service shortcuts are not counted
+        server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let service_client = SyncServiceClient::new(0, &client);
+        for n in 0..5 {
+            let res = service_client.even(&n).unwrap();
+            assert_eq!(res.is_ok(), n % 2 == 0);
+        }
+        match client.send(0, prepend_u64(1, vec![])) {
+            Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)) => {},
+            other => panic!("{:?}", other)
+        }
+        match client.send(1, prepend_u64(1, vec![])) {
+            Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)) => {},
+            other => panic!("{:?}", other)
+        }
+
+        let snapshot = server.metrics_snapshot();
+        assert_eq!(snapshot.requests, 7);
+        assert_eq!(snapshot.errors.len(), 3);
+        assert_eq!(snapshot.errors["ServiceError"], 2);
+        assert_eq!(snapshot.errors["FunctionIdNotFound"], 1);
+        assert_eq!(snapshot.errors["ServiceIdNotFound"], 1);
+        assert_eq!(snapshot.latencies[&0].count, 6);
+        assert_eq!(snapshot.latencies[&1].count, 1);
+        assert!(snapshot.latencies[&0].percentile(99.0).is_some());
+        assert!(snapshot.bytes_in > 0 && snapshot.bytes_out > 0);
+
+        // the client sees the same frames from the other end
+        let client_snapshot = client.metrics_snapshot();
+        assert_eq!(client_snapshot.requests, 7);
+        assert_eq!(client_snapshot.errors, snapshot.errors);
+        assert_eq!(client_snapshot.bytes_out, snapshot.bytes_in);
+        assert_eq!(client_snapshot.bytes_in, snapshot.bytes_out);
+
+        // taken before the metrics request itself is counted
+        let remote = client.server_metrics().unwrap();
+        assert_eq!(remote.requests, 7);
+        assert_eq!(remote.errors, snapshot.errors);
+        assert_eq!(server.metrics_snapshot().requests, 8);
+    }
+}
+
+mod auth {
+    use bifrost::rpc::*;
+    use bifrost::tcp::auth::{mac, NONCE_LEN};
+    use bifrost::tcp::framed::BytesCodec;
+    use bifrost::tcp::server::ServerOptions;
+    use bifrost::utils::bincode;
+    use bifrost::utils::u8vec::prepend_u64;
+    use tokio_core::io::{Codec, EasyBuf};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::client_pool::IdServer;
+
+    fn start_server(addr: &String) -> Arc<Server> {
+        let server = Server::new_with_options(addr, ServerOptions {
+            auth_token: Some(String::from("secret")),
+            .. ServerOptions::default()
+        });
+        server.register_service(0, &Arc::new(IdServer));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        server
+    }
+
+    // answer the nonce with the token and issue one call to the id service
+    fn call_with_token(addr: &String, token: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr.as_str()).unwrap();
+        let mut nonce = [0u8; NONCE_LEN];
+        stream.read_exact(&mut nonce).unwrap();
+        stream.write_all(&mac(token, &nonce)).unwrap();
+        let req = prepend_u64(0, prepend_u64(hash_ident!(id) as u64, bincode::serialize(&())));
+        let mut frame = Vec::new();
+        BytesCodec::default().encode((1, Ok(Bytes::from(req))), &mut frame).unwrap();
+        stream.write_all(&frame).unwrap();
+        let mut header = [0u8; 16];
+        stream.read_exact(&mut header).unwrap();
+        let len = bincode::deserialize::<u64>(&header[8..].to_vec()) as usize;
+        let mut rest = vec![0u8; len];
+        stream.read_exact(&mut rest).unwrap();
+        let buf: Vec<u8> = header.iter().cloned().chain(rest.into_iter()).collect();
+        let (_, res) = BytesCodec::default().decode(&mut EasyBuf::from(buf)).unwrap().unwrap();
+        res.unwrap().to_vec()
+    }
+
+    #[test]
+    fn wrong_token () {
+        let addr = String::from("127.0.0.1:1540");
+        let server = start_server(&addr);
+        let res = call_with_token(&addr, "guess");
+        assert_eq!(res[0], 9); // status byte of unauthorized
+        let res = call_with_token(&addr, "secret");
+        assert_eq!(res[0], 0);
+        assert_eq!(bincode::deserialize::<Result<u64, ()>>(&res[1..].to_vec()), Ok(42));
+        assert_eq!(server.metrics_snapshot().errors["Unauthorized"], 1);
+    }
+
+    // in-process clients take the shortcut, which is not authenticated,
+    // dialing another address of the server takes tcp
+    #[test]
+    fn clients () {
+        use bifrost::tcp::client::ClientOptions;
+        use super::client_pool::SyncServiceClient;
+        let addr = String::from("127.0.0.1:1541");
+        start_server(&String::from("0.0.0.0:1541"));
+        let connect = |token: &str| RPCClient::with_options(&addr, ClientOptions {
+            auth_token: Some(token.to_string()),
+            .. ClientOptions::default()
+        }).unwrap();
+        match SyncServiceClient::new(0, &connect("guess")).id() {
+            Err(RPCError::RequestError(RPCRequestError::Unauthorized)) => {},
+            other => panic!("expect unauthorized, got {:?}", other)
+        }
+        assert_eq!(SyncServiceClient::new(0, &connect("secret")).id().unwrap().unwrap(), 42);
+    }
+}
+
+mod versioning {
+    use bifrost::rpc::*;
+    use futures::Future;
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    mod v1 {
+        service! {
+            version 1;
+            rpc hello(name: String) -> String;
+        }
+
+        pub struct OldServer;
+
+        impl Service for OldServer {
+            fn hello(&self, name: &String) -> Result<String, ()> {
+                Ok(format!("hello {}", name))
+            }
+        }
+        dispatch_rpc_service_functions!(OldServer);
+    }
+
+    mod v2 {
+        service! {
+            version 2;
+            rpc hello(name: String) -> String;
+            rpc greet(name: String) -> String;
+        }
+    }
+
+    #[test]
+    fn newer_client () {
+        let addr = String::from("127.0.0.1:1560");
+        {
+            let server = Server::new(&addr);
+            server.register_service(0, &Arc::new(v1::OldServer));
+            Server::listen_and_resume(&server);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let old_client = v1::SyncServiceClient::new(0, &client);
+        assert_eq!(old_client.server_version().unwrap(), 1);
+        let new_client = v2::SyncServiceClient::new(0, &client);
+        assert_eq!(new_client.hello(&String::from("a")).unwrap().unwrap(), "hello a");
+        match new_client.greet(&String::from("a")) {
+            Err(RPCError::RequestError(RPCRequestError::VersionMismatch { server: 1, client: 2 })) => {},
+            other => panic!("expect version mismatch, got {:?}", other)
+        }
+        assert_eq!(new_client.server_version().unwrap(), 1);
+        let async_client = v2::AsyncServiceClient::new(0, &client);
+        match async_client.greet(&String::from("a")).wait() {
+            Err(RPCError::RequestError(RPCRequestError::VersionMismatch { server: 1, client: 2 })) => {},
+            other => panic!("expect version mismatch, got {:?}", other)
+        }
+    }
+}
+
+mod streaming {
+    use bifrost::rpc::*;
+    use bifrost::rpc::stream::{self, StreamingService, ResponseSink};
+    use futures::{Future, Stream};
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // sends the requested number of bytes in chunks of 1000
+    pub struct Repeat;
+
+    impl StreamingService for Repeat {
+        fn dispatch(&self, data: Vec<u8>, sink: ResponseSink) {
+            let len = data[0] as usize * 10000;
+            let payload: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            if sink.send_chunked(&payload, 1000).is_ok() {
+                sink.finish();
+            }
+        }
+    }
+
+    // gives up after the first chunk
+    pub struct Broken;
+
+    impl StreamingService for Broken {
+        fn dispatch(&self, _: Vec<u8>, sink: ResponseSink) {
+            sink.send(vec![1, 2, 3]).unwrap();
+        }
+    }
+
+    #[test]
+    fn streams() {
+        let addr = String::from("127.0.0.1:1570");
+        let server = Server::new(&addr);
+        server.register_streaming_service(1, Arc::new(Repeat));
+        server.register_streaming_service(2, Arc::new(Broken));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+
+        // much larger than the buffer of the stream on the server
+        let data = stream::collect(RPCClient::send_stream(&client, 1, vec![50])).wait().unwrap();
+        assert_eq!(data.len(), 500000);
+        assert!(data.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+
+        let chunks = RPCClient::send_stream(&client, 2, vec![]).wait().collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap(), &vec![1u8, 2, 3]);
+        match chunks[1] {
+            Err(RPCError::RequestError(RPCRequestError::StreamClosed)) => {},
+            ref other => panic!("{:?}", other)
+        }
+
+        match RPCClient::send_stream(&client, 3, vec![]).wait().next() {
+            Some(Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound))) => {},
+            other => panic!("{:?}", other)
+        }
+
+        // dropped early, the handler stops as it sends to a closed stream
+        let first = RPCClient::send_stream(&client, 1, vec![100]).wait().next();
+        assert_eq!(first.unwrap().unwrap().len(), 1000);
+    }
+}
+
+// shortcut calls make no connections, the server is dialed by another of its addresses to take tcp
+mod connection_events {
+    use bifrost::rpc::*;
+    use std::thread;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use std::net::SocketAddr;
+    use std::io;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    #[test]
+    fn connect_and_disconnect () {
+        let addr = String::from("127.0.0.1:1580");
+        let server = Server::new(&String::from("0.0.0.0:1580"));
+        server.register_service(0, &Arc::new(IdServer));
+        let server_events = Arc::new(Mutex::new(Vec::new()));
+        let connected = server_events.clone();
+        let disconnected = server_events.clone();
+        server.set_connection_listener(
+            Box::new(move |peer: SocketAddr| connected.lock().unwrap().push(("connect", peer))),
+            Box::new(move |peer: SocketAddr, _: io::Error| disconnected.lock().unwrap().push(("disconnect", peer))));
+        let handle = Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+
+        let client = RPCClient::new(&addr).unwrap();
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        drop(client);
+        thread::sleep(Duration::from_millis(500));
+        {
+            let events = server_events.lock().unwrap();
+            assert_eq!(events.len(), 2);
+            assert_eq!(events[0].0, "connect");
+            assert_eq!(events[1], ("disconnect", events[0].1));
+        }
+
+        // the pooled client reports the address it dialed when the server goes away
+        let client_events = Arc::new(Mutex::new(Vec::new()));
+        let disconnected = client_events.clone();
+        let client = DEFAULT_CLIENT_POOL.get(&addr).unwrap();
+        client.set_disconnect_handler(Box::new(move |peer: SocketAddr, _: io::Error| disconnected.lock().unwrap().push(peer)));
+        assert_eq!(SyncServiceClient::new(0, &client).id().unwrap().unwrap(), 42);
+        handle.shutdown();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(*client_events.lock().unwrap(), vec![addr.parse::<SocketAddr>().unwrap()]);
+        assert_eq!(server_events.lock().unwrap().len(), 4);
+    }
+}
+
+mod service_batch {
+    use bifrost::rpc::*;
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    service! {
+        rpc generation() -> u64;
+    }
+
+    pub struct First;
+    pub struct Second;
+
+    impl Service for First {
+        fn generation(&self) -> Result<u64, ()> {
+            Ok(1)
+        }
+    }
+    impl Service for Second {
+        fn generation(&self) -> Result<u64, ()> {
+            Ok(2)
+        }
+    }
+    dispatch_rpc_service_functions!(First);
+    dispatch_rpc_service_functions!(Second);
+
+    fn generation(client: &Arc<RPCClient>, service_id: u64) -> Result<u64, RPCError> {
+        SyncServiceClient::new(service_id, client).generation().map(|res| res.unwrap())
+    }
+
+    #[test]
+    fn register_replace_remove () {
+        let addr = String::from("127.0.0.1:1590");
+        let server = Server::new(&addr);
+        let first: Arc<RPCService> = Arc::new(First);
+        server.register_services((1..5).map(|id| (id, first.clone())).collect());
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        for id in 1..5 {
+            assert_eq!(generation(&client, id).unwrap(), 1);
+        }
+
+        // callers never see the id missing while it is swapped
+        let stop = Arc::new(AtomicBool::new(false));
+        let caller = {
+            let client = client.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut seen = vec![];
+                while !stop.load(Ordering::Relaxed) {
+                    seen.push(generation(&client, 2).unwrap());
+                }
+                seen
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        let old = server.replace_service(2, Arc::new(Second));
+        assert!(old.is_some());
+        thread::sleep(Duration::from_millis(100));
+        stop.store(true, Ordering::Relaxed);
+        let seen = caller.join().unwrap();
+        assert_eq!(seen.first(), Some(&1));
+        assert_eq!(seen.last(), Some(&2));
+        assert_eq!(generation(&client, 2).unwrap(), 2);
+
+        // shortcuts go away with the services
+        server.remove_services(vec![1, 2, 3]);
+        for id in 1..4 {
+            match generation(&client, id) {
+                Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)) => {},
+                other => panic!("{:?}", other)
+            }
+        }
+        assert_eq!(generation(&client, 4).unwrap(), 1);
+    }
+}
+
+mod shortcut_cleanup {
+    use bifrost::rpc::*;
+    use std::sync::Arc;
+
+    service! {
+        rpc hello() -> String;
+    }
+
+    pub struct HelloServer;
+
+    impl Service for HelloServer {
+        fn hello(&self) -> Result<String, ()> {
+            Ok(String::from("hello"))
+        }
+    }
+    dispatch_rpc_service_functions!(HelloServer);
+
+    #[test]
+    fn register_remove_loop () {
+        // never listened, only the shortcut is used
+        let addr = String::from("127.0.0.1:1591");
+        let server = Server::new(&addr);
+        let service = Arc::new(HelloServer);
+        for _ in 0..1000 {
+            server.register_service(0, &service);
+            assert_eq!(get_local(server.server_id, 0).is_some(), !bifrost::DISABLE_SHORTCUT);
+            server.remove_service(0);
+            assert!(get_local(server.server_id, 0).is_none());
+            // nothing but this test holds the service
+            assert_eq!(Arc::strong_count(&service), 1);
+        }
+        assert!(RPC_SVRS.read().is_empty());
+
+        let server_id = server.server_id;
+        server.register_service(0, &service);
+        drop(server);
+        assert!(get_local(server_id, 0).is_none());
+        assert!(RPC_SVRS.read().is_empty());
+        assert_eq!(Arc::strong_count(&service), 1);
+    }
+}
+
+mod shortcut_policy {
+    use bifrost::rpc::*;
+    use std::thread;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use super::client_pool::{IdServer, SyncServiceClient};
+
+    #[test]
+    fn per_service () {
+        let addr = String::from("127.0.0.1:1592");
+        let server = Server::new(&addr);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_ref = seen.clone();
+        server.register_middleware(Box::new(move |svr_id: u64, _: &[u8]| {
+            seen_ref.lock().unwrap().push(svr_id);
+            MiddlewareDecision::Continue
+        }));
+        let service = Arc::new(IdServer);
+        server.register_service_with_shortcut(1, &service, ShortcutPolicy::Always);
+        server.register_service_with_shortcut(2, &service, ShortcutPolicy::Never);
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        for svr_id in vec![1, 2] {
+            for _ in 0..3 {
+                assert_eq!(SyncServiceClient::new(svr_id, &client).id().unwrap().unwrap(), 42);
+            }
+        }
+        // the shortcut skips the middleware, even though the server has one
+        assert_eq!(*seen.lock().unwrap(), vec![2, 2, 2]);
+    }
+}
+
+mod large_entries {
+    use bifrost::rpc::*;
+    use bifrost::utils::u8vec::{prepend_u64, split_u64_head};
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    service! {
+        rpc append(entries: Vec<Vec<u8>>) -> u64;
+    }
+
+    pub struct LogServer;
+
+    impl Service for LogServer {
+        fn append(&self, entries: &Vec<Vec<u8>>) -> Result<u64, ()> {
+            Ok(entries.iter().map(|entry| entry.len() as u64).sum())
+        }
+    }
+    dispatch_rpc_service_functions!(LogServer);
+
+    fn ms(duration: Duration) -> f64 {
+        duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1000000.0
+    }
+
+    #[test]
+    fn replicate () {
+        let entry: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        // header extraction as it was done before, against slicing the frame
+        let frame = prepend_u64(1, entry.clone());
+        let rounds = 20;
+        let start = Instant::now();
+        for _ in 0..rounds {
+            let rest: Vec<u8> = frame.iter().skip(8).cloned().collect();
+            assert_eq!(rest.len(), entry.len());
+        }
+        let copied = start.elapsed();
+        let frame = Bytes::from(frame);
+        let start = Instant::now();
+        for _ in 0..rounds {
+            let (head, rest) = split_u64_head(frame.clone());
+            assert_eq!((head, rest.len()), (1, entry.len()));
+        }
+        let sliced = start.elapsed();
+        println!("extract header of 1MB frames: copied {:.3}ms, sliced {:.3}ms", ms(copied), ms(sliced));
+        assert!(sliced < copied);
+
+        let addr = String::from("127.0.0.1:1593");
+        let server = Server::new(&addr);
+        server.register_service(0, &Arc::new(LogServer));
+        // serialize the requests as for remote peers
+        server.register_middleware(Box::new(|_: u64, _: &[u8]| MiddlewareDecision::Continue));
+        Server::listen_and_resume(&server);
+        thread::sleep(Duration::from_millis(1000));
+        let client = RPCClient::new(&addr).unwrap();
+        let service_client = SyncServiceClient::new(0, &client);
+        let entries = vec![entry.clone(); 4];
+        let start = Instant::now();
+        for _ in 0..8 {
+            assert_eq!(service_client.append(&entries).unwrap().unwrap(), 4 * entry.len() as u64);
+        }
+        let elapsed = start.elapsed();
+        println!("replicated 32MB in {:.3}ms, {:.1}MB/s", ms(elapsed), 32.0 / (ms(elapsed) / 1000.0));
+    }
+}
@@ -0,0 +1,130 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::callback::SubFilter;
+use bifrost::store::btree::string_string_btreemap;
+use bifrost::store::btree::string_string_btreemap::{OrderedMap, StateMachineCmds};
+use bifrost::store::btree::string_string_btreemap::client::SMClient;
+use bifrost::store::btree::string_string_btreemap::commands::on_inserted;
+use bifrost::rpc::*;
+
+use std::sync::Arc;
+use std::thread;
+use parking_lot::Mutex;
+
+use raft::{wait, options};
+
+fn key(i: u64) -> String {
+    format!("key/{:04}", i)
+}
+
+#[test]
+fn btree_map_ranges() {
+    let mut map = OrderedMap::new_by_name(&String::from("btree_map_ranges"));
+    for i in 0..3000 {
+        map.insert(key(i), i.to_string()).unwrap();
+    }
+    let keys = |entries: Vec<(String, String)>| -> Vec<String> {
+        entries.into_iter().map(|(k, _)| k).collect()
+    };
+    assert_eq!(map.len(), Ok(3000));
+    assert_eq!(map.first(), Ok(Some((key(0), String::from("0")))));
+    assert_eq!(map.last(), Ok(Some((key(2999), String::from("2999")))));
+
+    // from the from key, up to the to key
+    let range = keys(map.range(Some(key(1000)), Some(key(2000)), 5000).unwrap());
+    assert_eq!(range.len(), 1000);
+    assert_eq!(range[0], key(1000));
+    assert_eq!(range[999], key(1999));
+    assert_eq!(keys(map.range(Some(key(10)), Some(key(13)), 5000).unwrap()), vec!(key(10), key(11), key(12)));
+    assert_eq!(keys(map.range(Some(key(10)), Some(key(100)), 2).unwrap()), vec!(key(10), key(11)));
+    // bounds between keys, unbounded and empty ranges
+    assert_eq!(keys(map.range(Some(String::from("key/0009x")), Some(String::from("key/0011x")), 5000).unwrap()), vec!(key(10), key(11)));
+    assert_eq!(map.range(None, Some(key(100)), 5000).unwrap().len(), 100);
+    assert_eq!(map.range(Some(key(2900)), None, 5000).unwrap().len(), 100);
+    assert_eq!(map.range(None, None, 5000).unwrap().len(), 3000);
+    assert!(map.range(Some(key(20)), Some(key(20)), 5000).unwrap().is_empty());
+    assert!(map.range(Some(key(20)), Some(key(10)), 5000).unwrap().is_empty());
+    assert!(map.range(Some(key(3000)), None, 5000).unwrap().is_empty());
+    // continued after the last key
+    assert_eq!(keys(map.range_after(key(10), Some(key(13)), 5000).unwrap()), vec!(key(11), key(12)));
+    assert!(map.range_after(key(12), Some(key(13)), 5000).unwrap().is_empty());
+    assert!(map.range_after(key(20), Some(key(10)), 5000).unwrap().is_empty());
+
+    // keys with the prefix, and only those
+    let prefixed = keys(map.prefix(String::from("key/12"), 5000).unwrap());
+    assert_eq!(prefixed.len(), 100);
+    assert_eq!(prefixed[0], key(1200));
+    assert_eq!(prefixed[99], key(1299));
+    assert_eq!(keys(map.prefix_after(String::from("key/12"), key(1297), 5000).unwrap()), vec!(key(1298), key(1299)));
+    assert_eq!(map.prefix(String::from("key/"), 5000).unwrap().len(), 3000);
+    assert!(map.prefix(String::from("key/3"), 5000).unwrap().is_empty());
+    assert!(map.prefix(String::from("other"), 5000).unwrap().is_empty());
+}
+
+#[test]
+fn btree_map_pages() {
+    let addr = String::from("127.0.0.1:1745");
+    let mut map_sm = OrderedMap::new_by_name(&String::from("btree_map_pages"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = map_sm.id;
+    map_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(map_sm));
+    raft_service.bootstrap().unwrap();
+
+    let raft_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &raft_client);
+    RaftClient::prepare_subscription(&server);
+    let registered = Arc::new(Mutex::new(Vec::new()));
+    let registered_ref = registered.clone();
+    sm_client.subscribe_where(on_inserted::new(), SubFilter::prefix(&String::from("services/db/")), move |res: Result<(String, String), ()>| {
+        if let Ok((key, _)) = res {registered_ref.lock().push(key);}
+    }).unwrap().unwrap();
+
+    for i in 0..200 {
+        sm_client.insert(&format!("services/web/{:03}", i), &String::from("up")).unwrap().unwrap();
+    }
+    for i in 0..3 {
+        sm_client.insert(&format!("services/db/{:03}", i), &String::from("up")).unwrap().unwrap();
+    }
+
+    // pages go on after the last key, even while another client inserts before it
+    let writer = {
+        let servers = vec!(addr.clone());
+        thread::spawn(move || {
+            let raft_client = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+            let sm_client = string_string_btreemap::client::SMClient::new(sm_id, &raft_client);
+            for i in 0..100 {
+                sm_client.insert(&format!("services/web/{:03}a", i), &String::from("up")).unwrap().unwrap();
+            }
+        })
+    };
+    let prefix = String::from("services/web/");
+    let mut paged: Vec<String> = Vec::new();
+    let mut page = sm_client.prefix(&prefix, &30).unwrap().unwrap();
+    while !page.is_empty() {
+        paged.extend(page.into_iter().map(|(k, _)| k));
+        page = sm_client.prefix_after(&prefix, paged.last().unwrap(), &30).unwrap().unwrap();
+    }
+    writer.join().unwrap();
+    for i in 0..200 {
+        assert!(paged.contains(&format!("services/web/{:03}", i)));
+    }
+    let mut sorted = paged.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted, paged);
+    assert_eq!(sm_client.prefix(&prefix, &1000).unwrap().unwrap().len(), 300);
+
+    assert_eq!(sm_client.range(&Some(String::from("services/db/")), &Some(String::from("services/db/002")), &10).unwrap().unwrap(), vec!(
+        (String::from("services/db/000"), String::from("up")),
+        (String::from("services/db/001"), String::from("up"))
+    ));
+    wait();
+    assert_eq!(*registered.lock(), vec!(
+        String::from("services/db/000"), String::from("services/db/001"), String::from("services/db/002")
+    ));
+}
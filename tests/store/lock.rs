@@ -0,0 +1,102 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::lock::{Lock, LockError};
+use bifrost::store::lock::client::SMClient;
+use bifrost::store::ttl::ChangeReason;
+use bifrost::rpc::*;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use parking_lot::Mutex;
+
+use raft::{wait, options};
+
+#[test]
+fn lock_leases() {
+    let addr = String::from("127.0.0.1:1743");
+    let mut lock_sm = Lock::new_by_name(&String::from("lock_leases"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = lock_sm.id;
+    lock_sm.init_callback(&raft_service);
+    lock_sm.init_expiry(&raft_service, 100);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(lock_sm));
+    raft_service.bootstrap();
+
+    let client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    RaftClient::prepare_subscription(&server);
+    let released = Arc::new(Mutex::new(Vec::new()));
+    let released_ref = released.clone();
+    sm_client.on_released(move |res: Result<(u64, u64, ChangeReason), ()>| {
+        if let Ok(event) = res {released_ref.lock().push(event);}
+    }).unwrap().unwrap();
+
+    // owners waiting in turn hold the lock one at a time, each with a larger token
+    let inside = Arc::new(AtomicBool::new(false));
+    let tokens = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = (1..4).map(|owner: u64| {
+        let client = client.clone();
+        let inside = inside.clone();
+        let tokens = tokens.clone();
+        thread::spawn(move || {
+            let sm_client = SMClient::new(sm_id, &client);
+            for _ in 0..5 {
+                let token = sm_client.acquire_wait(&owner, &5000, Duration::from_secs(30)).unwrap().unwrap();
+                assert!(!inside.swap(true, Ordering::SeqCst));
+                tokens.lock().push(token);
+                thread::sleep(Duration::from_millis(20));
+                inside.store(false, Ordering::SeqCst);
+                sm_client.release(&owner, &token).unwrap().unwrap();
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let tokens = tokens.lock().clone();
+    assert_eq!(tokens.len(), 15);
+    assert!(tokens.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(sm_client.holder().unwrap().unwrap(), None);
+
+    // the holder crashes without releasing
+    let crashed_token = {
+        let crashing_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+        let crashing = SMClient::new(sm_id, &crashing_client);
+        crashing.acquire(&9, &1000).unwrap().unwrap()
+    };
+    assert!(crashed_token > *tokens.last().unwrap());
+    match sm_client.acquire(&1, &5000).unwrap() {
+        Err(LockError::LockHeld { owner, remaining_ms }) => {
+            assert_eq!(owner, 9);
+            assert!(remaining_ms <= 1000);
+        },
+        res => panic!("acquired a held lock, {:?}", res)
+    }
+    let token = sm_client.acquire_wait(&1, &5000, Duration::from_secs(10)).unwrap().unwrap();
+    assert!(token > crashed_token);
+    let holder = sm_client.holder().unwrap().unwrap().unwrap();
+    assert_eq!((holder.owner, holder.token), (1, token));
+    // fenced out once its lease expired
+    assert_eq!(sm_client.renew(&9, &crashed_token).unwrap(), Err(LockError::NotHolder));
+    assert_eq!(sm_client.release(&9, &crashed_token).unwrap(), Err(LockError::NotHolder));
+
+    // the holder acquiring again keeps its token, renewing needs the current one
+    assert_eq!(sm_client.acquire(&1, &5000).unwrap(), Ok(token));
+    assert_eq!(sm_client.renew(&1, &token).unwrap(), Ok(()));
+    assert_eq!(sm_client.renew(&1, &crashed_token).unwrap(), Err(LockError::NotHolder));
+    assert_eq!(sm_client.release(&1, &token).unwrap(), Ok(()));
+    assert_eq!(sm_client.release(&1, &token).unwrap(), Err(LockError::NotHolder));
+    assert!(sm_client.acquire(&1, &5000).unwrap().unwrap() > token);
+
+    wait();
+    let released = released.lock().clone();
+    assert_eq!(released.len(), 17);
+    assert!(released.contains(&(9, crashed_token, ChangeReason::Expired)));
+    assert!(released.contains(&(1, token, ChangeReason::Command)));
+}
@@ -3,22 +3,25 @@ use bifrost::raft::state_machine::callback::client::SubscriptionService;
 use bifrost::raft::client::RaftClient;
 use bifrost::store::map::string_string_hashmap;
 use bifrost::store::map::string_string_hashmap::client::SMClient;
+use bifrost::store::map::string_string_hashmap::commands::on_inserted;
+use bifrost::raft::state_machine::callback::SubFilter;
+use bifrost::store::map::{TxnResult, TxnAborted};
+use bifrost::store::ttl::ChangeReason;
 use bifrost::rpc::*;
 
 use std::collections::{HashSet, HashMap};
 use std::iter::FromIterator;
+use std::sync::Arc;
+use std::thread;
+use parking_lot::Mutex;
 
-use raft::wait;
+use raft::{wait, options};
 
 #[test]
 fn hash_map(){
     let addr = String::from("127.0.0.1:2013");
     let mut map_sm = string_string_hashmap::Map::new_by_name(&String::from("test"));
-    let raft_service = RaftService::new(Options{
-        storage: Storage::Default(),
-        address: addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
     let server = Server::new(&addr);
     server.register_service(DEFAULT_SERVICE_ID, &raft_service);
     Server::listen_and_resume(&server);
@@ -58,8 +61,9 @@ fn hash_map(){
         }
     });
     sm_client.on_removed(move |res| {
-        if let Ok((key, value)) = res {
+        if let Ok((key, value, reason)) = res {
             println!("GOT REMOVED CALLBACK {:?} -> {:?}", key, value);
+            assert_eq!(reason, ChangeReason::Command);
             assert_eq!(removed_stash.get(&key).unwrap(), &value);
         }
     });
@@ -70,7 +74,7 @@ fn hash_map(){
         }
     }, &sk1);
     sm_client.on_key_removed(|res| {
-        if let Ok(value) = res {
+        if let Ok((value, _)) = res {
             println!("GOT K2 CALLBACK {:?}", value);
             assert_eq!(&String::from("v2"), &value);
         }
@@ -141,4 +145,269 @@ fn hash_map(){
     assert!(sm_client.contains_key(&sk4).unwrap().unwrap());
 
     wait();
-}
\ No newline at end of file
+}
+#[test]
+fn filtered_subscriptions() {
+    let addr = String::from("127.0.0.1:1726");
+    let callback_addr = String::from("127.0.0.1:1727");
+    let mut map_sm = string_string_hashmap::Map::new_by_name(&String::from("filtered"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = map_sm.id;
+    map_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(map_sm));
+    raft_service.bootstrap().unwrap();
+    let callback_server = Server::new(&callback_addr);
+    Server::listen_and_resume(&callback_server);
+    RaftClient::prepare_subscription(&callback_server);
+
+    let raft_client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &raft_client);
+    let users = Arc::new(Mutex::new(Vec::new()));
+    let admins = Arc::new(Mutex::new(Vec::new()));
+    let users_ref = users.clone();
+    let admins_ref = admins.clone();
+    sm_client.subscribe_where(on_inserted::new(), SubFilter::prefix(&String::from("users/")), move |res: Result<(String, String), ()>| {
+        if let Ok((key, _)) = res {
+            users_ref.lock().push(key);
+        }
+    }).unwrap().unwrap();
+    sm_client.subscribe_where(on_inserted::new(), SubFilter::key(&String::from("groups/admin")), move |res: Result<(String, String), ()>| {
+        if let Ok((key, _)) = res {
+            admins_ref.lock().push(key);
+        }
+    }).unwrap().unwrap();
+
+    for key in &["users/1", "groups/admin", "groups/dev", "users/2", "user"] {
+        sm_client.insert(&String::from(*key), &String::from("v")).unwrap().unwrap();
+    }
+    wait();
+    assert_eq!(*users.lock(), vec!(String::from("users/1"), String::from("users/2")));
+    assert_eq!(*admins.lock(), vec!(String::from("groups/admin")));
+}
+
+#[test]
+fn hash_map_update_and_pages() {
+    let addr = String::from("127.0.0.1:1733");
+    let callback_addr = String::from("127.0.0.1:1734");
+    let mut map_sm = string_string_hashmap::Map::new_by_name(&String::from("update and pages"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = map_sm.id;
+    map_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(map_sm));
+    raft_service.bootstrap().unwrap();
+    let callback_server = Server::new(&callback_addr);
+    Server::listen_and_resume(&callback_server);
+    RaftClient::prepare_subscription(&callback_server);
+
+    let raft_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &raft_client);
+    let updates = Arc::new(Mutex::new(Vec::new()));
+    let updates_ref = updates.clone();
+    sm_client.on_updated(move |res| {
+        if let Ok(updated) = res {
+            updates_ref.lock().push(updated);
+        }
+    }).unwrap().unwrap();
+    let k = String::from("k");
+    sm_client.insert(&k, &String::from("v1")).unwrap().unwrap();
+    // swapped only from the value it still has
+    assert!(!sm_client.update(&k, &String::from("v0"), &String::from("v2")).unwrap().unwrap());
+    assert!(sm_client.update(&k, &String::from("v1"), &String::from("v2")).unwrap().unwrap());
+    assert!(!sm_client.update(&String::from("absent"), &String::from("v1"), &String::from("v2")).unwrap().unwrap());
+    assert_eq!(sm_client.get(&k).unwrap().unwrap(), Some(String::from("v2")));
+    wait();
+    assert_eq!(*updates.lock(), vec!((k.clone(), String::from("v1"), String::from("v2"))));
+    sm_client.clear().unwrap().unwrap();
+
+    // clients inserting at the same time
+    let clients = 4;
+    let per_client = 25;
+    let threads: Vec<_> = (0..clients).map(|c| {
+        let servers = vec!(addr.clone());
+        thread::spawn(move || {
+            let raft_client = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+            let sm_client = SMClient::new(sm_id, &raft_client);
+            for i in 0..per_client {
+                let key = format!("{:02}-{:02}", c, i);
+                assert!(sm_client.insert(&key, &key).unwrap().unwrap().is_none());
+            }
+        })
+    }).collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+    assert_eq!(sm_client.len().unwrap().unwrap(), (clients * per_client) as u64);
+
+    // pages in key order cover the map once
+    let mut paged = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = sm_client.entries_paged(&offset, &30).unwrap().unwrap();
+        if page.is_empty() {break;}
+        offset += page.len() as u64;
+        paged.extend(page);
+    }
+    let mut expected = sm_client.entries().unwrap().unwrap();
+    expected.sort();
+    assert_eq!(paged, expected);
+    assert_eq!(paged[0].0, String::from("00-00"));
+}
+
+#[test]
+fn hash_map_transactions() {
+    let addr = String::from("127.0.0.1:1744");
+    let mut map_sm = string_string_hashmap::Map::new_by_name(&String::from("transactions"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = map_sm.id;
+    map_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(map_sm));
+    raft_service.bootstrap().unwrap();
+
+    let raft_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &raft_client);
+    RaftClient::prepare_subscription(&server);
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let removed_ref = removed.clone();
+    sm_client.on_removed(move |res: Result<(String, String, ChangeReason), ()>| {
+        if let Ok((key, value, _)) = res {removed_ref.lock().push((key, value));}
+    }).unwrap().unwrap();
+
+    // moves the item between the entries, or does nothing
+    let s = |val: &str| String::from(val);
+    sm_client.insert(&s("shelf/a"), &s("item")).unwrap().unwrap();
+    let res = sm_client.txn()
+        .compare(&s("shelf/a"), &s("item"))
+        .compare_absent(&s("shelf/b"))
+        .delete(&s("shelf/a"))
+        .put(&s("shelf/b"), &s("item"))
+        .commit().unwrap();
+    assert_eq!(res, Ok(vec!(
+        TxnResult::Compared,
+        TxnResult::Compared,
+        TxnResult::Deleted(Some(s("item"))),
+        TxnResult::Put(None)
+    )));
+    let res = sm_client.txn()
+        .compare(&s("shelf/a"), &s("item"))
+        .compare(&s("shelf/b"), &s("item"))
+        .compare_absent(&s("shelf/b"))
+        .delete(&s("shelf/a"))
+        .put(&s("shelf/b"), &s("other"))
+        .commit().unwrap();
+    assert_eq!(res, Err(TxnAborted { failed: vec!(0, 2) }));
+    assert_eq!(sm_client.get(&s("shelf/a")).unwrap().unwrap(), None);
+    assert_eq!(sm_client.get(&s("shelf/b")).unwrap().unwrap(), Some(s("item")));
+    assert_eq!(sm_client.len().unwrap().unwrap(), 1);
+    wait();
+    assert_eq!(*removed.lock(), vec!((s("shelf/a"), s("item"))));
+
+    // clients increasing the counter at the same time, only from the value each one read
+    let counter = s("counter");
+    sm_client.insert(&counter, &s("0")).unwrap().unwrap();
+    let clients = 2;
+    let threads: Vec<_> = (0..clients).map(|_| {
+        let servers = vec!(addr.clone());
+        let counter = counter.clone();
+        thread::spawn(move || {
+            let raft_client = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+            let sm_client = SMClient::new(sm_id, &raft_client);
+            let mut committed = 0;
+            while committed < 20 {
+                let read = sm_client.get(&counter).unwrap().unwrap().unwrap();
+                let next = (read.parse::<u64>().unwrap() + 1).to_string();
+                match sm_client.txn().compare(&counter, &read).put(&counter, &next).commit().unwrap() {
+                    Ok(results) => {
+                        assert_eq!(results, vec!(TxnResult::Compared, TxnResult::Put(Some(read))));
+                        committed += 1;
+                    },
+                    // the other client increased it since
+                    Err(aborted) => assert_eq!(aborted.failed, vec!(0))
+                }
+            }
+        })
+    }).collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+    // no increment was lost to the other client
+    assert_eq!(sm_client.get(&counter).unwrap().unwrap(), Some((clients * 20).to_string()));
+}
+
+#[test]
+fn hash_map_bulk() {
+    let addr = String::from("127.0.0.1:1746");
+    let mut bulk_sm = string_string_hashmap::Map::new_by_name(&String::from("bulk"));
+    let mut single_sm = string_string_hashmap::Map::new_by_name(&String::from("single"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let (bulk_id, single_id) = (bulk_sm.id, single_sm.id);
+    bulk_sm.init_callback(&raft_service);
+    single_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(bulk_sm));
+    raft_service.register_state_machine(Box::new(single_sm));
+    raft_service.bootstrap().unwrap();
+
+    let raft_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let bulk = SMClient::new(bulk_id, &raft_client);
+    let single = SMClient::new(single_id, &raft_client);
+    RaftClient::prepare_subscription(&server);
+    let events = |client: &SMClient| {
+        let inserted = Arc::new(Mutex::new(Vec::new()));
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let (inserted_ref, removed_ref) = (inserted.clone(), removed.clone());
+        client.on_inserted(move |res: Result<(String, String), ()>| {
+            if let Ok(entry) = res {inserted_ref.lock().push(entry);}
+        }).unwrap().unwrap();
+        client.on_removed(move |res: Result<(String, String, ChangeReason), ()>| {
+            if let Ok((k, v, _)) = res {removed_ref.lock().push((k, v));}
+        }).unwrap().unwrap();
+        (inserted, removed)
+    };
+    let (bulk_inserted, bulk_removed) = events(&bulk);
+    let (single_inserted, single_removed) = events(&single);
+
+    // the keys of the later entries overlap the earlier ones
+    let entries: Vec<(String, String)> = (0..500)
+        .map(|i| (format!("k{}", i % 400), format!("v{}", i)))
+        .collect();
+    let keys: Vec<String> = (0..600).map(|i| format!("k{}", i)).collect();
+    let removing: Vec<String> = (0..600).filter(|i| i % 3 == 0).map(|i| format!("k{}", i)).collect();
+
+    let inserted = bulk.insert_many(&entries).unwrap().unwrap();
+    let expected_inserted: Vec<Option<String>> = entries.iter()
+        .map(|&(ref k, ref v)| single.insert(k, v).unwrap().unwrap())
+        .collect();
+    assert_eq!(inserted, expected_inserted);
+    let got = bulk.get_many(&keys).unwrap().unwrap();
+    let expected_got: Vec<Option<String>> = keys.iter().map(|k| single.get(k).unwrap().unwrap()).collect();
+    assert_eq!(got, expected_got);
+    assert_eq!(got[0], Some(String::from("v400")));
+    assert_eq!(got[450], None);
+
+    let removed = bulk.remove_many(&removing).unwrap().unwrap();
+    let expected_removed: Vec<Option<String>> = removing.iter().map(|k| single.remove(k).unwrap().unwrap()).collect();
+    assert_eq!(removed, expected_removed);
+    assert_eq!(bulk.get_many(&keys).unwrap().unwrap(), single.get_many(&keys).unwrap().unwrap());
+    assert_eq!(bulk.entries_paged(&0, &1000).unwrap().unwrap(), single.entries_paged(&0, &1000).unwrap().unwrap());
+    assert_eq!(bulk.len().unwrap().unwrap(), 266);
+
+    wait();
+    assert_eq!(bulk_inserted.lock().len(), 500);
+    assert_eq!(*bulk_inserted.lock(), *single_inserted.lock());
+    assert_eq!(*bulk_removed.lock(), *single_removed.lock());
+}
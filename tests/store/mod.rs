@@ -1,3 +1,8 @@
 mod value;
 mod number;
-mod map;
\ No newline at end of file
+mod map;
+mod btree;
+mod set;
+mod queue;
+mod ttl;
+mod lock;
\ No newline at end of file
@@ -15,6 +15,12 @@ mod u32 {
     use bifrost::store::number::U32::client::SMClient;
     use bifrost::rpc::Server;
     use bifrost::raft::state_machine::callback::client::SubscriptionService;
+    use bifrost::store::number::NumberError;
+    use raft::options;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test(){
@@ -23,11 +29,7 @@ mod u32 {
             &String::from("test"),
             0
         );
-        let service = RaftService::new(Options{
-            storage: Storage::Default(),
-            address: addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
-        });
+        let service = RaftService::new(options(Storage::Default(), &addr));
         let sm_id = num_sm.id;
         let server = Server::new(&addr);
         server.register_service(DEFAULT_SERVICE_ID, &service);
@@ -62,11 +64,74 @@ mod u32 {
         assert_eq!(sm_client.divide_and_get(&4).unwrap().unwrap(), 1);
         assert_eq!(sm_client.swap(&5).unwrap().unwrap(), 1);
         assert_eq!(sm_client.get().unwrap().unwrap(), 5);
-        assert_eq!(sm_client.compare_and_swap(&1, &10).unwrap().unwrap(), 5);
+        assert_eq!(sm_client.compare_and_swap(&1, &10).unwrap(), Err(5));
         assert_eq!(sm_client.get().unwrap().unwrap(), 5);
-        assert_eq!(sm_client.compare_and_swap(&5 ,&11).unwrap().unwrap(), 5);
+        assert_eq!(sm_client.compare_and_swap(&5 ,&11).unwrap(), Ok(5));
         assert_eq!(sm_client.get().unwrap().unwrap(), 11);
     }
+
+    #[test]
+    fn concurrent_adds() {
+        let addr = String::from("127.0.0.1:1735");
+        let mut num_sm = U32::Number::new_by_name(
+            &String::from("concurrent_adds"),
+            0
+        );
+        let service = RaftService::new(options(Storage::Default(), &addr));
+        let sm_id = num_sm.id;
+        let server = Server::new(&addr);
+        server.register_service(DEFAULT_SERVICE_ID, &service);
+        Server::listen_and_resume(&server);
+        num_sm.init_callback(&service);
+        assert!(RaftService::start(&service));
+        service.register_state_machine(Box::new(num_sm));
+        service.bootstrap();
+
+        let client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+        let sm_client = SMClient::new(sm_id, &client);
+        RaftClient::prepare_subscription(&server);
+        let changes = Arc::new(AtomicUsize::new(0));
+        let contiguous = Arc::new(AtomicBool::new(true));
+        {
+            let changes = changes.clone();
+            let contiguous = contiguous.clone();
+            sm_client.on_changed(move |res: Result<(u32, u32), ()>| {
+                if let Ok((old, new)) = res {
+                    if new != old + 1 {contiguous.store(false, Ordering::SeqCst);}
+                    changes.fetch_add(1, Ordering::SeqCst);
+                }
+            }).unwrap().unwrap();
+        }
+
+        let handles: Vec<_> = (0..10).map(|_| {
+            let client = client.clone();
+            thread::spawn(move || {
+                let sm_client = SMClient::new(sm_id, &client);
+                for _ in 0..1000 {
+                    sm_client.add(&1).unwrap().unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(sm_client.get().unwrap().unwrap(), 10000);
+        for _ in 0..100 {
+            if changes.load(Ordering::SeqCst) >= 10000 {break;}
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(changes.load(Ordering::SeqCst), 10000);
+        assert!(contiguous.load(Ordering::SeqCst));
+
+        // arithmetic out of range fails and leaves the number as it was
+        assert_eq!(sm_client.sub(&10001).unwrap(), Err(NumberError::Overflow));
+        assert_eq!(sm_client.get_and_add(&u32::max_value()).unwrap(), Err(NumberError::Overflow));
+        assert_eq!(sm_client.divide_and_get(&0).unwrap(), Err(NumberError::DivideByZero));
+        assert_eq!(sm_client.get().unwrap().unwrap(), 10000);
+        assert_eq!(sm_client.sub(&1).unwrap(), Ok(9999));
+        assert_eq!(sm_client.get_and_add(&1).unwrap(), Ok(9999));
+        assert_eq!(sm_client.get().unwrap().unwrap(), 10000);
+    }
 }
 
 mod f64 {
@@ -85,6 +150,7 @@ mod f64 {
     };
     use bifrost::rpc::Server;
     use bifrost::store::number::F64::client::SMClient;
+    use raft::options;
 
     #[test]
     fn test(){
@@ -93,11 +159,7 @@ mod f64 {
             &String::from("test"),
             0.0
         );
-        let service = RaftService::new(Options{
-            storage: Storage::Default(),
-            address: addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
-        });
+        let service = RaftService::new(options(Storage::Default(), &addr));
         let sm_id = num_sm.id;
         let server = Server::new(&addr);
         server.register_service(DEFAULT_SERVICE_ID, &service);
@@ -124,9 +186,9 @@ mod f64 {
         assert_eq!(sm_client.divide_and_get(&4.0).unwrap().unwrap(), 1.0);
         assert_eq!(sm_client.swap(&5.0).unwrap().unwrap(), 1.0);
         assert_eq!(sm_client.get().unwrap().unwrap(), 5.0);
-        assert_eq!(sm_client.compare_and_swap(&1.0, &10.0).unwrap().unwrap(), 5.0);
+        assert_eq!(sm_client.compare_and_swap(&1.0, &10.0).unwrap(), Err(5.0));
         assert_eq!(sm_client.get().unwrap().unwrap(), 5.0);
-        assert_eq!(sm_client.compare_and_swap(&5.0 ,&11.0).unwrap().unwrap(), 5.0);
+        assert_eq!(sm_client.compare_and_swap(&5.0 ,&11.0).unwrap(), Ok(5.0));
         assert_eq!(sm_client.get().unwrap().unwrap(), 11.0);
     }
 }
\ No newline at end of file
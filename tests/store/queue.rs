@@ -0,0 +1,109 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::{StateMachineCtl, CommandCtx};
+use bifrost::store::queue::QueueError;
+use bifrost::store::queue::string_queue;
+use bifrost::store::queue::string_queue::StateMachineCmds;
+use bifrost::store::queue::string_queue::client::SMClient;
+use bifrost::rpc::*;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use parking_lot::Mutex;
+
+use raft::{wait, options};
+
+fn at(leader_time_ms: u64) -> CommandCtx {
+    CommandCtx {
+        leader_time_ms: leader_time_ms,
+        ..CommandCtx::default()
+    }
+}
+
+#[test]
+fn queue_leases() {
+    let mut queue = string_queue::Queue::new_by_name(&String::from("queue_leases"), 3);
+    let (a, b, c) = (String::from("a"), String::from("b"), String::from("c"));
+    assert_eq!(queue.push(at(0), a.clone()), Ok(1));
+    assert_eq!(queue.push(at(0), b.clone()), Ok(2));
+    assert_eq!(queue.push(at(0), c.clone()), Ok(3));
+    assert_eq!(queue.push(at(0), String::from("d")), Err(QueueError::QueueFull));
+
+    assert_eq!(queue.pop_timeout_lease(at(10), 1, 100), Ok(Some((1, a.clone()))));
+    assert_eq!(queue.pop_timeout_lease(at(20), 1, 100), Ok(Some((2, b.clone()))));
+    assert_eq!(queue.peek(), Ok(Some((3, c.clone()))));
+    // leased items still count against the bound
+    assert_eq!(queue.len(), Ok(3));
+    assert_eq!(queue.push(at(30), String::from("d")), Err(QueueError::QueueFull));
+
+    // queries do not expire leases, the next command does.
+    // both expired, the items are back at the head in the order they were pushed
+    assert_eq!(queue.peek(), Ok(Some((3, c.clone()))));
+    assert_eq!(queue.pop_timeout_lease(at(120), 2, 100), Ok(Some((1, a.clone()))));
+    assert_eq!(queue.ack(at(130), 2), Ok(false));
+    assert_eq!(queue.pop_timeout_lease(at(140), 2, 100), Ok(Some((2, b.clone()))));
+    assert_eq!(queue.ack(at(150), 1), Ok(true));
+    assert_eq!(queue.ack(at(150), 1), Ok(false));
+    assert_eq!(queue.nack(at(160), 2), Ok(true));
+    assert_eq!(queue.peek(), Ok(Some((2, b.clone()))));
+    assert_eq!(queue.len(), Ok(2));
+    assert_eq!(queue.push(at(170), String::from("d")), Ok(4));
+
+    // replicas recovered from the snapshot expire the same leases
+    assert_eq!(queue.pop_timeout_lease(at(180), 2, 100), Ok(Some((2, b.clone()))));
+    let mut replica = string_queue::Queue::new_by_name(&String::from("queue_leases"), 3);
+    replica.recover(queue.snapshot().unwrap());
+    assert_eq!(replica.pop_timeout_lease(at(280), 3, 100), Ok(Some((2, b.clone()))));
+    assert_eq!(queue.pop_timeout_lease(at(280), 3, 100), Ok(Some((2, b.clone()))));
+    assert_eq!(replica.snapshot(), queue.snapshot());
+}
+
+#[test]
+fn queue_consumer_crash() {
+    let addr = String::from("127.0.0.1:1737");
+    let mut queue_sm = string_queue::Queue::new_by_name(&String::from("queue_consumer_crash"), 100);
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = queue_sm.id;
+    queue_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(queue_sm));
+    raft_service.bootstrap();
+
+    let raft_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &raft_client);
+    RaftClient::prepare_subscription(&server);
+    let pushed = Arc::new(Mutex::new(Vec::new()));
+    let pushed_ref = pushed.clone();
+    sm_client.on_pushed(move |res: Result<(u64, String), ()>| {
+        if let Ok((msg_id, _)) = res {pushed_ref.lock().push(msg_id);}
+    }).unwrap().unwrap();
+
+    let job1 = String::from("job1");
+    let job2 = String::from("job2");
+    assert_eq!(sm_client.push(&job1).unwrap(), Ok(1));
+    assert_eq!(sm_client.push(&job2).unwrap(), Ok(2));
+    wait();
+    assert_eq!(*pushed.lock(), vec!(1, 2));
+
+    // the consumer leases the first job and crashes before acking it
+    {
+        let crashing_client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+        let crashing = SMClient::new(sm_id, &crashing_client);
+        assert_eq!(crashing.pop_timeout_lease(&1, &1000).unwrap().unwrap(), Some((1, job1.clone())));
+    }
+    // hidden from the others until the lease expires
+    assert_eq!(sm_client.pop_timeout_lease(&2, &1000).unwrap().unwrap(), Some((2, job2.clone())));
+    assert_eq!(sm_client.pop_timeout_lease(&2, &1000).unwrap().unwrap(), None);
+    assert!(sm_client.ack(&2).unwrap().unwrap());
+    assert_eq!(sm_client.len().unwrap().unwrap(), 1);
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(sm_client.pop_timeout_lease(&2, &1000).unwrap().unwrap(), Some((1, job1.clone())));
+    assert!(sm_client.ack(&1).unwrap().unwrap());
+    assert_eq!(sm_client.len().unwrap().unwrap(), 0);
+    assert_eq!(sm_client.peek().unwrap().unwrap(), None);
+}
@@ -0,0 +1,100 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::raft::state_machine::StateMachineCtl;
+use bifrost::store::set::string_hashset;
+use bifrost::store::set::string_hashset::StateMachineCmds;
+use bifrost::store::set::string_hashset::client::SMClient;
+use bifrost::rpc::*;
+
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use raft::{wait, options};
+
+#[test]
+fn hash_set() {
+    let addr = String::from("127.0.0.1:1736");
+    let mut set_sm = string_hashset::Set::new_by_name(&String::from("hash_set"));
+    let raft_service = RaftService::new(options(Storage::Default(), &addr));
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &raft_service);
+    Server::listen_and_resume(&server);
+    let sm_id = set_sm.id;
+    set_sm.init_callback(&raft_service);
+    assert!(RaftService::start(&raft_service));
+    raft_service.register_state_machine(Box::new(set_sm));
+    raft_service.bootstrap();
+
+    let raft_client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &raft_client);
+    RaftClient::prepare_subscription(&server);
+
+    let inserted = Arc::new(Mutex::new(Vec::new()));
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let inserted_ref = inserted.clone();
+    let removed_ref = removed.clone();
+    sm_client.on_inserted(move |res: Result<String, ()>| {
+        if let Ok(v) = res {inserted_ref.lock().push(v);}
+    }).unwrap().unwrap();
+    sm_client.on_removed(move |res: Result<String, ()>| {
+        if let Ok(v) = res {removed_ref.lock().push(v);}
+    }).unwrap().unwrap();
+
+    let a = String::from("a");
+    let b = String::from("b");
+    let c = String::from("c");
+    let d = String::from("d");
+
+    assert!(sm_client.is_empty().unwrap().unwrap());
+    assert!(sm_client.insert(&a).unwrap().unwrap());
+    assert!(sm_client.insert(&c).unwrap().unwrap());
+    assert!(sm_client.insert(&b).unwrap().unwrap());
+    // duplicates are not added again, nor raise events
+    assert!(!sm_client.insert(&a).unwrap().unwrap());
+    assert_eq!(sm_client.len().unwrap().unwrap(), 3);
+    assert!(sm_client.contains(&b).unwrap().unwrap());
+    assert!(!sm_client.contains(&d).unwrap().unwrap());
+
+    assert_eq!(sm_client.to_vec_paged(&0, &2).unwrap().unwrap(), vec!(a.clone(), b.clone()));
+    assert_eq!(sm_client.to_vec_paged(&2, &2).unwrap().unwrap(), vec!(c.clone()));
+    assert!(sm_client.to_vec_paged(&3, &2).unwrap().unwrap().is_empty());
+    assert_eq!(
+        sm_client.intersection_with(&vec!(d.clone(), c.clone(), a.clone())).unwrap().unwrap(),
+        vec!(c.clone(), a.clone())
+    );
+    assert_eq!(
+        sm_client.difference_with(&vec!(d.clone(), c.clone(), a.clone())).unwrap().unwrap(),
+        vec!(d.clone())
+    );
+
+    assert!(sm_client.remove(&b).unwrap().unwrap());
+    assert!(!sm_client.remove(&b).unwrap().unwrap());
+    assert!(!sm_client.remove(&d).unwrap().unwrap());
+    sm_client.clear().unwrap().unwrap();
+    assert!(sm_client.is_empty().unwrap().unwrap());
+
+    wait();
+    let mut inserted = inserted.lock().clone();
+    inserted.sort();
+    assert_eq!(inserted, vec!(a.clone(), b.clone(), c.clone()));
+    assert_eq!(*removed.lock(), vec!(b.clone(), a.clone(), c.clone()));
+}
+
+#[test]
+fn hash_set_snapshot() {
+    // elements inserted in any order give the same snapshot
+    let mut set1 = string_hashset::Set::new_by_name(&String::from("hash_set_snapshot"));
+    let mut set2 = string_hashset::Set::new_by_name(&String::from("hash_set_snapshot"));
+    for i in 0..100 {
+        set1.insert(format!("v{}", i)).unwrap();
+        set2.insert(format!("v{}", 99 - i)).unwrap();
+    }
+    let snapshot = set1.snapshot().unwrap();
+    assert_eq!(snapshot, set2.snapshot().unwrap());
+
+    let mut recovered = string_hashset::Set::new_by_name(&String::from("hash_set_snapshot"));
+    recovered.recover(snapshot.clone());
+    assert_eq!(recovered.len().unwrap(), 100);
+    assert!(recovered.contains(String::from("v42")).unwrap());
+    assert_eq!(recovered.snapshot().unwrap(), snapshot);
+}
@@ -0,0 +1,120 @@
+use bifrost::raft::*;
+use bifrost::raft::client::RaftClient;
+use bifrost::store::map::string_string_hashmap;
+use bifrost::store::map::string_string_hashmap::commands::entries_paged;
+use bifrost::store::value::string;
+use bifrost::store::value::string::commands::{get, revision};
+use bifrost::store::ttl::ChangeReason;
+use bifrost::rpc::Server;
+
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use raft::{wait, options};
+
+const TICK_MS: u64 = 100;
+
+fn expiring_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(options(Storage::Default(), addr));
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    let mut map_sm = string_string_hashmap::Map::new_by_name(&String::from("ttl_map"));
+    map_sm.init_callback(&service);
+    map_sm.init_expiry(&service, TICK_MS);
+    service.register_state_machine(Box::new(map_sm));
+    let mut value_sm = string::Value::new_by_name(&String::from("ttl_value"), String::from("nobody"));
+    value_sm.init_callback(&service);
+    value_sm.init_expiry(&service, TICK_MS);
+    service.register_state_machine(Box::new(value_sm));
+    (service, server)
+}
+
+// answer of the query in the state machine of the server, without going through the leader
+fn local_query<M: RaftMsg<R>, R>(service: &Arc<RaftService>, sm_id: u64, msg: &M) -> R {
+    let (fn_id, _, data) = msg.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    match service.c_query(&entry).unwrap() {
+        ClientQryResponse::Success { data, .. } => msg.decode_return(&data.unwrap()).unwrap(),
+        ClientQryResponse::LeftBehind => panic!("left behind")
+    }
+}
+
+#[test]
+fn expiry_on_replicas() {
+    let addrs: Vec<String> = (1740..1743).map(|port| format!("127.0.0.1:{}", port)).collect();
+    let map_id = string_string_hashmap::Map::new_by_name(&String::from("ttl_map")).id;
+    let value_id = string::Value::new_by_name(&String::from("ttl_value"), String::new()).id;
+    let (service1, server1) = expiring_service(&addrs[0]);
+    service1.bootstrap().unwrap();
+    let (service2, _server2) = expiring_service(&addrs[1]);
+    service2.join(&vec!(addrs[0].clone())).unwrap().unwrap();
+    let (service3, _server3) = expiring_service(&addrs[2]);
+    service3.join(&vec!(addrs[0].clone())).unwrap().unwrap();
+    wait();
+
+    let client = RaftClient::new(&vec!(addrs[0].clone()), DEFAULT_SERVICE_ID).unwrap();
+    RaftClient::prepare_subscription(&server1);
+    let map_client = string_string_hashmap::client::SMClient::new(map_id, &client);
+    let value_client = string::client::SMClient::new(value_id, &client);
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let changed = Arc::new(Mutex::new(Vec::new()));
+    let removed_ref = removed.clone();
+    let changed_ref = changed.clone();
+    map_client.on_removed(move |res: Result<(String, String, ChangeReason), ()>| {
+        if let Ok((k, _, reason)) = res {removed_ref.lock().push((k, reason));}
+    }).unwrap().unwrap();
+    value_client.on_changed(move |res: Result<(String, String, u64, ChangeReason), ()>| {
+        if let Ok((_, new, _, reason)) = res {changed_ref.lock().push((new, reason));}
+    }).unwrap().unwrap();
+
+    let s = |val: &str| String::from(val);
+    map_client.insert_with_ttl(&s("lease"), &s("holder"), &800).unwrap().unwrap();
+    map_client.insert_with_ttl(&s("session/2"), &s("open"), &800).unwrap().unwrap();
+    map_client.insert_with_ttl(&s("session/3"), &s("open"), &60_000).unwrap().unwrap();
+    map_client.insert(&s("forever"), &s("kept")).unwrap().unwrap();
+    // inserted again without one, it no longer expires
+    map_client.insert_with_ttl(&s("renewed"), &s("first"), &800).unwrap().unwrap();
+    map_client.insert(&s("renewed"), &s("second")).unwrap().unwrap();
+    value_client.set_with_ttl(&s("leader"), &800).unwrap().unwrap();
+    assert_eq!(map_client.get(&s("lease")).unwrap().unwrap(), Some(s("holder")));
+    assert_eq!(value_client.get().unwrap().unwrap(), s("leader"));
+    assert!(!value_client.set_if_absent(&s("usurper")).unwrap().unwrap());
+
+    wait();
+    assert_eq!(map_client.get(&s("lease")).unwrap().unwrap(), None);
+    assert!(!map_client.contains_key(&s("session/2")).unwrap().unwrap());
+    assert_eq!(value_client.get().unwrap().unwrap(), s("nobody"));
+
+    // every replica removed the same entries at the same place in the log
+    let expected = vec!(
+        (s("forever"), s("kept")),
+        (s("renewed"), s("second")),
+        (s("session/3"), s("open"))
+    );
+    let value_revision = local_query(&service1, value_id, &revision::new()).unwrap();
+    for service in &[&service1, &service2, &service3] {
+        assert_eq!(local_query(service, map_id, &entries_paged::new(&0, &100)).unwrap(), expected);
+        assert_eq!(local_query(service, value_id, &get::new()).unwrap(), s("nobody"));
+        assert_eq!(local_query(service, value_id, &revision::new()).unwrap(), value_revision);
+    }
+    let mut removed = removed.lock().clone();
+    removed.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(removed, vec!((s("lease"), ChangeReason::Expired), (s("session/2"), ChangeReason::Expired)));
+    assert_eq!(*changed.lock(), vec!((s("leader"), ChangeReason::Command), (s("nobody"), ChangeReason::Expired)));
+
+    // expired values are absent again
+    assert!(value_client.set_if_absent(&s("successor")).unwrap().unwrap());
+    assert_eq!(value_client.get().unwrap().unwrap(), s("successor"));
+}
@@ -1,9 +1,22 @@
 use bifrost::raft::*;
-use bifrost::raft::client::RaftClient;
+use bifrost::raft::client::{RaftClient, ClientOptions, SubscriptionError};
+use bifrost::raft::state_machine::configs::CONFIG_SM_ID;
+use bifrost::raft::state_machine::configs::commands::subscribe;
 use bifrost::store::value::string;
 use bifrost::store::value::string::client::SMClient;
-use bifrost::rpc::Server;
+use bifrost::store::value::string::commands::{get, revision, on_changed};
+use bifrost::store::ttl::ChangeReason;
+use bifrost::rpc::{Server, MiddlewareDecision, RPCRequestError};
+use bifrost::utils::bincode;
 use bifrost::raft::state_machine::callback::client::SubscriptionService;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::thread;
+use parking_lot::Mutex;
+use bifrost_hasher::hash_bytes;
+
+use raft::{wait, options};
 
 #[test]
 fn string(){
@@ -14,11 +27,7 @@ fn string(){
         &String::from("test"),
         original_string.clone()
     );
-    let service = RaftService::new(Options{
-        storage: Storage::Default(),
-        address: addr.clone(),
-        service_id: DEFAULT_SERVICE_ID,
-    });
+    let service = RaftService::new(options(Storage::Default(), &addr));
     let sm_id = string_sm.id;
     let server = Server::new(&addr);
     string_sm.init_callback(&service);
@@ -44,9 +53,497 @@ fn string(){
         &sm_client.get().unwrap().unwrap(),
         &original_string
     );
+    assert_eq!(sm_client.revision().unwrap().unwrap(), 0);
     sm_client.set(&altered_string).unwrap().unwrap();
     assert_eq!(
         &sm_client.get().unwrap().unwrap(),
         &altered_string
     );
-}
\ No newline at end of file
+    // the log index of the change
+    assert_eq!(Some(sm_client.revision().unwrap().unwrap()), service.last_log_id());
+}
+
+fn snapshotting_service(addr: &String) -> (Arc<RaftService>, Arc<Server>) {
+    let service = RaftService::new(Options {
+        compaction: Compaction {
+            max_entries: Some(10),
+            max_bytes: None,
+            snapshot_chunk_size: 8,
+        },
+        ..options(Storage::Default(), addr)
+    });
+    let server = Server::new(addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string::Value::new_by_name(
+        &String::from("snapshot"),
+        String::new()
+    )));
+    (service, server)
+}
+
+#[test]
+fn string_from_snapshot(){
+    let s1_addr = String::from("127.0.0.1:1602");
+    let s2_addr = String::from("127.0.0.1:1603");
+    let sm_id = string::Value::new_by_name(&String::from("snapshot"), String::new()).id;
+    let (service1, _server1) = snapshotting_service(&s1_addr);
+    service1.bootstrap();
+    let client = RaftClient::new(&vec!(s1_addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    for i in 0..100 {
+        sm_client.set(&format!("text {}", i)).unwrap().unwrap();
+    }
+    assert!(service1.last_snapshot_id().is_some());
+
+    let (service2, _server2) = snapshotting_service(&s2_addr);
+    service2.join(&vec!(s1_addr.clone())).unwrap();
+    wait();
+    // the value came with the snapshot, not from the commands setting it
+    assert!(service2.last_snapshot_id().is_some());
+    assert!(service2.num_logs() < 100);
+    let msg = get::new();
+    let (fn_id, _, data) = msg.encode();
+    let entry = LogEntry {
+        id: 0,
+        term: 0,
+        sm_id: sm_id,
+        fn_id: fn_id,
+        data: data.clone(),
+        trace_id: None,
+        session: None,
+        time: 0,
+        checksum: 0,
+    };
+    let value = match service2.c_query(&entry).unwrap() {
+        ClientQryResponse::Success { data, .. } => msg.decode_return(&data.unwrap()).unwrap().unwrap(),
+        ClientQryResponse::LeftBehind => panic!("left behind")
+    };
+    assert_eq!(value, String::from("text 99"));
+    // so did the revision
+    let msg = revision::new();
+    let (fn_id, _, data) = msg.encode();
+    let entry = LogEntry { fn_id: fn_id, data: data.clone(), ..entry };
+    let revision = match service2.c_query(&entry).unwrap() {
+        ClientQryResponse::Success { data, .. } => msg.decode_return(&data.unwrap()).unwrap().unwrap(),
+        ClientQryResponse::LeftBehind => panic!("left behind")
+    };
+    assert!(revision >= 100);
+    assert_eq!(revision, sm_client.revision().unwrap().unwrap());
+}
+
+// state machines notify through the callback, the log is compacted after a few entries
+fn subscribed_service(addr: &String, server: &Arc<Server>) -> Arc<RaftService> {
+    let service = RaftService::new(Options {
+        compaction: Compaction {
+            max_entries: Some(10),
+            ..Compaction::Default()
+        },
+        ..options(Storage::Default(), addr)
+    });
+    let mut string_sm = string::Value::new_by_name(&String::from("resubscribe"), String::new());
+    string_sm.init_callback(&service);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string_sm));
+    service
+}
+
+#[test]
+fn string_resubscribe() {
+    let s1_addr = String::from("127.0.0.1:1717");
+    let s2_addr = String::from("127.0.0.1:1718");
+    let s3_addr = String::from("127.0.0.1:1719");
+    let callback_addr = String::from("127.0.0.1:1720");
+    let sm_id = string::Value::new_by_name(&String::from("resubscribe"), String::new()).id;
+    let server1 = Server::new(&s1_addr);
+    Server::listen_and_resume(&server1);
+    let service1 = subscribed_service(&s1_addr, &server1);
+    service1.bootstrap().unwrap();
+    let server2 = Server::new(&s2_addr);
+    Server::listen_and_resume(&server2);
+    let service2 = subscribed_service(&s2_addr, &server2);
+    service2.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    let server3 = Server::new(&s3_addr);
+    Server::listen_and_resume(&server3);
+    let service3 = subscribed_service(&s3_addr, &server3);
+    service3.join(&vec!(s1_addr.clone())).unwrap().unwrap();
+    wait();
+
+    let callback_server = Server::new(&callback_addr);
+    Server::listen_and_resume(&callback_server);
+    RaftClient::prepare_subscription(&callback_server);
+    let servers = vec!(s1_addr.clone(), s2_addr.clone(), s3_addr.clone());
+    let client = RaftClient::with_options(&servers, DEFAULT_SERVICE_ID, ClientOptions {
+        members_refresh: Some(Duration::from_millis(200)),
+        ..ClientOptions::Default()
+    }).unwrap();
+    let resubscribed = Arc::new(AtomicUsize::new(0));
+    let resubscribed_ref = resubscribed.clone();
+    client.on_resubscribed(move |ids| {resubscribed_ref.fetch_add(ids.len(), Ordering::SeqCst);});
+    let sm_client = SMClient::new(sm_id, &client);
+    let latest = Arc::new(Mutex::new(String::new()));
+    let latest_ref = latest.clone();
+    sm_client.on_changed(move |res| {
+        if let Ok((_, new, _, _)) = res {
+            *latest_ref.lock() = new;
+        }
+    }).unwrap().unwrap();
+    sm_client.set(&String::from("before restart")).unwrap().unwrap();
+    wait();
+    assert_eq!(*latest.lock(), String::from("before restart"));
+
+    // the server notifications came from is restarted without its state, it gets a snapshot of the
+    // others. by then they compacted the subscription out of their logs
+    assert!(service1.leave());
+    server1.remove_service(DEFAULT_SERVICE_ID);
+    wait();
+    for i in 0..30 {
+        sm_client.set(&format!("text {}", i)).unwrap().unwrap();
+    }
+    let restarted = subscribed_service(&s1_addr, &server1);
+    restarted.join(&vec!(s2_addr.clone(), s3_addr.clone())).unwrap().unwrap();
+    wait();
+    assert!(restarted.last_snapshot_id().is_some());
+    let leader = if service2.is_leader() {&service2} else {&service3};
+    leader.transfer_leadership(restarted.id).unwrap();
+    wait();
+    assert!(restarted.is_leader());
+    assert_eq!(resubscribed.load(Ordering::SeqCst), 1);
+
+    sm_client.set(&String::from("after restart")).unwrap().unwrap();
+    wait();
+    assert_eq!(*latest.lock(), String::from("after restart"));
+}
+
+#[test]
+fn string_unsubscribe() {
+    let addr = String::from("127.0.0.1:1721");
+    let callback_addr = String::from("127.0.0.1:1722");
+    let service = RaftService::new(Options {
+        subscriber_ttl: Duration::from_millis(500),
+        ..options(Storage::Default(), &addr)
+    });
+    let mut string_sm = string::Value::new_by_name(&String::from("unsubscribe"), String::new());
+    let sm_id = string_sm.id;
+    string_sm.init_callback(&service);
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string_sm));
+    service.bootstrap().unwrap();
+    let callback_server = Server::new(&callback_addr);
+    Server::listen_and_resume(&callback_server);
+    RaftClient::prepare_subscription(&callback_server);
+
+    let servers = vec!(addr.clone());
+    let client = RaftClient::new(&servers, DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    let fired = Arc::new(AtomicUsize::new(0));
+    let fired_ref = fired.clone();
+    let handle = sm_client.on_changed(move |_| {fired_ref.fetch_add(1, Ordering::SeqCst);}).unwrap().unwrap();
+    assert_eq!(handle.sm_id(), sm_id);
+    assert_eq!(service.num_subscriptions(), 1);
+    sm_client.set(&String::from("first")).unwrap().unwrap();
+    wait();
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+    handle.cancel().unwrap();
+    assert_eq!(service.num_subscriptions(), 0);
+    sm_client.set(&String::from("second")).unwrap().unwrap();
+    wait();
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+    match handle.cancel() {
+        Err(SubscriptionError::NotSubscribed) => {},
+        other => panic!("expect not subscribed, got {:?}", other)
+    }
+
+    // handles of the client cancel when dropped
+    let cancelling = RaftClient::with_options(&servers, DEFAULT_SERVICE_ID, ClientOptions {
+        cancel_dropped_subscriptions: true,
+        ..ClientOptions::Default()
+    }).unwrap();
+    {
+        let _handle = SMClient::new(sm_id, &cancelling).on_changed(|_| {}).unwrap().unwrap();
+        assert_eq!(service.num_subscriptions(), 1);
+    }
+    assert_eq!(service.num_subscriptions(), 0);
+
+    // a subscriber nothing listens for is dropped once unreachable for longer than the ttl
+    let (fn_id, _, pattern) = on_changed::new().encode();
+    let key = (DEFAULT_SERVICE_ID, sm_id, fn_id, hash_bytes(&pattern));
+    let gone = String::from("127.0.0.1:1723");
+    client.execute(CONFIG_SM_ID, &subscribe::new(&key, &gone, &1, &42)).unwrap().unwrap();
+    assert_eq!(service.num_subscriptions(), 1);
+    sm_client.set(&String::from("third")).unwrap().unwrap();
+    assert_eq!(service.num_subscriptions(), 1);
+    thread::sleep(Duration::from_secs(1));
+    sm_client.set(&String::from("fourth")).unwrap().unwrap();
+    assert_eq!(service.num_subscriptions(), 0);
+}
+
+#[test]
+fn string_redelivery() {
+    let addr = String::from("127.0.0.1:1724");
+    let callback_addr = String::from("127.0.0.1:1725");
+    let service = RaftService::new(Options {
+        callback_buffer: 4,
+        ..options(Storage::Default(), &addr)
+    });
+    let mut string_sm = string::Value::new_by_name(&String::from("redelivery"), String::new());
+    let sm_id = string_sm.id;
+    string_sm.init_callback(&service);
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string_sm));
+    service.bootstrap().unwrap();
+
+    // a subscriber of its own, deliveries to it are refused while refusing or drops are left
+    let drops = Arc::new(AtomicUsize::new(0));
+    let refusing = Arc::new(AtomicBool::new(false));
+    let callback_server = Server::new(&callback_addr);
+    let drops_ref = drops.clone();
+    let refusing_ref = refusing.clone();
+    callback_server.register_middleware(Box::new(move |_: u64, _: &[u8]| {
+        let left = drops_ref.load(Ordering::SeqCst);
+        if refusing_ref.load(Ordering::SeqCst) {
+            MiddlewareDecision::Reject(RPCRequestError::Rejected)
+        } else if left > 0 {
+            drops_ref.store(left - 1, Ordering::SeqCst);
+            MiddlewareDecision::Reject(RPCRequestError::Rejected)
+        } else {
+            MiddlewareDecision::Continue
+        }
+    }));
+    Server::listen_and_resume(&callback_server);
+    let subscription = SubscriptionService::initialize(&callback_server);
+    let (fn_id, _, pattern) = on_changed::new().encode();
+    let key = (DEFAULT_SERVICE_ID, sm_id, fn_id, hash_bytes(&pattern));
+    let sub_id = 7;
+    let got = Arc::new(Mutex::new(Vec::new()));
+    let got_ref = got.clone();
+    subscription.subs.write().entry(key).or_insert_with(|| Vec::new()).push((sub_id, Arc::new(move |data: Vec<u8>| {
+        let changed: Result<(String, String, u64, ChangeReason), ()> = bincode::deserialize(&data);
+        got_ref.lock().push(changed.unwrap().1);
+    })));
+    let resyncs = Arc::new(AtomicUsize::new(0));
+    let resyncs_ref = resyncs.clone();
+    subscription.on_resync(sub_id, Arc::new(move || {resyncs_ref.fetch_add(1, Ordering::SeqCst);}));
+    let client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    client.execute(CONFIG_SM_ID, &subscribe::new(
+        &key, &subscription.server_address, &subscription.session_id, &sub_id
+    )).unwrap().unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    let set = |val: &str| sm_client.set(&String::from(val)).unwrap().unwrap();
+    let values = |vals: &[&str]| vals.iter().map(|val| String::from(*val)).collect::<Vec<_>>();
+
+    set("a");
+    wait();
+    assert_eq!(*got.lock(), values(&["a"]));
+
+    // the refused delivery is tried again
+    drops.store(1, Ordering::SeqCst);
+    set("b");
+    set("c");
+    wait();
+    assert_eq!(drops.load(Ordering::SeqCst), 0);
+    assert_eq!(*got.lock(), values(&["a", "b", "c"]));
+    assert_eq!(resyncs.load(Ordering::SeqCst), 0);
+
+    // more missed than the leader keeps
+    refusing.store(true, Ordering::SeqCst);
+    for val in &["d", "e", "f", "g", "h", "i"] {
+        set(*val);
+    }
+    wait();
+    assert_eq!(*got.lock(), values(&["a", "b", "c"]));
+    refusing.store(false, Ordering::SeqCst);
+    wait();
+    assert_eq!(resyncs.load(Ordering::SeqCst), 1);
+    assert_eq!(*got.lock(), values(&["a", "b", "c", "f", "g", "h", "i"]));
+    set("j");
+    wait();
+    assert_eq!(resyncs.load(Ordering::SeqCst), 1);
+    assert_eq!(got.lock().last(), Some(&String::from("j")));
+}
+
+#[test]
+fn string_slow_subscriber() {
+    let addr = String::from("127.0.0.1:1728");
+    let callback_addr = String::from("127.0.0.1:1729");
+    let service = RaftService::new(options(Storage::Default(), &addr));
+    let mut string_sm = string::Value::new_by_name(&String::from("slow subscriber"), String::new());
+    let sm_id = string_sm.id;
+    string_sm.init_callback(&service);
+    let server = Server::new(&addr);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string_sm));
+    service.bootstrap().unwrap();
+    let callback_server = Server::new(&callback_addr);
+    Server::listen_and_resume(&callback_server);
+    RaftClient::prepare_subscription(&callback_server);
+
+    let client = RaftClient::new(&vec!(addr.clone()), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    let slow = Arc::new(AtomicUsize::new(0));
+    let fast = Arc::new(AtomicUsize::new(0));
+    let slow_ref = slow.clone();
+    let fast_ref = fast.clone();
+    sm_client.on_changed(move |_| {
+        thread::sleep(Duration::from_secs(1));
+        slow_ref.fetch_add(1, Ordering::SeqCst);
+    }).unwrap().unwrap();
+    sm_client.on_changed(move |_| {fast_ref.fetch_add(1, Ordering::SeqCst);}).unwrap().unwrap();
+    sm_client.on_changed(|_| panic!("closure of a subscriber panics")).unwrap().unwrap();
+
+    // neither the log nor the fast subscriber waits for the slow one
+    let started = Instant::now();
+    for i in 0..3 {
+        sm_client.set(&format!("value {}", i)).unwrap().unwrap();
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(fast.load(Ordering::SeqCst), 3);
+    assert!(slow.load(Ordering::SeqCst) < 3);
+
+    // the panics did not take the subscription service down
+    sm_client.set(&String::from("after panics")).unwrap().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(fast.load(Ordering::SeqCst), 4);
+    thread::sleep(Duration::from_secs(4));
+    assert_eq!(slow.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn string_compare_and_set() {
+    let addr = String::from("127.0.0.1:1738");
+    let mut string_sm = string::Value::new_by_name(
+        &String::from("string_compare_and_set"),
+        String::new()
+    );
+    let service = RaftService::new(options(Storage::Default(), &addr));
+    let sm_id = string_sm.id;
+    let server = Server::new(&addr);
+    string_sm.init_callback(&service);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(string_sm));
+    service.bootstrap();
+
+    let client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = SMClient::new(sm_id, &client);
+    RaftClient::prepare_subscription(&server);
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let changes_ref = changes.clone();
+    sm_client.on_changed(move |res: Result<(String, String, u64, ChangeReason), ()>| {
+        if let Ok((old, new, _, _)) = res {changes_ref.lock().push((old, new));}
+    }).unwrap().unwrap();
+    let s = |val: &str| String::from(val);
+
+    assert!(sm_client.set_if_absent(&s("first")).unwrap().unwrap());
+    assert!(!sm_client.set_if_absent(&s("second")).unwrap().unwrap());
+    assert_eq!(sm_client.get().unwrap().unwrap(), s("first"));
+
+    // only the expected value is replaced, failures do not raise events
+    assert_eq!(sm_client.compare_and_set(&s("other"), &s("second")).unwrap(), Err(s("first")));
+    assert_eq!(sm_client.compare_and_set(&s("first"), &s("second")).unwrap(), Ok(()));
+    assert_eq!(sm_client.compare_and_set(&s("first"), &s("third")).unwrap(), Err(s("second")));
+    // neither do sets of the same value
+    sm_client.set(&s("second")).unwrap().unwrap();
+    assert_eq!(sm_client.compare_and_set(&s("second"), &s("second")).unwrap(), Ok(()));
+
+    assert_eq!(sm_client.append(&s(" and more")).unwrap().unwrap(), s("second and more"));
+    assert_eq!(sm_client.len().unwrap().unwrap(), 15);
+    assert_eq!(sm_client.append(&s("")).unwrap().unwrap(), s("second and more"));
+
+    wait();
+    assert_eq!(*changes.lock(), vec!(
+        (s(""), s("first")),
+        (s("first"), s("second")),
+        (s("second"), s("second and more"))
+    ));
+}
+
+mod config {
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Limits {
+        pub max_connections: u32,
+        pub timeouts_ms: Vec<u64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Config {
+        pub name: String,
+        pub limits: Limits,
+        pub fallback: Option<Limits>,
+        pub labels: BTreeMap<String, String>,
+    }
+
+    def_store_value!(replicated, Config);
+}
+
+#[test]
+fn user_defined_value() {
+    use self::config::{Config, Limits};
+    use self::config::replicated;
+    use bifrost::raft::state_machine::StateMachineCtl;
+    use bifrost_hasher::hash_str;
+
+    let addr = String::from("127.0.0.1:1739");
+    let initial = Config {
+        name: String::from("initial"),
+        limits: Limits { max_connections: 10, timeouts_ms: vec!(100, 1000) },
+        fallback: None,
+        labels: vec!((String::from("zone"), String::from("a"))).into_iter().collect(),
+    };
+    let mut config_sm = replicated::Value::new_by_name(&String::from("user_defined_value"), initial.clone());
+    assert_eq!(config_sm.id, hash_str(&String::from("user_defined_value")));
+    let service = RaftService::new(options(Storage::Default(), &addr));
+    let sm_id = config_sm.id;
+    let server = Server::new(&addr);
+    config_sm.init_callback(&service);
+    server.register_service(DEFAULT_SERVICE_ID, &service);
+    Server::listen_and_resume(&server);
+    assert!(RaftService::start(&service));
+    service.register_state_machine(Box::new(config_sm));
+    service.bootstrap();
+
+    let client = RaftClient::new(&vec!(addr), DEFAULT_SERVICE_ID).unwrap();
+    let sm_client = replicated::client::SMClient::new(sm_id, &client);
+    RaftClient::prepare_subscription(&server);
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let changes_ref = changes.clone();
+    sm_client.on_changed(move |res: Result<(Config, Config, u64, ChangeReason), ()>| {
+        if let Ok((_, new, _, _)) = res {changes_ref.lock().push(new.name);}
+    }).unwrap().unwrap();
+
+    assert_eq!(sm_client.get().unwrap().unwrap(), initial);
+    let mut altered = initial.clone();
+    altered.name = String::from("altered");
+    altered.limits.timeouts_ms.push(10000);
+    altered.fallback = Some(Limits { max_connections: 1, timeouts_ms: vec!() });
+    altered.labels.insert(String::from("rack"), String::from("r1"));
+    sm_client.set(&altered).unwrap().unwrap();
+    assert_eq!(sm_client.get().unwrap().unwrap(), altered);
+    assert_eq!(sm_client.compare_and_set(&initial, &initial).unwrap(), Err(altered.clone()));
+    assert_eq!(sm_client.compare_and_set(&altered, &initial).unwrap(), Ok(()));
+    wait();
+    assert_eq!(*changes.lock(), vec!(String::from("altered"), String::from("initial")));
+
+    // the nested fields survive snapshots
+    let mut source = replicated::Value::new_by_name(&String::from("user_defined_value"), altered.clone());
+    source.revision = 42;
+    let mut recovered = replicated::Value::new_by_name(&String::from("user_defined_value"), initial.clone());
+    recovered.recover(source.snapshot().unwrap());
+    assert_eq!(recovered.val, altered);
+    assert_eq!(recovered.revision, 42);
+}
@@ -8,7 +8,9 @@ extern crate bifrost;
 extern crate bifrost_hasher;
 extern crate byteorder;
 extern crate bincode;
+extern crate serde;
 extern crate futures;
+extern crate tokio_core;
 
 #[macro_use]
 extern crate serde_derive;
@@ -16,6 +18,7 @@ extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
 extern crate parking_lot;
+extern crate rand;
 
 #[macro_use]
 extern crate log;
@@ -9,4 +9,112 @@ fn test() {
     assert!(clock > blank_clock);
     assert!(blank_clock < clock);
     assert!(blank_clock != clock);
-}
\ No newline at end of file
+}
+mod vclock {
+    use bifrost::utils::vclock::{VectorClock, Relation, Versioned};
+    use rand;
+
+    static SERVERS: u64 = 4;
+
+    fn random_clock() -> VectorClock {
+        let mut clock = VectorClock::new();
+        for server in 0..SERVERS {
+            for _ in 0..(rand::random::<u64>() % 3) {
+                clock.inc(server);
+            }
+        }
+        clock
+    }
+
+    // the relation by the counters of each server, clocks are ordered when all of them are
+    fn relation_of_counters(a: &VectorClock, b: &VectorClock) -> Relation {
+        let before = (0..SERVERS).all(|server| a.get(&server) <= b.get(&server));
+        let after = (0..SERVERS).all(|server| a.get(&server) >= b.get(&server));
+        match (before, after) {
+            (true, true) => Relation::Equal,
+            (true, false) => Relation::Before,
+            (false, true) => Relation::After,
+            (false, false) => Relation::Concurrent
+        }
+    }
+
+    #[test]
+    fn merge_properties() {
+        for _ in 0..1000 {
+            let (a, b, c) = (random_clock(), random_clock(), random_clock());
+            assert_eq!(a.merge(&b).encode(), b.merge(&a).encode());
+            assert_eq!(a.merge(&b).merge(&c).encode(), a.merge(&b.merge(&c)).encode());
+            assert_eq!(a.merge(&a).encode(), a.encode());
+            // the merged clock is after or equal to both of them
+            let merged = a.merge(&b);
+            for server in 0..SERVERS {
+                assert_eq!(merged.get(&server), ::std::cmp::max(a.get(&server), b.get(&server)));
+            }
+            assert!(a.relation(&merged) == Relation::Before || a.relation(&merged) == Relation::Equal);
+            assert!(b.relation(&merged) == Relation::Before || b.relation(&merged) == Relation::Equal);
+        }
+    }
+
+    #[test]
+    fn relation_properties() {
+        for _ in 0..1000 {
+            let (a, b) = (random_clock(), random_clock());
+            let relation = a.relation(&b);
+            assert_eq!(relation, relation_of_counters(&a, &b));
+            assert_eq!(b.relation(&a), match relation {
+                Relation::Before => Relation::After,
+                Relation::After => Relation::Before,
+                relation => relation
+            });
+            assert_eq!(a.relation(&a), Relation::Equal);
+            let mut later = a.clone();
+            later.inc(rand::random::<u64>() % SERVERS);
+            assert_eq!(a.relation(&later), Relation::Before);
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        for _ in 0..1000 {
+            let clock = random_clock();
+            let decoded = VectorClock::decode(&clock.encode()).unwrap();
+            assert_eq!(decoded.relation(&clock), Relation::Equal);
+            assert_eq!(decoded.encode(), clock.encode());
+        }
+        let mut clock = VectorClock::new();
+        assert_eq!(clock.encode(), vec!(0));
+        clock.inc(2);
+        clock.inc(1);
+        clock.inc(300);
+        assert_eq!(clock.encode(), vec!(3, 1, 1, 2, 1, 0xac, 0x02, 1));
+        let mut large = VectorClock::new();
+        large.inc(u64::max_value());
+        assert_eq!(VectorClock::decode(&large.encode()).unwrap().get(&u64::max_value()), 1);
+        // truncated, out of order, zero counters and trailing bytes
+        assert!(VectorClock::decode(&[]).is_err());
+        assert!(VectorClock::decode(&[3, 1, 1, 2, 1, 0xac]).is_err());
+        assert!(VectorClock::decode(&[2, 2, 1, 1, 1]).is_err());
+        assert!(VectorClock::decode(&[1, 1, 0]).is_err());
+        assert!(VectorClock::decode(&[1, 1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn versioned() {
+        let mut base = Versioned::new(String::from("a"), VectorClock::new());
+        base.update(1, String::from("b"));
+        let mut later = base.clone();
+        later.update(2, String::from("c"));
+        let pick_concatenated = |a: String, b: String| if a < b {a + &b} else {b + &a};
+        // ordered versions keep the later one, the hook is only for concurrent ones
+        assert_eq!(base.clone().reconcile(later.clone(), |_, _| panic!("not concurrent")).value, "c");
+        assert_eq!(later.clone().reconcile(base.clone(), |_, _| panic!("not concurrent")).value, "c");
+        let mut concurrent = base.clone();
+        concurrent.update(3, String::from("d"));
+        let resolved = later.clone().reconcile(concurrent.clone(), &pick_concatenated);
+        assert_eq!(resolved.value, "cd");
+        assert_eq!(resolved.clock.relation(&later.clock), Relation::After);
+        assert_eq!(resolved.clock.relation(&concurrent.clock), Relation::After);
+        // resolved the same, whichever side it is on
+        assert_eq!(concurrent.reconcile(later, &pick_concatenated).value, "cd");
+    }
+}